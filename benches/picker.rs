@@ -0,0 +1,72 @@
+//! Compares how contiguous the pieces each peer is asked for are, with and without
+//! telling the picker which piece a peer was last given. Peers sending runs of
+//! adjacent pieces read them sequentially, and we write them sequentially. Run with
+//! `cargo bench --bench picker --features testing`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use torrent::testing::internals::{RarestFirst, Sha1Pieces, WorkQueue, BLOCK_SIZE};
+
+const PIECE_LENGTH: usize = 4 * BLOCK_SIZE;
+const PIECES: usize = 1024;
+/// Seeds downloaded from at once, each asked for a block in turn.
+const PEERS: usize = 8;
+
+fn main() {
+    for affinity in [false, true] {
+        let queue = WorkQueue::new(
+            Box::new(Sha1Pieces::new(vec![[0; 20]; PIECES])),
+            PIECE_LENGTH,
+            PIECE_LENGTH * PIECES,
+            Box::<RarestFirst>::default(),
+        );
+        let bitfield = vec![0xff; PIECES.div_ceil(8)];
+        let peers: Vec<SocketAddr> = (0..PEERS as u16)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        let mut last = [None; PEERS];
+        // The pieces each peer was asked for, in order, without repeats.
+        let mut pieces = vec![Vec::new(); PEERS];
+
+        let start = Instant::now();
+        let mut blocks = 0;
+        'picking: loop {
+            for peer in 0..PEERS {
+                let hint = if affinity { last[peer] } else { None };
+                let block = match queue.pop_for(peers[peer], &bitfield, hint) {
+                    Some(block) => block,
+                    None => break 'picking,
+                };
+                blocks += 1;
+                last[peer] = Some(block.piece);
+                if pieces[peer].last() != Some(&block.piece) {
+                    pieces[peer].push(block.piece);
+                }
+            }
+        }
+        report(affinity, start.elapsed(), blocks, &pieces);
+    }
+}
+
+fn report(affinity: bool, elapsed: Duration, blocks: usize, pieces: &[Vec<usize>]) {
+    // A run is broken whenever a peer moves to a piece other than the next one.
+    let runs: usize = pieces
+        .iter()
+        .map(|p| 1 + p.windows(2).filter(|w| w[1] != w[0] + 1).count())
+        .sum();
+    let mut peers_per_piece: HashMap<usize, usize> = HashMap::new();
+    for piece in pieces.iter().flatten() {
+        *peers_per_piece.entry(*piece).or_default() += 1;
+    }
+    let shared = peers_per_piece.values().filter(|&&n| n > 1).count();
+
+    println!(
+        "affinity {}: {:.1} pieces per run, {} of {} pieces from several peers, {:.0} ns per block",
+        if affinity { "on" } else { "off" },
+        PIECES as f64 / runs as f64,
+        shared,
+        PIECES,
+        elapsed.as_nanos() as f64 / blocks as f64,
+    );
+}
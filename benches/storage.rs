@@ -0,0 +1,63 @@
+//! Compares how fast each storage backend writes a torrent's pieces and serves
+//! uploads from them. Run with `cargo bench --features testing,io-uring`.
+
+use std::time::{Duration, Instant};
+use torrent::testing::internals::{IoBackend, Storage};
+
+const PIECE_LENGTH: usize = 256 * 1024;
+const PIECES: usize = 256;
+const BLOCK_SIZE: usize = 16 * 1024;
+/// How many upload requests are served at once, as if from many peers.
+const CONCURRENT_READS: usize = 64;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let content: Vec<u8> = (0..PIECE_LENGTH * PIECES).map(|i| i as u8).collect();
+    let torrent = torrent::testing::torrent("bench", &content, PIECE_LENGTH);
+
+    #[cfg(not(feature = "io-uring"))]
+    let backends = vec![IoBackend::Tokio];
+    #[cfg(feature = "io-uring")]
+    let backends = match IoBackend::default() {
+        IoBackend::IoUring => vec![IoBackend::Tokio, IoBackend::IoUring],
+        _ => vec![IoBackend::Tokio],
+    };
+
+    for backend in backends {
+        let root = std::env::temp_dir().join(format!("storage-bench-{}", std::process::id()));
+        let storage = Storage::new(&torrent, &root).unwrap().with_backend(backend);
+
+        let start = Instant::now();
+        for (idx, piece) in content.chunks(PIECE_LENGTH).enumerate() {
+            storage.write_piece(idx, piece).await?;
+        }
+        report(backend, "write pieces", start.elapsed(), content.len());
+
+        // Blocks are requested out of order across pieces, so read-ahead doesn't help.
+        let blocks: Vec<_> = (0..PIECE_LENGTH / BLOCK_SIZE)
+            .flat_map(|block| (0..PIECES).map(move |idx| (idx, block * BLOCK_SIZE)))
+            .collect();
+        let start = Instant::now();
+        for batch in blocks.chunks(CONCURRENT_READS) {
+            let reads = batch
+                .iter()
+                .map(|&(idx, begin)| storage.read_block(idx, begin, BLOCK_SIZE));
+            for block in futures::future::join_all(reads).await {
+                block?;
+            }
+        }
+        report(backend, "read blocks", start.elapsed(), content.len());
+
+        tokio::fs::remove_dir_all(&root).await?;
+    }
+
+    Ok(())
+}
+
+fn report(backend: IoBackend, what: &str, elapsed: Duration, bytes: usize) {
+    let mib_per_sec = bytes as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+    println!(
+        "{:?} {}: {:.0} MiB/s ({:?})",
+        backend, what, mib_per_sec, elapsed
+    );
+}
@@ -0,0 +1,53 @@
+/* C API for the torrent client, built with the `ffi` feature. See src/ffi.rs. */
+
+#ifndef TORRENT_H
+#define TORRENT_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+typedef struct TorrentClient TorrentClient;
+
+typedef enum TorrentCState {
+    TORRENT_PAUSED = 0,
+    TORRENT_DOWNLOADING = 1,
+    TORRENT_COMPLETE = 2,
+    TORRENT_SEEDING = 3,
+    TORRENT_FAILED = 4,
+    /* Only passed to the callback. */
+    TORRENT_REMOVED = 5,
+    TORRENT_QUEUED = 6,
+} TorrentCState;
+
+typedef struct TorrentCStatus {
+    TorrentCState state;
+    double progress;
+    uint64_t downloaded;
+    uint64_t uploaded;
+    uint64_t download_rate;
+    uint64_t upload_rate;
+    uint32_t peers;
+} TorrentCStatus;
+
+/* Called on a thread of the client's own. info_hash points to 20 bytes. */
+typedef void (*TorrentCallback)(void *user_data, const uint8_t *info_hash,
+                                TorrentCState state);
+
+/* Functions returning int return 0 on success and -1 on failure, which passing a null
+ * pointer where one isn't allowed is. */
+const char *torrent_last_error(void);
+
+TorrentClient *torrent_client_new(const char *save_path, uint16_t port);
+void torrent_client_free(TorrentClient *client);
+int torrent_client_set_callback(TorrentClient *client, TorrentCallback callback,
+                                void *user_data);
+
+int torrent_add(const TorrentClient *client, const char *source, bool paused,
+                uint8_t *info_hash_out);
+int torrent_remove(const TorrentClient *client, const uint8_t *info_hash);
+int torrent_pause(const TorrentClient *client, const uint8_t *info_hash);
+int torrent_resume(const TorrentClient *client, const uint8_t *info_hash);
+int torrent_status(const TorrentClient *client, const uint8_t *info_hash,
+                   TorrentCStatus *status_out);
+
+#endif
@@ -1,5 +1,7 @@
 pub trait Bitfield {
     fn has_piece(&self, index: usize) -> bool;
+
+    fn count_pieces(&self) -> usize;
 }
 
 pub trait BitfieldMut: Bitfield {
@@ -17,6 +19,10 @@ where
         let offset = index % 8;
         self.as_ref()[byte_idx] >> (7 - offset) & 1 != 0
     }
+
+    fn count_pieces(&self) -> usize {
+        self.as_ref().iter().map(|b| b.count_ones() as usize).sum()
+    }
 }
 
 impl<T> BitfieldMut for T
@@ -34,7 +40,7 @@ where
         let byte_idx = index / 8;
         let offset = index % 8;
 
-        self.as_mut()[byte_idx] &= 0 << (7 - offset);
+        self.as_mut()[byte_idx] &= !(1 << (7 - offset));
     }
 }
 
@@ -62,6 +68,12 @@ mod test {
 
         bitfield.unset_piece(3);
         assert!(bitfield.has_piece(3) == false);
+
+        // Only that piece is unset.
+        bitfield.set_piece(2);
+        bitfield.set_piece(3);
+        bitfield.unset_piece(3);
+        assert!(bitfield.has_piece(2));
     }
 
     #[test]
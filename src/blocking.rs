@@ -0,0 +1,192 @@
+//! A blocking API for programs which don't otherwise use async, in the style of
+//! reqwest's. Each [`Client`] runs a Tokio runtime of its own, on which its torrents
+//! carry on downloading in the background between calls. Don't use it from within
+//! another runtime: blocking calls there panic.
+
+use crate::client::{self, ClientConfig, TorrentState};
+use crate::fetch::TorrentSource;
+use crate::options::AddTorrentOptions;
+use crate::stats::TorrentStatus;
+use crate::verify::Verification;
+use crate::Torrent;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tracing::warn;
+
+/// Download the torrent `source` refers to, such as the path to a .torrent file or a
+/// magnet link, into `save_path`, returning once it's complete.
+pub fn download(source: &str, save_path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let client = Client::new(ClientConfig::new(save_path.as_ref()))?;
+    let handle = client.add(&source.parse()?, AddTorrentOptions::default())?;
+    handle.wait()
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    inner: client::Client,
+    runtime: Arc<Runtime>,
+}
+
+impl Client {
+    /// Start a client, accepting connections from peers and running a DHT node if
+    /// `config` asks for one.
+    pub fn new(config: ClientConfig) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let inner = client::Client::new(config);
+        runtime.block_on(async {
+            if let Err(e) = inner.start_dht().await {
+                warn!("Couldn't start the DHT node: {}", e);
+            }
+        });
+        let listener = inner.clone();
+        runtime.spawn(async move {
+            if let Err(e) = listener.listen().await {
+                warn!("Stopped accepting peer connections: {}", e);
+            }
+        });
+
+        Ok(Self {
+            inner,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Get the metainfo of the torrent `source` refers to, and add it.
+    pub fn add(
+        &self,
+        source: &TorrentSource,
+        options: AddTorrentOptions,
+    ) -> anyhow::Result<TorrentHandle> {
+        let handle = self.runtime.block_on(self.inner.add(source, options))?;
+        Ok(self.handle(handle))
+    }
+
+    /// Add a torrent and start downloading it in the background.
+    pub fn add_torrent(&self, torrent: Torrent) -> anyhow::Result<TorrentHandle> {
+        let handle = self.runtime.block_on(self.inner.add_torrent(torrent))?;
+        Ok(self.handle(handle))
+    }
+
+    /// Add a torrent whose content is already in `data`, and seed it.
+    pub fn seed(&self, torrent: Torrent, data: &Path) -> anyhow::Result<TorrentHandle> {
+        let handle = self.runtime.block_on(self.inner.seed(torrent, data))?;
+        Ok(self.handle(handle))
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        let handles = self.inner.torrents();
+        handles.into_iter().map(|h| self.handle(h)).collect()
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.inner.get(info_hash).map(|h| self.handle(h))
+    }
+
+    /// Stop a torrent and forget it, leaving its content on disk.
+    pub fn remove(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        self.inner.remove(info_hash)
+    }
+
+    fn handle(&self, inner: client::TorrentHandle) -> TorrentHandle {
+        TorrentHandle {
+            inner,
+            runtime: Arc::clone(&self.runtime),
+        }
+    }
+}
+
+/// A torrent which has been added to a [`Client`].
+#[derive(Debug, Clone)]
+pub struct TorrentHandle {
+    inner: client::TorrentHandle,
+    runtime: Arc<Runtime>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.inner.info_hash()
+    }
+
+    pub fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.inner.state()
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        self.inner.status()
+    }
+
+    /// Disconnect from every peer until the torrent is resumed.
+    pub fn pause(&self) -> anyhow::Result<()> {
+        self.inner.pause()
+    }
+
+    /// Start a torrent which was added or has been paused.
+    pub fn resume(&self) -> anyhow::Result<()> {
+        self.inner.resume()
+    }
+
+    /// Run the torrent whatever the client's limits on active torrents.
+    pub fn set_force_start(&self, force_start: bool) -> anyhow::Result<()> {
+        self.inner.set_force_start(force_start)
+    }
+
+    /// Hash the content on disk again, downloading pieces which turn out to be missing
+    /// or corrupt if the torrent is still downloading.
+    pub fn recheck(&self) -> anyhow::Result<Verification> {
+        self.runtime.block_on(self.inner.recheck())
+    }
+
+    /// Stop the recheck running on another thread, which fails.
+    pub fn cancel_check(&self) -> anyhow::Result<()> {
+        self.inner.cancel_check()
+    }
+
+    /// Block until the torrent finishes downloading.
+    pub fn wait(&self) -> anyhow::Result<()> {
+        self.runtime.block_on(self.inner.wait())
+    }
+
+    /// Block until the torrent finishes downloading and then reaches its seed ratio,
+    /// if it has one.
+    pub fn wait_complete(&self) -> anyhow::Result<()> {
+        self.runtime.block_on(self.inner.wait_complete())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::Output;
+
+    #[test]
+    fn seed_without_a_runtime() {
+        let root = std::env::temp_dir().join(format!("blocking-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let content = vec![7; 100];
+        std::fs::write(root.join("blocking"), &content).unwrap();
+        let torrent = crate::testing::torrent("blocking", &content, 64);
+        let info_hash = torrent.info_hash;
+
+        let client = Client::new(ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(&root)
+        })
+        .unwrap();
+        let handle = client.seed(torrent, &root).unwrap();
+        handle.wait().unwrap();
+        assert_eq!(handle.state(), TorrentState::Seeding);
+        assert_eq!(client.get(&info_hash).unwrap().name(), "blocking");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
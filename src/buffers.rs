@@ -0,0 +1,254 @@
+//! Reusing the buffers pieces are assembled in, within a memory budget shared by every
+//! torrent. Pieces can be 16 MiB or more, so allocating a fresh buffer for each one
+//! churns memory, and many sessions starting pieces at once could use it all up. Each
+//! torrent takes from the budget through a [`Share`] of it, so one torrent can't keep
+//! the others from starting pieces.
+
+use crate::fair_share::{FairShare, Grant, Share};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+pub const DEFAULT_BUDGET: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    state: Arc<Mutex<PoolState>>,
+    memory: FairShare,
+    share: Share,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    budget: usize,
+    /// Bytes in buffers which have been taken and not given back.
+    in_use: usize,
+    /// Buffers which have been given back, by length.
+    free: HashMap<usize, Vec<Vec<u8>>>,
+    free_bytes: usize,
+    /// Woken as buffers are given back, so one of those who couldn't take one can try
+    /// again.
+    given_back: Arc<Notify>,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUDGET)
+    }
+}
+
+impl BufferPool {
+    pub fn new(budget: usize) -> Self {
+        let memory = FairShare::new(budget);
+        Self {
+            state: Arc::new(Mutex::new(PoolState {
+                budget,
+                in_use: 0,
+                free: HashMap::new(),
+                free_bytes: 0,
+                given_back: Arc::new(Notify::new()),
+            })),
+            share: memory.share(1),
+            memory,
+        }
+    }
+
+    /// The same pool, taking from a share of the budget of its own with `weight`.
+    pub fn share(&self, weight: u32) -> Self {
+        Self {
+            state: Arc::clone(&self.state),
+            memory: self.memory.clone(),
+            share: self.memory.share(weight),
+        }
+    }
+
+    /// A buffer of `len` bytes, if taking it keeps us within the budget and doesn't
+    /// take room a share which is behind is waiting for. One buffer can always be
+    /// taken, so pieces larger than the budget can still be downloaded.
+    pub fn try_take(&self, len: usize) -> Option<Buffer> {
+        let grant = self.share.try_acquire(len)?;
+        Some(self.take_granted(len, grant))
+    }
+
+    /// A buffer of `len` bytes, even if it takes us over budget.
+    pub fn take(&self, len: usize) -> Buffer {
+        self.take_granted(len, self.share.force(len))
+    }
+
+    fn take_granted(&self, len: usize, grant: Grant) -> Buffer {
+        let mut state = self.state.lock().unwrap();
+        state.in_use += len;
+        let reused = state.free.get_mut(&len).and_then(Vec::pop);
+        let buf = match reused {
+            Some(buf) => {
+                state.free_bytes -= len;
+                buf
+            }
+            None => {
+                state.shrink();
+                vec![0; len]
+            }
+        };
+
+        Buffer {
+            buf,
+            pool: Some(Arc::clone(&self.state)),
+            grant: Some(grant),
+        }
+    }
+
+    /// Bytes in buffers which have been taken and not given back.
+    pub fn in_use(&self) -> usize {
+        self.state.lock().unwrap().in_use
+    }
+
+    /// Notified as each buffer is given back, waking one waiter at a time.
+    pub fn given_back(&self) -> Arc<Notify> {
+        Arc::clone(&self.state.lock().unwrap().given_back)
+    }
+}
+
+impl PoolState {
+    /// Free given back buffers until those in use and those kept fit in the budget.
+    fn shrink(&mut self) {
+        while self.in_use + self.free_bytes > self.budget {
+            let len = match self.free.iter().find(|(_, bufs)| !bufs.is_empty()) {
+                Some((&len, _)) => len,
+                None => break,
+            };
+            let bufs = self.free.get_mut(&len).unwrap();
+            bufs.pop();
+            if bufs.is_empty() {
+                self.free.remove(&len);
+            }
+            self.free_bytes -= len;
+        }
+    }
+
+    fn give_back(&mut self, buf: Vec<u8>) {
+        let len = buf.len();
+        self.in_use -= len;
+        if self.in_use + self.free_bytes + len <= self.budget {
+            self.free.entry(len).or_default().push(buf);
+            self.free_bytes += len;
+        }
+        self.given_back.notify_one();
+    }
+}
+
+/// Bytes borrowed from a [`BufferPool`], given back when dropped. Buffers are reused
+/// without being cleared, so they may hold what was last written to them.
+pub struct Buffer {
+    buf: Vec<u8>,
+    pool: Option<Arc<Mutex<PoolState>>>,
+    grant: Option<Grant>,
+}
+
+impl From<Vec<u8>> for Buffer {
+    /// A buffer which doesn't belong to a pool.
+    fn from(buf: Vec<u8>) -> Self {
+        Self {
+            buf,
+            pool: None,
+            grant: None,
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl AsRef<[u8]> for Buffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Clone for Buffer {
+    /// A copy which doesn't count against the pool's budget.
+    fn clone(&self) -> Self {
+        Self::from(self.buf.clone())
+    }
+}
+
+impl PartialEq<Vec<u8>> for Buffer {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self.buf == *other
+    }
+}
+
+impl std::fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Buffer({} bytes)", self.buf.len())
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        // Release the budget before saying the buffer's back, so whoever is told can
+        // take it.
+        self.grant.take();
+        if let Some(pool) = &self.pool {
+            let buf = std::mem::take(&mut self.buf);
+            pool.lock().unwrap().give_back(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reuse_buffers_within_budget() {
+        let pool = BufferPool::new(10);
+        let mut first = pool.take(4);
+        first[0] = 7;
+        let second = pool.try_take(4).unwrap();
+        assert!(pool.try_take(4).is_none());
+        assert_eq!(pool.in_use(), 8);
+
+        // Buffers given back are taken again as they are.
+        drop(first);
+        assert_eq!(pool.in_use(), 4);
+        assert_eq!(pool.try_take(4).unwrap()[0], 7);
+
+        // Kept buffers are freed to make room for ones of other sizes.
+        drop(second);
+        let large = pool.try_take(10).unwrap();
+        assert_eq!(pool.state.lock().unwrap().free_bytes, 0);
+        drop(large);
+
+        // However large, the first buffer can always be taken.
+        let huge = pool.try_take(100).unwrap();
+        assert!(pool.try_take(1).is_none());
+        drop(huge);
+        assert_eq!(pool.in_use(), 0);
+    }
+
+    #[test]
+    fn keep_room_for_torrents_starved_of_buffers() {
+        let pool = BufferPool::new(8);
+        let busy = pool.share(1);
+        let quiet = pool.share(1);
+
+        let mut buffers = vec![busy.try_take(4).unwrap(), busy.try_take(4).unwrap()];
+        assert!(quiet.try_take(4).is_none());
+        buffers.pop();
+        assert!(busy.try_take(4).is_none());
+        buffers.push(quiet.try_take(4).unwrap());
+        assert_eq!(pool.in_use(), 8);
+    }
+}
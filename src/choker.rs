@@ -0,0 +1,400 @@
+//! Deciding which interested peers get one of our upload slots. Peers which sent us
+//! the most the last time we met them, even in an earlier run, are preferred when
+//! there's nothing else to tell peers apart by, such as just after they connect.
+
+use crate::stats::PeerStats;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Instant, SystemTime};
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+
+/// How often upload slots are reassigned.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long round-robin lets a peer keep its slot while others are waiting.
+const MIN_SLOT_TIME: Duration = Duration::from_secs(30);
+/// Most peers how dialing went is remembered for. Past this, the peers first dialed
+/// longest ago are forgotten.
+const MAX_DIALS: usize = 50_000;
+
+/// How upload slots are shared out between interested peers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlotPolicy {
+    /// Peers take turns, giving up their slot after a while if others are waiting.
+    RoundRobin,
+    /// Slots go to the peers we're exchanging data with fastest.
+    FastestPeer,
+    /// Peers keep their slot while they're interested, and free slots go to whoever
+    /// has waited longest.
+    LongestWaiting,
+}
+
+impl std::str::FromStr for SlotPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "fastest-peer" => Ok(Self::FastestPeer),
+            "longest-waiting" => Ok(Self::LongestWaiting),
+            _ => Err(anyhow::anyhow!("Unknown slot policy: {}", s)),
+        }
+    }
+}
+
+/// What a peer sent us the last time we met it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reciprocation {
+    /// Bytes of pieces the peer sent us.
+    pub given: u64,
+    pub last_seen: SystemTime,
+}
+
+/// How dialing a peer has gone, this run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Dials {
+    /// Whether we've finished a handshake with the peer.
+    pub connected: bool,
+    /// Dials in a row which failed before the handshake.
+    pub failures: u32,
+}
+
+/// What peers sent us the last time we met them, by IP, shared by every torrent.
+#[derive(Debug, Default)]
+pub struct PeerHistory {
+    peers: Mutex<HashMap<IpAddr, Reciprocation>>,
+    dials: Mutex<DialLog>,
+}
+
+#[derive(Debug, Default)]
+struct DialLog {
+    by_ip: HashMap<IpAddr, Dials>,
+    /// The peers in `by_ip`, in the order they were first dialed.
+    order: VecDeque<IpAddr>,
+}
+
+impl PeerHistory {
+    /// Bytes the peer at `ip` sent us last time, or 0 if we haven't met it.
+    pub fn given(&self, ip: IpAddr) -> u64 {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&ip).map_or(0, |r| r.given)
+    }
+
+    /// Remember that the peer at `ip` just sent us `given` bytes in a session.
+    pub fn record(&self, ip: IpAddr, given: u64) {
+        let reciprocation = Reciprocation {
+            given,
+            last_seen: SystemTime::now(),
+        };
+        self.peers.lock().unwrap().insert(ip, reciprocation);
+    }
+
+    pub fn dials(&self, ip: IpAddr) -> Dials {
+        let dials = self.dials.lock().unwrap();
+        dials.by_ip.get(&ip).copied().unwrap_or_default()
+    }
+
+    /// Remember whether dialing the peer at `ip` got as far as the handshake.
+    pub fn dialed(&self, ip: IpAddr, connected: bool) {
+        let mut log = self.dials.lock().unwrap();
+        if !log.by_ip.contains_key(&ip) {
+            if log.order.len() >= MAX_DIALS {
+                let oldest = log.order.pop_front().expect("Log is full");
+                log.by_ip.remove(&oldest);
+            }
+            log.order.push_back(ip);
+        }
+        let dials = log.by_ip.entry(ip).or_default();
+        if connected {
+            dials.connected = true;
+            dials.failures = 0;
+        } else {
+            dials.failures += 1;
+        }
+    }
+
+    /// Add what an earlier run remembered, keeping what we've learnt since.
+    pub fn restore(&self, history: HashMap<IpAddr, Reciprocation>) {
+        let mut peers = self.peers.lock().unwrap();
+        for (ip, reciprocation) in history {
+            peers.entry(ip).or_insert(reciprocation);
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<IpAddr, Reciprocation> {
+        self.peers.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    stats: Arc<PeerStats>,
+    /// What the peer sent us last time we met it.
+    given: u64,
+    unchoke_tx: watch::Sender<bool>,
+    /// When the peer was given the slot it holds.
+    unchoked_at: Option<Instant>,
+    /// When the peer became interested while choked.
+    waiting_since: Option<Instant>,
+}
+
+/// Tracks a torrent's peers and tells their sessions when to choke or unchoke them,
+/// unchoking at most `slots` interested peers at once.
+#[derive(Debug)]
+pub struct Choker {
+    slots: usize,
+    policy: SlotPolicy,
+    history: Arc<PeerHistory>,
+    peers: Mutex<HashMap<SocketAddr, Slot>>,
+}
+
+impl Choker {
+    pub fn new(slots: usize, policy: SlotPolicy) -> Self {
+        Self {
+            slots,
+            policy,
+            history: Default::default(),
+            peers: Default::default(),
+        }
+    }
+
+    /// Prefer peers by what they sent us before, as `history` remembers it.
+    pub fn with_history(mut self, history: Arc<PeerHistory>) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Start tracking a peer, returning whether its session should unchoke it.
+    pub fn register(&self, addr: SocketAddr, stats: Arc<PeerStats>) -> watch::Receiver<bool> {
+        let (unchoke_tx, unchoke_rx) = watch::channel(false);
+        self.peers.lock().unwrap().insert(
+            addr,
+            Slot {
+                stats,
+                given: self.history.given(addr.ip()),
+                unchoke_tx,
+                unchoked_at: None,
+                waiting_since: None,
+            },
+        );
+
+        unchoke_rx
+    }
+
+    /// Stop tracking a peer, handing its slot to someone else.
+    pub fn unregister(&self, addr: &SocketAddr) {
+        let removed = self.peers.lock().unwrap().remove(addr);
+        if removed.is_some_and(|slot| slot.unchoked_at.is_some()) {
+            self.rechoke();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn unchoked(&self) -> usize {
+        let peers = self.peers.lock().unwrap();
+        peers.values().filter(|s| s.unchoked_at.is_some()).count()
+    }
+
+    /// Reassign upload slots according to the policy.
+    pub fn rechoke(&self) {
+        self.rechoke_at(Instant::now());
+    }
+
+    fn rechoke_at(&self, now: Instant) {
+        let mut peers = self.peers.lock().unwrap();
+        for slot in peers.values_mut() {
+            let interested = slot.stats.peer_interested.load(Ordering::Relaxed);
+            if interested && slot.unchoked_at.is_none() {
+                slot.waiting_since.get_or_insert(now);
+            } else {
+                slot.waiting_since = None;
+            }
+        }
+
+        let mut candidates: Vec<_> = peers
+            .iter()
+            .filter(|(_, slot)| slot.stats.peer_interested.load(Ordering::Relaxed))
+            .collect();
+        // Ties, such as between peers which started waiting together, go to the peers
+        // which sent us most last time.
+        match self.policy {
+            SlotPolicy::RoundRobin => candidates.sort_by_key(|(_, slot)| {
+                let key = match slot.unchoked_at {
+                    Some(at) if now.duration_since(at) < MIN_SLOT_TIME => (0, at),
+                    Some(at) => (2, at),
+                    None => (1, slot.waiting_since.unwrap_or(now)),
+                };
+                (key, Reverse(slot.given))
+            }),
+            SlotPolicy::FastestPeer => candidates.sort_by_key(|(_, slot)| {
+                let rate = slot.stats.download_rate() + slot.stats.upload_rate();
+                (
+                    Reverse(rate),
+                    Reverse(slot.given),
+                    slot.waiting_since.unwrap_or(now),
+                )
+            }),
+            SlotPolicy::LongestWaiting => candidates.sort_by_key(|(_, slot)| {
+                let key = match slot.unchoked_at {
+                    Some(at) => (0, at),
+                    None => (1, slot.waiting_since.unwrap_or(now)),
+                };
+                (key, Reverse(slot.given))
+            }),
+        }
+
+        let selected: Vec<_> = candidates
+            .into_iter()
+            .take(self.slots)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for (addr, slot) in peers.iter_mut() {
+            let unchoke = selected.contains(addr);
+            if unchoke == slot.unchoked_at.is_some() {
+                continue;
+            }
+
+            slot.unchoked_at = unchoke.then_some(now);
+            slot.waiting_since = None;
+            let _ = slot.unchoke_tx.send(unchoke);
+        }
+    }
+}
+
+/// Reassign the choker's slots every [`RECHOKE_INTERVAL`] until it's dropped.
+pub async fn run(choker: Weak<Choker>) {
+    let mut interval = time::interval(RECHOKE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match choker.upgrade() {
+            Some(choker) => choker.rechoke(),
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::peer::PeerSource;
+
+    fn peer(choker: &Choker, port: u16) -> (Arc<PeerStats>, watch::Receiver<bool>) {
+        let stats = Arc::new(PeerStats::new(PeerSource::Tracker));
+        stats.peer_interested.store(true, Ordering::Relaxed);
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let rx = choker.register(addr, Arc::clone(&stats));
+
+        (stats, rx)
+    }
+
+    #[test]
+    fn forget_the_oldest_dials() {
+        let history = PeerHistory::default();
+        let ip = |i: usize| IpAddr::from(std::net::Ipv4Addr::from(i as u32));
+        history.dialed(ip(0), true);
+        for i in 1..MAX_DIALS {
+            history.dialed(ip(i), false);
+        }
+        // Dialing a peer again doesn't make room.
+        history.dialed(ip(0), false);
+        assert_eq!(history.dials(ip(0)).failures, 1);
+
+        history.dialed(ip(MAX_DIALS), false);
+        assert_eq!(history.dials(ip(0)), Dials::default());
+        assert_eq!(history.dials(ip(1)).failures, 1);
+        assert_eq!(history.dials.lock().unwrap().by_ip.len(), MAX_DIALS);
+    }
+
+    #[test]
+    fn unchoke_at_most_slots_peers() {
+        let choker = Choker::new(2, SlotPolicy::LongestWaiting);
+        let peers: Vec<_> = (0..5).map(|i| peer(&choker, 6881 + i)).collect();
+
+        choker.rechoke();
+
+        assert_eq!(choker.unchoked(), 2);
+        assert_eq!(peers.iter().filter(|(_, rx)| *rx.borrow()).count(), 2);
+    }
+
+    #[test]
+    fn uninterested_peers_are_choked() {
+        let choker = Choker::new(2, SlotPolicy::LongestWaiting);
+        let (stats, rx) = peer(&choker, 6881);
+
+        choker.rechoke();
+        assert!(*rx.borrow());
+
+        stats.peer_interested.store(false, Ordering::Relaxed);
+        choker.rechoke();
+        assert!(!*rx.borrow());
+    }
+
+    #[test]
+    fn round_robin_rotates_slots() {
+        let choker = Choker::new(1, SlotPolicy::RoundRobin);
+        let (_, first) = peer(&choker, 6881);
+        let (_, second) = peer(&choker, 6882);
+        let start = Instant::now();
+
+        choker.rechoke_at(start);
+        let first_unchoked = *first.borrow();
+        assert_ne!(first_unchoked, *second.borrow());
+
+        choker.rechoke_at(start + RECHOKE_INTERVAL);
+        assert_eq!(*first.borrow(), first_unchoked);
+
+        choker.rechoke_at(start + MIN_SLOT_TIME + RECHOKE_INTERVAL);
+        assert_ne!(*first.borrow(), first_unchoked);
+        assert_ne!(*first.borrow(), *second.borrow());
+    }
+
+    #[test]
+    fn fastest_peer_gets_the_slot() {
+        let choker = Choker::new(1, SlotPolicy::FastestPeer);
+        let (_, slow) = peer(&choker, 6881);
+        let (fast_stats, fast) = peer(&choker, 6882);
+        fast_stats.record_download(1 << 20);
+
+        choker.rechoke();
+
+        assert!(*fast.borrow());
+        assert!(!*slow.borrow());
+    }
+
+    #[test]
+    fn prefer_peers_which_gave_us_most_last_time() {
+        let history = Arc::new(PeerHistory::default());
+        history.record([127, 0, 0, 1].into(), 1 << 20);
+        history.restore(HashMap::from([(
+            [127, 0, 0, 1].into(),
+            Reciprocation {
+                given: 0,
+                last_seen: SystemTime::UNIX_EPOCH,
+            },
+        )]));
+        assert_eq!(history.given([127, 0, 0, 1].into()), 1 << 20);
+
+        for policy in [
+            SlotPolicy::RoundRobin,
+            SlotPolicy::FastestPeer,
+            SlotPolicy::LongestWaiting,
+        ] {
+            let choker = Choker::new(1, policy).with_history(Arc::clone(&history));
+            let stranger = choker.register(
+                SocketAddr::from(([192, 0, 2, 1], 6881)),
+                Arc::new(PeerStats::new(PeerSource::Tracker)),
+            );
+            let (_, friend) = peer(&choker, 6881);
+            for stats in choker.peers.lock().unwrap().values() {
+                stats.stats.peer_interested.store(true, Ordering::Relaxed);
+            }
+
+            choker.rechoke();
+            assert!(*friend.borrow(), "{:?}", policy);
+            assert!(!*stranger.borrow(), "{:?}", policy);
+        }
+    }
+}
@@ -0,0 +1,3170 @@
+use crate::bitfield::{Bitfield, BitfieldMut};
+use crate::buffers::BufferPool;
+use crate::choker::{self, Choker, PeerHistory, SlotPolicy};
+use crate::dht::{Dht, DEFAULT_ROUTERS};
+use crate::disk::DiskQueue;
+use crate::external_ip::{Consensus, ExternalIp, Voter};
+use crate::fair_share::FairShare;
+use crate::fetch::{self, TorrentSource};
+use crate::hash_cache::HashCache;
+use crate::hooks::{HookContext, HookEvent, Hooks};
+use crate::ip_filter::IpFilter;
+use crate::lsd::Lsd;
+use crate::magnet::hex;
+use crate::net::{self, SocketOptions};
+use crate::options::AddTorrentOptions;
+use crate::peer::{
+    Handshake, HandshakeCodec, HavePolicy, Holepunch, PeerData, PeerSession, PeerSource,
+    SelfConnection, SessionContext, WireLog, DEFAULT_RESERVED,
+};
+use crate::picker::{PickerKind, PiecePicker, Priority};
+use crate::queues::{InOrder, SchedulerStatus, Selection, WorkQueue, WorkResult};
+use crate::rate_limit::{AltSpeed, RateLimits};
+use crate::seed_rules::{self, SeedAction, SeedGoal, SeedRule};
+use crate::session_store::{SessionStore, StoredState, TorrentRecord};
+use crate::stats::{TorrentStats, TorrentStatus};
+use crate::storage::{alloc::Preallocate, FileProgress, Storage};
+use crate::supervisor::Supervisor;
+use crate::tracker::{self, Announcer, PeersInfo};
+use crate::verify::{
+    merkle_tree, verify, verify_and_cache_with, verify_cached, verify_with, CheckProgress,
+    HashCheck, Verification,
+};
+use crate::watchdog::{self, Conditions, WatchdogEvent};
+use crate::Torrent;
+use anyhow::anyhow;
+use futures::{FutureExt, StreamExt};
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{broadcast, watch, Notify, Semaphore};
+use tokio::time::{self, Duration};
+use tokio_util::codec::Framed;
+use tracing::{debug, info, warn};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Seconds to wait before announcing again when every tracker failed.
+const ANNOUNCE_RETRY: u64 = 60;
+/// How often to check whether the bind address is still assigned to an interface.
+const BIND_ADDRESS_CHECK: Duration = Duration::from_secs(5);
+/// How often to check whether the IP filter file has changed.
+const IP_FILTER_CHECK: Duration = Duration::from_secs(30);
+/// How often to check free disk space and whether the network is metered.
+const CONDITIONS_CHECK: Duration = Duration::from_secs(30);
+/// Watchdog events kept for subscribers which fall behind.
+const WATCHDOG_EVENTS: usize = 16;
+/// File completions kept for subscribers which fall behind.
+const FILE_COMPLETIONS: usize = 64;
+/// Longest a torrent being removed waits for its trackers to be told it has stopped.
+const STOPPED_ANNOUNCE_WAIT: Duration = Duration::from_secs(10);
+/// How often to check whether a torrent has reached its seed ratio.
+const SEED_GOAL_CHECK: Duration = Duration::from_secs(10);
+/// How often torrents announce to the DHT, and how soon they try again if no nodes
+/// answered.
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const DHT_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// How often torrents are announced on the local network. BEP 14 asks for no more than
+/// once a minute.
+const LSD_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// How often we drop the slowest peer for one we haven't tried, while we're at the
+/// connection limit and have peers waiting. At most one goes each time.
+const ROTATE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a peer is connected before it can be dropped for being slow.
+const ROTATE_MIN_AGE: Duration = Duration::from_secs(2 * 60);
+/// How long a peer dropped for being slow isn't taken as a candidate, so it isn't
+/// dialed straight back.
+const ROTATE_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    /// Our public address, if known, so trackers returning it don't make us dial ourselves.
+    pub external_ip: Option<Ipv4Addr>,
+    /// Local address to listen on and send all peer and tracker traffic from. Torrents
+    /// wait while it isn't assigned to any interface.
+    pub bind_address: Option<IpAddr>,
+    /// Number of peers to ask trackers for, or the tracker's default if `None`.
+    pub numwant: Option<u32>,
+    /// Announce to trackers on this machine or private networks, which are otherwise
+    /// refused so torrents can't reach internal services through their tracker URLs.
+    pub allow_local_trackers: bool,
+    /// Options for the sockets of connections to peers.
+    pub socket: SocketOptions,
+    /// How to choose which pieces to download first.
+    pub picker: PickerKind,
+    /// Maximum number of connections which are still being dialed or handshaking.
+    pub max_half_open: usize,
+    /// Maximum number of peer connections across all torrents.
+    pub max_connections: usize,
+    /// Maximum number of torrents downloading at once. The rest are queued, and start
+    /// in the order they were added as others finish or are paused.
+    pub max_active_downloads: Option<usize>,
+    /// Maximum number of torrents seeding at once.
+    pub max_active_seeds: Option<usize>,
+    /// Maximum number of torrents downloading or seeding at once.
+    pub max_active: Option<usize>,
+    /// How many peers each torrent dials and stays connected to, dialing others it
+    /// knows of as they leave.
+    pub peers_per_torrent: usize,
+    /// Maximum number of peers each torrent uploads to at once.
+    pub upload_slots: usize,
+    /// How upload slots are shared between interested peers.
+    pub slot_policy: SlotPolicy,
+    /// When to tell peers about pieces we complete.
+    pub have_policy: HavePolicy,
+    /// How long torrents without a seed ratio of their own seed for, by the trackers
+    /// they announce to. The first rule for a torrent applies, and torrents which no
+    /// rule is for stop once they finish downloading.
+    pub seed_rules: Vec<SeedRule>,
+    /// Directory downloads are saved to.
+    pub save_path: PathBuf,
+    /// Directories the torrents in each category are saved to, instead of the save path.
+    pub category_paths: HashMap<String, PathBuf>,
+    /// Where completed pieces are written.
+    pub output: Output,
+    /// Deliver completed pieces in index order, holding back at most this many pieces
+    /// which complete early. Streaming output is always in order.
+    pub in_order: Option<usize>,
+    /// How many tasks check downloaded pieces' hashes for each torrent.
+    pub disk_workers: usize,
+    /// Bytes of downloaded pieces which can wait to be checked and saved before
+    /// sessions stop requesting blocks.
+    pub max_disk_queue: usize,
+    /// How the space for each file is set aside when it's first written to.
+    pub preallocate: Preallocate,
+    /// Bytes of memory every torrent's partly downloaded pieces can take up between
+    /// them before no more are started.
+    pub memory_budget: usize,
+    /// Re-hash the saved files once the download completes, and fail if they're corrupt.
+    pub verify_on_complete: bool,
+    /// Directory for state kept between runs, such as which pieces have been verified.
+    pub state_dir: Option<PathBuf>,
+    /// Blocklist of peer addresses, reloaded when the file changes.
+    pub ip_filter: Option<PathBuf>,
+    /// Only connect to and accept peers in these ranges, if set.
+    pub allowed_peers: Option<IpFilter>,
+    /// Reserved bits to send in handshakes. Usually `DEFAULT_RESERVED`, but features can
+    /// be turned off, or advertised without being supported, to test other clients.
+    pub reserved: [u8; 8],
+    /// Disconnect peers which send messages we don't know, rather than ignoring them.
+    pub strict_protocol: bool,
+    /// Log every message sent to and received from peers to this file, as JSON lines.
+    pub wire_log: Option<PathBuf>,
+    /// Run a DHT node on the peer port when [`Client::start_dht`] is called.
+    pub dht: bool,
+    /// Find peers on the local network (BEP 14) when [`Client::start_lsd`] is called.
+    pub lsd: bool,
+    /// Pause torrents downloading to a disk with fewer bytes free than this, until it
+    /// has room again, when [`Client::watch_conditions`] is running.
+    pub min_free_space: Option<u64>,
+    /// Pause every torrent while the system says the network is metered, when
+    /// [`Client::watch_conditions`] is running. Only NetworkManager is asked so far.
+    pub pause_on_metered: bool,
+    /// Bytes a second every torrent can download between them while the alternate
+    /// speeds are switched on with [`Client::set_alt_speed`].
+    pub alt_download_limit: u64,
+    /// Bytes a second every torrent can upload between them while the alternate speeds
+    /// are switched on.
+    pub alt_upload_limit: u64,
+    /// Start with the alternate speeds switched on.
+    pub alt_speed: bool,
+    pub hooks: Hooks,
+}
+
+/// Where completed pieces are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    /// Save pieces to the torrent's files in the save path.
+    Files,
+    /// Keep track of completed pieces without writing them anywhere.
+    Discard,
+    /// Stream the torrent's content to standard output.
+    Stdout,
+}
+
+/// How many out-of-order pieces streaming output buffers if `in_order` isn't set.
+pub const DEFAULT_REORDER_BUFFER: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentState {
+    /// Added or paused with [`TorrentHandle::pause`], and waiting for
+    /// [`TorrentHandle::resume`].
+    Paused,
+    /// Waiting until the client's limits on active torrents let it run.
+    Queued,
+    Downloading,
+    Complete,
+    /// Uploading content which was already on disk, or which we've finished downloading
+    /// and are seeding until the torrent's seed ratio is reached.
+    Seeding,
+    Failed(String),
+}
+
+/// One of a torrent's files has been saved in full, so it can be processed before the
+/// rest of the torrent is done.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCompleted {
+    pub info_hash: [u8; 20],
+    /// The file's index in [`TorrentHandle::file_progress`].
+    pub index: usize,
+    pub path: PathBuf,
+}
+
+#[derive(Debug)]
+struct TorrentInner {
+    torrent: Arc<Torrent>,
+    stats: Arc<TorrentStats>,
+    options: Mutex<AddTorrentOptions>,
+    /// Tiers of trackers the torrent announces to, which can change while it runs.
+    trackers: watch::Sender<Vec<Vec<String>>>,
+    state: watch::Receiver<TorrentState>,
+    /// Whether the torrent should stop talking to peers, as it's paused or queued.
+    /// Torrents added paused or queued don't start until this is cleared.
+    halted: watch::Sender<bool>,
+    /// Held back by the client's limits on active torrents.
+    queued: AtomicBool,
+    /// When the torrent was added, relative to the client's other torrents. Queued
+    /// torrents start in this order.
+    position: u64,
+    /// The client's torrents, to start or queue them when this one changes.
+    torrents: Weak<Torrents>,
+    /// Stops the task running the torrent, once it has been spawned.
+    task: OnceLock<tokio::task::AbortHandle>,
+    work_queue: WorkQueue,
+    storage: Arc<Storage>,
+    reannounce: Arc<Notify>,
+    rechecked: Arc<Notify>,
+    stopped: Arc<Notify>,
+    disk_tasks: Arc<DiskTasks>,
+    /// The recheck running, if one is.
+    checking: Mutex<Option<HashCheck>>,
+    /// The torrent's peer sessions, whichever of downloading or seeding is running them.
+    sessions: Supervisor,
+    /// Connections peers made to us asking for this torrent.
+    incoming: Sender<Incoming>,
+    shared: Arc<Shared>,
+}
+
+type Torrents = Mutex<HashMap<[u8; 20], TorrentHandle>>;
+
+/// How a torrent's handle prods the task running it.
+#[derive(Debug, Clone)]
+struct Signals {
+    trackers: watch::Receiver<Vec<Vec<String>>>,
+    /// Announce now, rather than waiting for the tracker's interval.
+    reannounce: Arc<Notify>,
+    /// The content was checked again, so the pieces left to download may have changed.
+    rechecked: Arc<Notify>,
+    sessions: Supervisor,
+    /// Whether the torrent is paused, queued or held, when it doesn't announce.
+    halted: watch::Receiver<bool>,
+    /// Notified once the trackers have been told the torrent has stopped.
+    stopped: Arc<Notify>,
+    disk_tasks: Arc<DiskTasks>,
+}
+
+/// The tasks checking and saving a torrent's pieces. They're spawned apart from the
+/// torrent's task, so they carry on after it's aborted unless they're stopped too.
+#[derive(Debug, Default)]
+struct DiskTasks {
+    tasks: Mutex<Vec<tokio::task::JoinHandle<()>>>,
+    stopped: AtomicBool,
+}
+
+impl DiskTasks {
+    fn add(&self, task: tokio::task::JoinHandle<()>) {
+        let mut tasks = self.tasks.lock().unwrap();
+        // The torrent's task may still be running as it's stopped.
+        if self.stopped.load(Ordering::SeqCst) {
+            task.abort();
+        }
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    /// Abort the tasks, and any added later, and wait for them to finish.
+    async fn stop(&self) {
+        let tasks = {
+            let mut tasks = self.tasks.lock().unwrap();
+            self.stopped.store(true, Ordering::SeqCst);
+            std::mem::take(&mut *tasks)
+        };
+        for task in tasks {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+}
+
+/// A connection a peer made to us, after we've read its handshake.
+#[derive(Debug)]
+struct Incoming {
+    addr: SocketAddrV4,
+    stream: Framed<TcpStream, HandshakeCodec>,
+    handshake: Handshake,
+}
+
+/// A torrent which has been added to a [`Client`].
+#[derive(Debug, Clone)]
+pub struct TorrentHandle {
+    inner: Arc<TorrentInner>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.inner.torrent.info_hash
+    }
+
+    /// The name the torrent is shown by: the one it was given, or else its own.
+    pub fn name(&self) -> String {
+        let name = self.inner.options.lock().unwrap().name.clone();
+        name.unwrap_or_else(|| self.inner.torrent.file.info.name.clone())
+    }
+
+    /// Show the torrent by `name` instead of its own, or by its own again given `None`.
+    /// Its files keep their names.
+    pub fn set_name(&self, name: Option<String>) -> anyhow::Result<()> {
+        info!(
+            "Renaming {} to {}",
+            self.name(),
+            name.as_deref().unwrap_or("its own name")
+        );
+        self.update_options(|options| options.name = name)
+    }
+
+    /// Number of the torrent, counting up from 1 in the order torrents were added since
+    /// the client started.
+    pub fn id(&self) -> u64 {
+        self.inner.position + 1
+    }
+
+    pub fn torrent(&self) -> &Torrent {
+        &self.inner.torrent
+    }
+
+    /// Directory the torrent's content is saved in.
+    pub fn save_path(&self) -> PathBuf {
+        self.inner.shared.config.save_path_for(&self.options())
+    }
+
+    /// Move the torrent's content to `save_path` while it runs, keeping what it has
+    /// downloaded and its peers. Reads and writes of its content wait for the move.
+    pub async fn move_storage(&self, save_path: &Path) -> anyhow::Result<()> {
+        if self.inner.checking.lock().unwrap().is_some() {
+            return Err(anyhow!(
+                "Can't move {} while it's being checked",
+                self.name()
+            ));
+        }
+        info!("Moving {} to {}", self.name(), save_path.display());
+        self.inner.storage.relocate(save_path).await?;
+        self.update_options(|options| options.save_path = Some(save_path.to_path_buf()))
+    }
+
+    pub fn state(&self) -> TorrentState {
+        let state = self.inner.state.borrow().clone();
+        let running = matches!(
+            state,
+            TorrentState::Paused | TorrentState::Downloading | TorrentState::Seeding
+        );
+        if running && self.is_paused() {
+            TorrentState::Paused
+        } else if running && self.is_queued() {
+            TorrentState::Queued
+        } else {
+            state
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner.options.lock().unwrap().paused
+    }
+
+    /// Whether the torrent is waiting for the client's limits on active torrents to
+    /// let it run.
+    pub fn is_queued(&self) -> bool {
+        self.inner.queued.load(Ordering::Relaxed)
+    }
+
+    /// Whether the client's watchdog is holding the torrent back, as the disk it's
+    /// saved to is nearly full or the network is metered.
+    pub fn is_held(&self) -> bool {
+        let (save_path, downloading) = (self.save_path(), self.is_downloading());
+        self.inner
+            .shared
+            .conditions
+            .borrow()
+            .holds(&save_path, downloading)
+    }
+
+    /// Whether the torrent is downloading, or will once it starts.
+    fn is_downloading(&self) -> bool {
+        matches!(
+            *self.inner.state.borrow(),
+            TorrentState::Paused | TorrentState::Queued | TorrentState::Downloading
+        )
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        TorrentStatus {
+            category: self.category(),
+            checking: self.checking(),
+            ..self.inner.stats.status(&self.info_hash(), &self.name())
+        }
+    }
+
+    pub fn category(&self) -> Option<String> {
+        self.inner.options.lock().unwrap().category.clone()
+    }
+
+    /// Put the torrent in `category`, or in none, remembering it in the session store.
+    /// The torrent's content stays where it is.
+    pub fn set_category(&self, category: Option<String>) -> anyhow::Result<()> {
+        self.update_options(|options| options.category = category)
+    }
+
+    pub fn options(&self) -> AddTorrentOptions {
+        self.inner.options.lock().unwrap().clone()
+    }
+
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        self.inner.trackers.borrow().clone()
+    }
+
+    /// Announce to `url` as well, in the given tier. Tiers past the last one add a new
+    /// tier at the end.
+    pub fn add_tracker(&self, url: &str, tier: usize) -> anyhow::Result<()> {
+        let mut tiers = self.trackers();
+        if tiers.iter().flatten().any(|t| t == url) {
+            return Err(anyhow!("Torrent already has tracker {}", url));
+        }
+        match tiers.get_mut(tier) {
+            Some(tier) => tier.push(url.to_owned()),
+            None => tiers.push(vec![url.to_owned()]),
+        }
+
+        self.replace_trackers(tiers)
+    }
+
+    pub fn remove_tracker(&self, url: &str) -> anyhow::Result<()> {
+        let mut tiers = self.trackers();
+        if !tiers.iter().flatten().any(|t| t == url) {
+            return Err(anyhow!("Torrent has no tracker {}", url));
+        }
+        for tier in &mut tiers {
+            tier.retain(|t| t != url);
+        }
+
+        self.replace_trackers(tiers)
+    }
+
+    /// Announce to `tiers` instead of the current trackers from the next announce on,
+    /// remembering them in the session store.
+    pub fn replace_trackers(&self, tiers: Vec<Vec<String>>) -> anyhow::Result<()> {
+        for url in tiers.iter().flatten() {
+            tracker::check_url(url, self.inner.shared.config.allow_local_trackers)?;
+        }
+        let tiers: Vec<Vec<String>> = tiers.into_iter().filter(|t| !t.is_empty()).collect();
+
+        let mut options = self.inner.options.lock().unwrap();
+        options.trackers = Some(tiers.clone());
+        if let Some(store) = &self.inner.shared.store {
+            store.set_options(&self.info_hash(), &options)?;
+        }
+        self.inner.trackers.send_replace(tiers);
+
+        Ok(())
+    }
+
+    /// Disconnect from every peer, and don't talk to any until the torrent is resumed.
+    /// Trackers are told the torrent has stopped, and that it's started again once it's
+    /// resumed.
+    pub fn pause(&self) -> anyhow::Result<()> {
+        if self.is_paused() {
+            return Ok(());
+        }
+        info!("Pausing {}", self.name());
+        self.update_options(|options| options.paused = true)
+    }
+
+    /// Start a torrent which was added or has been paused, once the client's limits on
+    /// active torrents let it.
+    pub fn resume(&self) -> anyhow::Result<()> {
+        if !self.is_paused() {
+            return Ok(());
+        }
+        info!("Resuming {}", self.name());
+        self.update_options(|options| options.paused = false)
+    }
+
+    /// Run the torrent whatever the client's limits on active torrents, without it
+    /// counting towards them, resuming it if it's paused. Clearing it queues the torrent
+    /// again if the limits call for it.
+    pub fn set_force_start(&self, force_start: bool) -> anyhow::Result<()> {
+        self.update_options(|options| {
+            options.force_start = force_start;
+            if force_start {
+                options.paused = false;
+            }
+        })
+    }
+
+    /// Change the torrent's options, remembering them in the session store, and start
+    /// or halt it and the client's other torrents as the change calls for.
+    fn update_options(&self, update: impl FnOnce(&mut AddTorrentOptions)) -> anyhow::Result<()> {
+        let result = {
+            let mut options = self.inner.options.lock().unwrap();
+            update(&mut options);
+            match &self.inner.shared.store {
+                Some(store) => store.set_options(&self.info_hash(), &options),
+                None => Ok(()),
+            }
+        };
+        self.update_halted();
+        if let Some(torrents) = self.inner.torrents.upgrade() {
+            update_queue(&torrents, &self.inner.shared.config);
+        }
+
+        result
+    }
+
+    fn set_queued(&self, queued: bool) {
+        if self.inner.queued.swap(queued, Ordering::Relaxed) != queued {
+            match queued {
+                true => info!("Queueing {}", self.name()),
+                false => info!("Starting {}, as it's no longer queued", self.name()),
+            }
+        }
+        self.update_halted();
+    }
+
+    /// Stop talking to peers if the torrent is paused, queued or held, and start again
+    /// once it's none of them.
+    fn update_halted(&self) {
+        let halted = self.is_paused() || self.is_queued() || self.is_held();
+        if self.inner.halted.send_replace(halted) == halted {
+            return;
+        }
+        if halted {
+            self.inner.sessions.pause();
+        } else {
+            self.inner.sessions.resume();
+            // Torrents which haven't started yet announce as they start anyway.
+            if *self.inner.state.borrow() != TorrentState::Paused {
+                self.inner.reannounce.notify_one();
+            }
+        }
+    }
+
+    /// Announce to the trackers now, rather than when the last announce's interval is up.
+    pub fn reannounce(&self) {
+        self.inner.reannounce.notify_one();
+    }
+
+    /// Hash the content on disk again, ignoring the hash cache. Pieces which turn out
+    /// to be missing or corrupt are downloaded again if the torrent is still downloading,
+    /// and pieces found intact aren't. The torrent keeps seeding while it's checked, and
+    /// stops offering each corrupt piece as soon as the check finds it.
+    pub async fn recheck(&self) -> anyhow::Result<Verification> {
+        let config = &self.inner.shared.config;
+        if config.output != Output::Files {
+            return Err(anyhow!("Torrent isn't being saved to disk"));
+        }
+        let work_queue = &self.inner.work_queue;
+        let had: Vec<bool> = (0..self.inner.stats.piece_count)
+            .map(|idx| work_queue.has_piece(idx))
+            .collect();
+        let check = HashCheck::new().with_on_piece({
+            let work_queue = work_queue.clone();
+            move |idx, ok| match (ok, work_queue.has_piece(idx)) {
+                (true, false) => work_queue.mark_complete(idx),
+                (false, true) => work_queue.mark_missing(idx),
+                _ => {}
+            }
+        });
+        {
+            let mut checking = self.inner.checking.lock().unwrap();
+            if checking.is_some() {
+                return Err(anyhow!("Torrent is already being checked"));
+            }
+            *checking = Some(check.clone());
+        }
+        let _running = Running(&self.inner.checking);
+
+        info!("Checking {}", self.name());
+        let torrent = &self.inner.torrent;
+        let save_path = config.save_path_for(&self.options());
+        let verification = check_uncached(config, torrent, &save_path, &check).await?;
+
+        let bad_pieces: HashSet<_> = verification.bad_pieces.iter().copied().collect();
+        let mut lost = 0;
+        for idx in 0..verification.piece_count {
+            match (bad_pieces.contains(&idx), work_queue.has_piece(idx)) {
+                (false, false) => {
+                    work_queue.mark_complete(idx);
+                    self.inner.storage.mark_saved(idx);
+                }
+                (true, true) => {
+                    work_queue.mark_missing(idx);
+                    self.inner.storage.forget(idx);
+                }
+                _ => {}
+            }
+            if bad_pieces.contains(&idx) && had.get(idx).copied().unwrap_or_default() {
+                lost += 1;
+            }
+        }
+        self.inner
+            .stats
+            .set_pieces_done(verification.piece_count - bad_pieces.len());
+        self.inner.rechecked.notify_one();
+
+        let downloading = matches!(
+            *self.inner.state.borrow(),
+            TorrentState::Paused | TorrentState::Downloading
+        );
+        if lost > 0 && !downloading {
+            warn!(
+                "{} pieces of {} are no longer intact, and won't be downloaded again",
+                lost,
+                self.name()
+            );
+        }
+
+        Ok(verification)
+    }
+
+    /// How far the running recheck has got, if one is running.
+    pub fn checking(&self) -> Option<CheckProgress> {
+        let checking = self.inner.checking.lock().unwrap();
+        checking.as_ref().map(HashCheck::progress)
+    }
+
+    /// Stop the running recheck, which fails. Pieces it already checked keep its results.
+    pub fn cancel_check(&self) -> anyhow::Result<()> {
+        match &*self.inner.checking.lock().unwrap() {
+            Some(check) => {
+                check.cancel();
+                Ok(())
+            }
+            None => Err(anyhow!("Torrent isn't being checked")),
+        }
+    }
+
+    /// What the torrent's scheduler is doing: the pieces being downloaded, which peers
+    /// have which blocks, and the pieces which failed their hash check.
+    pub fn scheduler(&self) -> SchedulerStatus {
+        self.inner.work_queue.status()
+    }
+
+    /// How much of each of the torrent's files has been saved.
+    pub fn file_progress(&self) -> Vec<FileProgress> {
+        self.inner.storage.file_progress()
+    }
+
+    /// The torrent's scheduler, to change what it downloads while it runs, for example
+    /// revoking pieces of files which are no longer wanted.
+    pub fn work_queue(&self) -> &WorkQueue {
+        &self.inner.work_queue
+    }
+
+    /// Change which of the torrent's files are downloaded, given their priorities in
+    /// order, while it runs. Requests for pieces no longer wanted are cancelled, and
+    /// the new priorities are remembered in the session store. Torrents which have
+    /// finished downloading can't be given pieces they're missing to download.
+    pub fn set_file_priorities(&self, priorities: Vec<Priority>) -> anyhow::Result<Selection> {
+        let storage = &self.inner.storage;
+        if priorities.len() > storage.files().len() {
+            return Err(anyhow!(
+                "{} has {} files, but got priorities for {}",
+                self.name(),
+                storage.files().len(),
+                priorities.len()
+            ));
+        }
+
+        let piece_priorities = storage.piece_priorities(&priorities);
+        let finished = matches!(
+            *self.inner.state.borrow(),
+            TorrentState::Complete | TorrentState::Seeding
+        );
+        let work_queue = &self.inner.work_queue;
+        let missing = piece_priorities
+            .iter()
+            .enumerate()
+            .filter(|&(idx, &priority)| priority != Priority::Skip && !work_queue.has_piece(idx))
+            .count();
+        if finished && missing > 0 {
+            return Err(anyhow!(
+                "{} has finished downloading, so can't download the {} pieces it's missing; add it again instead",
+                self.name(),
+                missing
+            ));
+        }
+
+        let selection = work_queue.set_priorities(&piece_priorities)?;
+        self.update_options(|options| options.file_priorities = priorities)?;
+        info!(
+            "Now downloading {} of {} pieces of {}",
+            selection.wanted,
+            self.inner.stats.piece_count,
+            self.name()
+        );
+        Ok(selection)
+    }
+
+    /// End our session with the peer at `addr`. We may connect to it again if a tracker
+    /// hands it out again.
+    pub fn disconnect_peer(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        if !self.inner.sessions.disconnect(addr) {
+            return Err(anyhow!("Not connected to {}", addr));
+        }
+
+        Ok(())
+    }
+
+    /// Disconnect from every peer at `addr`'s IP, and refuse to talk to it for `duration`.
+    pub fn ban_peer(&self, addr: IpAddr, duration: Duration) {
+        info!("Banning {} from {} for {:?}", addr, self.name(), duration);
+        self.inner.sessions.ban(addr, duration);
+    }
+
+    /// Wait for the torrent to finish downloading.
+    pub async fn wait(&self) -> anyhow::Result<()> {
+        self.wait_until(|state| matches!(state, TorrentState::Complete | TorrentState::Seeding))
+            .await
+    }
+
+    /// Wait for the torrent to finish downloading and then reach its seed ratio, if
+    /// it has one.
+    pub async fn wait_complete(&self) -> anyhow::Result<()> {
+        self.wait_until(|state| *state == TorrentState::Complete)
+            .await
+    }
+
+    /// Stop the torrent's task and its sessions.
+    fn stop(&self) {
+        if let Some(task) = self.inner.task.get() {
+            task.abort();
+        }
+        self.inner.sessions.abort_all();
+    }
+
+    /// Halt the torrent and wait for its trackers to be told it has stopped, for at most
+    /// [`STOPPED_ANNOUNCE_WAIT`], before it's removed.
+    async fn announce_stopped(&self) {
+        let running = self
+            .inner
+            .task
+            .get()
+            .is_some_and(|task| !task.is_finished());
+        let has_trackers = self.trackers().iter().any(|tier| !tier.is_empty());
+        if !running || !has_trackers || *self.inner.halted.borrow() {
+            return;
+        }
+        let stopped = self.inner.stopped.notified();
+        self.inner.halted.send_replace(true);
+        self.inner.sessions.pause();
+        if time::timeout(STOPPED_ANNOUNCE_WAIT, stopped).await.is_err() {
+            debug!("Gave up telling {}'s trackers it has stopped", self.name());
+        }
+    }
+
+    fn signals(&self) -> Signals {
+        Signals {
+            trackers: self.inner.trackers.subscribe(),
+            reannounce: Arc::clone(&self.inner.reannounce),
+            rechecked: Arc::clone(&self.inner.rechecked),
+            sessions: self.inner.sessions.clone(),
+            halted: self.inner.halted.subscribe(),
+            stopped: Arc::clone(&self.inner.stopped),
+            disk_tasks: Arc::clone(&self.inner.disk_tasks),
+        }
+    }
+
+    async fn wait_until(&self, done: impl Fn(&TorrentState) -> bool) -> anyhow::Result<()> {
+        let mut state = self.inner.state.clone();
+        loop {
+            match &*state.borrow() {
+                TorrentState::Failed(e) => return Err(anyhow!("{}", e)),
+                state if done(state) => return Ok(()),
+                _ => {}
+            }
+            state.changed().await?;
+        }
+    }
+}
+
+/// Owns the torrents being downloaded and the tasks downloading them.
+#[derive(Debug, Clone)]
+pub struct Client {
+    shared: Arc<Shared>,
+    torrents: Arc<Torrents>,
+}
+
+/// State shared between the client and all of its torrents' download tasks.
+#[derive(Debug)]
+struct Shared {
+    config: ClientConfig,
+    /// Addresses which turned out to be us when we connected to them.
+    own_addrs: Mutex<HashSet<SocketAddr>>,
+    /// Peers we refuse to connect to or accept connections from.
+    ip_filter: Mutex<Arc<IpFilter>>,
+    /// Whether the bind address can be used. Torrents don't announce or dial peers
+    /// while it can't.
+    network: watch::Sender<bool>,
+    /// Full disks and metered networks, which hold torrents back.
+    conditions: watch::Sender<Conditions>,
+    watchdog_events: broadcast::Sender<WatchdogEvent>,
+    file_completions: broadcast::Sender<FileCompleted>,
+    /// Caps on every torrent's transfers between them, while switched on.
+    alt_speed: AltSpeed,
+    /// Limits connections which haven't finished the handshake yet.
+    half_open: Semaphore,
+    /// Limits connections which have finished the handshake, or are waiting to start it,
+    /// sharing them between torrents by weight.
+    connections: FairShare,
+    /// Limits how many pieces are being checked or saved at once, sharing turns at the
+    /// disk between torrents by weight.
+    disk_turns: FairShare,
+    /// Where torrents are remembered between runs.
+    store: Option<SessionStore>,
+    /// What peers sent us last time, for the chokers to prefer them.
+    peer_history: Arc<PeerHistory>,
+    /// The DHT node, once it has started.
+    dht: watch::Sender<Option<Dht>>,
+    /// Local service discovery, once it has started.
+    lsd: watch::Sender<Option<Lsd>>,
+    /// The address others see us at.
+    external_ip: ExternalIp,
+    /// Buffers every torrent assembles pieces in.
+    buffers: BufferPool,
+    /// Where peer messages are logged, if `config.wire_log` is set and could be opened.
+    wire_log: Option<Arc<WireLog>>,
+    /// How many torrents have been added, to order the queue by.
+    added: AtomicU64,
+}
+
+impl Client {
+    pub fn new(config: ClientConfig) -> Self {
+        Self::build(config, None)
+    }
+
+    /// Create a client which remembers its torrents in `store`. Call [`Client::restore`]
+    /// to start the torrents it already holds.
+    pub fn with_session_store(config: ClientConfig, store: SessionStore) -> Self {
+        Self::build(config, Some(store))
+    }
+
+    fn build(config: ClientConfig, store: Option<SessionStore>) -> Self {
+        let alt_speed = AltSpeed::new(config.alt_download_limit, config.alt_upload_limit);
+        alt_speed.set_enabled(config.alt_speed);
+        let wire_log = config.wire_log.as_deref().and_then(|path| {
+            WireLog::open(path)
+                .map_err(|e| warn!("Couldn't open wire log {}: {}", path.display(), e))
+                .ok()
+        });
+        Self {
+            shared: Arc::new(Shared {
+                half_open: Semaphore::new(config.max_half_open),
+                connections: FairShare::new(config.max_connections),
+                // Enough for one torrent's workers and its writer, so a torrent on its
+                // own isn't slowed down.
+                disk_turns: FairShare::new(config.disk_workers.max(1) + 1),
+                own_addrs: Default::default(),
+                ip_filter: Default::default(),
+                network: watch::Sender::new(true),
+                conditions: watch::Sender::new(Conditions::default()),
+                watchdog_events: broadcast::channel(WATCHDOG_EVENTS).0,
+                file_completions: broadcast::channel(FILE_COMPLETIONS).0,
+                alt_speed,
+                store,
+                peer_history: Default::default(),
+                dht: watch::Sender::new(None),
+                lsd: watch::Sender::new(None),
+                external_ip: ExternalIp::new(),
+                buffers: BufferPool::new(config.memory_budget),
+                added: AtomicU64::new(0),
+                wire_log,
+                config,
+            }),
+            torrents: Default::default(),
+        }
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        self.torrents.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.torrents.lock().unwrap().get(info_hash).cloned()
+    }
+
+    /// Stop a torrent and forget it, including in the session store. Its content is
+    /// left on disk.
+    pub fn remove(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let handle = self
+            .torrents
+            .lock()
+            .unwrap()
+            .remove(info_hash)
+            .ok_or_else(|| anyhow!("No torrent {}", hex(info_hash)))?;
+        info!("Removing {}", handle.name());
+        handle.stop();
+        update_queue(&self.torrents, &self.shared.config);
+        if let Some(store) = &self.shared.store {
+            store.remove_torrent(info_hash)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stop a torrent and forget it, including in the session store, once its trackers
+    /// have been told it has stopped. With `delete_data`, its content is deleted too:
+    /// only the files the torrent lists, and directories under its save path they leave
+    /// empty.
+    pub async fn remove_torrent(
+        &self,
+        info_hash: &[u8; 20],
+        delete_data: bool,
+    ) -> anyhow::Result<()> {
+        let handle = self
+            .get(info_hash)
+            .ok_or_else(|| anyhow!("No torrent {}", hex(info_hash)))?;
+        handle.announce_stopped().await;
+        match delete_data {
+            true => self.remove_with_data(info_hash).await?,
+            false => self.remove(info_hash)?,
+        }
+        if let Some(dir) = &self.shared.config.state_dir {
+            HashCache::new(dir.join("verified"))
+                .remove(info_hash)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove a torrent which has seeded for long enough, if its seed rule says to.
+    fn after_seeding(&self, info_hash: [u8; 20], action: Option<SeedAction>) {
+        let delete_data = match action {
+            Some(SeedAction::Remove) => false,
+            Some(SeedAction::RemoveData) => true,
+            Some(SeedAction::Pause) | None => return,
+        };
+        let client = self.clone();
+        // Removing the torrent aborts the task calling this, so it's done from another.
+        tokio::spawn(async move {
+            let result = match delete_data {
+                true => client.remove_with_data(&info_hash).await,
+                false => client.remove(&info_hash),
+            };
+            if let Err(e) = result {
+                warn!("Couldn't remove {} after seeding: {}", hex(&info_hash), e);
+            }
+        });
+    }
+
+    /// Remove a torrent, as [`Client::remove`] does, and delete its content once
+    /// nothing is left writing to it.
+    pub(crate) async fn remove_with_data(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let handle = self
+            .get(info_hash)
+            .ok_or_else(|| anyhow!("No torrent {}", hex(info_hash)))?;
+        let save_path = handle.save_path();
+        self.remove(info_hash)?;
+        handle.inner.disk_tasks.stop().await;
+        handle.inner.storage.delete(&save_path).await
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.shared.config
+    }
+
+    /// Pause every torrent, as [`TorrentHandle::pause`] does.
+    pub fn pause_all(&self) -> anyhow::Result<()> {
+        info!("Pausing every torrent");
+        for handle in self.torrents() {
+            handle.pause()?;
+        }
+        Ok(())
+    }
+
+    /// Resume every paused torrent, as [`TorrentHandle::resume`] does.
+    pub fn resume_all(&self) -> anyhow::Result<()> {
+        info!("Resuming every torrent");
+        for handle in self.torrents() {
+            handle.resume()?;
+        }
+        Ok(())
+    }
+
+    /// Switch the alternate speeds on or off. While they're on, every torrent's
+    /// transfers are capped at [`ClientConfig::alt_download_limit`] and
+    /// [`ClientConfig::alt_upload_limit`] between them, as well as by their own limits.
+    pub fn set_alt_speed(&self, enabled: bool) {
+        if self.alt_speed() != enabled {
+            info!(
+                "Switching alternate speeds {}",
+                if enabled { "on" } else { "off" }
+            );
+            self.shared.alt_speed.set_enabled(enabled);
+        }
+    }
+
+    pub fn alt_speed(&self) -> bool {
+        self.shared.alt_speed.is_enabled()
+    }
+
+    #[cfg(feature = "rss")]
+    pub(crate) fn session_store(&self) -> Option<&SessionStore> {
+        self.shared.store.as_ref()
+    }
+
+    /// The torrents in `category`.
+    pub fn in_category(&self, category: &str) -> Vec<TorrentHandle> {
+        self.torrents()
+            .into_iter()
+            .filter(|t| t.category().as_deref() == Some(category))
+            .collect()
+    }
+
+    /// Find torrents whose hex info hash starts with `prefix`.
+    pub fn find(&self, prefix: &str) -> Vec<TorrentHandle> {
+        let prefix = prefix.to_lowercase();
+        self.torrents()
+            .into_iter()
+            .filter(|t| hex(&t.info_hash()).starts_with(&prefix))
+            .collect()
+    }
+
+    /// Get the metainfo of the torrent `source` refers to, and add it.
+    pub async fn add(
+        &self,
+        source: &TorrentSource,
+        options: AddTorrentOptions,
+    ) -> anyhow::Result<TorrentHandle> {
+        let config = &self.shared.config;
+        let dht = self.dht();
+        let torrent = fetch::resolve(
+            source,
+            &config.peer_id,
+            config.port,
+            config.bind_address,
+            &config.socket,
+            dht.as_ref(),
+        )
+        .await?;
+        self.add_torrent_with_options(torrent, options).await
+    }
+
+    /// Add a torrent and start downloading it in the background.
+    pub async fn add_torrent(&self, torrent: Torrent) -> anyhow::Result<TorrentHandle> {
+        self.add_torrent_with_options(torrent, AddTorrentOptions::default())
+            .await
+    }
+
+    /// Add a torrent with settings which override the client's configuration.
+    pub async fn add_torrent_with_options(
+        &self,
+        torrent: Torrent,
+        options: AddTorrentOptions,
+    ) -> anyhow::Result<TorrentHandle> {
+        let picker = self.picker(&options);
+        self.spawn_download(torrent, picker, options, &[], false)
+            .await
+    }
+
+    /// Add a torrent which chooses pieces to download with a custom picker.
+    pub async fn add_torrent_with_picker(
+        &self,
+        torrent: Torrent,
+        picker: Box<dyn PiecePicker>,
+    ) -> anyhow::Result<TorrentHandle> {
+        self.spawn_download(torrent, picker, AddTorrentOptions::default(), &[], false)
+            .await
+    }
+
+    /// Add a torrent whose content is already in `data`, checking it and seeding the
+    /// pieces which are intact without downloading anything.
+    pub async fn seed(&self, torrent: Torrent, data: &Path) -> anyhow::Result<TorrentHandle> {
+        let options = AddTorrentOptions {
+            save_path: Some(data.to_owned()),
+            ..Default::default()
+        };
+        self.spawn_seed(torrent, options, StoredState::Seeding, false)
+            .await
+    }
+
+    /// Start the torrents in the session store which aren't running yet. Unfinished
+    /// downloads carry on from the pieces already on disk.
+    pub async fn restore(&self) -> anyhow::Result<Vec<TorrentHandle>> {
+        let records = match &self.shared.store {
+            Some(store) => {
+                self.shared.peer_history.restore(store.peer_history()?);
+                store.torrents()?
+            }
+            None => return Ok(Vec::new()),
+        };
+
+        let mut handles = Vec::new();
+        for record in records {
+            if self.get(&record.info_hash).is_some() {
+                continue;
+            }
+            match self.restore_torrent(&record).await {
+                Ok(handle) => {
+                    let stats = &handle.inner.stats;
+                    stats
+                        .downloaded
+                        .fetch_add(record.downloaded, Ordering::Relaxed);
+                    stats.uploaded.fetch_add(record.uploaded, Ordering::Relaxed);
+                    stats
+                        .seeding_time
+                        .fetch_add(record.seeding_time, Ordering::Relaxed);
+                    handles.push(handle);
+                }
+                Err(e) => warn!("Couldn't restore torrent {}: {}", hex(&record.info_hash), e),
+            }
+        }
+
+        Ok(handles)
+    }
+
+    async fn restore_torrent(&self, record: &TorrentRecord) -> anyhow::Result<TorrentHandle> {
+        let torrent = Torrent::from_bytes(&record.metainfo)?;
+        info!("Restoring {}", torrent.file.info.name);
+        let options = AddTorrentOptions {
+            save_path: Some(record.save_path.clone()),
+            ..record.options.clone()
+        };
+        if record.state != StoredState::Downloading {
+            return self.spawn_seed(torrent, options, record.state, true).await;
+        }
+
+        let have = match self.shared.config.output {
+            Output::Files => {
+                let verification = check(&self.shared.config, &torrent, &record.save_path).await?;
+                let bad_pieces: HashSet<_> = verification.bad_pieces.into_iter().collect();
+                (0..verification.piece_count)
+                    .filter(|idx| !bad_pieces.contains(idx))
+                    .collect()
+            }
+            Output::Discard | Output::Stdout => Vec::new(),
+        };
+        let picker = self.picker(&options);
+        self.spawn_download(torrent, picker, options, &have, true)
+            .await
+    }
+
+    fn picker(&self, options: &AddTorrentOptions) -> Box<dyn PiecePicker> {
+        if options.sequential {
+            PickerKind::Sequential.build()
+        } else {
+            self.shared.config.picker.build()
+        }
+    }
+
+    /// Start the DHT node, if the config has one, from the routing table saved by the
+    /// last run, and bootstrap it in the background.
+    pub async fn start_dht(&self) -> anyhow::Result<Option<Dht>> {
+        let config = &self.shared.config;
+        if !config.dht {
+            return Ok(None);
+        }
+        let state = match &self.shared.store {
+            Some(store) => store.dht()?,
+            None => None,
+        };
+        // Both families on the peer port, unless we're bound to one address.
+        let addrs = match config.bind_address {
+            Some(ip) => vec![SocketAddr::new(ip, config.port)],
+            None => vec![
+                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), config.port),
+                SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), config.port),
+            ],
+        };
+        let dht = Dht::bind(&addrs, self.shared.external_ip(), state).await?;
+        dht.report_external_ip(self.shared.external_ip.clone());
+        self.shared.dht.send_replace(Some(dht.clone()));
+        tokio::spawn(follow_external_ip(
+            self.shared.external_ip.subscribe(),
+            dht.clone(),
+        ));
+
+        let bootstrap = dht.clone();
+        tokio::spawn(async move {
+            match bootstrap.bootstrap(DEFAULT_ROUTERS).await {
+                Ok(()) => info!("Joined the DHT with {} nodes", bootstrap.node_count()),
+                Err(e) => warn!("Couldn't join the DHT: {}", e),
+            }
+        });
+
+        Ok(Some(dht))
+    }
+
+    pub fn dht(&self) -> Option<Dht> {
+        self.shared.dht.borrow().clone()
+    }
+
+    /// Start finding peers on the local network, if the config allows, on the interface
+    /// we're bound to. Only IPv4 is supported.
+    pub fn start_lsd(&self) -> anyhow::Result<Option<Lsd>> {
+        let config = &self.shared.config;
+        if !config.lsd {
+            return Ok(None);
+        }
+        let interface = match config.bind_address {
+            Some(IpAddr::V4(ip)) => ip,
+            Some(IpAddr::V6(ip)) => {
+                return Err(anyhow!("Can't find local peers while bound to {}", ip));
+            }
+            None => Ipv4Addr::UNSPECIFIED,
+        };
+        let lsd = Lsd::bind(interface, config.port)?;
+        self.shared.lsd.send_replace(Some(lsd.clone()));
+
+        Ok(Some(lsd))
+    }
+
+    /// Our public address: the one we were configured with, or the one peers, trackers
+    /// and DHT nodes agree they see us at.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.shared.external_ip()
+    }
+
+    /// Write every torrent's transfer totals and the DHT routing table to the session
+    /// store, so they carry over to the next run.
+    pub fn save_session(&self) -> anyhow::Result<()> {
+        let store = match &self.shared.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        if let Some(dht) = self.dht() {
+            store.save_dht(&dht.state())?;
+        }
+        store.save_peer_history(&self.shared.peer_history.snapshot())?;
+        for handle in self.torrents() {
+            let stats = &handle.inner.stats;
+            store.set_totals(
+                &handle.info_hash(),
+                stats.downloaded.load(Ordering::Relaxed),
+                stats.uploaded.load(Ordering::Relaxed),
+                stats.seeding_time.load(Ordering::Relaxed),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Register a torrent and download the pieces which aren't in `have`, then seed it
+    /// if it has a seed ratio. Torrents restored from the session store aren't stored
+    /// again and don't run the added hook.
+    async fn spawn_download(
+        &self,
+        mut torrent: Torrent,
+        picker: Box<dyn PiecePicker>,
+        mut options: AddTorrentOptions,
+        have: &[usize],
+        restored: bool,
+    ) -> anyhow::Result<TorrentHandle> {
+        let save_path = self.shared.config.save_path_for(&options);
+        options.save_path = Some(save_path.clone());
+        if let Some(tiers) = &options.trackers {
+            torrent.set_trackers(tiers.clone());
+        }
+
+        let work_queue = torrent
+            .work_queue(picker)?
+            .with_buffers(self.shared.buffers.share(options.weight()));
+        let storage = Arc::new(
+            Storage::new(&torrent, &save_path)?.with_preallocate(self.shared.config.preallocate),
+        );
+        work_queue.set_priorities(&storage.piece_priorities(&options.file_priorities))?;
+        let torrent = Arc::new(torrent);
+        let stats = Arc::new(TorrentStats::new(torrent.file.info.piece_count()));
+        for &idx in have {
+            work_queue.mark_complete(idx);
+            storage.mark_saved(idx);
+            stats.piece_done();
+        }
+        let state = if options.paused {
+            TorrentState::Paused
+        } else {
+            TorrentState::Downloading
+        };
+        let (handle, state_tx, incoming_rx) =
+            self.register(&torrent, &stats, &work_queue, &storage, &options, state)?;
+
+        let mut ctx = hook_context(&torrent, save_path.clone());
+        if !restored {
+            self.persist(&torrent, &options, StoredState::Downloading)?;
+            self.shared.config.hooks.run(HookEvent::Added, &ctx).await?;
+        }
+
+        let goal = seed_goal(&self.shared.config, &options, &torrent);
+        let client = self.clone();
+        let shared = Arc::clone(&self.shared);
+        let mut halted = handle.inner.halted.subscribe();
+        let signals = handle.signals();
+        let task = tokio::spawn(async move {
+            if *halted.borrow_and_update() {
+                if halted.wait_for(|&halted| !halted).await.is_err() {
+                    return;
+                }
+                let _ = state_tx.send(TorrentState::Downloading);
+            }
+
+            let hooks = &shared.config.hooks;
+            let info_hash = torrent.info_hash;
+            let seed_queue = work_queue.clone();
+            let seed_storage = Arc::clone(&storage);
+            let (session_ctx, save_rx) = session_context(
+                &torrent,
+                &stats,
+                work_queue,
+                storage,
+                &options,
+                &shared,
+                &signals.disk_tasks,
+                false,
+            );
+            let result = download(
+                session_ctx,
+                save_rx,
+                &save_path,
+                Arc::clone(&shared),
+                incoming_rx,
+                signals.clone(),
+            )
+            .await;
+            let action = goal.as_ref().map(|goal| goal.action);
+            let result = match (result, goal) {
+                (Ok(incoming_rx), Some(goal)) => {
+                    set_stored_state(&shared, &info_hash, StoredState::Seeding);
+                    run_hook(hooks, HookEvent::Complete, &ctx).await;
+                    let _ = state_tx.send(TorrentState::Seeding);
+                    let (session_ctx, _) = session_context(
+                        &torrent,
+                        &stats,
+                        seed_queue,
+                        seed_storage,
+                        &options,
+                        &shared,
+                        &signals.disk_tasks,
+                        true,
+                    );
+                    seed(
+                        session_ctx,
+                        Some(goal),
+                        Arc::clone(&shared),
+                        incoming_rx,
+                        signals,
+                    )
+                    .await
+                }
+                (Ok(_), None) => {
+                    run_hook(hooks, HookEvent::Complete, &ctx).await;
+                    Ok(())
+                }
+                (Err(e), _) => Err(e),
+            };
+            let state = match result {
+                Ok(()) => {
+                    set_stored_state(&shared, &info_hash, StoredState::Complete);
+                    client.after_seeding(info_hash, action);
+                    TorrentState::Complete
+                }
+                Err(e) => {
+                    ctx.error = Some(e.to_string());
+                    run_hook(hooks, HookEvent::Error, &ctx).await;
+                    TorrentState::Failed(e.to_string())
+                }
+            };
+            let _ = state_tx.send(state);
+        });
+        let _ = handle.inner.task.set(task.abort_handle());
+
+        Ok(handle)
+    }
+
+    /// Register a torrent and seed whatever of its content is intact in its save path.
+    async fn spawn_seed(
+        &self,
+        mut torrent: Torrent,
+        options: AddTorrentOptions,
+        stored_state: StoredState,
+        restored: bool,
+    ) -> anyhow::Result<TorrentHandle> {
+        let data = self.shared.config.save_path_for(&options);
+        if let Some(tiers) = &options.trackers {
+            torrent.set_trackers(tiers.clone());
+        }
+
+        let verification = check(&self.shared.config, &torrent, &data).await?;
+        if verification.bad_pieces.len() == verification.piece_count {
+            return Err(anyhow!(
+                "None of the torrent's content was found in {}",
+                data.display()
+            ));
+        }
+        if !verification.is_ok() {
+            warn!("Only seeding some of the torrent: {}", verification);
+        }
+
+        let picker = self.shared.config.picker.build();
+        let work_queue = match torrent.file.info.merkle_root()? {
+            // Peers need the hash chain of every piece we send, so seeds need the whole tree.
+            Some(_) => {
+                let cache = (self.shared.config.state_dir.as_ref())
+                    .map(|dir| HashCache::new(dir.join("verified")));
+                let tree = merkle_tree(&torrent, &data, cache.as_ref()).await?;
+                torrent.work_queue_with(Box::new(tree), picker)
+            }
+            None => torrent.work_queue(picker)?,
+        };
+        let torrent = Arc::new(torrent);
+        let stats = Arc::new(TorrentStats::new(verification.piece_count));
+        let bad_pieces: HashSet<_> = verification.bad_pieces.iter().copied().collect();
+        let storage = Arc::new(
+            Storage::new(&torrent, &data)?.with_preallocate(self.shared.config.preallocate),
+        );
+        for idx in (0..verification.piece_count).filter(|idx| !bad_pieces.contains(idx)) {
+            work_queue.mark_complete(idx);
+            storage.mark_saved(idx);
+            stats.piece_done();
+        }
+        let (handle, state_tx, incoming_rx) = self.register(
+            &torrent,
+            &stats,
+            &work_queue,
+            &storage,
+            &options,
+            TorrentState::Seeding,
+        )?;
+
+        let mut ctx = hook_context(&torrent, data.clone());
+        if !restored {
+            self.persist(&torrent, &options, stored_state)?;
+            self.shared.config.hooks.run(HookEvent::Added, &ctx).await?;
+        }
+
+        let goal = seed_goal(&self.shared.config, &options, &torrent);
+        let client = self.clone();
+        let shared = Arc::clone(&self.shared);
+        let signals = handle.signals();
+        let task = tokio::spawn(async move {
+            let info_hash = torrent.info_hash;
+            let action = goal.as_ref().map(|goal| goal.action);
+            let (session_ctx, _) = session_context(
+                &torrent,
+                &stats,
+                work_queue,
+                storage,
+                &options,
+                &shared,
+                &signals.disk_tasks,
+                true,
+            );
+            let result = seed(session_ctx, goal, Arc::clone(&shared), incoming_rx, signals).await;
+            match result {
+                Ok(()) => {
+                    set_stored_state(&shared, &info_hash, StoredState::Complete);
+                    let _ = state_tx.send(TorrentState::Complete);
+                    client.after_seeding(info_hash, action);
+                }
+                Err(e) => {
+                    ctx.error = Some(e.to_string());
+                    run_hook(&shared.config.hooks, HookEvent::Error, &ctx).await;
+                    let _ = state_tx.send(TorrentState::Failed(e.to_string()));
+                }
+            }
+        });
+        let _ = handle.inner.task.set(task.abort_handle());
+
+        Ok(handle)
+    }
+
+    /// Remember a newly added torrent in the session store, if there is one.
+    fn persist(
+        &self,
+        torrent: &Torrent,
+        options: &AddTorrentOptions,
+        state: StoredState,
+    ) -> anyhow::Result<()> {
+        let store = match &self.shared.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        let save_path = self.shared.config.save_path_for(options);
+
+        store.save_torrent(&TorrentRecord {
+            info_hash: torrent.info_hash,
+            metainfo: torrent.to_bytes()?,
+            save_path,
+            state,
+            downloaded: 0,
+            uploaded: 0,
+            seeding_time: 0,
+            options: options.clone(),
+        })
+    }
+
+    fn register(
+        &self,
+        torrent: &Arc<Torrent>,
+        stats: &Arc<TorrentStats>,
+        work_queue: &WorkQueue,
+        storage: &Arc<Storage>,
+        options: &AddTorrentOptions,
+        state: TorrentState,
+    ) -> anyhow::Result<(
+        TorrentHandle,
+        watch::Sender<TorrentState>,
+        Receiver<Incoming>,
+    )> {
+        let mut torrents = self.torrents.lock().unwrap();
+        if torrents.contains_key(&torrent.info_hash) {
+            return Err(anyhow!("Torrent has already been added"));
+        }
+
+        let (state_tx, state_rx) = watch::channel(state);
+        let (incoming_tx, incoming_rx) = channel(16);
+        let handle = TorrentHandle {
+            inner: Arc::new(TorrentInner {
+                torrent: Arc::clone(torrent),
+                stats: Arc::clone(stats),
+                options: Mutex::new(options.clone()),
+                trackers: watch::Sender::new(torrent.trackers()),
+                state: state_rx,
+                halted: watch::Sender::new(false),
+                queued: AtomicBool::new(false),
+                position: self.shared.added.fetch_add(1, Ordering::Relaxed),
+                torrents: Arc::downgrade(&self.torrents),
+                task: OnceLock::new(),
+                work_queue: work_queue.clone(),
+                storage: Arc::clone(storage),
+                reannounce: Default::default(),
+                rechecked: Default::default(),
+                stopped: Default::default(),
+                disk_tasks: Default::default(),
+                checking: Mutex::new(None),
+                sessions: Supervisor::new(),
+                incoming: incoming_tx,
+                shared: Arc::clone(&self.shared),
+            }),
+        };
+        torrents.insert(torrent.info_hash, handle.clone());
+        drop(torrents);
+
+        // Torrents finishing their downloads, or failing, free slots for queued ones.
+        let mut state = state_tx.subscribe();
+        let queue = Arc::downgrade(&self.torrents);
+        let shared = Arc::clone(&self.shared);
+        tokio::spawn(async move {
+            while state.changed().await.is_ok() {
+                match queue.upgrade() {
+                    Some(torrents) => update_queue(&torrents, &shared.config),
+                    None => return,
+                }
+            }
+        });
+        update_queue(&self.torrents, &self.shared.config);
+
+        Ok((handle, state_tx, incoming_rx))
+    }
+
+    /// Load the IP filter from the file in the config, replacing the one in use.
+    /// Returns how many ranges it blocks.
+    pub async fn reload_ip_filter(&self) -> anyhow::Result<usize> {
+        let path = match &self.shared.config.ip_filter {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let filter = IpFilter::load(path).await?;
+        let ranges = filter.len();
+        *self.shared.ip_filter.lock().unwrap() = Arc::new(filter);
+
+        Ok(ranges)
+    }
+
+    /// Reload the IP filter whenever its file changes, keeping the old filter if the new
+    /// one can't be read. Call [`Client::reload_ip_filter`] first to load it initially.
+    pub async fn watch_ip_filter(&self) -> anyhow::Result<()> {
+        let path = match &self.shared.config.ip_filter {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let modified = |path| async move { tokio::fs::metadata(path).await?.modified() };
+
+        let mut loaded = modified(path).await.ok();
+        let mut interval = time::interval(IP_FILTER_CHECK);
+        loop {
+            interval.tick().await;
+            let current = modified(path).await.ok();
+            if current == loaded {
+                continue;
+            }
+            loaded = current;
+            match self.reload_ip_filter().await {
+                Ok(ranges) => info!("Reloaded IP filter: {} ranges", ranges),
+                Err(e) => warn!("Couldn't reload IP filter {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Check the bind address is still assigned to an interface, pausing torrents while
+    /// it isn't, such as when a VPN goes down, and resuming them when it's back.
+    pub async fn watch_bind_address(&self) -> anyhow::Result<()> {
+        let ip = match self.shared.config.bind_address {
+            Some(ip) => ip,
+            None => return Ok(()),
+        };
+
+        let mut interval = time::interval(BIND_ADDRESS_CHECK);
+        loop {
+            interval.tick().await;
+            let available = net::is_available(ip);
+            let changed = self.shared.network.send_if_modified(|up| {
+                let changed = *up != available;
+                *up = available;
+                changed
+            });
+            match (changed, available) {
+                (true, false) => warn!("{} has gone away, pausing torrents", ip),
+                (true, true) => info!("{} is back, resuming torrents", ip),
+                (false, _) => {}
+            }
+        }
+    }
+
+    /// Check free disk space and whether the network is metered every so often, holding
+    /// torrents back while the config says they should be, and letting them carry on
+    /// once they shouldn't.
+    pub async fn watch_conditions(&self) -> anyhow::Result<()> {
+        let config = &self.shared.config;
+        if config.min_free_space.is_none() && !config.pause_on_metered {
+            return Ok(());
+        }
+
+        let mut interval = time::interval(CONDITIONS_CHECK);
+        loop {
+            interval.tick().await;
+            let save_paths = self
+                .torrents()
+                .iter()
+                .filter(|handle| handle.is_downloading())
+                .map(TorrentHandle::save_path)
+                .collect();
+            let last = self.shared.conditions.borrow().clone();
+            let (conditions, events) = watchdog::check(
+                &last,
+                &save_paths,
+                config.min_free_space,
+                config.pause_on_metered,
+            )
+            .await;
+            self.set_conditions(conditions, events);
+        }
+    }
+
+    /// Hold torrents back or let them go as `conditions` call for, telling subscribers
+    /// about the `events` which led to them.
+    fn set_conditions(&self, conditions: Conditions, events: Vec<WatchdogEvent>) {
+        let changed = self.shared.conditions.send_if_modified(|current| {
+            let changed = *current != conditions;
+            *current = conditions;
+            changed
+        });
+        if !changed {
+            return;
+        }
+
+        for event in events {
+            match event {
+                WatchdogEvent::DiskLow { .. } | WatchdogEvent::Metered => warn!("{}", event),
+                _ => info!("{}", event),
+            }
+            let _ = self.shared.watchdog_events.send(event);
+        }
+        for handle in self.torrents() {
+            handle.update_halted();
+        }
+    }
+
+    /// What the watchdog has found, which changes as [`Client::watch_conditions`] runs.
+    pub fn conditions(&self) -> watch::Receiver<Conditions> {
+        self.shared.conditions.subscribe()
+    }
+
+    /// Hear about torrents being held back by full disks or metered networks, and let go
+    /// again.
+    pub fn watchdog_events(&self) -> broadcast::Receiver<WatchdogEvent> {
+        self.shared.watchdog_events.subscribe()
+    }
+
+    /// Hear about each file of a torrent being saved in full, as soon as its last piece
+    /// is written.
+    pub fn file_completions(&self) -> broadcast::Receiver<FileCompleted> {
+        self.shared.file_completions.subscribe()
+    }
+
+    /// Accept connections from peers on our port, handing each to the torrent it asks for.
+    pub async fn listen(&self) -> anyhow::Result<()> {
+        let ip = self
+            .shared
+            .config
+            .bind_address
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let socket = &self.shared.config.socket;
+        let listener = net::listen(SocketAddr::new(ip, self.shared.config.port), socket)?;
+        info!("Listening for peers on {}", listener.local_addr()?);
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let addr = match addr {
+                SocketAddr::V4(addr) => addr,
+                SocketAddr::V6(_) => continue,
+            };
+            if let Err(e) = socket.apply(&stream) {
+                debug!("Couldn't set socket options for {}: {}", addr, e);
+            }
+
+            let client = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = client.route_incoming(stream, addr).await {
+                    debug!("Dropping connection from {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn route_incoming(&self, stream: TcpStream, addr: SocketAddrV4) -> anyhow::Result<()> {
+        let mut stream = Framed::new(stream, HandshakeCodec);
+        let handshake = time::timeout(HANDSHAKE_TIMEOUT, stream.next())
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for handshake"))?
+            .ok_or_else(|| anyhow!("Connection closed before handshake"))??;
+
+        let incoming = self
+            .torrents
+            .lock()
+            .unwrap()
+            .get(&handshake.info_hash)
+            .map(|t| t.inner.incoming.clone())
+            .ok_or_else(|| anyhow!("Unknown info hash"))?;
+        incoming
+            .send(Incoming {
+                addr,
+                stream,
+                handshake,
+            })
+            .await
+            .map_err(|_| anyhow!("Torrent is no longer running"))
+    }
+}
+
+impl Shared {
+    /// Wait until the bind address can be used, if it's gone away.
+    async fn network_up(&self) {
+        let mut network = self.network.subscribe();
+        let _ = network.wait_for(|&up| up).await;
+    }
+
+    /// Whether the IP filter or the allowed ranges stop us talking to `ip`.
+    fn is_blocked(&self, ip: IpAddr) -> bool {
+        let allowed = match &self.config.allowed_peers {
+            Some(allowed) => allowed.contains(ip),
+            None => true,
+        };
+        !allowed || self.ip_filter.lock().unwrap().contains(ip)
+    }
+
+    /// The address we were configured with, or failing that the one others agree we
+    /// have, preferring IPv4.
+    fn external_ip(&self) -> Option<IpAddr> {
+        let consensus = self.external_ip.get();
+        let learned = consensus
+            .v4
+            .map(IpAddr::V4)
+            .or(consensus.v6.map(IpAddr::V6));
+        self.config.external_ip.map(IpAddr::V4).or(learned)
+    }
+
+    /// Whether `addr` is us: one of our listening addresses, including at the address
+    /// others see us at, or one we've connected to and found ourselves.
+    fn is_own_addr(&self, addr: SocketAddr) -> bool {
+        self.config.is_own_addr(addr)
+            || (addr.port() == self.config.port && self.external_ip.get().contains(addr.ip()))
+            || self.own_addrs.lock().unwrap().contains(&addr)
+    }
+}
+
+/// Log our external address as others agree on it, and keep the DHT node's id secure
+/// for it.
+async fn follow_external_ip(mut consensus: watch::Receiver<Consensus>, dht: Dht) {
+    while consensus.changed().await.is_ok() {
+        let consensus = *consensus.borrow_and_update();
+        info!(
+            "Peers see us at {:?} over IPv4 and {:?} over IPv6",
+            consensus.v4, consensus.v6
+        );
+        if let Some(ip) = consensus
+            .v4
+            .map(IpAddr::V4)
+            .or(consensus.v6.map(IpAddr::V6))
+        {
+            dht.set_external_ip(ip);
+        }
+    }
+}
+
+/// Hash all of the torrent's content under `root`, updating the hash cache if there is one.
+async fn check_uncached(
+    config: &ClientConfig,
+    torrent: &Torrent,
+    root: &Path,
+    check: &HashCheck,
+) -> anyhow::Result<Verification> {
+    match &config.state_dir {
+        Some(dir) => {
+            let cache = HashCache::new(dir.join("verified"));
+            verify_and_cache_with(torrent, root, &cache, check).await
+        }
+        None => verify_with(torrent, root, check).await,
+    }
+}
+
+/// Clears a torrent's running recheck once it ends, however it ends.
+struct Running<'a>(&'a Mutex<Option<HashCheck>>);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+/// Hash the torrent's content under `root`, using the hash cache if there is one.
+async fn check(
+    config: &ClientConfig,
+    torrent: &Torrent,
+    root: &Path,
+) -> anyhow::Result<Verification> {
+    match &config.state_dir {
+        Some(dir) => verify_cached(torrent, root, &HashCache::new(dir.join("verified"))).await,
+        None => verify(torrent, root).await,
+    }
+}
+
+fn hook_context(torrent: &Torrent, save_path: PathBuf) -> HookContext {
+    HookContext {
+        name: torrent.file.info.name.clone(),
+        info_hash: torrent.info_hash,
+        save_path,
+        size: torrent.file.info.total_length(),
+        error: None,
+        file: None,
+    }
+}
+
+async fn run_hook(hooks: &Hooks, event: HookEvent, ctx: &HookContext) {
+    if let Err(e) = hooks.run(event, ctx).await {
+        warn!("Couldn't run {} hook: {}", event, e);
+    }
+}
+
+impl ClientConfig {
+    /// A configuration saving downloads to `save_path`, with a random peer id and the
+    /// defaults the command line uses.
+    pub fn new(save_path: impl Into<PathBuf>) -> Self {
+        let mut peer_id = *b"-RS0001-000000000000";
+        let mut rng = rand::thread_rng();
+        for byte in &mut peer_id[8..] {
+            *byte = rng.sample(rand::distributions::Alphanumeric);
+        }
+
+        Self {
+            peer_id,
+            port: 6881,
+            external_ip: None,
+            bind_address: None,
+            socket: SocketOptions::default(),
+            numwant: None,
+            allow_local_trackers: false,
+            picker: PickerKind::RarestFirst,
+            max_half_open: 20,
+            max_connections: 100,
+            max_active_downloads: None,
+            max_active_seeds: None,
+            max_active: None,
+            peers_per_torrent: 40,
+            upload_slots: 8,
+            slot_policy: SlotPolicy::FastestPeer,
+            have_policy: HavePolicy::SkipRedundant,
+            seed_rules: Vec::new(),
+            save_path: save_path.into(),
+            category_paths: HashMap::new(),
+            output: Output::Files,
+            in_order: None,
+            disk_workers: crate::disk::DEFAULT_WORKERS,
+            max_disk_queue: crate::disk::DEFAULT_MAX_QUEUED,
+            preallocate: Preallocate::default(),
+            memory_budget: crate::buffers::DEFAULT_BUDGET,
+            verify_on_complete: false,
+            state_dir: None,
+            ip_filter: None,
+            allowed_peers: None,
+            reserved: DEFAULT_RESERVED,
+            strict_protocol: false,
+            wire_log: None,
+            dht: true,
+            lsd: true,
+            min_free_space: None,
+            pause_on_metered: false,
+            alt_download_limit: crate::rate_limit::DEFAULT_ALT_SPEED,
+            alt_upload_limit: crate::rate_limit::DEFAULT_ALT_SPEED,
+            alt_speed: false,
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Where a torrent with `options` is saved: its own save path if it has one, otherwise
+    /// its category's, otherwise the client's.
+    pub fn save_path_for(&self, options: &AddTorrentOptions) -> PathBuf {
+        let category_path = options
+            .category
+            .as_ref()
+            .and_then(|category| self.category_paths.get(category));
+        options
+            .save_path
+            .as_ref()
+            .or(category_path)
+            .unwrap_or(&self.save_path)
+            .clone()
+    }
+
+    /// Whether `addr` is one of our own listening addresses.
+    pub fn is_own_addr(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip();
+        addr.port() == self.port
+            && (ip.is_loopback()
+                || ip.is_unspecified()
+                || Some(ip) == self.external_ip.map(IpAddr::V4))
+    }
+}
+
+/// Queue the torrents past the client's limits on active torrents, in the order they
+/// were added, and start the queued torrents which now fit. Paused and force started
+/// torrents don't count towards the limits.
+fn update_queue(torrents: &Torrents, config: &ClientConfig) {
+    let mut handles: Vec<_> = torrents.lock().unwrap().values().cloned().collect();
+    handles.sort_by_key(|handle| handle.inner.position);
+
+    let (mut downloads, mut seeds, mut total) = (0, 0, 0);
+    for handle in handles {
+        let options = handle.options();
+        let state = handle.inner.state.borrow().clone();
+        let (active, limit) = match state {
+            _ if options.paused || options.force_start => {
+                handle.set_queued(false);
+                continue;
+            }
+            TorrentState::Paused | TorrentState::Downloading => {
+                (&mut downloads, config.max_active_downloads)
+            }
+            TorrentState::Seeding => (&mut seeds, config.max_active_seeds),
+            TorrentState::Queued | TorrentState::Complete | TorrentState::Failed(_) => {
+                handle.set_queued(false);
+                continue;
+            }
+        };
+        let fits = limit.is_none_or(|limit| *active < limit)
+            && config.max_active.is_none_or(|max| total < max);
+        if fits {
+            *active += 1;
+            total += 1;
+        }
+        handle.set_queued(!fits);
+    }
+}
+
+/// Set up what a torrent's peer sessions share, returning it along with where the
+/// sessions send the pieces they complete.
+#[allow(clippy::too_many_arguments)]
+fn session_context(
+    torrent: &Arc<Torrent>,
+    stats: &Arc<TorrentStats>,
+    work_queue: WorkQueue,
+    storage: Arc<Storage>,
+    options: &AddTorrentOptions,
+    shared: &Shared,
+    disk_tasks: &DiskTasks,
+    seed: bool,
+) -> (SessionContext, Receiver<WorkResult>) {
+    let (save_tx, save_rx) = channel(50);
+    let disk = DiskQueue::start(
+        work_queue.clone(),
+        shared.config.disk_workers,
+        shared.config.max_disk_queue,
+        shared.disk_turns.share(options.weight()),
+        save_tx,
+    );
+    for worker in disk.take_workers() {
+        disk_tasks.add(worker);
+    }
+    let ctx = SessionContext {
+        torrent: Arc::clone(torrent),
+        stats: Arc::clone(stats),
+        work_queue,
+        storage,
+        choker: start_choker(shared),
+        disk,
+        peer_id: shared.config.peer_id,
+        seed,
+        limits: RateLimits::new(options.download_limit, options.upload_limit),
+        alt_speed: shared.alt_speed.share(options.weight()),
+        connections: shared.connections.share(options.weight()),
+        bind_address: shared.config.bind_address,
+        socket: shared.config.socket,
+        reserved: shared.config.reserved,
+        holepunch: Holepunch::new(),
+        external_ip: shared.external_ip.clone(),
+        have_policy: shared.config.have_policy,
+        strict_protocol: shared.config.strict_protocol,
+        wire_log: shared.wire_log.clone(),
+        stop: watch::channel(false).1,
+    };
+
+    (ctx, save_rx)
+}
+
+/// Download the pieces we don't have yet, returning the connections peers make to us
+/// so they can be handed on if we carry on seeding.
+async fn download(
+    mut ctx: SessionContext,
+    save_rx: Receiver<WorkResult>,
+    save_path: &Path,
+    shared: Arc<Shared>,
+    incoming_rx: Receiver<Incoming>,
+    mut signals: Signals,
+) -> anyhow::Result<Receiver<Incoming>> {
+    let config = &shared.config;
+    let torrent = Arc::clone(&ctx.torrent);
+    let stats = Arc::clone(&ctx.stats);
+    let (stop_tx, stop_rx) = watch::channel(false);
+    ctx.stop = stop_rx;
+    let sessions = supervise(&ctx, &shared, &signals.sessions);
+    dial_known_peers(&ctx, &shared, &sessions);
+    let accept_handle = tokio::spawn(accept_peers(
+        incoming_rx,
+        ctx.clone(),
+        Arc::clone(&shared),
+        sessions.clone(),
+    ));
+    tokio::spawn(announce_to_dht(
+        ctx.clone(),
+        Arc::clone(&shared),
+        sessions.clone(),
+    ));
+    tokio::spawn(announce_to_lsd(
+        ctx.clone(),
+        Arc::clone(&shared),
+        sessions.clone(),
+    ));
+    tokio::spawn(rotate_slow_peers(ctx.clone(), sessions.clone()));
+
+    let mut announcer = Announcer::from_trackers(signals.trackers.borrow_and_update().clone())?
+        .with_numwant(config.numwant)
+        .with_bind_address(config.bind_address)?
+        .with_local_trackers(config.allow_local_trackers)?;
+
+    let (saved_tx, saved_rx) = tokio::sync::oneshot::channel();
+    let saving = save_results(
+        save_rx,
+        hook_context(&torrent, save_path.to_path_buf()),
+        ctx.work_queue.clone(),
+        Arc::clone(&signals.rechecked),
+        Arc::clone(&stats),
+        Arc::clone(&ctx.storage),
+        ctx.disk.clone(),
+        Arc::clone(&shared),
+    );
+    signals.disk_tasks.add(tokio::spawn(async move {
+        let _ = saved_tx.send(saving.await);
+    }));
+    let work_queue = ctx.work_queue.clone();
+
+    // Sessions are dialed as soon as any source finds peers, rather than once every
+    // source has answered, and we keep finding peers in case the first ones leave.
+    let keep_announcing = async {
+        loop {
+            let interval =
+                announce_and_connect(&mut announcer, &mut signals, &ctx, &shared, &sessions).await;
+            wait_to_announce(interval, &mut signals).await;
+        }
+    };
+    // We're done once every piece is saved, whatever state the sessions are in.
+    tokio::select! {
+        result = saved_rx => result??,
+        _ = keep_announcing => {}
+    }
+    let _ = stop_tx.send(true);
+    sessions.abort_all();
+    let incoming_rx = accept_handle.await?;
+
+    if config.verify_on_complete && config.output == Output::Files {
+        let verification = check(config, &torrent, save_path).await?;
+        // Skipped pieces were never downloaded, so only the rest need to be intact.
+        let bad_pieces = verification
+            .bad_pieces
+            .iter()
+            .filter(|&&idx| work_queue.has_piece(idx))
+            .count();
+        if bad_pieces > 0 {
+            return Err(anyhow!("Download failed verification: {}", verification));
+        }
+        info!("Verified download: {}", verification);
+    }
+
+    Ok(incoming_rx)
+}
+
+/// Upload the pieces we have to peers from the trackers and peers which connect to us,
+/// announcing again every interval to find new peers. Seeding stops once we've uploaded
+/// `goal` bytes, if there is one.
+async fn seed(
+    mut ctx: SessionContext,
+    goal: Option<SeedGoal>,
+    shared: Arc<Shared>,
+    incoming_rx: Receiver<Incoming>,
+    mut signals: Signals,
+) -> anyhow::Result<()> {
+    let config = &shared.config;
+    let (stop_tx, stop_rx) = watch::channel(false);
+    ctx.stop = stop_rx;
+    let sessions = supervise(&ctx, &shared, &signals.sessions);
+    dial_known_peers(&ctx, &shared, &sessions);
+    tokio::spawn(accept_peers(
+        incoming_rx,
+        ctx.clone(),
+        Arc::clone(&shared),
+        sessions.clone(),
+    ));
+    tokio::spawn(announce_to_dht(
+        ctx.clone(),
+        Arc::clone(&shared),
+        sessions.clone(),
+    ));
+    tokio::spawn(announce_to_lsd(
+        ctx.clone(),
+        Arc::clone(&shared),
+        sessions.clone(),
+    ));
+
+    let mut announcer = Announcer::from_trackers(signals.trackers.borrow_and_update().clone())?
+        .with_numwant(config.numwant)
+        .with_bind_address(config.bind_address)?
+        .with_local_trackers(config.allow_local_trackers)?;
+    loop {
+        let interval =
+            announce_and_connect(&mut announcer, &mut signals, &ctx, &shared, &sessions).await;
+
+        tokio::select! {
+            _ = wait_to_announce(interval, &mut signals) => {}
+            _ = reach_goal(&ctx.stats, goal.as_ref(), &sessions) => {
+                info!("Reached seeding goal for {}", ctx.torrent.file.info.name);
+                let _ = stop_tx.send(true);
+                sessions.abort_all();
+                let transfer = ctx.stats.transfer(ctx.work_queue.left(), false);
+                announcer
+                    .announce_stopped(&ctx.torrent.info_hash, transfer, &config.peer_id, config.port)
+                    .await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Have the torrent's supervisor dial peers it's given until the torrent has as many
+/// sessions as it should, best candidates first. Peers which keep timing out are
+/// holepunched to through a peer we're connected to.
+fn supervise(ctx: &SessionContext, shared: &Arc<Shared>, sessions: &Supervisor) -> Supervisor {
+    sessions.score_with(Arc::clone(&shared.peer_history));
+    let holepunch = ctx.holepunch.clone();
+    sessions.on_unreachable(move |addr| {
+        if !holepunch.request(addr) {
+            debug!("No peers to relay a holepunch to {}", addr);
+        }
+    });
+    tokio::spawn(dial_holepunched(ctx.clone(), sessions.clone()));
+
+    let ctx = ctx.clone();
+    let shared = Arc::clone(shared);
+    sessions.start(shared.config.peers_per_torrent, move |peer| {
+        run_session(Connection::Dial(peer), ctx.clone(), Arc::clone(&shared)).boxed()
+    });
+    sessions.clone()
+}
+
+/// Dial the peers trackers gave the torrent last time, without waiting for them to
+/// answer this time.
+fn dial_known_peers(ctx: &SessionContext, shared: &Shared, sessions: &Supervisor) {
+    let store = match &shared.store {
+        Some(store) => store,
+        None => return,
+    };
+    match store.known_peers(&ctx.torrent.info_hash) {
+        Ok(peers) => {
+            debug!("Dialing {} peers from the last announce", peers.len());
+            // Peers are only dialed over IPv4 for now.
+            sessions.add_candidates(peers.into_iter().filter_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(PeerData::new(addr, PeerSource::Tracker)),
+                SocketAddr::V6(_) => None,
+            }));
+        }
+        Err(e) => warn!("Couldn't read the peers from the last announce: {}", e),
+    }
+}
+
+/// Dial the peers relays put us in touch with straight away, while they're dialing us,
+/// until the torrent stops.
+async fn dial_holepunched(ctx: SessionContext, sessions: Supervisor) {
+    let mut stop = ctx.stop.clone();
+    loop {
+        let addr = tokio::select! {
+            addr = ctx.holepunch.next_connect() => addr,
+            _ = stop.wait_for(|&stop| stop) => return,
+        };
+        // Peers are only dialed over IPv4 for now.
+        if let SocketAddr::V4(addr) = addr {
+            if !ctx.stats.has_peer(&addr.into()) {
+                sessions.dial(PeerData::new(addr, PeerSource::Holepunch));
+            }
+        }
+    }
+}
+
+/// Announce, and hand the peers we aren't already connected to to `sessions`. Returns
+/// how many seconds to wait before announcing again.
+async fn announce_and_connect(
+    announcer: &mut Announcer,
+    signals: &mut Signals,
+    ctx: &SessionContext,
+    shared: &Arc<Shared>,
+    sessions: &Supervisor,
+) -> u64 {
+    let stats = &ctx.stats;
+    match announce(announcer, signals, ctx, shared).await {
+        Ok(details) => {
+            let peers = details.peers.into_iter();
+            sessions.add_candidates(peers.filter(|peer| !stats.has_peer(&peer.addr())));
+            details.interval.into()
+        }
+        Err(e) => {
+            warn!("Couldn't announce: {}", e);
+            ANNOUNCE_RETRY
+        }
+    }
+}
+
+/// Wait until the torrent has uploaded `goal` bytes, or forever if there's no goal.
+async fn reach_goal(stats: &TorrentStats, goal: Option<&SeedGoal>, sessions: &Supervisor) {
+    let mut interval = time::interval(SEED_GOAL_CHECK);
+    let mut last = time::Instant::now();
+    // Seeding time not yet counted in the stats, which only hold whole seconds.
+    let mut seeded = Duration::ZERO;
+    loop {
+        interval.tick().await;
+        // Time spent paused doesn't count as seeding.
+        let now = time::Instant::now();
+        if !sessions.is_paused() {
+            seeded += now.duration_since(last);
+            let secs = seeded.as_secs();
+            stats.seeding_time.fetch_add(secs, Ordering::Relaxed);
+            seeded -= Duration::from_secs(secs);
+        }
+        last = now;
+
+        let uploaded = stats.uploaded.load(Ordering::Relaxed);
+        let seeding_time = Duration::from_secs(stats.seeding_time.load(Ordering::Relaxed));
+        if goal.is_some_and(|goal| goal.is_met(uploaded, seeding_time)) {
+            return;
+        }
+    }
+}
+
+/// How long a torrent seeds for: to its own seed ratio if it has one, otherwise to the
+/// goal of the first seed rule for its trackers.
+fn seed_goal(
+    config: &ClientConfig,
+    options: &AddTorrentOptions,
+    torrent: &Torrent,
+) -> Option<SeedGoal> {
+    let total_length = torrent.file.info.total_length();
+    match options.seed_goal(total_length) {
+        Some(uploaded) => Some(SeedGoal {
+            uploaded: Some(uploaded),
+            ..Default::default()
+        }),
+        None => seed_rules::goal_for(&config.seed_rules, &torrent.trackers(), total_length),
+    }
+}
+
+fn set_stored_state(shared: &Shared, info_hash: &[u8; 20], state: StoredState) {
+    if let Some(store) = &shared.store {
+        if let Err(e) = store.set_state(info_hash, state) {
+            warn!("Couldn't save torrent state: {}", e);
+        }
+    }
+}
+
+fn start_choker(shared: &Shared) -> Arc<Choker> {
+    let config = &shared.config;
+    let choker = Choker::new(config.upload_slots, config.slot_policy)
+        .with_history(Arc::clone(&shared.peer_history));
+    let choker = Arc::new(choker);
+    tokio::spawn(choker::run(Arc::downgrade(&choker)));
+
+    choker
+}
+
+/// Wait `secs` seconds before announcing again, or until the torrent's trackers change,
+/// we're asked to announce, or the torrent is halted.
+async fn wait_to_announce(secs: u64, signals: &mut Signals) {
+    let sleep = time::sleep(Duration::from_secs(secs));
+    tokio::pin!(sleep);
+    tokio::select! {
+        _ = &mut sleep => {}
+        _ = signals.reannounce.notified() => {}
+        result = signals.trackers.changed() => {
+            // The handle is gone, so the trackers won't change again.
+            if result.is_err() {
+                sleep.await;
+            }
+        }
+        halted = async { signals.halted.wait_for(|&halted| halted).await.is_ok() } => {
+            if !halted {
+                sleep.await;
+            }
+        }
+    }
+}
+
+/// Announce to the torrent's trackers, returning the peers which aren't us. While the
+/// torrent is halted, trackers are told it has stopped, and the announce waits until
+/// it's let go.
+async fn announce(
+    announcer: &mut Announcer,
+    signals: &mut Signals,
+    ctx: &SessionContext,
+    shared: &Shared,
+) -> anyhow::Result<PeersInfo> {
+    let config = &shared.config;
+    let stats = &ctx.stats;
+    if *signals.halted.borrow() {
+        let left = ctx.work_queue.left();
+        let transfer = stats.transfer(left, false);
+        announcer
+            .announce_stopped(
+                &ctx.torrent.info_hash,
+                transfer,
+                &config.peer_id,
+                config.port,
+            )
+            .await;
+        stats.set_tracker_statuses(announcer.statuses());
+        signals.stopped.notify_waiters();
+        let _ = signals.halted.wait_for(|&halted| !halted).await;
+    }
+    shared.network_up().await;
+    let trackers = &mut signals.trackers;
+    if trackers.has_changed().unwrap_or(false) {
+        announcer.set_trackers(trackers.borrow_and_update().clone());
+    }
+    announcer.set_external_ip(shared.external_ip());
+    let left = ctx.work_queue.left();
+    let transfer = stats.transfer(left, left > 0 && ctx.work_queue.is_finished());
+    let result = announcer
+        .announce(&ctx.torrent, transfer, &config.peer_id, config.port)
+        .await;
+    stats.set_tracker_statuses(announcer.statuses());
+
+    let mut details = result?;
+    if let Some(ip) = details.external_ip {
+        shared.external_ip.vote(Voter::Tracker, ip);
+    }
+    stats.peers_discovered(PeerSource::Tracker, details.peers.len());
+    details.peers.retain(|p| !shared.is_own_addr(p.addr()));
+    if let Some(store) = shared.store.as_ref().filter(|_| !details.peers.is_empty()) {
+        let peers: Vec<_> = details.peers.iter().map(PeerData::addr).collect();
+        if let Err(e) = store.save_known_peers(&ctx.torrent.info_hash, &peers) {
+            warn!("Couldn't remember the tracker's peers: {}", e);
+        }
+    }
+
+    Ok(details)
+}
+
+/// Announce the torrent to the DHT every interval once the node has started, handing
+/// the peers it finds to `sessions`, until the torrent stops. Private torrents only
+/// get peers from their trackers.
+async fn announce_to_dht(ctx: SessionContext, shared: Arc<Shared>, sessions: Supervisor) {
+    if ctx.torrent.file.info.private == Some(1) {
+        return;
+    }
+    let config = &shared.config;
+    let info_hash = ctx.torrent.info_hash;
+    let mut stop = ctx.stop.clone();
+    let mut dht = shared.dht.subscribe();
+
+    let announce = async {
+        loop {
+            let node = match dht.wait_for(Option::is_some).await {
+                Ok(node) => node.clone().unwrap(),
+                Err(_) => return,
+            };
+            shared.network_up().await;
+
+            // Dial peers as the lookup finds them, rather than once it's done.
+            let found = |peers: Vec<SocketAddr>| {
+                // Peers are only dialed over IPv4 for now.
+                let peers = peers
+                    .into_iter()
+                    .filter_map(|addr| match addr {
+                        SocketAddr::V4(addr) => Some(PeerData::new(addr, PeerSource::Dht)),
+                        SocketAddr::V6(_) => None,
+                    })
+                    .filter(|p| !shared.is_own_addr(p.addr()) && !ctx.stats.has_peer(&p.addr()));
+                sessions.add_candidates(peers);
+            };
+            let interval = match node.announce_with(info_hash, config.port, found).await {
+                Ok(peers) => {
+                    ctx.stats.peers_discovered(PeerSource::Dht, peers.len());
+                    DHT_ANNOUNCE_INTERVAL
+                }
+                Err(e) => {
+                    debug!("Couldn't announce to the DHT: {}", e);
+                    DHT_RETRY_INTERVAL
+                }
+            };
+            time::sleep(interval).await;
+        }
+    };
+    tokio::select! {
+        _ = announce => {}
+        _ = stop.wait_for(|&stop| stop) => {}
+    }
+}
+
+/// Announce the torrent on the local network every interval once local service
+/// discovery has started, handing the peers which announce it to `sessions`, until the
+/// torrent stops. Private torrents only get peers from their trackers.
+async fn announce_to_lsd(ctx: SessionContext, shared: Arc<Shared>, sessions: Supervisor) {
+    if ctx.torrent.file.info.private == Some(1) {
+        return;
+    }
+    let info_hash = ctx.torrent.info_hash;
+    let mut stop = ctx.stop.clone();
+    let mut lsd = shared.lsd.subscribe();
+
+    let announce = async {
+        let lsd = match lsd.wait_for(Option::is_some).await {
+            Ok(lsd) => lsd.clone().unwrap(),
+            Err(_) => return,
+        };
+        let mut found = lsd.subscribe();
+        let mut interval = time::interval(LSD_ANNOUNCE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    shared.network_up().await;
+                    if let Err(e) = lsd.announce(&info_hash).await {
+                        debug!("Couldn't announce on the local network: {}", e);
+                    }
+                }
+                found = found.recv() => match found {
+                    Ok(found) if found.info_hashes.contains(&info_hash) => {
+                        let peer = PeerData::new(found.peer, PeerSource::Lsd);
+                        if !shared.is_own_addr(peer.addr()) && !ctx.stats.has_peer(&peer.addr()) {
+                            ctx.stats.peers_discovered(PeerSource::Lsd, 1);
+                            sessions.add_candidates([peer]);
+                        }
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            }
+        }
+    };
+    tokio::select! {
+        _ = announce => {}
+        _ = stop.wait_for(|&stop| stop) => {}
+    }
+}
+
+/// Until the torrent stops, now and then drop the slowest peer to make room for one
+/// we haven't tried, while we have as many sessions as we want and peers waiting.
+async fn rotate_slow_peers(ctx: SessionContext, sessions: Supervisor) {
+    let mut stop = ctx.stop.clone();
+    let start = time::Instant::now() + ROTATE_INTERVAL;
+    let mut interval = time::interval_at(start, ROTATE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stop.wait_for(|&stop| stop) => return,
+        }
+        if !sessions.is_full() || sessions.candidates() == 0 {
+            continue;
+        }
+        if let Some(addr) = ctx.stats.slowest_peer(ROTATE_MIN_AGE) {
+            debug!("Dropping {}, the slowest peer, to try another", addr);
+            sessions.back_off(addr, ROTATE_BACKOFF);
+        }
+    }
+}
+
+/// How we came to be talking to a peer.
+enum Connection {
+    Dial(PeerData),
+    Accepted(Incoming),
+}
+
+/// Connect to a peer and exchange pieces with it until one of us is done.
+async fn run_session(
+    connection: Connection,
+    ctx: SessionContext,
+    shared: Arc<Shared>,
+) -> anyhow::Result<()> {
+    let stats = Arc::clone(&ctx.stats);
+    let (addr, source) = match &connection {
+        Connection::Dial(peer_data) => (peer_data.addr(), peer_data.source()),
+        Connection::Accepted(incoming) => (incoming.addr.into(), PeerSource::Incoming),
+    };
+    if shared.is_blocked(addr.ip()) {
+        debug!("{} is blocked by the IP filter", addr);
+        stats.peer_blocked(source);
+        return Ok(());
+    }
+
+    let _connection = ctx.connections.acquire(1).await;
+    let peer_stats = stats.add_peer(addr, source);
+    let given = Arc::clone(&peer_stats);
+    let _peer = ConnectedPeer {
+        stats: &stats,
+        addr,
+    };
+    let result = async {
+        let mut session = match connection {
+            Connection::Dial(peer_data) => {
+                shared.network_up().await;
+                let half_open = shared.half_open.acquire().await?;
+                let session = async {
+                    PeerSession::new(peer_data, peer_stats, ctx)
+                        .await?
+                        .connect()
+                        .await
+                }
+                .await;
+                shared.peer_history.dialed(addr.ip(), session.is_ok());
+                drop(half_open);
+                session?
+            }
+            Connection::Accepted(incoming) => {
+                let peer_data = PeerData::new(incoming.addr, PeerSource::Incoming);
+                PeerSession::accept(
+                    peer_data,
+                    incoming.stream,
+                    incoming.handshake,
+                    peer_stats,
+                    ctx,
+                )
+                .await?
+            }
+        };
+        stats.peer_connected(source);
+        session.start_download().await?;
+
+        Ok(()) as anyhow::Result<()>
+    }
+    .await;
+    let given = given.downloaded.load(Ordering::Relaxed);
+    if given > 0 {
+        shared.peer_history.record(addr.ip(), given);
+    }
+
+    match result {
+        Err(e) if e.is::<SelfConnection>() => {
+            debug!("{} is our own address, dropping it", addr);
+            shared.own_addrs.lock().unwrap().insert(addr);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Removes a peer from the torrent's stats when its session ends, including when the
+/// session is aborted.
+struct ConnectedPeer<'a> {
+    stats: &'a TorrentStats,
+    addr: SocketAddr,
+}
+
+impl Drop for ConnectedPeer<'_> {
+    fn drop(&mut self) {
+        self.stats.remove_peer(&self.addr);
+    }
+}
+
+/// Start sessions with peers which connect to us until the torrent stops, returning the
+/// connections which haven't been accepted yet.
+async fn accept_peers(
+    mut incoming_rx: Receiver<Incoming>,
+    ctx: SessionContext,
+    shared: Arc<Shared>,
+    sessions: Supervisor,
+) -> Receiver<Incoming> {
+    let mut stop = ctx.stop.clone();
+    loop {
+        let incoming = tokio::select! {
+            incoming = incoming_rx.recv() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = stop.wait_for(|&stop| stop) => break,
+        };
+        let addr = incoming.addr.into();
+        let session = run_session(
+            Connection::Accepted(incoming),
+            ctx.clone(),
+            Arc::clone(&shared),
+        );
+        sessions.spawn(addr, session);
+    }
+
+    incoming_rx
+}
+
+#[tracing::instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
+async fn save_results(
+    mut save_rx: Receiver<WorkResult>,
+    hook_ctx: HookContext,
+    work_queue: WorkQueue,
+    rechecked: Arc<Notify>,
+    stats: Arc<TorrentStats>,
+    storage: Arc<Storage>,
+    disk: DiskQueue,
+    shared: Arc<Shared>,
+) -> anyhow::Result<()> {
+    let config = &shared.config;
+    let piece_count = stats.piece_count;
+    let mut in_order = match (config.output, config.in_order) {
+        (_, Some(max_buffered)) => Some(InOrder::new(max_buffered)),
+        (Output::Stdout, None) => Some(InOrder::new(DEFAULT_REORDER_BUFFER)),
+        (Output::Files | Output::Discard, None) => None,
+    };
+    let mut stdout = tokio::io::stdout();
+    let mut total_bytes = 0;
+    // Pieces received, so a piece delivered twice isn't saved or counted again.
+    let mut received = vec![0; piece_count.div_ceil(8)];
+    let mut selection = work_queue.selection_changes();
+    let mut unsaved = Unsaved::new(&work_queue, &storage);
+    // Empty files are in no piece, so they're done as soon as they're made.
+    if config.output == Output::Files {
+        for index in storage.create_empty_files().await? {
+            file_completed(
+                &shared,
+                &hook_ctx,
+                index,
+                storage.files()[index].path.clone(),
+            );
+        }
+    }
+    // Pieces which have been verified may still be on their way to us, so we're done
+    // once every piece we want is saved, however many pieces we've been sent.
+    while !unsaved.is_empty() {
+        let result = tokio::select! {
+            result = save_rx.recv() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            // A recheck may have found the pieces we were waiting for on disk, or found
+            // pieces we saved corrupt, which are saved again once downloaded again.
+            _ = rechecked.notified() => {
+                for idx in (0..piece_count).filter(|&idx| !work_queue.has_piece(idx)) {
+                    received.unset_piece(idx);
+                }
+                unsaved = Unsaved::new(&work_queue, &storage);
+                continue;
+            }
+            // Or the pieces we were waiting for may no longer be wanted.
+            _ = selection.changed() => {
+                unsaved = Unsaved::new(&work_queue, &storage);
+                continue;
+            }
+        };
+        if received.has_piece(result.idx) {
+            debug!("Piece {} was already saved", result.idx);
+            continue;
+        }
+        received.set_piece(result.idx);
+        let downloaded_count = stats.piece_done();
+        total_bytes += result.bytes.len();
+        info!(
+            "downloaded piece {} of {}: {} total bytes",
+            downloaded_count, piece_count, total_bytes
+        );
+
+        let ready = match &mut in_order {
+            Some(in_order) => in_order.push(result)?,
+            None => vec![result],
+        };
+        for result in ready {
+            unsaved.saved(result.idx);
+            match config.output {
+                Output::Files => {
+                    let turn = disk.turn().await;
+                    let written = storage.write_piece(result.idx, &result.bytes).await?;
+                    drop(turn);
+                    if !written {
+                        continue;
+                    }
+                    for index in storage.files_completed_by(result.idx) {
+                        let path = storage.files()[index].path.clone();
+                        file_completed(&shared, &hook_ctx, index, path);
+                    }
+                }
+                Output::Stdout => {
+                    stdout.write_all(&result.bytes).await?;
+                    storage.mark_saved(result.idx);
+                }
+                Output::Discard => storage.mark_saved(result.idx),
+            }
+        }
+    }
+    stdout.flush().await?;
+    // Pieces of merkle torrents can only be checked one by one later with the hashes
+    // peers sent to prove them.
+    if let (Output::Files, Some(dir)) = (config.output, &config.state_dir) {
+        let tree_hashes = work_queue.known_hashes();
+        if !tree_hashes.is_empty() {
+            HashCache::new(dir.join("verified"))
+                .store_tree(&hook_ctx.info_hash, &tree_hashes)
+                .await?;
+        }
+    }
+    if !unsaved.is_empty() {
+        return Err(anyhow!(
+            "Stopped receiving pieces before the download was complete"
+        ));
+    }
+    info!("Download complete!");
+
+    Ok(())
+}
+
+/// Tell whoever's watching that one of a torrent's files has been saved in full, and
+/// run the hook for it without holding up saving.
+fn file_completed(shared: &Arc<Shared>, ctx: &HookContext, index: usize, path: PathBuf) {
+    info!("Finished {}", path.display());
+    let _ = shared.file_completions.send(FileCompleted {
+        info_hash: ctx.info_hash,
+        index,
+        path: path.clone(),
+    });
+    if shared
+        .config
+        .hooks
+        .command(HookEvent::FileCompleted)
+        .is_some()
+    {
+        let shared = Arc::clone(shared);
+        let ctx = HookContext {
+            file: Some((index, path)),
+            ..ctx.clone()
+        };
+        tokio::spawn(async move {
+            run_hook(&shared.config.hooks, HookEvent::FileCompleted, &ctx).await;
+        });
+    }
+}
+
+/// The pieces we want which aren't saved, going by the storage's bitfield rather than
+/// counting the pieces saved, which pieces sent twice or found on disk would throw off.
+/// Kept up to date as pieces are saved, so checking whether we're done doesn't mean
+/// going through every piece.
+struct Unsaved {
+    pieces: Vec<u8>,
+    count: usize,
+}
+
+impl Unsaved {
+    fn new(work_queue: &WorkQueue, storage: &Storage) -> Self {
+        let saved = storage.saved();
+        let pieces: Vec<u8> = work_queue
+            .wanted()
+            .iter()
+            .zip(&saved)
+            .map(|(wanted, saved)| wanted & !saved)
+            .collect();
+        let count = pieces.iter().map(|byte| byte.count_ones() as usize).sum();
+        Self { pieces, count }
+    }
+
+    /// Note that piece `idx` has been saved, or is about to be.
+    fn saved(&mut self, idx: usize) {
+        if self.pieces.has_piece(idx) {
+            self.pieces.unset_piece(idx);
+            self.count -= 1;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recognise_own_addresses() {
+        let config = ClientConfig {
+            external_ip: Some(Ipv4Addr::new(203, 0, 113, 7)),
+            ..ClientConfig::new(".")
+        };
+
+        assert!(config.is_own_addr("127.0.0.1:6881".parse().unwrap()));
+        assert!(config.is_own_addr("203.0.113.7:6881".parse().unwrap()));
+        assert!(!config.is_own_addr("203.0.113.7:6882".parse().unwrap()));
+        assert!(!config.is_own_addr("198.51.100.1:6881".parse().unwrap()));
+    }
+
+    #[test]
+    fn save_torrents_by_category() {
+        let config = ClientConfig {
+            category_paths: [("tv".to_owned(), PathBuf::from("/media/tv"))].into(),
+            ..ClientConfig::new("/downloads")
+        };
+        let in_category = |category: &str| AddTorrentOptions {
+            category: Some(category.to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            config.save_path_for(&in_category("tv")),
+            Path::new("/media/tv")
+        );
+        assert_eq!(
+            config.save_path_for(&in_category("films")),
+            Path::new("/downloads")
+        );
+        let own_path = AddTorrentOptions {
+            save_path: Some("/elsewhere".into()),
+            ..in_category("tv")
+        };
+        assert_eq!(config.save_path_for(&own_path), Path::new("/elsewhere"));
+    }
+
+    #[test]
+    fn complete_once_every_wanted_piece_is_saved() {
+        let torrent = crate::testing::torrent("complete", &[0; 256], 64);
+        let work_queue = torrent.work_queue(PickerKind::Sequential.build()).unwrap();
+        let storage = Storage::new(&torrent, &std::env::temp_dir()).unwrap();
+        work_queue.set_priority(3, Priority::Skip);
+
+        storage.mark_saved(0);
+        let mut unsaved = Unsaved::new(&work_queue, &storage);
+        assert_eq!(unsaved.count, 2);
+        for idx in [1, 1, 3] {
+            unsaved.saved(idx);
+        }
+        assert!(!unsaved.is_empty());
+        unsaved.saved(2);
+        assert!(unsaved.is_empty());
+        storage.forget(0);
+        assert!(!Unsaved::new(&work_queue, &storage).is_empty());
+    }
+
+    #[tokio::test]
+    async fn queue_torrents_past_the_active_limit() {
+        let root = std::env::temp_dir().join(format!("queue-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let client = Client::new(ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            max_active_seeds: Some(1),
+            ..ClientConfig::new(&root)
+        });
+        let mut handles = Vec::new();
+        for name in ["queue-first", "queue-second"] {
+            let content = vec![7; 100];
+            std::fs::write(root.join(name), &content).unwrap();
+            let torrent = crate::testing::torrent(name, &content, 64);
+            handles.push(client.seed(torrent, &root).await.unwrap());
+        }
+        let [first, second] = &handles[..] else {
+            unreachable!()
+        };
+        assert_eq!(first.state(), TorrentState::Seeding);
+        assert_eq!(second.state(), TorrentState::Queued);
+
+        // Pausing the first torrent frees its slot, and force starting it again doesn't
+        // take the slot back.
+        first.pause().unwrap();
+        assert_eq!(second.state(), TorrentState::Seeding);
+        first.set_force_start(true).unwrap();
+        assert_eq!(first.state(), TorrentState::Seeding);
+        assert_eq!(second.state(), TorrentState::Seeding);
+
+        first.set_force_start(false).unwrap();
+        assert_eq!(second.state(), TorrentState::Queued);
+        client.remove(&first.info_hash()).unwrap();
+        assert_eq!(second.state(), TorrentState::Seeding);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn remove_a_torrent_and_its_data() {
+        let root = std::env::temp_dir().join(format!("remove-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let client = Client::new(ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(&root)
+        });
+        let mut handles = Vec::new();
+        for name in ["remove-kept", "remove-deleted"] {
+            let content = vec![7; 100];
+            std::fs::write(root.join(name), &content).unwrap();
+            let torrent = crate::testing::torrent(name, &content, 64);
+            handles.push(client.seed(torrent, &root).await.unwrap());
+        }
+
+        // With no trackers to tell the torrent has stopped, it's removed at once.
+        let started = std::time::Instant::now();
+        client
+            .remove_torrent(&handles[0].info_hash(), false)
+            .await
+            .unwrap();
+        client
+            .remove_torrent(&handles[1].info_hash(), true)
+            .await
+            .unwrap();
+        assert!(started.elapsed() < STOPPED_ANNOUNCE_WAIT);
+        assert!(client.torrents().is_empty());
+        assert!(root.join("remove-kept").exists());
+        assert!(!root.join("remove-deleted").exists());
+        assert!(client
+            .remove_torrent(&handles[0].info_hash(), false)
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn remove_a_torrent_while_its_pieces_are_written() {
+        let root = std::env::temp_dir().join(format!("remove-busy-test-{}", std::process::id()));
+        let (seed_dir, download_dir) = (root.join("seed"), root.join("download"));
+        std::fs::create_dir_all(&seed_dir).unwrap();
+        let content: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i / 7) as u8).collect();
+        std::fs::write(seed_dir.join("busy"), &content).unwrap();
+        let torrent = || crate::testing::torrent("busy", &content, crate::picker::BLOCK_SIZE);
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let seeder = Client::new(ClientConfig {
+            port,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(&seed_dir)
+        });
+        seeder.seed(torrent(), &seed_dir).await.unwrap();
+        let listener = seeder.clone();
+        tokio::spawn(async move { listener.listen().await });
+
+        // The downloader finds the seeder among the peers it remembers.
+        let store = SessionStore::open_in_memory().unwrap();
+        let info_hash = torrent().info_hash;
+        store
+            .save_known_peers(&info_hash, &[SocketAddr::from(([127, 0, 0, 1], port))])
+            .unwrap();
+        let client = Client::with_session_store(
+            ClientConfig {
+                port: 0,
+                dht: false,
+                lsd: false,
+                ..ClientConfig::new(&download_dir)
+            },
+            store,
+        );
+        let handle = client.add_torrent(torrent()).await.unwrap();
+        let deadline = time::Instant::now() + Duration::from_secs(10);
+        while handle.status().pieces_done == 0 {
+            assert!(time::Instant::now() < deadline, "No pieces were downloaded");
+            time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // Pieces still on their way to the disk are dropped rather than written after
+        // the file is deleted.
+        client.remove_torrent(&info_hash, true).await.unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!download_dir.join("busy").exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hold_torrents_while_conditions_call_for_it() {
+        let root = std::env::temp_dir().join(format!("watchdog-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let client = Client::new(ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(&root)
+        });
+        let content = vec![7; 100];
+        std::fs::write(root.join("held"), &content).unwrap();
+        let torrent = crate::testing::torrent("held", &content, 64);
+        let handle = client.seed(torrent, &root).await.unwrap();
+        let mut events = client.watchdog_events();
+
+        // Seeds only read from disk, so a full one doesn't hold them back.
+        let low_disk = Conditions {
+            low_disks: [root.clone()].into(),
+            metered: false,
+        };
+        let event = WatchdogEvent::DiskLow {
+            path: root.clone(),
+            free: 0,
+        };
+        client.set_conditions(low_disk, vec![event.clone()]);
+        assert_eq!(events.recv().await.unwrap(), event);
+        assert!(!handle.is_held());
+
+        let metered = Conditions {
+            metered: true,
+            ..Default::default()
+        };
+        client.set_conditions(metered, vec![WatchdogEvent::Metered]);
+        assert!(handle.is_held());
+        assert!(*handle.inner.halted.borrow());
+
+        client.set_conditions(Conditions::default(), vec![WatchdogEvent::Unmetered]);
+        assert!(!*handle.inner.halted.borrow());
+        assert_eq!(events.recv().await.unwrap(), WatchdogEvent::Metered);
+        assert_eq!(events.recv().await.unwrap(), WatchdogEvent::Unmetered);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_every_torrent() {
+        let root = std::env::temp_dir().join(format!("pause-all-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let client = Client::new(ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(&root)
+        });
+        for name in ["first", "second"] {
+            let content = name.as_bytes().repeat(20);
+            std::fs::write(root.join(name), &content).unwrap();
+            let torrent = crate::testing::torrent(name, &content, 64);
+            client.seed(torrent, &root).await.unwrap();
+        }
+
+        client.pause_all().unwrap();
+        assert!(client.torrents().iter().all(|t| t.is_paused()));
+        client.resume_all().unwrap();
+        assert!(client.torrents().iter().all(|t| !t.is_paused()));
+
+        assert!(!client.alt_speed());
+        client.set_alt_speed(true);
+        assert!(client.alt_speed());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rename_and_move_a_seeding_torrent() {
+        let root = std::env::temp_dir().join(format!("move-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let client = Client::new(ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(&root)
+        });
+        let content = vec![7; 100];
+        std::fs::write(root.join("moving"), &content).unwrap();
+        let torrent = crate::testing::torrent("moving", &content, 64);
+        let handle = client.seed(torrent, &root).await.unwrap();
+
+        handle.set_name(Some("Renamed".into())).unwrap();
+        assert_eq!(handle.name(), "Renamed");
+        assert_eq!(handle.status().name, "Renamed");
+        handle.set_name(None).unwrap();
+        assert_eq!(handle.name(), "moving");
+
+        let moved = root.join("moved");
+        handle.move_storage(&moved).await.unwrap();
+        assert_eq!(handle.save_path(), moved);
+        assert_eq!(handle.state(), TorrentState::Seeding);
+        assert!(!root.join("moving").exists());
+        assert_eq!(std::fs::read(moved.join("moving")).unwrap(), content);
+        assert_eq!(
+            handle.inner.storage.read_piece(1).await.unwrap(),
+            vec![7; 36]
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finished_torrents_cant_want_missing_pieces() {
+        let root = std::env::temp_dir().join(format!("priority-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let client = Client::new(ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(&root)
+        });
+        let content = vec![7; 100];
+        std::fs::write(root.join("finished"), &content).unwrap();
+        let torrent = crate::testing::torrent("finished", &content, 64);
+        let handle = client.seed(torrent, &root).await.unwrap();
+        handle.inner.work_queue.mark_missing(1);
+
+        assert!(handle.set_file_priorities(vec![Priority::High]).is_err());
+        assert_eq!(handle.inner.work_queue.selection().wanted, 2);
+        let selection = handle.set_file_priorities(vec![Priority::Skip]).unwrap();
+        assert_eq!(selection.wanted, 0);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
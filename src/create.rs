@@ -0,0 +1,686 @@
+//! Making torrents of content on disk, as v1 torrents, v2 torrents (BEP 52), or hybrid
+//! torrents which v1 and v2 clients can both download.
+
+use crate::piece_hash::{small_file_root, MerklePieces};
+use crate::torrent_file::{File, FileTree, FileTreeEntry, Info, TorrentFile};
+use crate::Torrent;
+use anyhow::anyhow;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+pub const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+/// Picked piece lengths are the smallest giving at most this many pieces, so there are
+/// between 1000 and 2000 unless the content is very small or very large.
+const MAX_PIECES: u64 = 2000;
+
+/// Which metadata a torrent is made with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetaVersion {
+    /// SHA-1 hashes of each piece, which every client understands.
+    #[default]
+    V1,
+    /// A SHA-256 merkle tree over each file (BEP 52).
+    V2,
+    /// Both, with padding files aligning each file to a piece boundary as v2 needs.
+    Hybrid,
+    /// The root of a SHA-1 hash tree over the pieces instead of each piece's hash
+    /// (BEP 30), so the torrent stays small however many pieces there are.
+    Merkle,
+}
+
+impl FromStr for MetaVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Self::V1),
+            "v2" => Ok(Self::V2),
+            "hybrid" => Ok(Self::Hybrid),
+            "merkle" => Ok(Self::Merkle),
+            _ => Err(anyhow!("Unknown torrent version: {}", s)),
+        }
+    }
+}
+
+/// The smallest power of two piece length which splits `total_length` bytes into at
+/// most [`MAX_PIECES`] pieces, within the lengths clients accept.
+pub fn pick_piece_length(total_length: u64) -> u64 {
+    let mut length = MIN_PIECE_LENGTH;
+    while length < MAX_PIECE_LENGTH && total_length.div_ceil(length) > MAX_PIECES {
+        length *= 2;
+    }
+    length
+}
+
+/// Check a piece length is a power of two from 16 KiB to 16 MiB.
+pub fn check_piece_length(length: u64) -> anyhow::Result<()> {
+    if !length.is_power_of_two() {
+        return Err(anyhow!("Piece length {} isn't a power of two", length));
+    }
+    if !(MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&length) {
+        return Err(anyhow!(
+            "Piece length {} isn't between {} and {}",
+            length,
+            MIN_PIECE_LENGTH,
+            MAX_PIECE_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+/// Makes a torrent of a file, or of a directory and everything in it. One thread reads
+/// the content ahead while the others hash it.
+#[derive(Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    name: Option<String>,
+    piece_length: Option<u64>,
+    trackers: Vec<Vec<String>>,
+    comment: Option<String>,
+    private: bool,
+    version: MetaVersion,
+    threads: usize,
+    progress: Option<Arc<dyn Fn(CreateProgress) + Send + Sync>>,
+}
+
+impl std::fmt::Debug for TorrentBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TorrentBuilder")
+            .field("path", &self.path)
+            .field("name", &self.name)
+            .field("piece_length", &self.piece_length)
+            .field("version", &self.version)
+            .field("threads", &self.threads)
+            .finish()
+    }
+}
+
+/// How much of the content a [`TorrentBuilder`] has hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CreateProgress {
+    pub bytes_hashed: u64,
+    pub total_length: u64,
+}
+
+impl CreateProgress {
+    pub fn percent(&self) -> f64 {
+        match self.total_length {
+            0 => 100.0,
+            total => self.bytes_hashed as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+/// A file going in the torrent.
+#[derive(Debug)]
+struct Source {
+    path: PathBuf,
+    /// Its path within the torrent, which single-file torrents don't have.
+    components: Vec<String>,
+    length: u64,
+}
+
+impl TorrentBuilder {
+    /// A v1 torrent of `path`, named after it, with a piece length picked for its size.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            name: None,
+            piece_length: None,
+            trackers: Vec::new(),
+            comment: None,
+            private: false,
+            version: MetaVersion::default(),
+            threads: std::thread::available_parallelism().map_or(1, usize::from),
+            progress: None,
+        }
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Use pieces of `length` bytes, which [`TorrentBuilder::build`] checks with
+    /// [`check_piece_length`].
+    pub fn with_piece_length(mut self, length: u64) -> Self {
+        self.piece_length = Some(length);
+        self
+    }
+
+    /// Announce to tiers of trackers.
+    pub fn with_trackers(mut self, trackers: Vec<Vec<String>>) -> Self {
+        self.trackers = trackers;
+        self
+    }
+
+    pub fn with_comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Mark the torrent private (BEP 27), so clients only find peers from its trackers.
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn with_version(mut self, version: MetaVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Hash on `threads` threads, rather than one for each CPU.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Call `progress` as pieces are hashed, from the thread calling
+    /// [`TorrentBuilder::build`].
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(CreateProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Hash the content and make the torrent.
+    pub fn build(&self) -> anyhow::Result<Torrent> {
+        let sources = sources(&self.path)?;
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .ok_or_else(|| anyhow!("Can't name a torrent of {}", self.path.display()))?,
+        };
+        let total_length = sources.iter().map(|s| s.length).sum();
+        let piece_length = match self.piece_length {
+            Some(length) => {
+                check_piece_length(length)?;
+                length
+            }
+            None => pick_piece_length(total_length),
+        };
+        let v1 = self.version != MetaVersion::V2;
+        let v2 = matches!(self.version, MetaVersion::V2 | MetaVersion::Hybrid);
+        let single_file = sources.len() == 1 && sources[0].components.is_empty();
+
+        let hashes = self.hash(&sources, piece_length, v1, v2)?;
+
+        let mut files = Vec::new();
+        let mut file_tree = BTreeMap::new();
+        let mut piece_layers = BTreeMap::new();
+        for (i, (source, layer)) in sources.iter().zip(hashes.layers).enumerate() {
+            if v1 && !single_file {
+                files.push(File {
+                    path: source.components.clone(),
+                    length: source.length as i64,
+                    md5sum: None,
+                    attr: None,
+                });
+                let padding = padding(source.length, piece_length);
+                if v2 && padding > 0 && i + 1 < sources.len() {
+                    files.push(File {
+                        path: vec![String::from(".pad"), padding.to_string()],
+                        length: padding as i64,
+                        md5sum: None,
+                        attr: Some(String::from("p")),
+                    });
+                }
+            }
+            if v2 {
+                let pieces_root = match layer.len() {
+                    0 => None,
+                    1 if source.length <= piece_length => Some(layer[0]),
+                    _ => {
+                        let root =
+                            MerklePieces::new(piece_length as usize, layer.clone()).pieces_root();
+                        let layer = ByteBuf::from(layer.concat());
+                        piece_layers.insert(ByteBuf::from(root.to_vec()), layer);
+                        Some(root)
+                    }
+                };
+                let entry = FileTreeEntry {
+                    length: source.length as i64,
+                    pieces_root: pieces_root.map(|root| ByteBuf::from(root.to_vec())),
+                };
+                let path = match single_file {
+                    true => std::slice::from_ref(&name),
+                    false => &source.components[..],
+                };
+                insert_file(&mut file_tree, path, entry);
+            }
+        }
+
+        let mut info = Info {
+            name,
+            pieces: ByteBuf::from(hashes.pieces.concat()),
+            piece_length: piece_length as i64,
+            md5sum: None,
+            length: single_file.then_some(total_length as i64),
+            files: (!single_file && v1).then_some(files),
+            private: self.private.then_some(1),
+            path: None,
+            root_hash: None,
+            meta_version: v2.then_some(2),
+            file_tree: v2.then_some(file_tree),
+        };
+        if self.version == MetaVersion::Merkle {
+            info.use_merkle_root();
+        }
+        let mut torrent: Torrent = TorrentFile {
+            info,
+            announce: None,
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            announce_list: None,
+            creation_date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs() as i64),
+            comment: self.comment.clone(),
+            created_by: Some(format!("torrent {}", env!("CARGO_PKG_VERSION"))),
+            piece_layers: (v2 && !piece_layers.is_empty()).then_some(piece_layers),
+        }
+        .into();
+        torrent.set_trackers(self.trackers.clone());
+
+        Ok(torrent)
+    }
+}
+
+/// Hashes of the content, as the torrent's metadata has them.
+struct Hashes {
+    /// SHA-1 hashes of the v1 pieces.
+    pieces: Vec<[u8; 20]>,
+    /// Each file's v2 piece layer, or for files no larger than a piece, its pieces root.
+    layers: Vec<Vec<[u8; 32]>>,
+}
+
+/// A piece read from disk, to be hashed.
+struct Job {
+    /// Index of the v1 piece, for v1 and hybrid torrents.
+    piece: Option<usize>,
+    /// Which file the data's from, which piece of it, and how many bytes of the data are
+    /// the file's rather than padding, for v2 and hybrid torrents.
+    file: Option<(usize, usize, usize)>,
+    data: Vec<u8>,
+}
+
+struct Hashed {
+    piece: Option<(usize, [u8; 20])>,
+    file: Option<(usize, usize, [u8; 32])>,
+    bytes: u64,
+}
+
+impl TorrentBuilder {
+    /// Read the content on one thread, and hash it on the others.
+    fn hash(
+        &self,
+        sources: &[Source],
+        piece_length: u64,
+        v1: bool,
+        v2: bool,
+    ) -> anyhow::Result<Hashes> {
+        let total_length = sources.iter().map(|s| s.length).sum();
+        let (job_tx, job_rx) = mpsc::sync_channel::<Job>(self.threads);
+        let job_rx = Mutex::new(job_rx);
+        let (hashed_tx, hashed_rx) = mpsc::channel::<Hashed>();
+        let merkle = MerklePieces::from_root([0; 32], piece_length as usize, 0);
+
+        std::thread::scope(|scope| {
+            let reader = scope.spawn(|| read_pieces(sources, piece_length, v1, v2, job_tx));
+            for _ in 0..self.threads {
+                let (job_rx, hashed_tx, merkle) = (&job_rx, hashed_tx.clone(), &merkle);
+                scope.spawn(move || loop {
+                    let job = match job_rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let file = job.file.map(|(file, idx, len)| {
+                        let data = &job.data[..len];
+                        let hash = match sources[file].length <= piece_length {
+                            true => small_file_root(data),
+                            false => merkle.piece_root(data),
+                        };
+                        (file, idx, hash)
+                    });
+                    let hashed = Hashed {
+                        piece: job.piece.map(|idx| (idx, Sha1::digest(&job.data).into())),
+                        bytes: job.file.map_or(job.data.len(), |(_, _, len)| len) as u64,
+                        file,
+                    };
+                    if hashed_tx.send(hashed).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(hashed_tx);
+
+            let mut pieces = BTreeMap::new();
+            let mut layers = vec![BTreeMap::new(); sources.len()];
+            let mut bytes_hashed = 0;
+            for hashed in hashed_rx {
+                if let Some((idx, hash)) = hashed.piece {
+                    pieces.insert(idx, hash);
+                }
+                if let Some((file, idx, hash)) = hashed.file {
+                    layers[file].insert(idx, hash);
+                }
+                bytes_hashed += hashed.bytes;
+                if let Some(progress) = &self.progress {
+                    progress(CreateProgress {
+                        bytes_hashed,
+                        total_length,
+                    });
+                }
+            }
+            reader.join().unwrap()?;
+
+            Ok(Hashes {
+                pieces: pieces.into_values().collect(),
+                layers: layers
+                    .into_iter()
+                    .map(|layer| layer.into_values().collect())
+                    .collect(),
+            })
+        })
+    }
+}
+
+/// Read the content into pieces to hash. v1 pieces run on from one file into the next,
+/// while v2 pieces start at the start of each file, with hybrid torrents' v1 pieces
+/// padded out to the end of the last piece of each file.
+fn read_pieces(
+    sources: &[Source],
+    piece_length: u64,
+    v1: bool,
+    v2: bool,
+    jobs: mpsc::SyncSender<Job>,
+) -> anyhow::Result<()> {
+    let piece_length = piece_length as usize;
+    let mut piece = 0;
+    let mut buf = Vec::with_capacity(piece_length);
+    for (i, source) in sources.iter().enumerate() {
+        let mut file = std::fs::File::open(&source.path)?;
+        let mut read = 0;
+        let mut chunk = 0;
+        loop {
+            let start = buf.len();
+            buf.resize(piece_length, 0);
+            let n = read_full(&mut file, &mut buf[start..])?;
+            buf.truncate(start + n);
+            read += n as u64;
+            let end_of_file = buf.len() < piece_length;
+            if v2 && !buf.is_empty() {
+                let len = buf.len();
+                let last_file = i + 1 == sources.len();
+                if v1 && !last_file {
+                    buf.resize(piece_length, 0);
+                }
+                let job = Job {
+                    piece: v1.then_some(piece),
+                    file: Some((i, chunk, len)),
+                    data: std::mem::replace(&mut buf, Vec::with_capacity(piece_length)),
+                };
+                piece += 1;
+                chunk += 1;
+                if jobs.send(job).is_err() {
+                    break;
+                }
+            } else if !v2 && buf.len() == piece_length {
+                let job = Job {
+                    piece: Some(piece),
+                    file: None,
+                    data: std::mem::replace(&mut buf, Vec::with_capacity(piece_length)),
+                };
+                piece += 1;
+                if jobs.send(job).is_err() {
+                    break;
+                }
+            }
+            if end_of_file {
+                break;
+            }
+        }
+        if read != source.length {
+            return Err(anyhow!(
+                "{} changed while hashing it",
+                source.path.display()
+            ));
+        }
+    }
+    // The last v1 piece can be short.
+    if !buf.is_empty() {
+        let _ = jobs.send(Job {
+            piece: Some(piece),
+            file: None,
+            data: buf,
+        });
+    }
+
+    Ok(())
+}
+
+/// Bytes of padding which take a hybrid torrent's file to the end of its last piece.
+fn padding(length: u64, piece_length: u64) -> u64 {
+    (piece_length - length % piece_length) % piece_length
+}
+
+/// The files under `path`, in the order they go in the torrent.
+fn sources(path: &Path) -> anyhow::Result<Vec<Source>> {
+    let metadata = std::fs::metadata(path)?;
+    if metadata.is_file() {
+        return Ok(vec![Source {
+            path: path.to_path_buf(),
+            components: Vec::new(),
+            length: metadata.len(),
+        }]);
+    }
+
+    let mut sources = Vec::new();
+    let mut dirs = vec![(path.to_path_buf(), Vec::new())];
+    while let Some((dir, components)) = dirs.pop() {
+        let mut entries = std::fs::read_dir(&dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let mut components = components.clone();
+            components.push(entry.file_name().to_string_lossy().into_owned());
+            let metadata = std::fs::metadata(entry.path())?;
+            if metadata.is_dir() {
+                dirs.push((entry.path(), components));
+            } else {
+                sources.push(Source {
+                    path: entry.path(),
+                    components,
+                    length: metadata.len(),
+                });
+            }
+        }
+    }
+    if sources.is_empty() {
+        return Err(anyhow!("{} has no files in it", path.display()));
+    }
+    sources.sort_by(|a, b| a.components.cmp(&b.components));
+
+    Ok(sources)
+}
+
+/// Fill `buf` from `reader`, short only at the end.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn insert_file(tree: &mut BTreeMap<String, FileTree>, path: &[String], entry: FileTreeEntry) {
+    match path {
+        [] => {}
+        [name] => {
+            let file = BTreeMap::from([(String::new(), entry)]);
+            tree.insert(name.clone(), FileTree::File(file));
+        }
+        [dir, rest @ ..] => {
+            let node = tree
+                .entry(dir.clone())
+                .or_insert_with(|| FileTree::Directory(BTreeMap::new()));
+            if let FileTree::Directory(children) = node {
+                insert_file(children, rest, entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::piece_hash::{MerkleTree, PieceVerifier};
+
+    #[test]
+    fn pick_and_check_piece_lengths() {
+        assert_eq!(pick_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(pick_piece_length(1 << 30), 1 << 20);
+        assert_eq!(pick_piece_length(1 << 50), MAX_PIECE_LENGTH);
+        for total in [1u64 << 25, 3 << 30, 20 << 30] {
+            let pieces = total.div_ceil(pick_piece_length(total));
+            assert!((1000..=2000).contains(&pieces), "{} pieces", pieces);
+        }
+
+        assert!(check_piece_length(256 * 1024).is_ok());
+        assert!(check_piece_length(1000 * 1024).is_err());
+        assert!(check_piece_length(8 * 1024).is_err());
+        assert!(check_piece_length(32 * 1024 * 1024).is_err());
+    }
+
+    #[tokio::test]
+    async fn build_v1_and_hybrid_torrents() {
+        let root = std::env::temp_dir().join(format!("create-test-{}", std::process::id()));
+        let dir = root.join("content");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("sub").join("big"), &big).unwrap();
+        std::fs::write(dir.join("small"), b"hello world").unwrap();
+        std::fs::write(dir.join("empty"), b"").unwrap();
+
+        let last = Arc::new(Mutex::new(None));
+        let torrent = TorrentBuilder::new(&dir)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_trackers(vec![vec![String::from("http://tracker.example/announce")]])
+            .with_threads(3)
+            .with_progress({
+                let last = Arc::clone(&last);
+                move |progress| *last.lock().unwrap() = Some(progress)
+            })
+            .build()
+            .unwrap();
+        let info = &torrent.file.info;
+        assert_eq!(info.name, "content");
+        assert_eq!(info.files.as_ref().unwrap().len(), 3);
+        assert!(info.file_tree.is_none());
+        assert!(crate::verify::verify(&torrent, &root)
+            .await
+            .unwrap()
+            .is_ok());
+        let last = last.lock().unwrap().unwrap();
+        assert_eq!(last.bytes_hashed, 100_011);
+        assert_eq!(last.percent(), 100.0);
+
+        // Hashing on one thread or many makes the same torrent.
+        let single = TorrentBuilder::new(&dir)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_trackers(vec![vec![String::from("http://tracker.example/announce")]])
+            .with_threads(1)
+            .build()
+            .unwrap();
+        assert_eq!(single.file.info.pieces, torrent.file.info.pieces);
+
+        let torrent = TorrentBuilder::new(&dir)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_version(MetaVersion::Hybrid)
+            .build()
+            .unwrap();
+        let info = &torrent.file.info;
+        assert_eq!(info.meta_version, Some(2));
+        assert!(info.files.as_ref().unwrap().iter().any(File::is_padding));
+        assert!(crate::verify::verify(&torrent, &root)
+            .await
+            .unwrap()
+            .is_ok());
+        // Which checks pieces against the files' piece layers, having all of them.
+        assert!(torrent.piece_verifier().unwrap().hash_request().is_none());
+        let parsed = Torrent::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.info_hash, torrent.info_hash);
+        assert_eq!(parsed.file.info.file_tree, info.file_tree);
+
+        // The big file's piece layer checks its pieces, and leads to its pieces root.
+        let tree = info.file_tree.as_ref().unwrap();
+        let big_entry = match &tree["sub"] {
+            FileTree::Directory(sub) => match &sub["big"] {
+                FileTree::File(file) => file[""].clone(),
+                _ => panic!("big isn't a file"),
+            },
+            _ => panic!("sub isn't a directory"),
+        };
+        let pieces_root = big_entry.pieces_root.unwrap();
+        let layer = &torrent.file.piece_layers.as_ref().unwrap()[&pieces_root];
+        let layer: Vec<[u8; 32]> = layer.chunks(32).map(|h| h.try_into().unwrap()).collect();
+        let pieces = MerklePieces::new(MIN_PIECE_LENGTH as usize, layer);
+        assert_eq!(&pieces.pieces_root()[..], &pieces_root[..]);
+        assert!(pieces.verify(6, &big[6 * MIN_PIECE_LENGTH as usize..]));
+        match &tree["empty"] {
+            FileTree::File(file) => assert_eq!(file[""].pieces_root, None),
+            _ => panic!("empty isn't a file"),
+        }
+
+        // Merkle torrents keep only the root of the tree over the v1 pieces' hashes.
+        let torrent = TorrentBuilder::new(&dir)
+            .with_piece_length(MIN_PIECE_LENGTH)
+            .with_version(MetaVersion::Merkle)
+            .build()
+            .unwrap();
+        let info = &torrent.file.info;
+        assert!(info.pieces.is_empty() && info.file_tree.is_none());
+        let leaves: Vec<[u8; 20]> = (single.file.info.hash_pieces())
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
+        assert_eq!(
+            info.merkle_root().unwrap(),
+            Some(MerkleTree::from_leaves(&leaves).root())
+        );
+        assert!(crate::verify::verify(&torrent, &root)
+            .await
+            .unwrap()
+            .is_ok());
+        let parsed = Torrent::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.info_hash, torrent.info_hash);
+
+        // Torrents with no piece length are refused rather than divided by.
+        let mut zero: TorrentFile =
+            serde_bencode::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+        zero.info.piece_length = 0;
+        assert!(Torrent::from_bytes(&serde_bencode::to_bytes(&zero).unwrap()).is_err());
+
+        assert!(TorrentBuilder::new(&dir)
+            .with_piece_length(1000)
+            .build()
+            .is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+//! KRPC, the bencoded query and response messages DHT nodes send each other over UDP.
+
+use super::routing::NodeId;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    #[serde(rename = "t")]
+    pub transaction: ByteBuf,
+    /// "q" for queries, "r" for responses and "e" for errors.
+    #[serde(rename = "y")]
+    pub kind: String,
+    #[serde(rename = "q", default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(rename = "a", default, skip_serializing_if = "Option::is_none")]
+    pub args: Option<Args>,
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Values>,
+    #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<(i64, String)>,
+    /// In responses, the address the query came from, in compact form (BEP 42).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<ByteBuf>,
+}
+
+/// The arguments of a query.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Args {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// 1 if the announced peer listens on the port the query came from, rather than
+    /// `port`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+    /// Which families of nodes to return: "n4" for IPv4 and "n6" for IPv6 (BEP 32).
+    /// Only the family the query was sent over if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub want: Option<Vec<String>>,
+}
+
+/// The values a response returns.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Values {
+    pub id: ByteBuf,
+    /// Nodes in compact form: a 20-byte id followed by a 6-byte address for each.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    /// IPv6 nodes in compact form: a 20-byte id followed by an 18-byte address for each.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes6: Option<ByteBuf>,
+    /// Write token, for announcing to the node after a `get_peers`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    /// Peers in compact form, 6 bytes each for IPv4 or 18 for IPv6.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    /// Seconds to wait before sampling the node again (BEP 51).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    /// How many info hashes the node has peers of, of which `samples` are some.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num: Option<u64>,
+    /// Info hashes the node has peers of, 20 bytes each.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples: Option<ByteBuf>,
+}
+
+impl Message {
+    pub fn query(transaction: &[u8], method: &str, args: Args) -> Self {
+        Self {
+            transaction: ByteBuf::from(transaction),
+            kind: String::from("q"),
+            method: Some(method.to_owned()),
+            args: Some(args),
+            values: None,
+            error: None,
+            ip: None,
+        }
+    }
+
+    pub fn response(transaction: &[u8], values: Values) -> Self {
+        Self {
+            transaction: ByteBuf::from(transaction),
+            kind: String::from("r"),
+            method: None,
+            args: None,
+            values: Some(values),
+            error: None,
+            ip: None,
+        }
+    }
+
+    pub fn error(transaction: &[u8], code: i64, message: &str) -> Self {
+        Self {
+            transaction: ByteBuf::from(transaction),
+            kind: String::from("e"),
+            method: None,
+            args: None,
+            values: None,
+            error: Some((code, message.to_owned())),
+            ip: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+}
+
+impl Args {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(id.0.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    pub fn id(&self) -> anyhow::Result<NodeId> {
+        NodeId::from_slice(&self.id).ok_or_else(|| anyhow!("Invalid node id"))
+    }
+
+    pub fn target(&self) -> anyhow::Result<NodeId> {
+        self.target
+            .as_ref()
+            .and_then(|target| NodeId::from_slice(target))
+            .ok_or_else(|| anyhow!("Missing or invalid target"))
+    }
+
+    pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
+        self.info_hash
+            .as_ref()
+            .and_then(|info_hash| info_hash.as_slice().try_into().ok())
+            .ok_or_else(|| anyhow!("Missing or invalid info hash"))
+    }
+}
+
+impl Values {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(id.0.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    pub fn id(&self) -> anyhow::Result<NodeId> {
+        NodeId::from_slice(&self.id).ok_or_else(|| anyhow!("Invalid node id"))
+    }
+
+    /// The IPv4 and IPv6 nodes in the response, skipping any trailing partial entry.
+    pub fn nodes(&self) -> Vec<(NodeId, SocketAddr)> {
+        let v4 = self.nodes.iter().flat_map(|nodes| nodes.chunks_exact(26));
+        let v6 = self.nodes6.iter().flat_map(|nodes| nodes.chunks_exact(38));
+        v4.chain(v6)
+            .filter_map(|chunk| {
+                let id = NodeId::from_slice(&chunk[..20])?;
+                Some((id, compact_addr(&chunk[20..])?))
+            })
+            .collect()
+    }
+
+    /// The peers in the response, skipping any which aren't a compact address.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.values
+            .iter()
+            .flatten()
+            .filter_map(|peer| compact_addr(peer))
+            .collect()
+    }
+
+    pub fn set_peers(&mut self, peers: impl IntoIterator<Item = SocketAddr>) {
+        let peers = peers
+            .into_iter()
+            .map(|addr| ByteBuf::from(to_compact(addr)))
+            .collect();
+        self.values = Some(peers);
+    }
+
+    /// The info hashes in a `sample_infohashes` response.
+    pub fn samples(&self) -> Vec<[u8; 20]> {
+        self.samples
+            .iter()
+            .flat_map(|samples| samples.chunks_exact(20))
+            .map(|info_hash| info_hash.try_into().unwrap())
+            .collect()
+    }
+
+    /// Set the nodes in the response, IPv4 ones in `nodes` and IPv6 ones in `nodes6`.
+    pub fn set_nodes(&mut self, nodes: impl IntoIterator<Item = (NodeId, SocketAddr)>) {
+        for (id, addr) in nodes {
+            let compact = match addr {
+                SocketAddr::V4(_) => &mut self.nodes,
+                SocketAddr::V6(_) => &mut self.nodes6,
+            };
+            let compact = compact.get_or_insert_with(Default::default);
+            compact.extend_from_slice(&id.0);
+            compact.extend_from_slice(&to_compact(addr));
+        }
+    }
+}
+
+/// An IP and port, as the 6 bytes of an IPv4 address or the 18 of an IPv6 one.
+pub fn compact_addr(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = match bytes.len() {
+        6 => {
+            let ip: [u8; 4] = bytes[..4].try_into().unwrap();
+            (IpAddr::from(Ipv4Addr::from(ip)), &bytes[4..])
+        }
+        18 => {
+            let ip: [u8; 16] = bytes[..16].try_into().unwrap();
+            (IpAddr::from(Ipv6Addr::from(ip)), &bytes[16..])
+        }
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+pub fn to_compact(addr: SocketAddr) -> Vec<u8> {
+    let mut compact = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    compact.extend_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode_messages() {
+        let id = NodeId([b'a'; 20]);
+        let ping = Message::query(b"aa", "ping", Args::new(id));
+        assert_eq!(
+            ping.to_bytes().unwrap(),
+            b"d1:ad2:id20:aaaaaaaaaaaaaaaaaaaae1:q4:ping1:t2:aa1:y1:qe".to_vec()
+        );
+
+        let mut values = Values::new(id);
+        let node = (NodeId([b'b'; 20]), "192.0.2.1:6881".parse().unwrap());
+        let node6 = (NodeId([b'c'; 20]), "[2001:db8::1]:6881".parse().unwrap());
+        values.set_nodes([node, node6]);
+        let response = Message::response(b"bb", values);
+        let decoded = Message::from_bytes(&response.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded, response);
+        let values = decoded.values.unwrap();
+        assert_eq!(values.nodes.as_ref().unwrap().len(), 26);
+        assert_eq!(values.nodes(), vec![node, node6]);
+
+        let mut values = Values::new(id);
+        let peers = vec![
+            "192.0.2.2:51413".parse().unwrap(),
+            "[2001:db8::2]:51413".parse().unwrap(),
+        ];
+        values.set_peers(peers.clone());
+        values.token = Some(ByteBuf::from(b"tok".to_vec()));
+        let response = Message::response(b"cc", values);
+        let decoded = Message::from_bytes(&response.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.values.unwrap().peers(), peers);
+
+        let mut values = Values::new(id);
+        values.samples = Some(ByteBuf::from([[1; 20], [2; 20]].concat()));
+        values.num = Some(2);
+        let response = Message::response(b"ee", values);
+        let decoded = Message::from_bytes(&response.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded, response);
+        assert_eq!(decoded.values.unwrap().samples(), vec![[1; 20], [2; 20]]);
+
+        let mut args = Args::new(id);
+        args.want = Some(vec![String::from("n4"), String::from("n6")]);
+        let query = Message::query(b"dd", "find_node", args);
+        assert_eq!(
+            Message::from_bytes(&query.to_bytes().unwrap()).unwrap(),
+            query
+        );
+
+        let error =
+            Message::from_bytes(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(
+            error.error,
+            Some((201, String::from("A Generic Error Ocurred")))
+        );
+    }
+}
@@ -0,0 +1,1071 @@
+//! A node in the mainline DHT of BEP 5, which finds peers without a tracker.
+//!
+//! The node can run over IPv4 and IPv6 at once, keeping a routing table for each as
+//! BEP 32 describes, and picks an id derived from our external address (BEP 42) when we
+//! know it, so other nodes trust it.
+//!
+//! The routing tables can be saved with [`Dht::state`] and handed back to [`Dht::bind`],
+//! so a restarted node starts from the nodes it knew rather than bootstrapping again.
+
+mod krpc;
+pub mod routing;
+
+use crate::external_ip::{ExternalIp, Voter};
+use crate::magnet::hex;
+use anyhow::anyhow;
+use futures::future::join_all;
+use krpc::{Args, Message, Values};
+use rand::seq::IteratorRandom;
+use routing::{Node, NodeId, RoutingTable, K};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
+use tokio::time::{self, Duration};
+use tracing::{debug, trace, warn};
+
+/// Well-known nodes to bootstrap from when we don't know any others.
+pub const DEFAULT_ROUTERS: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many queries a lookup has in flight at once.
+const ALPHA: usize = 3;
+/// How often to ping questionable nodes, dropping those which don't answer.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// How often the secret our write tokens are made from changes. Tokens made from the
+/// last secret are accepted too.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// How long peers which announce to us are remembered for.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Most torrents we store announced peers for.
+const MAX_STORED_TORRENTS: usize = 2000;
+/// Most announced peers we store for each torrent.
+const MAX_STORED_PEERS: usize = 500;
+/// Most peers returned in one `get_peers` response, to keep it in a single packet.
+const MAX_PEERS_RETURNED: usize = 50;
+/// Most info hashes returned in one `sample_infohashes` response.
+const MAX_SAMPLES: usize = 20;
+/// How long crawlers are asked to wait before sampling us again.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// What a node needs to pick up where it left off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DhtState {
+    pub id: NodeId,
+    pub nodes: Vec<Node>,
+}
+
+/// What a node returned from `sample_infohashes`, for crawling the DHT (BEP 51).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Samples {
+    /// Some of the info hashes the node has peers of.
+    pub info_hashes: Vec<[u8; 20]>,
+    /// How many info hashes the node has peers of in all.
+    pub total: usize,
+    /// How long to wait before sampling the node again.
+    pub interval: Duration,
+    /// Nodes close to the target, to sample next.
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+}
+
+type Reply = Result<Values, (i64, String)>;
+
+#[derive(Debug, Clone, Copy)]
+enum Method {
+    FindNode,
+    GetPeers,
+}
+
+/// What a lookup found: the closest nodes which answered, with the write tokens they
+/// gave us, and any peers they returned.
+#[derive(Debug, Default)]
+struct Lookup {
+    nodes: Vec<(Node, Option<ByteBuf>)>,
+    peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Dht {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Changes if our external address does, so it stays secure.
+    id: Mutex<NodeId>,
+    v4: Option<Family>,
+    v6: Option<Family>,
+    /// Queries waiting for a response, by transaction id, with the node they were sent to.
+    pending: Mutex<HashMap<u16, (SocketAddr, oneshot::Sender<Reply>)>>,
+    next_transaction: AtomicU16,
+    tokens: Mutex<Tokens>,
+    /// Where to report the addresses nodes see us at.
+    external_ip: Mutex<Option<ExternalIp>>,
+    /// Peers which announced themselves to us, by info hash.
+    peers: Mutex<HashMap<[u8; 20], StoredTorrent>>,
+    tasks: Mutex<Vec<AbortHandle>>,
+}
+
+/// The peers which announced a torrent to us.
+#[derive(Debug)]
+struct StoredTorrent {
+    /// Each peer, with when it last announced.
+    peers: HashMap<SocketAddr, Instant>,
+    /// When any peer last announced.
+    announced: Instant,
+}
+
+/// The socket and routing table for one of IPv4 or IPv6.
+#[derive(Debug)]
+struct Family {
+    socket: Arc<UdpSocket>,
+    table: Mutex<RoutingTable>,
+}
+
+/// Write tokens, which prove a node asked us for peers from the address it announces
+/// from.
+#[derive(Debug)]
+struct Tokens {
+    secret: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new() -> Self {
+        Self {
+            secret: rand::random(),
+            previous: rand::random(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.secret;
+            self.secret = rand::random();
+            self.rotated = Instant::now();
+        }
+    }
+
+    fn issue(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate();
+        token(&self.secret, ip)
+    }
+
+    fn check(&mut self, ip: IpAddr, token: &[u8]) -> bool {
+        self.rotate();
+        [self.secret, self.previous]
+            .iter()
+            .any(|secret| self::token(secret, ip) == token)
+    }
+}
+
+fn token(secret: &[u8; 16], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.finalize()[..8].to_vec()
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for task in self.tasks.get_mut().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Dht {
+    /// Start a node on each of `addrs`, at most one IPv4 and one IPv6 address, skipping
+    /// those which can't be bound. The node keeps the id and nodes from `state` if there
+    /// is one, unless the id doesn't match `external_ip`, in which case it picks one that
+    /// does. Call [`Dht::bootstrap`] to fill the routing tables.
+    pub async fn bind(
+        addrs: &[SocketAddr],
+        external_ip: Option<IpAddr>,
+        state: Option<DhtState>,
+    ) -> anyhow::Result<Self> {
+        let id = match (&state, external_ip) {
+            (Some(state), Some(ip)) if !state.id.is_secure_for(ip) => {
+                debug!("Our external address changed, so picking a new DHT node id");
+                NodeId::secure(ip)
+            }
+            (Some(state), _) => state.id,
+            (None, Some(ip)) => NodeId::secure(ip),
+            (None, None) => NodeId::random(),
+        };
+
+        let (mut v4, mut v6) = (None, None);
+        for &addr in addrs {
+            let socket = match bind_socket(addr) {
+                Ok(socket) => Arc::new(socket),
+                Err(e) => {
+                    warn!("Couldn't run the DHT on {}: {}", addr, e);
+                    continue;
+                }
+            };
+            let family = Some(Family {
+                socket,
+                table: Mutex::new(RoutingTable::new(id)),
+            });
+            match addr {
+                SocketAddr::V4(_) => v4 = family,
+                SocketAddr::V6(_) => v6 = family,
+            }
+        }
+        if v4.is_none() && v6.is_none() {
+            return Err(anyhow!("Couldn't bind any DHT sockets"));
+        }
+
+        let dht = Self {
+            inner: Arc::new(Inner {
+                id: Mutex::new(id),
+                v4,
+                v6,
+                pending: Default::default(),
+                next_transaction: AtomicU16::new(rand::random()),
+                tokens: Mutex::new(Tokens::new()),
+                external_ip: Default::default(),
+                peers: Default::default(),
+                tasks: Default::default(),
+            }),
+        };
+        for node in state.into_iter().flat_map(|state| state.nodes) {
+            dht.inner.insert(node);
+        }
+        debug!(
+            "DHT node {} on {:?} with {} known nodes",
+            id,
+            dht.local_addrs(),
+            dht.node_count()
+        );
+
+        let mut tasks = vec![tokio::spawn(refresh(Arc::downgrade(&dht.inner))).abort_handle()];
+        for family in dht.inner.families() {
+            let receive = receive(Arc::clone(&family.socket), Arc::downgrade(&dht.inner));
+            tasks.push(tokio::spawn(receive).abort_handle());
+        }
+        *dht.inner.tasks.lock().unwrap() = tasks;
+
+        Ok(dht)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id()
+    }
+
+    /// Pick a new id if ours isn't secure for our address changing to `ip`, keeping the
+    /// nodes we know.
+    pub fn set_external_ip(&self, ip: IpAddr) {
+        let mut id = self.inner.id.lock().unwrap();
+        if id.is_secure_for(ip) {
+            return;
+        }
+        *id = NodeId::secure(ip);
+        debug!("Our address is now {}, so our DHT node id is {}", ip, id);
+        for family in self.inner.families() {
+            let mut table = family.table.lock().unwrap();
+            let nodes = table.nodes();
+            *table = RoutingTable::new(*id);
+            for node in nodes {
+                table.insert(node);
+            }
+        }
+    }
+
+    /// Count the addresses nodes tell us they see us at (BEP 42) towards `external_ip`.
+    pub fn report_external_ip(&self, external_ip: ExternalIp) {
+        *self.inner.external_ip.lock().unwrap() = Some(external_ip);
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.inner
+            .families()
+            .filter_map(|family| family.socket.local_addr().ok())
+            .collect()
+    }
+
+    /// Our id and the good nodes in the routing tables, to save for the next run.
+    pub fn state(&self) -> DhtState {
+        let nodes = self.inner.families().flat_map(|family| {
+            let nodes = family.table.lock().unwrap().nodes();
+            nodes.into_iter().filter(Node::is_good)
+        });
+        DhtState {
+            id: self.id(),
+            nodes: nodes.collect(),
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.inner
+            .families()
+            .map(|family| family.table.lock().unwrap().len())
+            .sum()
+    }
+
+    /// Fill the routing tables by looking up our own id, starting from the nodes we
+    /// already know, or from `routers` if none of them answer.
+    pub async fn bootstrap(&self, routers: &[&str]) -> anyhow::Result<()> {
+        let id = self.id();
+        if !self
+            .lookup(id, Vec::new(), Method::FindNode, |_| {})
+            .await
+            .nodes
+            .is_empty()
+        {
+            debug!("Bootstrapped DHT from known nodes");
+            return Ok(());
+        }
+
+        let mut seeds = Vec::new();
+        for router in routers {
+            match tokio::net::lookup_host(router).await {
+                Ok(addrs) => seeds.extend(addrs.filter(|addr| self.inner.family(addr).is_some())),
+                Err(e) => debug!("Couldn't resolve DHT router {}: {}", router, e),
+            }
+        }
+        let replies = join_all(seeds.iter().map(|&addr| self.find_node(addr, id))).await;
+        let found: Vec<_> = replies.into_iter().flatten().flatten().collect();
+        if self
+            .lookup(id, found, Method::FindNode, |_| {})
+            .await
+            .nodes
+            .is_empty()
+        {
+            return Err(anyhow!("No DHT nodes answered"));
+        }
+        debug!("Bootstrapped DHT from routers");
+
+        Ok(())
+    }
+
+    /// Find peers of the torrent with `info_hash`.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.lookup(NodeId(info_hash), Vec::new(), Method::GetPeers, |_| {})
+            .await
+            .peers
+    }
+
+    /// Find peers of the torrent with `info_hash`, and tell the nodes closest to it that
+    /// we're a peer listening on `port`.
+    pub async fn announce(
+        &self,
+        info_hash: [u8; 20],
+        port: u16,
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        self.announce_with(info_hash, port, |_| {}).await
+    }
+
+    /// Like [`Dht::announce`], handing peers to `on_peers` as the lookup finds them
+    /// rather than only once it's done, so they can be dialed straight away.
+    pub async fn announce_with(
+        &self,
+        info_hash: [u8; 20],
+        port: u16,
+        on_peers: impl FnMut(Vec<SocketAddr>),
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        let found = self
+            .lookup(NodeId(info_hash), Vec::new(), Method::GetPeers, on_peers)
+            .await;
+        if found.nodes.is_empty() {
+            return Err(anyhow!("No DHT nodes answered"));
+        }
+
+        let announces = found.nodes.iter().filter_map(|(node, token)| {
+            let mut args = Args::new(self.id());
+            args.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+            args.token = Some(token.clone()?);
+            args.port = Some(port);
+            Some(self.query(node.addr, "announce_peer", args))
+        });
+        let stored = join_all(announces)
+            .await
+            .iter()
+            .filter(|r| r.is_ok())
+            .count();
+        debug!(
+            "Announced {} to {} DHT nodes, and found {} peers",
+            hex(&info_hash),
+            stored,
+            found.peers.len()
+        );
+
+        Ok(found.peers)
+    }
+
+    /// Ask the node at `addr` for a sample of the info hashes it has peers of, along
+    /// with the nodes it knows closest to `target`. Crawlers can index the DHT by
+    /// sampling those nodes in turn, with targets spread across the id space.
+    pub async fn sample_infohashes(
+        &self,
+        addr: SocketAddr,
+        target: NodeId,
+    ) -> anyhow::Result<Samples> {
+        let mut args = Args::new(self.id());
+        args.target = Some(ByteBuf::from(target.0.to_vec()));
+        let values = self.query(addr, "sample_infohashes", args).await?;
+        let info_hashes = values.samples();
+
+        Ok(Samples {
+            total: values.num.map_or(info_hashes.len(), |num| num as usize),
+            interval: Duration::from_secs(values.interval.unwrap_or_default()),
+            nodes: values.nodes(),
+            info_hashes,
+        })
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> anyhow::Result<NodeId> {
+        let values = self.query(addr, "ping", Args::new(self.id())).await?;
+        values.id()
+    }
+
+    /// Ask the node at `addr` for the nodes it knows closest to `target`.
+    async fn find_node(
+        &self,
+        addr: SocketAddr,
+        target: NodeId,
+    ) -> anyhow::Result<Vec<(NodeId, SocketAddr)>> {
+        Ok(self.ask(addr, Method::FindNode, target).await?.nodes())
+    }
+
+    /// Send a `find_node` or `get_peers` query for `target`, asking for nodes of each
+    /// family we run on.
+    async fn ask(
+        &self,
+        addr: SocketAddr,
+        method: Method,
+        target: NodeId,
+    ) -> anyhow::Result<Values> {
+        let mut args = Args::new(self.id());
+        if self.inner.v4.is_some() && self.inner.v6.is_some() {
+            args.want = Some(vec![String::from("n4"), String::from("n6")]);
+        }
+        let target = Some(ByteBuf::from(target.0.to_vec()));
+        let name = match method {
+            Method::FindNode => {
+                args.target = target;
+                "find_node"
+            }
+            Method::GetPeers => {
+                args.info_hash = target;
+                "get_peers"
+            }
+        };
+        self.query(addr, name, args).await
+    }
+
+    /// Find the nodes closest to `target`, asking the closest nodes we know of and then
+    /// the closer ones they tell us of until no closer ones turn up. Peers are handed to
+    /// `on_peers` as they're found, as well as returned.
+    async fn lookup(
+        &self,
+        target: NodeId,
+        seeds: Vec<(NodeId, SocketAddr)>,
+        method: Method,
+        mut on_peers: impl FnMut(Vec<SocketAddr>),
+    ) -> Lookup {
+        let own_id = self.id();
+        let reachable =
+            |(id, addr): &(NodeId, SocketAddr)| *id != own_id && self.inner.family(addr).is_some();
+        let known = self.inner.closest(&target, true, true);
+        let mut candidates: Vec<_> = known.into_iter().map(|n| (n.id, n.addr)).collect();
+        candidates.extend(seeds);
+        candidates.retain(reachable);
+        let mut queried = HashSet::new();
+        let mut found = Lookup::default();
+
+        loop {
+            candidates.sort_by_key(|(id, _)| id.distance(&target));
+            candidates.dedup_by_key(|(_, addr)| *addr);
+            let next: Vec<_> = candidates
+                .iter()
+                .take(K)
+                .filter(|(_, addr)| !queried.contains(addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+
+            queried.extend(next.iter().map(|(_, addr)| *addr));
+            let replies =
+                join_all(next.iter().map(|&(_, addr)| self.ask(addr, method, target))).await;
+            let mut new_peers = Vec::new();
+            for ((id, addr), reply) in next.into_iter().zip(replies) {
+                match reply {
+                    Ok(values) => {
+                        candidates.extend(values.nodes().into_iter().filter(reachable));
+                        for peer in values.peers() {
+                            if !found.peers.contains(&peer) {
+                                found.peers.push(peer);
+                                new_peers.push(peer);
+                            }
+                        }
+                        found.nodes.push((Node::new(id, addr), values.token));
+                    }
+                    Err(e) => {
+                        trace!("DHT node {} didn't answer: {}", addr, e);
+                        candidates.retain(|(_, a)| *a != addr);
+                    }
+                }
+            }
+            if !new_peers.is_empty() {
+                on_peers(new_peers);
+            }
+        }
+
+        found.nodes.sort_by_key(|(n, _)| n.id.distance(&target));
+        found.nodes.truncate(K);
+        found
+    }
+
+    async fn query(&self, addr: SocketAddr, method: &str, args: Args) -> anyhow::Result<Values> {
+        self.inner.query(addr, method, args).await
+    }
+}
+
+/// A UDP socket on `addr`. IPv6 sockets only take IPv6 traffic, so they can share a
+/// port with an IPv4 one.
+fn bind_socket(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    crate::net::ignore_port_unreachable(SockRef::from(&socket))?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+impl Inner {
+    fn id(&self) -> NodeId {
+        *self.id.lock().unwrap()
+    }
+
+    fn families(&self) -> impl Iterator<Item = &Family> {
+        self.v4.iter().chain(&self.v6)
+    }
+
+    /// The family we reach `addr` with, if we run on it.
+    fn family(&self, addr: &SocketAddr) -> Option<&Family> {
+        match addr {
+            SocketAddr::V4(_) => self.v4.as_ref(),
+            SocketAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+
+    fn insert(&self, node: Node) {
+        if let Some(family) = self.family(&node.addr) {
+            family.table.lock().unwrap().insert(node);
+        }
+    }
+
+    /// The nodes closest to `target` in the IPv4 table, the IPv6 table, or both.
+    fn closest(&self, target: &NodeId, v4: bool, v6: bool) -> Vec<Node> {
+        let tables = [(v4, &self.v4), (v6, &self.v6)];
+        tables
+            .into_iter()
+            .filter_map(|(wanted, family)| family.as_ref().filter(|_| wanted))
+            .flat_map(|family| family.table.lock().unwrap().closest(target, K))
+            .collect()
+    }
+
+    async fn query(&self, addr: SocketAddr, method: &str, args: Args) -> anyhow::Result<Values> {
+        let family = self
+            .family(&addr)
+            .ok_or_else(|| anyhow!("Not running the DHT over {}'s address family", addr))?;
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(transaction, (addr, tx));
+
+        let message = Message::query(&transaction.to_be_bytes(), method, args);
+        let reply = async {
+            family.socket.send_to(&message.to_bytes()?, addr).await?;
+            time::timeout(QUERY_TIMEOUT, rx)
+                .await
+                .map_err(|_| anyhow!("Timed out"))?
+                .map_err(|_| anyhow!("DHT node stopped"))
+        }
+        .await;
+        self.pending.lock().unwrap().remove(&transaction);
+
+        match reply? {
+            Ok(values) => Ok(values),
+            Err((code, message)) => Err(anyhow!("DHT error {}: {}", code, message)),
+        }
+    }
+
+    /// Peers which announced the torrent to us lately from the same family as `from`.
+    fn stored_peers(&self, info_hash: &[u8; 20], from: &SocketAddr) -> Vec<SocketAddr> {
+        let peers = self.peers.lock().unwrap();
+        let torrent = match peers.get(info_hash) {
+            Some(torrent) => torrent,
+            None => return Vec::new(),
+        };
+        torrent
+            .peers
+            .iter()
+            .filter(|(peer, announced)| {
+                peer.is_ipv4() == from.is_ipv4() && announced.elapsed() < PEER_TTL
+            })
+            .map(|(peer, _)| *peer)
+            .take(MAX_PEERS_RETURNED)
+            .collect()
+    }
+
+    /// Some of the info hashes peers announced to us lately, and how many there are.
+    fn sample_info_hashes(&self) -> (Vec<[u8; 20]>, usize) {
+        let peers = self.peers.lock().unwrap();
+        let mut rng = rand::thread_rng();
+        let sample = peers.keys().copied().choose_multiple(&mut rng, MAX_SAMPLES);
+        (sample, peers.len())
+    }
+
+    /// Remember that `peer` announced the torrent with `info_hash`. When we're storing
+    /// as many torrents or peers as we will, the one announced least recently makes way.
+    fn store_peer(&self, info_hash: [u8; 20], peer: SocketAddr) {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(&info_hash) && peers.len() >= MAX_STORED_TORRENTS {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, torrent)| torrent.announced)
+                .map(|(info_hash, _)| *info_hash);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        let torrent = peers.entry(info_hash).or_insert_with(|| StoredTorrent {
+            peers: HashMap::new(),
+            announced: now,
+        });
+        // Only this torrent's peers are swept here, so announcing costs little however
+        // many torrents we store.
+        torrent
+            .peers
+            .retain(|_, announced| now.duration_since(*announced) < PEER_TTL);
+        if !torrent.peers.contains_key(&peer) && torrent.peers.len() >= MAX_STORED_PEERS {
+            let oldest = torrent
+                .peers
+                .iter()
+                .min_by_key(|(_, announced)| **announced)
+                .map(|(peer, _)| *peer);
+            if let Some(oldest) = oldest {
+                torrent.peers.remove(&oldest);
+            }
+        }
+        torrent.peers.insert(peer, now);
+        torrent.announced = now;
+    }
+
+    /// Forget the peers which haven't announced lately, and the torrents left without
+    /// any.
+    fn expire_peers(&self) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, torrent| {
+            torrent
+                .peers
+                .retain(|_, announced| announced.elapsed() < PEER_TTL);
+            !torrent.peers.is_empty()
+        });
+    }
+
+    fn handle(&self, message: Message, from: SocketAddr) -> Option<Message> {
+        match message.kind.as_str() {
+            "r" | "e" => {
+                let transaction =
+                    u16::from_be_bytes(message.transaction.as_slice().try_into().ok()?);
+                // Only replies to queries we sent, from the nodes we sent them to, count.
+                let tx = {
+                    let mut pending = self.pending.lock().unwrap();
+                    match pending.get(&transaction) {
+                        Some((to, _)) if *to == from => pending.remove(&transaction)?.1,
+                        _ => return None,
+                    }
+                };
+                if let Some(ip) = message.ip.as_ref().and_then(|ip| krpc::compact_addr(ip)) {
+                    if let Some(external_ip) = &*self.external_ip.lock().unwrap() {
+                        external_ip.vote(Voter::Ip(from.ip()), ip.ip());
+                    }
+                }
+                let reply = match (message.values, message.error) {
+                    (Some(values), _) => {
+                        if let Ok(id) = values.id() {
+                            self.insert(Node::new(id, from));
+                        }
+                        Ok(values)
+                    }
+                    (None, Some(error)) => Err(error),
+                    (None, None) => Err((krpc::ERROR_PROTOCOL, String::from("Empty reply"))),
+                };
+                let _ = tx.send(reply);
+                None
+            }
+            "q" => {
+                let transaction = &message.transaction;
+                let mut response = match self.answer(&message, from) {
+                    Ok(values) => Message::response(transaction, values),
+                    Err((code, e)) => Message::error(transaction, code, &e),
+                };
+                response.ip = Some(ByteBuf::from(krpc::to_compact(from)));
+                Some(response)
+            }
+            _ => None,
+        }
+    }
+
+    fn answer(&self, query: &Message, from: SocketAddr) -> Reply {
+        let protocol_error = |e: anyhow::Error| (krpc::ERROR_PROTOCOL, e.to_string());
+        let args = query
+            .args
+            .as_ref()
+            .ok_or_else(|| protocol_error(anyhow!("Query has no arguments")))?;
+        let id = args.id().map_err(protocol_error)?;
+        self.insert(Node::new(id, from));
+
+        // Nodes of the family the query came over, unless it asked for others.
+        let (want_v4, want_v6) = match &args.want {
+            Some(want) => (
+                want.iter().any(|w| w == "n4"),
+                want.iter().any(|w| w == "n6"),
+            ),
+            None => (from.is_ipv4(), from.is_ipv6()),
+        };
+        let closest = |target| {
+            let nodes = self.closest(&target, want_v4, want_v6);
+            nodes.into_iter().map(|n| (n.id, n.addr))
+        };
+
+        let mut values = Values::new(self.id());
+        match query.method.as_deref() {
+            Some("ping") => {}
+            Some("find_node") => {
+                let target = args.target().map_err(protocol_error)?;
+                values.set_nodes(closest(target));
+            }
+            Some("get_peers") => {
+                let info_hash = args.info_hash().map_err(protocol_error)?;
+                let peers = self.stored_peers(&info_hash, &from);
+                if peers.is_empty() {
+                    values.set_nodes(closest(NodeId(info_hash)));
+                } else {
+                    values.set_peers(peers);
+                }
+                let token = self.tokens.lock().unwrap().issue(from.ip());
+                values.token = Some(ByteBuf::from(token));
+            }
+            Some("sample_infohashes") => {
+                let target = args.target().map_err(protocol_error)?;
+                let (samples, total) = self.sample_info_hashes();
+                values.samples = Some(ByteBuf::from(samples.concat()));
+                values.num = Some(total as u64);
+                values.interval = Some(SAMPLE_INTERVAL.as_secs());
+                values.set_nodes(closest(target));
+            }
+            Some("announce_peer") => {
+                let info_hash = args.info_hash().map_err(protocol_error)?;
+                let token = args
+                    .token
+                    .as_ref()
+                    .map_or(&[][..], |token| token.as_slice());
+                if !self.tokens.lock().unwrap().check(from.ip(), token) {
+                    return Err((krpc::ERROR_PROTOCOL, String::from("Bad token")));
+                }
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return Err(protocol_error(anyhow!("Missing port"))),
+                };
+                self.store_peer(info_hash, SocketAddr::new(from.ip(), port));
+            }
+            _ => return Err((krpc::ERROR_METHOD_UNKNOWN, String::from("Method Unknown"))),
+        }
+
+        Ok(values)
+    }
+}
+
+/// Answer queries arriving on `socket` and hand responses to the queries waiting for
+/// them, until the node is dropped.
+async fn receive(socket: Arc<UdpSocket>, inner: Weak<Inner>) {
+    let mut buf = vec![0; 2048];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("DHT socket error: {}", e);
+                continue;
+            }
+        };
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let message = match Message::from_bytes(&buf[..len]) {
+            Ok(message) => message,
+            Err(e) => {
+                trace!("Invalid DHT message from {}: {}", from, e);
+                continue;
+            }
+        };
+
+        if let Some(response) = inner.handle(message, from) {
+            if let Ok(bytes) = response.to_bytes() {
+                let _ = socket.send_to(&bytes, from).await;
+            }
+        }
+    }
+}
+
+/// Check the nodes we haven't heard from in a while are still there, so the tables
+/// stay full of good nodes, and forget peers which stopped announcing.
+async fn refresh(inner: Weak<Inner>) {
+    let mut interval = time::interval(REFRESH_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        inner.expire_peers();
+        let questionable: Vec<_> = inner
+            .families()
+            .flat_map(|family| family.table.lock().unwrap().questionable())
+            .collect();
+        let pings = questionable
+            .iter()
+            .map(|node| inner.query(node.addr, "ping", Args::new(inner.id())));
+        for (node, reply) in questionable.iter().zip(join_all(pings).await) {
+            if let (Err(_), Some(family)) = (reply, inner.family(&node.addr)) {
+                family.table.lock().unwrap().remove(&node.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn node(state: Option<DhtState>) -> (Dht, SocketAddr) {
+        let dht = Dht::bind(&["127.0.0.1:0".parse().unwrap()], None, state)
+            .await
+            .unwrap();
+        let addr = dht.local_addrs()[0];
+        (dht, addr)
+    }
+
+    #[tokio::test]
+    async fn bootstrap_from_saved_nodes() {
+        let (router, router_addr) = node(None).await;
+        let (other, _) = node(None).await;
+        // Pinging the router puts each in the other's table.
+        assert_eq!(other.ping(router_addr).await.unwrap(), router.id());
+        assert_eq!(router.node_count(), 1);
+
+        let (first, _) = node(None).await;
+        first.bootstrap(&[&router_addr.to_string()]).await.unwrap();
+        assert_eq!(first.node_count(), 2);
+
+        // A node restarted from the first's state finds the same nodes without the router.
+        let state = first.state();
+        drop(first);
+        let (restarted, _) = node(Some(state.clone())).await;
+        assert_eq!(restarted.id(), state.id);
+        restarted.bootstrap(&[]).await.unwrap();
+        assert_eq!(restarted.node_count(), 2);
+
+        let (lost, _) = node(None).await;
+        assert!(lost.bootstrap(&[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ignore_unsolicited_replies() {
+        let (dht, _) = node(None).await;
+        let (other, other_addr) = node(None).await;
+        let (asked, asked_addr) = node(None).await;
+        let reply = |transaction: u16| {
+            Message::response(&transaction.to_be_bytes(), Values::new(other.id()))
+        };
+        assert!(dht.inner.handle(reply(1), other_addr).is_none());
+        assert_eq!(dht.node_count(), 0);
+
+        // Nor do replies from nodes other than the one asked.
+        let transaction = dht.inner.next_transaction.load(Ordering::Relaxed);
+        let ping = tokio::spawn({
+            let dht = dht.clone();
+            async move { dht.ping(asked_addr).await }
+        });
+        while dht.inner.pending.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        dht.inner.handle(reply(transaction), other_addr);
+        assert_eq!(dht.node_count(), 0);
+        assert_eq!(ping.await.unwrap().unwrap(), asked.id());
+        assert_eq!(dht.node_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn limit_stored_peers() {
+        let (dht, _) = node(None).await;
+        let inner = &dht.inner;
+        let peer = |port| SocketAddr::from(([192, 0, 2, 1], port));
+        for port in 0..=MAX_STORED_PEERS as u16 {
+            inner.store_peer([1; 20], peer(port));
+        }
+        let stored = inner.peers.lock().unwrap()[&[1; 20]].peers.clone();
+        assert_eq!(stored.len(), MAX_STORED_PEERS);
+        assert!(!stored.contains_key(&peer(0)));
+
+        for i in 0..MAX_STORED_TORRENTS as u32 {
+            let mut info_hash = [2; 20];
+            info_hash[..4].copy_from_slice(&i.to_be_bytes());
+            inner.store_peer(info_hash, peer(1));
+        }
+        let peers = inner.peers.lock().unwrap();
+        assert_eq!(peers.len(), MAX_STORED_TORRENTS);
+        assert!(!peers.contains_key(&[1; 20]));
+    }
+
+    #[tokio::test]
+    async fn expire_stored_peers() {
+        let (dht, _) = node(None).await;
+        let inner = &dht.inner;
+        let (old, new) = (
+            "192.0.2.1:6881".parse().unwrap(),
+            "192.0.2.2:6881".parse().unwrap(),
+        );
+        inner.store_peer([1; 20], old);
+        inner.store_peer([2; 20], old);
+        let long_ago = Instant::now() - PEER_TTL;
+        for torrent in inner.peers.lock().unwrap().values_mut() {
+            torrent.peers.insert(old, long_ago);
+        }
+
+        // Peers which stopped announcing aren't handed out, and go once we sweep.
+        inner.store_peer([1; 20], new);
+        assert_eq!(inner.stored_peers(&[1; 20], &new), vec![new]);
+        assert!(inner.stored_peers(&[2; 20], &new).is_empty());
+        inner.expire_peers();
+        let peers = inner.peers.lock().unwrap();
+        assert_eq!(peers.keys().collect::<Vec<_>>(), vec![&[1; 20]]);
+        assert_eq!(peers[&[1; 20]].peers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn announce_and_find_peers() {
+        let (router, router_addr) = node(None).await;
+        let (seeder, _) = node(None).await;
+        let (leecher, _) = node(None).await;
+        for dht in [&seeder, &leecher] {
+            dht.bootstrap(&[&router_addr.to_string()]).await.unwrap();
+        }
+
+        let info_hash = [7; 20];
+        assert_eq!(seeder.announce(info_hash, 6881).await.unwrap(), vec![]);
+        let peers = leecher.get_peers(info_hash).await;
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert!(router.get_peers([8; 20]).await.is_empty());
+
+        // Peers are handed over as they're found, as well as at the end.
+        let mut streamed = Vec::new();
+        let found = leecher
+            .announce_with(info_hash, 6882, |peers| streamed.extend(peers))
+            .await
+            .unwrap();
+        assert_eq!(streamed, found);
+        assert_eq!(found, peers);
+
+        // Announces need a token from an earlier get_peers.
+        let mut args = Args::new(leecher.id());
+        args.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+        args.token = Some(ByteBuf::from(b"forged".to_vec()));
+        args.port = Some(6882);
+        let announce = leecher.query(router_addr, "announce_peer", args).await;
+        assert!(announce.is_err());
+    }
+
+    #[tokio::test]
+    async fn sample_info_hashes() {
+        let (_router, router_addr) = node(None).await;
+        let (seeder, _) = node(None).await;
+        seeder.bootstrap(&[&router_addr.to_string()]).await.unwrap();
+        for info_hash in [[1; 20], [2; 20]] {
+            seeder.announce(info_hash, 6881).await.unwrap();
+        }
+
+        let (crawler, _) = node(None).await;
+        let samples = crawler
+            .sample_infohashes(router_addr, NodeId::random())
+            .await
+            .unwrap();
+        let info_hashes: HashSet<_> = samples.info_hashes.into_iter().collect();
+        assert_eq!(info_hashes, [[1; 20], [2; 20]].into_iter().collect());
+        assert_eq!(samples.total, 2);
+        assert_eq!(samples.interval, SAMPLE_INTERVAL);
+        assert!(samples
+            .nodes
+            .contains(&(seeder.id(), seeder.local_addrs()[0])));
+    }
+
+    #[tokio::test]
+    async fn find_nodes_of_both_families() {
+        let dual = ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+        let router = Dht::bind(&dual, None, None).await.unwrap();
+        let (router_v4, router_v6) = (router.local_addrs()[0], router.local_addrs()[1]);
+        let v6_only = Dht::bind(&dual[1..], None, None).await.unwrap();
+        v6_only.ping(router_v6).await.unwrap();
+
+        // The router hands out its IPv6 node when asked for both families over IPv4.
+        let first = Dht::bind(&dual, None, None).await.unwrap();
+        first.bootstrap(&[&router_v4.to_string()]).await.unwrap();
+        let nodes: HashSet<_> = first.state().nodes.iter().map(|n| n.addr).collect();
+        let expected = [router_v4, router_v6, v6_only.local_addrs()[0]];
+        assert_eq!(nodes, expected.into_iter().collect());
+    }
+
+    #[tokio::test]
+    async fn pick_an_id_matching_our_address() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let addrs = ["127.0.0.1:0".parse().unwrap()];
+        let dht = Dht::bind(&addrs, Some(ip), None).await.unwrap();
+        assert!(dht.id().is_secure_for(ip));
+
+        // Saved state keeps its id while the address stays the same.
+        let state = dht.state();
+        let restarted = Dht::bind(&addrs, Some(ip), Some(state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(restarted.id(), state.id);
+
+        let moved: IpAddr = "198.51.100.1".parse().unwrap();
+        let restarted = Dht::bind(&addrs, Some(moved), Some(state)).await.unwrap();
+        assert!(restarted.id().is_secure_for(moved));
+
+        // Learning our address while running picks a new id too.
+        let id = restarted.id();
+        restarted.set_external_ip(moved);
+        assert_eq!(restarted.id(), id);
+        restarted.set_external_ip(ip);
+        assert!(restarted.id().is_secure_for(ip));
+    }
+}
@@ -0,0 +1,302 @@
+//! The routing table of BEP 5: the nodes we know of, kept in buckets by how far their ids
+//! are from ours, with more room for nodes close to us than far away.
+
+use crate::magnet::hex;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+/// How many nodes each bucket holds.
+pub const K: usize = 8;
+/// Nodes we haven't heard from in this long are questionable, and may be replaced.
+pub const GOOD_FOR: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    /// A random id which other nodes can check came from `ip`, as described in BEP 42:
+    /// the first 21 bits come from a hash of the IP and the last byte.
+    pub fn secure(ip: IpAddr) -> Self {
+        let mut id: [u8; 20] = rand::random();
+        let prefix = secure_prefix(ip, id[19]);
+        id[0] = prefix[0];
+        id[1] = prefix[1];
+        id[2] = (prefix[2] & 0xf8) | (id[2] & 0x07);
+        Self(id)
+    }
+
+    /// Whether the id is one a node at `ip` could have picked with [`NodeId::secure`].
+    /// Nodes on local networks can pick any id.
+    pub fn is_secure_for(&self, ip: IpAddr) -> bool {
+        if is_local(ip) {
+            return true;
+        }
+        let prefix = secure_prefix(ip, self.0[19]);
+        self.0[..2] == prefix[..2] && self.0[2] & 0xf8 == prefix[2] & 0xf8
+    }
+
+    /// The XOR distance between two ids, which compares as a big-endian number.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0; 20];
+        for (d, (a, b)) in distance.iter_mut().zip(self.0.iter().zip(&other.0)) {
+            *d = a ^ b;
+        }
+        distance
+    }
+
+    /// How many leading bits the ids have in common.
+    fn common_prefix(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        match distance.iter().position(|&b| b != 0) {
+            Some(idx) => idx * 8 + distance[idx].leading_zeros() as usize,
+            None => 160,
+        }
+    }
+}
+
+/// The CRC32-C of the masked IP, which the first 21 bits of a secure id must match.
+fn secure_prefix(ip: IpAddr, r: u8) -> [u8; 4] {
+    let mut masked = match ip {
+        IpAddr::V4(ip) => {
+            let mut masked = ip.octets().to_vec();
+            for (byte, mask) in masked.iter_mut().zip([0x03, 0x0f, 0x3f, 0xff]) {
+                *byte &= mask;
+            }
+            masked
+        }
+        IpAddr::V6(ip) => {
+            let mut masked = ip.octets()[..8].to_vec();
+            let masks = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+            for (byte, mask) in masked.iter_mut().zip(masks) {
+                *byte &= mask;
+            }
+            masked
+        }
+    };
+    masked[0] |= (r & 0x07) << 5;
+
+    crc32c(&masked).to_be_bytes()
+}
+
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0x82f6_3b78 & mask);
+        }
+    }
+    !crc
+}
+
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
+
+impl std::fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NodeId({})", hex(&self.0))
+    }
+}
+
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex(&self.0))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    /// When we last heard from the node.
+    pub last_seen: SystemTime,
+}
+
+impl Node {
+    pub fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Self {
+            id,
+            addr,
+            last_seen: SystemTime::now(),
+        }
+    }
+
+    pub fn is_good(&self) -> bool {
+        self.last_seen
+            .elapsed()
+            .map_or(true, |elapsed| elapsed < GOOD_FOR)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    /// Nodes by how many leading bits their id shares with ours, least recently seen
+    /// first.
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// Add a node we've heard from, or note that we've heard from it again. Nodes only
+    /// displace questionable ones from a full bucket. Returns whether the node is in the
+    /// table.
+    pub fn insert(&mut self, node: Node) -> bool {
+        if node.id == self.id {
+            return false;
+        }
+        let bucket = &mut self.buckets[self.id.common_prefix(&node.id).min(159)];
+
+        if let Some(pos) = bucket.iter().position(|n| n.id == node.id) {
+            let mut existing = bucket.remove(pos);
+            existing.addr = node.addr;
+            existing.last_seen = existing.last_seen.max(node.last_seen);
+            bucket.push(existing);
+        } else if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(pos) = bucket.iter().position(|n| !n.is_good()) {
+            bucket.remove(pos);
+            bucket.push(node);
+        } else {
+            return false;
+        }
+
+        true
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|n| n.id != *id);
+        }
+    }
+
+    /// The `count` nodes whose ids are closest to `target`, closest first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes = self.nodes();
+        nodes.sort_by_key(|n| n.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.buckets.iter().flatten().cloned().collect()
+    }
+
+    /// Nodes we haven't heard from lately, which should be pinged to check they're
+    /// still there.
+    pub fn questionable(&self) -> Vec<Node> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|n| !n.is_good())
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn node(first: u8, last: u8) -> Node {
+        let mut id = [0; 20];
+        id[0] = first;
+        id[19] = last;
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, last as u16);
+        Node::new(NodeId(id), addr.into())
+    }
+
+    #[test]
+    fn fill_buckets_replacing_questionable_nodes() {
+        let mut table = RoutingTable::new(NodeId([0; 20]));
+        // All share no leading bits with us, so go in the same bucket.
+        for last in 0..K as u8 {
+            assert!(table.insert(node(0x80, last)));
+        }
+        assert!(!table.insert(node(0x80, 100)));
+        assert!(table.insert(node(0x40, 100)));
+        assert!(!table.insert(Node::new(NodeId([0; 20]), node(1, 1).addr)));
+
+        // Hearing from a node again refreshes it rather than adding it twice.
+        let mut stale = node(0x80, 3);
+        stale.last_seen = SystemTime::UNIX_EPOCH;
+        assert!(table.insert(stale.clone()));
+        assert_eq!(table.len(), K + 1);
+        assert!(table.questionable().is_empty());
+
+        table.remove(&stale.id);
+        stale.id.0[19] = 200;
+        table.insert(stale.clone());
+        assert_eq!(table.questionable(), vec![stale]);
+        assert!(table.insert(node(0x80, 101)));
+        assert!(table.questionable().is_empty());
+        assert_eq!(table.len(), K + 1);
+    }
+
+    #[test]
+    fn secure_node_ids() {
+        // Examples from BEP 42: the IP, the last byte, and the first 21 bits of the id.
+        let examples = [
+            ("124.31.75.21", 1, [0x5f, 0xbf, 0xbf]),
+            ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+            ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+            ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+            ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+        ];
+        for (ip, r, prefix) in examples {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut id = [0; 20];
+            id[..3].copy_from_slice(&prefix);
+            id[19] = r;
+            assert!(NodeId(id).is_secure_for(ip), "{}", ip);
+            id[1] ^= 1;
+            assert!(!NodeId(id).is_secure_for(ip), "{}", ip);
+        }
+
+        for ip in ["203.0.113.7", "2001:db8::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(NodeId::secure(ip).is_secure_for(ip));
+            assert!(!NodeId::secure(ip).is_secure_for("198.51.100.1".parse().unwrap()));
+        }
+        assert!(NodeId::random().is_secure_for("192.168.1.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn find_closest_nodes() {
+        let mut table = RoutingTable::new(NodeId::random());
+        for first in [0x01, 0x02, 0x10, 0x80, 0xff] {
+            table.insert(node(first, 0));
+        }
+
+        let closest: Vec<_> = table
+            .closest(&node(0x03, 0).id, 3)
+            .iter()
+            .map(|n| n.id.0[0])
+            .collect();
+        assert_eq!(closest, vec![0x02, 0x01, 0x10]);
+    }
+}
@@ -0,0 +1,192 @@
+//! Checking and saving downloaded pieces off the sessions' tasks. Sessions hand the
+//! pieces they assemble to a pool of workers, which hash them and pass those which
+//! pass on to be saved. When pieces arrive faster than they can be hashed and saved,
+//! the queue says it's overloaded and sessions stop requesting blocks until it catches up.
+//! Torrents take turns at the disk through a [`Share`] of the client's turns, so one
+//! torrent with a deep queue can't keep the others from saving their pieces.
+
+use crate::fair_share::{Grant, Share};
+use crate::queues::{Verified, WorkQueue, WorkResult};
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+
+pub const DEFAULT_WORKERS: usize = 2;
+/// Bytes of pieces which can wait to be saved before the queue is overloaded.
+pub const DEFAULT_MAX_QUEUED: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct DiskQueue {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    jobs: UnboundedSender<WorkResult>,
+    /// Bytes of pieces handed to the queue which haven't been passed on to be saved.
+    queued: Arc<watch::Sender<usize>>,
+    max_queued: usize,
+    /// The torrent's turns at the disk.
+    turns: Share,
+    /// The workers, until they're taken to be stopped.
+    workers: std::sync::Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl DiskQueue {
+    /// Start `workers` tasks checking the hashes of `work_queue`'s pieces, each taking a
+    /// turn from `turns`, and sending those which pass to `save_tx`. The workers stop once
+    /// every handle to the queue is dropped.
+    pub fn start(
+        work_queue: WorkQueue,
+        workers: usize,
+        max_queued: usize,
+        turns: Share,
+        save_tx: Sender<WorkResult>,
+    ) -> Self {
+        let (jobs, jobs_rx) = mpsc::unbounded_channel();
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+        let queued = Arc::new(watch::Sender::new(0));
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                tokio::spawn(work(
+                    Arc::clone(&jobs_rx),
+                    Arc::clone(&queued),
+                    turns.clone(),
+                    work_queue.clone(),
+                    save_tx.clone(),
+                ))
+            })
+            .collect();
+
+        Self {
+            inner: Arc::new(Inner {
+                jobs,
+                queued,
+                max_queued,
+                turns,
+                workers: std::sync::Mutex::new(workers),
+            }),
+        }
+    }
+
+    /// The worker tasks, so they can be stopped before the queue is dropped. They're
+    /// only given out once.
+    pub fn take_workers(&self) -> Vec<JoinHandle<()>> {
+        std::mem::take(&mut *self.inner.workers.lock().unwrap())
+    }
+
+    /// Queue an assembled piece to be checked and saved. This never waits, however far
+    /// behind the disk is.
+    pub fn submit(&self, piece: WorkResult) {
+        let len = piece.bytes.len();
+        self.inner.queued.send_modify(|queued| *queued += len);
+        if self.is_overloaded() {
+            debug!("Disk queue overloaded, pausing requests");
+        }
+        let _ = self.inner.jobs.send(piece);
+    }
+
+    /// Whether more is waiting to be saved than the queue allows, so no more blocks
+    /// should be requested for now.
+    pub fn is_overloaded(&self) -> bool {
+        *self.inner.queued.borrow() > self.inner.max_queued
+    }
+
+    /// Whether every piece handed to the queue has been checked, and passed on to be
+    /// saved if it passed.
+    pub fn is_idle(&self) -> bool {
+        *self.inner.queued.borrow() == 0
+    }
+
+    /// Watch the bytes waiting in the queue change.
+    pub fn subscribe(&self) -> watch::Receiver<usize> {
+        self.inner.queued.subscribe()
+    }
+
+    /// Wait for a turn at the disk, such as to save a piece, which lasts until the
+    /// grant is dropped.
+    pub async fn turn(&self) -> Grant {
+        self.inner.turns.acquire(1).await
+    }
+}
+
+async fn work(
+    jobs: Arc<Mutex<UnboundedReceiver<WorkResult>>>,
+    queued: Arc<watch::Sender<usize>>,
+    turns: Share,
+    work_queue: WorkQueue,
+    save_tx: Sender<WorkResult>,
+) {
+    loop {
+        let piece = match jobs.lock().await.recv().await {
+            Some(piece) => piece,
+            None => return,
+        };
+        let len = piece.bytes.len();
+        let work_queue = work_queue.clone();
+        let turn = turns.acquire(1).await;
+        let verified = tokio::task::spawn_blocking(move || work_queue.verify(piece)).await;
+        // Waiting for the writer isn't a turn at the disk.
+        drop(turn);
+        match verified {
+            Ok(Verified::Passed(piece)) => {
+                // Waits for the writer, holding up the rest of the queue.
+                let _ = save_tx.send(piece).await;
+            }
+            Ok(Verified::Failed(idx)) => warn!("Piece {} failed integrity check", idx),
+            Ok(Verified::Duplicate(idx)) => debug!("Piece {} was already complete", idx),
+            Err(e) => error!("Checking piece failed: {}", e),
+        }
+        queued.send_modify(|queued| *queued -= len);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fair_share::FairShare;
+    use crate::picker::{Sequential, BLOCK_SIZE};
+    use crate::piece_hash::Sha1Pieces;
+    use crate::queues::Received;
+    use sha1::{Digest, Sha1};
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn overloaded_until_pieces_are_saved() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| i as u8).collect();
+        let hashes = data
+            .chunks(BLOCK_SIZE)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let verifier = Box::new(Sha1Pieces::new(hashes));
+        let work_queue = WorkQueue::new(verifier, BLOCK_SIZE, data.len(), Box::new(Sequential));
+
+        // The writer only takes one piece at a time.
+        let (save_tx, mut save_rx) = channel(1);
+        let turns = FairShare::new(1).share(1);
+        let disk = DiskQueue::start(work_queue.clone(), 1, BLOCK_SIZE, turns, save_tx);
+        let mut bad = true;
+        while let Some(block) = work_queue.pop(&[0xff]) {
+            let mut bytes = data[block.piece * BLOCK_SIZE..][..BLOCK_SIZE].to_vec();
+            if std::mem::take(&mut bad) {
+                bytes[0] ^= 1;
+            }
+            match work_queue.receive(block, &bytes).unwrap() {
+                Received::Assembled(piece) => disk.submit(piece),
+                Received::Pending => panic!("Expected assembled piece"),
+            }
+        }
+        assert!(disk.is_overloaded());
+
+        // The bad piece is dropped, and the rest are saved once they're taken.
+        assert_eq!(save_rx.recv().await.unwrap().idx, 1);
+        assert_eq!(save_rx.recv().await.unwrap().idx, 2);
+        let mut queued = disk.subscribe();
+        queued.wait_for(|&queued| queued == 0).await.unwrap();
+        assert!(disk.is_idle());
+        assert!(!work_queue.has_piece(0));
+        assert_eq!(work_queue.verified_count(), 2);
+    }
+}
@@ -0,0 +1,499 @@
+//! Finding out why a download can't start: whether the torrent can be read, whether
+//! its trackers answer, whether peers can reach us, whether the DHT can be joined and
+//! whether the content can be written, with hints on what to do about each problem.
+
+use crate::client::ClientConfig;
+use crate::dht::{Dht, DEFAULT_ROUTERS};
+use crate::fetch::{self, TorrentSource};
+use crate::net;
+use crate::tracker::{self, Announcer, Transfer};
+use crate::watchdog;
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+/// How long a tracker has to answer, including retries.
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long connecting to ourselves through our public address can take.
+const REACH_TIMEOUT: Duration = Duration::from_secs(5);
+const DHT_TIMEOUT: Duration = Duration::from_secs(30);
+/// What we tell trackers we have left before we know the torrent's size.
+const UNKNOWN_LEFT: u64 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Ok,
+    Warning,
+    Failure,
+}
+
+/// The outcome of one check, and what to do about it if it isn't ok.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub level: Level,
+    /// What was checked, such as `tracker` or `port`.
+    pub check: &'static str,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, message: String) -> Self {
+        Self {
+            level: Level::Ok,
+            check,
+            message,
+            hint: None,
+        }
+    }
+
+    fn warning(check: &'static str, message: String, hint: impl Into<String>) -> Self {
+        Self {
+            level: Level::Warning,
+            check,
+            message,
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn failure(check: &'static str, message: String, hint: impl Into<String>) -> Self {
+        Self {
+            level: Level::Failure,
+            check,
+            message,
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    /// Whether nothing failed, though there may be warnings.
+    pub fn passed(&self) -> bool {
+        self.findings.iter().all(|f| f.level != Level::Failure)
+    }
+
+    /// The findings one to a line, with any hint underneath, coloured by level for
+    /// terminals if `color` is set.
+    pub fn render(&self, color: bool) -> String {
+        let mut out = String::new();
+        for finding in &self.findings {
+            let (label, code) = match finding.level {
+                Level::Ok => ("  ok", "32"),
+                Level::Warning => ("warn", "33"),
+                Level::Failure => ("FAIL", "31"),
+            };
+            let label = match color {
+                true => format!("\x1b[1;{}m{}\x1b[0m", code, label),
+                false => label.to_owned(),
+            };
+            writeln!(out, "[{}] {}: {}", label, finding.check, finding.message).unwrap();
+            if let Some(hint) = &finding.hint {
+                writeln!(out, "       hint: {}", hint).unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// What the checks need to know about the torrent.
+struct Subject {
+    info_hash: [u8; 20],
+    trackers: Vec<Vec<String>>,
+    /// Unknown for magnet links, until the metadata is fetched.
+    size: Option<u64>,
+    private: bool,
+}
+
+/// Run every check for downloading `source` with `config`. Nothing is downloaded, but
+/// trackers are announced to, and told we've stopped again afterwards.
+pub async fn diagnose(config: &ClientConfig, source: &TorrentSource) -> Report {
+    let mut report = Report::default();
+    let subject = check_torrent(config, source, &mut report).await;
+    check_disk(config, subject.as_ref(), &mut report);
+    let external_ip = match &subject {
+        Some(subject) => check_trackers(config, subject, &mut report).await,
+        None => None,
+    };
+    check_port(config, external_ip, &mut report).await;
+    check_dht(config, &mut report).await;
+    report
+}
+
+async fn check_torrent(
+    config: &ClientConfig,
+    source: &TorrentSource,
+    report: &mut Report,
+) -> Option<Subject> {
+    // Fetching a magnet link's metadata needs peers, which the other checks are about.
+    if let TorrentSource::Magnet(magnet) = source {
+        report.findings.push(Finding::ok(
+            "torrent",
+            format!(
+                "Magnet link for {}, whose metadata will come from peers",
+                magnet.name.as_deref().unwrap_or("an unnamed torrent")
+            ),
+        ));
+        return Some(Subject {
+            info_hash: magnet.info_hash,
+            trackers: magnet.trackers.iter().map(|t| vec![t.clone()]).collect(),
+            size: None,
+            private: false,
+        });
+    }
+
+    match fetch::resolve(
+        source,
+        &config.peer_id,
+        config.port,
+        config.bind_address,
+        &config.socket,
+        None,
+    )
+    .await
+    {
+        Ok(torrent) => {
+            let size = torrent.file.info.total_length();
+            report.findings.push(Finding::ok(
+                "torrent",
+                format!("{}, {}", torrent.file.info.name, mib(size)),
+            ));
+            Some(Subject {
+                info_hash: torrent.info_hash,
+                trackers: torrent.trackers(),
+                size: Some(size),
+                private: torrent.file.info.private == Some(1),
+            })
+        }
+        Err(e) => {
+            let hint = match source {
+                TorrentSource::Url(_) => "Check the URL opens in a browser from this machine",
+                _ => "Check the path, and that the file is a whole .torrent file",
+            };
+            report.findings.push(Finding::failure(
+                "torrent",
+                format!("Couldn't read {}: {}", source, e),
+                hint,
+            ));
+            None
+        }
+    }
+}
+
+fn check_disk(config: &ClientConfig, subject: Option<&Subject>, report: &mut Report) {
+    let dir = &config.save_path;
+    let probe = dir.join(format!(".torrent-doctor-{}", std::process::id()));
+    let written = std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(&probe, b"probe"))
+        .and_then(|()| std::fs::remove_file(&probe));
+    if let Err(e) = written {
+        let hint = match e.kind() {
+            ErrorKind::PermissionDenied => {
+                "Give this user write access to the directory, or pick another with --output"
+            }
+            _ => "Pick another directory with --output",
+        };
+        report.findings.push(Finding::failure(
+            "disk",
+            format!("Can't write to {}: {}", dir.display(), e),
+            hint,
+        ));
+        return;
+    }
+
+    let free = match watchdog::free_space(dir) {
+        Ok(free) => free,
+        Err(e) => {
+            report.findings.push(Finding::warning(
+                "disk",
+                format!(
+                    "Can write to {}, but not tell how much room it has: {}",
+                    dir.display(),
+                    e
+                ),
+                "Make sure the disk has room for the download",
+            ));
+            return;
+        }
+    };
+    match subject.and_then(|s| s.size) {
+        Some(size) if size > free => report.findings.push(Finding::failure(
+            "disk",
+            format!(
+                "{} has {} free, but the torrent needs {}",
+                dir.display(),
+                mib(free),
+                mib(size)
+            ),
+            "Free up space, or pick another directory with --output",
+        )),
+        _ => report.findings.push(Finding::ok(
+            "disk",
+            format!(
+                "Can write to {}, which has {} free",
+                dir.display(),
+                mib(free)
+            ),
+        )),
+    }
+}
+
+/// Announce to each tracker in turn, returning the public address the first to say
+/// gave for us.
+async fn check_trackers(
+    config: &ClientConfig,
+    subject: &Subject,
+    report: &mut Report,
+) -> Option<IpAddr> {
+    let urls: Vec<_> = subject.trackers.iter().flatten().collect();
+    if urls.is_empty() {
+        let finding = match subject.private {
+            true => Finding::failure(
+                "tracker",
+                String::from("The torrent is private but has no trackers"),
+                "Private torrents only get peers from trackers, so get a fresh .torrent file",
+            ),
+            false => Finding::warning(
+                "tracker",
+                String::from("The torrent has no trackers"),
+                "Peers can only be found through the DHT",
+            ),
+        };
+        report.findings.push(finding);
+        return None;
+    }
+
+    let transfer = Transfer {
+        left: subject.size.unwrap_or(UNKNOWN_LEFT),
+        ..Default::default()
+    };
+    let mut external_ip = None;
+    for url in urls {
+        if let Err(e) = tracker::check_url(url, config.allow_local_trackers) {
+            report.findings.push(Finding::failure(
+                "tracker",
+                format!("Won't announce to {}: {}", url, e),
+                "Pass --allow-local-trackers if the tracker is on this machine or network",
+            ));
+            continue;
+        }
+        let announcer = Announcer::from_trackers(vec![vec![url.clone()]])
+            .and_then(|a| a.with_bind_address(config.bind_address))
+            .and_then(|a| a.with_local_trackers(config.allow_local_trackers));
+        let mut announcer = match announcer {
+            Ok(announcer) => announcer,
+            Err(e) => {
+                report.findings.push(Finding::failure(
+                    "tracker",
+                    format!("Couldn't set up announcing: {}", e),
+                    "Check --bind-address is an address of this machine",
+                ));
+                return None;
+            }
+        };
+
+        let info_hash = &subject.info_hash;
+        let announce =
+            announcer.announce_info_hash(info_hash, transfer, &config.peer_id, config.port);
+        match timeout(TRACKER_TIMEOUT, announce).await {
+            Ok(Ok(info)) => {
+                external_ip = external_ip.or(info.external_ip);
+                report.findings.push(Finding::ok(
+                    "tracker",
+                    format!("{} answered with {} peers", url, info.peers.len()),
+                ));
+                announcer
+                    .announce_stopped(info_hash, transfer, &config.peer_id, config.port)
+                    .await;
+            }
+            Ok(Err(e)) => report.findings.push(Finding::failure(
+                "tracker",
+                format!("{} didn't answer: {}", url, e),
+                "Check the tracker is still running, and that a firewall or proxy isn't \
+                 blocking connections to it",
+            )),
+            Err(_) => report.findings.push(Finding::failure(
+                "tracker",
+                format!("{} didn't answer within {:?}", url, TRACKER_TIMEOUT),
+                "Check that a firewall isn't dropping connections to the tracker",
+            )),
+        }
+    }
+    external_ip
+}
+
+/// Check the peer port is free, and whether connections to our public address reach it.
+async fn check_port(config: &ClientConfig, external_ip: Option<IpAddr>, report: &mut Report) {
+    let ip = config
+        .bind_address
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let listener = match net::listen(SocketAddr::new(ip, config.port), &config.socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let hint = match e.kind() {
+                ErrorKind::AddrInUse => {
+                    "Another program, or another copy of this one, is using \
+                                        the port: stop it or pick another port with --port"
+                }
+                ErrorKind::PermissionDenied => {
+                    "Ports below 1024 need extra privileges, so pick \
+                                               a higher one with --port"
+                }
+                _ => "Pick another port with --port",
+            };
+            report.findings.push(Finding::failure(
+                "port",
+                format!("Can't listen on port {}: {}", config.port, e),
+                hint,
+            ));
+            return;
+        }
+    };
+
+    let ip = match external_ip.or(config.external_ip.map(IpAddr::V4)) {
+        Some(ip) => ip,
+        None => {
+            report.findings.push(Finding::warning(
+                "port",
+                format!(
+                    "Listening on port {}, but our public address is unknown, so whether \
+                     peers can reach us wasn't checked",
+                    config.port
+                ),
+                "Give it with --external-ip to check, or check the port from another network",
+            ));
+            return;
+        }
+    };
+    let addr = SocketAddr::new(ip, config.port);
+    let reached = async {
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        connected.is_ok() && accepted.is_ok()
+    };
+    match timeout(REACH_TIMEOUT, reached).await {
+        Ok(true) => report.findings.push(Finding::ok(
+            "port",
+            format!("Peers can reach us at {}", addr),
+        )),
+        _ => report.findings.push(Finding::warning(
+            "port",
+            format!("Couldn't reach ourselves at {}", addr),
+            format!(
+                "Forward TCP and UDP port {} to this machine on your router, and allow it \
+                 through the firewall. Some routers don't loop connections from inside back \
+                 in, so check from another network to be sure. We can still connect out to \
+                 peers, just not to those who can't be reached either",
+                config.port
+            ),
+        )),
+    }
+}
+
+async fn check_dht(config: &ClientConfig, report: &mut Report) {
+    if !config.dht {
+        report.findings.push(Finding::ok(
+            "dht",
+            String::from("Not checked, as the DHT is turned off"),
+        ));
+        return;
+    }
+    let ip = config
+        .bind_address
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let dht = match Dht::bind(&[SocketAddr::new(ip, config.port)], None, None).await {
+        Ok(dht) => dht,
+        Err(e) => {
+            report.findings.push(Finding::failure(
+                "dht",
+                format!("Can't start a DHT node on UDP port {}: {}", config.port, e),
+                "Stop whatever else is using the port, or pick another with --port",
+            ));
+            return;
+        }
+    };
+    match timeout(DHT_TIMEOUT, dht.bootstrap(DEFAULT_ROUTERS)).await {
+        Ok(Ok(())) => report.findings.push(Finding::ok(
+            "dht",
+            format!("Joined the DHT with {} nodes", dht.node_count()),
+        )),
+        result => {
+            let reason = match result {
+                Ok(Err(e)) => e.to_string(),
+                _ => format!("timed out after {:?}", DHT_TIMEOUT),
+            };
+            report.findings.push(Finding::failure(
+                "dht",
+                format!("Couldn't join the DHT: {}", reason),
+                format!(
+                    "Check a firewall isn't blocking UDP on port {}, and that DNS works for {}",
+                    config.port, DEFAULT_ROUTERS[0]
+                ),
+            ));
+        }
+    }
+}
+
+fn mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::Output;
+
+    #[test]
+    fn render_findings_with_hints() {
+        let report = Report {
+            findings: vec![
+                Finding::ok("disk", String::from("Can write to /tmp")),
+                Finding::failure("port", String::from("Can't listen"), "Pick another"),
+            ],
+        };
+        assert!(!report.passed());
+        assert_eq!(
+            report.render(false),
+            "[  ok] disk: Can write to /tmp\n[FAIL] port: Can't listen\n       hint: Pick another\n"
+        );
+        assert!(report.render(true).contains("\x1b[1;31mFAIL\x1b[0m"));
+    }
+
+    #[tokio::test]
+    async fn diagnose_without_the_network() {
+        let root = std::env::temp_dir().join(format!("doctor-test-{}", std::process::id()));
+        let config = ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(&root)
+        };
+        let magnet = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&tr=http://127.0.0.1:1/announce";
+        let report = diagnose(&config, &magnet.parse().unwrap()).await;
+
+        let levels: Vec<_> = report.findings.iter().map(|f| (f.check, f.level)).collect();
+        assert_eq!(
+            levels,
+            vec![
+                ("torrent", Level::Ok),
+                ("disk", Level::Ok),
+                ("tracker", Level::Failure),
+                ("port", Level::Warning),
+                ("dht", Level::Ok),
+            ]
+        );
+        assert!(report.findings[2]
+            .hint
+            .as_ref()
+            .unwrap()
+            .contains("--allow-local-trackers"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
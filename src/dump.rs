@@ -0,0 +1,156 @@
+//! Rendering any bencoded value readably, for looking inside torrents and tracker
+//! responses. Strings which aren't printable text are shown as hex, cut short after
+//! `max_bytes` bytes so the pieces of a large torrent don't fill the screen.
+
+use serde_bencode::value::Value;
+use std::fmt::Write;
+
+/// Bytes of binary strings shown by default.
+pub const DEFAULT_MAX_BYTES: usize = 32;
+
+/// Render `value` as indented text, one dictionary entry or list item to a line, with
+/// dictionary keys in order.
+pub fn dump(value: &Value, max_bytes: usize) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value, 0, max_bytes);
+    out.push('\n');
+    out
+}
+
+fn write_value(out: &mut String, value: &Value, depth: usize, max_bytes: usize) {
+    let indent = "  ".repeat(depth + 1);
+    match value {
+        Value::Int(n) => write!(out, "{}", n).unwrap(),
+        Value::Bytes(bytes) => out.push_str(&render_bytes(bytes, max_bytes)),
+        Value::List(items) if items.is_empty() => out.push_str("[]"),
+        Value::List(items) => {
+            out.push_str("[\n");
+            for item in items {
+                out.push_str(&indent);
+                write_value(out, item, depth + 1, max_bytes);
+                out.push('\n');
+            }
+            write!(out, "{}]", "  ".repeat(depth)).unwrap();
+        }
+        Value::Dict(entries) if entries.is_empty() => out.push_str("{}"),
+        Value::Dict(entries) => {
+            let mut entries: Vec<_> = entries.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.push_str("{\n");
+            for (key, value) in entries {
+                let key = match text(key) {
+                    Some(key) => key.to_owned(),
+                    None => render_bytes(key, max_bytes),
+                };
+                write!(out, "{}{}: ", indent, key).unwrap();
+                write_value(out, value, depth + 1, max_bytes);
+                out.push('\n');
+            }
+            write!(out, "{}}}", "  ".repeat(depth)).unwrap();
+        }
+    }
+}
+
+/// A string as quoted text if it's printable, or as hex otherwise.
+fn render_bytes(bytes: &[u8], max_bytes: usize) -> String {
+    match text(bytes) {
+        Some(text) => format!("{:?}", text),
+        None => format!("<{} bytes: {}>", bytes.len(), hex(bytes, max_bytes)),
+    }
+}
+
+/// The bytes as text, if they're UTF-8 without control characters other than
+/// whitespace.
+fn text(bytes: &[u8]) -> Option<&str> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    text.chars()
+        .all(|c| !c.is_control() || c.is_whitespace())
+        .then_some(text)
+}
+
+/// Hex of the first `max_bytes` bytes, with an ellipsis if there are more.
+fn hex(bytes: &[u8], max_bytes: usize) -> String {
+    let mut hex: String = bytes
+        .iter()
+        .take(max_bytes)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if bytes.len() > max_bytes {
+        hex.push('…');
+    }
+    hex
+}
+
+/// `value` as JSON. Strings which aren't printable text become objects with their
+/// length and hex, so they can't be mistaken for text.
+#[cfg(feature = "engine")]
+pub fn to_json(value: &Value, max_bytes: usize) -> serde_json::Value {
+    use serde_json::json;
+
+    let bytes = |bytes: &[u8]| match text(bytes) {
+        Some(text) => json!(text),
+        None => json!({ "length": bytes.len(), "hex": hex(bytes, max_bytes) }),
+    };
+    match value {
+        Value::Int(n) => json!(n),
+        Value::Bytes(b) => bytes(b),
+        Value::List(items) => items.iter().map(|v| to_json(v, max_bytes)).collect(),
+        Value::Dict(entries) => serde_json::Value::Object(
+            entries
+                .iter()
+                .map(|(key, value)| {
+                    let key = match text(key) {
+                        Some(key) => key.to_owned(),
+                        None => hex(key, usize::MAX),
+                    };
+                    (key, to_json(value, max_bytes))
+                })
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn torrent() -> Value {
+        serde_bencode::from_bytes(
+            b"d8:announce20:http://tracker.a/ann4:infod6:lengthi5e4:name4:file12:piece lengthi16384e6:pieces4:\x00\x01\x02\xffe5:tiersll1:ael1:beee",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn dump_binary_strings_as_hex() {
+        let expected = r#"{
+  announce: "http://tracker.a/ann"
+  info: {
+    length: 5
+    name: "file"
+    piece length: 16384
+    pieces: <4 bytes: 0001…>
+  }
+  tiers: [
+    [
+      "a"
+    ]
+    [
+      "b"
+    ]
+  ]
+}
+"#;
+        assert_eq!(dump(&torrent(), 2), expected);
+        assert!(dump(&torrent(), DEFAULT_MAX_BYTES).contains("<4 bytes: 000102ff>"));
+    }
+
+    #[cfg(feature = "engine")]
+    #[test]
+    fn dump_as_json() {
+        let json = to_json(&torrent(), DEFAULT_MAX_BYTES);
+        assert_eq!(json["info"]["name"], "file");
+        assert_eq!(json["info"]["pieces"]["hex"], "000102ff");
+        assert_eq!(json["tiers"][1][0], "b");
+    }
+}
@@ -0,0 +1,219 @@
+//! Editing .torrent files. The file is edited as plain bencode, rather than through
+//! [`TorrentFile`](crate::torrent_file::TorrentFile), so keys this crate doesn't know
+//! about survive, and the info dictionary stays byte for byte the same unless it's
+//! edited, keeping the info hash.
+
+use crate::info_hash::info_bytes;
+use anyhow::anyhow;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+
+/// Keys which [`TorrentEditor::strip`] removes, as clients don't need them.
+const STRIPPED_KEYS: [&[u8]; 4] = [b"comment", b"created by", b"creation date", b"encoding"];
+
+#[derive(Debug, Clone)]
+pub struct TorrentEditor {
+    dict: HashMap<Vec<u8>, Value>,
+    /// The info dictionary's bytes as they were read, which are written out as they
+    /// are until it's edited.
+    raw_info: Option<Vec<u8>>,
+}
+
+impl TorrentEditor {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        match serde_bencode::from_bytes(bytes)? {
+            Value::Dict(dict) if matches!(dict.get(&b"info"[..]), Some(Value::Dict(_))) => {
+                Ok(Self {
+                    dict,
+                    raw_info: Some(info_bytes(bytes)?.to_vec()),
+                })
+            }
+            _ => Err(anyhow!("Not a torrent: there's no info dictionary")),
+        }
+    }
+
+    /// Encode the edited torrent as a .torrent file, with the info dictionary as it
+    /// was read unless it was edited.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut keys: Vec<_> = self.dict.keys().collect();
+        keys.sort();
+        let mut bytes = vec![b'd'];
+        for key in keys {
+            bytes.extend_from_slice(format!("{}:", key.len()).as_bytes());
+            bytes.extend_from_slice(key);
+            match &self.raw_info {
+                Some(info) if key == b"info" => bytes.extend_from_slice(info),
+                _ => bytes.extend(serde_bencode::to_bytes(&self.dict[key])?),
+            }
+        }
+        bytes.push(b'e');
+
+        Ok(bytes)
+    }
+
+    /// The SHA-1 hash of the info dictionary as it stands, which changes with any edit
+    /// to it.
+    pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
+        let info = match &self.raw_info {
+            Some(info) => info.clone(),
+            None => serde_bencode::to_bytes(&self.dict[&b"info"[..]])?,
+        };
+        Ok(Sha1::digest(&info).into())
+    }
+
+    /// Announce to tiers of trackers instead, or to none if `tiers` is empty.
+    pub fn set_trackers(&mut self, tiers: Vec<Vec<String>>) {
+        let tiers: Vec<Vec<String>> = tiers.into_iter().filter(|t| !t.is_empty()).collect();
+        match tiers.iter().flatten().next() {
+            Some(first) => {
+                self.set(b"announce", Some(bytes(first)));
+                let list = tiers
+                    .iter()
+                    .map(|tier| Value::List(tier.iter().map(|t| bytes(t)).collect()))
+                    .collect();
+                self.set(b"announce-list", Some(Value::List(list)));
+            }
+            None => {
+                self.set(b"announce", None);
+                self.set(b"announce-list", None);
+            }
+        }
+    }
+
+    pub fn set_comment(&mut self, comment: Option<&str>) {
+        self.set(b"comment", comment.map(bytes));
+    }
+
+    /// Set or unset the private flag (BEP 27). This edits the info dictionary, so
+    /// changes the info hash.
+    pub fn set_private(&mut self, private: bool) {
+        self.set_info(b"private", private.then_some(Value::Int(1)));
+    }
+
+    /// Rename the torrent's file, or its directory of files. This edits the info
+    /// dictionary, so changes the info hash.
+    pub fn set_name(&mut self, name: &str) {
+        self.set_info(b"name", Some(bytes(name)));
+    }
+
+    /// Remove the comment, creator, creation date and encoding.
+    pub fn strip(&mut self) {
+        for key in STRIPPED_KEYS {
+            self.dict.remove(key);
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: Option<Value>) {
+        match value {
+            Some(value) => self.dict.insert(key.to_vec(), value),
+            None => self.dict.remove(key),
+        };
+    }
+
+    fn set_info(&mut self, key: &[u8], value: Option<Value>) {
+        let info = match self.dict.get_mut(&b"info"[..]) {
+            Some(Value::Dict(info)) => info,
+            _ => unreachable!("Checked for in from_bytes"),
+        };
+        let changed = match value {
+            Some(value) => info.insert(key.to_vec(), value.clone()) != Some(value),
+            None => info.remove(key).is_some(),
+        };
+        if changed {
+            self.raw_info = None;
+        }
+    }
+}
+
+fn bytes(s: &str) -> Value {
+    Value::Bytes(s.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Torrent;
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn edit_outside_the_info_dictionary_keeping_the_hash() {
+        // A key this crate doesn't parse, which must survive and count towards the hash.
+        let info = dict(vec![
+            ("name", bytes("file")),
+            ("piece length", Value::Int(16384)),
+            ("pieces", Value::Bytes(vec![0; 20])),
+            ("length", Value::Int(100)),
+            ("source", bytes("tracker.example")),
+        ]);
+        let original = serde_bencode::to_bytes(&dict(vec![
+            ("announce", bytes("http://old.example/announce")),
+            ("comment", bytes("old")),
+            ("created by", bytes("someone")),
+            ("info", info.clone()),
+        ]))
+        .unwrap();
+        let expected: [u8; 20] = Sha1::digest(&serde_bencode::to_bytes(&info).unwrap()).into();
+
+        let mut editor = TorrentEditor::from_bytes(&original).unwrap();
+        assert_eq!(editor.info_hash().unwrap(), expected);
+        editor.set_trackers(vec![
+            vec![String::from("http://a.example/announce")],
+            vec![String::from("udp://b.example:80")],
+        ]);
+        editor.set_comment(Some("new"));
+        editor.strip();
+        assert_eq!(editor.info_hash().unwrap(), expected);
+
+        let edited = editor.to_bytes().unwrap();
+        let torrent = Torrent::from_bytes(&edited).unwrap();
+        assert_eq!(
+            torrent.trackers(),
+            vec![
+                vec![String::from("http://a.example/announce")],
+                vec![String::from("udp://b.example:80")]
+            ]
+        );
+        assert_eq!(torrent.file.comment, None);
+        assert_eq!(torrent.file.created_by, None);
+        let reread = TorrentEditor::from_bytes(&edited).unwrap();
+        assert_eq!(reread.info_hash().unwrap(), expected);
+
+        editor.set_private(true);
+        editor.set_name("renamed");
+        assert_ne!(editor.info_hash().unwrap(), expected);
+        let torrent = Torrent::from_bytes(&editor.to_bytes().unwrap()).unwrap();
+        assert_eq!(torrent.file.info.private, Some(1));
+        assert_eq!(torrent.file.info.name, "renamed");
+
+        assert!(TorrentEditor::from_bytes(b"d4:name4:filee").is_err());
+    }
+
+    #[test]
+    fn keep_the_info_dictionary_as_encoded() {
+        // Keys out of order, which encoding the dictionary again would sort.
+        let info =
+            b"d4:name4:file6:lengthi5e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let original = [&b"d7:comment3:old4:info"[..], info, b"e"].concat();
+        let expected: [u8; 20] = Sha1::digest(info).into();
+
+        let mut editor = TorrentEditor::from_bytes(&original).unwrap();
+        assert_eq!(editor.info_hash().unwrap(), expected);
+        editor.set_comment(Some("new"));
+        // Setting what's already there isn't an edit.
+        editor.set_private(false);
+        editor.set_name("file");
+        assert_eq!(editor.info_hash().unwrap(), expected);
+        let edited = editor.to_bytes().unwrap();
+        assert_eq!(edited, [&b"d7:comment3:new4:info"[..], info, b"e"].concat());
+        assert_eq!(info_bytes(&edited).unwrap(), info);
+    }
+}
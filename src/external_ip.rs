@@ -0,0 +1,183 @@
+//! Working out our public address from what others tell us they see us connect from:
+//! peers' extension handshakes, trackers' responses (BEP 24) and DHT nodes' responses
+//! (BEP 42). An address only counts once several of them agree on it, so one peer
+//! can't make us believe we're somewhere we aren't.
+
+use crate::net::is_public;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How many sources must agree on an address before we believe it.
+const MIN_VOTES: usize = 3;
+/// Most sources whose votes we remember, dropping the oldest.
+const MAX_VOTERS: usize = 200;
+/// How long a vote counts for, so we notice when our address changes.
+const VOTE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Who told us our address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Voter {
+    /// A peer or DHT node at this address.
+    Ip(IpAddr),
+    /// Any of the trackers, which count as one source between them.
+    Tracker,
+}
+
+/// The addresses most sources agree we have, for each family.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Consensus {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+}
+
+impl Consensus {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.v4 == Some(ip),
+            IpAddr::V6(ip) => self.v6 == Some(ip),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExternalIp {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Each source's latest vote, and when it was cast.
+    votes: Mutex<HashMap<Voter, (IpAddr, Instant)>>,
+    consensus: watch::Sender<Consensus>,
+}
+
+impl Default for ExternalIp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExternalIp {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                votes: Default::default(),
+                consensus: watch::Sender::new(Consensus::default()),
+            }),
+        }
+    }
+
+    /// Note that `voter` sees us at `ip`. Addresses which aren't public, such as those
+    /// peers on our own network see us at, are ignored.
+    pub fn vote(&self, voter: Voter, ip: IpAddr) {
+        if !is_public(ip) {
+            return;
+        }
+        let mut votes = self.inner.votes.lock().unwrap();
+        let now = Instant::now();
+        votes.retain(|_, (_, cast)| now.duration_since(*cast) < VOTE_TTL);
+        votes.insert(voter, (ip, now));
+        if votes.len() > MAX_VOTERS {
+            let oldest = votes.iter().min_by_key(|(_, (_, cast))| *cast);
+            if let Some(&oldest) = oldest.map(|(voter, _)| voter) {
+                votes.remove(&oldest);
+            }
+        }
+
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+        for (ip, _) in votes.values() {
+            *counts.entry(*ip).or_default() += 1;
+        }
+        self.inner.consensus.send_if_modified(|consensus| {
+            let v4 = winner(&counts, consensus.v4.map(IpAddr::V4), IpAddr::is_ipv4);
+            let v6 = winner(&counts, consensus.v6.map(IpAddr::V6), IpAddr::is_ipv6);
+            let updated = Consensus {
+                v4: v4.and_then(|ip| match ip {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None,
+                }),
+                v6: v6.and_then(|ip| match ip {
+                    IpAddr::V6(ip) => Some(ip),
+                    IpAddr::V4(_) => None,
+                }),
+            };
+            let changed = updated != *consensus;
+            *consensus = updated;
+            changed
+        });
+    }
+
+    pub fn get(&self) -> Consensus {
+        *self.inner.consensus.borrow()
+    }
+
+    /// Watch the consensus change as votes come in.
+    pub fn subscribe(&self) -> watch::Receiver<Consensus> {
+        self.inner.consensus.subscribe()
+    }
+}
+
+/// The address of a family with the most votes, if it has enough. Ties go to the
+/// address we already believe.
+fn winner(
+    counts: &HashMap<IpAddr, usize>,
+    current: Option<IpAddr>,
+    family: fn(&IpAddr) -> bool,
+) -> Option<IpAddr> {
+    let (&ip, &count) = counts
+        .iter()
+        .filter(|(ip, _)| family(ip))
+        .max_by_key(|(&ip, &count)| (count, Some(ip) == current, ip))?;
+
+    (count >= MIN_VOTES).then_some(ip)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer(last: u8) -> Voter {
+        Voter::Ip(IpAddr::V4(Ipv4Addr::new(198, 51, 100, last)))
+    }
+
+    #[test]
+    fn agree_on_an_address() {
+        let external_ip = ExternalIp::new();
+        let ours: IpAddr = "203.0.113.7".parse().unwrap();
+        let liar: IpAddr = "203.0.113.66".parse().unwrap();
+        let changes = external_ip.subscribe();
+
+        external_ip.vote(peer(1), ours);
+        external_ip.vote(Voter::Tracker, ours);
+        external_ip.vote(peer(2), liar);
+        // Peers on our network see our private address.
+        external_ip.vote(peer(3), "192.168.1.2".parse().unwrap());
+        assert_eq!(external_ip.get(), Consensus::default());
+        assert!(!changes.has_changed().unwrap());
+
+        // A source only gets one vote, however often it tells us.
+        external_ip.vote(peer(1), ours);
+        assert_eq!(external_ip.get(), Consensus::default());
+        external_ip.vote(peer(4), ours);
+        assert!(external_ip.get().contains(ours));
+        assert!(changes.has_changed().unwrap());
+
+        // It takes more votes than ours has to move us.
+        for last in 5..7 {
+            external_ip.vote(peer(last), liar);
+        }
+        assert!(external_ip.get().contains(ours));
+        external_ip.vote(peer(7), liar);
+        assert!(external_ip.get().contains(liar));
+
+        let v6: IpAddr = "2001:db8::7".parse().unwrap();
+        for last in 10..13 {
+            external_ip.vote(peer(last), v6);
+        }
+        assert!(external_ip.get().contains(v6));
+        assert!(external_ip.get().contains(liar));
+    }
+}
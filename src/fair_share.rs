@@ -0,0 +1,244 @@
+//! Sharing what all torrents draw on, such as peer connections and memory for pieces,
+//! in proportion to each torrent's weight. Whatever is free goes to whoever asks for it,
+//! except that room is kept for a torrent which has been turned away while others use
+//! more than it for their weight, so one busy torrent can't starve the rest.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::time::{self, Duration, Instant};
+
+/// How long a torrent which was turned away counts as waiting. Torrents which still
+/// want units ask again well within it.
+const WAITING_FOR: Duration = Duration::from_secs(10);
+
+/// Units of a resource, such as connections or bytes, shared between weighted accounts.
+#[derive(Debug, Clone)]
+pub struct FairShare {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    capacity: usize,
+    in_use: usize,
+    accounts: HashMap<u64, Account>,
+    next_id: u64,
+    /// How many grants have been released, so whoever is waiting can ask again.
+    released: watch::Sender<u64>,
+}
+
+#[derive(Debug)]
+struct Account {
+    weight: usize,
+    held: usize,
+    /// How many units the account was last turned away for, and when.
+    waiting: Option<(usize, Instant)>,
+}
+
+impl Account {
+    /// Whether the account holds less than `other` for its weight.
+    fn is_behind(&self, other: &Account) -> bool {
+        self.held * other.weight < other.held * self.weight
+    }
+}
+
+impl FairShare {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                capacity,
+                in_use: 0,
+                accounts: HashMap::new(),
+                next_id: 0,
+                released: watch::Sender::new(0),
+            })),
+        }
+    }
+
+    /// An account taking units in proportion to `weight`, such as a torrent's. It's
+    /// closed when every clone of it is dropped.
+    pub fn share(&self, weight: u32) -> Share {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.accounts.insert(
+            id,
+            Account {
+                weight: weight.max(1) as usize,
+                held: 0,
+                waiting: None,
+            },
+        );
+
+        Share {
+            inner: Arc::new(ShareInner {
+                id,
+                state: Arc::clone(&self.state),
+            }),
+        }
+    }
+}
+
+/// One account's claim on a [`FairShare`].
+#[derive(Debug, Clone)]
+pub struct Share {
+    inner: Arc<ShareInner>,
+}
+
+#[derive(Debug)]
+struct ShareInner {
+    id: u64,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for ShareInner {
+    fn drop(&mut self) {
+        // Grants hold the share, so nothing is still held by now.
+        self.state.lock().unwrap().accounts.remove(&self.id);
+    }
+}
+
+impl Share {
+    /// Take `units` if they're free, and no account which is behind this one is waiting
+    /// for them. Units can always be taken while none are in use, so requests larger
+    /// than the capacity can still be met.
+    pub fn try_acquire(&self, units: usize) -> Option<Grant> {
+        let now = Instant::now();
+        let mut state = self.inner.state.lock().unwrap();
+        let State {
+            capacity,
+            in_use,
+            accounts,
+            ..
+        } = &mut *state;
+        let ours = &accounts[&self.inner.id];
+        let fits = *in_use == 0 || *in_use + units <= *capacity;
+        let deferred = accounts.iter().any(|(&id, other)| match other.waiting {
+            Some((wanted, since)) => {
+                id != self.inner.id
+                    && now.duration_since(since) < WAITING_FOR
+                    && other.is_behind(ours)
+                    && *in_use + units + wanted > *capacity
+            }
+            None => false,
+        });
+
+        let ours = accounts.get_mut(&self.inner.id).unwrap();
+        if !fits || deferred {
+            ours.waiting = Some((units, now));
+            return None;
+        }
+        ours.waiting = None;
+        ours.held += units;
+        *in_use += units;
+        drop(state);
+
+        Some(self.grant(units))
+    }
+
+    /// Take `units` once they can be had.
+    pub async fn acquire(&self, units: usize) -> Grant {
+        let mut released = self.inner.state.lock().unwrap().released.subscribe();
+        loop {
+            released.mark_unchanged();
+            if let Some(grant) = self.try_acquire(units) {
+                return grant;
+            }
+            // Ask again now and then, so we keep counting as waiting.
+            let _ = time::timeout(WAITING_FOR / 2, released.changed()).await;
+        }
+    }
+
+    /// Take `units` even if that goes over the capacity.
+    pub fn force(&self, units: usize) -> Grant {
+        let mut state = self.inner.state.lock().unwrap();
+        state.accounts.get_mut(&self.inner.id).unwrap().held += units;
+        state.in_use += units;
+        drop(state);
+
+        self.grant(units)
+    }
+
+    fn grant(&self, units: usize) -> Grant {
+        Grant {
+            share: self.clone(),
+            units,
+        }
+    }
+}
+
+/// Units taken from a [`Share`], released when dropped.
+#[derive(Debug)]
+pub struct Grant {
+    share: Share,
+    units: usize,
+}
+
+impl Drop for Grant {
+    fn drop(&mut self) {
+        let mut state = self.share.inner.state.lock().unwrap();
+        state.in_use -= self.units;
+        if let Some(account) = state.accounts.get_mut(&self.share.inner.id) {
+            account.held -= self.units;
+        }
+        state.released.send_modify(|count| *count += 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keep_room_for_torrents_behind_their_share() {
+        let connections = FairShare::new(4);
+        let busy = connections.share(1);
+        let quiet = connections.share(1);
+
+        let mut grants: Vec<_> = (0..4).map(|_| busy.try_acquire(1).unwrap()).collect();
+        assert!(quiet.try_acquire(1).is_none());
+
+        // The busy torrent can't take back what it releases while the quiet one waits.
+        grants.pop();
+        assert!(busy.try_acquire(1).is_none());
+        let quiet_grant = quiet.try_acquire(1).unwrap();
+        grants.pop();
+        grants.push(busy.try_acquire(1).unwrap());
+        assert!(quiet.try_acquire(1).is_none());
+        drop(quiet_grant);
+    }
+
+    #[test]
+    fn share_by_weight() {
+        let memory = FairShare::new(4);
+        let heavy = memory.share(3);
+        let light = memory.share(1);
+
+        let mut grants: Vec<_> = (0..4).map(|_| heavy.try_acquire(1).unwrap()).collect();
+        assert!(light.try_acquire(1).is_none());
+        grants.pop();
+        let _light = light.try_acquire(1).unwrap();
+
+        // With a quarter, the light torrent has its share, so no room is kept for it.
+        assert!(light.try_acquire(1).is_none());
+        grants.pop();
+        assert!(heavy.try_acquire(1).is_some());
+    }
+
+    #[tokio::test]
+    async fn wait_for_units_to_be_released() {
+        let connections = FairShare::new(1);
+        let share = connections.share(1);
+        let grant = share.try_acquire(1).unwrap();
+
+        let waiter = tokio::spawn({
+            let share = share.clone();
+            async move { share.acquire(1).await }
+        });
+        tokio::task::yield_now().await;
+        drop(grant);
+        let _grant = waiter.await.unwrap();
+        assert!(share.try_acquire(1).is_none());
+    }
+}
@@ -0,0 +1,197 @@
+//! Getting a torrent's metainfo from a file, a URL or a magnet link.
+
+use crate::dht::Dht;
+use crate::magnet::Magnet;
+use crate::net::SocketOptions;
+use crate::peer::fetch_metadata;
+use crate::tracker::{Announcer, Transfer};
+use crate::Torrent;
+use anyhow::anyhow;
+use futures::StreamExt;
+use reqwest::{redirect, Url};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use tokio::time::Duration;
+use tracing::{debug, info};
+
+/// The largest .torrent file we'll download.
+const MAX_TORRENT_SIZE: usize = 10 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many peers we ask for metadata at once.
+const METADATA_PEERS: usize = 8;
+/// What we tell trackers we have left before we know the torrent's size. Anything other
+/// than zero stops them treating us as a seed.
+const UNKNOWN_LEFT: u64 = 16 * 1024;
+
+/// Where to get a torrent's metainfo from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentSource {
+    File(PathBuf),
+    Url(Url),
+    Magnet(Magnet),
+}
+
+impl std::str::FromStr for TorrentSource {
+    type Err = anyhow::Error;
+
+    /// Parse an `http(s)://` URL, a magnet link or 40-character hex info hash, or
+    /// otherwise a path to a .torrent file.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(Self::Url(Url::parse(s)?))
+        } else if s.starts_with("magnet:")
+            || (s.len() == 40 && s.bytes().all(|b| b.is_ascii_hexdigit()))
+        {
+            Ok(Self::Magnet(s.parse()?))
+        } else {
+            Ok(Self::File(s.into()))
+        }
+    }
+}
+
+impl std::fmt::Display for TorrentSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Url(url) => write!(f, "{}", url),
+            Self::Magnet(magnet) => write!(f, "{}", magnet.to_uri()),
+        }
+    }
+}
+
+/// Read, download or fetch from peers the metainfo of the torrent `source` refers to,
+/// connecting from `bind_address` if there is one, with `socket`'s options for peers.
+/// Magnets' peers are looked up on `dht` as well as with their trackers.
+pub async fn resolve(
+    source: &TorrentSource,
+    peer_id: &[u8; 20],
+    port: u16,
+    bind_address: Option<IpAddr>,
+    socket: &SocketOptions,
+    dht: Option<&Dht>,
+) -> anyhow::Result<Torrent> {
+    match source {
+        TorrentSource::File(path) => Torrent::from_bytes(&tokio::fs::read(path).await?),
+        TorrentSource::Url(url) => fetch_url(url, bind_address).await,
+        TorrentSource::Magnet(magnet) => {
+            fetch_magnet(magnet, peer_id, port, bind_address, socket, dht).await
+        }
+    }
+}
+
+/// Download a .torrent file over HTTP(S), following a few redirects.
+pub async fn fetch_url(url: &Url, bind_address: Option<IpAddr>) -> anyhow::Result<Torrent> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(anyhow!("Can't download torrents over {}", url.scheme()));
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(redirect::Policy::limited(MAX_REDIRECTS))
+        .local_address(bind_address)
+        .build()?;
+    let mut response = client.get(url.clone()).send().await?.error_for_status()?;
+    if response.content_length().unwrap_or(0) > MAX_TORRENT_SIZE as u64 {
+        return Err(anyhow!("Torrent file at {} is too large", url));
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if bytes.len() + chunk.len() > MAX_TORRENT_SIZE {
+            return Err(anyhow!("Torrent file at {} is too large", url));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Torrent::from_bytes(&bytes).map_err(|e| anyhow!("{} isn't a valid torrent: {}", url, e))
+}
+
+/// Find peers with the magnet's trackers, and on `dht` if there is one, and fetch the
+/// torrent's info dictionary from the first which will give it to us.
+pub async fn fetch_magnet(
+    magnet: &Magnet,
+    peer_id: &[u8; 20],
+    port: u16,
+    bind_address: Option<IpAddr>,
+    socket: &SocketOptions,
+    dht: Option<&Dht>,
+) -> anyhow::Result<Torrent> {
+    if magnet.trackers.is_empty() && dht.is_none() {
+        return Err(anyhow!(
+            "Magnet link has no trackers, and there's no DHT to find peers with"
+        ));
+    }
+
+    let mut peers = Vec::new();
+    if !magnet.trackers.is_empty() {
+        let tiers = magnet.trackers.iter().map(|t| vec![t.clone()]).collect();
+        let transfer = Transfer {
+            left: UNKNOWN_LEFT,
+            ..Default::default()
+        };
+        let announced = Announcer::from_trackers(tiers)?
+            .with_bind_address(bind_address)?
+            .announce_info_hash(&magnet.info_hash, transfer, peer_id, port)
+            .await;
+        match announced {
+            Ok(response) => peers.extend(response.peers.iter().map(|peer| peer.addr())),
+            // The DHT may still find peers.
+            Err(e) if dht.is_some() => debug!("Couldn't announce magnet: {}", e),
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(dht) = dht {
+        for addr in dht.get_peers(magnet.info_hash).await {
+            if !peers.contains(&addr) {
+                peers.push(addr);
+            }
+        }
+    }
+    info!("Fetching metadata from {} peers", peers.len());
+
+    let info_hash = magnet.info_hash;
+    let mut attempts = futures::stream::iter(peers)
+        .map(|addr| async move {
+            (
+                addr,
+                fetch_metadata(addr, &info_hash, peer_id, bind_address, socket).await,
+            )
+        })
+        .buffer_unordered(METADATA_PEERS);
+    while let Some((addr, result)) = attempts.next().await {
+        match result {
+            Ok(info) => return Torrent::from_info(&info, magnet.trackers.clone()),
+            Err(e) => debug!("Couldn't get metadata from {}: {}", addr, e),
+        }
+    }
+
+    Err(anyhow!(
+        "None of the magnet's peers sent us the torrent's metadata"
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_sources() {
+        let hash = "c9e15763f722f23e98a29decdfae341b98d53056";
+
+        assert!(matches!(
+            "https://example.com/file.torrent".parse().unwrap(),
+            TorrentSource::Url(_)
+        ));
+        assert!(matches!(hash.parse().unwrap(), TorrentSource::Magnet(_)));
+        assert!(matches!(
+            format!("magnet:?xt=urn:btih:{}", hash).parse().unwrap(),
+            TorrentSource::Magnet(_)
+        ));
+        assert_eq!(
+            "file.torrent".parse::<TorrentSource>().unwrap(),
+            TorrentSource::File("file.torrent".into())
+        );
+        assert!("magnet:?xt=urn:btih:nope".parse::<TorrentSource>().is_err());
+    }
+}
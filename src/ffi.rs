@@ -0,0 +1,460 @@
+//! A C API for embedding the client in programs written in other languages, such as a
+//! GUI in Swift or Kotlin. It wraps the [blocking](crate::blocking) client, so none of
+//! its functions are async. Build it as a shared library with
+//! `cargo rustc --release --features ffi --crate-type cdylib`; `include/torrent.h`
+//! declares what's here.
+//!
+//! Torrents are referred to by their 20 byte info hashes, which stay the same across
+//! runs. Functions which can fail return 0 on success and -1 on failure, when
+//! [`torrent_last_error`] describes what went wrong. Passing null for any pointer they
+//! take, other than where it's said to be allowed, fails.
+
+use crate::blocking::Client;
+use crate::client::{ClientConfig, TorrentState};
+use crate::options::AddTorrentOptions;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often torrents' states are checked for changes to report to the callback.
+const EVENT_POLL: Duration = Duration::from_millis(250);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// A torrent's state, as reported to C.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TorrentCState {
+    Paused = 0,
+    Downloading = 1,
+    Complete = 2,
+    Seeding = 3,
+    Failed = 4,
+    /// The torrent was removed from the client. Only passed to the callback.
+    Removed = 5,
+    Queued = 6,
+}
+
+impl From<&TorrentState> for TorrentCState {
+    fn from(state: &TorrentState) -> Self {
+        match state {
+            TorrentState::Paused => Self::Paused,
+            TorrentState::Queued => Self::Queued,
+            TorrentState::Downloading => Self::Downloading,
+            TorrentState::Complete => Self::Complete,
+            TorrentState::Seeding => Self::Seeding,
+            TorrentState::Failed(_) => Self::Failed,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TorrentCStatus {
+    pub state: TorrentCState,
+    /// How much of the torrent we have, from 0 to 1.
+    pub progress: f64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bytes a second.
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub peers: u32,
+}
+
+/// Called with a torrent's info hash and its new state whenever it changes, on a
+/// thread of the client's own.
+pub type TorrentCallback =
+    extern "C" fn(user_data: *mut c_void, info_hash: *const u8, state: TorrentCState);
+
+struct Callback {
+    callback: TorrentCallback,
+    user_data: *mut c_void,
+}
+
+// Safety: whoever registers the callback promises it can be called from any thread
+// with their user data.
+unsafe impl Send for Callback {}
+
+/// A client, as handed out to C.
+pub struct TorrentClient {
+    client: Client,
+    events: Option<Events>,
+}
+
+/// The thread watching for state changes to report to a callback.
+struct Events {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl TorrentClient {
+    fn new(config: ClientConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: Client::new(config)?,
+            events: None,
+        })
+    }
+
+    fn stop_events(&mut self) {
+        if let Some(events) = self.events.take() {
+            events.stop.store(true, Ordering::Relaxed);
+            let _ = events.thread.join();
+        }
+    }
+}
+
+impl Drop for TorrentClient {
+    fn drop(&mut self) {
+        self.stop_events();
+    }
+}
+
+/// Report each torrent's state to `callback` when it changes, until told to stop.
+fn watch_states(client: Client, callback: Callback, stop: Arc<AtomicBool>) {
+    let mut states: HashMap<[u8; 20], TorrentCState> = HashMap::new();
+    while !stop.load(Ordering::Relaxed) {
+        let mut current: HashMap<_, _> = client
+            .torrents()
+            .iter()
+            .map(|handle| (handle.info_hash(), TorrentCState::from(&handle.state())))
+            .collect();
+        for (info_hash, &state) in &current {
+            if states.get(info_hash) != Some(&state) {
+                (callback.callback)(callback.user_data, info_hash.as_ptr(), state);
+            }
+        }
+        for info_hash in states.keys().filter(|h| !current.contains_key(*h)) {
+            let removed = TorrentCState::Removed;
+            (callback.callback)(callback.user_data, info_hash.as_ptr(), removed);
+        }
+        std::mem::swap(&mut states, &mut current);
+        std::thread::sleep(EVENT_POLL);
+    }
+}
+
+fn set_last_error(e: anyhow::Error) {
+    let message =
+        CString::new(e.to_string().replace('\0', "")).expect("Error message has no nul bytes");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// 0 if `result` is Ok, otherwise remember its error and return -1.
+fn status(result: anyhow::Result<()>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> anyhow::Result<&'a str> {
+    if s.is_null() {
+        return Err(anyhow::anyhow!("Unexpected null string"));
+    }
+    Ok(CStr::from_ptr(s).to_str()?)
+}
+
+unsafe fn client_arg<'a>(client: *const TorrentClient) -> anyhow::Result<&'a TorrentClient> {
+    client
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Unexpected null client"))
+}
+
+fn out_arg<T>(out: *mut T) -> anyhow::Result<*mut T> {
+    if out.is_null() {
+        return Err(anyhow::anyhow!("Unexpected null output"));
+    }
+    Ok(out)
+}
+
+unsafe fn info_hash_arg(info_hash: *const u8) -> anyhow::Result<[u8; 20]> {
+    if info_hash.is_null() {
+        return Err(anyhow::anyhow!("Unexpected null info hash"));
+    }
+    Ok(*(info_hash as *const [u8; 20]))
+}
+
+/// Describes the last error on this thread, or null if there hasn't been one. The
+/// string is valid until the next call which fails on this thread.
+#[no_mangle]
+pub extern "C" fn torrent_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => std::ptr::null(),
+    })
+}
+
+/// Start a client saving torrents in `save_path`, listening for peers on `port`, or
+/// null if it couldn't start. Free it with [`torrent_client_free`].
+///
+/// # Safety
+///
+/// `save_path` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_client_new(
+    save_path: *const c_char,
+    port: u16,
+) -> *mut TorrentClient {
+    let client = str_arg(save_path).and_then(|save_path| {
+        TorrentClient::new(ClientConfig {
+            port,
+            ..ClientConfig::new(PathBuf::from(save_path))
+        })
+    });
+    match client {
+        Ok(client) => Box::into_raw(Box::new(client)),
+        Err(e) => {
+            set_last_error(e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Stop a client and all its torrents.
+///
+/// # Safety
+///
+/// `client` must have come from [`torrent_client_new`] and not been freed, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_client_free(client: *mut TorrentClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Call `callback` with `user_data` whenever a torrent's state changes, replacing
+/// any callback already set, or stop calling one if `callback` is null. The callback
+/// isn't called again once this returns.
+///
+/// # Safety
+///
+/// `client` must be a live client or null. `callback` must be safe to call from another
+/// thread with `user_data` until it's replaced or the client is freed.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_client_set_callback(
+    client: *mut TorrentClient,
+    callback: Option<TorrentCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    let client = match client.as_mut() {
+        Some(client) => client,
+        None => return status(Err(anyhow::anyhow!("Unexpected null client"))),
+    };
+    client.stop_events();
+    let callback = match callback {
+        Some(callback) => Callback {
+            callback,
+            user_data,
+        },
+        None => return 0,
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let watched = client.client.clone();
+    let thread_stop = Arc::clone(&stop);
+    let thread = std::thread::spawn(move || watch_states(watched, callback, thread_stop));
+    client.events = Some(Events { stop, thread });
+    0
+}
+
+/// Add the torrent `source` refers to, such as the path to a .torrent file or a magnet
+/// link, writing its info hash to `info_hash_out`. Paused torrents don't start until
+/// they're resumed.
+///
+/// # Safety
+///
+/// `client` must be a live client or null, `source` a nul-terminated string and
+/// `info_hash_out` must have room for 20 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_add(
+    client: *const TorrentClient,
+    source: *const c_char,
+    paused: bool,
+    info_hash_out: *mut u8,
+) -> c_int {
+    status(client_arg(client).and_then(|client| {
+        let source = str_arg(source)?;
+        let info_hash_out = out_arg(info_hash_out)?;
+        let options = AddTorrentOptions {
+            paused,
+            ..Default::default()
+        };
+        let handle = client.client.add(&source.parse()?, options)?;
+        let info_hash = handle.info_hash();
+        std::ptr::copy_nonoverlapping(info_hash.as_ptr(), info_hash_out, info_hash.len());
+        Ok(())
+    }))
+}
+
+/// Stop a torrent and forget it, leaving its content on disk.
+///
+/// # Safety
+///
+/// `client` must be a live client or null and `info_hash` must point to 20 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_remove(
+    client: *const TorrentClient,
+    info_hash: *const u8,
+) -> c_int {
+    status(client_arg(client).and_then(|client| client.client.remove(&info_hash_arg(info_hash)?)))
+}
+
+/// # Safety
+///
+/// `client` must be a live client or null and `info_hash` must point to 20 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_pause(
+    client: *const TorrentClient,
+    info_hash: *const u8,
+) -> c_int {
+    with_torrent(client, info_hash, |handle| handle.pause())
+}
+
+/// # Safety
+///
+/// `client` must be a live client or null and `info_hash` must point to 20 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_resume(
+    client: *const TorrentClient,
+    info_hash: *const u8,
+) -> c_int {
+    with_torrent(client, info_hash, |handle| handle.resume())
+}
+
+/// Write how the torrent is getting on to `status_out`.
+///
+/// # Safety
+///
+/// `client` must be a live client or null, `info_hash` must point to 20 bytes and `status_out`
+/// to a `TorrentCStatus`.
+#[no_mangle]
+pub unsafe extern "C" fn torrent_status(
+    client: *const TorrentClient,
+    info_hash: *const u8,
+    status_out: *mut TorrentCStatus,
+) -> c_int {
+    with_torrent(client, info_hash, |handle| {
+        let status_out = out_arg(status_out)?;
+        let status = handle.status();
+        *status_out = TorrentCStatus {
+            state: TorrentCState::from(&handle.state()),
+            progress: status.progress,
+            downloaded: status.downloaded,
+            uploaded: status.uploaded,
+            download_rate: status.download_rate,
+            upload_rate: status.upload_rate,
+            peers: status.peers.len() as u32,
+        };
+        Ok(())
+    })
+}
+
+unsafe fn with_torrent(
+    client: *const TorrentClient,
+    info_hash: *const u8,
+    f: impl FnOnce(&crate::blocking::TorrentHandle) -> anyhow::Result<()>,
+) -> c_int {
+    status(client_arg(client).and_then(|client| {
+        let handle = client
+            .client
+            .get(&info_hash_arg(info_hash)?)
+            .ok_or_else(|| anyhow::anyhow!("No such torrent"))?;
+        f(&handle)
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::Output;
+    use std::sync::{mpsc, Mutex};
+
+    extern "C" fn send_state(user_data: *mut c_void, _: *const u8, state: TorrentCState) {
+        let tx = unsafe { &*(user_data as *const Mutex<mpsc::Sender<TorrentCState>>) };
+        let _ = tx.lock().unwrap().send(state);
+    }
+
+    #[test]
+    fn pause_and_remove_over_c_abi() {
+        let root = std::env::temp_dir().join(format!("ffi-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let content = vec![7; 100];
+        std::fs::write(root.join("ffi"), &content).unwrap();
+        let torrent = crate::testing::torrent("ffi", &content, 64);
+        let info_hash = torrent.info_hash;
+
+        let client = TorrentClient::new(ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(&root)
+        })
+        .unwrap();
+        client.client.seed(torrent, &root).unwrap().wait().unwrap();
+        let client = Box::into_raw(Box::new(client));
+        let (tx, rx) = mpsc::channel::<TorrentCState>();
+        let tx = Mutex::new(tx);
+        let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        unsafe {
+            let user_data = &tx as *const _ as *mut c_void;
+            assert_eq!(
+                torrent_client_set_callback(client, Some(send_state), user_data),
+                0
+            );
+            assert_eq!(next(), TorrentCState::Seeding);
+
+            // Null pointers fail rather than being followed.
+            let null_client = std::ptr::null_mut();
+            assert_eq!(
+                torrent_client_set_callback(null_client, None, user_data),
+                -1
+            );
+            assert_eq!(torrent_pause(null_client, info_hash.as_ptr()), -1);
+            assert_eq!(torrent_pause(client, std::ptr::null()), -1);
+            let source = CString::new("file.torrent").unwrap();
+            assert_eq!(
+                torrent_add(client, source.as_ptr(), false, std::ptr::null_mut()),
+                -1
+            );
+            let error = CStr::from_ptr(torrent_last_error());
+            assert_eq!(error.to_str().unwrap(), "Unexpected null output");
+            assert_eq!(
+                torrent_status(client, info_hash.as_ptr(), std::ptr::null_mut()),
+                -1
+            );
+
+            assert_eq!(torrent_pause(client, info_hash.as_ptr()), 0);
+            assert_eq!(next(), TorrentCState::Paused);
+            let mut status = std::mem::MaybeUninit::uninit();
+            assert_eq!(
+                torrent_status(client, info_hash.as_ptr(), status.as_mut_ptr()),
+                0
+            );
+            let status = status.assume_init();
+            assert_eq!(status.state, TorrentCState::Paused);
+            assert_eq!(status.progress, 1.0);
+
+            assert_eq!(torrent_remove(client, info_hash.as_ptr()), 0);
+            assert_eq!(next(), TorrentCState::Removed);
+            assert_eq!(torrent_resume(client, info_hash.as_ptr()), -1);
+            let error = CStr::from_ptr(torrent_last_error());
+            assert_eq!(error.to_str().unwrap(), "No such torrent");
+
+            torrent_client_free(client);
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
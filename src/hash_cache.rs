@@ -0,0 +1,254 @@
+//! Remembering which pieces of a torrent have been verified on disk, so they don't need
+//! hashing again until the files change.
+
+use crate::bitfield::BitfieldMut;
+use crate::magnet::hex;
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+
+/// Identifies a version of a file's content without reading it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Fingerprint {
+    length: u64,
+    /// Modification time, in nanoseconds since the Unix epoch.
+    mtime: u64,
+}
+
+impl Fingerprint {
+    /// Stands in for a file which doesn't exist.
+    const MISSING: Self = Self {
+        length: 0,
+        mtime: 0,
+    };
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    bitfield: ByteBuf,
+    files: Vec<Fingerprint>,
+}
+
+/// Bytes stored for each hash of a merkle torrent's tree: its offset, then the hash.
+const TREE_RECORD_SIZE: usize = 8 + 20;
+
+/// Verified bitfields stored as one bencoded file per torrent, named by info hash.
+#[derive(Debug, Clone)]
+pub struct HashCache {
+    dir: PathBuf,
+}
+
+impl HashCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, info_hash: &[u8; 20]) -> PathBuf {
+        self.dir.join(format!("{}.verified", hex(info_hash)))
+    }
+
+    fn tree_path(&self, info_hash: &[u8; 20]) -> PathBuf {
+        self.dir.join(format!("{}.tree", hex(info_hash)))
+    }
+
+    /// The bitfield of verified pieces, if the torrent's files haven't changed since
+    /// it was stored.
+    pub async fn load(&self, info_hash: &[u8; 20], storage: &Storage) -> Option<Vec<u8>> {
+        let bytes = fs::read(self.path(info_hash)).await.ok()?;
+        let entry: Entry = serde_bencode::from_bytes(&bytes).ok()?;
+
+        if fingerprints(storage).await == entry.files {
+            Some(entry.bitfield.into_vec())
+        } else {
+            None
+        }
+    }
+
+    /// Remember that the pieces in `bitfield` are intact in the torrent's files as they
+    /// are now. Pieces of files which are missing aren't.
+    pub async fn store(
+        &self,
+        info_hash: &[u8; 20],
+        storage: &Storage,
+        bitfield: &[u8],
+    ) -> anyhow::Result<()> {
+        let files = fingerprints(storage).await;
+        let mut bitfield = bitfield.to_vec();
+        for (index, _) in files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| **file == Fingerprint::MISSING)
+        {
+            for idx in storage.file_pieces(index) {
+                bitfield.unset_piece(idx);
+            }
+        }
+        let entry = Entry {
+            bitfield: ByteBuf::from(bitfield),
+            files,
+        };
+
+        fs::create_dir_all(&self.dir).await?;
+        let path = self.path(info_hash);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_bencode::to_bytes(&entry)?).await?;
+        fs::rename(tmp, path).await?;
+
+        Ok(())
+    }
+
+    /// The hashes of a merkle torrent's tree stored by [`HashCache::store_tree`], as
+    /// (offset in the tree, hash) pairs. These don't depend on the files, so they're
+    /// kept when the files change.
+    pub async fn load_tree(&self, info_hash: &[u8; 20]) -> Vec<(usize, [u8; 20])> {
+        let bytes = fs::read(self.tree_path(info_hash))
+            .await
+            .unwrap_or_default();
+        bytes
+            .chunks_exact(TREE_RECORD_SIZE)
+            .map(|record| {
+                let (offset, hash) = record.split_at(8);
+                let offset = u64::from_be_bytes(offset.try_into().unwrap());
+                (offset as usize, hash.try_into().unwrap())
+            })
+            .collect()
+    }
+
+    /// Remember hashes of a merkle torrent's tree which have been proven against its
+    /// root, so pieces on disk can be checked one by one later. Those stored before are
+    /// kept, since they still hold for pieces which have gone missing since.
+    pub async fn store_tree(
+        &self,
+        info_hash: &[u8; 20],
+        hashes: &[(usize, [u8; 20])],
+    ) -> anyhow::Result<()> {
+        let mut tree: BTreeMap<_, _> = self.load_tree(info_hash).await.into_iter().collect();
+        tree.extend(hashes.iter().copied());
+        let mut bytes = Vec::with_capacity(tree.len() * TREE_RECORD_SIZE);
+        for (offset, hash) in &tree {
+            bytes.extend_from_slice(&(*offset as u64).to_be_bytes());
+            bytes.extend_from_slice(hash);
+        }
+
+        fs::create_dir_all(&self.dir).await?;
+        let path = self.tree_path(info_hash);
+        let tmp = path.with_extension("tree-tmp");
+        fs::write(&tmp, bytes).await?;
+        fs::rename(tmp, path).await?;
+
+        Ok(())
+    }
+
+    /// Forget the torrent's verified pieces and hash tree, if any are stored.
+    pub async fn remove(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        for path in [self.path(info_hash), self.tree_path(info_hash)] {
+            match fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn fingerprints(storage: &Storage) -> Vec<Fingerprint> {
+    let mut fingerprints = Vec::new();
+    for file in storage.files().iter() {
+        fingerprints.push(
+            fingerprint(&file.path)
+                .await
+                .unwrap_or(Fingerprint::MISSING),
+        );
+    }
+
+    fingerprints
+}
+
+async fn fingerprint(path: &Path) -> Option<Fingerprint> {
+    let metadata = fs::metadata(path).await.ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(Fingerprint {
+        length: metadata.len(),
+        mtime: mtime.as_nanos() as u64,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent_file::{Info, TorrentFile};
+    use crate::Torrent;
+    use std::time::{Duration, SystemTime};
+
+    fn torrent() -> Torrent {
+        TorrentFile {
+            info: Info {
+                name: "file".into(),
+                pieces: ByteBuf::from(vec![0; 20]),
+                piece_length: 4,
+                md5sum: None,
+                length: Some(4),
+                files: None,
+                private: None,
+                path: None,
+                root_hash: None,
+                meta_version: None,
+                file_tree: None,
+            },
+            announce: None,
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            piece_layers: None,
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn invalidate_when_files_change() {
+        let root = std::env::temp_dir().join(format!("hash-cache-test-{}", std::process::id()));
+        let torrent = torrent();
+        let storage = Storage::new(&torrent, &root.join("data")).unwrap();
+        let cache = HashCache::new(root.join("state"));
+
+        // Pieces of missing files aren't trusted, and the file turning up changes it.
+        cache
+            .store(&torrent.info_hash, &storage, &[0x80])
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.load(&torrent.info_hash, &storage).await,
+            Some(vec![0])
+        );
+
+        storage.write_piece(0, b"data").await.unwrap();
+        assert_eq!(cache.load(&torrent.info_hash, &storage).await, None);
+        cache
+            .store(&torrent.info_hash, &storage, &[0x80])
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.load(&torrent.info_hash, &storage).await,
+            Some(vec![0x80])
+        );
+
+        let file = std::fs::File::options()
+            .write(true)
+            .open(root.join("data").join("file"))
+            .unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(cache.load(&torrent.info_hash, &storage).await, None);
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+use crate::magnet::hex;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookEvent {
+    Added,
+    Complete,
+    Error,
+    /// One of the torrent's files has been saved in full.
+    FileCompleted,
+}
+
+impl std::fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Added => "added",
+            Self::Complete => "complete",
+            Self::Error => "error",
+            Self::FileCompleted => "file_completed",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+/// Details about a torrent which are passed to hook commands as environment variables.
+#[derive(Debug, Clone)]
+pub struct HookContext {
+    pub name: String,
+    pub info_hash: [u8; 20],
+    pub save_path: PathBuf,
+    pub size: u64,
+    pub error: Option<String>,
+    /// The index and path of the file a [`HookEvent::FileCompleted`] is for.
+    pub file: Option<(usize, PathBuf)>,
+}
+
+impl HookContext {
+    fn env(&self, event: HookEvent) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("TORRENT_EVENT", event.to_string()),
+            ("TORRENT_NAME", self.name.clone()),
+            ("TORRENT_INFO_HASH", hex(&self.info_hash)),
+            ("TORRENT_SAVE_PATH", self.save_path.display().to_string()),
+            ("TORRENT_SIZE", self.size.to_string()),
+        ];
+
+        if let Some(error) = &self.error {
+            env.push(("TORRENT_ERROR", error.clone()));
+        }
+        if let Some((index, path)) = &self.file {
+            env.push(("TORRENT_FILE_INDEX", index.to_string()));
+            env.push(("TORRENT_FILE_PATH", path.display().to_string()));
+        }
+
+        env
+    }
+}
+
+/// Shell commands to run when a torrent reaches a point in its lifecycle.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    pub on_added: Option<String>,
+    pub on_complete: Option<String>,
+    pub on_error: Option<String>,
+    pub on_file_completed: Option<String>,
+}
+
+impl Hooks {
+    pub fn command(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Added => self.on_added.as_deref(),
+            HookEvent::Complete => self.on_complete.as_deref(),
+            HookEvent::Error => self.on_error.as_deref(),
+            HookEvent::FileCompleted => self.on_file_completed.as_deref(),
+        }
+    }
+
+    /// Run the hook for `event` through `sh -c`, if one is configured, and wait for it to exit.
+    #[tracing::instrument(skip(self))]
+    pub async fn run(
+        &self,
+        event: HookEvent,
+        ctx: &HookContext,
+    ) -> anyhow::Result<Option<ExitStatus>> {
+        let command = match self.command(event) {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+
+        debug!("Running {} hook: {}", event, command);
+
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(ctx.env(event))
+            .status()
+            .await?;
+
+        if !status.success() {
+            warn!("{} hook exited with {}", event, status);
+        }
+
+        Ok(Some(status))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn context() -> HookContext {
+        HookContext {
+            name: String::from("ubuntu.iso"),
+            info_hash: [0xab; 20],
+            save_path: PathBuf::from("/downloads"),
+            size: 1024,
+            error: None,
+            file: None,
+        }
+    }
+
+    #[test]
+    fn hook_env_contains_torrent_details() {
+        let env = context().env(HookEvent::Complete);
+
+        assert!(env.contains(&("TORRENT_EVENT", String::from("complete"))));
+        assert!(env.contains(&("TORRENT_NAME", String::from("ubuntu.iso"))));
+        assert!(env.contains(&("TORRENT_INFO_HASH", "ab".repeat(20))));
+        assert!(env.contains(&("TORRENT_SAVE_PATH", String::from("/downloads"))));
+        assert!(env.contains(&("TORRENT_SIZE", String::from("1024"))));
+        assert!(!env.iter().any(|(k, _)| *k == "TORRENT_ERROR"));
+
+        let ctx = HookContext {
+            file: Some((2, PathBuf::from("/downloads/ubuntu/README"))),
+            ..context()
+        };
+        let env = ctx.env(HookEvent::FileCompleted);
+        assert!(env.contains(&("TORRENT_EVENT", String::from("file_completed"))));
+        assert!(env.contains(&("TORRENT_FILE_INDEX", String::from("2"))));
+        assert!(env.contains(&(
+            "TORRENT_FILE_PATH",
+            String::from("/downloads/ubuntu/README")
+        )));
+    }
+
+    #[tokio::test]
+    async fn run_hook_with_env() {
+        let hooks = Hooks {
+            on_error: Some(String::from("test \"$TORRENT_ERROR\" = \"boom\"")),
+            ..Default::default()
+        };
+        let ctx = HookContext {
+            error: Some(String::from("boom")),
+            ..context()
+        };
+
+        let status = hooks.run(HookEvent::Error, &ctx).await.unwrap().unwrap();
+        assert!(status.success());
+
+        let skipped = hooks.run(HookEvent::Added, &ctx).await.unwrap();
+        assert!(skipped.is_none());
+    }
+}
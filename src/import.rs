@@ -0,0 +1,571 @@
+//! Taking over torrents from other clients by reading their resume data, so a seedbox
+//! can move to this client without hashing everything it holds again. Each torrent is
+//! added to the session store with the other client's save path, and the pieces it had
+//! verified go in the hash cache, which [`crate::Client::restore`] trusts as long as
+//! the files haven't changed since.
+
+use crate::bitfield::{Bitfield, BitfieldMut};
+use crate::hash_cache::HashCache;
+use crate::magnet::hex;
+use crate::options::AddTorrentOptions;
+use crate::picker::{Priority, BLOCK_SIZE};
+use crate::session_store::{SessionStore, StoredState, TorrentRecord};
+use crate::storage::{paths, Storage};
+use crate::Torrent;
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::path::{Path, PathBuf};
+
+/// The clients whose resume data can be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportSource {
+    /// qBittorrent's `BT_backup` directory, holding a `.fastresume` and a `.torrent`
+    /// file for each torrent.
+    QBittorrent,
+    /// Transmission's config directory, holding `resume` and `torrents` directories.
+    Transmission,
+}
+
+impl std::str::FromStr for ImportSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "qbittorrent" => Ok(Self::QBittorrent),
+            "transmission" => Ok(Self::Transmission),
+            _ => Err(anyhow!(
+                "Unknown client: {}, expected qbittorrent or transmission",
+                s
+            )),
+        }
+    }
+}
+
+/// A torrent read from another client's resume data.
+#[derive(Debug)]
+pub struct Imported {
+    pub torrent: Torrent,
+    /// The torrent's .torrent file.
+    pub metainfo: Vec<u8>,
+    pub save_path: PathBuf,
+    /// The pieces the other client had verified.
+    pub have: Vec<u8>,
+    /// Where the other client saved each of the torrent's files instead, relative to
+    /// the save path, for files renamed there, in the order of [`Storage::files`].
+    pub renamed: Vec<Option<PathBuf>>,
+    pub options: AddTorrentOptions,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Seconds spent seeding.
+    pub seeding_time: u64,
+}
+
+impl Imported {
+    pub fn is_complete(&self) -> bool {
+        (0..self.torrent.file.info.piece_count()).all(|idx| self.have.has_piece(idx))
+    }
+}
+
+/// Read every torrent in the other client's resume data under `dir`. Torrents which
+/// can't be read are given as errors, without stopping the rest being read.
+pub fn read(source: ImportSource, dir: &Path) -> anyhow::Result<Vec<anyhow::Result<Imported>>> {
+    let (resume_dir, torrent_dir, extension) = match source {
+        ImportSource::QBittorrent => (dir.to_path_buf(), dir.to_path_buf(), "fastresume"),
+        ImportSource::Transmission => (dir.join("resume"), dir.join("torrents"), "resume"),
+    };
+    let mut paths: Vec<_> = std::fs::read_dir(&resume_dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<anyhow::Result<_>>()?;
+    paths.retain(|path| path.extension().is_some_and(|e| e == extension));
+    paths.sort();
+
+    Ok(paths
+        .iter()
+        .map(|path| {
+            // The .torrent file is named like the resume file.
+            let metainfo_path = torrent_dir
+                .join(path.file_name().expect("Read from a directory"))
+                .with_extension("torrent");
+            let import = || {
+                let resume = std::fs::read(path)?;
+                let metainfo = std::fs::read(&metainfo_path)?;
+                match source {
+                    ImportSource::QBittorrent => from_qbittorrent(&resume, metainfo),
+                    ImportSource::Transmission => {
+                        let imported = from_transmission(&resume, metainfo)?;
+                        check_transmission_name(path, &imported.torrent)?;
+                        Ok(imported)
+                    }
+                }
+            };
+            import().map_err(|e| anyhow!("Couldn't import {}: {}", path.display(), e))
+        })
+        .collect())
+}
+
+/// Add `imported` to `store`, and its verified pieces to the hash cache in `state_dir`.
+/// Files the other client renamed are hard linked to where the torrent has them. Pieces
+/// of files which are missing aren't trusted, and files which change before the torrent
+/// is restored have it hashed again.
+pub async fn register(
+    imported: &Imported,
+    store: &SessionStore,
+    state_dir: &Path,
+) -> anyhow::Result<()> {
+    let torrent = &imported.torrent;
+    if store.torrent(&torrent.info_hash)?.is_some() {
+        return Err(anyhow!("{} has already been added", torrent.file.info.name));
+    }
+    let storage = Storage::new(torrent, &imported.save_path)?;
+    for (file, renamed) in storage.files().iter().zip(&imported.renamed) {
+        if let Some(renamed) = renamed {
+            link_renamed(&imported.save_path, renamed, &file.path).await?;
+        }
+    }
+    HashCache::new(state_dir.join("verified"))
+        .store(&torrent.info_hash, &storage, &imported.have)
+        .await?;
+    store.save_torrent(&TorrentRecord {
+        info_hash: torrent.info_hash,
+        metainfo: imported.metainfo.clone(),
+        save_path: imported.save_path.clone(),
+        state: match imported.is_complete() {
+            true => StoredState::Seeding,
+            false => StoredState::Downloading,
+        },
+        downloaded: imported.downloaded,
+        uploaded: imported.uploaded,
+        seeding_time: imported.seeding_time,
+        options: imported.options.clone(),
+    })
+}
+
+/// Hard link a file the other client saved at `renamed`, under `save_path`, to `path`,
+/// where the torrent has it, leaving the other client's copy alone.
+async fn link_renamed(save_path: &Path, renamed: &Path, path: &Path) -> anyhow::Result<()> {
+    let components: Vec<String> = renamed
+        .iter()
+        .map(|component| component.to_string_lossy().into_owned())
+        .collect();
+    let from = paths::join(save_path, &components)?;
+    if from == path
+        || tokio::fs::symlink_metadata(path).await.is_ok()
+        || tokio::fs::symlink_metadata(&from).await.is_err()
+    {
+        return Ok(());
+    }
+    paths::check_no_symlinks(save_path, &from).await?;
+    paths::check_no_symlinks(save_path, path).await?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::hard_link(&from, path).await.map_err(|e| {
+        anyhow!(
+            "Couldn't link renamed file {} to {}: {}",
+            from.display(),
+            path.display(),
+            e
+        )
+    })
+}
+
+/// The parts of a libtorrent resume file, with qBittorrent's additions, which carry
+/// over.
+#[derive(Debug, Deserialize)]
+struct FastResume {
+    #[serde(rename = "info-hash")]
+    info_hash: ByteBuf,
+    save_path: String,
+    /// One byte for each piece, whose lowest bit is set if we have the piece.
+    #[serde(default)]
+    pieces: Option<ByteBuf>,
+    #[serde(default)]
+    paused: i64,
+    #[serde(default)]
+    file_priority: Vec<i64>,
+    #[serde(default)]
+    total_downloaded: i64,
+    #[serde(default)]
+    total_uploaded: i64,
+    #[serde(default)]
+    seeding_time: i64,
+    /// Each file's path relative to the save path, given when any were renamed.
+    #[serde(default)]
+    mapped_files: Vec<String>,
+    #[serde(rename = "qBt-category", default)]
+    category: Option<String>,
+    #[serde(rename = "qBt-name", default)]
+    name: Option<String>,
+}
+
+fn from_qbittorrent(resume: &[u8], metainfo: Vec<u8>) -> anyhow::Result<Imported> {
+    let resume: FastResume = serde_bencode::from_bytes(resume)?;
+    let torrent = Torrent::from_bytes(&metainfo)?;
+    if resume.info_hash.as_slice() != torrent.info_hash {
+        return Err(anyhow!("Resume data is for another torrent"));
+    }
+    let piece_count = torrent.file.info.piece_count();
+    let mut have = vec![0; piece_count.div_ceil(8)];
+    let pieces = resume.pieces.unwrap_or_default();
+    for (idx, _) in pieces
+        .iter()
+        .enumerate()
+        .filter(|(_, &piece)| piece & 1 != 0)
+    {
+        if idx < piece_count {
+            have.set_piece(idx);
+        }
+    }
+    // libtorrent's priorities go from 0 to 7, with 4 the default.
+    let priorities = resume.file_priority.iter().map(|&priority| match priority {
+        0 => Priority::Skip,
+        1..=4 => Priority::Normal,
+        _ => Priority::High,
+    });
+    // libtorrent's own save path is where the files are now. qBittorrent's is where
+    // they go once they're complete, which differs while they're in its temp folder.
+    let save_path = resume.save_path;
+    let renamed = without_padding(&torrent, resume.mapped_files.into_iter())
+        .into_iter()
+        .map(|path| {
+            let path: PathBuf = path.split(['/', '\\']).filter(|c| !c.is_empty()).collect();
+            Some(path).filter(|path| !path.as_os_str().is_empty())
+        })
+        .collect();
+
+    Ok(Imported {
+        options: AddTorrentOptions {
+            save_path: Some(PathBuf::from(&save_path)),
+            name: resume.name.filter(|name| !name.is_empty()),
+            paused: resume.paused != 0,
+            file_priorities: file_priorities(&torrent, priorities),
+            category: resume.category.filter(|category| !category.is_empty()),
+            ..Default::default()
+        },
+        torrent,
+        metainfo,
+        save_path: PathBuf::from(save_path),
+        have,
+        renamed,
+        downloaded: resume.total_downloaded.max(0) as u64,
+        uploaded: resume.total_uploaded.max(0) as u64,
+        seeding_time: resume.seeding_time.max(0) as u64,
+    })
+}
+
+/// The parts of a Transmission resume file which carry over.
+#[derive(Debug, Deserialize)]
+struct TransmissionResume {
+    destination: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    paused: i64,
+    #[serde(default)]
+    progress: Option<Progress>,
+    /// For each file, 1 if it isn't wanted.
+    #[serde(default)]
+    dnd: Vec<i64>,
+    /// For each file, -1 for low priority, 0 for normal and 1 for high.
+    #[serde(default)]
+    priority: Vec<i64>,
+    #[serde(default)]
+    labels: Vec<String>,
+    #[serde(default)]
+    downloaded: i64,
+    #[serde(default)]
+    uploaded: i64,
+    #[serde(rename = "seeding-time-seconds", default)]
+    seeding_time: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Progress {
+    /// `all` or `none`, or a bitfield of the torrent's blocks.
+    #[serde(default)]
+    blocks: Option<ByteBuf>,
+    /// `all` in older versions, once every piece was had.
+    #[serde(default)]
+    have: Option<ByteBuf>,
+    /// A bitfield of the pieces had, in older versions.
+    #[serde(default)]
+    bitfield: Option<ByteBuf>,
+}
+
+fn from_transmission(resume: &[u8], metainfo: Vec<u8>) -> anyhow::Result<Imported> {
+    let resume: TransmissionResume = serde_bencode::from_bytes(resume)?;
+    let torrent = Torrent::from_bytes(&metainfo)?;
+    let info = &torrent.file.info;
+    let piece_count = info.piece_count();
+    let mut have = vec![0; piece_count.div_ceil(8)];
+    let has_block = |blocks: &[u8], block: u64| {
+        blocks.len() as u64 > block / 8 && blocks.has_piece(block as usize)
+    };
+    match resume.progress {
+        Some(Progress {
+            have: Some(all), ..
+        }) if all.as_slice() == b"all" => {
+            (0..piece_count).for_each(|idx| have.set_piece(idx));
+        }
+        Some(Progress {
+            blocks: Some(blocks),
+            ..
+        }) => {
+            let (piece_length, total_length) = (info.piece_length as u64, info.total_length());
+            let block_size = BLOCK_SIZE as u64;
+            for idx in 0..piece_count {
+                let begin = idx as u64 * piece_length;
+                let end = (begin + piece_length).min(total_length);
+                let complete = match blocks.as_slice() {
+                    b"all" => true,
+                    b"none" => false,
+                    blocks => (begin / block_size..end.div_ceil(block_size))
+                        .all(|block| has_block(blocks, block)),
+                };
+                if complete {
+                    have.set_piece(idx);
+                }
+            }
+        }
+        Some(Progress {
+            bitfield: Some(bitfield),
+            ..
+        }) => {
+            for idx in (0..piece_count).filter(|&idx| has_block(&bitfield, idx as u64)) {
+                have.set_piece(idx);
+            }
+        }
+        _ => {}
+    }
+    let priorities = (0..resume.dnd.len().max(resume.priority.len())).map(|i| {
+        match (resume.dnd.get(i), resume.priority.get(i)) {
+            (Some(1), _) => Priority::Skip,
+            (_, Some(1)) => Priority::High,
+            _ => Priority::Normal,
+        }
+    });
+
+    Ok(Imported {
+        options: AddTorrentOptions {
+            save_path: Some(PathBuf::from(&resume.destination)),
+            name: resume.name.filter(|name| *name != info.name),
+            paused: resume.paused != 0,
+            file_priorities: file_priorities(&torrent, priorities),
+            category: resume.labels.into_iter().next(),
+            ..Default::default()
+        },
+        save_path: PathBuf::from(resume.destination),
+        have,
+        renamed: Vec::new(),
+        downloaded: resume.downloaded.max(0) as u64,
+        uploaded: resume.uploaded.max(0) as u64,
+        seeding_time: resume.seeding_time.max(0) as u64,
+        torrent,
+        metainfo,
+    })
+}
+
+/// Check the resume file at `path` is for `torrent`. Transmission's resume files don't
+/// hold the info hash, but are named by it: in full, or by its first 16 hex digits
+/// after the torrent's name in older versions.
+fn check_transmission_name(path: &Path, torrent: &Torrent) -> anyhow::Result<()> {
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+    let named = stem.rsplit('.').next().unwrap_or_default();
+    if !matches!(named.len(), 16 | 40) || !named.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(anyhow!("Resume file isn't named by an info hash"));
+    }
+    if !hex(&torrent.info_hash).starts_with(&named.to_ascii_lowercase()) {
+        return Err(anyhow!("Resume data is for another torrent"));
+    }
+
+    Ok(())
+}
+
+/// Something given for every file in the torrent, as other clients give them, without
+/// those for padding files, which we don't count.
+fn without_padding<T>(torrent: &Torrent, items: impl Iterator<Item = T>) -> Vec<T> {
+    match &torrent.file.info.files {
+        Some(files) => files
+            .iter()
+            .zip(items)
+            .filter(|(file, _)| !file.is_padding())
+            .map(|(_, item)| item)
+            .collect(),
+        None => items.take(1).collect(),
+    }
+}
+
+/// File priorities given for every file in the torrent, see [`without_padding`].
+fn file_priorities(torrent: &Torrent, priorities: impl Iterator<Item = Priority>) -> Vec<Priority> {
+    let priorities = without_padding(torrent, priorities);
+    // All normal is the same as none given.
+    match priorities.iter().all(|&p| p == Priority::Normal) {
+        true => Vec::new(),
+        false => priorities,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_bencode::value::Value;
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    fn bytes(s: &[u8]) -> Value {
+        Value::Bytes(s.to_vec())
+    }
+
+    /// A torrent of four pieces, of two blocks each but the last.
+    fn metainfo() -> Vec<u8> {
+        let content = vec![7; BLOCK_SIZE * 7];
+        crate::testing::torrent("imported", &content, BLOCK_SIZE * 2)
+            .to_bytes()
+            .unwrap()
+    }
+
+    #[test]
+    fn import_from_qbittorrent() {
+        let metainfo = metainfo();
+        let info_hash = Torrent::from_bytes(&metainfo).unwrap().info_hash;
+        let resume = dict(vec![
+            ("info-hash", bytes(&info_hash)),
+            ("save_path", bytes(b"/incomplete")),
+            ("qBt-savePath", bytes(b"/downloads")),
+            ("qBt-category", bytes(b"linux")),
+            ("qBt-name", bytes(b"")),
+            ("pieces", bytes(&[1, 0, 3, 1])),
+            ("paused", Value::Int(1)),
+            ("total_uploaded", Value::Int(300)),
+            ("file_priority", Value::List(vec![Value::Int(4)])),
+            ("trackers", Value::List(vec![Value::List(vec![])])),
+            (
+                "mapped_files",
+                Value::List(vec![bytes(b"renamed\\imported.bin")]),
+            ),
+        ]);
+        let resume = serde_bencode::to_bytes(&resume).unwrap();
+
+        let imported = from_qbittorrent(&resume, metainfo.clone()).unwrap();
+        assert_eq!(imported.save_path, PathBuf::from("/incomplete"));
+        assert_eq!(
+            imported.renamed,
+            vec![Some(PathBuf::from("renamed").join("imported.bin"))]
+        );
+        assert_eq!(imported.have, vec![0b1011_0000]);
+        assert!(!imported.is_complete());
+        assert_eq!(imported.uploaded, 300);
+        assert!(imported.options.paused);
+        assert_eq!(imported.options.category.as_deref(), Some("linux"));
+        assert_eq!(imported.options.name, None);
+        assert!(imported.options.file_priorities.is_empty());
+
+        let other = dict(vec![
+            ("info-hash", bytes(&[0; 20])),
+            ("save_path", bytes(b"/downloads")),
+        ]);
+        let other = serde_bencode::to_bytes(&other).unwrap();
+        assert!(from_qbittorrent(&other, metainfo).is_err());
+    }
+
+    #[test]
+    fn import_from_transmission() {
+        let resume = |progress| {
+            let resume = dict(vec![
+                ("destination", bytes(b"/downloads")),
+                ("name", bytes(b"imported")),
+                ("progress", progress),
+                ("dnd", Value::List(vec![Value::Int(0)])),
+                ("priority", Value::List(vec![Value::Int(1)])),
+                ("labels", Value::List(vec![bytes(b"tv")])),
+                ("seeding-time-seconds", Value::Int(60)),
+            ]);
+            serde_bencode::to_bytes(&resume).unwrap()
+        };
+
+        // The first three blocks and the last, so the first and last pieces.
+        let blocks = dict(vec![("blocks", bytes(&[0b1110_0010]))]);
+        let imported = from_transmission(&resume(blocks), metainfo()).unwrap();
+        assert_eq!(imported.save_path, PathBuf::from("/downloads"));
+        assert_eq!(imported.have, vec![0b1001_0000]);
+        assert_eq!(imported.seeding_time, 60);
+        assert_eq!(imported.options.name, None);
+        assert_eq!(imported.options.category.as_deref(), Some("tv"));
+        assert_eq!(imported.options.file_priorities, vec![Priority::High]);
+
+        for progress in [
+            dict(vec![("blocks", bytes(b"all"))]),
+            dict(vec![("have", bytes(b"all"))]),
+            dict(vec![("bitfield", bytes(&[0xf0]))]),
+        ] {
+            let imported = from_transmission(&resume(progress), metainfo()).unwrap();
+            assert!(imported.is_complete());
+        }
+    }
+
+    #[tokio::test]
+    async fn register_imported_torrents() {
+        let dir = std::env::temp_dir().join(format!("import-test-{}", std::process::id()));
+        let metainfo = metainfo();
+        let torrent = Torrent::from_bytes(&metainfo).unwrap();
+        let content = dir.join("content");
+        std::fs::create_dir_all(&content).unwrap();
+        // The other client renamed the file.
+        std::fs::write(content.join("renamed"), vec![7; BLOCK_SIZE * 7]).unwrap();
+        for sub in ["resume", "torrents"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let resume = dict(vec![
+            ("destination", bytes(content.to_str().unwrap().as_bytes())),
+            ("progress", dict(vec![("blocks", bytes(b"all"))])),
+        ]);
+        let resume = serde_bencode::to_bytes(&resume).unwrap();
+        // Resume files are named by the info hash, so one named for another torrent
+        // isn't for this one.
+        let own = format!("imported.{}", &hex(&torrent.info_hash)[..16]);
+        for name in [own.as_str(), "mixed.0123456789abcdef"] {
+            let resume_path = dir.join("resume").join(format!("{}.resume", name));
+            std::fs::write(resume_path, &resume).unwrap();
+            let metainfo_path = dir.join("torrents").join(format!("{}.torrent", name));
+            std::fs::write(metainfo_path, &metainfo).unwrap();
+        }
+        std::fs::write(dir.join("resume").join("broken.resume"), b"x").unwrap();
+
+        let mut results = read(ImportSource::Transmission, &dir).unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results.remove(0).is_err());
+        assert!(results.remove(1).is_err());
+        let mut imported = results.remove(0).unwrap();
+        imported.renamed = vec![Some(PathBuf::from("renamed"))];
+        let store = SessionStore::open_in_memory().unwrap();
+        let state_dir = dir.join("state");
+        register(&imported, &store, &state_dir).await.unwrap();
+        assert!(register(&imported, &store, &state_dir).await.is_err());
+
+        let record = store.torrent(&torrent.info_hash).unwrap().unwrap();
+        assert_eq!(record.save_path, content);
+        assert_eq!(record.state, StoredState::Seeding);
+        assert!(content.join("imported").exists());
+        assert!(content.join("renamed").exists());
+        // The pieces are trusted without hashing them.
+        let storage = Storage::new(&torrent, &content).unwrap();
+        let cache = HashCache::new(state_dir.join("verified"));
+        assert_eq!(
+            cache.load(&torrent.info_hash, &storage).await,
+            Some(vec![0xf0])
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+//! Working out a torrent's info hashes from whatever identifies it: a magnet link, a bare
+//! info hash, a .torrent file, or an info dictionary on its own.
+
+use crate::magnet::{hex, to_base32};
+use crate::Magnet;
+use anyhow::anyhow;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+/// How deeply lists and dictionaries in a .torrent file may be nested. A v2 torrent's
+/// file tree nests once for each directory.
+const MAX_TORRENT_DEPTH: usize = 256;
+
+/// A torrent's info hashes. v1 and hybrid torrents have a SHA-1 one, and v2 and hybrid
+/// torrents (BEP 52) a SHA-256 one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InfoHashes {
+    pub v1: Option<[u8; 20]>,
+    pub v2: Option<[u8; 32]>,
+}
+
+impl InfoHashes {
+    /// The hashes of a bencoded info dictionary, hashing the bytes exactly as given.
+    pub fn of_info(info: &[u8]) -> anyhow::Result<Self> {
+        let dict = match serde_bencode::from_bytes(info)? {
+            Value::Dict(dict) => dict,
+            _ => return Err(anyhow!("Info isn't a dictionary")),
+        };
+        let v2 = matches!(dict.get(&b"meta version"[..]), Some(Value::Int(2)));
+        let v1 = !v2 || dict.contains_key(&b"pieces"[..]);
+
+        Ok(Self {
+            v1: v1.then(|| Sha1::digest(info).into()),
+            v2: v2.then(|| Sha256::digest(info).into()),
+        })
+    }
+
+    /// The hashes of a .torrent file's info dictionary.
+    pub fn of_torrent(torrent: &[u8]) -> anyhow::Result<Self> {
+        Self::of_info(info_bytes(torrent)?)
+    }
+
+    pub fn of_magnet(magnet: &Magnet) -> Self {
+        Self {
+            v1: Some(magnet.info_hash),
+            v2: magnet.info_hash_v2,
+        }
+    }
+
+    /// The hashes of a .torrent file or an info dictionary, which are told apart by
+    /// whether there's an `info` key, or of a magnet link or bare info hash as text.
+    pub fn parse(input: &[u8]) -> anyhow::Result<Self> {
+        if input.starts_with(b"d") {
+            return match serde_bencode::from_bytes(input)? {
+                Value::Dict(dict) if dict.contains_key(&b"info"[..]) => Self::of_torrent(input),
+                _ => Self::of_info(input),
+            };
+        }
+        let text = std::str::from_utf8(input)?.trim();
+        Ok(Self::of_magnet(&text.parse()?))
+    }
+
+    pub fn v1_hex(&self) -> Option<String> {
+        self.v1.map(|hash| hex(&hash))
+    }
+
+    pub fn v1_base32(&self) -> Option<String> {
+        self.v1.map(|hash| to_base32(&hash))
+    }
+
+    pub fn v2_hex(&self) -> Option<String> {
+        self.v2.map(|hash| hex(&hash))
+    }
+
+    pub fn v2_base32(&self) -> Option<String> {
+        self.v2.map(|hash| to_base32(&hash))
+    }
+
+    /// The v2 hash truncated to 20 bytes, which v2 torrents use where v1 ones use their
+    /// info hash, such as in handshakes, the DHT and tracker announces.
+    pub fn v2_truncated(&self) -> Option<[u8; 20]> {
+        self.v2.map(|hash| hash[..20].try_into().unwrap())
+    }
+}
+
+/// The info dictionary's bytes exactly as they are in a .torrent file. Its info hash is
+/// a hash of these, which decoding and encoding it again would change if it wasn't
+/// encoded the way we'd encode it, with its keys in order.
+pub fn info_bytes(torrent: &[u8]) -> anyhow::Result<&[u8]> {
+    let invalid = || anyhow!("Not a torrent: it isn't valid bencode");
+    if torrent.first() != Some(&b'd') {
+        return Err(anyhow!("Not a torrent: it isn't a dictionary"));
+    }
+    let mut pos = 1;
+    while torrent.get(pos).ok_or_else(invalid)? != &b'e' {
+        let key_len = bencode_len(&torrent[pos..], MAX_TORRENT_DEPTH).ok_or_else(invalid)?;
+        let key = &torrent[pos..pos + key_len];
+        pos += key_len;
+        let value_len = bencode_len(&torrent[pos..], MAX_TORRENT_DEPTH).ok_or_else(invalid)?;
+        let value = &torrent[pos..pos + value_len];
+        if key == b"4:info" && value.starts_with(b"d") {
+            return Ok(value);
+        }
+        pos += value_len;
+    }
+
+    Err(anyhow!("Not a torrent: there's no info dictionary"))
+}
+
+/// Length of the bencoded value at the start of `bytes`, if it's valid and its lists
+/// and dictionaries nest no deeper than `max_depth`. They're walked without recursing,
+/// so bytes from peers can't run us out of stack.
+pub(crate) fn bencode_len(bytes: &[u8], max_depth: usize) -> Option<usize> {
+    let mut len = 0;
+    let mut depth = 0usize;
+    loop {
+        match bytes.get(len)? {
+            b'i' => len += bytes[len..].iter().position(|&b| b == b'e')? + 1,
+            b'l' | b'd' => {
+                depth += 1;
+                if depth > max_depth {
+                    return None;
+                }
+                len += 1;
+                continue;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                len += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = bytes[len..].iter().position(|&b| b == b':')?;
+                let strlen: usize = std::str::from_utf8(&bytes[len..len + colon])
+                    .ok()?
+                    .parse()
+                    .ok()?;
+                len = len.checked_add(colon + 1)?.checked_add(strlen)?;
+                if len > bytes.len() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const INFO: &[u8] =
+        b"d6:lengthi5e4:name4:file12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+
+    #[test]
+    fn hash_torrents_info_dictionaries_and_magnets() {
+        let expected: [u8; 20] = Sha1::digest(INFO).into();
+        let hashes = InfoHashes::parse(INFO).unwrap();
+        assert_eq!(hashes.v1, Some(expected));
+        assert_eq!(hashes.v2, None);
+
+        let torrent = [&b"d8:announce3:url4:info"[..], INFO, b"e"].concat();
+        assert_eq!(InfoHashes::parse(&torrent).unwrap(), hashes);
+
+        let magnet = format!("magnet:?xt=urn:btih:{}\n", hashes.v1_base32().unwrap());
+        assert_eq!(InfoHashes::parse(magnet.as_bytes()).unwrap(), hashes);
+        let bare = hashes.v1_hex().unwrap();
+        assert_eq!(InfoHashes::parse(bare.as_bytes()).unwrap(), hashes);
+
+        let hybrid = b"d12:meta versioni2e4:name4:file6:pieces0:e";
+        let hashes = InfoHashes::parse(hybrid).unwrap();
+        assert_eq!(hashes.v2, Some(Sha256::digest(hybrid).into()));
+        assert!(hashes.v1.is_some());
+        let v2_only = InfoHashes::parse(b"d12:meta versioni2e4:name4:filee").unwrap();
+        assert_eq!(v2_only.v1, None);
+        assert_eq!(
+            v2_only.v2_truncated().unwrap()[..],
+            v2_only.v2.unwrap()[..20]
+        );
+    }
+
+    #[test]
+    fn hash_info_dictionaries_as_encoded() {
+        // Keys out of order, which encoding the dictionary again would sort.
+        let info =
+            b"d4:name4:file6:lengthi5e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let torrent = [&b"d8:announce3:url4:info"[..], info, b"7:comment2:hie"].concat();
+        assert_eq!(info_bytes(&torrent).unwrap(), info);
+        let hashes = InfoHashes::parse(&torrent).unwrap();
+        assert_eq!(hashes.v1, Some(Sha1::digest(info).into()));
+
+        assert!(info_bytes(b"d8:announce3:urle").is_err());
+        assert!(info_bytes(b"d4:info").is_err());
+        assert_eq!(bencode_len(b"d1:ali1eee4:rest", 16), Some(10));
+        assert_eq!(bencode_len(b"llleee", 2), None);
+    }
+}
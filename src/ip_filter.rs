@@ -0,0 +1,276 @@
+//! Filtering peers by IP address, using lists in the formats other clients read: eMule's
+//! ipfilter.dat, PeerGuardian's P2P format, and CIDR blocks or single addresses.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use tracing::warn;
+
+/// eMule access levels below this block the range. Higher levels allow it.
+const EMULE_BLOCK_LEVEL: u32 = 128;
+
+/// Ranges of IPv4 and IPv6 addresses, such as peers to refuse to talk to, sorted and
+/// merged so a lookup is a binary search. IPv4 addresses are kept as IPv4-mapped IPv6
+/// addresses, so either form of an IPv4 peer's address matches.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpFilter {
+    ranges: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    /// Build a filter from inclusive ranges, which may overlap. Ranges from one family
+    /// to the other are left out.
+    pub fn from_ranges(ranges: impl IntoIterator<Item = (IpAddr, IpAddr)>) -> Self {
+        Self::merge(
+            ranges
+                .into_iter()
+                .filter(|(start, end)| start.is_ipv4() == end.is_ipv4())
+                .map(|(start, end)| (key(start), key(end)))
+                .collect(),
+        )
+    }
+
+    fn merge(mut ranges: Vec<(u128, u128)>) -> Self {
+        ranges.retain(|(start, end)| start <= end);
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        Self { ranges: merged }
+    }
+
+    /// Parse a blocklist, one range per line. Lines which can't be parsed are skipped.
+    pub fn parse(text: &str) -> Self {
+        let mut skipped = 0;
+        let ranges: Vec<_> = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with("//"))
+            .filter_map(|line| match parse_line(line) {
+                Ok(range) => range,
+                Err(()) => {
+                    skipped += 1;
+                    None
+                }
+            })
+            .collect();
+        if skipped > 0 {
+            warn!("Skipped {} lines of the IP filter", skipped);
+        }
+
+        Self::from_ranges(ranges)
+    }
+
+    /// Loopback, link-local and private addresses, which don't route beyond the LAN.
+    pub fn private_networks() -> Self {
+        Self::parse(
+            "127.0.0.0/8\n10.0.0.0/8\n172.16.0.0/12\n192.168.0.0/16\n169.254.0.0/16\n\
+             ::1\nfc00::/7\nfe80::/10",
+        )
+    }
+
+    /// The addresses in either filter.
+    pub fn union(&self, other: &Self) -> Self {
+        Self::merge(self.ranges.iter().chain(&other.ranges).copied().collect())
+    }
+
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = tokio::fs::read(path).await?;
+        Ok(Self::parse(&String::from_utf8_lossy(&bytes)))
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = key(ip);
+        let idx = self.ranges.partition_point(|&(start, _)| start <= ip);
+        idx > 0 && ip <= self.ranges[idx - 1].1
+    }
+
+    /// Number of separate ranges, after merging ones which overlap.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+/// Where an address sorts among the ranges.
+fn key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+/// Parse a line of a blocklist into the range it blocks, or `None` if it allows it.
+fn parse_line(line: &str) -> Result<Option<(IpAddr, IpAddr)>, ()> {
+    // eMule: "001.002.003.000 - 001.002.003.255 , 000 , Description"
+    if let Some((range, rest)) = line.split_once(',') {
+        let level: u32 = rest
+            .split(',')
+            .next()
+            .and_then(|level| level.trim().parse().ok())
+            .ok_or(())?;
+        if level >= EMULE_BLOCK_LEVEL {
+            return Ok(None);
+        }
+        return parse_range(range).map(Some);
+    }
+
+    // PeerGuardian: "Description:1.2.3.0-1.2.3.255". IPv6 addresses are full of colons
+    // too, so take the longest part after a colon which is a range.
+    let after_colons = line.match_indices(':').map(|(i, _)| &line[i + 1..]);
+    std::iter::once(line)
+        .chain(after_colons)
+        .find_map(|range| parse_block(range).ok())
+        .map(Some)
+        .ok_or(())
+}
+
+/// Parse "1.2.3.0-1.2.3.255", "1.2.3.0/24" or a single address, of either family.
+fn parse_block(block: &str) -> Result<(IpAddr, IpAddr), ()> {
+    if block.contains('-') {
+        return parse_range(block);
+    }
+    match block.split_once('/') {
+        Some((ip, bits)) => {
+            let bits: u32 = bits.trim().parse().map_err(|_| ())?;
+            match parse_ip(ip)? {
+                IpAddr::V4(ip) if bits <= 32 => {
+                    let (ip, mask) = (u32::from(ip), u32::MAX.checked_shl(32 - bits).unwrap_or(0));
+                    let (start, end) = (Ipv4Addr::from(ip & mask), Ipv4Addr::from(ip | !mask));
+                    Ok((start.into(), end.into()))
+                }
+                IpAddr::V6(ip) if bits <= 128 => {
+                    let (ip, mask) = (
+                        u128::from(ip),
+                        u128::MAX.checked_shl(128 - bits).unwrap_or(0),
+                    );
+                    let (start, end) = (Ipv6Addr::from(ip & mask), Ipv6Addr::from(ip | !mask));
+                    Ok((start.into(), end.into()))
+                }
+                _ => Err(()),
+            }
+        }
+        None => {
+            let ip = parse_ip(block)?;
+            Ok((ip, ip))
+        }
+    }
+}
+
+fn parse_range(range: &str) -> Result<(IpAddr, IpAddr), ()> {
+    let (start, end) = range.split_once('-').ok_or(())?;
+    let (start, end) = (parse_ip(start)?, parse_ip(end)?);
+    if start.is_ipv4() != end.is_ipv4() {
+        return Err(());
+    }
+    Ok((start, end))
+}
+
+/// Parse an IPv4 address, allowing the zero-padded octets eMule lists use, or an IPv6
+/// address.
+fn parse_ip(s: &str) -> Result<IpAddr, ()> {
+    let s = s.trim();
+    if s.contains(':') {
+        return s.parse::<Ipv6Addr>().map(IpAddr::V6).map_err(|_| ());
+    }
+    parse_ipv4(s).map(IpAddr::V4)
+}
+
+fn parse_ipv4(s: &str) -> Result<Ipv4Addr, ()> {
+    let mut octets = [0; 4];
+    let mut parts = s.split('.');
+    for octet in &mut octets {
+        *octet = parts.next().and_then(|p| p.parse().ok()).ok_or(())?;
+    }
+    if parts.next().is_some() {
+        return Err(());
+    }
+
+    Ok(octets.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn has(filter: &IpFilter, ip: &str) -> bool {
+        filter.contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_blocklist_formats() {
+        let filter = IpFilter::parse(
+            "# comment
+            001.002.003.000 - 001.002.003.255 , 000 , Blocked by eMule
+            005.000.000.000 - 005.255.255.255 , 200 , Allowed by eMule
+            Some range:10.0.0.0-10.0.0.9
+            192.168.1.0/24
+            203.0.113.7
+            2001:db8::/32
+            Some IPv6 range:2001:db9::1-2001:db9::ff
+            fe80::1 - 192.168.0.1
+            not an address",
+        );
+
+        assert!(has(&filter, "1.2.3.0"));
+        assert!(has(&filter, "1.2.3.255"));
+        assert!(!has(&filter, "1.2.4.0"));
+        assert!(!has(&filter, "5.1.1.1"));
+        assert!(has(&filter, "10.0.0.9"));
+        assert!(!has(&filter, "10.0.0.10"));
+        assert!(has(&filter, "192.168.1.77"));
+        assert!(has(&filter, "203.0.113.7"));
+        assert!(!has(&filter, "203.0.113.8"));
+        assert!(has(&filter, "::ffff:203.0.113.7"));
+        assert!(!has(&filter, "::1"));
+        assert!(has(&filter, "2001:db8:ffff::1"));
+        assert!(!has(&filter, "2001:db7::1"));
+        assert!(has(&filter, "2001:db9::ff"));
+        assert!(!has(&filter, "2001:db9::100"));
+        assert!(!has(&filter, "fe80::2"));
+        assert_eq!(filter.len(), 6);
+    }
+
+    #[test]
+    fn merge_overlapping_ranges() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let filter = IpFilter::from_ranges(vec![
+            (ip("10.0.0.5"), ip("10.0.0.20")),
+            (ip("10.0.0.0"), ip("10.0.0.9")),
+            (ip("10.0.0.21"), ip("10.0.0.30")),
+            (ip("255.255.255.0"), ip("255.255.255.255")),
+        ]);
+
+        assert_eq!(filter.len(), 2);
+        assert!(has(&filter, "10.0.0.0"));
+        assert!(has(&filter, "10.0.0.30"));
+        assert!(!has(&filter, "10.0.0.31"));
+        assert!(has(&filter, "255.255.255.255"));
+        assert!(IpFilter::parse("0.0.0.0/0").contains("8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn private_networks() {
+        let lan = IpFilter::private_networks();
+        assert!(has(&lan, "192.168.1.20"));
+        assert!(has(&lan, "172.31.255.255"));
+        assert!(!has(&lan, "172.32.0.0"));
+        assert!(!has(&lan, "8.8.8.8"));
+        assert!(has(&lan, "::1"));
+        assert!(has(&lan, "fd12:3456::1"));
+        assert!(has(&lan, "fe80::1c2:3ff:fe45:6789"));
+        assert!(!has(&lan, "2001:db8::1"));
+
+        let filter = lan.union(&IpFilter::parse("8.8.8.0/24"));
+        assert!(has(&filter, "8.8.8.8"));
+        assert!(has(&filter, "10.1.2.3"));
+    }
+}
@@ -1,7 +1,109 @@
-pub mod peer;
-mod torrent_file;
+//! A BitTorrent client. [`Client`] downloads and seeds torrents, configured with a
+//! [`ClientConfig`] and the types re-exported alongside it; how it does so is private.
+//!
+//! The parsing and editing of metainfo files and magnet links, and the info hashes
+//! computed from them (see [`info_hash`]), builds without the `engine` feature. That
+//! leaves out tokio and the networking stack, so it also builds for wasm32. The command
+//! line program needs the `cli` feature, which is on by default; libraries using the
+//! client should turn off default features and turn on `engine`.
 
-pub use peer::request_peer_info;
+pub use magnet::Magnet;
 pub use torrent_file::Torrent;
-pub mod bitfield;
-pub mod queues;
+#[cfg(feature = "engine")]
+pub use {
+    choker::SlotPolicy,
+    client::{Client, ClientConfig, Output, TorrentHandle, TorrentState},
+    fetch::TorrentSource,
+    hooks::Hooks,
+    ip_filter::IpFilter,
+    net::SocketOptions,
+    options::AddTorrentOptions,
+    peer::{HavePolicy, DEFAULT_RESERVED},
+    picker::{PickerKind, Priority},
+    seed_rules::SeedRule,
+    session_store::SessionStore,
+    stats::TorrentStatus,
+    storage::alloc::Preallocate,
+    tracker::request_peer_info,
+    verify::Verification,
+};
+
+#[cfg(feature = "engine")]
+pub(crate) mod bitfield;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "engine")]
+pub(crate) mod buffers;
+#[cfg(feature = "engine")]
+pub(crate) mod choker;
+#[cfg(feature = "engine")]
+pub mod client;
+#[cfg(feature = "engine")]
+pub mod create;
+#[cfg(feature = "engine")]
+pub(crate) mod dht;
+#[cfg(feature = "engine")]
+pub(crate) mod disk;
+#[cfg(feature = "engine")]
+pub mod doctor;
+pub mod dump;
+pub mod edit;
+#[cfg(feature = "engine")]
+pub(crate) mod external_ip;
+#[cfg(feature = "engine")]
+pub(crate) mod fair_share;
+#[cfg(feature = "engine")]
+pub mod fetch;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "engine")]
+pub(crate) mod hash_cache;
+#[cfg(feature = "engine")]
+pub(crate) mod hooks;
+#[cfg(feature = "engine")]
+pub mod import;
+pub mod info_hash;
+#[cfg(feature = "engine")]
+pub(crate) mod ip_filter;
+#[cfg(feature = "engine")]
+pub(crate) mod lsd;
+pub mod magnet;
+#[cfg(feature = "engine")]
+pub(crate) mod net;
+#[cfg(feature = "engine")]
+pub mod options;
+#[cfg(feature = "engine")]
+pub(crate) mod peer;
+#[cfg(feature = "engine")]
+pub(crate) mod picker;
+#[cfg(feature = "engine")]
+pub(crate) mod piece_hash;
+#[cfg(feature = "engine")]
+pub(crate) mod queues;
+#[cfg(feature = "engine")]
+pub(crate) mod rate_limit;
+#[cfg(feature = "engine")]
+pub mod rpc;
+#[cfg(feature = "rss")]
+pub mod rss;
+#[cfg(feature = "engine")]
+pub(crate) mod seed_rules;
+#[cfg(feature = "engine")]
+pub(crate) mod session_store;
+#[cfg(feature = "engine")]
+pub(crate) mod stats;
+#[cfg(feature = "engine")]
+pub(crate) mod storage;
+#[cfg(feature = "engine")]
+pub(crate) mod supervisor;
+#[cfg(all(feature = "engine", any(test, feature = "testing")))]
+pub mod testing;
+pub(crate) mod torrent_file;
+#[cfg(feature = "engine")]
+pub(crate) mod tracker;
+#[cfg(feature = "engine")]
+pub mod verify;
+#[cfg(feature = "engine")]
+pub(crate) mod watchdog;
+#[cfg(feature = "web")]
+pub mod web;
@@ -0,0 +1,243 @@
+//! Local service discovery (BEP 14), which finds peers on the local network by
+//! multicasting the torrents we have, so transfers between nearby machines don't need a
+//! tracker or the DHT.
+
+use crate::magnet::hex;
+use anyhow::anyhow;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio::task::AbortHandle;
+use tracing::{debug, trace};
+
+/// The multicast group and port announcements are sent to.
+pub const LSD_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+/// How many announcements to hold for torrents slow to read them.
+const FOUND_BACKLOG: usize = 64;
+/// Largest announcement we read. They're sent in one packet.
+const MAX_ANNOUNCEMENT: usize = 1400;
+
+/// A peer on the local network which announced it has the torrents with these info
+/// hashes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Found {
+    pub peer: SocketAddrV4,
+    pub info_hashes: Vec<[u8; 20]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Lsd {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    socket: Arc<UdpSocket>,
+    /// The port we accept peers on.
+    port: u16,
+    /// Tells our own announcements apart when the group loops them back to us.
+    cookie: String,
+    found: broadcast::Sender<Found>,
+    task: AbortHandle,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Lsd {
+    /// Join the group on the interface with address `interface`, or on the default
+    /// interface if it's unspecified, announcing that we accept peers on `port`.
+    pub fn bind(interface: Ipv4Addr, port: u16) -> anyhow::Result<Self> {
+        let socket = Arc::new(bind_socket(interface)?);
+        let (found, _) = broadcast::channel(FOUND_BACKLOG);
+        let cookie = hex(&rand::random::<[u8; 8]>());
+        let receive = receive(Arc::clone(&socket), cookie.clone(), found.clone());
+        debug!("Listening for local peers on {}", interface);
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                socket,
+                port,
+                cookie,
+                found,
+                task: tokio::spawn(receive).abort_handle(),
+            }),
+        })
+    }
+
+    /// Tell the local network we have the torrent with `info_hash`.
+    pub async fn announce(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let message = announcement(self.inner.port, info_hash, &self.inner.cookie);
+        self.inner
+            .socket
+            .send_to(message.as_bytes(), SocketAddr::V4(LSD_GROUP))
+            .await?;
+        Ok(())
+    }
+
+    /// Peers announced on the local network from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Found> {
+        self.inner.found.subscribe()
+    }
+}
+
+fn bind_socket(interface: Ipv4Addr) -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Other clients on this machine listen on the same port.
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), LSD_GROUP.port()).into())?;
+    socket
+        .join_multicast_v4(LSD_GROUP.ip(), &interface)
+        .map_err(|e| anyhow!("Couldn't join {}: {}", LSD_GROUP.ip(), e))?;
+    if !interface.is_unspecified() {
+        socket.set_multicast_if_v4(&interface)?;
+    }
+    // Announcements stay on the local network.
+    socket.set_multicast_ttl_v4(1)?;
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+async fn receive(socket: Arc<UdpSocket>, cookie: String, found: broadcast::Sender<Found>) {
+    let mut buf = [0; MAX_ANNOUNCEMENT];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                trace!("Couldn't receive a local announcement: {}", e);
+                continue;
+            }
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        match parse_announcement(&buf[..len]) {
+            Some(announced) if announced.cookie.as_deref() != Some(cookie.as_str()) => {
+                trace!(
+                    "{} announced {} torrents",
+                    from,
+                    announced.info_hashes.len()
+                );
+                let _ = found.send(Found {
+                    peer: SocketAddrV4::new(*from.ip(), announced.port),
+                    info_hashes: announced.info_hashes,
+                });
+            }
+            Some(_) => {}
+            None => trace!("Ignoring a malformed local announcement from {}", from),
+        }
+    }
+}
+
+/// What an announcement says.
+#[derive(Debug, PartialEq)]
+struct Announcement {
+    port: u16,
+    info_hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+fn announcement(port: u16, info_hash: &[u8; 20], cookie: &str) -> String {
+    format!(
+        "BT-SEARCH * HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Port: {}\r\n\
+         Infohash: {}\r\n\
+         cookie: {}\r\n\
+         \r\n\r\n",
+        LSD_GROUP,
+        port,
+        hex(info_hash),
+        cookie
+    )
+}
+
+fn parse_announcement(packet: &[u8]) -> Option<Announcement> {
+    let text = std::str::from_utf8(packet).ok()?;
+    let mut lines = text.split("\r\n");
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+
+    let (mut port, mut info_hashes, mut cookie) = (None, vec![], None);
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse().ok().filter(|&port| port != 0),
+            "infohash" => info_hashes.push(parse_info_hash(value)?),
+            "cookie" => cookie = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    if info_hashes.is_empty() {
+        return None;
+    }
+    Some(Announcement {
+        port: port?,
+        info_hashes,
+        cookie,
+    })
+}
+
+fn parse_info_hash(s: &str) -> Option<[u8; 20]> {
+    if s.len() != 40 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0; 20];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_announcements() {
+        let message = announcement(6881, &[0xab; 20], "c00k1e");
+        assert_eq!(
+            parse_announcement(message.as_bytes()),
+            Some(Announcement {
+                port: 6881,
+                info_hashes: vec![[0xab; 20]],
+                cookie: Some("c00k1e".into()),
+            })
+        );
+
+        // Headers are case-insensitive and may list several torrents.
+        let message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHOST: {}\r\nport: 51413\r\nINFOHASH: {}\r\ninfohash: {}\r\n\r\n\r\n",
+            LSD_GROUP,
+            "AB".repeat(20),
+            "cd".repeat(20)
+        );
+        assert_eq!(
+            parse_announcement(message.as_bytes()),
+            Some(Announcement {
+                port: 51413,
+                info_hashes: vec![[0xab; 20], [0xcd; 20]],
+                cookie: None,
+            })
+        );
+
+        for bad in [
+            "M-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: ".to_string() + &"ab".repeat(20),
+            "BT-SEARCH * HTTP/1.1\r\nPort: 0\r\nInfohash: ".to_string() + &"ab".repeat(20),
+            "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: abc".to_string(),
+            "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n".to_string(),
+            "BT-SEARCH * HTTP/1.1\r\nInfohash: ".to_string() + &"ab".repeat(20),
+        ] {
+            assert_eq!(parse_announcement(bad.as_bytes()), None, "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Magnet links, which identify a torrent by its info hash rather than its metainfo.
+
+use anyhow::anyhow;
+use url::Url;
+
+/// The multihash prefix of a SHA-256 hash: the code for SHA-256, then the length.
+const SHA256_MULTIHASH: &str = "1220";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// The SHA-256 info hash of hybrid torrents (BEP 52), which links give as well.
+    pub info_hash_v2: Option<[u8; 32]>,
+    /// Name to show until we have the torrent's metainfo.
+    pub name: Option<String>,
+    /// Trackers to find peers with, each in its own tier.
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    /// A magnet link with no name or trackers, from a hex-encoded info hash.
+    pub fn from_info_hash(hex: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            info_hash: parse_info_hash(hex)?,
+            info_hash_v2: None,
+            name: None,
+            trackers: Vec::new(),
+        })
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut url = Url::parse("magnet:").expect("magnet: is a valid URL");
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("xt", &format!("urn:btih:{}", hex(&self.info_hash)));
+            if let Some(v2) = &self.info_hash_v2 {
+                query.append_pair("xt", &format!("urn:btmh:{}{}", SHA256_MULTIHASH, hex(v2)));
+            }
+            if let Some(name) = &self.name {
+                query.append_pair("dn", name);
+            }
+            for tracker in &self.trackers {
+                query.append_pair("tr", tracker);
+            }
+        }
+
+        url.to_string()
+    }
+}
+
+impl std::str::FromStr for Magnet {
+    type Err = anyhow::Error;
+
+    /// Parse a `magnet:` URI, or a bare 40-character hex info hash.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with("magnet:") {
+            return Self::from_info_hash(s);
+        }
+
+        let url = Url::parse(s)?;
+        let mut info_hash = None;
+        let mut info_hash_v2 = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    } else if let Some(hash) = value.strip_prefix("urn:btmh:") {
+                        info_hash_v2 = Some(parse_multihash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash
+                .ok_or_else(|| anyhow!("Magnet link has no BitTorrent info hash"))?,
+            info_hash_v2,
+            name,
+            trackers,
+        })
+    }
+}
+
+/// Parse an info hash in hex, or in the base32 form some magnet links use.
+fn parse_info_hash(s: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = match s.len() {
+        40 if s.bytes().all(|b| b.is_ascii_hexdigit()) => (0..40)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+            .collect(),
+        32 => base32(s),
+        _ => None,
+    };
+
+    bytes
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| anyhow!("Invalid info hash: {}", s))
+}
+
+/// Parse a SHA-256 multihash in hex, as `urn:btmh:` gives v2 info hashes.
+fn parse_multihash(s: &str) -> anyhow::Result<[u8; 32]> {
+    let hash = s
+        .strip_prefix(SHA256_MULTIHASH)
+        .filter(|hash| hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+        .ok_or_else(|| anyhow!("Invalid v2 info hash: {}", s))?;
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hash[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(bytes)
+}
+
+fn base32(s: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Encode bytes in unpadded base32, the other form magnet links give info hashes in.
+pub(crate) fn to_base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut s = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            s.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        s.push(ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "c9e15763f722f23e98a29decdfae341b98d53056";
+
+    #[test]
+    fn parse_magnet_links() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=Cosmos+Laundromat&tr=udp%3A%2F%2Ftracker.example%3A1337",
+            HASH.to_uppercase()
+        );
+        let magnet: Magnet = uri.parse().unwrap();
+
+        assert_eq!(hex(&magnet.info_hash), HASH);
+        assert_eq!(magnet.name.as_deref(), Some("Cosmos Laundromat"));
+        assert_eq!(magnet.trackers, vec!["udp://tracker.example:1337"]);
+        assert_eq!(magnet.to_uri().parse::<Magnet>().unwrap(), magnet);
+
+        let v2 = "1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e";
+        let magnet: Magnet = format!("magnet:?xt=urn:btih:{}&xt=urn:btmh:{}", HASH, v2)
+            .parse()
+            .unwrap();
+        assert_eq!(hex(&magnet.info_hash_v2.unwrap()), v2[4..]);
+        assert_eq!(magnet.to_uri().parse::<Magnet>().unwrap(), magnet);
+    }
+
+    #[test]
+    fn parse_info_hashes() {
+        let magnet: Magnet = HASH.parse().unwrap();
+        assert_eq!(hex(&magnet.info_hash), HASH);
+        assert!(magnet.trackers.is_empty());
+
+        let base32: Magnet = "ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW".parse().unwrap();
+        assert_eq!(base32, magnet);
+        assert_eq!(
+            to_base32(&magnet.info_hash),
+            "ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW"
+        );
+
+        assert!("c9e15763f722f23e".parse::<Magnet>().is_err());
+        assert!("magnet:?dn=nothing".parse::<Magnet>().is_err());
+        assert!(HASH.replace('c', "x").parse::<Magnet>().is_err());
+    }
+}
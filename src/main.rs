@@ -1,84 +1,1382 @@
+use std::io::IsTerminal;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::mpsc::{channel, Receiver};
-use torrent::{peer::PeerSession, queues::WorkResult, request_peer_info, Torrent};
-use tracing::{debug, info};
-use tracing_subscriber::prelude::*;
+use std::time::Duration;
+use torrent::{
+    create::{MetaVersion, TorrentBuilder},
+    doctor, dump,
+    edit::TorrentEditor,
+    fetch,
+    import::{self, ImportSource},
+    info_hash::InfoHashes,
+    rpc::{self, Request, Response},
+    rss::{self, FeedRule, RssConfig},
+    web::{self, auth::Credentials, Protocol, WebConfig},
+    AddTorrentOptions, Client, ClientConfig, HavePolicy, Hooks, IpFilter, Output, PickerKind,
+    Preallocate, Priority, SeedRule, SessionStore, SlotPolicy, SocketOptions, Torrent,
+    TorrentSource, TorrentStatus, DEFAULT_RESERVED,
+};
+use tracing::{info, warn};
+use url::Url;
 
 use structopt::StructOpt;
 
 const PEER_ID: &[u8; 20] = b"-TR2940-k8hj0wgej6ch";
 const PORT: u16 = 6881;
+/// Bytes in the KiB and MiB options are given in.
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
 
+// Parsed once at startup, so its size doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, StructOpt)]
-struct Opt {
+enum Opt {
+    /// Download a torrent
+    Download(DownloadOpt),
+    /// Check content which is already on disk and seed it
+    Recheck(RecheckOpt),
+    /// Control a running client
+    Ctl(CtlOpt),
+    /// Check downloaded content against a torrent's hashes
+    Verify {
+        torrent: PathBuf,
+        /// Directory the torrent was downloaded to
+        path: PathBuf,
+    },
+    /// Make a torrent of a file or directory
+    Create(CreateOpt),
+    /// Change a .torrent file's trackers, comment, name or private flag
+    Edit(EditOpt),
+    /// Take over another client's torrents from its resume data, keeping their save paths
+    /// and the pieces it verified, to start with --state-dir
+    Import {
+        /// The client: qbittorrent or transmission
+        from: ImportSource,
+        /// qBittorrent's BT_backup directory, or Transmission's config directory
+        dir: PathBuf,
+        /// Directory this client keeps its state in
+        #[structopt(long)]
+        state_dir: PathBuf,
+    },
+    /// Show the info hashes of a magnet link, info hash, .torrent file or info dictionary
+    Hash {
+        /// A magnet link or info hash, or a file with a torrent, magnet link or info
+        /// dictionary in
+        input: String,
+    },
+    /// Show everything in a .torrent file, or any other bencoded file
+    Dump {
+        file: PathBuf,
+        /// Print JSON rather than indented text
+        #[structopt(long)]
+        json: bool,
+        /// Show all of binary strings, rather than their first bytes
+        #[structopt(long)]
+        full: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+struct EditOpt {
+    torrent: PathBuf,
+    /// Where to write the edited .torrent file
+    #[structopt(short, long)]
+    output: PathBuf,
+    /// Announce to this tracker instead, each in its own tier. Can be given more than once
+    #[structopt(long)]
+    tracker: Vec<String>,
+    /// Remove every tracker
+    #[structopt(long, conflicts_with = "tracker")]
+    no_trackers: bool,
+    #[structopt(long)]
+    comment: Option<String>,
+    /// Rename the torrent's file or directory. Changes the info hash
+    #[structopt(long)]
+    name: Option<String>,
+    /// Mark the torrent private. Changes the info hash
+    #[structopt(long)]
+    private: bool,
+    /// Unmark the torrent private. Changes the info hash
+    #[structopt(long, conflicts_with = "private")]
+    public: bool,
+    /// Remove the comment, creator, creation date and encoding
+    #[structopt(long)]
+    strip: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct CreateOpt {
+    /// File or directory to make a torrent of
+    path: PathBuf,
+    /// Where to write the .torrent file
+    #[structopt(short, long)]
+    output: PathBuf,
+    /// Name of the torrent, rather than the file or directory's name
+    #[structopt(long)]
+    name: Option<String>,
+    /// KiB in each piece, a power of two from 16 to 16384. Picked for the content's size
+    /// by default
+    #[structopt(long)]
+    piece_length: Option<u64>,
+    /// Tracker to announce to, each in its own tier. Can be given more than once
+    #[structopt(long)]
+    tracker: Vec<String>,
+    #[structopt(long)]
+    comment: Option<String>,
+    /// Only find peers through the trackers
+    #[structopt(long)]
+    private: bool,
+    /// Metadata to make: v1, v2, hybrid or merkle
+    #[structopt(long, default_value = "v1")]
+    version: MetaVersion,
+    /// Threads to hash the content on, rather than one for each CPU
+    #[structopt(long)]
+    threads: Option<usize>,
+}
+
+/// Options for running a client, shared by the commands which do.
+#[derive(Debug, StructOpt)]
+struct ClientOpt {
+    /// Our public IP address, so we don't try to connect to ourselves
+    #[structopt(long)]
+    external_ip: Option<Ipv4Addr>,
+    /// Local address to listen on and connect to peers and trackers from, such as a
+    /// VPN's. Torrents pause while no interface has it
+    #[structopt(long)]
+    bind_address: Option<IpAddr>,
+    /// Number of peers to ask the tracker for
+    #[structopt(long)]
+    numwant: Option<u32>,
+    /// Announce to trackers on this machine or a private network, which are refused
+    /// otherwise so torrents can't make requests to internal services
+    #[structopt(long)]
+    allow_local_trackers: bool,
+    /// Maximum number of connections being dialed at once
+    #[structopt(long, default_value = "20")]
+    max_half_open: usize,
+    /// Maximum number of peer connections
+    #[structopt(long, default_value = "100")]
+    max_connections: usize,
+    /// Maximum number of torrents downloading at once, queueing the rest
+    #[structopt(long)]
+    max_active_downloads: Option<usize>,
+    /// Maximum number of torrents seeding at once, queueing the rest
+    #[structopt(long)]
+    max_active_seeds: Option<usize>,
+    /// Maximum number of torrents downloading or seeding at once
+    #[structopt(long)]
+    max_active: Option<usize>,
+    /// Number of peers each torrent stays connected to, replacing those which leave
+    #[structopt(long, default_value = "40")]
+    peers_per_torrent: usize,
+    /// Maximum number of peers to upload to at once, per torrent
+    #[structopt(long, default_value = "8")]
+    upload_slots: usize,
+    /// How to share upload slots: round-robin, fastest-peer or longest-waiting
+    #[structopt(long, default_value = "fastest-peer")]
+    slot_policy: SlotPolicy,
+    /// When to tell peers about pieces we complete: all, skip-redundant or lazy
+    #[structopt(long, default_value = "skip-redundant")]
+    have_policy: HavePolicy,
+    /// How long torrents without a seed ratio seed for, such as
+    /// tracker=example.org,ratio=1.5,days=14,action=remove. Actions are pause, remove
+    /// and remove-data. Can be given more than once, and the first rule for a torrent
+    /// applies
+    #[structopt(long = "seed-rule")]
+    seed_rules: Vec<SeedRule>,
+    /// Directory to save torrents in a category in, such as tv=/media/tv. Can be given
+    /// more than once
+    #[structopt(long = "category-path", parse(try_from_str = parse_category_path))]
+    category_paths: Vec<(String, PathBuf)>,
+    /// Wait to fill packets before sending peer messages, rather than sending them
+    /// straight away
+    #[structopt(long)]
+    no_nodelay: bool,
+    /// Seconds a peer connection can be idle before the OS starts probing it
+    #[structopt(long)]
+    tcp_keepalive: Option<u64>,
+    /// KiB of kernel receive buffer for each peer connection, rather than the OS's default
+    #[structopt(long)]
+    recv_buffer: Option<u32>,
+    /// KiB of kernel send buffer for each peer connection, rather than the OS's default
+    #[structopt(long)]
+    send_buffer: Option<u32>,
+    /// Number of tasks checking each torrent's downloaded pieces
+    #[structopt(long, default_value = "2")]
+    disk_workers: usize,
+    /// MiB of downloaded pieces waiting to be saved before requests pause
+    #[structopt(long, default_value = "64")]
+    disk_queue: usize,
+    /// How to set aside space for files: none, growing them as they're written; sparse,
+    /// setting them to full size; or full, reserving all the space on disk up front
+    #[structopt(long, default_value = "sparse")]
+    preallocate: Preallocate,
+    /// MiB of memory partly downloaded pieces can take up before no more are started
+    #[structopt(long, default_value = "256")]
+    memory_budget: usize,
+    /// Command to run when the torrent is added
+    #[structopt(long)]
+    on_added: Option<String>,
+    /// Command to run when the download completes
+    #[structopt(long)]
+    on_complete: Option<String>,
+    /// Command to run when the download fails
+    #[structopt(long)]
+    on_error: Option<String>,
+    /// Command to run when each of the torrent's files has been downloaded, with its
+    /// path in TORRENT_FILE_PATH
+    #[structopt(long)]
+    on_file_completed: Option<String>,
+    /// Directory to keep state in between runs, such as added torrents and hash check
+    /// results
+    #[structopt(long)]
+    state_dir: Option<PathBuf>,
+    /// Blocklist of peer addresses in eMule .dat, P2P or CIDR format, reloaded when it
+    /// changes
+    #[structopt(long)]
+    ip_filter: Option<PathBuf>,
+    /// Only connect to and accept peers in this CIDR range. Can be given more than once
+    #[structopt(long = "allow-peers")]
+    allowed_peers: Vec<String>,
+    /// Only connect to and accept peers on private networks, which are found by local
+    /// service discovery
+    #[structopt(long, conflicts_with = "no-lsd")]
+    lan_only: bool,
+    /// Reserved handshake bits to send, as 16 hex digits, to turn extensions on or off
+    /// when testing other clients
+    #[structopt(long, parse(try_from_str = parse_reserved))]
+    reserved: Option<[u8; 8]>,
+    /// Disconnect peers which send messages we don't know, rather than ignoring them
+    #[structopt(long)]
+    strict_protocol: bool,
+    /// Log every message sent to and received from peers to this file, as JSON lines,
+    /// for reporting problems with other clients
+    #[structopt(long)]
+    wire_log: Option<PathBuf>,
+    /// Don't find peers through the DHT
+    #[structopt(long)]
+    no_dht: bool,
+    /// Don't find peers on the local network by multicast
+    #[structopt(long)]
+    no_lsd: bool,
+    /// Pause torrents downloading to a disk with fewer than this many MiB free, until
+    /// it has room again
+    #[structopt(long)]
+    min_free_space: Option<u64>,
+    /// Pause torrents while NetworkManager says the network is metered
+    #[structopt(long)]
+    pause_on_metered: bool,
+    /// Maximum download rate of every torrent together in KiB a second, while the
+    /// alternate speeds are on
+    #[structopt(long, default_value = "50")]
+    alt_download_limit: u64,
+    /// Maximum upload rate of every torrent together in KiB a second, while the
+    /// alternate speeds are on
+    #[structopt(long, default_value = "50")]
+    alt_upload_limit: u64,
+    /// Start with the alternate speeds on
+    #[structopt(long)]
+    alt_speed: bool,
+    /// Address to accept control connections on. Addresses other than loopback ones need
+    /// --rpc-token
+    #[structopt(long, default_value = rpc::DEFAULT_ADDR)]
+    rpc: SocketAddr,
+    /// Token control connections must give
+    #[structopt(long)]
+    rpc_token: Option<String>,
+    /// RSS feed to add torrents from when their items match a feed rule. Can be given
+    /// more than once
+    #[structopt(long = "feed")]
+    feeds: Vec<Url>,
+    /// Which feed items to add, such as match=Some Show S\d+E\d+,exclude=720p,
+    /// max-size=2048,category=tv,save-path=/media/tv. Patterns are regular expressions
+    /// ignoring case, and sizes are in MiB. Can be given more than once, and the first
+    /// rule an item matches applies
+    #[structopt(long = "feed-rule")]
+    feed_rules: Vec<FeedRule>,
+    /// Minutes between checks of each feed
+    #[structopt(long, default_value = "15")]
+    feed_interval: u64,
+    /// Address to serve the HTTP API on, such as 127.0.0.1:9091, for tools which speak
+    /// Transmission's RPC protocol or qBittorrent's Web API. Addresses other than
+    /// loopback ones need --web-password or --web-token
+    #[structopt(long)]
+    web: Option<SocketAddr>,
+    /// Username clients of the HTTP API log in with, along with --web-password
+    #[structopt(long, default_value = "admin")]
+    web_user: String,
+    /// Password clients of the HTTP API log in with
+    #[structopt(long, conflicts_with = "web-token")]
+    web_password: Option<String>,
+    /// Token clients of the HTTP API give, as a bearer token or as the password
+    #[structopt(long)]
+    web_token: Option<String>,
+    /// PEM certificate chain to serve the HTTP API over HTTPS with, along with --web-key
+    #[structopt(long, requires = "web-key")]
+    web_cert: Option<PathBuf>,
+    /// PEM PKCS #8 private key of --web-cert
+    #[structopt(long, requires = "web-cert")]
+    web_key: Option<PathBuf>,
+    /// Protocol the HTTP API speaks: transmission or qbittorrent. Can be given more than
+    /// once, and it speaks both if not given
+    #[structopt(long = "web-protocol")]
+    web_protocols: Vec<Protocol>,
+}
+
+impl ClientOpt {
+    /// Where and how to serve the HTTP API, if anywhere.
+    fn web(&self) -> anyhow::Result<Option<WebConfig>> {
+        let addr = match self.web {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        let credentials = match (&self.web_token, &self.web_password) {
+            (Some(token), _) => Some(Credentials::Token(token.clone())),
+            (None, Some(password)) => Some(Credentials::Password {
+                username: self.web_user.clone(),
+                password: password.clone(),
+            }),
+            (None, None) => None,
+        };
+        if credentials.is_none() && !addr.ip().is_loopback() {
+            anyhow::bail!(
+                "Serving the HTTP API on {} needs --web-password or --web-token",
+                addr
+            );
+        }
+
+        let mut config = WebConfig {
+            credentials,
+            tls: self.web_cert.clone().zip(self.web_key.clone()),
+            ..WebConfig::new(addr)
+        };
+        if !self.web_protocols.is_empty() {
+            config.protocols = self.web_protocols.clone();
+        }
+        Ok(Some(config))
+    }
+
+    /// Where to accept control connections, and the token they must give.
+    fn rpc(&self) -> anyhow::Result<(SocketAddr, Option<String>)> {
+        if self.rpc_token.is_none() && !self.rpc.ip().is_loopback() {
+            anyhow::bail!(
+                "Accepting control connections on {} needs --rpc-token",
+                self.rpc
+            );
+        }
+        Ok((self.rpc, self.rpc_token.clone()))
+    }
+
+    fn rss(&self) -> anyhow::Result<RssConfig> {
+        let interval = match self.feed_interval.checked_mul(60) {
+            Some(0) => anyhow::bail!("--feed-interval must be at least a minute"),
+            Some(secs) => Duration::from_secs(secs),
+            None => anyhow::bail!("--feed-interval {} is too long", self.feed_interval),
+        };
+        Ok(RssConfig {
+            feeds: self.feeds.clone(),
+            rules: self.feed_rules.clone(),
+            interval,
+        })
+    }
+
+    fn config(self, save_path: PathBuf) -> anyhow::Result<ClientConfig> {
+        Ok(ClientConfig {
+            peer_id: *PEER_ID,
+            port: PORT,
+            external_ip: self.external_ip,
+            bind_address: self.bind_address,
+            numwant: self.numwant,
+            allow_local_trackers: self.allow_local_trackers,
+            socket: SocketOptions {
+                nodelay: !self.no_nodelay,
+                keepalive: self.tcp_keepalive.map(Duration::from_secs),
+                recv_buffer: (self.recv_buffer)
+                    .map(|kib| bytes("recv-buffer", kib.into(), KIB))
+                    .transpose()?,
+                send_buffer: (self.send_buffer)
+                    .map(|kib| bytes("send-buffer", kib.into(), KIB))
+                    .transpose()?,
+            },
+            picker: PickerKind::RarestFirst,
+            max_half_open: self.max_half_open,
+            max_connections: self.max_connections,
+            max_active_downloads: self.max_active_downloads,
+            max_active_seeds: self.max_active_seeds,
+            max_active: self.max_active,
+            peers_per_torrent: self.peers_per_torrent,
+            upload_slots: self.upload_slots,
+            slot_policy: self.slot_policy,
+            have_policy: self.have_policy,
+            seed_rules: self.seed_rules,
+            save_path,
+            category_paths: self.category_paths.into_iter().collect(),
+            output: Output::Files,
+            in_order: None,
+            disk_workers: self.disk_workers,
+            max_disk_queue: bytes("disk-queue", self.disk_queue as u64, MIB)?,
+            preallocate: self.preallocate,
+            memory_budget: bytes("memory-budget", self.memory_budget as u64, MIB)?,
+            verify_on_complete: false,
+            state_dir: self.state_dir,
+            ip_filter: self.ip_filter,
+            allowed_peers: allowed_peers(&self.allowed_peers, self.lan_only)?,
+            reserved: self.reserved.unwrap_or(DEFAULT_RESERVED),
+            strict_protocol: self.strict_protocol,
+            wire_log: self.wire_log,
+            dht: !self.no_dht,
+            lsd: !self.no_lsd,
+            min_free_space: (self.min_free_space)
+                .map(|mib| bytes("min-free-space", mib, MIB))
+                .transpose()?,
+            pause_on_metered: self.pause_on_metered,
+            alt_download_limit: bytes("alt-download-limit", self.alt_download_limit, KIB)?,
+            alt_upload_limit: bytes("alt-upload-limit", self.alt_upload_limit, KIB)?,
+            alt_speed: self.alt_speed,
+            hooks: Hooks {
+                on_added: self.on_added,
+                on_complete: self.on_complete,
+                on_error: self.on_error,
+                on_file_completed: self.on_file_completed,
+            },
+        })
+    }
+}
+
+/// `count` of the `unit`s the option `name` is given in, in bytes, unless that's too
+/// many to count.
+fn bytes<T: TryFrom<u64>>(name: &str, count: u64, unit: u64) -> anyhow::Result<T> {
+    count
+        .checked_mul(unit)
+        .and_then(|bytes| T::try_from(bytes).ok())
+        .ok_or_else(|| anyhow::anyhow!("--{} {} is too large", name, count))
+}
+
+fn parse_category_path(s: &str) -> anyhow::Result<(String, PathBuf)> {
+    let (category, path) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected category=path, got {}", s))?;
+    Ok((category.to_owned(), PathBuf::from(path)))
+}
+
+fn parse_reserved(s: &str) -> anyhow::Result<[u8; 8]> {
+    if s.len() != 16 {
+        anyhow::bail!("Reserved bits must be 16 hex digits");
+    }
+    Ok(u64::from_str_radix(s, 16)?.to_be_bytes())
+}
+
+/// The ranges peers must be in, if the user limited them.
+fn allowed_peers(ranges: &[String], lan_only: bool) -> anyhow::Result<Option<IpFilter>> {
+    let mut allowed = IpFilter::default();
+    for range in ranges {
+        let filter = IpFilter::parse(range);
+        if filter.is_empty() {
+            anyhow::bail!("Invalid address range: {}", range);
+        }
+        allowed = allowed.union(&filter);
+    }
+    if lan_only {
+        allowed = allowed.union(&IpFilter::private_networks());
+    }
+
+    Ok((!allowed.is_empty()).then_some(allowed))
+}
+
+/// Settings for a single torrent, overriding the client's.
+#[derive(Debug, StructOpt)]
+struct AddOpt {
+    /// Directory to save this torrent in
+    #[structopt(long)]
+    save_path: Option<PathBuf>,
+    /// Add the torrent without starting it
+    #[structopt(long)]
+    paused: bool,
+    /// Start the torrent whatever the limits on active torrents
+    #[structopt(long)]
+    force_start: bool,
+    /// Download pieces in order
+    #[structopt(long)]
+    sequential: bool,
+    /// Maximum download rate in bytes a second
+    #[structopt(long)]
+    download_limit: Option<u64>,
+    /// Maximum upload rate in bytes a second
+    #[structopt(long)]
+    upload_limit: Option<u64>,
+    /// Keep seeding until we've uploaded this many times the torrent's size
+    #[structopt(long)]
+    seed_ratio: Option<f64>,
+    /// Priority of each file in order: skip, normal or high
+    #[structopt(long = "file-priority")]
+    file_priorities: Vec<Priority>,
+    /// Tracker to announce to instead of the torrent's own, each in its own tier
+    #[structopt(long = "tracker")]
+    trackers: Vec<String>,
+    /// Share of connections, memory, disk and alternate speeds relative to other torrents
+    #[structopt(long)]
+    weight: Option<u32>,
+    /// Category to file the torrent under
+    #[structopt(long)]
+    category: Option<String>,
+    /// Name to show the torrent by instead of its own
+    #[structopt(long)]
+    name: Option<String>,
+}
+
+impl From<AddOpt> for AddTorrentOptions {
+    fn from(opt: AddOpt) -> Self {
+        let trackers =
+            (!opt.trackers.is_empty()).then(|| opt.trackers.into_iter().map(|t| vec![t]).collect());
+        Self {
+            save_path: opt.save_path,
+            name: opt.name,
+            paused: opt.paused,
+            force_start: opt.force_start,
+            sequential: opt.sequential,
+            download_limit: opt.download_limit,
+            upload_limit: opt.upload_limit,
+            seed_ratio: opt.seed_ratio,
+            file_priorities: opt.file_priorities,
+            trackers,
+            weight: opt.weight,
+            category: opt.category,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct DownloadOpt {
+    /// Path or URL of a .torrent file, a magnet link or an info hash
+    torrent: TorrentSource,
+    /// Directory the download is saved to
+    #[structopt(short, long, default_value = ".")]
+    output: PathBuf,
+    /// How to choose pieces to download: rarest-first, sequential, random or streaming
+    #[structopt(long, default_value = "rarest-first")]
+    picker: PickerKind,
+    /// With the streaming picker, how many pieces past the playback position to
+    /// download in order
+    #[structopt(long, default_value = "16")]
+    stream_window: usize,
+    /// With the streaming picker, the percentage of requests for rare pieces past the
+    /// window
+    #[structopt(long, default_value = "10")]
+    stream_mix: u8,
+    /// Re-hash the saved files once the download completes
+    #[structopt(long)]
+    verify: bool,
+    /// Stream a single-file torrent's content to standard output instead of saving it
+    #[structopt(long)]
+    stdout: bool,
+    /// Deliver pieces in order, buffering at most this many pieces which complete early
+    #[structopt(long)]
+    reorder_buffer: Option<usize>,
+    /// Instead of downloading, check the torrent, its trackers, the port, the DHT and the
+    /// output directory, and suggest fixes for what's wrong
+    #[structopt(long)]
+    doctor: bool,
+    #[structopt(flatten)]
+    add: AddOpt,
+    #[structopt(flatten)]
+    client: ClientOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct RecheckOpt {
     torrent: PathBuf,
+    /// Directory containing the torrent's content
+    #[structopt(long)]
+    data: PathBuf,
+    #[structopt(flatten)]
+    client: ClientOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct CtlOpt {
+    /// Address of the running client
+    #[structopt(long, default_value = rpc::DEFAULT_ADDR)]
+    rpc: SocketAddr,
+    /// Token the running client was given with --rpc-token
+    #[structopt(long)]
+    token: Option<String>,
+    #[structopt(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(Debug, StructOpt)]
+enum CtlCommand {
+    /// Show progress, peers and trackers of running torrents
+    Status {
+        /// Only show torrents whose info hash starts with this
+        hash: Option<String>,
+        /// Only show torrents in this category
+        #[structopt(long)]
+        category: Option<String>,
+        /// Print the status as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Add a torrent from a path, URL, magnet link or info hash
+    Add {
+        /// Paths are read by the client, so relative paths are relative to where it runs
+        source: String,
+        #[structopt(flatten)]
+        add: AddOpt,
+    },
+    /// Announce to a torrent's trackers now
+    Reannounce {
+        /// Info hash, or the start of one
+        hash: String,
+    },
+    /// Stop a torrent and forget it, telling its trackers it has stopped
+    Remove {
+        /// Info hash, or the start of one
+        hash: String,
+        /// Delete the torrent's downloaded files too
+        #[structopt(long)]
+        delete_data: bool,
+    },
+    /// Hash a torrent's content on disk again, ignoring any cached check
+    Recheck {
+        /// Info hash, or the start of one
+        hash: String,
+    },
+    /// Stop a torrent's recheck, keeping what it found so far
+    CancelCheck {
+        /// Info hash, or the start of one
+        hash: String,
+    },
+    /// Disconnect from one of a torrent's peers
+    Disconnect {
+        /// Info hash, or the start of one
+        hash: String,
+        /// The peer's address, as shown by status
+        peer: SocketAddr,
+    },
+    /// Disconnect from a peer and refuse to talk to its IP for a while
+    Ban {
+        /// Info hash, or the start of one
+        hash: String,
+        /// The peer's IP, or its address as shown by status
+        #[structopt(parse(try_from_str = parse_peer_ip))]
+        peer: IpAddr,
+        /// How long to ban the peer for
+        #[structopt(long, default_value = "3600")]
+        seconds: u64,
+    },
+    /// Show a torrent by another name, or by its own name again
+    Rename {
+        /// Info hash, or the start of one
+        hash: String,
+        /// The name, or none to go back to the torrent's own
+        name: Option<String>,
+    },
+    /// Move a torrent's content to another directory while it runs
+    Move {
+        /// Info hash, or the start of one
+        hash: String,
+        /// Directory to move the content to, on the client's machine
+        save_path: PathBuf,
+    },
+    /// Put a torrent in a category, or take it out of its category
+    Category {
+        /// Info hash, or the start of one
+        hash: String,
+        /// The category, or none to take the torrent out of its category
+        category: Option<String>,
+    },
+    /// Change which files of a torrent are downloaded while it runs, or without
+    /// priorities, show how much of each file is saved
+    Files {
+        /// Info hash, or the start of one
+        hash: String,
+        /// Priority of each file in order: skip, normal or high
+        priorities: Vec<Priority>,
+    },
+    /// Print the path of each file downloaded in full, as they finish, until stopped
+    WatchFiles {
+        /// Only show files of torrents whose info hash starts with this
+        hash: Option<String>,
+    },
+    /// Pause every torrent
+    PauseAll,
+    /// Resume every paused torrent
+    ResumeAll,
+    /// Switch the alternate speeds on or off, or toggle them
+    AltSpeed {
+        /// "on" or "off"; toggles them if not given
+        #[structopt(parse(try_from_str = parse_on_off))]
+        enabled: Option<bool>,
+    },
+}
+
+fn parse_peer_ip(s: &str) -> Result<IpAddr, std::net::AddrParseError> {
+    s.parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| s.parse())
+}
+
+fn parse_on_off(s: &str) -> anyhow::Result<bool> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(anyhow::anyhow!("Expected on or off, not {}", s)),
+    }
 }
 
 fn init_tracing() {
-    tracing_subscriber::fmt::init();
+    // Logs go to stderr so they don't end up in content streamed to stdout.
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     init_tracing();
-    let opt = Opt::from_args();
 
-    let file = tokio::fs::read(opt.torrent).await?;
+    match Opt::from_args() {
+        Opt::Download(opt) => download(opt).await,
+        Opt::Recheck(opt) => recheck(opt).await,
+        Opt::Ctl(opt) => ctl(opt).await,
+        Opt::Verify { torrent, path } => verify(torrent, path).await,
+        Opt::Create(opt) => create(opt),
+        Opt::Edit(opt) => edit(opt),
+        Opt::Dump { file, json, full } => dump(file, json, full),
+        Opt::Hash { input } => hash(&input),
+        Opt::Import {
+            from,
+            dir,
+            state_dir,
+        } => import(from, dir, state_dir).await,
+    }
+}
+
+async fn download(opt: DownloadOpt) -> anyhow::Result<()> {
+    if opt.doctor {
+        let config = opt.client.config(opt.output)?;
+        let report = doctor::diagnose(&config, &opt.torrent).await;
+        print!("{}", report.render(std::io::stdout().is_terminal()));
+        if !report.passed() {
+            anyhow::bail!("Some checks failed");
+        }
+        return Ok(());
+    }
+    let (output, picker) = if opt.stdout {
+        // Pieces arriving far out of order would overflow the reorder buffer.
+        (Output::Stdout, PickerKind::Sequential)
+    } else {
+        let picker = match opt.picker {
+            PickerKind::Streaming { .. } => PickerKind::Streaming {
+                window: opt.stream_window,
+                mix: opt.stream_mix,
+            },
+            picker => picker,
+        };
+        (Output::Files, picker)
+    };
+
+    let rpc = opt.client.rpc()?;
+    let web = opt.client.web()?;
+    let rss = opt.client.rss()?;
+    let config = ClientConfig {
+        picker,
+        output,
+        in_order: opt.reorder_buffer,
+        verify_on_complete: opt.verify,
+        ..opt.client.config(opt.output)?
+    };
+    let client = start_client(config, rpc, web, rss).await?;
+
+    // Resolved once the client is running, so magnets' peers can be found on its DHT.
+    let dht = client.dht();
+    let torrent = fetch::resolve(
+        &opt.torrent,
+        PEER_ID,
+        PORT,
+        client.config().bind_address,
+        &client.config().socket,
+        dht.as_ref(),
+    )
+    .await?;
+    if opt.stdout && torrent.file.info.files.is_some() {
+        anyhow::bail!("Only single-file torrents can be streamed to stdout");
+    }
+    let handle = match client.get(&torrent.info_hash) {
+        Some(handle) => handle,
+        None => {
+            client
+                .add_torrent_with_options(torrent, opt.add.into())
+                .await?
+        }
+    };
+    if handle.options().paused {
+        info!("{} is paused", handle.name());
+    }
+    let result = tokio::select! {
+        result = handle.wait_complete() => result,
+        result = tokio::signal::ctrl_c() => result.map_err(Into::into),
+    };
+    client.save_session()?;
+    result
+}
+
+async fn recheck(opt: RecheckOpt) -> anyhow::Result<()> {
+    let file = tokio::fs::read(&opt.torrent).await?;
     let torrent = Torrent::from_bytes(&file)?;
 
-    let details = request_peer_info(&torrent, PEER_ID, PORT).await?;
+    let rpc = opt.client.rpc()?;
+    let web = opt.client.web()?;
+    let rss = opt.client.rss()?;
+    let config = opt.client.config(opt.data.clone())?;
+    let client = start_client(config, rpc, web, rss).await?;
 
-    let mut handles = Vec::new();
+    let handle = match client.get(&torrent.info_hash) {
+        Some(handle) => handle,
+        None => client.seed(torrent, &opt.data).await?,
+    };
+    let status = handle.status();
+    println!(
+        "Seeding {} ({}/{} pieces)",
+        handle.name(),
+        status.pieces_done,
+        status.piece_count
+    );
 
-    let (save_tx, save_rx) = channel(50);
+    tokio::signal::ctrl_c().await?;
+    client.save_session()
+}
 
-    let work_queue = torrent.work_queue().await?;
+/// Create a client, restore the torrents from its last run if it has a state directory,
+/// start accepting connections from peers, from `ctl` and to the HTTP API, and watch any
+/// RSS feeds.
+async fn start_client(
+    config: ClientConfig,
+    (rpc_addr, rpc_token): (SocketAddr, Option<String>),
+    web: Option<WebConfig>,
+    rss: RssConfig,
+) -> anyhow::Result<Client> {
+    let client = match &config.state_dir {
+        Some(dir) => {
+            let store = SessionStore::open(&dir.join("session.sqlite"))?;
+            Client::with_session_store(config, store)
+        }
+        None => Client::new(config),
+    };
+    let ranges = client.reload_ip_filter().await?;
+    if ranges > 0 {
+        info!("Loaded IP filter: {} ranges", ranges);
+    }
+    for handle in client.restore().await? {
+        info!("Restored {}", handle.name());
+    }
+    if let Err(e) = client.start_dht().await {
+        warn!("Couldn't start the DHT node: {}", e);
+    }
+    if let Err(e) = client.start_lsd() {
+        warn!("Couldn't start finding local peers: {}", e);
+    }
 
-    let torrent = Arc::new(torrent);
-    let piece_count = torrent.file.info.hash_pieces().len();
+    let rpc_client = client.clone();
+    tokio::spawn(async move {
+        if let Err(e) = rpc::serve(rpc_client, rpc_addr, rpc_token).await {
+            warn!("Control server stopped: {}", e);
+        }
+    });
 
-    for peer_data in details.peers.into_iter() {
-        let torrent = Arc::clone(&torrent);
-        let work_queue = work_queue.clone();
-        let save_tx = save_tx.clone();
-        let handle = tokio::spawn(async move {
-            let mut session = PeerSession::new(peer_data, torrent, work_queue, save_tx, PEER_ID)
-                .await?
-                .connect()
-                .await?;
-            session.start_download().await?;
+    let network_client = client.clone();
+    tokio::spawn(async move {
+        if let Err(e) = network_client.watch_bind_address().await {
+            warn!("Stopped watching the bind address: {}", e);
+        }
+    });
+
+    let conditions_client = client.clone();
+    tokio::spawn(async move {
+        if let Err(e) = conditions_client.watch_conditions().await {
+            warn!("Stopped watching disk space and the network: {}", e);
+        }
+    });
 
-            Ok(()) as anyhow::Result<()>
+    let filter_client = client.clone();
+    tokio::spawn(async move {
+        if let Err(e) = filter_client.watch_ip_filter().await {
+            warn!("Stopped watching the IP filter: {}", e);
+        }
+    });
+
+    if let Some(web) = web {
+        let web_client = client.clone();
+        tokio::spawn(async move {
+            if let Err(e) = web::serve(web_client, web).await {
+                warn!("HTTP API stopped: {}", e);
+            }
         });
+    }
 
-        handles.push(handle);
+    if !rss.feeds.is_empty() {
+        let feed_client = client.clone();
+        tokio::spawn(async move {
+            if let Err(e) = rss::watch(&feed_client, &rss).await {
+                warn!("Stopped watching RSS feeds: {}", e);
+            }
+        });
     }
 
-    let save_handle = tokio::spawn(save_results(save_rx, piece_count));
+    let peer_client = client.clone();
+    tokio::spawn(async move {
+        if let Err(e) = peer_client.listen().await {
+            warn!("Stopped accepting peer connections: {}", e);
+        }
+    });
 
-    for handle in handles {
-        handle.await??;
+    Ok(client)
+}
+
+async fn import(from: ImportSource, dir: PathBuf, state_dir: PathBuf) -> anyhow::Result<()> {
+    let store = SessionStore::open(&state_dir.join("session.sqlite"))?;
+    let mut failed = 0;
+    for imported in import::read(from, &dir)? {
+        let result = match imported {
+            Ok(imported) => import::register(&imported, &store, &state_dir)
+                .await
+                .map(|()| imported),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(imported) => println!(
+                "imported: {} in {}",
+                imported.torrent.file.info.name,
+                imported.save_path.display()
+            ),
+            Err(e) => {
+                failed += 1;
+                println!("failed: {}", e);
+            }
+        }
     }
-    save_handle.await?;
 
+    if failed > 0 {
+        anyhow::bail!("{} torrents couldn't be imported", failed);
+    }
     Ok(())
 }
 
-#[tracing::instrument]
-async fn save_results(mut save_rx: Receiver<WorkResult>, piece_count: usize) {
-    let mut downloaded_count = 0;
-    let mut total_bytes = 0;
-    while let Some(result) = save_rx.recv().await {
-        downloaded_count += 1;
-        total_bytes += result.bytes.len();
-        info!(
-            "downloaded piece {} of {}: {} total bytes",
-            downloaded_count, piece_count, total_bytes
-        );
-        if downloaded_count >= piece_count {
-            info!("Download complete!");
-            break;
+async fn verify(torrent: PathBuf, path: PathBuf) -> anyhow::Result<()> {
+    let file = tokio::fs::read(&torrent).await?;
+    let torrent = Torrent::from_bytes(&file)?;
+
+    let verification = torrent::verify::verify(&torrent, &path).await?;
+    for path in &verification.missing_files {
+        println!("missing: {}", path.display());
+    }
+    for path in &verification.md5_mismatches {
+        println!("md5 mismatch: {}", path.display());
+    }
+    for idx in &verification.bad_pieces {
+        println!("bad piece: {}", idx);
+    }
+    println!("{}", verification);
+
+    if !verification.is_ok() {
+        anyhow::bail!("Verification failed");
+    }
+    Ok(())
+}
+
+fn create(opt: CreateOpt) -> anyhow::Result<()> {
+    let mut builder = TorrentBuilder::new(&opt.path)
+        .with_trackers(opt.tracker.into_iter().map(|t| vec![t]).collect())
+        .with_private(opt.private)
+        .with_version(opt.version);
+    if let Some(name) = opt.name {
+        builder = builder.with_name(name);
+    }
+    if let Some(length) = opt.piece_length {
+        builder = builder.with_piece_length(bytes("piece-length", length, KIB)?);
+    }
+    if let Some(comment) = opt.comment {
+        builder = builder.with_comment(comment);
+    }
+    if let Some(threads) = opt.threads {
+        builder = builder.with_threads(threads);
+    }
+    let shown = std::sync::atomic::AtomicU64::new(0);
+    builder = builder.with_progress(move |progress| {
+        // Only once for each whole percent, to keep the terminal quiet.
+        let percent = progress.percent() as u64;
+        if shown.swap(percent, std::sync::atomic::Ordering::Relaxed) != percent {
+            eprint!("\rHashed {}%", percent);
+        }
+    });
+
+    let torrent = builder.build()?;
+    eprintln!();
+    std::fs::write(&opt.output, torrent.to_bytes()?)?;
+    let info_hash: String = torrent
+        .info_hash
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    println!(
+        "{}  {} ({} pieces of {})",
+        info_hash,
+        torrent.file.info.name,
+        torrent.file.info.piece_count(),
+        format_bytes(torrent.file.info.piece_length as u64)
+    );
+    Ok(())
+}
+
+fn hash(input: &str) -> anyhow::Result<()> {
+    let path = std::path::Path::new(input);
+    let hashes = match path.is_file() {
+        true => InfoHashes::parse(&std::fs::read(path)?)?,
+        false => InfoHashes::parse(input.as_bytes())?,
+    };
+    if let (Some(hex), Some(base32)) = (hashes.v1_hex(), hashes.v1_base32()) {
+        println!("v1  {}  {}", hex, base32);
+    }
+    if let (Some(hex), Some(base32)) = (hashes.v2_hex(), hashes.v2_base32()) {
+        println!("v2  {}  {}", hex, base32);
+    }
+    Ok(())
+}
+
+fn dump(file: PathBuf, json: bool, full: bool) -> anyhow::Result<()> {
+    let value = serde_bencode::from_bytes(&std::fs::read(&file)?)?;
+    let max_bytes = if full {
+        usize::MAX
+    } else {
+        dump::DEFAULT_MAX_BYTES
+    };
+    if json {
+        let json = dump::to_json(&value, max_bytes);
+        println!("{}", serde_json::to_string_pretty(&json)?);
+    } else {
+        print!("{}", dump::dump(&value, max_bytes));
+    }
+    Ok(())
+}
+
+fn edit(opt: EditOpt) -> anyhow::Result<()> {
+    let mut editor = TorrentEditor::from_bytes(&std::fs::read(&opt.torrent)?)?;
+    let info_hash = editor.info_hash()?;
+    if opt.strip {
+        editor.strip();
+    }
+    if !opt.tracker.is_empty() || opt.no_trackers {
+        editor.set_trackers(opt.tracker.into_iter().map(|t| vec![t]).collect());
+    }
+    if let Some(comment) = &opt.comment {
+        editor.set_comment(Some(comment));
+    }
+    if let Some(name) = &opt.name {
+        editor.set_name(name);
+    }
+    if opt.private || opt.public {
+        editor.set_private(opt.private);
+    }
+
+    if editor.info_hash()? != info_hash {
+        warn!("The edits change the info hash, so this is now a different torrent to peers");
+    }
+    std::fs::write(&opt.output, editor.to_bytes()?)?;
+    Ok(())
+}
+
+async fn ctl(opt: CtlOpt) -> anyhow::Result<()> {
+    let token = opt.token.as_deref();
+    match opt.command {
+        CtlCommand::Status {
+            hash,
+            category,
+            json,
+        } => {
+            let request = Request::Status {
+                info_hash: hash,
+                category,
+            };
+            let torrents = match rpc::call(opt.rpc, token, &request).await? {
+                Response::Status(torrents) => torrents,
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            };
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&torrents)?);
+            } else {
+                for torrent in &torrents {
+                    print_status(torrent);
+                }
+            }
+        }
+        CtlCommand::Add { source, add } => {
+            // Check it parses before sending it, so mistakes are reported here.
+            source.parse::<TorrentSource>()?;
+            let request = Request::Add {
+                source,
+                options: add.into(),
+            };
+            match rpc::call(opt.rpc, token, &request).await? {
+                Response::Added(torrent) => {
+                    println!("Added {}  {}", torrent.info_hash, torrent.name)
+                }
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::Reannounce { hash } => {
+            match rpc::call(opt.rpc, token, &Request::Reannounce { info_hash: hash }).await? {
+                Response::Reannounced => {}
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::Remove { hash, delete_data } => {
+            let request = Request::Remove {
+                info_hash: hash,
+                delete_data,
+            };
+            match rpc::call(opt.rpc, token, &request).await? {
+                Response::Removed => {}
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::Recheck { hash } => {
+            match rpc::call(opt.rpc, token, &Request::Recheck { info_hash: hash }).await? {
+                Response::Rechecked(verification) => println!("{}", verification),
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::CancelCheck { hash } => {
+            match rpc::call(opt.rpc, token, &Request::CancelCheck { info_hash: hash }).await? {
+                Response::CheckCancelled => {}
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::Disconnect { hash, peer } => {
+            let request = Request::DisconnectPeer {
+                info_hash: hash,
+                peer,
+            };
+            match rpc::call(opt.rpc, token, &request).await? {
+                Response::Disconnected => {}
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::Ban {
+            hash,
+            peer,
+            seconds,
+        } => {
+            let request = Request::BanPeer {
+                info_hash: hash,
+                peer,
+                seconds,
+            };
+            match rpc::call(opt.rpc, token, &request).await? {
+                Response::Banned => {}
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::Rename { hash, name } => {
+            let request = Request::SetName {
+                info_hash: hash,
+                name,
+            };
+            match rpc::call(opt.rpc, token, &request).await? {
+                Response::NameSet => {}
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::Move { hash, save_path } => {
+            let request = Request::MoveStorage {
+                info_hash: hash,
+                save_path,
+            };
+            match rpc::call(opt.rpc, token, &request).await? {
+                Response::StorageMoved => {}
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::Category { hash, category } => {
+            let request = Request::SetCategory {
+                info_hash: hash,
+                category,
+            };
+            match rpc::call(opt.rpc, token, &request).await? {
+                Response::CategorySet => {}
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::Files { hash, priorities } if priorities.is_empty() => {
+            let request = Request::FileProgress { info_hash: hash };
+            match rpc::call(opt.rpc, token, &request).await? {
+                Response::FileProgress(files) => {
+                    for file in files {
+                        println!("{:>6.1}%  {}", file.progress() * 100.0, file.path.display());
+                    }
+                }
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::Files { hash, priorities } => {
+            let request = Request::SetFilePriorities {
+                info_hash: hash,
+                priorities,
+            };
+            match rpc::call(opt.rpc, token, &request).await? {
+                Response::FilePrioritiesSet(selection) => println!(
+                    "Downloading {} pieces, {:.1}% done",
+                    selection.wanted,
+                    selection.progress() * 100.0
+                ),
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
+        }
+        CtlCommand::WatchFiles { hash } => {
+            let request = Request::WatchFiles { info_hash: hash };
+            rpc::watch(opt.rpc, token, &request, |response| match response {
+                Response::FileCompleted {
+                    info_hash, path, ..
+                } => {
+                    println!("{}  {}", info_hash, path.display());
+                    Ok(())
+                }
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            })
+            .await?;
+        }
+        CtlCommand::PauseAll => match rpc::call(opt.rpc, token, &Request::PauseAll).await? {
+            Response::PausedAll => {}
+            Response::Error(e) => anyhow::bail!(e),
+            response => anyhow::bail!("Unexpected response: {:?}", response),
+        },
+        CtlCommand::ResumeAll => match rpc::call(opt.rpc, token, &Request::ResumeAll).await? {
+            Response::ResumedAll => {}
+            Response::Error(e) => anyhow::bail!(e),
+            response => anyhow::bail!("Unexpected response: {:?}", response),
+        },
+        CtlCommand::AltSpeed { enabled } => {
+            let request = Request::SetAltSpeed { enabled };
+            match rpc::call(opt.rpc, token, &request).await? {
+                Response::AltSpeed(true) => println!("Alternate speeds on"),
+                Response::AltSpeed(false) => println!("Alternate speeds off"),
+                Response::Error(e) => anyhow::bail!(e),
+                response => anyhow::bail!("Unexpected response: {:?}", response),
+            }
         }
     }
+
+    Ok(())
+}
+
+fn print_status(torrent: &TorrentStatus) {
+    match &torrent.category {
+        Some(category) => println!("{}  {}  [{}]", torrent.info_hash, torrent.name, category),
+        None => println!("{}  {}", torrent.info_hash, torrent.name),
+    }
+    println!(
+        "  {:.1}% ({}/{} pieces)  down {}/s  up {}/s  downloaded {}  uploaded {}",
+        torrent.progress * 100.0,
+        torrent.pieces_done,
+        torrent.piece_count,
+        format_bytes(torrent.download_rate),
+        format_bytes(torrent.upload_rate),
+        format_bytes(torrent.downloaded),
+        format_bytes(torrent.uploaded),
+    );
+    let traffic = &torrent.traffic;
+    println!(
+        "  payload down {}  up {}  overhead down {}  up {}",
+        format_bytes(traffic.payload_down),
+        format_bytes(traffic.payload_up),
+        format_bytes(traffic.overhead_down),
+        format_bytes(traffic.overhead_up),
+    );
+    if let Some(checking) = &torrent.checking {
+        match &checking.file {
+            Some(file) => println!("  checking {:.1}% ({})", checking.percent(), file.display()),
+            None => println!("  checking {:.1}%", checking.percent()),
+        }
+    }
+
+    println!();
+    println!(
+        "  {:<22} {:<8} {:<10} {:>7} {:>12} {:>12} {:>7} {:>6}  EXTENSIONS",
+        "PEER", "SOURCE", "CLIENT", "DONE", "DOWN", "UP", "LATENCY", "FLAGS"
+    );
+    for peer in &torrent.peers {
+        let latency = match peer.latency_ms {
+            Some(ms) => format!("{}ms", ms),
+            None => String::from("-"),
+        };
+        println!(
+            "  {:<22} {:<8} {:<10} {:>6.1}% {:>10}/s {:>10}/s {:>7} {:>6}  {}",
+            peer.addr.to_string(),
+            peer.source.to_string(),
+            peer.client.as_deref().unwrap_or("?"),
+            peer.progress * 100.0,
+            format_bytes(peer.download_rate),
+            format_bytes(peer.upload_rate),
+            latency,
+            peer.flags(),
+            peer.extensions,
+        );
+    }
+
+    println!();
+    println!(
+        "  {:<8} {:>10} {:>10} {:>10} {:>10}",
+        "SOURCE", "FOUND", "DIALED", "CONNECTED", "BLOCKED"
+    );
+    for (source, counts) in &torrent.sources {
+        println!(
+            "  {:<8} {:>10} {:>10} {:>10} {:>10}",
+            source.to_string(),
+            counts.discovered,
+            counts.attempted,
+            counts.connected,
+            counts.blocked
+        );
+    }
+
+    println!();
+    println!("  {:<50} {:>6} STATUS", "TRACKER", "PEERS");
+    for tracker in &torrent.trackers {
+        println!(
+            "  {:<50} {:>6} {}",
+            tracker.url, tracker.peers, tracker.status
+        );
+    }
+    println!();
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
 }
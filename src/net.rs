@@ -0,0 +1,265 @@
+//! Opening sockets from the local address the user chose, if any, so traffic leaves
+//! through a particular interface such as a VPN's, and tuning peer connections' sockets.
+
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+
+/// Connections waiting to be accepted before the OS refuses more.
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// Options for the sockets of peer connections, whether we dialed or accepted them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SocketOptions {
+    /// Send messages straight away rather than waiting to fill a packet, so requests
+    /// aren't held back.
+    pub nodelay: bool,
+    /// Start probing connections which have been idle this long, so peers which
+    /// vanished are noticed. No probes if `None`.
+    pub keepalive: Option<Duration>,
+    /// Sizes of the kernel's receive and send buffers, in bytes, or the OS's defaults.
+    /// Links with a large bandwidth-delay product need them larger than the defaults
+    /// to be kept full.
+    pub recv_buffer: Option<u32>,
+    pub send_buffer: Option<u32>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            recv_buffer: None,
+            send_buffer: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Set the buffer sizes, which have to be set before connecting or listening for
+    /// the TCP window to be scaled to them.
+    fn set_buffers(&self, socket: &TcpSocket) -> io::Result<()> {
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Set the options on a connected socket. Accepted sockets already have the
+    /// listener's buffer sizes.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time);
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+fn tcp_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
+    match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+}
+
+/// Connect to `addr`, from `bind_address` if there is one.
+pub async fn connect(
+    addr: SocketAddr,
+    bind_address: Option<IpAddr>,
+    options: &SocketOptions,
+) -> io::Result<TcpStream> {
+    let socket = tcp_socket(addr)?;
+    options.set_buffers(&socket)?;
+    if let Some(ip) = bind_address {
+        socket.bind(SocketAddr::new(ip, 0))?;
+    }
+    let stream = socket.connect(addr).await?;
+    options.apply(&stream)?;
+    Ok(stream)
+}
+
+/// Listen for peers on `addr`. The connections accepted get the buffer sizes in
+/// `options`, but the rest need to be applied to each.
+pub fn listen(addr: SocketAddr, options: &SocketOptions) -> io::Result<TcpListener> {
+    let socket = tcp_socket(addr)?;
+    socket.set_reuseaddr(true)?;
+    options.set_buffers(&socket)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// A UDP socket on an ephemeral port of `bind_address`, or of every IPv4 interface.
+pub async fn bind_udp(bind_address: Option<IpAddr>) -> io::Result<UdpSocket> {
+    let ip = bind_address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).await?;
+    ignore_port_unreachable(SockRef::from(&socket))?;
+    Ok(socket)
+}
+
+/// Stop a UDP socket's receives failing because an earlier send was answered with an
+/// ICMP port unreachable, which Windows reports on the next receive, and which would
+/// otherwise let one dead node or tracker disrupt the rest. Other platforms only
+/// report them on connected sockets, for the address they're connected to.
+#[cfg(windows)]
+pub fn ignore_port_unreachable(socket: SockRef<'_>) -> io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+    use windows_sys::Win32::Networking::WinSock::{
+        WSAIoctl, SIO_UDP_CONNRESET, SOCKET, SOCKET_ERROR,
+    };
+
+    let report: u32 = 0;
+    let mut returned = 0;
+    // SAFETY: the socket is open for as long as it's borrowed, and the input buffer
+    // outlives the synchronous call.
+    let result = unsafe {
+        WSAIoctl(
+            socket.as_raw_socket() as SOCKET,
+            SIO_UDP_CONNRESET,
+            &report as *const u32 as *const std::ffi::c_void,
+            std::mem::size_of::<u32>() as u32,
+            std::ptr::null_mut(),
+            0,
+            &mut returned,
+            std::ptr::null_mut(),
+            None,
+        )
+    };
+    match result {
+        SOCKET_ERROR => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn ignore_port_unreachable(_socket: SockRef<'_>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether `ip` is reachable across the internet, rather than being this machine's, one
+/// on a private, link-local or carrier-grade NAT network, a reserved one, or an IPv6
+/// address standing in for an IPv4 one.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            let shared = a == 100 && b & 0xc0 == 64;
+            let this_network = a == 0;
+            let protocol_assignments = [a, b, c] == [192, 0, 0];
+            let benchmarking = a == 198 && b & 0xfe == 18;
+            let reserved = a >= 240;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || shared
+                || this_network
+                || protocol_assignments
+                || benchmarking
+                || reserved)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            let unique_local = segments[0] & 0xfe00 == 0xfc00;
+            let link_local = segments[0] & 0xffc0 == 0xfe80;
+            // Addresses which stand in for IPv4 ones, through translators or relays.
+            let nat64 = segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+            let six_to_four = segments[0] == 0x2002;
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || unique_local
+                || link_local
+                || nat64
+                || six_to_four)
+        }
+    }
+}
+
+/// Whether `ip` still belongs to one of our interfaces, so sockets can be bound to it.
+pub fn is_available(ip: IpAddr) -> bool {
+    std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connect_from_bind_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let stream = connect(addr, Some(localhost), &SocketOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), localhost);
+        assert!(stream.nodelay().unwrap());
+
+        assert!(is_available(localhost));
+        // TEST-NET-1 is reserved for documentation, so no machine should have it.
+        assert!(!is_available("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn tell_public_addresses() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        for ip in [
+            "93.184.216.34",
+            "2606:2800:220:1::1",
+            "::ffff:93.184.216.34",
+        ] {
+            assert!(public(ip), "{}", ip);
+        }
+        for ip in [
+            "10.1.2.3",
+            "100.64.0.1",
+            "0.1.2.3",
+            "192.0.0.8",
+            "198.19.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "fd00::1",
+            "64:ff9b::5db8:d822",
+            "2002:5db8:d822::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn tune_dialed_and_accepted_sockets() {
+        let options = SocketOptions {
+            nodelay: false,
+            keepalive: Some(Duration::from_secs(30)),
+            recv_buffer: Some(256 * 1024),
+            send_buffer: Some(256 * 1024),
+        };
+        let listener = listen("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let dialed = connect(addr, None, &options).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        options.apply(&accepted).unwrap();
+        for stream in [&dialed, &accepted] {
+            let socket = SockRef::from(stream);
+            assert!(!stream.nodelay().unwrap());
+            assert!(socket.keepalive().unwrap());
+            // Linux doubles the sizes asked for, to leave room for bookkeeping.
+            assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+            assert!(socket.send_buffer_size().unwrap() >= 256 * 1024);
+        }
+    }
+}
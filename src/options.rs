@@ -0,0 +1,50 @@
+//! Settings for a single torrent, which take precedence over the client's configuration.
+
+use crate::picker::Priority;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AddTorrentOptions {
+    /// Directory to save the torrent in, instead of the client's save path.
+    pub save_path: Option<PathBuf>,
+    /// Name to show the torrent by instead of its own. Its files keep their names.
+    pub name: Option<String>,
+    /// Add the torrent without starting it, until it's resumed.
+    pub paused: bool,
+    /// Run the torrent whatever the client's limits on active torrents.
+    pub force_start: bool,
+    /// Download pieces in order.
+    pub sequential: bool,
+    /// Maximum download rate, in bytes a second.
+    pub download_limit: Option<u64>,
+    /// Maximum upload rate, in bytes a second.
+    pub upload_limit: Option<u64>,
+    /// Keep seeding after the download completes, until we've uploaded this many times
+    /// the torrent's size.
+    pub seed_ratio: Option<f64>,
+    /// Priorities of the torrent's files, in order. Files without one are normal priority.
+    pub file_priorities: Vec<Priority>,
+    /// Label for organising torrents, which can also choose where they're saved.
+    pub category: Option<String>,
+    /// Tiers of trackers to announce to instead of the torrent's own.
+    pub trackers: Option<Vec<Vec<String>>>,
+    /// The torrent's share of peer connections, memory for pieces, turns at the disk and
+    /// the alternate speed caps when torrents compete for them, relative to other
+    /// torrents' weights. 1 if not set.
+    pub weight: Option<u32>,
+}
+
+impl AddTorrentOptions {
+    pub fn weight(&self) -> u32 {
+        self.weight.unwrap_or(1).max(1)
+    }
+
+    /// How many bytes the torrent has to upload to reach its seed ratio, if it has one.
+    pub fn seed_goal(&self, total_length: u64) -> Option<u64> {
+        self.seed_ratio
+            .filter(|&ratio| ratio > 0.0)
+            .map(|ratio| (ratio * total_length as f64) as u64)
+    }
+}
@@ -0,0 +1,108 @@
+//! The extension protocol from BEP 10.
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// Extended message id of the extension handshake.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+/// How many outstanding requests from a peer we advertise we can queue.
+pub const OUR_REQQ: u32 = 250;
+
+/// The dictionary peers exchange after the handshake to agree on extensions.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names, mapped to the message ids the sender wants them sent with.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// How many outstanding requests the sender will queue before dropping them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    /// Size of the torrent's info dictionary, if the sender has it and supports BEP 9.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+    /// 1 if the sender only uploads, because it's a seed or a partial seed (BEP 21).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_only: Option<u8>,
+    /// The address the sender sees the receiver at, as 4 or 16 bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+}
+
+impl ExtendedHandshake {
+    /// The handshake we send to peers which support extensions.
+    pub fn ours() -> Self {
+        Self {
+            m: BTreeMap::new(),
+            v: Some(format!("torrent {}", env!("CARGO_PKG_VERSION"))),
+            reqq: Some(OUR_REQQ),
+            metadata_size: None,
+            upload_only: None,
+            yourip: None,
+        }
+    }
+
+    pub fn is_upload_only(&self) -> bool {
+        self.upload_only.unwrap_or(0) != 0
+    }
+
+    /// The address the sender sees us at, if it told us.
+    pub fn yourip(&self) -> Option<IpAddr> {
+        let ip = self.yourip.as_ref()?;
+        match ip.len() {
+            4 => Some(<[u8; 4]>::try_from(ip.as_slice()).ok()?.into()),
+            16 => Some(<[u8; 16]>::try_from(ip.as_slice()).ok()?.into()),
+            _ => None,
+        }
+    }
+
+    pub fn set_yourip(&mut self, ip: IpAddr) {
+        let ip = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        self.yourip = Some(ByteBuf::from(ip));
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_extended_handshake() {
+        let bytes = b"d1:md11:ut_metadatai3ee13:metadata_sizei31235e1:pi6881e4:reqqi500e11:upload_onlyi1e1:v14:uTorrent 3.5.5e";
+        let handshake = ExtendedHandshake::from_bytes(bytes).unwrap();
+
+        assert!(handshake.is_upload_only());
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.m.get("ut_metadata"), Some(&3));
+        assert_eq!(handshake.metadata_size, Some(31235));
+        assert_eq!(handshake.v.as_deref(), Some("uTorrent 3.5.5"));
+        assert_eq!(handshake.yourip(), None);
+    }
+
+    #[test]
+    fn round_trip_our_handshake() {
+        let mut ours = ExtendedHandshake::ours();
+        let ip: IpAddr = "2001:db8::7".parse().unwrap();
+        ours.set_yourip(ip);
+        let bytes = ours.to_bytes().unwrap();
+
+        let theirs = ExtendedHandshake::from_bytes(&bytes).unwrap();
+        assert_eq!(theirs, ours);
+        assert_eq!(theirs.yourip(), Some(ip));
+    }
+}
@@ -0,0 +1,188 @@
+//! Entry points for fuzzing the wire codecs with arbitrary bytes. They panic only if a
+//! codec misbehaves: decoding mustn't panic, must give the same messages however the
+//! input is split up as it arrives, and must give back what was encoded.
+
+use super::{Handshake, HandshakeCodec, PeerMessageCodec};
+use bytes::BytesMut;
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Decode everything a codec can from `data`, delivered `chunk_len` bytes at a time.
+pub fn decode_in_chunks<C: Decoder<Error = io::Error>>(
+    codec: &mut C,
+    data: &[u8],
+    chunk_len: usize,
+) -> io::Result<Vec<C::Item>> {
+    let mut buf = BytesMut::new();
+    let mut items = Vec::new();
+    for chunk in data.chunks(chunk_len.max(1)) {
+        buf.extend_from_slice(chunk);
+        while let Some(item) = codec.decode(&mut buf)? {
+            items.push(item);
+        }
+    }
+
+    Ok(items)
+}
+
+/// Decode peer messages from `data`. The first byte picks how the rest is split up.
+pub fn peer_messages(data: &[u8]) {
+    let (chunk_len, data) = match data.split_first() {
+        Some((&chunk_len, data)) => (chunk_len as usize, data),
+        None => return,
+    };
+
+    let whole = decode_in_chunks(&mut PeerMessageCodec::default(), data, data.len());
+    let chunked = decode_in_chunks(&mut PeerMessageCodec::default(), data, chunk_len);
+    match (whole, chunked) {
+        (Ok(whole), Ok(chunked)) => {
+            assert_eq!(whole, chunked);
+            assert_eq!(
+                round_trip(&mut PeerMessageCodec::default(), whole.clone()),
+                whole
+            );
+        }
+        (Err(_), Err(_)) => {}
+        (whole, chunked) => panic!("Decoded {:?} whole but {:?} in chunks", whole, chunked),
+    }
+}
+
+/// Decode a handshake from `data`. The first byte picks how the rest is split up.
+pub fn handshake(data: &[u8]) {
+    let (chunk_len, data) = match data.split_first() {
+        Some((&chunk_len, data)) => (chunk_len as usize, data),
+        None => return,
+    };
+
+    let whole = decode_in_chunks(&mut HandshakeCodec, data, data.len()).map(first);
+    let chunked = decode_in_chunks(&mut HandshakeCodec, data, chunk_len).map(first);
+    match (whole, chunked) {
+        (Ok(whole), Ok(chunked)) => {
+            assert_eq!(whole, chunked);
+            if let Some(handshake) = whole {
+                let decoded = round_trip(&mut HandshakeCodec, vec![handshake.clone()]);
+                assert_eq!(decoded, vec![handshake]);
+            }
+        }
+        (Err(_), Err(_)) => {}
+        (whole, chunked) => panic!("Decoded {:?} whole but {:?} in chunks", whole, chunked),
+    }
+}
+
+/// Only the first handshake on a connection is decoded as one.
+fn first(handshakes: Vec<Handshake>) -> Option<Handshake> {
+    handshakes.into_iter().next()
+}
+
+fn round_trip<T, C>(codec: &mut C, items: Vec<T>) -> Vec<T>
+where
+    C: Encoder<T, Error = io::Error> + Decoder<Item = T, Error = io::Error>,
+{
+    let mut buf = BytesMut::new();
+    for item in items {
+        codec.encode(item, &mut buf).unwrap();
+    }
+    decode_in_chunks(codec, &buf, buf.len()).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::peer::{HashRequest, PeerMessage};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    fn hash_request() -> impl Strategy<Value = HashRequest> {
+        any::<([u8; 32], u32, u32, u32, u32)>().prop_map(
+            |(pieces_root, base_layer, index, length, proof_layers)| HashRequest {
+                pieces_root,
+                base_layer,
+                index,
+                length,
+                proof_layers,
+            },
+        )
+    }
+
+    fn peer_message() -> impl Strategy<Value = PeerMessage> {
+        let bytes = || vec(any::<u8>(), 0..64);
+        prop_oneof![
+            Just(PeerMessage::KeepAlive),
+            Just(PeerMessage::Choke),
+            Just(PeerMessage::Unchoke),
+            Just(PeerMessage::Interested),
+            Just(PeerMessage::NotInterested),
+            any::<u32>().prop_map(PeerMessage::Have),
+            bytes().prop_map(PeerMessage::Bitfield),
+            any::<(u32, u32, u32)>().prop_map(|(i, b, l)| PeerMessage::Request(i, b, l)),
+            (any::<u32>(), any::<u32>(), bytes()).prop_map(|(i, b, d)| PeerMessage::Piece(
+                i,
+                b,
+                d.into()
+            )),
+            any::<(u32, u32, u32)>().prop_map(|(i, b, l)| PeerMessage::Cancel(i, b, l)),
+            any::<(u32, u32, u32)>().prop_map(|(i, b, l)| PeerMessage::RejectRequest(i, b, l)),
+            (any::<u8>(), bytes()).prop_map(|(id, p)| PeerMessage::Extended(id, p)),
+            hash_request().prop_map(PeerMessage::HashRequest),
+            (hash_request(), vec(any::<[u8; 32]>(), 0..8))
+                .prop_map(|(req, hashes)| PeerMessage::Hashes(req, hashes)),
+            hash_request().prop_map(PeerMessage::HashReject),
+            (9..=15_u8, bytes()).prop_map(|(id, p)| PeerMessage::Unknown(id, p)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn messages_survive_fragmented_delivery(
+            messages in vec(peer_message(), 0..16),
+            chunk_len in 1..32_usize,
+        ) {
+            let mut buf = BytesMut::new();
+            for msg in messages.clone() {
+                PeerMessageCodec::default().encode(msg, &mut buf).unwrap();
+            }
+
+            let decoded = decode_in_chunks(&mut PeerMessageCodec::default(), &buf, chunk_len).unwrap();
+            prop_assert_eq!(decoded, messages);
+        }
+
+        #[test]
+        fn handshakes_survive_fragmented_delivery(
+            info_hash in any::<[u8; 20]>(),
+            peer_id in any::<[u8; 20]>(),
+            reserved in any::<[u8; 8]>(),
+            chunk_len in 1..70_usize,
+        ) {
+            let handshake = Handshake::with_reserved(&info_hash, &peer_id, reserved);
+            let mut buf = BytesMut::new();
+            HandshakeCodec.encode(handshake.clone(), &mut buf).unwrap();
+
+            let decoded = decode_in_chunks(&mut HandshakeCodec, &buf, chunk_len).unwrap();
+            prop_assert_eq!(decoded, vec![handshake]);
+        }
+
+        #[test]
+        fn arbitrary_bytes_decode_consistently(data in vec(any::<u8>(), 0..256)) {
+            peer_messages(&data);
+            handshake(&data);
+        }
+    }
+
+    #[test]
+    fn message_prefixes_decode_consistently() {
+        let mut buf = BytesMut::new();
+        PeerMessageCodec::default()
+            .encode(PeerMessage::Piece(1, 2, b"data".to_vec().into()), &mut buf)
+            .unwrap();
+        PeerMessageCodec::default()
+            .encode(PeerMessage::Have(3), &mut buf)
+            .unwrap();
+        for len in 0..=buf.len() {
+            for chunk_len in 1..=8 {
+                let mut data = vec![chunk_len];
+                data.extend_from_slice(&buf[..len]);
+                peer_messages(&data);
+            }
+        }
+    }
+}
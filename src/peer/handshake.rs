@@ -1,8 +1,72 @@
 use bytes::{Buf, BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use tokio_util::codec::{Decoder, Encoder};
 
 pub(crate) const PROTOCOL_NAME: [u8; 19] = *b"BitTorrent protocol";
+/// Reserved bit 20 (from the right) signals support for the extension protocol.
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+/// The last reserved byte has the bits for the fast extension and DHT.
+const FAST_BYTE: usize = 7;
+const FAST_BIT: u8 = 0x04;
+const DHT_BYTE: usize = 7;
+const DHT_BIT: u8 = 0x01;
+
+/// Reserved bits we send by default: we only implement the extension protocol.
+pub const DEFAULT_RESERVED: [u8; 8] = {
+    let mut reserved = [0_u8; 8];
+    reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+    reserved
+};
+
+/// The extensions a handshake's reserved bits advertise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extensions {
+    pub extension_protocol: bool,
+    pub fast: bool,
+    pub dht: bool,
+}
+
+impl Extensions {
+    pub fn from_reserved(reserved: &[u8; 8]) -> Self {
+        Self {
+            extension_protocol: reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0,
+            fast: reserved[FAST_BYTE] & FAST_BIT != 0,
+            dht: reserved[DHT_BYTE] & DHT_BIT != 0,
+        }
+    }
+
+    /// The extensions both sides advertised, which are the ones we can use.
+    pub fn negotiate(&self, theirs: &Self) -> Self {
+        Self {
+            extension_protocol: self.extension_protocol && theirs.extension_protocol,
+            fast: self.fast && theirs.fast,
+            dht: self.dht && theirs.dht,
+        }
+    }
+}
+
+impl std::fmt::Display for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = [
+            (self.extension_protocol, "ext"),
+            (self.fast, "fast"),
+            (self.dht, "dht"),
+        ];
+        let names: Vec<_> = names
+            .iter()
+            .filter(|(enabled, _)| *enabled)
+            .map(|(_, name)| *name)
+            .collect();
+
+        if names.is_empty() {
+            write!(f, "-")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Handshake {
@@ -16,14 +80,30 @@ pub struct Handshake {
 pub struct HandshakeCodec;
 
 impl Handshake {
+    /// Bytes of a handshake on the wire.
+    pub const LEN: usize = 1 + PROTOCOL_NAME.len() + 8 + 20 + 20;
+
     pub fn new(info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Self {
+        Self::with_reserved(info_hash, peer_id, DEFAULT_RESERVED)
+    }
+
+    /// A handshake advertising the features in `reserved` instead of the ones we support.
+    pub fn with_reserved(info_hash: &[u8; 20], peer_id: &[u8; 20], reserved: [u8; 8]) -> Self {
         Self {
             info_hash: info_hash.to_owned(),
             peer_id: peer_id.to_owned(),
             protocol_name: PROTOCOL_NAME,
-            reserved: [0_u8; 8],
+            reserved,
         }
     }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.extensions().extension_protocol
+    }
+
+    pub fn extensions(&self) -> Extensions {
+        Extensions::from_reserved(&self.reserved)
+    }
 }
 
 impl Encoder<Handshake> for HandshakeCodec {
@@ -32,7 +112,7 @@ impl Encoder<Handshake> for HandshakeCodec {
     fn encode(&mut self, item: Handshake, dst: &mut BytesMut) -> Result<(), Self::Error> {
         dst.put_u8(item.protocol_name.len().try_into().unwrap());
         dst.extend_from_slice(&item.protocol_name);
-        dst.extend_from_slice(&[0u8; 8]);
+        dst.extend_from_slice(&item.reserved);
         dst.extend_from_slice(&item.info_hash);
         dst.extend_from_slice(&item.peer_id);
 
@@ -107,5 +187,26 @@ mod test {
         let round_tripped_handshake = codec.decode(&mut bytes).unwrap().unwrap();
 
         assert_eq!(original_handshake, round_tripped_handshake);
+        assert!(round_tripped_handshake.supports_extensions());
+    }
+
+    #[test]
+    fn negotiate_reserved_bits() {
+        let mut reserved = [0_u8; 8];
+        reserved[7] = 0x05;
+        let theirs = Handshake::with_reserved(&[1u8; 20], &[2u8; 20], reserved).extensions();
+        assert_eq!(
+            theirs,
+            Extensions {
+                extension_protocol: false,
+                fast: true,
+                dht: true,
+            }
+        );
+
+        let ours = Extensions::from_reserved(&DEFAULT_RESERVED);
+        assert_eq!(ours.negotiate(&theirs), Extensions::default());
+        assert_eq!(theirs.to_string(), "fast,dht");
+        assert_eq!(Extensions::default().to_string(), "-");
     }
 }
@@ -0,0 +1,89 @@
+//! Pieces of merkle torrents, sent with the hashes needed to check them against the
+//! root hash (BEP 30).
+
+use anyhow::anyhow;
+use serde_bytes::ByteBuf;
+
+pub const TR_HASHPIECE: &str = "Tr_hashpiece";
+/// Extended message id we ask peers to send pieces with their hashes with.
+pub const OUR_TR_HASHPIECE_ID: u8 = 2;
+
+/// A block, and if it starts its piece, the hashes on the path from the piece to the
+/// root of the tree, by their offset in it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashPiece {
+    pub piece: u32,
+    pub begin: u32,
+    pub hashes: Vec<(usize, [u8; 20])>,
+    pub data: Vec<u8>,
+}
+
+impl HashPiece {
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let hashes: Vec<(usize, ByteBuf)> = self
+            .hashes
+            .iter()
+            .map(|(offset, hash)| (*offset, ByteBuf::from(hash.to_vec())))
+            .collect();
+        let hashes = serde_bencode::to_bytes(&hashes)?;
+
+        let mut bytes = Vec::with_capacity(12 + hashes.len() + self.data.len());
+        bytes.extend_from_slice(&self.piece.to_be_bytes());
+        bytes.extend_from_slice(&self.begin.to_be_bytes());
+        bytes.extend_from_slice(&(hashes.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&hashes);
+        bytes.extend_from_slice(&self.data);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let int = |at: usize| -> anyhow::Result<u32> {
+            let int = bytes
+                .get(at..at + 4)
+                .ok_or_else(|| anyhow!("Hash piece message too short"))?;
+            Ok(u32::from_be_bytes(int.try_into().unwrap()))
+        };
+        let (piece, begin, hashes_len) = (int(0)?, int(4)?, int(8)? as usize);
+        let hashes = bytes
+            .get(12..12 + hashes_len)
+            .ok_or_else(|| anyhow!("Hash piece message too short"))?;
+        let hashes: Vec<(usize, ByteBuf)> = serde_bencode::from_bytes(hashes)?;
+        let hashes = hashes
+            .into_iter()
+            .map(|(offset, hash)| match hash[..].try_into() {
+                Ok(hash) => Ok((offset, hash)),
+                Err(_) => Err(anyhow!("Hash in hash piece message isn't 20 bytes long")),
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            piece,
+            begin,
+            hashes,
+            data: bytes[12 + hashes_len..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode_hash_piece() {
+        let msg = HashPiece {
+            piece: 3,
+            begin: 0,
+            hashes: vec![(6, [1; 20]), (1, [2; 20])],
+            data: b"data".to_vec(),
+        };
+        let bytes = msg.to_bytes().unwrap();
+        assert_eq!(&bytes[..12], &[0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 58]);
+        assert_eq!(HashPiece::from_bytes(&bytes).unwrap(), msg);
+
+        assert!(HashPiece::from_bytes(&bytes[..20]).is_err());
+        let mut bad_hash = bytes.clone();
+        bad_hash[11] = 57;
+        assert!(HashPiece::from_bytes(&bad_hash).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! Telling peers about the pieces we complete after the handshake, with `Have`
+//! messages. Peers which already have a piece don't need telling, and on torrents
+//! whose pieces complete quickly the Haves can be collected up and sent together.
+
+use crate::bitfield::{Bitfield, BitfieldMut};
+use tokio::sync::watch;
+use tokio::time::{self, Duration, Instant};
+
+/// How long lazy Haves are collected for before they're sent.
+const LAZY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// When to tell peers about pieces we've completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HavePolicy {
+    /// Every piece, to every peer, as soon as it's verified.
+    All,
+    /// As soon as it's verified, but not to peers which already have the piece, such as
+    /// seeds.
+    SkipRedundant,
+    /// Like [`HavePolicy::SkipRedundant`], but collected up and sent every few seconds.
+    Lazy,
+}
+
+impl std::str::FromStr for HavePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "skip-redundant" => Ok(Self::SkipRedundant),
+            "lazy" => Ok(Self::Lazy),
+            _ => Err(anyhow::anyhow!("Unknown have policy: {}", s)),
+        }
+    }
+}
+
+/// The pieces we've told a peer we have, and when to tell it about more.
+#[derive(Debug)]
+pub(crate) struct Haves {
+    policy: HavePolicy,
+    /// Pieces we've sent the peer a Bitfield or Have for, or decided not to.
+    announced: Vec<u8>,
+    /// Changes when the torrent completes a piece.
+    completions: watch::Receiver<u64>,
+    last_sent: Instant,
+}
+
+impl Haves {
+    /// Haves for a peer we've sent the `announced` bitfield to.
+    pub fn new(policy: HavePolicy, announced: Vec<u8>, completions: watch::Receiver<u64>) -> Self {
+        Self {
+            policy,
+            announced,
+            completions,
+            last_sent: Instant::now(),
+        }
+    }
+
+    /// Wait until there may be pieces to tell the peer about.
+    pub async fn due(&mut self) {
+        if self.completions.changed().await.is_err() {
+            return futures::future::pending().await;
+        }
+        if self.policy == HavePolicy::Lazy {
+            time::sleep_until(self.last_sent + LAZY_INTERVAL).await;
+        }
+    }
+
+    /// The pieces in `ours` to send a peer with `theirs` Haves for, now or when they're
+    /// next due.
+    pub fn take(&mut self, ours: &[u8], theirs: &[u8]) -> Vec<u32> {
+        let now = Instant::now();
+        if self.policy == HavePolicy::Lazy && now < self.last_sent + LAZY_INTERVAL {
+            return Vec::new();
+        }
+        self.completions.mark_unchanged();
+        self.last_sent = now;
+
+        let mut haves = Vec::new();
+        for idx in 0..self.announced.len() * 8 {
+            if !ours.has_piece(idx) || self.announced.has_piece(idx) {
+                continue;
+            }
+            self.announced.set_piece(idx);
+            let redundant = idx / 8 < theirs.len() && theirs.has_piece(idx);
+            if self.policy == HavePolicy::All || !redundant {
+                haves.push(idx as u32);
+            }
+        }
+        haves
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn skip_and_batch_haves() {
+        let (_completions_tx, completions) = watch::channel(0);
+        let ours = [0b1110_0000];
+        let theirs = [0b0100_0000];
+
+        let mut haves = Haves::new(HavePolicy::All, vec![0b1000_0000], completions.clone());
+        assert_eq!(haves.take(&ours, &theirs), vec![1, 2]);
+        assert!(haves.take(&ours, &theirs).is_empty());
+
+        let mut haves = Haves::new(HavePolicy::SkipRedundant, vec![0], completions.clone());
+        assert_eq!(haves.take(&ours, &theirs), vec![0, 2]);
+
+        // Lazy Haves wait out the interval since the last ones were sent.
+        let mut haves = Haves::new(HavePolicy::Lazy, vec![0], completions);
+        assert!(haves.take(&ours, &theirs).is_empty());
+        haves.last_sent -= LAZY_INTERVAL;
+        assert_eq!(haves.take(&ours, &theirs), vec![0, 2]);
+    }
+}
@@ -0,0 +1,332 @@
+//! NAT traversal with the holepunch extension (BEP 55). A peer we can't dial asks a
+//! peer both of us are connected to to relay a rendezvous, and the relay tells each of
+//! us to connect to the other at once, which gets through most NATs.
+
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::debug;
+
+pub const UT_HOLEPUNCH: &str = "ut_holepunch";
+/// Extended message id we ask peers to send holepunch messages with.
+pub const OUR_UT_HOLEPUNCH_ID: u8 = 3;
+/// How long a relay has to answer a rendezvous we asked for.
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchMessage {
+    /// Ask the relay to put us in touch with the peer at the address.
+    Rendezvous(SocketAddr),
+    /// Connect to the peer at the address, which is connecting to us too.
+    Connect(SocketAddr),
+    /// The relay couldn't put us in touch with the peer at the address.
+    Error(SocketAddr, HolepunchError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchError {
+    /// The address isn't one a peer could be at.
+    NoSuchPeer,
+    /// The relay isn't connected to the peer.
+    NotConnected,
+    /// The peer doesn't support holepunching.
+    NoSupport,
+    /// We asked to be put in touch with ourselves.
+    NoSelf,
+    Other(u32),
+}
+
+impl HolepunchError {
+    fn code(self) -> u32 {
+        match self {
+            Self::NoSuchPeer => 1,
+            Self::NotConnected => 2,
+            Self::NoSupport => 3,
+            Self::NoSelf => 4,
+            Self::Other(code) => code,
+        }
+    }
+
+    fn from_code(code: u32) -> Self {
+        match code {
+            1 => Self::NoSuchPeer,
+            2 => Self::NotConnected,
+            3 => Self::NoSupport,
+            4 => Self::NoSelf,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl std::fmt::Display for HolepunchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSuchPeer => write!(f, "No such peer"),
+            Self::NotConnected => write!(f, "Relay isn't connected to the peer"),
+            Self::NoSupport => write!(f, "Peer doesn't support holepunching"),
+            Self::NoSelf => write!(f, "Can't holepunch to ourselves"),
+            Self::Other(code) => write!(f, "Error {}", code),
+        }
+    }
+}
+
+impl HolepunchMessage {
+    /// The message type, address type, address, port and error code, as BEP 55 lays
+    /// them out.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let (kind, addr, error) = match self {
+            Self::Rendezvous(addr) => (0, addr, 0),
+            Self::Connect(addr) => (1, addr, 0),
+            Self::Error(addr, error) => (2, addr, error.code()),
+        };
+        let mut bytes = vec![kind];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(0);
+                bytes.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&ip.octets());
+            }
+        }
+        bytes.extend_from_slice(&addr.port().to_be_bytes());
+        bytes.extend_from_slice(&error.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let invalid = || anyhow!("Invalid holepunch message");
+        let (ip, rest): (IpAddr, _) = match bytes.get(1) {
+            Some(0) if bytes.len() == 12 => {
+                let ip: [u8; 4] = bytes[2..6].try_into().unwrap();
+                (Ipv4Addr::from(ip).into(), &bytes[6..])
+            }
+            Some(1) if bytes.len() == 24 => {
+                let ip: [u8; 16] = bytes[2..18].try_into().unwrap();
+                (Ipv6Addr::from(ip).into(), &bytes[18..])
+            }
+            _ => return Err(invalid()),
+        };
+        let addr = SocketAddr::new(ip, u16::from_be_bytes([rest[0], rest[1]]));
+        let error = u32::from_be_bytes(rest[2..6].try_into().unwrap());
+
+        match bytes[0] {
+            0 => Ok(Self::Rendezvous(addr)),
+            1 => Ok(Self::Connect(addr)),
+            2 => Ok(Self::Error(addr, HolepunchError::from_code(error))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A torrent's sessions, so any of them can relay holepunch messages to the others, and
+/// the peers relays told us to connect to.
+#[derive(Debug, Clone)]
+pub struct Holepunch {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    sessions: Mutex<HashMap<SocketAddr, Relay>>,
+    /// The peers we asked relays to put us in touch with, with the relay we asked and
+    /// when. Only relays answering these are listened to, so peers can't have us dial
+    /// wherever they like.
+    pending: Mutex<HashMap<SocketAddr, (SocketAddr, Instant)>>,
+    connect_tx: UnboundedSender<SocketAddr>,
+    connect_rx: tokio::sync::Mutex<UnboundedReceiver<SocketAddr>>,
+}
+
+/// Where to send holepunch messages for a session's peer.
+#[derive(Debug)]
+struct Relay {
+    tx: UnboundedSender<HolepunchMessage>,
+    /// The peer told us it supports holepunching.
+    supported: bool,
+}
+
+impl Default for Holepunch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Holepunch {
+    pub fn new() -> Self {
+        let (connect_tx, connect_rx) = mpsc::unbounded_channel();
+        Self {
+            inner: Arc::new(Inner {
+                sessions: Default::default(),
+                pending: Default::default(),
+                connect_tx,
+                connect_rx: tokio::sync::Mutex::new(connect_rx),
+            }),
+        }
+    }
+
+    /// Add the session with the peer at `addr`, returning the messages it should send
+    /// the peer.
+    pub fn register(&self, addr: SocketAddr) -> UnboundedReceiver<HolepunchMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let relay = Relay {
+            tx,
+            supported: false,
+        };
+        self.inner.sessions.lock().unwrap().insert(addr, relay);
+        rx
+    }
+
+    pub fn unregister(&self, addr: &SocketAddr) {
+        self.inner.sessions.lock().unwrap().remove(addr);
+    }
+
+    /// Note that the peer at `addr` told us it supports holepunching.
+    pub fn set_supported(&self, addr: &SocketAddr) {
+        if let Some(relay) = self.inner.sessions.lock().unwrap().get_mut(addr) {
+            relay.supported = true;
+        }
+    }
+
+    /// Relay a rendezvous with `target` that the peer at `from` asked for, returning
+    /// what to tell `from`.
+    pub fn rendezvous(&self, from: SocketAddr, target: SocketAddr) -> HolepunchMessage {
+        if from == target {
+            return HolepunchMessage::Error(target, HolepunchError::NoSelf);
+        }
+        if target.ip().is_unspecified() || target.port() == 0 {
+            return HolepunchMessage::Error(target, HolepunchError::NoSuchPeer);
+        }
+        let sessions = self.inner.sessions.lock().unwrap();
+        match sessions.get(&target) {
+            None => HolepunchMessage::Error(target, HolepunchError::NotConnected),
+            Some(relay) if !relay.supported => {
+                HolepunchMessage::Error(target, HolepunchError::NoSupport)
+            }
+            Some(relay) => match relay.tx.send(HolepunchMessage::Connect(from)) {
+                Ok(()) => HolepunchMessage::Connect(target),
+                Err(_) => HolepunchMessage::Error(target, HolepunchError::NotConnected),
+            },
+        }
+    }
+
+    /// Ask a peer which supports holepunching to put us in touch with `target`, returning
+    /// whether there was one to ask.
+    pub fn request(&self, target: SocketAddr) -> bool {
+        let sessions = self.inner.sessions.lock().unwrap();
+        let relay = sessions
+            .iter()
+            .filter(|(addr, relay)| **addr != target && relay.supported)
+            .find(|(_, relay)| relay.tx.send(HolepunchMessage::Rendezvous(target)).is_ok());
+        match relay {
+            Some((&addr, _)) => {
+                debug!("Asking {} to relay a holepunch to {}", addr, target);
+                let now = Instant::now();
+                let mut pending = self.inner.pending.lock().unwrap();
+                pending.retain(|_, (_, asked)| now.duration_since(*asked) < RENDEZVOUS_TIMEOUT);
+                pending.insert(target, (addr, now));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Note that the relay at `relay` told us to connect to the peer at `addr`,
+    /// returning whether it was answering a rendezvous we asked it for. Those it
+    /// wasn't are ignored.
+    pub fn connect(&self, relay: SocketAddr, addr: SocketAddr) -> bool {
+        let asked = {
+            let mut pending = self.inner.pending.lock().unwrap();
+            match pending.get(&addr) {
+                Some(&(asked, at)) if asked == relay => {
+                    pending.remove(&addr);
+                    at.elapsed() < RENDEZVOUS_TIMEOUT
+                }
+                _ => false,
+            }
+        };
+        if asked {
+            let _ = self.inner.connect_tx.send(addr);
+        }
+        asked
+    }
+
+    /// Wait for a relay to tell us to connect to a peer.
+    pub async fn next_connect(&self) -> SocketAddr {
+        let mut connect_rx = self.inner.connect_rx.lock().await;
+        // We hold a sender, so the channel never closes.
+        connect_rx.recv().await.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode_messages() {
+        let v4: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let rendezvous = HolepunchMessage::Rendezvous(v4);
+        assert_eq!(
+            rendezvous.to_bytes(),
+            [0, 0, 192, 0, 2, 1, 0x1a, 0xe1, 0, 0, 0, 0]
+        );
+
+        for msg in [
+            rendezvous,
+            HolepunchMessage::Connect(v6),
+            HolepunchMessage::Error(v4, HolepunchError::NoSupport),
+            HolepunchMessage::Error(v6, HolepunchError::Other(9)),
+        ] {
+            assert_eq!(HolepunchMessage::from_bytes(&msg.to_bytes()).unwrap(), msg);
+        }
+        assert!(HolepunchMessage::from_bytes(&[0, 0, 192, 0, 2, 1]).is_err());
+        assert!(HolepunchMessage::from_bytes(&[3, 0, 192, 0, 2, 1, 0, 1, 0, 0, 0, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn relay_rendezvous() {
+        let holepunch = Holepunch::new();
+        let (a, b, c) = (
+            "192.0.2.1:1".parse().unwrap(),
+            "192.0.2.2:2".parse().unwrap(),
+            "192.0.2.3:3".parse().unwrap(),
+        );
+        let mut a_rx = holepunch.register(a);
+        let mut b_rx = holepunch.register(b);
+        holepunch.set_supported(&a);
+
+        assert_eq!(
+            holepunch.rendezvous(a, b),
+            HolepunchMessage::Error(b, HolepunchError::NoSupport)
+        );
+        assert_eq!(
+            holepunch.rendezvous(a, c),
+            HolepunchMessage::Error(c, HolepunchError::NotConnected)
+        );
+        assert_eq!(
+            holepunch.rendezvous(a, a),
+            HolepunchMessage::Error(a, HolepunchError::NoSelf)
+        );
+
+        holepunch.set_supported(&b);
+        assert_eq!(holepunch.rendezvous(a, b), HolepunchMessage::Connect(b));
+        assert_eq!(b_rx.recv().await, Some(HolepunchMessage::Connect(a)));
+
+        // We ask a supporting peer other than the one we can't reach, and only dial
+        // the peer when the relay we asked answers, once.
+        assert!(holepunch.request(b));
+        assert_eq!(a_rx.recv().await, Some(HolepunchMessage::Rendezvous(b)));
+        assert!(!holepunch.connect(a, c));
+        assert!(!holepunch.connect(c, b));
+        assert!(holepunch.connect(a, b));
+        assert!(!holepunch.connect(a, b));
+        assert_eq!(holepunch.next_connect().await, b);
+        holepunch.unregister(&a);
+        assert!(!holepunch.request(b));
+    }
+}
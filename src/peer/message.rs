@@ -1,20 +1,74 @@
-use bytes::{Buf, BufMut};
+use super::wire_log::{Direction, SessionLog};
+use crate::stats::Traffic;
+use bytes::{Buf, BufMut, Bytes};
 use std::convert::TryInto;
+use std::sync::Arc;
 use tokio_util::codec::{Decoder, Encoder};
 use tracing::trace;
 
+/// The longest message we'll buffer, comfortably more than the largest block or
+/// bitfield a peer should send.
+const MAX_MESSAGE_LENGTH: usize = 2 * 1024 * 1024;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum PeerMessage {
     KeepAlive,
-    Choke,                    // messageID = 0
-    Unchoke,                  // messageID = 1
-    Interested,               // messageID = 2
-    NotInterested,            // messageID = 3
-    Have(u32),                // messageID = 4
-    Bitfield(Vec<u8>),        // messageID = 5
-    Request(u32, u32, u32),   // messageID = 6
-    Piece(u32, u32, Vec<u8>), // messageID = 7
-    Cancel(u32, u32, u32),    // messageId = 8
+    Choke,                              // messageID = 0
+    Unchoke,                            // messageID = 1
+    Interested,                         // messageID = 2
+    NotInterested,                      // messageID = 3
+    Have(u32),                          // messageID = 4
+    Bitfield(Vec<u8>),                  // messageID = 5
+    Request(u32, u32, u32),             // messageID = 6
+    Piece(u32, u32, Bytes),             // messageID = 7
+    Cancel(u32, u32, u32),              // messageId = 8
+    RejectRequest(u32, u32, u32),       // messageID = 16
+    Extended(u8, Vec<u8>),              // messageID = 20
+    HashRequest(HashRequest),           // messageID = 21
+    Hashes(HashRequest, Vec<[u8; 32]>), // messageID = 22
+    HashReject(HashRequest),            // messageID = 23
+    /// A message with an ID we don't know, such as one from an extension we don't
+    /// support, which is ignored unless the codec is strict.
+    Unknown(u8, Vec<u8>),
+}
+
+/// Which hashes from a file's merkle tree in a v2 torrent a hash request, hashes or
+/// hash reject message is about (BEP 52).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    /// Root of the file's merkle tree.
+    pub pieces_root: [u8; 32],
+    /// Layer of the tree the hashes are from, counting up from the 16 KiB blocks at 0.
+    pub base_layer: u32,
+    /// Offset of the first hash in its layer.
+    pub index: u32,
+    /// How many hashes from the base layer.
+    pub length: u32,
+    /// How many layers of uncle hashes above them, to prove them against the root.
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    const LEN: usize = 32 + 4 * 4;
+
+    fn put(&self, dst: &mut bytes::BytesMut) {
+        dst.extend_from_slice(&self.pieces_root);
+        dst.put_u32(self.base_layer);
+        dst.put_u32(self.index);
+        dst.put_u32(self.length);
+        dst.put_u32(self.proof_layers);
+    }
+
+    fn get(src: &mut bytes::BytesMut) -> Self {
+        let pieces_root = src.split_to(32)[..].try_into().unwrap();
+        Self {
+            pieces_root,
+            base_layer: src.get_u32(),
+            index: src.get_u32(),
+            length: src.get_u32(),
+            proof_layers: src.get_u32(),
+        }
+    }
 }
 
 impl std::fmt::Display for PeerMessage {
@@ -41,6 +95,30 @@ impl std::fmt::Display for PeerMessage {
                 "Cancel (index {}, begin: {}, length: {})",
                 idx, begin, length
             ),
+            Self::RejectRequest(idx, begin, length) => format!(
+                "RejectRequest (index {}, begin: {}, length: {})",
+                idx, begin, length
+            ),
+            Self::Extended(id, payload) => {
+                format!("Extended (id: {}, len: {})", id, payload.len())
+            }
+            Self::HashRequest(req) => format!(
+                "HashRequest (layer: {}, index: {}, length: {})",
+                req.base_layer, req.index, req.length
+            ),
+            Self::Hashes(req, hashes) => format!(
+                "Hashes (layer: {}, index: {}, hashes: {})",
+                req.base_layer,
+                req.index,
+                hashes.len()
+            ),
+            Self::HashReject(req) => format!(
+                "HashReject (layer: {}, index: {}, length: {})",
+                req.base_layer, req.index, req.length
+            ),
+            Self::Unknown(id, payload) => {
+                format!("Unknown (id: {}, len: {})", id, payload.len())
+            }
         };
 
         write!(f, "[PeerMessage]: {}", s)
@@ -48,6 +126,28 @@ impl std::fmt::Display for PeerMessage {
 }
 
 impl PeerMessage {
+    /// The message's type, such as `request` or `piece`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::KeepAlive => "keep_alive",
+            Self::Choke => "choke",
+            Self::Unchoke => "unchoke",
+            Self::Interested => "interested",
+            Self::NotInterested => "not_interested",
+            Self::Have(_) => "have",
+            Self::Bitfield(_) => "bitfield",
+            Self::Request(_, _, _) => "request",
+            Self::Piece(_, _, _) => "piece",
+            Self::Cancel(_, _, _) => "cancel",
+            Self::RejectRequest(_, _, _) => "reject_request",
+            Self::Extended(_, _) => "extended",
+            Self::HashRequest(_) => "hash_request",
+            Self::Hashes(_, _) => "hashes",
+            Self::HashReject(_) => "hash_reject",
+            Self::Unknown(_, _) => "unknown",
+        }
+    }
+
     pub fn payload_len(&self) -> usize {
         let u32_size = std::mem::size_of::<u32>();
         match self {
@@ -60,29 +160,103 @@ impl PeerMessage {
             Self::Bitfield(p) => p.len(),
             Self::Request(_, _, _) => u32_size * 3,
             Self::Piece(_, _, p) => u32_size + u32_size + p.len(),
-            Self::Cancel(_, _, _) => u32_size * 3,
+            Self::Cancel(_, _, _) | Self::RejectRequest(_, _, _) => u32_size * 3,
+            Self::Extended(_, p) => 1 + p.len(),
+            Self::HashRequest(_) | Self::HashReject(_) => HashRequest::LEN,
+            Self::Hashes(_, hashes) => HashRequest::LEN + 32 * hashes.len(),
+            Self::Unknown(_, p) => p.len(),
         }
     }
     pub fn message_id(&self) -> Option<u8> {
         let id = match self {
             Self::KeepAlive => return None,
-            Self::Choke => 0,            // messageID = 0
-            Self::Unchoke => 1,          // messageID = 1
-            Self::Interested => 2,       // messageID = 2
-            Self::NotInterested => 3,    // messageID = 3
-            Self::Have(_) => 4,          // messageID = 4
-            Self::Bitfield(_) => 5,      // messageID = 5
-            Self::Request(_, _, _) => 6, // messageID = 6
-            Self::Piece(_, _, _) => 7,   // messageID = 7
-            Self::Cancel(_, _, _) => 8,  // messageId = 8
+            Self::Choke => 0,                   // messageID = 0
+            Self::Unchoke => 1,                 // messageID = 1
+            Self::Interested => 2,              // messageID = 2
+            Self::NotInterested => 3,           // messageID = 3
+            Self::Have(_) => 4,                 // messageID = 4
+            Self::Bitfield(_) => 5,             // messageID = 5
+            Self::Request(_, _, _) => 6,        // messageID = 6
+            Self::Piece(_, _, _) => 7,          // messageID = 7
+            Self::Cancel(_, _, _) => 8,         // messageId = 8
+            Self::RejectRequest(_, _, _) => 16, // messageID = 16
+            Self::Extended(_, _) => 20,         // messageID = 20
+            Self::HashRequest(_) => 21,         // messageID = 21
+            Self::Hashes(_, _) => 22,           // messageID = 22
+            Self::HashReject(_) => 23,          // messageID = 23
+            Self::Unknown(id, _) => *id,
         };
 
         Some(id)
     }
 }
 
-#[derive(Debug)]
-pub struct PeerMessageCodec;
+/// Encodes and decodes peer messages, counting the bytes of each.
+#[derive(Debug, Default)]
+pub struct PeerMessageCodec {
+    /// Counters every message is added to, such as the peer's and its torrent's.
+    traffic: Vec<Arc<Traffic>>,
+    /// Fail on messages with IDs we don't know.
+    strict: bool,
+    /// Where every message is logged, if it is.
+    log: Option<SessionLog>,
+    /// The extension IDs of merkle pieces we send, as the peer asked, and of those we
+    /// receive, as we asked, so their data is counted as payload.
+    hashpiece_ids: (Option<u8>, Option<u8>),
+}
+
+impl PeerMessageCodec {
+    pub fn counting(traffic: Vec<Arc<Traffic>>) -> Self {
+        Self {
+            traffic,
+            strict: false,
+            log: None,
+            hashpiece_ids: (None, None),
+        }
+    }
+
+    /// Fail on messages with IDs we don't know, rather than decoding them as
+    /// [`PeerMessage::Unknown`] to be skipped.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Log every message sent and received to `log`.
+    pub fn with_log(mut self, log: Option<SessionLog>) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Count the data of merkle pieces we receive as extended messages with `id` as
+    /// payload.
+    pub fn receive_hashpieces(&mut self, id: Option<u8>) {
+        self.hashpiece_ids.1 = id;
+    }
+
+    /// Count the data of merkle pieces we send as extended messages with `id` as
+    /// payload.
+    pub fn send_hashpieces(&mut self, id: Option<u8>) {
+        self.hashpiece_ids.0 = id;
+    }
+
+    /// Bytes of piece data in a message, with everything else in it being overhead.
+    fn piece_data_len(&self, message: &PeerMessage, direction: Direction) -> usize {
+        let hashpiece_id = match direction {
+            Direction::Sent => self.hashpiece_ids.0,
+            Direction::Received => self.hashpiece_ids.1,
+        };
+        match message {
+            PeerMessage::Piece(_, _, data) => data.len(),
+            // The piece, offset and hashes come before the data.
+            PeerMessage::Extended(id, payload) if Some(*id) == hashpiece_id => payload
+                .get(8..12)
+                .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+                .map_or(0, |hashes| payload.len().saturating_sub(12 + hashes)),
+            _ => 0,
+        }
+    }
+}
 
 impl Encoder<PeerMessage> for PeerMessageCodec {
     type Error = std::io::Error;
@@ -90,6 +264,11 @@ impl Encoder<PeerMessage> for PeerMessageCodec {
     fn encode(&mut self, item: PeerMessage, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
         use PeerMessage::*;
         let message_id = item.message_id();
+        let start = dst.len();
+        let payload = self.piece_data_len(&item, Direction::Sent);
+        if let Some(log) = &self.log {
+            log.record(Direction::Sent, &item);
+        }
 
         match item {
             KeepAlive => {
@@ -116,15 +295,44 @@ impl Encoder<PeerMessage> for PeerMessageCodec {
                 dst.put_u32(offset);
                 dst.extend_from_slice(&data);
             }
-            Request(idx, begin, length) | Cancel(idx, begin, length) => {
+            Request(idx, begin, length)
+            | Cancel(idx, begin, length)
+            | RejectRequest(idx, begin, length) => {
                 dst.put_u32(1 + 4 + 4 + 4);
                 dst.put_u8(message_id.unwrap());
                 dst.put_u32(idx);
                 dst.put_u32(begin);
                 dst.put_u32(length);
             }
+            Extended(id, payload) => {
+                dst.put_u32(1 + 1 + payload.len() as u32);
+                dst.put_u8(message_id.unwrap());
+                dst.put_u8(id);
+                dst.extend_from_slice(&payload);
+            }
+            Unknown(id, payload) => {
+                dst.put_u32(1 + payload.len() as u32);
+                dst.put_u8(id);
+                dst.extend_from_slice(&payload);
+            }
+            PeerMessage::HashRequest(req) | PeerMessage::HashReject(req) => {
+                dst.put_u32(1 + self::HashRequest::LEN as u32);
+                dst.put_u8(message_id.unwrap());
+                req.put(dst);
+            }
+            PeerMessage::Hashes(req, hashes) => {
+                dst.put_u32(1 + (self::HashRequest::LEN + 32 * hashes.len()) as u32);
+                dst.put_u8(message_id.unwrap());
+                req.put(dst);
+                for hash in hashes {
+                    dst.extend_from_slice(&hash);
+                }
+            }
         }
 
+        for traffic in &self.traffic {
+            traffic.sent(dst.len() - start, payload);
+        }
         Ok(())
     }
 }
@@ -142,13 +350,13 @@ impl Decoder for PeerMessageCodec {
         let message_length = u32::from_be_bytes(src[..4].try_into().unwrap()) as usize;
         let length_size = std::mem::size_of::<u32>();
 
-        if src.remaining() >= message_length + length_size {
-            src.advance(length_size);
-            if message_length == 0 {
-                // Keep-alive
-                return Ok(Some(PeerMessage::KeepAlive));
-            }
-        } else {
+        if message_length > MAX_MESSAGE_LENGTH {
+            return Err(invalid_message(format!(
+                "Message is {} bytes long",
+                message_length
+            )));
+        }
+        if src.remaining() < message_length + length_size {
             trace!(
                 "Read buffer is {} bytes long, message is {} bytes long",
                 src.remaining(),
@@ -157,7 +365,41 @@ impl Decoder for PeerMessageCodec {
             return Ok(None);
         }
 
+        src.advance(length_size);
+        if message_length == 0 {
+            // Keep-alive
+            if let Some(log) = &self.log {
+                log.record(Direction::Received, &PeerMessage::KeepAlive);
+            }
+            for traffic in &self.traffic {
+                traffic.received(length_size, 0);
+            }
+            return Ok(Some(PeerMessage::KeepAlive));
+        }
+
+        // Only read from this message, so a bad length can't run into the next one.
+        let mut src = src.split_to(message_length);
         let message_id = src.get_u8();
+        let payload_len = src.remaining();
+        let valid_length = match message_id {
+            0..=3 => payload_len == 0,
+            4 => payload_len == 4,
+            6 | 8 | 16 => payload_len == 12,
+            7 => payload_len >= 8,
+            20 => payload_len >= 1,
+            21 | 23 => payload_len == HashRequest::LEN,
+            22 => {
+                payload_len >= HashRequest::LEN
+                    && (payload_len - HashRequest::LEN).is_multiple_of(32)
+            }
+            _ => true,
+        };
+        if !valid_length {
+            return Err(invalid_message(format!(
+                "Message {} has a {} byte payload",
+                message_id, payload_len
+            )));
+        }
 
         let message = match message_id {
             0 => PeerMessage::Choke,
@@ -168,12 +410,7 @@ impl Decoder for PeerMessageCodec {
                 let payload = src.get_u32();
                 PeerMessage::Have(payload)
             }
-            5 => {
-                let mut payload = vec![0; message_length - 1];
-                src.copy_to_slice(&mut payload);
-
-                PeerMessage::Bitfield(payload)
-            }
+            5 => PeerMessage::Bitfield(src.to_vec()),
             6 => {
                 let idx = src.get_u32();
                 let begin = src.get_u32();
@@ -183,9 +420,8 @@ impl Decoder for PeerMessageCodec {
             7 => {
                 let idx = src.get_u32();
                 let offset = src.get_u32();
-                let mut payload = vec![0; message_length - 9];
-                src.copy_to_slice(&mut payload);
-                PeerMessage::Piece(idx, offset, payload)
+                // The block shares the read buffer rather than being copied out.
+                PeerMessage::Piece(idx, offset, src.freeze())
             }
             8 => {
                 let idx = src.get_u32();
@@ -193,18 +429,48 @@ impl Decoder for PeerMessageCodec {
                 let length = src.get_u32();
                 PeerMessage::Cancel(idx, begin, length)
             }
+            16 => {
+                let idx = src.get_u32();
+                let begin = src.get_u32();
+                let length = src.get_u32();
+                PeerMessage::RejectRequest(idx, begin, length)
+            }
+            20 => {
+                let id = src.get_u8();
+                PeerMessage::Extended(id, src.to_vec())
+            }
+            21 => PeerMessage::HashRequest(HashRequest::get(&mut src)),
+            22 => {
+                let req = HashRequest::get(&mut src);
+                let hashes = src
+                    .chunks_exact(32)
+                    .map(|hash| hash.try_into().unwrap())
+                    .collect();
+                PeerMessage::Hashes(req, hashes)
+            }
+            23 => PeerMessage::HashReject(HashRequest::get(&mut src)),
+            n if self.strict => return Err(invalid_message(format!("Invalid message ID: {}", n))),
             n => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    format!("Invalid message ID: {}", n),
-                ))
+                trace!("Skipping message with unknown ID {}", n);
+                PeerMessage::Unknown(n, src.to_vec())
             }
         };
 
+        if let Some(log) = &self.log {
+            log.record(Direction::Received, &message);
+        }
+        for traffic in &self.traffic {
+            let payload = self.piece_data_len(&message, Direction::Received);
+            traffic.received(length_size + message_length, payload);
+        }
         Ok(Some(message))
     }
 }
 
+fn invalid_message(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, reason)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -214,7 +480,7 @@ mod test {
     fn encode_decode_message() {
         let msg = PeerMessage::Request(12, 333, 4);
         let original_handshake = msg.clone();
-        let mut codec = PeerMessageCodec;
+        let mut codec = PeerMessageCodec::default();
 
         let mut bytes = BytesMut::new();
         codec.encode(msg, &mut bytes).unwrap();
@@ -225,4 +491,138 @@ mod test {
 
         assert_eq!(original_handshake, round_tripped_handshake);
     }
+
+    #[test]
+    fn count_payload_and_overhead() {
+        let traffic = Arc::new(Traffic::default());
+        let mut codec = PeerMessageCodec::counting(vec![Arc::clone(&traffic)]);
+        let mut bytes = BytesMut::new();
+        codec
+            .encode(PeerMessage::Piece(0, 0, vec![1; 100].into()), &mut bytes)
+            .unwrap();
+        codec.encode(PeerMessage::Have(3), &mut bytes).unwrap();
+        codec.encode(PeerMessage::KeepAlive, &mut bytes).unwrap();
+        while codec.decode(&mut bytes).unwrap().is_some() {}
+
+        let status = traffic.status();
+        assert_eq!(status.payload_up, 100);
+        // 13 bytes of piece header, 9 of have and 4 of keep-alive.
+        assert_eq!(status.overhead_up, 13 + 9 + 4);
+        assert_eq!(status.payload_down, status.payload_up);
+        assert_eq!(status.overhead_down, status.overhead_up);
+    }
+
+    #[test]
+    fn count_merkle_piece_data_as_payload() {
+        let traffic = Arc::new(Traffic::default());
+        let mut codec = PeerMessageCodec::counting(vec![Arc::clone(&traffic)]);
+        codec.send_hashpieces(Some(5));
+        codec.receive_hashpieces(Some(2));
+        let piece = crate::peer::HashPiece {
+            piece: 0,
+            begin: 0,
+            hashes: vec![(1, [2; 20])],
+            data: vec![1; 100],
+        }
+        .to_bytes()
+        .unwrap();
+
+        let mut bytes = BytesMut::new();
+        codec
+            .encode(PeerMessage::Extended(5, piece.clone()), &mut bytes)
+            .unwrap();
+        // Other extension messages are overhead, as is everything but the data.
+        codec
+            .encode(PeerMessage::Extended(2, piece.clone()), &mut bytes)
+            .unwrap();
+        let status = traffic.status();
+        assert_eq!(status.payload_up, 100);
+        assert_eq!(status.overhead_up as usize, 2 * (4 + 2 + piece.len()) - 100);
+
+        bytes.clear();
+        PeerMessageCodec::default()
+            .encode(PeerMessage::Extended(2, piece.clone()), &mut bytes)
+            .unwrap();
+        codec.decode(&mut bytes).unwrap();
+        assert_eq!(traffic.status().payload_down, 100);
+    }
+
+    #[test]
+    fn skip_unknown_messages_unless_strict() {
+        // A suggest piece message from the fast extension, followed by a have.
+        let mut bytes = BytesMut::from(&[0, 0, 0, 5, 13, 0, 0, 0, 7][..]);
+        PeerMessageCodec::default()
+            .encode(PeerMessage::Have(3), &mut bytes)
+            .unwrap();
+
+        let mut strict = PeerMessageCodec::default().with_strict(true);
+        assert!(strict.decode(&mut bytes.clone()).is_err());
+
+        let mut codec = PeerMessageCodec::default();
+        assert_eq!(
+            codec.decode(&mut bytes).unwrap(),
+            Some(PeerMessage::Unknown(13, vec![0, 0, 0, 7]))
+        );
+        assert_eq!(
+            codec.decode(&mut bytes).unwrap(),
+            Some(PeerMessage::Have(3))
+        );
+    }
+
+    #[test]
+    fn encode_decode_extended_message() {
+        let msg = PeerMessage::Extended(0, b"d4:reqqi250ee".to_vec());
+        let mut codec = PeerMessageCodec::default();
+
+        let mut bytes = BytesMut::new();
+        codec.encode(msg.clone(), &mut bytes).unwrap();
+
+        assert_eq!(bytes.len(), 4 + 2 + 13);
+        assert_eq!(codec.decode(&mut bytes).unwrap().unwrap(), msg);
+    }
+
+    #[test]
+    fn encode_decode_hash_messages() {
+        let req = HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 2,
+            index: 512,
+            length: 512,
+            proof_layers: 3,
+        };
+        let mut codec = PeerMessageCodec::default();
+        for msg in [
+            PeerMessage::HashRequest(req),
+            PeerMessage::Hashes(req, vec![[1; 32], [2; 32]]),
+            PeerMessage::HashReject(req),
+        ] {
+            let mut bytes = BytesMut::new();
+            codec.encode(msg.clone(), &mut bytes).unwrap();
+            assert_eq!(bytes.len(), 4 + 1 + msg.payload_len());
+            assert_eq!(codec.decode(&mut bytes).unwrap().unwrap(), msg);
+        }
+
+        // Hashes which aren't a whole number of 32 byte hashes.
+        let mut bytes = BytesMut::new();
+        codec
+            .encode(PeerMessage::Hashes(req, vec![[1; 32]]), &mut bytes)
+            .unwrap();
+        bytes.truncate(bytes.len() - 1);
+        bytes[3] -= 1;
+        assert!(codec.decode(&mut bytes).is_err());
+    }
+
+    #[test]
+    fn reject_messages_with_bad_lengths() {
+        let mut codec = PeerMessageCodec::default();
+        // A have message without its piece index, followed by an unchoke.
+        let mut bytes = BytesMut::from(&[0, 0, 0, 1, 4, 0, 0, 0, 1, 1][..]);
+        assert!(codec.decode(&mut bytes).is_err());
+
+        let mut bytes = BytesMut::from(&[0, 0, 0, 2, 7, 0][..]);
+        assert!(codec.decode(&mut bytes).is_err());
+
+        let mut bytes = BytesMut::from(&[0xff, 0xff, 0xff, 0xff][..]);
+        assert!(codec.decode(&mut bytes).is_err());
+    }
 }
@@ -0,0 +1,235 @@
+//! Fetching a torrent's info dictionary from a peer with the metadata extension (BEP 9).
+
+use super::stream::make_message_stream;
+use super::{
+    ExtendedHandshake, Handshake, HandshakeCodec, PeerMessage, PeerMessageCodec,
+    EXTENDED_HANDSHAKE_ID,
+};
+use crate::info_hash::bencode_len;
+use crate::net::{self, SocketOptions};
+use anyhow::anyhow;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::net::{IpAddr, SocketAddr};
+use tokio::time::{self, Duration};
+use tokio_util::codec::Framed;
+use tracing::debug;
+
+pub const UT_METADATA: &str = "ut_metadata";
+/// Extended message id we ask peers to send metadata messages with.
+const OUR_UT_METADATA_ID: u8 = 1;
+/// Metadata is exchanged in pieces of this size, apart from the last.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// The largest info dictionary we'll accept from a peer.
+const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+/// How deeply lists and dictionaries in a message's header may be nested.
+const MAX_HEADER_DEPTH: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataMessage {
+    Request(usize),
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject(usize),
+}
+
+impl MetadataMessage {
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let (header, data) = match self {
+            Self::Request(piece) => (header(0, *piece, None), &[][..]),
+            Self::Data {
+                piece,
+                total_size,
+                data,
+            } => (header(1, *piece, Some(*total_size)), &data[..]),
+            Self::Reject(piece) => (header(2, *piece, None), &[][..]),
+        };
+
+        let mut bytes = serde_bencode::to_bytes(&header)?;
+        bytes.extend_from_slice(data);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        // Data messages have the piece's bytes straight after the bencoded header.
+        let header_len = bencode_len(bytes, MAX_HEADER_DEPTH)
+            .ok_or_else(|| anyhow!("Invalid metadata message"))?;
+        let header: Header = serde_bencode::from_bytes(&bytes[..header_len])?;
+
+        match header.msg_type {
+            0 => Ok(Self::Request(header.piece)),
+            1 => Ok(Self::Data {
+                piece: header.piece,
+                total_size: header
+                    .total_size
+                    .ok_or_else(|| anyhow!("Metadata piece without total_size"))?,
+                data: bytes[header_len..].to_vec(),
+            }),
+            2 => Ok(Self::Reject(header.piece)),
+            n => Err(anyhow!("Unknown metadata message type {}", n)),
+        }
+    }
+}
+
+fn header(msg_type: u8, piece: usize, total_size: Option<usize>) -> Header {
+    Header {
+        msg_type,
+        piece,
+        total_size,
+    }
+}
+
+/// Connect to the peer at `addr` with `socket`'s options and download the info
+/// dictionary of the torrent with `info_hash` from it, checking it against the hash.
+pub async fn fetch_metadata(
+    addr: SocketAddr,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    bind_address: Option<IpAddr>,
+    socket: &SocketOptions,
+) -> anyhow::Result<Vec<u8>> {
+    time::timeout(
+        METADATA_TIMEOUT,
+        exchange_metadata(addr, info_hash, peer_id, bind_address, socket),
+    )
+    .await
+    .map_err(|_| anyhow!("Timed out fetching metadata from {}", addr))?
+}
+
+async fn exchange_metadata(
+    addr: SocketAddr,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    bind_address: Option<IpAddr>,
+    socket: &SocketOptions,
+) -> anyhow::Result<Vec<u8>> {
+    let stream = net::connect(addr, bind_address, socket).await?;
+    let mut stream = Framed::new(stream, HandshakeCodec);
+    stream.send(Handshake::new(info_hash, peer_id)).await?;
+    let peer_shake = stream
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("Connection closed before handshake"))??;
+    if peer_shake.info_hash != *info_hash {
+        return Err(anyhow!("Not the same hash"));
+    }
+    if !peer_shake.supports_extensions() {
+        return Err(anyhow!("Peer doesn't support extensions"));
+    }
+
+    let mut stream = make_message_stream(stream, PeerMessageCodec::default());
+    let mut ours = ExtendedHandshake::ours();
+    ours.m.insert(UT_METADATA.into(), OUR_UT_METADATA_ID.into());
+    stream
+        .send(PeerMessage::Extended(
+            EXTENDED_HANDSHAKE_ID,
+            ours.to_bytes()?,
+        ))
+        .await?;
+
+    let mut pieces: Vec<Option<Vec<u8>>> = Vec::new();
+    let mut size = 0;
+    while let Some(msg) = stream.next().await {
+        match msg? {
+            PeerMessage::Extended(EXTENDED_HANDSHAKE_ID, payload) => {
+                let theirs = ExtendedHandshake::from_bytes(&payload)?;
+                let id = theirs
+                    .m
+                    .get(UT_METADATA)
+                    .copied()
+                    .filter(|&id| id > 0)
+                    .ok_or_else(|| anyhow!("Peer doesn't support {}", UT_METADATA))?;
+                size = theirs
+                    .metadata_size
+                    .and_then(|size| usize::try_from(size).ok())
+                    .filter(|&size| size > 0 && size <= MAX_METADATA_SIZE)
+                    .ok_or_else(|| anyhow!("Peer sent an invalid metadata_size"))?;
+
+                pieces = vec![None; size.div_ceil(METADATA_PIECE_SIZE)];
+                debug!("Requesting {} metadata pieces from {}", pieces.len(), addr);
+                for piece in 0..pieces.len() {
+                    let request = MetadataMessage::Request(piece).to_bytes()?;
+                    stream
+                        .send(PeerMessage::Extended(id as u8, request))
+                        .await?;
+                }
+            }
+            PeerMessage::Extended(OUR_UT_METADATA_ID, payload) => {
+                match MetadataMessage::from_bytes(&payload)? {
+                    MetadataMessage::Data { piece, data, .. } if piece < pieces.len() => {
+                        pieces[piece] = Some(data);
+                    }
+                    MetadataMessage::Reject(piece) => {
+                        return Err(anyhow!(
+                            "Peer rejected request for metadata piece {}",
+                            piece
+                        ))
+                    }
+                    _ => {}
+                }
+
+                if !pieces.is_empty() && pieces.iter().all(Option::is_some) {
+                    let info: Vec<u8> = pieces.into_iter().flatten().flatten().collect();
+                    if info.len() != size || Sha1::digest(&info)[..] != info_hash[..] {
+                        return Err(anyhow!(
+                            "Metadata from {} doesn't match the info hash",
+                            addr
+                        ));
+                    }
+                    return Ok(info);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Err(anyhow!("Connection closed before metadata was received"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode_metadata_messages() {
+        let data = MetadataMessage::Data {
+            piece: 1,
+            total_size: 16390,
+            data: b"d4:name1:xe".to_vec(),
+        };
+        let bytes = data.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            b"d8:msg_typei1e5:piecei1e10:total_sizei16390eed4:name1:xe"
+        );
+        assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), data);
+
+        let request = MetadataMessage::Request(0);
+        let bytes = request.to_bytes().unwrap();
+        assert_eq!(bytes, b"d8:msg_typei0e5:piecei0ee");
+        assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), request);
+
+        assert!(MetadataMessage::from_bytes(b"d8:msg_typei1e5:piecei0ee").is_err());
+        assert!(MetadataMessage::from_bytes(b"d8:msg_type").is_err());
+    }
+
+    #[test]
+    fn reject_hostile_headers() {
+        let nested = [vec![b'l'; 100_000], vec![b'e'; 100_000]].concat();
+        assert!(MetadataMessage::from_bytes(&nested).is_err());
+        assert!(MetadataMessage::from_bytes(b"d18446744073709551615:x").is_err());
+    }
+}
@@ -1,76 +1,90 @@
-use crate::torrent_file::Torrent;
-use serde::Deserialize;
-use serde_bytes::ByteBuf;
-use std::net::Ipv4Addr;
+use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
+mod extension;
+#[cfg(any(test, feature = "testing"))]
+pub mod fuzz;
 mod handshake;
+mod hashpiece;
+mod have;
+mod holepunch;
 mod message;
+mod metadata;
+mod protocol;
 mod session;
-mod stream;
+pub(crate) mod stream;
+mod wire_log;
 
+pub use extension::*;
 pub use handshake::*;
+pub use hashpiece::*;
+pub use have::HavePolicy;
+pub(crate) use have::Haves;
+pub use holepunch::*;
 pub use message::*;
+pub use metadata::*;
+pub use protocol::*;
 pub use session::*;
+pub use wire_log::*;
 
-#[derive(Debug, Deserialize)]
-struct TrackerResponse {
-    interval: u16,
-    peers: ByteBuf,
+/// How we found out about a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    /// A relay put us in touch with the peer (BEP 55).
+    Holepunch,
+    /// The peer connected to us.
+    Incoming,
+}
+
+impl std::fmt::Display for PeerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Tracker => "tracker",
+            Self::Dht => "dht",
+            Self::Pex => "pex",
+            Self::Lsd => "lsd",
+            Self::Holepunch => "holepunch",
+            Self::Incoming => "incoming",
+        };
+
+        write!(f, "{}", s)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct PeerData {
     ip: Ipv4Addr,
     port: u16,
+    source: PeerSource,
 }
 
 impl PeerData {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
-        let port = u16::from_be_bytes([bytes[4], bytes[5]]);
-
-        Self { ip, port }
-    }
-}
-
-#[derive(Debug)]
-pub struct PeersInfo {
-    pub interval: u16,
-    pub peers: Vec<PeerData>,
-}
-
-impl From<TrackerResponse> for PeersInfo {
-    fn from(res: TrackerResponse) -> Self {
-        let peers = res
-            .peers
-            .chunks_exact(6)
-            .map(PeerData::from_bytes)
-            .collect();
-
+    pub fn new(addr: SocketAddrV4, source: PeerSource) -> Self {
         Self {
-            interval: res.interval,
-            peers,
+            ip: *addr.ip(),
+            port: addr.port(),
+            source,
         }
     }
-}
-
-pub async fn request_peer_info(
-    torrent: &Torrent,
-    peer_id: &[u8],
-    port: u16,
-) -> anyhow::Result<PeersInfo> {
-    let client: reqwest::Client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(15))
-        .build()?;
-    let url = torrent.build_tracker_url(peer_id, port)?;
 
-    let req = client.get(url).build()?;
+    /// Parse a peer from its compact 6-byte form.
+    pub fn from_bytes(bytes: &[u8], source: PeerSource) -> Self {
+        let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+        let port = u16::from_be_bytes([bytes[4], bytes[5]]);
 
-    let tracker_response = client.execute(req).await?;
+        Self { ip, port, source }
+    }
 
-    let bytes = tracker_response.bytes().await?;
-    let tracker_response: TrackerResponse = serde_bencode::from_bytes(&bytes)?;
+    pub fn source(&self) -> PeerSource {
+        self.source
+    }
 
-    let details = tracker_response.into();
-    Ok(details)
+    pub fn addr(&self) -> SocketAddr {
+        (self.ip, self.port).into()
+    }
 }
@@ -0,0 +1,263 @@
+//! Where a session is in the peer protocol: whether the handshake is done, and which
+//! of us is choking and interested in the other. Sessions change it only through the
+//! transitions here, which is what keeps them from, say, requesting blocks while the
+//! peer is choking them.
+
+use super::message::PeerMessage;
+use crate::stats::PeerStats;
+use anyhow::anyhow;
+use std::sync::atomic::Ordering;
+
+/// What can flow over a session, from [`ProtocolState::phase`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// The handshake isn't done, so nothing else can be sent.
+    Handshaking,
+    /// Blocks can't flow either way.
+    Idle,
+    /// We're interested and the peer is unchoking us, so we can request blocks.
+    Downloading,
+    /// The peer is interested and we're unchoking it, so it can request blocks.
+    Seeding,
+    /// Both downloading and seeding.
+    Trading,
+}
+
+/// One side's choking and interest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Side {
+    pub choking: bool,
+    pub interested: bool,
+}
+
+impl Default for Side {
+    /// Both sides start out choking and not interested.
+    fn default() -> Self {
+        Self {
+            choking: true,
+            interested: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtocolState {
+    handshaken: bool,
+    ours: Side,
+    theirs: Side,
+}
+
+impl ProtocolState {
+    pub fn phase(&self) -> Phase {
+        if !self.handshaken {
+            return Phase::Handshaking;
+        }
+        let downloading = self.ours.interested && !self.theirs.choking;
+        let seeding = self.theirs.interested && !self.ours.choking;
+        match (downloading, seeding) {
+            (false, false) => Phase::Idle,
+            (true, false) => Phase::Downloading,
+            (false, true) => Phase::Seeding,
+            (true, true) => Phase::Trading,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn ours(&self) -> Side {
+        self.ours
+    }
+
+    pub fn theirs(&self) -> Side {
+        self.theirs
+    }
+
+    /// Whether we can request blocks from the peer.
+    pub fn can_request(&self) -> bool {
+        matches!(self.phase(), Phase::Downloading | Phase::Trading)
+    }
+
+    /// Whether we take requests from the peer, which we do while we're unchoking it,
+    /// even if it hasn't said it's interested.
+    pub fn accepts_requests(&self) -> bool {
+        self.handshaken && !self.ours.choking
+    }
+
+    /// Finish the handshake, after which other messages can flow.
+    pub fn handshake(&mut self) -> anyhow::Result<()> {
+        if self.handshaken {
+            return Err(anyhow!("Already handshaken"));
+        }
+        self.handshaken = true;
+        Ok(())
+    }
+
+    /// Take in a message from the peer.
+    pub fn receive(&mut self, msg: &PeerMessage) -> anyhow::Result<()> {
+        if !self.handshaken {
+            return Err(anyhow!("Peer sent {} before the handshake", msg));
+        }
+        match msg {
+            PeerMessage::Choke => self.theirs.choking = true,
+            PeerMessage::Unchoke => self.theirs.choking = false,
+            PeerMessage::Interested => self.theirs.interested = true,
+            PeerMessage::NotInterested => self.theirs.interested = false,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Become interested in the peer or not, returning the message telling it so if
+    /// that's a change.
+    pub fn set_interested(&mut self, interested: bool) -> anyhow::Result<Option<PeerMessage>> {
+        self.check_handshaken()?;
+        if self.ours.interested == interested {
+            return Ok(None);
+        }
+        self.ours.interested = interested;
+        Ok(Some(match interested {
+            true => PeerMessage::Interested,
+            false => PeerMessage::NotInterested,
+        }))
+    }
+
+    /// Choke or unchoke the peer, returning the message telling it so if that's a
+    /// change.
+    pub fn set_choking(&mut self, choking: bool) -> anyhow::Result<Option<PeerMessage>> {
+        self.check_handshaken()?;
+        if self.ours.choking == choking {
+            return Ok(None);
+        }
+        self.ours.choking = choking;
+        Ok(Some(match choking {
+            true => PeerMessage::Choke,
+            false => PeerMessage::Unchoke,
+        }))
+    }
+
+    /// Show the state in the peer's stats, for the choker and status to read.
+    pub fn publish(&self, stats: &PeerStats) {
+        stats.am_choking.store(self.ours.choking, Ordering::Relaxed);
+        stats
+            .am_interested
+            .store(self.ours.interested, Ordering::Relaxed);
+        stats
+            .peer_choking
+            .store(self.theirs.choking, Ordering::Relaxed);
+        stats
+            .peer_interested
+            .store(self.theirs.interested, Ordering::Relaxed);
+    }
+
+    fn check_handshaken(&self) -> anyhow::Result<()> {
+        match self.handshaken {
+            true => Ok(()),
+            false => Err(anyhow!("Can't send messages before the handshake")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Every combination of both sides' choking and interest, after the handshake.
+    fn quadrants() -> Vec<ProtocolState> {
+        let sides = [false, true].into_iter().flat_map(|choking| {
+            [false, true].map(|interested| Side {
+                choking,
+                interested,
+            })
+        });
+        let sides: Vec<_> = sides.collect();
+        let mut states = Vec::new();
+        for &ours in &sides {
+            for &theirs in &sides {
+                states.push(ProtocolState {
+                    handshaken: true,
+                    ours,
+                    theirs,
+                });
+            }
+        }
+        states
+    }
+
+    #[test]
+    fn nothing_flows_before_the_handshake() {
+        let mut state = ProtocolState::default();
+        assert_eq!(state.phase(), Phase::Handshaking);
+        assert!(state.receive(&PeerMessage::Unchoke).is_err());
+        assert!(state.set_interested(true).is_err());
+        assert!(state.set_choking(false).is_err());
+        assert!(!state.can_request() && !state.accepts_requests());
+
+        state.handshake().unwrap();
+        assert!(state.handshake().is_err());
+        assert_eq!(state.phase(), Phase::Idle);
+    }
+
+    #[test]
+    fn only_request_when_interested_and_unchoked() {
+        for state in quadrants() {
+            let (ours, theirs) = (state.ours(), state.theirs());
+            assert_eq!(
+                state.can_request(),
+                ours.interested && !theirs.choking,
+                "{:?}",
+                state
+            );
+            assert_eq!(state.accepts_requests(), !ours.choking, "{:?}", state);
+            let expected = match (state.can_request(), theirs.interested && !ours.choking) {
+                (false, false) => Phase::Idle,
+                (true, false) => Phase::Downloading,
+                (false, true) => Phase::Seeding,
+                (true, true) => Phase::Trading,
+            };
+            assert_eq!(state.phase(), expected);
+        }
+    }
+
+    #[test]
+    fn transitions_only_change_their_own_side() {
+        for state in quadrants() {
+            for msg in [
+                PeerMessage::Choke,
+                PeerMessage::Unchoke,
+                PeerMessage::Interested,
+                PeerMessage::NotInterested,
+                PeerMessage::Have(0),
+            ] {
+                let mut next = state;
+                next.receive(&msg).unwrap();
+                assert_eq!(next.ours(), state.ours());
+                let theirs = match msg {
+                    PeerMessage::Choke | PeerMessage::Unchoke => Side {
+                        choking: msg == PeerMessage::Choke,
+                        ..state.theirs()
+                    },
+                    PeerMessage::Interested | PeerMessage::NotInterested => Side {
+                        interested: msg == PeerMessage::Interested,
+                        ..state.theirs()
+                    },
+                    _ => state.theirs(),
+                };
+                assert_eq!(next.theirs(), theirs);
+            }
+
+            for interested in [false, true] {
+                let mut next = state;
+                let msg = next.set_interested(interested).unwrap();
+                assert_eq!(next.theirs(), state.theirs());
+                assert_eq!(next.ours().interested, interested);
+                assert_eq!(msg.is_some(), state.ours().interested != interested);
+            }
+            for choking in [false, true] {
+                let mut next = state;
+                let msg = next.set_choking(choking).unwrap();
+                assert_eq!(next.theirs(), state.theirs());
+                assert_eq!(next.ours().choking, choking);
+                assert_eq!(msg.is_some(), state.ours().choking != choking);
+            }
+        }
+    }
+}
@@ -1,121 +1,331 @@
 use super::message::PeerMessage;
 use super::PeerData;
 use super::PeerMessageCodec;
+use super::ProtocolState;
+use super::WireLog;
 use super::{
-    handshake::{Handshake, HandshakeCodec},
+    handshake::{Extensions, Handshake, HandshakeCodec},
     stream::make_message_stream,
 };
-use crate::queues::{WorkQueue, WorkResult};
+use super::{ExtendedHandshake, EXTENDED_HANDSHAKE_ID, OUR_REQQ};
+use super::{HashPiece, OUR_TR_HASHPIECE_ID, TR_HASHPIECE};
+use super::{HavePolicy, Haves};
+use super::{Holepunch, HolepunchMessage, OUR_UT_HOLEPUNCH_ID, UT_HOLEPUNCH};
+use crate::choker::Choker;
+use crate::disk::DiskQueue;
+use crate::external_ip::{ExternalIp, Voter};
+use crate::fair_share::Share;
+use crate::net::{self, SocketOptions};
+use crate::queues::{ByteRanges, Received, WorkQueue};
+use crate::rate_limit::{AltSpeed, RateLimits};
+use crate::stats::{client_name, PeerStats, TorrentStats};
+use crate::storage::Storage;
 use crate::Torrent;
 use crate::{
     bitfield::{Bitfield, BitfieldMut},
-    queues::PieceOfWork,
+    picker::{BlockRange, BLOCK_SIZE},
 };
 use anyhow::anyhow;
-use futures::{SinkExt, StreamExt};
+use futures::{FutureExt, SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::watch;
 use tokio::time::{self, Duration};
 use tokio_util::codec::Framed;
-use tracing::{debug, error, warn};
+use tracing::{debug, error};
 
-const MAX_BLOCK_SIZE: usize = 16_384;
+/// How many requests we pipeline to peers which don't tell us their queue depth.
 const MAX_BACKLOG: usize = 5;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an idle session reads for before reading again, so the receive timeout
+/// doesn't disconnect quiet peers we have nothing to ask for.
+const IDLE_POLL: Duration = Duration::from_secs(5);
+/// The largest block we'll send in response to a request.
+const MAX_REQUEST_LENGTH: usize = 128 * 1024;
+/// How many bytes we didn't ask for a peer can send before we disconnect it. Some
+/// arrive legitimately, such as blocks which were in flight when the peer choked us.
+const MAX_UNSOLICITED: usize = 1024 * 1024;
+/// How long a session lasts while neither we nor the peer want anything from the
+/// other, before its slot is freed for a peer which might be useful.
+const UNINTERESTING_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many requests we can refuse a peer within [`REFUSED_WINDOW`], whether the
+/// queue was full, we were choking it or we don't have the piece, before we take it
+/// to be flooding us and disconnect it.
+const MAX_REFUSED_REQUESTS: usize = 2 * OUR_REQQ as usize;
+/// How far back refused requests count towards [`MAX_REFUSED_REQUESTS`].
+const REFUSED_WINDOW: Duration = Duration::from_secs(30);
+/// In endgame, how many of the torrent's lowest-latency peers are asked for blocks
+/// other peers haven't sent yet.
+const ENDGAME_PEERS: usize = 3;
 
-struct PieceState {
-    index: usize,
-    downloaded: usize,
-    requested: usize,
-    backlog: usize,
-    buf: Vec<u8>,
+struct PeerSessionState {
+    /// Where we are in the protocol, changed only through [`PeerSession::transition`].
+    protocol: ProtocolState,
+    /// Blocks we've requested from the peer and haven't received all of yet.
+    outstanding: Vec<Request>,
+    /// Blocks we cancelled the requests for, which may arrive anyway.
+    cancelled: VecDeque<BlockRange>,
+    /// The work queue's revocations we've cancelled requests for.
+    revocations: u64,
+    /// Blocks the peer has requested which we haven't sent yet.
+    uploads: UploadQueue,
+    /// How many requests the peer will queue, from its extension handshake. We may
+    /// keep fewer outstanding, see [`PeerSession::backlog`].
+    max_backlog: usize,
+    /// The piece we last asked the peer for a new block of, so it can be asked for the
+    /// pieces after it.
+    last_piece: Option<usize>,
+    /// Extensions both we and the peer advertised in the handshake.
+    extensions: Extensions,
+    /// The peer is a seed or partial seed, and won't download from us.
+    upload_only: bool,
+    /// Bytes the peer has sent which we didn't request.
+    unsolicited: usize,
+    /// The id the peer wants merkle torrent pieces sent with, if it supports them.
+    hashpiece_id: Option<u8>,
+    /// The id the peer wants holepunch messages sent with, if it supports them.
+    holepunch_id: Option<u8>,
+    /// Holepunch messages other sessions want sent to the peer.
+    relayed: Option<UnboundedReceiver<HolepunchMessage>>,
+    /// The pieces we've told the peer about, once we've sent our bitfield.
+    haves: Option<Haves>,
+    bitfield: Vec<u8>,
+    /// Whether the peer has pieces we want, as of the work queue's count of changes to
+    /// them, or none if its pieces have changed since.
+    wants_any: Option<(u64, bool)>,
 }
 
-impl std::fmt::Debug for PieceState {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("PieceState")
-            .field("index", &self.index)
-            .field("downloaded", &self.downloaded)
-            .field("requested", &self.requested)
-            .field("backlog", &self.backlog)
-            .finish()
-    }
+/// A block we've requested, and the parts of it the peer has sent so far. Peers may
+/// send a block in several smaller pieces.
+struct Request {
+    block: BlockRange,
+    received: ByteRanges,
+    sent: time::Instant,
 }
 
-impl PieceState {
-    pub fn new(index: usize, len: usize) -> Self {
+impl Request {
+    fn new(block: BlockRange) -> Self {
         Self {
-            index,
-            downloaded: 0,
-            requested: 0,
-            backlog: 0,
-            buf: vec![0; len],
+            block,
+            received: ByteRanges::default(),
+            sent: time::Instant::now(),
         }
     }
-}
 
-struct PeerSessionState {
-    index: usize,
-    choked: bool,
-    interested: bool,
-    downloaded: usize,
-    requested: usize,
-    backlog: usize,
-    buf: Vec<u8>,
-    bitfield: Vec<u8>,
+    fn end(&self) -> usize {
+        self.block.begin + self.block.length
+    }
 }
 
 impl std::fmt::Debug for PeerSessionState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let Self {
-            index,
-            choked,
-            interested,
-            downloaded,
-            requested,
-            backlog,
+            protocol,
+            outstanding,
+            max_backlog,
             ..
         } = self;
-        write!(f, "[PeerSessionState: index {index}, choked {choked}, interested {interested}, downloaded {downloaded}, requested: {requested}, backlog: {backlog}]",)
+        let phase = protocol.phase();
+        let backlog = outstanding.len();
+        write!(
+            f,
+            "[PeerSessionState: {phase:?}, backlog: {backlog}/{max_backlog}]",
+        )
     }
 }
 
 impl Default for PeerSessionState {
     fn default() -> Self {
         Self {
-            index: 0,
-            choked: true,
-            interested: false,
-            downloaded: 0,
-            requested: 0,
-            backlog: 0,
-            buf: Vec::default(),
+            protocol: ProtocolState::default(),
+            outstanding: Vec::new(),
+            cancelled: VecDeque::new(),
+            revocations: 0,
+            uploads: UploadQueue::default(),
+            max_backlog: MAX_BACKLOG,
+            last_piece: None,
+            extensions: Extensions::default(),
+            upload_only: false,
+            unsolicited: 0,
+            hashpiece_id: None,
+            holepunch_id: None,
+            relayed: None,
+            haves: None,
             bitfield: Default::default(),
+            wants_any: None,
+        }
+    }
+}
+
+fn block_range(idx: u32, begin: u32, length: u32) -> BlockRange {
+    BlockRange {
+        piece: idx as usize,
+        begin: begin as usize,
+        length: length as usize,
+    }
+}
+
+/// Blocks a peer has requested from us, in the order it asked for them. They're sent
+/// one at a time, so a `Cancel` arriving meanwhile can take a request back before we
+/// read it from disk.
+#[derive(Debug, Default)]
+struct UploadQueue {
+    blocks: VecDeque<BlockRange>,
+    /// When requests were refused, over the last [`REFUSED_WINDOW`].
+    refused: VecDeque<time::Instant>,
+}
+
+/// What became of a request pushed onto an [`UploadQueue`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Queued {
+    Added,
+    /// The block was already queued.
+    Repeated,
+    /// The peer already has as many requests queued as we told it we'd take.
+    Full,
+}
+
+impl UploadQueue {
+    fn push(&mut self, block: BlockRange) -> Queued {
+        if self.blocks.contains(&block) {
+            return Queued::Repeated;
         }
+        if self.blocks.len() >= OUR_REQQ as usize {
+            return Queued::Full;
+        }
+        self.blocks.push_back(block);
+        Queued::Added
+    }
+
+    /// Drop a request the peer cancelled, returning whether it was still queued.
+    fn cancel(&mut self, block: &BlockRange) -> bool {
+        let before = self.blocks.len();
+        self.blocks.retain(|b| b != block);
+        self.blocks.len() != before
+    }
+
+    fn pop(&mut self) -> Option<BlockRange> {
+        self.blocks.pop_front()
+    }
+
+    /// Drop every request, as choking a peer does, returning them.
+    fn clear(&mut self) -> Vec<BlockRange> {
+        self.blocks.drain(..).collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Note a request was refused at `now`, forgetting those outside the window.
+    fn refuse(&mut self, now: time::Instant) {
+        while let Some(&at) = self.refused.front() {
+            if now.saturating_duration_since(at) < REFUSED_WINDOW {
+                break;
+            }
+            self.refused.pop_front();
+        }
+        self.refused.push_back(now);
+    }
+
+    /// Whether we've refused the peer so many requests lately that it seems to be
+    /// flooding us.
+    fn is_flooded(&self) -> bool {
+        self.refused.len() > MAX_REFUSED_REQUESTS
     }
 }
 
-pub struct PeerSession<Codec = HandshakeCodec> {
+/// The peer answered the handshake with our own peer id, so we've connected to ourselves.
+#[derive(Debug)]
+pub struct SelfConnection;
+
+impl std::fmt::Display for SelfConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connected to ourselves")
+    }
+}
+
+impl std::error::Error for SelfConnection {}
+
+/// The peer didn't answer when we dialed it, perhaps because it's behind a NAT.
+#[derive(Debug)]
+pub struct ConnectTimeout;
+
+impl std::fmt::Display for ConnectTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timed out connecting to peer")
+    }
+}
+
+impl std::error::Error for ConnectTimeout {}
+
+/// The torrent a session is exchanging pieces of, and where it sends them.
+#[derive(Debug, Clone)]
+pub struct SessionContext {
+    pub torrent: Arc<Torrent>,
+    pub stats: Arc<TorrentStats>,
+    pub work_queue: WorkQueue,
+    pub storage: Arc<Storage>,
+    pub choker: Arc<Choker>,
+    /// Where to hand pieces we assemble, to be checked and saved.
+    pub disk: DiskQueue,
+    pub peer_id: [u8; 20],
+    /// Only upload to the peer, and keep the session open once we have every piece.
+    pub seed: bool,
+    pub limits: RateLimits,
+    /// The client's alternate speed caps, shared by every torrent.
+    pub alt_speed: AltSpeed,
+    /// The torrent's share of the client's peer connections.
+    pub connections: Share,
+    /// Local address to connect to peers from.
+    pub bind_address: Option<IpAddr>,
+    pub socket: SocketOptions,
+    /// Reserved bits we send in the handshake, advertising the extensions we support.
+    pub reserved: [u8; 8],
+    /// The torrent's sessions, for relaying holepunch messages between them.
+    pub holepunch: Holepunch,
+    /// Where to report the address peers see us at.
+    pub external_ip: ExternalIp,
+    /// When to tell the peer about pieces we complete.
+    pub have_policy: HavePolicy,
+    /// Disconnect peers which send messages we don't know, rather than ignoring them.
+    pub strict_protocol: bool,
+    /// Where every message sessions send and receive is logged, if it is.
+    pub wire_log: Option<Arc<WireLog>>,
+    /// Becomes true when the torrent stops and sessions should end.
+    pub stop: watch::Receiver<bool>,
+}
+
+/// A connection a session can talk to a peer over: a TCP stream, or an in-memory one
+/// in tests.
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> PeerStream for S {}
+
+pub struct PeerSession<Codec = HandshakeCodec, S = TcpStream> {
     data: PeerData,
     state: PeerSessionState,
-    torrent: Arc<Torrent>,
-    work_queue: WorkQueue,
-    save_tx: Sender<WorkResult>,
-    peer_id: [u8; 20],
-    stream: Framed<TcpStream, Codec>,
+    ctx: SessionContext,
+    peer_stats: Arc<PeerStats>,
+    stream: Framed<S, Codec>,
 }
 
-impl<T> std::fmt::Debug for PeerSession<T> {
+impl<T, S> std::fmt::Debug for PeerSession<T, S> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}:{} - {:?}",
-            &self.data.ip, &self.data.port, &self.state
+            "{}:{} ({}) - {:?}",
+            &self.data.ip, &self.data.port, &self.data.source, &self.state
         )
     }
 }
 
-impl std::fmt::Display for PeerSession {
+impl<T, S> std::fmt::Display for PeerSession<T, S> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}:{}", &self.data.ip, &self.data.port)
     }
@@ -124,76 +334,185 @@ impl std::fmt::Display for PeerSession {
 impl PeerSession<HandshakeCodec> {
     pub async fn new(
         data: PeerData,
-        torrent: Arc<Torrent>,
-        work_queue: WorkQueue,
-        save_tx: Sender<WorkResult>,
-        peer_id: &[u8; 20],
+        peer_stats: Arc<PeerStats>,
+        ctx: SessionContext,
     ) -> anyhow::Result<Self> {
-        let stream = TcpStream::connect((data.ip, data.port)).await?;
-        let stream = Framed::new(stream, HandshakeCodec);
+        let connect = net::connect(data.addr(), ctx.bind_address, &ctx.socket);
+        let stream = time::timeout(CONNECT_TIMEOUT, connect)
+            .await
+            .map_err(|_| ConnectTimeout)??;
 
-        Ok(Self {
+        Ok(Self::with_stream(data, stream, peer_stats, ctx))
+    }
+}
+
+impl<S: PeerStream> PeerSession<HandshakeCodec, S> {
+    /// A session with the peer on the other end of `stream`, which hasn't handshaken yet.
+    pub fn with_stream(
+        data: PeerData,
+        stream: S,
+        peer_stats: Arc<PeerStats>,
+        ctx: SessionContext,
+    ) -> Self {
+        Self::from_stream(data, Framed::new(stream, HandshakeCodec), peer_stats, ctx)
+    }
+
+    /// Finish setting up a connection the peer made to us, once we've read its handshake.
+    pub async fn accept(
+        data: PeerData,
+        stream: Framed<S, HandshakeCodec>,
+        peer_shake: Handshake,
+        peer_stats: Arc<PeerStats>,
+        ctx: SessionContext,
+    ) -> anyhow::Result<PeerSession<PeerMessageCodec, S>> {
+        let mut session = Self::from_stream(data, stream, peer_stats, ctx);
+        let handshake = Handshake::with_reserved(
+            &session.ctx.torrent.info_hash,
+            &session.ctx.peer_id,
+            session.ctx.reserved,
+        );
+        session.stream.send(handshake).await?;
+
+        session.established(peer_shake).await
+    }
+
+    fn from_stream(
+        data: PeerData,
+        stream: Framed<S, HandshakeCodec>,
+        peer_stats: Arc<PeerStats>,
+        ctx: SessionContext,
+    ) -> Self {
+        let piece_count = ctx.torrent.file.info.piece_count();
+        let state = PeerSessionState {
+            bitfield: vec![0; piece_count.div_ceil(8)],
+            ..Default::default()
+        };
+
+        Self {
             data,
-            torrent,
-            work_queue,
-            save_tx,
-            peer_id: peer_id.to_owned(),
+            state,
+            ctx,
+            peer_stats,
             stream,
-            state: Default::default(),
-        })
+        }
     }
 
     #[tracing::instrument]
-    pub async fn connect(mut self) -> anyhow::Result<PeerSession<PeerMessageCodec>> {
+    pub async fn connect(mut self) -> anyhow::Result<PeerSession<PeerMessageCodec, S>> {
         debug!("Connecting to peer {}", self.data.ip);
 
-        let handshake = Handshake::new(&self.torrent.info_hash, &self.peer_id);
+        let handshake = Handshake::with_reserved(
+            &self.ctx.torrent.info_hash,
+            &self.ctx.peer_id,
+            self.ctx.reserved,
+        );
 
         self.stream.send(handshake).await?;
 
-        let mut session = loop {
-            let n = self.stream.next().await;
-            match n {
-                None => continue,
-                Some(peer_shake) => {
-                    if peer_shake?.info_hash == self.torrent.info_hash {
-                        let Self {
-                            data,
-                            state,
-                            torrent,
-                            work_queue,
-                            save_tx,
-                            peer_id,
-                            stream,
-                        } = self;
-                        break Ok(PeerSession {
-                            data,
-                            state,
-                            torrent,
-                            work_queue,
-                            save_tx,
-                            peer_id,
-                            stream: make_message_stream(stream),
-                        });
-                    } else {
-                        break Err(anyhow!("Not the same hash"));
-                    }
-                }
-            }
-        }?;
+        let peer_shake = self
+            .stream
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("Peer closed the connection during the handshake"))??;
+
+        self.established(peer_shake).await
+    }
 
-        if let PeerMessage::Bitfield(bitfield) = session.recv_message().await? {
-            debug!("connected to peer; bitfield length 0x{:0x}", bitfield.len());
-            session.state.bitfield = bitfield;
+    /// Check the peer's handshake, then switch to exchanging messages and tell the
+    /// peer which pieces we have.
+    async fn established(
+        self,
+        peer_shake: Handshake,
+    ) -> anyhow::Result<PeerSession<PeerMessageCodec, S>> {
+        if peer_shake.peer_id == self.ctx.peer_id {
+            return Err(SelfConnection.into());
+        }
+        if peer_shake.info_hash != self.ctx.torrent.info_hash {
+            return Err(anyhow!("Not the same hash"));
+        }
+        if let Some(client) = client_name(&peer_shake.peer_id) {
+            self.peer_stats.set_client(client);
+        }
+        let theirs = peer_shake.extensions();
+        self.peer_stats.set_extensions(theirs);
 
-            Ok(session)
-        } else {
-            Err(anyhow!("Peer didn't send bitfield"))
+        let Self {
+            data,
+            mut state,
+            ctx,
+            peer_stats,
+            stream,
+        } = self;
+        state.extensions = Extensions::from_reserved(&ctx.reserved).negotiate(&theirs);
+        state.protocol.handshake()?;
+        state.protocol.publish(&peer_stats);
+        let traffic = vec![
+            Arc::clone(&peer_stats.traffic),
+            Arc::clone(&ctx.stats.traffic),
+        ];
+        // Both handshakes were coded before we knew which torrent the connection was for.
+        for traffic in &traffic {
+            traffic.sent(Handshake::LEN, 0);
+            traffic.received(Handshake::LEN, 0);
         }
+        let log = ctx
+            .wire_log
+            .as_ref()
+            .map(|log| log.session(&ctx.torrent.info_hash, data.addr()));
+        let codec = PeerMessageCodec::counting(traffic)
+            .with_strict(ctx.strict_protocol)
+            .with_log(log);
+        let mut session = PeerSession {
+            data,
+            state,
+            ctx,
+            peer_stats,
+            stream: make_message_stream(stream, codec),
+        };
+
+        if session.state.extensions.extension_protocol {
+            let mut handshake = ExtendedHandshake::ours();
+            handshake.set_yourip(session.data.addr().ip());
+            if session.ctx.seed || session.ctx.work_queue.is_finished() {
+                handshake.upload_only = Some(1);
+            }
+            if session.ctx.torrent.file.info.root_hash.is_some() {
+                handshake
+                    .m
+                    .insert(TR_HASHPIECE.into(), OUR_TR_HASHPIECE_ID.into());
+                session
+                    .stream
+                    .codec_mut()
+                    .receive_hashpieces(Some(OUR_TR_HASHPIECE_ID));
+            }
+            handshake
+                .m
+                .insert(UT_HOLEPUNCH.into(), OUR_UT_HOLEPUNCH_ID.into());
+            let handshake = handshake.to_bytes()?;
+            session
+                .send_message(PeerMessage::Extended(EXTENDED_HANDSHAKE_ID, handshake))
+                .await?;
+        }
+
+        let completions = session.ctx.work_queue.completions();
+        let bitfield = session.ctx.work_queue.bitfield();
+        session.state.haves = Some(Haves::new(
+            session.ctx.have_policy,
+            bitfield.clone(),
+            completions,
+        ));
+        if bitfield.count_pieces() > 0 {
+            session
+                .send_message(PeerMessage::Bitfield(bitfield))
+                .await?;
+        }
+        session.request_hashes().await?;
+
+        Ok(session)
     }
 }
 
-impl PeerSession<PeerMessageCodec> {
+impl<S: PeerStream> PeerSession<PeerMessageCodec, S> {
     #[tracing::instrument]
     async fn send_message(&mut self, msg: PeerMessage) -> anyhow::Result<()> {
         debug!("Sending peer message: {}", &msg);
@@ -206,102 +525,678 @@ impl PeerSession<PeerMessageCodec> {
     #[tracing::instrument]
     async fn recv_message(&mut self) -> anyhow::Result<PeerMessage> {
         loop {
-            let timeout = time::sleep(Duration::from_secs(30));
-            tokio::pin!(timeout);
-            tokio::select! {
-                _ = &mut timeout => {
+            let incoming = time::timeout(Duration::from_secs(30), self.next_incoming())
+                .await
+                .map_err(|_| {
                     error!("Timed out");
-                    return Err(anyhow::anyhow!("Timed out while receiving message"));
+                    anyhow!("Timed out while receiving message")
+                })??;
+            match incoming {
+                Incoming::Message(msg) => return Ok(msg),
+                incoming => self.on_incoming(incoming).await?,
+            }
+        }
+    }
+
+    /// Wait for the next thing to act on. Nothing is read or sent but whole frames, so
+    /// this can be raced against other wake-ups without losing part of a message.
+    async fn next_incoming(&mut self) -> anyhow::Result<Incoming> {
+        loop {
+            tokio::select! {
+                Some(msg) = recv_relayed(&mut self.state.relayed) => {
+                    return Ok(Incoming::Relayed(msg));
                 }
-                n = self.stream.next() => match n {
-                    None => continue,
-                    Some(res) => {
-                        let msg = res?;
-                        if let PeerMessage::KeepAlive = msg {
-                            continue;
-                        }
-                        debug!("Received peer message: {}", &msg);
-                        return Ok(msg);
+                _ = haves_due(&mut self.state.haves) => return Ok(Incoming::HavesDue),
+                n = self.stream.next() => {
+                    let msg = n.ok_or_else(|| anyhow!("Peer closed the connection"))??;
+                    if let PeerMessage::KeepAlive = msg {
+                        continue;
                     }
+                    debug!("Received peer message: {}", &msg);
+                    return Ok(Incoming::Message(msg));
                 }
+            }
+        }
+    }
 
+    async fn on_incoming(&mut self, incoming: Incoming) -> anyhow::Result<()> {
+        match incoming {
+            Incoming::Message(msg) => self.on_message(msg).await,
+            Incoming::Relayed(msg) => self.send_holepunch(msg).await,
+            Incoming::HavesDue => self.send_haves().await,
+        }
+    }
+
+    /// Tell the peer about the pieces we've completed since we last did, as the
+    /// session's [`HavePolicy`] allows.
+    async fn send_haves(&mut self) -> anyhow::Result<()> {
+        let haves = match &mut self.state.haves {
+            Some(haves) => haves.take(&self.ctx.work_queue.bitfield(), &self.state.bitfield),
+            None => return Ok(()),
+        };
+        for idx in haves {
+            self.send_message(PeerMessage::Have(idx)).await?;
+        }
+        Ok(())
+    }
+
+    /// Send the peer a holepunch message, if it supports them.
+    async fn send_holepunch(&mut self, msg: HolepunchMessage) -> anyhow::Result<()> {
+        match self.state.holepunch_id {
+            Some(id) => {
+                self.send_message(PeerMessage::Extended(id, msg.to_bytes()))
+                    .await
             }
+            None => Ok(()),
         }
     }
 
+    /// Act on a holepunch message from the peer: relay a rendezvous it asks for, or
+    /// connect to the peer it put us in touch with.
+    async fn on_holepunch(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        match HolepunchMessage::from_bytes(payload)? {
+            HolepunchMessage::Rendezvous(target) => {
+                let reply = self.ctx.holepunch.rendezvous(self.data.addr(), target);
+                self.send_holepunch(reply).await?;
+            }
+            HolepunchMessage::Connect(addr) => {
+                if self.ctx.holepunch.connect(self.data.addr(), addr) {
+                    debug!("Relay put us in touch with {}", addr);
+                } else {
+                    debug!("Ignoring connect to {} we didn't ask for", addr);
+                }
+            }
+            HolepunchMessage::Error(addr, e) => {
+                debug!("Couldn't holepunch to {}: {}", addr, e);
+            }
+        }
+
+        Ok(())
+    }
+
     /// Receive a message from the peer and adjust session state accordingly.
     #[tracing::instrument]
-    async fn read_message(&mut self, state: &mut PieceState) -> anyhow::Result<()> {
+    async fn read_message(&mut self) -> anyhow::Result<()> {
         let msg = self.recv_message().await?;
+        self.on_message(msg).await
+    }
+
+    /// Act on messages which have already arrived from the peer, without waiting for
+    /// more.
+    async fn read_ready_messages(&mut self) -> anyhow::Result<()> {
+        while let Some(next) = self.stream.next().now_or_never() {
+            let msg = next.ok_or_else(|| anyhow!("Peer closed the connection"))??;
+            if let PeerMessage::KeepAlive = msg {
+                continue;
+            }
+            debug!("Received peer message: {}", &msg);
+            self.on_message(msg).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Change where we are in the protocol, keeping the peer's stats in step.
+    fn transition<T>(
+        &mut self,
+        f: impl FnOnce(&mut ProtocolState) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let result = f(&mut self.state.protocol);
+        self.state.protocol.publish(&self.peer_stats);
+        result
+    }
+
+    async fn on_message(&mut self, msg: PeerMessage) -> anyhow::Result<()> {
+        self.transition(|protocol| protocol.receive(&msg))?;
         match msg {
-            PeerMessage::Choke => self.state.choked = true,
-            PeerMessage::Unchoke => self.state.choked = false,
-            PeerMessage::Have(idx) => self.state.bitfield.set_piece(idx as usize),
-            PeerMessage::Bitfield(field) => self.state.bitfield = field,
-            // TODO: If we have the piece, send it when requested
-            PeerMessage::Request(_idx, _offset, _length) => {}
+            PeerMessage::Choke => {
+                // A choking peer drops our pending requests, so let other peers have them.
+                self.return_outstanding();
+                self.state.cancelled.clear();
+            }
+            PeerMessage::Unchoke => {}
+            PeerMessage::Interested | PeerMessage::NotInterested => self.ctx.choker.rechoke(),
+            PeerMessage::Have(idx) => {
+                let piece_count = self.ctx.torrent.file.info.piece_count();
+                if idx as usize >= piece_count {
+                    return Err(anyhow!(
+                        "Peer has piece {}, but there are only {}",
+                        idx,
+                        piece_count
+                    ));
+                }
+                self.state.bitfield.set_piece(idx as usize);
+                self.ctx.work_queue.on_have(idx as usize);
+                if let Some((_, wants_any)) = &mut self.state.wants_any {
+                    *wants_any |= self.ctx.work_queue.wants(idx as usize);
+                }
+                self.update_peer_pieces();
+            }
+            PeerMessage::Bitfield(field) => {
+                let piece_count = self.ctx.torrent.file.info.piece_count();
+                if field.len() != piece_count.div_ceil(8) {
+                    return Err(anyhow!(
+                        "Peer sent a bitfield of {} bytes for {} pieces",
+                        field.len(),
+                        piece_count
+                    ));
+                }
+                self.ctx
+                    .work_queue
+                    .on_peer_disconnected(&self.state.bitfield);
+                self.ctx.work_queue.on_bitfield(&field);
+                self.state.bitfield = field;
+                self.state.wants_any = None;
+                self.update_peer_pieces();
+            }
+            PeerMessage::Extended(EXTENDED_HANDSHAKE_ID, payload) => {
+                self.on_extended_handshake(&payload);
+            }
+            PeerMessage::Request(idx, begin, length) => {
+                self.queue_upload(block_range(idx, begin, length)).await?;
+            }
+            PeerMessage::Cancel(idx, begin, length) => {
+                let block = block_range(idx, begin, length);
+                if !self.state.uploads.cancel(&block) {
+                    debug!("Peer cancelled {:?}, which wasn't queued", block);
+                }
+            }
+            PeerMessage::RejectRequest(idx, begin, length) if self.state.extensions.fast => {
+                self.on_reject(block_range(idx, begin, length));
+            }
             PeerMessage::Piece(idx, offset, data) => {
                 // TODO make these usizes at the codex level.
-                let idx = idx as usize;
-                let offset = offset as usize;
-
-                if idx != state.index {
-                    return Err(anyhow::anyhow!("Incorrect piece index"));
+                self.on_piece(idx as usize, offset as usize, &data).await?;
+            }
+            PeerMessage::Extended(OUR_TR_HASHPIECE_ID, payload) => {
+                let msg = HashPiece::from_bytes(&payload)?;
+                self.ctx.work_queue.add_hashes(&msg.hashes);
+                self.on_piece(msg.piece as usize, msg.begin as usize, &msg.data)
+                    .await?;
+            }
+            PeerMessage::Extended(OUR_UT_HOLEPUNCH_ID, payload) => {
+                self.on_holepunch(&payload).await?;
+            }
+            PeerMessage::HashRequest(request) => {
+                let msg = match self.ctx.work_queue.layer_hashes(&request) {
+                    Some(hashes) => PeerMessage::Hashes(request, hashes),
+                    None => PeerMessage::HashReject(request),
+                };
+                self.send_message(msg).await?;
+            }
+            PeerMessage::Hashes(request, hashes) => {
+                if self.ctx.work_queue.add_layer_hashes(&request, &hashes) {
+                    self.request_hashes().await?;
+                } else {
+                    debug!("Ignoring hashes which don't match the file's root");
                 }
-                let len = data.len();
+            }
+            PeerMessage::HashReject(request) => {
+                debug!("Peer doesn't have hashes {:?}", request);
+            }
+            PeerMessage::Extended(id, _) => {
+                debug!(
+                    "Ignoring extended message {}, which we didn't advertise",
+                    id
+                );
+            }
+            PeerMessage::Unknown(id, _) => {
+                debug!("Ignoring message with unknown ID {}", id);
+            }
+            _ => {}
+        };
 
-                if offset > state.buf.len() {
-                    return Err(anyhow::anyhow!("Piece offset longer than buffer"));
-                }
+        Ok(())
+    }
+
+    /// Ask the peer for the next piece-layer hashes we're missing, if any.
+    async fn request_hashes(&mut self) -> anyhow::Result<()> {
+        if let Some(request) = self.ctx.work_queue.hash_request() {
+            self.send_message(PeerMessage::HashRequest(request)).await?;
+        }
 
-                if offset + len > state.buf.len() {
-                    return Err(anyhow::anyhow!("Data too long for piece"));
+        Ok(())
+    }
+
+    /// Store a block the peer sent, if we asked for it.
+    async fn on_piece(&mut self, idx: usize, offset: usize, data: &[u8]) -> anyhow::Result<()> {
+        let end = offset + data.len();
+        let pos = self
+            .state
+            .outstanding
+            .iter()
+            .position(|r| r.block.piece == idx && r.block.begin <= offset && end <= r.end());
+        let cancelled = || {
+            self.state
+                .cancelled
+                .iter()
+                .any(|b| b.piece == idx && b.begin <= offset && end <= b.begin + b.length)
+        };
+        let pos = match pos {
+            Some(pos) if !data.is_empty() => pos,
+            None if cancelled() => {
+                debug!("Ignoring block {}:{} we cancelled", idx, offset);
+                return Ok(());
+            }
+            _ => {
+                debug!("Ignoring block {}:{} we didn't request", idx, offset);
+                self.state.unsolicited += data.len();
+                if self.state.unsolicited > MAX_UNSOLICITED {
+                    return Err(anyhow!(
+                        "Peer sent {} bytes we didn't request",
+                        self.state.unsolicited
+                    ));
                 }
+                return Ok(());
+            }
+        };
+        let request = &mut self.state.outstanding[pos];
+        if request.received.is_empty() {
+            self.peer_stats.record_latency(request.sent.elapsed());
+        }
+        request.received.insert(offset, end);
+        if request
+            .received
+            .contains(request.block.begin, request.end())
+        {
+            let request = self.state.outstanding.swap_remove(pos);
+            let addr = self.data.addr();
+            self.ctx.work_queue.release(addr, &request.block);
+        }
+        let block = BlockRange {
+            piece: idx,
+            begin: offset,
+            length: data.len(),
+        };
+
+        self.peer_stats.record_download(data.len());
+        self.ctx.stats.record_download(data.len());
+
+        match self.ctx.work_queue.receive(block, data)? {
+            Received::Pending => {}
+            Received::Assembled(piece) => self.ctx.disk.submit(piece),
+        }
+
+        Ok(())
+    }
 
-                use std::io::Write;
-                (&mut state.buf[offset..]).write_all(&data)?;
-                state.downloaded += len;
-                state.backlog -= 1;
+    fn on_extended_handshake(&mut self, payload: &[u8]) {
+        let handshake = match ExtendedHandshake::from_bytes(payload) {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                debug!("Ignoring invalid extension handshake: {}", e);
+                return;
             }
-            _ => {}
         };
 
+        if let Some(reqq) = handshake.reqq {
+            self.state.max_backlog = (reqq as usize).clamp(1, OUR_REQQ as usize);
+        }
+        self.state.upload_only = handshake.is_upload_only();
+        if let Some(ip) = handshake.yourip() {
+            let voter = Voter::Ip(self.data.addr().ip());
+            self.ctx.external_ip.vote(voter, ip);
+        }
+        self.state.hashpiece_id = handshake
+            .m
+            .get(TR_HASHPIECE)
+            .and_then(|&id| u8::try_from(id).ok())
+            .filter(|&id| id > 0);
+        self.stream
+            .codec_mut()
+            .send_hashpieces(self.state.hashpiece_id);
+        self.state.holepunch_id = handshake
+            .m
+            .get(UT_HOLEPUNCH)
+            .and_then(|&id| u8::try_from(id).ok())
+            .filter(|&id| id > 0);
+        if self.state.holepunch_id.is_some() {
+            self.ctx.holepunch.set_supported(&self.data.addr());
+        }
+    }
+
+    /// Queue a block the peer requested to be sent, if we have its piece. Peers which
+    /// keep sending requests we have to refuse are disconnected.
+    async fn queue_upload(&mut self, block: BlockRange) -> anyhow::Result<()> {
+        if block.length > MAX_REQUEST_LENGTH {
+            return Err(anyhow!("Peer requested a {} byte block", block.length));
+        }
+        if !self.state.protocol.accepts_requests() {
+            debug!("Ignoring request from peer we're choking");
+            return self.refuse(block).await;
+        }
+        if !self.ctx.work_queue.has_piece(block.piece) {
+            debug!("Ignoring request for piece {} we don't have", block.piece);
+            return self.refuse(block).await;
+        }
+
+        match self.state.uploads.push(block) {
+            Queued::Added => Ok(()),
+            Queued::Repeated => {
+                debug!("Ignoring repeated request for {:?}", block);
+                Ok(())
+            }
+            Queued::Full => {
+                debug!("Ignoring request for {:?}, as the queue is full", block);
+                self.refuse(block).await
+            }
+        }
+    }
+
+    /// Reject a request, disconnecting the peer if it has had too many refused lately.
+    async fn refuse(&mut self, block: BlockRange) -> anyhow::Result<()> {
+        self.state.uploads.refuse(time::Instant::now());
+        if self.state.uploads.is_flooded() {
+            return Err(anyhow!(
+                "Peer sent more than {} requests we couldn't serve",
+                MAX_REFUSED_REQUESTS
+            ));
+        }
+        self.reject(block).await
+    }
+
+    /// Tell the peer we won't send a block it requested, if it supports the fast
+    /// extension. Otherwise it has to work that out for itself.
+    async fn reject(&mut self, block: BlockRange) -> anyhow::Result<()> {
+        if self.state.extensions.fast {
+            let BlockRange {
+                piece,
+                begin,
+                length,
+            } = block;
+            let msg = PeerMessage::RejectRequest(piece as u32, begin as u32, length as u32);
+            self.send_message(msg).await?;
+        }
         Ok(())
     }
 
+    /// Give back a block the peer won't send us, so another peer can be asked for it.
+    fn on_reject(&mut self, block: BlockRange) {
+        let pos = self.state.outstanding.iter().position(|r| r.block == block);
+        match pos {
+            Some(pos) => {
+                self.state.outstanding.remove(pos);
+                self.ctx.work_queue.release(self.data.addr(), &block);
+                self.ctx.work_queue.push(block);
+            }
+            None => debug!("Peer rejected {:?}, which we hadn't requested", block),
+        }
+    }
+
+    /// Send the next block the peer requested, once we've read any messages which have
+    /// arrived, as they may cancel it.
+    async fn send_upload(&mut self) -> anyhow::Result<()> {
+        self.read_ready_messages().await?;
+        match self.state.uploads.pop() {
+            Some(block) => self.send_block(block).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn send_block(&mut self, block: BlockRange) -> anyhow::Result<()> {
+        let BlockRange {
+            piece: idx,
+            begin,
+            length,
+        } = block;
+        if let Some(limit) = &self.ctx.limits.upload {
+            limit.acquire(length).await;
+        }
+        if let Some(limit) = self.ctx.alt_speed.upload() {
+            limit.acquire(length).await;
+        }
+        let data = self.ctx.storage.read_block(idx, begin, length).await?;
+        // Peers check merkle torrent pieces with the hashes sent with their first block.
+        let chain = match self.state.hashpiece_id {
+            Some(id) if begin == 0 => self.ctx.work_queue.hash_chain(idx).map(|c| (id, c)),
+            _ => None,
+        };
+        let msg = match chain {
+            Some((id, hashes)) => {
+                let msg = HashPiece {
+                    piece: idx as u32,
+                    begin: begin as u32,
+                    hashes,
+                    data,
+                };
+                PeerMessage::Extended(id, msg.to_bytes()?)
+            }
+            None => PeerMessage::Piece(idx as u32, begin as u32, data.into()),
+        };
+        self.send_message(msg).await?;
+        self.peer_stats.record_upload(length);
+        self.ctx.stats.record_upload(length);
+
+        Ok(())
+    }
+
+    /// Whether the peer has any piece we want and don't have yet, asking the work queue
+    /// only when that or the peer's pieces have changed.
+    fn wants_any(&mut self) -> bool {
+        let changes = self.ctx.work_queue.wants_changes();
+        match self.state.wants_any {
+            Some((seen, wants_any)) if seen == changes => wants_any,
+            _ => {
+                let wants_any = self.ctx.work_queue.wants_any(&self.state.bitfield);
+                self.state.wants_any = Some((changes, wants_any));
+                wants_any
+            }
+        }
+    }
+
+    fn update_peer_pieces(&self) {
+        self.peer_stats
+            .pieces
+            .store(self.state.bitfield.count_pieces(), Ordering::Relaxed);
+    }
+
     #[tracing::instrument]
     pub async fn start_download(&mut self) -> anyhow::Result<()> {
-        self.send_message(PeerMessage::Unchoke).await?;
-        self.send_message(PeerMessage::Interested).await?;
+        let addr = self.data.addr();
+        let unchoke = self.ctx.choker.register(addr, Arc::clone(&self.peer_stats));
+        self.state.relayed = Some(self.ctx.holepunch.register(addr));
+        let result = self.download_pieces(unchoke).await;
+        self.ctx.choker.unregister(&addr);
+        self.ctx.holepunch.unregister(&addr);
+        self.return_outstanding();
+        self.ctx
+            .work_queue
+            .on_peer_disconnected(&self.state.bitfield);
 
-        while let Ok(work) = self.work_queue.pop().await {
-            if !self.state.bitfield.has_piece(work.idx) {
-                self.work_queue.push(work).await?;
-                continue;
+        result
+    }
+
+    async fn download_pieces(&mut self, mut unchoke: watch::Receiver<bool>) -> anyhow::Result<()> {
+        let mut work = self.ctx.work_queue.watch();
+        let mut stop = self.ctx.stop.clone();
+        let mut uninteresting_since = None;
+        loop {
+            if *self.ctx.stop.borrow() {
+                break;
+            }
+            self.send_haves().await?;
+            if self.ctx.seed && self.state.upload_only {
+                debug!("Disconnecting, as neither of us will download");
+                break;
             }
 
-            let buf = self.attempt_download(&work).await?;
+            // Tell the peer whether it has pieces we want, as that changes.
+            let interested = !self.ctx.seed && self.wants_any();
+            if let Some(msg) = self.transition(|protocol| protocol.set_interested(interested))? {
+                self.send_message(msg).await?;
+            }
+            if interested || self.state.protocol.theirs().interested {
+                uninteresting_since = None;
+            } else if uninteresting_since
+                .get_or_insert_with(time::Instant::now)
+                .elapsed()
+                >= UNINTERESTING_TIMEOUT
+            {
+                debug!("Disconnecting, as neither of us wants anything from the other");
+                break;
+            }
 
-            // TODO: Make this a result?
-            if !work.verify_buf(&buf) {
-                warn!("Piece {} failed integrity check", work.idx);
-                self.work_queue.push(work).await?;
+            let unchoked = *unchoke.borrow_and_update();
+            if let Some(msg) = self.transition(|protocol| protocol.set_choking(!unchoked))? {
+                self.send_message(msg).await?;
+                // Choking a peer drops the requests it made.
+                if !unchoked {
+                    for block in self.state.uploads.clear() {
+                        self.reject(block).await?;
+                    }
+                }
+            }
+
+            self.cancel_revoked().await?;
+
+            // Blocks wait in memory while the disk catches up, so don't fetch more.
+            work.mark_seen();
+            if self.state.protocol.can_request() && !self.ctx.seed && !self.ctx.disk.is_overloaded()
+            {
+                let backlog = self.backlog();
+                while self.state.outstanding.len() < backlog {
+                    let (addr, last) = (self.data.addr(), self.state.last_piece);
+                    let block = match self
+                        .ctx
+                        .work_queue
+                        .pop_for(addr, &self.state.bitfield, last)
+                    {
+                        Some(block) => {
+                            self.state.last_piece = Some(block.piece);
+                            block
+                        }
+                        None => match self.pop_duplicate() {
+                            Some(block) => block,
+                            None => break,
+                        },
+                    };
+                    self.state.outstanding.push(Request::new(block));
+                    self.send_request(block.piece, block.begin, block.length)
+                        .await?;
+                    // Latency counts from when the request went out, rather than from
+                    // before waiting on the rate limit, which would feed the backlog.
+                    if let Some(request) = self.state.outstanding.last_mut() {
+                        request.sent = time::Instant::now();
+                    }
+                }
+            }
+
+            // Keep sending what the peer asked for, reading only the messages which have
+            // already arrived meanwhile.
+            if !self.state.uploads.is_empty() {
+                self.send_upload().await?;
+                continue;
+            }
+            if self.state.outstanding.is_empty() {
+                let mut disk = self.ctx.disk.subscribe();
+                // Pieces we handed to the disk queue may yet fail their checks.
+                if self.ctx.work_queue.is_finished() && self.ctx.disk.is_idle() && !self.ctx.seed {
+                    break;
+                }
+                // Park until there may be something to request: the peer announces
+                // new pieces or unchokes us, blocks are given back, or the disk
+                // catches up.
+                // Only waiting is raced, so a message is never dropped half read or
+                // half handled.
+                let idle = time::sleep(IDLE_POLL);
+                tokio::pin!(idle);
+                loop {
+                    let incoming = tokio::select! {
+                        incoming = self.next_incoming() => incoming?,
+                        _ = &mut idle => break,
+                        _ = work.changed() => break,
+                        _ = changed(&mut disk) => break,
+                        _ = changed(&mut unchoke) => break,
+                        _ = changed(&mut stop) => break,
+                    };
+                    let message = matches!(incoming, Incoming::Message(_));
+                    self.on_incoming(incoming).await?;
+                    if message {
+                        break;
+                    }
+                }
                 continue;
             }
 
-            self.send_message(PeerMessage::Have(work.idx as u32))
-                .await?;
-            self.save_tx
-                .send(WorkResult {
-                    idx: work.idx,
-                    bytes: buf,
-                })
-                .await?;
+            self.read_message().await?;
         }
 
         Ok(())
     }
 
+    /// How many requests to keep outstanding: enough to cover what the peer can send
+    /// while a request is on its way, at the rate it's been sending, up to as many as
+    /// it will queue.
+    fn backlog(&self) -> usize {
+        match self.peer_stats.latency() {
+            Some(latency) => {
+                let in_flight = self.peer_stats.download_rate() as f64 * latency.as_secs_f64();
+                let blocks = (2.0 * in_flight / BLOCK_SIZE as f64).ceil() as usize;
+                (blocks + MAX_BACKLOG).min(self.state.max_backlog)
+            }
+            None => self.state.max_backlog,
+        }
+    }
+
+    /// A block another peer hasn't sent yet, if we're in endgame and this peer is among
+    /// the quickest to answer.
+    fn pop_duplicate(&self) -> Option<BlockRange> {
+        if self.ctx.stats.latency_rank(&self.peer_stats) >= ENDGAME_PEERS {
+            return None;
+        }
+        self.ctx
+            .work_queue
+            .pop_duplicate(self.data.addr(), &self.state.bitfield)
+    }
+
+    /// Cancel our requests for blocks the work queue has taken back, such as blocks of
+    /// pieces we no longer want, or other copies of blocks which arrived from another
+    /// peer in endgame.
+    async fn cancel_revoked(&mut self) -> anyhow::Result<()> {
+        let revocations = self.ctx.work_queue.revocations();
+        if revocations == self.state.revocations {
+            return Ok(());
+        }
+        self.state.revocations = revocations;
+
+        let addr = self.data.addr();
+        let work_queue = &self.ctx.work_queue;
+        let (kept, revoked): (Vec<_>, Vec<_>) = self
+            .state
+            .outstanding
+            .drain(..)
+            .partition(|r| work_queue.is_assigned(addr, &r.block));
+        self.state.outstanding = kept;
+        for request in revoked {
+            let BlockRange {
+                piece,
+                begin,
+                length,
+            } = request.block;
+            self.send_message(PeerMessage::Cancel(
+                piece as u32,
+                begin as u32,
+                length as u32,
+            ))
+            .await?;
+            if self.state.cancelled.len() >= OUR_REQQ as usize {
+                self.state.cancelled.pop_front();
+            }
+            self.state.cancelled.push_back(request.block);
+        }
+
+        Ok(())
+    }
+
+    /// Give the blocks we're waiting on back to the work queue.
+    fn return_outstanding(&mut self) {
+        let addr = self.data.addr();
+        for request in self.state.outstanding.drain(..) {
+            self.ctx.work_queue.release(addr, &request.block);
+            self.ctx.work_queue.push(request.block);
+        }
+    }
+
     #[tracing::instrument]
     async fn send_request(
         &mut self,
@@ -309,6 +1204,12 @@ impl PeerSession<PeerMessageCodec> {
         requested: usize,
         block_size: usize,
     ) -> anyhow::Result<()> {
+        if let Some(limit) = &self.ctx.limits.download {
+            limit.acquire(block_size).await;
+        }
+        if let Some(limit) = self.ctx.alt_speed.download() {
+            limit.acquire(block_size).await;
+        }
         self.send_message(PeerMessage::Request(
             idx as u32,
             requested as u32,
@@ -316,34 +1217,174 @@ impl PeerSession<PeerMessageCodec> {
         ))
         .await
     }
+}
 
-    #[tracing::instrument]
-    async fn attempt_download(&mut self, work: &PieceOfWork) -> anyhow::Result<Vec<u8>> {
-        debug!(
-            "Attempting download of piece {} from peer {}",
-            work.idx, self.data.ip
-        );
-        let mut state = PieceState::new(work.idx, work.length);
+/// Something to act on, as [`PeerSession::next_incoming`] waits for.
+enum Incoming {
+    Message(PeerMessage),
+    /// A holepunch message another session relayed for us to pass on to the peer.
+    Relayed(HolepunchMessage),
+    /// Time to tell the peer about the pieces we've completed lately.
+    HavesDue,
+}
 
-        while state.downloaded < work.length {
-            if !self.state.choked {
-                while state.backlog < MAX_BACKLOG && state.requested < work.length {
-                    let mut block_size = MAX_BLOCK_SIZE;
+/// The next holepunch message to relay to the peer, once the session is registered.
+async fn recv_relayed(
+    relayed: &mut Option<UnboundedReceiver<HolepunchMessage>>,
+) -> Option<HolepunchMessage> {
+    match relayed {
+        Some(relayed) => relayed.recv().await,
+        None => futures::future::pending().await,
+    }
+}
 
-                    if work.length - state.requested < block_size {
-                        block_size = work.length - state.requested;
-                    }
+/// Wait for `rx` to change, or forever once its sender is gone.
+async fn changed<T>(rx: &mut watch::Receiver<T>) {
+    if rx.changed().await.is_err() {
+        futures::future::pending().await
+    }
+}
 
-                    self.send_request(work.idx, state.requested, block_size)
-                        .await?;
-                    state.backlog += 1;
-                    state.requested += block_size;
-                }
-            }
+/// Wait until there may be pieces to tell the peer about, once we've sent our bitfield.
+async fn haves_due(haves: &mut Option<Haves>) {
+    match haves {
+        Some(haves) => haves.due().await,
+        None => futures::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{assemble, session_context, torrent, FakePeer, Fault};
 
-            self.read_message(&mut state).await?;
+    #[tokio::test]
+    async fn download_from_fake_peer() {
+        let content = b"hello, peer!";
+        let torrent = torrent("session-test", content, 4);
+        // The peer only advertises the fast extension.
+        let mut reserved = [0; 8];
+        reserved[7] = 0x04;
+        let peer = FakePeer::seeder(&torrent, content).with_reserved(reserved);
+        let (ctx, mut results) = session_context(torrent);
+
+        let (session, peer) = peer.spawn(ctx);
+        let mut session = session.connect().await.unwrap();
+        // The peer didn't set the extension protocol bit, so we mustn't use it.
+        assert_eq!(session.state.extensions, Extensions::default());
+        assert!(session.peer_stats.extensions.lock().unwrap().fast);
+
+        session.start_download().await.unwrap();
+        let traffic = session.ctx.stats.traffic.status();
+        drop(session);
+        let report = peer.await.unwrap().unwrap();
+
+        assert_eq!(report.haves, vec![0, 1, 2]);
+        assert_eq!(assemble(&mut results, 4, content.len()), content);
+        assert_eq!(traffic.payload_down, content.len() as u64);
+        assert!(traffic.overhead_down >= Handshake::LEN as u64);
+        assert_eq!(traffic.payload_up, 0);
+    }
+
+    #[tokio::test]
+    async fn not_interested_in_peers_without_pieces_we_need() {
+        let content = b"hello, peer!";
+        let torrent = torrent("session-test", content, 4);
+        let peer = FakePeer::seeder(&torrent, content).with_pieces([0]);
+        let (ctx, _results) = session_context(torrent);
+        ctx.work_queue.mark_complete(0);
+
+        let (session, peer) = peer.spawn(ctx);
+        let mut session = session.connect().await.unwrap();
+        // The session waits for the peer to get pieces we need, rather than finishing.
+        let idle = time::timeout(Duration::from_millis(100), session.start_download());
+        assert!(idle.await.is_err());
+        assert!(!session.peer_stats.am_interested.load(Ordering::Relaxed));
+        drop(session);
+
+        let report = peer.await.unwrap().unwrap();
+        assert!(!report.interested);
+    }
+
+    #[tokio::test]
+    async fn disconnect_peers_sending_bitfields_of_the_wrong_length() {
+        let content = b"hello, peer!";
+        let torrent = torrent("session-test", content, 4);
+        let peer = FakePeer::seeder(&torrent, content).with_fault(Fault::EmptyBitfield);
+        let (ctx, _results) = session_context(torrent);
+
+        let (session, peer) = peer.spawn(ctx);
+        let mut session = session.connect().await.unwrap();
+        assert!(session.start_download().await.is_err());
+        drop(session);
+        peer.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn disconnect_peers_with_pieces_past_the_end() {
+        let content = b"hello, peer!";
+        let torrent = torrent("session-test", content, 4);
+        let peer = FakePeer::seeder(&torrent, content).with_fault(Fault::HavePastEnd);
+        let (ctx, _results) = session_context(torrent);
+
+        let (session, peer) = peer.spawn(ctx);
+        let mut session = session.connect().await.unwrap();
+        assert!(session.start_download().await.is_err());
+        drop(session);
+        peer.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn cancel_queued_uploads() {
+        let block = |piece, begin| BlockRange {
+            piece,
+            begin,
+            length: BLOCK_SIZE,
+        };
+        let mut uploads = UploadQueue::default();
+        assert_eq!(uploads.push(block(0, 0)), Queued::Added);
+        assert_eq!(uploads.push(block(0, BLOCK_SIZE)), Queued::Added);
+        assert_eq!(uploads.push(block(1, 0)), Queued::Added);
+        assert_eq!(uploads.push(block(1, 0)), Queued::Repeated);
+
+        // Only the exact block requested is cancelled.
+        assert!(!uploads.cancel(&BlockRange {
+            length: 1,
+            ..block(0, BLOCK_SIZE)
+        }));
+        assert!(uploads.cancel(&block(0, BLOCK_SIZE)));
+        assert_eq!(uploads.pop(), Some(block(0, 0)));
+        assert_eq!(uploads.pop(), Some(block(1, 0)));
+        assert_eq!(uploads.pop(), None);
+
+        // Peers can't queue more than we told them we would.
+        for begin in 0..OUR_REQQ as usize {
+            assert_eq!(uploads.push(block(0, begin)), Queued::Added);
         }
+        assert_eq!(uploads.push(block(1, 0)), Queued::Full);
+        assert_eq!(uploads.clear().len(), OUR_REQQ as usize);
+        assert!(uploads.is_empty());
+    }
+
+    #[test]
+    fn refused_requests_within_window() {
+        let start = time::Instant::now();
+        let mut uploads = UploadQueue::default();
 
-        Ok(state.buf)
+        // Refusals spread out over time are fine, and draining the queue in between
+        // doesn't let a peer off.
+        for i in 0..=MAX_REFUSED_REQUESTS as u32 {
+            uploads.refuse(start + REFUSED_WINDOW * i);
+            assert!(!uploads.is_flooded());
+        }
+        let later = start + REFUSED_WINDOW * (MAX_REFUSED_REQUESTS as u32 + 1);
+        for _ in 0..MAX_REFUSED_REQUESTS {
+            uploads.refuse(later);
+            assert!(!uploads.is_flooded());
+            uploads.clear();
+            assert_eq!(uploads.pop(), None);
+        }
+        uploads.refuse(later + Duration::from_secs(1));
+        assert!(uploads.is_flooded());
     }
 }
@@ -1,14 +1,18 @@
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio_util::codec::{Framed, FramedParts};
 
 use super::{handshake::HandshakeCodec, message::PeerMessageCodec};
 
-pub(crate) type HandshakeStream = Framed<TcpStream, HandshakeCodec>;
-pub(crate) type MessageStream = Framed<TcpStream, PeerMessageCodec>;
+pub(crate) type HandshakeStream<S = TcpStream> = Framed<S, HandshakeCodec>;
+pub(crate) type MessageStream<S = TcpStream> = Framed<S, PeerMessageCodec>;
 
-pub(crate) fn make_message_stream(stream: HandshakeStream) -> MessageStream {
+pub(crate) fn make_message_stream<S: AsyncRead + AsyncWrite>(
+    stream: HandshakeStream<S>,
+    codec: PeerMessageCodec,
+) -> MessageStream<S> {
     let old_parts = stream.into_parts();
-    let mut new_parts = FramedParts::new(old_parts.io, PeerMessageCodec);
+    let mut new_parts = FramedParts::new(old_parts.io, codec);
     // reuse buffers of previous codec
     new_parts.read_buf = old_parts.read_buf;
     new_parts.write_buf = old_parts.write_buf;
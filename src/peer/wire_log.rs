@@ -0,0 +1,238 @@
+//! An opt-in log of every message sessions send and receive, one JSON object to a line,
+//! for reporting interop problems with other clients, and replaying them in tests with
+//! `testing::Replay`. Only what each message is, its numbers and how
+//! long it is get logged, never piece data or extension payloads.
+
+use super::message::PeerMessage;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Records waiting to be written, beyond which more are dropped rather than hold up
+/// the sessions logging them.
+const QUEUED_RECORDS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// One message in the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WireRecord {
+    /// Milliseconds since the Unix epoch.
+    pub time: u64,
+    /// Hex info hash of the session's torrent.
+    pub info_hash: String,
+    pub peer: SocketAddr,
+    pub direction: Direction,
+    /// The message's type, such as `request` or `piece`.
+    pub kind: String,
+    /// The message ID, which keep-alives don't have.
+    pub id: Option<u8>,
+    /// The extension message ID of extended messages.
+    pub extended_id: Option<u8>,
+    /// Bytes the message took up on the wire, including its length prefix.
+    pub length: usize,
+    /// The message's numbers: a have's piece, a request's piece, offset and length, or
+    /// a piece's index and offset.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<u32>,
+    /// The pieces a bitfield message says the peer has, in hex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitfield: Option<String>,
+}
+
+/// The file every session's messages are logged to. Sessions log from their codecs,
+/// so records are handed to a thread of its own to write, and the rest are written
+/// when the log is dropped.
+#[derive(Debug)]
+pub struct WireLog {
+    records: Option<SyncSender<Vec<u8>>>,
+    /// Records dropped since the writer last said so.
+    dropped: Arc<AtomicU64>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl WireLog {
+    /// Log to the file at `path`, adding to it if it exists.
+    pub fn open(path: &Path) -> anyhow::Result<Arc<Self>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (records, rx) = mpsc::sync_channel(QUEUED_RECORDS);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer = {
+            let dropped = Arc::clone(&dropped);
+            thread::Builder::new()
+                .name("wire-log".into())
+                .spawn(move || write_records(file, rx, &dropped))?
+        };
+        Ok(Arc::new(Self {
+            records: Some(records),
+            dropped,
+            writer: Some(writer),
+        }))
+    }
+
+    /// Where a session with `peer` logs its messages.
+    pub fn session(self: &Arc<Self>, info_hash: &[u8; 20], peer: SocketAddr) -> SessionLog {
+        SessionLog {
+            log: Arc::clone(self),
+            info_hash: crate::magnet::hex(info_hash),
+            peer,
+        }
+    }
+
+    fn write(&self, record: &WireRecord) {
+        let mut line = serde_json::to_vec(record).expect("Records serialize");
+        line.push(b'\n');
+        let records = self.records.as_ref().expect("Only taken on drop");
+        if let Err(TrySendError::Full(_)) = records.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for WireLog {
+    fn drop(&mut self) {
+        self.records.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Write each line sent on `records` to `file`, flushing whenever none are waiting,
+/// until the log is dropped.
+fn write_records(file: File, records: Receiver<Vec<u8>>, dropped: &AtomicU64) {
+    let mut file = BufWriter::new(file);
+    while let Ok(line) = records.recv() {
+        let mut result = file.write_all(&line);
+        for line in records.try_iter() {
+            result = result.and_then(|_| file.write_all(&line));
+        }
+        if let Err(e) = result.and_then(|_| file.flush()) {
+            warn!("Couldn't write to the wire log: {}", e);
+        }
+        match dropped.swap(0, Ordering::Relaxed) {
+            0 => {}
+            n => warn!("Dropped {} records from the wire log, as it fell behind", n),
+        }
+    }
+}
+
+/// Logs one session's messages, from its codec.
+#[derive(Debug, Clone)]
+pub struct SessionLog {
+    log: Arc<WireLog>,
+    info_hash: String,
+    peer: SocketAddr,
+}
+
+impl SessionLog {
+    pub fn record(&self, direction: Direction, msg: &PeerMessage) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let id = msg.message_id();
+        self.log.write(&WireRecord {
+            time,
+            info_hash: self.info_hash.clone(),
+            peer: self.peer,
+            direction,
+            kind: msg.kind().to_owned(),
+            id,
+            extended_id: match msg {
+                PeerMessage::Extended(id, _) => Some(*id),
+                _ => None,
+            },
+            length: 4 + id.map_or(0, |_| 1) + msg.payload_len(),
+            fields: match *msg {
+                PeerMessage::Have(idx) => vec![idx],
+                PeerMessage::Request(idx, begin, length)
+                | PeerMessage::Cancel(idx, begin, length)
+                | PeerMessage::RejectRequest(idx, begin, length) => vec![idx, begin, length],
+                PeerMessage::Piece(idx, begin, _) => vec![idx, begin],
+                _ => Vec::new(),
+            },
+            bitfield: match msg {
+                PeerMessage::Bitfield(bitfield) => Some(crate::magnet::hex(bitfield)),
+                _ => None,
+            },
+        });
+    }
+}
+
+/// Read back a wire log, to replay or summarise it.
+#[cfg(test)]
+pub fn read_wire_log(path: &Path) -> anyhow::Result<Vec<WireRecord>> {
+    use std::io::{BufRead, BufReader};
+
+    let file = BufReader::new(File::open(path)?);
+    file.lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::peer::PeerMessageCodec;
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    #[test]
+    fn log_messages_through_the_codec() {
+        let path = std::env::temp_dir().join(format!("wire-log-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let log = WireLog::open(&path).unwrap();
+        let mut codec = PeerMessageCodec::default().with_log(Some(log.session(&[0xab; 20], peer)));
+
+        let mut bytes = BytesMut::new();
+        codec
+            .encode(PeerMessage::Piece(1, 0, vec![7; 100].into()), &mut bytes)
+            .unwrap();
+        let piece_len = bytes.len();
+        codec
+            .encode(PeerMessage::Extended(3, vec![0; 10]), &mut bytes)
+            .unwrap();
+        codec.encode(PeerMessage::KeepAlive, &mut bytes).unwrap();
+        while codec.decode(&mut bytes).unwrap().is_some() {}
+        // Everything logged is written by the time the log is dropped.
+        drop((codec, log));
+
+        let records = read_wire_log(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|r| (r.direction, r.kind.as_str(), r.length))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Direction::Sent, "piece", piece_len),
+                (Direction::Sent, "extended", 4 + 2 + 10),
+                (Direction::Sent, "keep_alive", 4),
+                (Direction::Received, "piece", piece_len),
+                (Direction::Received, "extended", 4 + 2 + 10),
+                (Direction::Received, "keep_alive", 4),
+            ]
+        );
+        assert_eq!(records[0].fields, vec![1, 0]);
+        assert_eq!(records[1].extended_id, Some(3));
+        assert_eq!(records[2].id, None);
+        assert_eq!(records[0].peer, peer);
+        assert_eq!(records[0].info_hash, "ab".repeat(20));
+    }
+}
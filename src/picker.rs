@@ -0,0 +1,695 @@
+//! Policies for choosing which blocks to request next.
+
+use crate::bitfield::Bitfield;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+
+/// The largest block we request from peers; most clients refuse anything bigger.
+pub const BLOCK_SIZE: usize = 16_384;
+/// Pieces past the playback position [`Streaming`] downloads in order, by default.
+pub const DEFAULT_STREAM_WINDOW: usize = 16;
+/// Percentage of [`Streaming`]'s requests which go to rare pieces past its window, by
+/// default.
+pub const DEFAULT_STREAM_MIX: u8 = 10;
+
+/// A range of bytes within a piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BlockRange {
+    pub piece: usize,
+    pub begin: usize,
+    pub length: usize,
+}
+
+/// How much we want a file or piece.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Don't download it.
+    Skip,
+    #[default]
+    Normal,
+    /// Download it before anything of normal priority.
+    High,
+}
+
+impl std::str::FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => Err(anyhow::anyhow!("Unknown priority: {}", s)),
+        }
+    }
+}
+
+/// The scheduler's view of the torrent's pieces: how they're laid out, which are
+/// complete, which we want, and which blocks have been requested from peers.
+#[derive(Debug, Clone)]
+pub struct InFlight {
+    piece_length: usize,
+    total_length: usize,
+    complete: Vec<bool>,
+    priorities: Vec<Priority>,
+    requested: BTreeSet<BlockRange>,
+    /// How many of each piece's blocks have been requested.
+    requested_blocks: Vec<usize>,
+    /// How many blocks of pieces we want nobody has been asked for yet.
+    unrequested: usize,
+    /// Pieces which are complete or have blocks requested, in order, so the room
+    /// around a piece can be found without walking every piece.
+    started: BTreeSet<usize>,
+    /// The piece the peer being picked for was last given a block of.
+    affinity: Option<usize>,
+}
+
+impl InFlight {
+    pub fn new(piece_length: usize, total_length: usize) -> Self {
+        let piece_count = total_length.div_ceil(piece_length);
+        let mut in_flight = Self {
+            piece_length,
+            total_length,
+            complete: vec![false; piece_count],
+            priorities: vec![Priority::Normal; piece_count],
+            requested: BTreeSet::new(),
+            unrequested: 0,
+            requested_blocks: vec![0; piece_count],
+            started: BTreeSet::new(),
+            affinity: None,
+        };
+        in_flight.unrequested = (0..piece_count).map(|p| in_flight.block_count(p)).sum();
+        in_flight
+    }
+
+    /// The piece the peer we're picking for was last given a block of, if any. Pickers
+    /// can keep a peer on the pieces after it, so each peer reads and sends a run of
+    /// contiguous pieces, and we write them.
+    pub fn affinity(&self) -> Option<usize> {
+        self.affinity
+    }
+
+    pub(crate) fn set_affinity(&mut self, piece: Option<usize>) {
+        self.affinity = piece;
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.complete.len()
+    }
+
+    pub fn piece_length(&self, piece: usize) -> usize {
+        let begin = piece * self.piece_length;
+        (begin + self.piece_length).min(self.total_length) - begin
+    }
+
+    fn block_count(&self, piece: usize) -> usize {
+        self.piece_length(piece).div_ceil(BLOCK_SIZE)
+    }
+
+    /// All the blocks making up `piece`, in order.
+    pub fn blocks(&self, piece: usize) -> impl Iterator<Item = BlockRange> {
+        let length = self.piece_length(piece);
+        (0..length)
+            .step_by(BLOCK_SIZE)
+            .map(move |begin| BlockRange {
+                piece,
+                begin,
+                length: BLOCK_SIZE.min(length - begin),
+            })
+    }
+
+    pub fn is_complete(&self, piece: usize) -> bool {
+        self.complete[piece]
+    }
+
+    pub fn is_requested(&self, block: &BlockRange) -> bool {
+        self.requested.contains(block)
+    }
+
+    /// Whether every block of every piece we want has been requested, so the download
+    /// is waiting only on blocks already in flight.
+    pub fn all_requested(&self) -> bool {
+        self.unrequested == 0
+    }
+
+    /// The blocks we've asked peers for, in order.
+    pub fn requested(&self) -> Vec<BlockRange> {
+        self.requests().copied().collect()
+    }
+
+    /// Like [`InFlight::requested`], without collecting them.
+    pub fn requests(&self) -> impl Iterator<Item = &BlockRange> {
+        self.requested.iter()
+    }
+
+    /// Whether the piece is complete or any of its blocks have been requested.
+    pub fn is_started(&self, piece: usize) -> bool {
+        self.started.contains(&piece)
+    }
+
+    /// Whether some, but not all, of the piece's blocks have been requested.
+    pub fn is_partial(&self, piece: usize) -> bool {
+        let requested = self.requested_blocks[piece];
+        requested > 0 && requested < self.block_count(piece)
+    }
+
+    /// How many pieces nobody has started lie between `piece` and the nearest started
+    /// piece, on whichever side that's nearer.
+    pub fn room(&self, piece: usize) -> usize {
+        let before = match self.started.range(..piece).next_back() {
+            Some(&p) => piece - p - 1,
+            None => piece,
+        };
+        let after = match self.started.range(piece + 1..).next() {
+            Some(&p) => p - piece - 1,
+            None => self.piece_count() - piece - 1,
+        };
+        before.min(after)
+    }
+
+    /// The first block of `piece` which nobody has been asked for.
+    pub fn next_block(&self, piece: usize) -> Option<BlockRange> {
+        if self.is_complete(piece) {
+            return None;
+        }
+
+        self.blocks(piece).find(|b| !self.is_requested(b))
+    }
+
+    pub fn priority(&self, piece: usize) -> Priority {
+        self.priorities[piece]
+    }
+
+    /// How many pieces we want which aren't complete yet.
+    pub fn remaining(&self) -> usize {
+        self.complete
+            .iter()
+            .zip(&self.priorities)
+            .filter(|(&complete, &priority)| !complete && priority != Priority::Skip)
+            .count()
+    }
+
+    /// Whether every piece we want is complete.
+    pub fn all_complete(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub(crate) fn set_priority(&mut self, piece: usize, priority: Priority) {
+        self.update(piece, |in_flight| {
+            in_flight.priorities[piece] = priority;
+            if priority == Priority::Skip {
+                in_flight.clear_requests(piece);
+            }
+        });
+    }
+
+    pub(crate) fn request(&mut self, block: BlockRange) {
+        self.update(block.piece, |in_flight| {
+            if in_flight.requested.insert(block) {
+                in_flight.requested_blocks[block.piece] += 1;
+            }
+        });
+    }
+
+    pub(crate) fn cancel(&mut self, block: &BlockRange) {
+        self.update(block.piece, |in_flight| {
+            if in_flight.requested.remove(block) {
+                in_flight.requested_blocks[block.piece] -= 1;
+            }
+        });
+    }
+
+    pub(crate) fn set_complete(&mut self, piece: usize) {
+        self.update(piece, |in_flight| {
+            in_flight.complete[piece] = true;
+            in_flight.clear_requests(piece);
+        });
+    }
+
+    pub(crate) fn set_incomplete(&mut self, piece: usize) {
+        self.update(piece, |in_flight| in_flight.complete[piece] = false);
+    }
+
+    fn clear_requests(&mut self, piece: usize) {
+        let start = |piece| BlockRange {
+            piece,
+            begin: 0,
+            length: 0,
+        };
+        let blocks: Vec<_> = self
+            .requested
+            .range(start(piece)..start(piece + 1))
+            .copied()
+            .collect();
+        for block in blocks {
+            self.requested.remove(&block);
+        }
+        self.requested_blocks[piece] = 0;
+    }
+
+    /// Change something about `piece`, keeping what's kept of every piece up to date.
+    fn update(&mut self, piece: usize, change: impl FnOnce(&mut Self)) {
+        let before = self.unrequested_blocks(piece);
+        change(self);
+        self.unrequested = self.unrequested - before + self.unrequested_blocks(piece);
+        self.update_started(piece);
+    }
+
+    /// How many of the piece's blocks we want and haven't asked for.
+    fn unrequested_blocks(&self, piece: usize) -> usize {
+        match self.complete[piece] || self.priorities[piece] == Priority::Skip {
+            true => 0,
+            false => self
+                .block_count(piece)
+                .saturating_sub(self.requested_blocks[piece]),
+        }
+    }
+
+    fn update_started(&mut self, piece: usize) {
+        if self.complete[piece] || self.requested_blocks[piece] > 0 {
+            self.started.insert(piece);
+        } else {
+            self.started.remove(&piece);
+        }
+    }
+
+    /// Pieces a peer with `peer_bitfield` could give us a new block of, limited to the
+    /// highest priority it has any of.
+    fn candidates<'a>(&'a self, peer_bitfield: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        let wanted = move |p: usize| {
+            self.priorities[p] > Priority::Skip
+                && peer_has(peer_bitfield, p)
+                && self.next_block(p).is_some()
+        };
+        let top = (0..self.piece_count())
+            .filter(move |&p| wanted(p))
+            .map(|p| self.priorities[p])
+            .max();
+
+        (0..self.piece_count()).filter(move |&p| Some(self.priorities[p]) == top && wanted(p))
+    }
+}
+
+pub(crate) fn peer_has(bitfield: &[u8], piece: usize) -> bool {
+    piece / 8 < bitfield.len() && bitfield.has_piece(piece)
+}
+
+/// Decides which block to request next. Implement this to plug a custom selection
+/// policy into the scheduler.
+pub trait PiecePicker: Send + std::fmt::Debug {
+    /// Choose a block to request from a peer which has the pieces in `peer_bitfield`,
+    /// or `None` if it has nothing we need which isn't already being downloaded.
+    fn pick(&mut self, peer_bitfield: &[u8], in_flight: &InFlight) -> Option<BlockRange>;
+
+    /// A peer told us it has `piece`.
+    fn on_have(&mut self, _piece: usize) {}
+
+    /// A peer sent us its bitfield.
+    fn on_bitfield(&mut self, _bitfield: &[u8]) {}
+
+    /// A peer with `bitfield` disconnected.
+    fn on_peer_disconnected(&mut self, _bitfield: &[u8]) {}
+
+    /// `piece` has been downloaded and verified.
+    fn on_piece_complete(&mut self, _piece: usize) {}
+}
+
+/// Requests pieces in order, which suits streaming at the cost of swarm health.
+#[derive(Debug, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, peer_bitfield: &[u8], in_flight: &InFlight) -> Option<BlockRange> {
+        let piece = in_flight.candidates(peer_bitfield).next()?;
+        in_flight.next_block(piece)
+    }
+}
+
+/// Finishes partially requested pieces first, then picks pieces at random.
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl PiecePicker for Random {
+    fn pick(&mut self, peer_bitfield: &[u8], in_flight: &InFlight) -> Option<BlockRange> {
+        let candidates: Vec<_> = in_flight.candidates(peer_bitfield).collect();
+        let piece = candidates
+            .iter()
+            .find(|&&p| in_flight.is_partial(p))
+            .or_else(|| candidates.choose(&mut rand::thread_rng()))?;
+
+        in_flight.next_block(*piece)
+    }
+}
+
+/// Finishes partially requested pieces first, then picks the pieces fewest peers have,
+/// so rare pieces spread through the swarm before their owners leave. A peer which
+/// has been given a piece finishes it first, then helps finish pieces other peers have
+/// started, so few are left half done. After that it moves on to the next piece if
+/// it's as rare as any, or else starts a new run of pieces where there's most room
+/// between other peers' runs.
+#[derive(Debug, Default)]
+pub struct RarestFirst {
+    availability: Vec<u32>,
+}
+
+impl RarestFirst {
+    fn availability(&self, piece: usize) -> u32 {
+        self.availability.get(piece).copied().unwrap_or(0)
+    }
+
+    fn adjust(&mut self, bitfield: &[u8], f: impl Fn(u32) -> u32) {
+        let pieces = bitfield.len() * 8;
+        if self.availability.len() < pieces {
+            self.availability.resize(pieces, 0);
+        }
+
+        for piece in 0..pieces {
+            if bitfield.has_piece(piece) {
+                self.availability[piece] = f(self.availability[piece]);
+            }
+        }
+    }
+}
+
+impl PiecePicker for RarestFirst {
+    fn pick(&mut self, peer_bitfield: &[u8], in_flight: &InFlight) -> Option<BlockRange> {
+        let piece = match in_flight.affinity() {
+            Some(last) => in_flight.candidates(peer_bitfield).min_by_key(|&p| {
+                (
+                    p != last,
+                    !in_flight.is_partial(p),
+                    self.availability(p),
+                    p != last + 1,
+                    Reverse(in_flight.room(p)),
+                )
+            }),
+            None => in_flight
+                .candidates(peer_bitfield)
+                .min_by_key(|&p| (!in_flight.is_partial(p), self.availability(p))),
+        }?;
+
+        in_flight.next_block(piece)
+    }
+
+    fn on_have(&mut self, piece: usize) {
+        if self.availability.len() <= piece {
+            self.availability.resize(piece + 1, 0);
+        }
+        self.availability[piece] += 1;
+    }
+
+    fn on_bitfield(&mut self, bitfield: &[u8]) {
+        self.adjust(bitfield, |a| a + 1);
+    }
+
+    fn on_peer_disconnected(&mut self, bitfield: &[u8]) {
+        self.adjust(bitfield, |a| a.saturating_sub(1));
+    }
+}
+
+/// For streaming without starving the swarm of pieces. Requests pieces in order within
+/// `window` pieces of the first one we're missing, which is where playback has got to,
+/// but sends `mix` percent of requests to the rarest pieces past the window. Peers with
+/// nothing in the window are asked for rare pieces whatever the mix.
+#[derive(Debug)]
+pub struct Streaming {
+    window: usize,
+    mix: u8,
+    rarest: RarestFirst,
+    picks: u64,
+}
+
+impl Streaming {
+    pub fn new(window: usize, mix: u8) -> Self {
+        Self {
+            window: window.max(1),
+            mix: mix.min(100),
+            rarest: RarestFirst::default(),
+            picks: 0,
+        }
+    }
+
+    /// Whether this pick should go past the window, spreading those picks evenly.
+    fn past_window(&mut self) -> bool {
+        let mix = self.mix as u64;
+        self.picks += 1;
+        self.picks * mix / 100 > (self.picks - 1) * mix / 100
+    }
+}
+
+impl PiecePicker for Streaming {
+    fn pick(&mut self, peer_bitfield: &[u8], in_flight: &InFlight) -> Option<BlockRange> {
+        let position = (0..in_flight.piece_count())
+            .find(|&p| !in_flight.is_complete(p) && in_flight.priority(p) > Priority::Skip)?;
+        let end = position + self.window;
+        let in_window = in_flight.candidates(peer_bitfield).find(|&p| p < end);
+        let past_window = in_flight
+            .candidates(peer_bitfield)
+            .filter(|&p| p >= end)
+            .min_by_key(|&p| (!in_flight.is_partial(p), self.rarest.availability(p)));
+        let piece = match self.past_window() {
+            true => past_window.or(in_window),
+            false => in_window.or(past_window),
+        }?;
+
+        in_flight.next_block(piece)
+    }
+
+    fn on_have(&mut self, piece: usize) {
+        self.rarest.on_have(piece);
+    }
+
+    fn on_bitfield(&mut self, bitfield: &[u8]) {
+        self.rarest.on_bitfield(bitfield);
+    }
+
+    fn on_peer_disconnected(&mut self, bitfield: &[u8]) {
+        self.rarest.on_peer_disconnected(bitfield);
+    }
+}
+
+/// The built-in pickers, for choosing one from configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PickerKind {
+    RarestFirst,
+    Sequential,
+    Random,
+    /// See [`Streaming`].
+    Streaming {
+        window: usize,
+        mix: u8,
+    },
+}
+
+impl PickerKind {
+    pub fn build(self) -> Box<dyn PiecePicker> {
+        match self {
+            Self::RarestFirst => Box::new(RarestFirst::default()),
+            Self::Sequential => Box::new(Sequential),
+            Self::Random => Box::new(Random),
+            Self::Streaming { window, mix } => Box::new(Streaming::new(window, mix)),
+        }
+    }
+}
+
+impl std::str::FromStr for PickerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rarest-first" => Ok(Self::RarestFirst),
+            "sequential" => Ok(Self::Sequential),
+            "random" => Ok(Self::Random),
+            "streaming" => Ok(Self::Streaming {
+                window: DEFAULT_STREAM_WINDOW,
+                mix: DEFAULT_STREAM_MIX,
+            }),
+            _ => Err(anyhow::anyhow!("Unknown piece picker: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_pieces_into_blocks() {
+        let in_flight = InFlight::new(BLOCK_SIZE * 2, BLOCK_SIZE * 3 + 10);
+
+        assert_eq!(in_flight.piece_count(), 2);
+        assert_eq!(in_flight.blocks(0).count(), 2);
+
+        let last: Vec<_> = in_flight.blocks(1).collect();
+        assert_eq!(last.len(), 2);
+        assert_eq!(last[1].begin, BLOCK_SIZE);
+        assert_eq!(last[1].length, 10);
+    }
+
+    #[test]
+    fn sequential_picks_lowest_piece_peer_has() {
+        let mut in_flight = InFlight::new(BLOCK_SIZE, BLOCK_SIZE * 4);
+        let mut picker = Sequential;
+
+        let block = picker.pick(&[0b0110_0000], &in_flight).unwrap();
+        assert_eq!(block.piece, 1);
+
+        in_flight.request(block);
+        assert_eq!(picker.pick(&[0b0110_0000], &in_flight).unwrap().piece, 2);
+
+        in_flight.set_complete(2);
+        assert_eq!(picker.pick(&[0b0110_0000], &in_flight), None);
+    }
+
+    #[test]
+    fn pick_by_priority() {
+        let mut in_flight = InFlight::new(BLOCK_SIZE, BLOCK_SIZE * 4);
+        in_flight.set_priority(0, Priority::Skip);
+        in_flight.set_priority(2, Priority::High);
+
+        assert_eq!(
+            Sequential.pick(&[0b1111_0000], &in_flight).unwrap().piece,
+            2
+        );
+        assert_eq!(
+            Sequential.pick(&[0b1101_0000], &in_flight).unwrap().piece,
+            1
+        );
+        assert_eq!(Sequential.pick(&[0b1000_0000], &in_flight), None);
+
+        for piece in 1..4 {
+            in_flight.set_complete(piece);
+        }
+        assert!(in_flight.all_complete());
+    }
+
+    #[test]
+    fn rarest_first_prefers_rare_pieces() {
+        let in_flight = InFlight::new(BLOCK_SIZE, BLOCK_SIZE * 3);
+        let mut picker = RarestFirst::default();
+
+        picker.on_bitfield(&[0b1110_0000]);
+        picker.on_bitfield(&[0b1100_0000]);
+        picker.on_have(0);
+
+        assert_eq!(picker.pick(&[0b1110_0000], &in_flight).unwrap().piece, 2);
+
+        picker.on_peer_disconnected(&[0b1110_0000]);
+        picker.on_peer_disconnected(&[0b1100_0000]);
+        assert_eq!(picker.pick(&[0b1110_0000], &in_flight).unwrap().piece, 1);
+    }
+
+    #[test]
+    fn rarest_first_keeps_peers_on_contiguous_pieces() {
+        let mut in_flight = InFlight::new(BLOCK_SIZE * 2, BLOCK_SIZE * 12);
+        let mut picker = RarestFirst::default();
+        picker.on_bitfield(&[0b1111_1100]);
+        picker.on_bitfield(&[0b1111_1100]);
+        picker.on_have(4);
+        in_flight.request(in_flight.next_block(0).unwrap());
+        let request_all = |in_flight: &mut InFlight, piece| {
+            while let Some(block) = in_flight.next_block(piece) {
+                in_flight.request(block);
+            }
+        };
+        request_all(&mut in_flight, 1);
+
+        // A peer which was given piece 1 helps finish another peer's piece 0, then goes
+        // on to piece 2.
+        in_flight.set_affinity(Some(1));
+        assert_eq!(picker.pick(&[0xff], &in_flight).unwrap().piece, 0);
+        request_all(&mut in_flight, 0);
+        assert_eq!(picker.pick(&[0xff], &in_flight).unwrap().piece, 2);
+        // Its own piece comes first, and rarer pieces before the next one.
+        in_flight.request(in_flight.next_block(2).unwrap());
+        in_flight.set_affinity(Some(2));
+        assert_eq!(picker.pick(&[0xff], &in_flight).unwrap().piece, 2);
+        in_flight.request(in_flight.next_block(2).unwrap());
+        assert_eq!(picker.pick(&[0xff], &in_flight).unwrap().piece, 3);
+        request_all(&mut in_flight, 3);
+        in_flight.set_affinity(Some(3));
+        assert_eq!(picker.pick(&[0xff], &in_flight).unwrap().piece, 5);
+
+        // A peer we haven't given anything helps finish a started piece.
+        in_flight.request(in_flight.next_block(5).unwrap());
+        in_flight.set_affinity(None);
+        assert_eq!(picker.pick(&[0xff], &in_flight).unwrap().piece, 5);
+    }
+
+    #[test]
+    fn room_between_started_pieces() {
+        let mut in_flight = InFlight::new(BLOCK_SIZE, BLOCK_SIZE * 10);
+        assert_eq!(in_flight.room(4), 4);
+        let block = in_flight.next_block(2).unwrap();
+        in_flight.request(block);
+        in_flight.set_complete(8);
+        assert_eq!(in_flight.room(5), 2);
+        assert_eq!(in_flight.room(6), 1);
+        assert_eq!(in_flight.room(0), 0);
+
+        in_flight.cancel(&block);
+        assert!(!in_flight.is_started(2));
+        assert_eq!(in_flight.room(5), 2);
+        assert_eq!(in_flight.room(3), 3);
+        in_flight.set_incomplete(8);
+        assert_eq!(in_flight.room(5), 4);
+    }
+
+    #[test]
+    fn count_unrequested_blocks() {
+        let mut in_flight = InFlight::new(BLOCK_SIZE * 2, BLOCK_SIZE * 3);
+        let first = in_flight.next_block(0).unwrap();
+        in_flight.request(first);
+        in_flight.request(in_flight.next_block(0).unwrap());
+        assert!(!in_flight.all_requested());
+
+        in_flight.set_priority(1, Priority::Skip);
+        assert!(in_flight.all_requested());
+        assert_eq!(in_flight.requests().next(), Some(&first));
+
+        in_flight.cancel(&first);
+        assert!(!in_flight.all_requested());
+        in_flight.set_complete(0);
+        assert!(in_flight.all_requested());
+        in_flight.set_priority(1, Priority::Normal);
+        assert!(!in_flight.all_requested());
+    }
+
+    #[test]
+    fn random_finishes_partial_pieces_first() {
+        let mut in_flight = InFlight::new(BLOCK_SIZE * 2, BLOCK_SIZE * 8);
+        let first = in_flight.next_block(2).unwrap();
+        in_flight.request(first);
+
+        let block = Random.pick(&[0b1111_0000], &in_flight).unwrap();
+        assert_eq!(block.piece, 2);
+        assert_eq!(block.begin, BLOCK_SIZE);
+    }
+
+    #[test]
+    fn streaming_mixes_rare_pieces_into_the_window() {
+        let mut in_flight = InFlight::new(BLOCK_SIZE, BLOCK_SIZE * 40);
+        let mut picker = Streaming::new(4, 25);
+        let everything = [0xff; 5];
+        picker.on_bitfield(&everything);
+        picker.on_bitfield(&everything);
+        picker.on_peer_disconnected(&[0, 0, 0, 0b0100_0000, 0]);
+        let mut pick = |in_flight: &mut InFlight| {
+            let block = picker.pick(&everything, in_flight).unwrap();
+            in_flight.request(block);
+            block.piece
+        };
+
+        let picks: Vec<_> = (0..5).map(|_| pick(&mut in_flight)).collect();
+        assert_eq!(picks, vec![0, 1, 2, 25, 3]);
+
+        // The window follows the first piece we're missing.
+        in_flight.set_complete(0);
+        assert_eq!(pick(&mut in_flight), 4);
+    }
+}
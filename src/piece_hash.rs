@@ -0,0 +1,754 @@
+//! Checking pieces against the hashes in the torrent, which are SHA-1 hashes of each
+//! piece in v1 torrents, the root of a SHA-1 hash tree in merkle torrents (BEP 30) and
+//! SHA-256 merkle roots in v2 torrents (BEP 52).
+
+use crate::peer::HashRequest;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Size of the blocks v2 torrents hash as the leaves of their merkle trees.
+pub const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
+
+/// Decides whether a downloaded piece is intact.
+pub trait PieceVerifier: std::fmt::Debug + Send + Sync {
+    fn piece_count(&self) -> usize;
+
+    /// Whether `data` is the content of piece `idx`.
+    fn verify(&self, idx: usize, data: &[u8]) -> bool;
+
+    /// Take hashes a peer sent to prove pieces with, as (offset in the tree, hash) pairs.
+    fn add_hashes(&self, _hashes: &[(usize, [u8; 20])]) {}
+
+    /// The hashes a peer needs to check piece `idx`, for verifiers which need them.
+    fn hash_chain(&self, _idx: usize) -> Option<Vec<(usize, [u8; 20])>> {
+        None
+    }
+
+    /// The hashes proven so far, to be given back with [`PieceVerifier::add_hashes`] to
+    /// check pieces on disk later, for verifiers which take hashes from peers.
+    fn known_hashes(&self) -> Vec<(usize, [u8; 20])> {
+        Vec::new()
+    }
+
+    /// Piece-layer hashes we're missing and should ask peers for, for v2 torrents.
+    fn hash_request(&self) -> Option<HashRequest> {
+        None
+    }
+
+    /// Take piece-layer hashes a peer sent, if they prove out against the file's root.
+    fn add_layer_hashes(&self, _request: &HashRequest, _hashes: &[[u8; 32]]) -> bool {
+        false
+    }
+
+    /// The hashes a peer asked for, followed by the uncles proving them, if we have them.
+    fn layer_hashes(&self, _request: &HashRequest) -> Option<Vec<[u8; 32]>> {
+        None
+    }
+}
+
+/// Pieces of a v1 torrent, each checked against its SHA-1 hash.
+#[derive(Debug, Clone)]
+pub struct Sha1Pieces {
+    hashes: Vec<[u8; 20]>,
+}
+
+impl Sha1Pieces {
+    pub fn new(hashes: Vec<[u8; 20]>) -> Self {
+        Self { hashes }
+    }
+
+    /// Hashes from the concatenated form of the info dictionary's `pieces`.
+    pub fn from_bytes(pieces: &[u8]) -> anyhow::Result<Self> {
+        if !pieces.len().is_multiple_of(20) {
+            anyhow::bail!("Piece hashes aren't a multiple of 20 bytes long");
+        }
+        let hashes = pieces
+            .chunks_exact(20)
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
+
+        Ok(Self::new(hashes))
+    }
+}
+
+impl PieceVerifier for Sha1Pieces {
+    fn piece_count(&self) -> usize {
+        self.hashes.len()
+    }
+
+    fn verify(&self, idx: usize, data: &[u8]) -> bool {
+        let digest: [u8; 20] = Sha1::digest(data).into();
+        self.hashes.get(idx) == Some(&digest)
+    }
+}
+
+/// Pieces of a merkle torrent (BEP 30), checked against the root of a tree of SHA-1
+/// hashes whose leaves are the pieces' hashes. Peers send the hashes on the path from a
+/// piece to the root along with the piece. Nodes are numbered from the root at 0, with
+/// node i's children at 2i + 1 and 2i + 2, and leaves past the last piece are zero.
+#[derive(Debug)]
+pub struct MerkleTree {
+    piece_count: usize,
+    nodes: Mutex<TreeNodes>,
+}
+
+#[derive(Debug, Default)]
+struct TreeNodes {
+    /// Hashes which lead to the root.
+    checked: HashMap<usize, [u8; 20]>,
+    /// Hashes peers sent which haven't been used to prove a piece yet.
+    unchecked: HashMap<usize, [u8; 20]>,
+}
+
+impl MerkleTree {
+    /// A tree we only know the root of, as a downloader has it.
+    pub fn new(root: [u8; 20], piece_count: usize) -> Self {
+        let mut nodes = TreeNodes::default();
+        nodes.checked.insert(0, root);
+
+        Self {
+            piece_count,
+            nodes: Mutex::new(nodes),
+        }
+    }
+
+    /// The whole tree over the hashes of every piece, as its creator or a seed has it.
+    pub fn from_leaves(leaves: &[[u8; 20]]) -> Self {
+        let leaf_count = leaves.len().next_power_of_two();
+        let mut tree = vec![[0; 20]; 2 * leaf_count - 1];
+        tree[leaf_count - 1..][..leaves.len()].copy_from_slice(leaves);
+        for offset in (0..leaf_count - 1).rev() {
+            tree[offset] = sha1_node(&tree[2 * offset + 1], &tree[2 * offset + 2]);
+        }
+
+        let nodes = TreeNodes {
+            checked: tree.into_iter().enumerate().collect(),
+            unchecked: HashMap::new(),
+        };
+        Self {
+            piece_count: leaves.len(),
+            nodes: Mutex::new(nodes),
+        }
+    }
+
+    pub fn root(&self) -> [u8; 20] {
+        self.nodes.lock().unwrap().checked[&0]
+    }
+
+    fn node_count(&self) -> usize {
+        2 * self.piece_count.next_power_of_two() - 1
+    }
+
+    fn leaf(&self, idx: usize) -> usize {
+        self.piece_count.next_power_of_two() - 1 + idx
+    }
+
+    /// Take the hashes of pieces on disk, as (piece, hash) pairs, along with the hashes
+    /// over them they make up, to check pieces against each other where nothing better
+    /// is known. Leaves past the last piece are taken to be zero.
+    pub fn add_leaves(&self, leaves: &[(usize, [u8; 20])]) {
+        let first_leaf = self.leaf(0);
+        let mut level: HashMap<usize, [u8; 20]> = leaves
+            .iter()
+            .filter(|(idx, _)| *idx < self.piece_count)
+            .map(|&(idx, hash)| (first_leaf + idx, hash))
+            .chain((self.piece_count..first_leaf + 1).map(|idx| (first_leaf + idx, [0; 20])))
+            .collect();
+        let mut hashes = Vec::new();
+        while !level.is_empty() {
+            let mut parents = HashMap::new();
+            for (&offset, hash) in &level {
+                // Each pair is hashed from its left node, once both are known.
+                if offset > 0 && offset % 2 == 1 {
+                    if let Some(right) = level.get(&(offset + 1)) {
+                        parents.insert((offset - 1) / 2, sha1_node(hash, right));
+                    }
+                }
+            }
+            hashes.extend(level.drain());
+            level = parents;
+        }
+
+        self.add_hashes(&hashes);
+    }
+
+    /// Whether `hash` is the hash of piece `idx`, as [`PieceVerifier::verify`] checks.
+    pub fn verify_hash(&self, idx: usize, mut hash: [u8; 20]) -> bool {
+        if idx >= self.piece_count {
+            return false;
+        }
+        let mut nodes = self.nodes.lock().unwrap();
+        let mut offset = self.leaf(idx);
+        let mut path = Vec::new();
+
+        // Hash up towards the root until we reach a node we already trust.
+        while nodes.checked.get(&offset) != Some(&hash) {
+            if offset == 0 || nodes.checked.contains_key(&offset) {
+                return false;
+            }
+            let sibling = if offset % 2 == 1 {
+                offset + 1
+            } else {
+                offset - 1
+            };
+            let sibling_hash = match nodes
+                .checked
+                .get(&sibling)
+                .or_else(|| nodes.unchecked.get(&sibling))
+            {
+                Some(&hash) => hash,
+                None => return false,
+            };
+            path.push((offset, hash));
+            path.push((sibling, sibling_hash));
+
+            hash = if offset % 2 == 1 {
+                sha1_node(&hash, &sibling_hash)
+            } else {
+                sha1_node(&sibling_hash, &hash)
+            };
+            offset = (offset - 1) / 2;
+        }
+
+        for (offset, hash) in path {
+            nodes.unchecked.remove(&offset);
+            nodes.checked.insert(offset, hash);
+        }
+        true
+    }
+}
+
+impl PieceVerifier for MerkleTree {
+    fn piece_count(&self) -> usize {
+        self.piece_count
+    }
+
+    fn verify(&self, idx: usize, data: &[u8]) -> bool {
+        self.verify_hash(idx, Sha1::digest(data).into())
+    }
+
+    fn add_hashes(&self, hashes: &[(usize, [u8; 20])]) {
+        let node_count = self.node_count();
+        let mut nodes = self.nodes.lock().unwrap();
+        for &(offset, hash) in hashes {
+            if offset < node_count && !nodes.checked.contains_key(&offset) {
+                nodes.unchecked.insert(offset, hash);
+            }
+        }
+    }
+
+    fn hash_chain(&self, idx: usize) -> Option<Vec<(usize, [u8; 20])>> {
+        if idx >= self.piece_count {
+            return None;
+        }
+        let nodes = self.nodes.lock().unwrap();
+        let mut offset = self.leaf(idx);
+        let mut chain = Vec::new();
+        while offset > 0 {
+            let sibling = if offset % 2 == 1 {
+                offset + 1
+            } else {
+                offset - 1
+            };
+            chain.push((sibling, *nodes.checked.get(&sibling)?));
+            offset = (offset - 1) / 2;
+        }
+
+        Some(chain)
+    }
+
+    fn known_hashes(&self) -> Vec<(usize, [u8; 20])> {
+        let mut hashes: Vec<_> = self
+            .nodes
+            .lock()
+            .unwrap()
+            .checked
+            .iter()
+            .map(|(&offset, &hash)| (offset, hash))
+            .collect();
+        hashes.sort_unstable();
+        hashes
+    }
+}
+
+fn sha1_node(left: &[u8; 20], right: &[u8; 20]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Pieces of a v2 torrent, checked against a file's piece layer: the roots of the merkle
+/// trees over each piece's 16 KiB blocks. The layer can come from the metainfo, or from
+/// peers in hashes messages, checked against the root of the file's tree.
+#[derive(Debug)]
+pub struct MerklePieces {
+    pieces_root: [u8; 32],
+    piece_length: usize,
+    /// The piece layer, as far as we have it.
+    layer: Mutex<Vec<Option<[u8; 32]>>>,
+}
+
+impl MerklePieces {
+    /// The most hashes we ask a peer for at once.
+    const MAX_REQUEST_HASHES: usize = 512;
+
+    /// Pieces whose whole piece layer we have.
+    pub fn new(piece_length: usize, layer: Vec<[u8; 32]>) -> Self {
+        let pieces = Self::from_root([0; 32], piece_length, layer.len());
+        let pieces_root = merkle_root(layer.clone(), layer.len(), pieces.padding());
+
+        Self {
+            pieces_root,
+            layer: Mutex::new(layer.into_iter().map(Some).collect()),
+            ..pieces
+        }
+    }
+
+    /// Pieces of the file with `pieces_root`, none of whose piece layer we have yet.
+    pub fn from_root(pieces_root: [u8; 32], piece_length: usize, piece_count: usize) -> Self {
+        Self {
+            pieces_root,
+            piece_length,
+            layer: Mutex::new(vec![None; piece_count]),
+        }
+    }
+
+    pub fn pieces_root(&self) -> [u8; 32] {
+        self.pieces_root
+    }
+
+    /// The root of the merkle tree over a piece's blocks. Short pieces at the end of a
+    /// file are padded with zero hashes, so every piece's tree has the same shape.
+    pub fn piece_root(&self, data: &[u8]) -> [u8; 32] {
+        let hashes = data
+            .chunks(MERKLE_BLOCK_SIZE)
+            .map(|block| Sha256::digest(block).into())
+            .collect();
+
+        merkle_root(hashes, self.leaves_per_piece(), [0; 32])
+    }
+
+    fn leaves_per_piece(&self) -> usize {
+        (self.piece_length / MERKLE_BLOCK_SIZE).max(1)
+    }
+
+    /// The layer of the file's tree the piece layer is, counting up from the blocks.
+    fn piece_layer(&self) -> u32 {
+        self.leaves_per_piece().next_power_of_two().trailing_zeros()
+    }
+
+    /// The hash of a piece past the end of the file, whose blocks are all padding.
+    fn padding(&self) -> [u8; 32] {
+        merkle_root(Vec::new(), self.leaves_per_piece(), [0; 32])
+    }
+
+    /// How many layers there are above `length` hashes of the piece layer up to the root.
+    fn proof_layers(&self, piece_count: usize, length: usize) -> u32 {
+        (piece_count.next_power_of_two() / length).trailing_zeros()
+    }
+
+    /// Whether `request` is for a power of two run of the piece layer, aligned to its
+    /// length and within the layer padded to a power of two, as peers must ask for.
+    fn is_valid(&self, request: &HashRequest, piece_count: usize) -> bool {
+        let (index, length) = (request.index as usize, request.length as usize);
+        request.pieces_root == self.pieces_root
+            && request.base_layer == self.piece_layer()
+            && length.is_power_of_two()
+            && length <= Self::MAX_REQUEST_HASHES
+            && index % length == 0
+            && index < piece_count
+            && index
+                .checked_add(length)
+                .is_some_and(|end| end <= piece_count.next_power_of_two())
+    }
+}
+
+impl PieceVerifier for MerklePieces {
+    fn piece_count(&self) -> usize {
+        self.layer.lock().unwrap().len()
+    }
+
+    fn verify(&self, idx: usize, data: &[u8]) -> bool {
+        let expected = self.layer.lock().unwrap().get(idx).copied().flatten();
+        data.len() <= self.piece_length && expected == Some(self.piece_root(data))
+    }
+
+    fn hash_request(&self) -> Option<HashRequest> {
+        let layer = self.layer.lock().unwrap();
+        let missing = layer.iter().position(Option::is_none)?;
+        let length = layer
+            .len()
+            .next_power_of_two()
+            .min(Self::MAX_REQUEST_HASHES);
+
+        Some(HashRequest {
+            pieces_root: self.pieces_root,
+            base_layer: self.piece_layer(),
+            index: (missing / length * length) as u32,
+            length: length as u32,
+            proof_layers: self.proof_layers(layer.len(), length),
+        })
+    }
+
+    fn add_layer_hashes(&self, request: &HashRequest, hashes: &[[u8; 32]]) -> bool {
+        let mut layer = self.layer.lock().unwrap();
+        if !self.is_valid(request, layer.len()) {
+            return false;
+        }
+        let (index, length) = (request.index as usize, request.length as usize);
+        let needed = self.proof_layers(layer.len(), length) as usize;
+        if hashes.len() < length + needed {
+            return false;
+        }
+
+        // Hash the run up to its subtree's root, then up to the file's root with the uncles.
+        let (run, uncles) = hashes.split_at(length);
+        let mut hash = merkle_root(run.to_vec(), length, [0; 32]);
+        let mut offset = index / length;
+        for uncle in &uncles[..needed] {
+            hash = if offset % 2 == 0 {
+                sha256_node(&hash, uncle)
+            } else {
+                sha256_node(uncle, &hash)
+            };
+            offset /= 2;
+        }
+        if hash != self.pieces_root {
+            return false;
+        }
+
+        let end = layer.len().min(index + length);
+        for (known, hash) in layer[index..end].iter_mut().zip(run) {
+            *known = Some(*hash);
+        }
+        true
+    }
+
+    fn layer_hashes(&self, request: &HashRequest) -> Option<Vec<[u8; 32]>> {
+        let layer = self.layer.lock().unwrap();
+        if !self.is_valid(request, layer.len()) {
+            return None;
+        }
+        let layer: Vec<_> = layer.iter().copied().collect::<Option<_>>()?;
+        let (index, length) = (request.index as usize, request.length as usize);
+        let layers = merkle_layers(layer, 0, self.padding());
+
+        let mut hashes = layers[0][index..index + length].to_vec();
+        let level = length.trailing_zeros() as usize;
+        let proof_layers = (request.proof_layers as usize).min(layers.len() - 1 - level);
+        let mut offset = index / length;
+        for layer in &layers[level..level + proof_layers] {
+            hashes.push(layer[offset ^ 1]);
+            offset /= 2;
+        }
+
+        Some(hashes)
+    }
+}
+
+/// Pieces of a v2 or hybrid torrent, where each file starts on a piece boundary and is
+/// checked against its own pieces root. In hybrid torrents, the padding after a file is
+/// part of its last piece, and is left out when checking it.
+#[derive(Debug)]
+pub struct V2Pieces {
+    piece_count: usize,
+    /// The first piece of each file, in order, with the file's pieces.
+    files: Vec<(usize, V2File)>,
+}
+
+#[derive(Debug)]
+struct V2File {
+    length: usize,
+    piece_length: usize,
+    pieces: FilePieces,
+}
+
+#[derive(Debug)]
+enum FilePieces {
+    /// A file no larger than a piece, whose pieces root is the root of its blocks.
+    Small([u8; 32]),
+    Layered(MerklePieces),
+}
+
+impl V2Pieces {
+    /// Pieces of files of the given lengths and pieces roots, in the torrent's order.
+    /// Files larger than a piece are checked against their piece layer, from
+    /// `layers` by pieces root if the metainfo has it, or otherwise fetched from peers.
+    pub fn new(
+        piece_length: usize,
+        files: &[(usize, Option<[u8; 32]>)],
+        layers: &HashMap<[u8; 32], Vec<[u8; 32]>>,
+    ) -> anyhow::Result<Self> {
+        let mut first = 0;
+        let mut pieces = Vec::new();
+        for &(length, root) in files {
+            let root = match (length, root) {
+                (0, _) => continue,
+                (_, Some(root)) => root,
+                (_, None) => anyhow::bail!("File without a pieces root"),
+            };
+            let count = length.div_ceil(piece_length);
+            let file_pieces = match (count, layers.get(&root)) {
+                (1, _) => FilePieces::Small(root),
+                (_, Some(layer)) => {
+                    let layered = MerklePieces::new(piece_length, layer.clone());
+                    if layer.len() != count || layered.pieces_root() != root {
+                        anyhow::bail!("Piece layer doesn't match its file");
+                    }
+                    FilePieces::Layered(layered)
+                }
+                (_, None) => {
+                    FilePieces::Layered(MerklePieces::from_root(root, piece_length, count))
+                }
+            };
+            pieces.push((
+                first,
+                V2File {
+                    length,
+                    piece_length,
+                    pieces: file_pieces,
+                },
+            ));
+            first += count;
+        }
+
+        Ok(Self {
+            piece_count: first,
+            files: pieces,
+        })
+    }
+
+    /// The file piece `idx` is in, and the index of the piece within it.
+    fn file(&self, idx: usize) -> Option<(&V2File, usize)> {
+        if idx >= self.piece_count {
+            return None;
+        }
+        let file = self.files.partition_point(|(first, _)| *first <= idx) - 1;
+        let (first, file) = &self.files[file];
+        Some((file, idx - first))
+    }
+
+    fn layered(&self, pieces_root: &[u8; 32]) -> Option<&MerklePieces> {
+        self.files.iter().find_map(|(_, file)| match &file.pieces {
+            FilePieces::Layered(pieces) if pieces.pieces_root() == *pieces_root => Some(pieces),
+            _ => None,
+        })
+    }
+}
+
+impl PieceVerifier for V2Pieces {
+    fn piece_count(&self) -> usize {
+        self.piece_count
+    }
+
+    fn verify(&self, idx: usize, data: &[u8]) -> bool {
+        let (file, local) = match self.file(idx) {
+            Some(file) => file,
+            None => return false,
+        };
+        let length = (file.length - local * file.piece_length).min(file.piece_length);
+        if data.len() < length {
+            return false;
+        }
+        let data = &data[..length];
+        match &file.pieces {
+            FilePieces::Small(root) => small_file_root(data) == *root,
+            FilePieces::Layered(pieces) => pieces.verify(local, data),
+        }
+    }
+
+    fn hash_request(&self) -> Option<HashRequest> {
+        self.files.iter().find_map(|(_, file)| match &file.pieces {
+            FilePieces::Layered(pieces) => pieces.hash_request(),
+            FilePieces::Small(_) => None,
+        })
+    }
+
+    fn add_layer_hashes(&self, request: &HashRequest, hashes: &[[u8; 32]]) -> bool {
+        self.layered(&request.pieces_root)
+            .is_some_and(|pieces| pieces.add_layer_hashes(request, hashes))
+    }
+
+    fn layer_hashes(&self, request: &HashRequest) -> Option<Vec<[u8; 32]>> {
+        self.layered(&request.pieces_root)?.layer_hashes(request)
+    }
+}
+
+/// The pieces root of a v2 file no larger than a piece, whose tree is only as wide as
+/// its blocks need, rather than a whole piece wide.
+pub fn small_file_root(data: &[u8]) -> [u8; 32] {
+    let hashes = data
+        .chunks(MERKLE_BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect();
+
+    merkle_root(hashes, 0, [0; 32])
+}
+
+/// Every layer of a merkle tree over `hashes`, padded with `pad` to `width` or the next
+/// power of two, from the hashes themselves up to the root.
+fn merkle_layers(mut hashes: Vec<[u8; 32]>, width: usize, pad: [u8; 32]) -> Vec<Vec<[u8; 32]>> {
+    hashes.resize(width.max(hashes.len()).next_power_of_two(), pad);
+    let mut layers = vec![hashes];
+    while layers.last().unwrap().len() > 1 {
+        let next = layers
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| sha256_node(&pair[0], &pair[1]))
+            .collect();
+        layers.push(next);
+    }
+
+    layers
+}
+
+fn merkle_root(hashes: Vec<[u8; 32]>, width: usize, pad: [u8; 32]) -> [u8; 32] {
+    merkle_layers(hashes, width, pad).pop().unwrap()[0]
+}
+
+fn sha256_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_sha1_pieces() {
+        let pieces: Vec<u8> = [&b"abcd"[..], b"ef"]
+            .into_iter()
+            .flat_map(Sha1::digest)
+            .collect();
+        let verifier = Sha1Pieces::from_bytes(&pieces).unwrap();
+
+        assert_eq!(verifier.piece_count(), 2);
+        assert!(verifier.verify(0, b"abcd"));
+        assert!(verifier.verify(1, b"ef"));
+        assert!(!verifier.verify(1, b"eF"));
+        assert!(!verifier.verify(2, b""));
+        assert!(Sha1Pieces::from_bytes(&pieces[1..]).is_err());
+    }
+
+    #[test]
+    fn verify_pieces_with_hash_chains() {
+        let pieces: Vec<&[u8]> = vec![b"one", b"two", b"three"];
+        let leaves: Vec<[u8; 20]> = pieces.iter().map(|p| Sha1::digest(p).into()).collect();
+        let seed = MerkleTree::from_leaves(&leaves);
+        let downloader = MerkleTree::new(seed.root(), pieces.len());
+
+        // The fourth leaf is padding, so the tree is [0] over [1, 2] over [3, 4, 5, 6].
+        let chain = seed.hash_chain(2).unwrap();
+        assert_eq!(
+            chain,
+            vec![(6, [0; 20]), (1, seed.nodes.lock().unwrap().checked[&1])]
+        );
+
+        assert!(!downloader.verify(2, pieces[2]));
+        downloader.add_hashes(&chain);
+        assert!(!downloader.verify(2, b"thre3"));
+        assert!(downloader.verify(2, pieces[2]));
+        assert!(downloader.hash_chain(2).is_some());
+
+        // Piece 0 only needs its sibling now the other half of the tree is checked.
+        downloader.add_hashes(&[(4, leaves[1])]);
+        assert!(downloader.verify(0, pieces[0]));
+        assert!(downloader.verify(1, pieces[1]));
+        assert!(!downloader.verify(1, pieces[0]));
+        assert!(!downloader.verify(3, b""));
+    }
+
+    #[test]
+    fn verify_merkle_pieces() {
+        let piece_length = MERKLE_BLOCK_SIZE * 4;
+        let data: Vec<u8> = (0..piece_length + 100).map(|i| i as u8).collect();
+        let leaf = |block: &[u8]| -> [u8; 32] { Sha256::digest(block).into() };
+        let node =
+            |a: [u8; 32], b: [u8; 32]| -> [u8; 32] { Sha256::digest(&[a, b].concat()).into() };
+
+        let blocks: Vec<_> = data[..piece_length]
+            .chunks(MERKLE_BLOCK_SIZE)
+            .map(leaf)
+            .collect();
+        let first = node(node(blocks[0], blocks[1]), node(blocks[2], blocks[3]));
+        // The last piece is one short block, padded to the same number of leaves.
+        let zero = [0; 32];
+        let last = node(node(leaf(&data[piece_length..]), zero), node(zero, zero));
+
+        let verifier = MerklePieces::new(piece_length, vec![first, last]);
+        assert!(verifier.verify(0, &data[..piece_length]));
+        assert!(verifier.verify(1, &data[piece_length..]));
+        let mut corrupt = data[..piece_length].to_vec();
+        corrupt[MERKLE_BLOCK_SIZE * 3] ^= 1;
+        assert!(!verifier.verify(0, &corrupt));
+        assert!(!verifier.verify(2, &data[piece_length..]));
+    }
+
+    #[test]
+    fn fetch_piece_layer_from_peer() {
+        let piece_length = MERKLE_BLOCK_SIZE * 2;
+        let layer: Vec<[u8; 32]> = (0..5u8).map(|i| Sha256::digest(&[i]).into()).collect();
+        let seed = MerklePieces::new(piece_length, layer.clone());
+        let downloader = MerklePieces::from_root(seed.pieces_root(), piece_length, 5);
+
+        // Five pieces make a tree eight wide, one layer above the blocks.
+        let request = downloader.hash_request().unwrap();
+        assert_eq!((request.base_layer, request.index), (1, 0));
+        assert_eq!((request.length, request.proof_layers), (8, 0));
+
+        // A smaller run needs uncles to reach the root.
+        let request = HashRequest {
+            index: 4,
+            length: 4,
+            proof_layers: 1,
+            ..request
+        };
+        let hashes = seed.layer_hashes(&request).unwrap();
+        assert_eq!(hashes.len(), 5);
+        assert_eq!(hashes[..1], layer[4..]);
+        assert_eq!(hashes[1], seed.padding());
+
+        let mut forged = hashes.clone();
+        forged[0][0] ^= 1;
+        assert!(!downloader.add_layer_hashes(&request, &forged));
+        assert!(!downloader.add_layer_hashes(&request, &hashes[..4]));
+        assert!(downloader.add_layer_hashes(&request, &hashes));
+        assert_eq!(downloader.hash_request().unwrap().index, 0);
+
+        // We can't serve hashes until we have the whole layer.
+        assert!(downloader.layer_hashes(&request).is_none());
+        let rest = seed
+            .layer_hashes(&HashRequest {
+                index: 0,
+                ..request
+            })
+            .unwrap();
+        assert!(downloader.add_layer_hashes(
+            &HashRequest {
+                index: 0,
+                ..request
+            },
+            &rest
+        ));
+        assert!(downloader.hash_request().is_none());
+        assert_eq!(downloader.layer_hashes(&request), Some(hashes));
+
+        // Runs past the end of the padded layer are refused rather than read.
+        for (index, length) in [(0, 16), (4, 512)] {
+            let request = HashRequest {
+                index,
+                length,
+                ..request
+            };
+            assert!(seed.layer_hashes(&request).is_none());
+            assert!(!downloader.add_layer_hashes(&request, &[[0; 32]; 32]));
+        }
+    }
+}
@@ -1,39 +1,1347 @@
-use async_channel::{RecvError, SendError};
-use sha1::{Digest, Sha1};
+use crate::bitfield::BitfieldMut;
+use crate::buffers::{Buffer, BufferPool};
+use crate::peer::HashRequest;
+use crate::picker::{peer_has, BlockRange, InFlight, PiecePicker, Priority};
+use crate::piece_hash::PieceVerifier;
+use anyhow::anyhow;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{watch, Notify};
 
+/// A piece which is being assembled from blocks sent by one or more peers.
 #[derive(Debug, Clone)]
 pub struct PieceOfWork {
     pub idx: usize,
-    pub hash: [u8; 20],
     pub length: usize,
+    buf: Buffer,
+    received: ByteRanges,
 }
 
 impl PieceOfWork {
-    pub fn verify_buf(&self, buf: &[u8]) -> bool {
-        let digest: [u8; 20] = Sha1::digest(buf).into();
+    fn new(idx: usize, buf: Buffer) -> Self {
+        Self {
+            idx,
+            length: buf.len(),
+            buf,
+            received: ByteRanges::default(),
+        }
+    }
+}
+
+/// Disjoint ranges of bytes, merged as they're added.
+#[derive(Debug, Clone, Default)]
+pub struct ByteRanges {
+    /// The start of each range, mapped to its end.
+    ranges: BTreeMap<usize, usize>,
+}
+
+impl ByteRanges {
+    pub fn insert(&mut self, mut start: usize, mut end: usize) {
+        if start >= end {
+            return;
+        }
+        let touching: Vec<_> = self
+            .ranges
+            .range(..=end)
+            .filter(|(_, &e)| e >= start)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for (s, e) in touching {
+            self.ranges.remove(&s);
+            start = start.min(s);
+            end = end.max(e);
+        }
+        self.ranges.insert(start, end);
+    }
+
+    /// Whether every byte in `start..end` is in one of the ranges.
+    pub fn contains(&self, start: usize, end: usize) -> bool {
+        start >= end
+            || self
+                .ranges
+                .range(..=start)
+                .next_back()
+                .is_some_and(|(_, &e)| e >= end)
+    }
 
-        self.hash == digest
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// How many bytes the ranges cover.
+    pub fn len(&self) -> usize {
+        self.ranges.iter().map(|(start, end)| end - start).sum()
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct WorkResult {
     pub idx: usize,
-    pub bytes: Vec<u8>,
+    pub bytes: Buffer,
+}
+
+/// What happened to a piece when one of its blocks arrived.
+#[derive(Debug)]
+pub enum Received {
+    /// The piece still has blocks outstanding.
+    Pending,
+    /// The block completed the piece, which needs its hash checking with
+    /// [`WorkQueue::verify`].
+    Assembled(WorkResult),
+}
+
+/// Whether an assembled piece passed its hash check.
+#[derive(Debug)]
+pub enum Verified {
+    Passed(WorkResult),
+    /// The piece will be downloaded again.
+    Failed(usize),
+    /// Another copy of a piece we already have, as peers may both send it in endgame,
+    /// which needn't be saved.
+    Duplicate(usize),
+}
+
+#[derive(Debug)]
+struct QueueState {
+    picker: Box<dyn PiecePicker>,
+    in_flight: InFlight,
+    pieces: HashMap<usize, PieceOfWork>,
+    /// Blocks handed to a second peer in endgame.
+    duplicated: HashSet<BlockRange>,
+    /// The peers each block was handed to, if it was handed to a particular peer.
+    assigned: HashMap<BlockRange, Vec<SocketAddr>>,
+    /// How many times each piece has failed its hash check.
+    failures: HashMap<usize, u32>,
+    /// How many pieces have been downloaded and passed their hash check.
+    verified: usize,
 }
 
+/// Hands out blocks to peer sessions, using a [`PiecePicker`] to decide which block
+/// each peer should download next, and assembles the blocks into pieces. Blocks of
+/// the same piece can be downloaded from several peers at once.
 #[derive(Debug, Clone)]
 pub struct WorkQueue {
-    pub tx: async_channel::Sender<PieceOfWork>,
-    pub rx: async_channel::Receiver<PieceOfWork>,
+    state: Arc<Mutex<QueueState>>,
+    /// Kept outside the lock, so hashing a piece doesn't hold up other sessions.
+    verifier: Arc<dyn PieceVerifier>,
+    /// Where the buffers pieces are assembled in come from.
+    buffers: BufferPool,
+    /// How many times a piece has been completed, so sessions can tell their peers.
+    completions: Arc<watch::Sender<u64>>,
+    /// Bumped when blocks which couldn't be handed out may be now, such as blocks
+    /// given back or pieces which have to be downloaded again.
+    available: Arc<watch::Sender<u64>>,
+    /// Bumped when blocks are taken back from the peers they were handed to, so their
+    /// sessions can cancel the requests.
+    revoked: Arc<AtomicU64>,
+    /// The pieces wanted, sent whenever priorities change which they are.
+    selection: Arc<watch::Sender<Selection>>,
+    /// Bumped when the pieces we want and don't have change, as pieces are completed
+    /// or found missing or priorities change, so sessions know to check again whether
+    /// their peers have any.
+    wants: Arc<AtomicU64>,
+}
+
+/// Waits for a queue to have blocks to hand out, for sessions which found nothing
+/// to request.
+#[derive(Debug)]
+pub struct WorkWatch {
+    available: watch::Receiver<u64>,
+    given_back: Arc<Notify>,
+}
+
+impl WorkWatch {
+    /// Forget the changes so far, before looking for blocks to request.
+    pub fn mark_seen(&mut self) {
+        self.available.borrow_and_update();
+        // A buffer given back meanwhile may be taken by the looking.
+        let _ = self.given_back.notified().now_or_never();
+    }
+
+    /// Wait for a change since [`WorkWatch::mark_seen`] which may let a session request
+    /// blocks it couldn't. A peer's new pieces aren't among them, and each buffer given
+    /// back wakes only one session.
+    pub async fn changed(&mut self) {
+        let result = tokio::select! {
+            result = self.available.changed() => result,
+            _ = self.given_back.notified() => Ok(()),
+        };
+        if result.is_err() {
+            futures::future::pending().await
+        }
+    }
 }
 
 impl WorkQueue {
-    pub async fn pop(&self) -> Result<PieceOfWork, RecvError> {
-        self.rx.recv().await
+    pub fn new(
+        verifier: Box<dyn PieceVerifier>,
+        piece_length: usize,
+        total_length: usize,
+        picker: Box<dyn PiecePicker>,
+    ) -> Self {
+        let in_flight = InFlight::new(piece_length, total_length);
+        let selection = Selection::of(&in_flight);
+        Self {
+            state: Arc::new(Mutex::new(QueueState {
+                picker,
+                in_flight,
+                pieces: HashMap::new(),
+                duplicated: HashSet::new(),
+                assigned: HashMap::new(),
+                failures: HashMap::new(),
+                verified: 0,
+            })),
+            verifier: Arc::from(verifier),
+            buffers: BufferPool::default(),
+            completions: Arc::new(watch::Sender::new(0)),
+            available: Arc::new(watch::Sender::new(0)),
+            revoked: Default::default(),
+            selection: Arc::new(watch::Sender::new(selection)),
+            wants: Default::default(),
+        }
+    }
+
+    /// Assemble pieces in buffers from `buffers`, such as a pool shared with other
+    /// torrents, rather than one of the queue's own.
+    pub fn with_buffers(mut self, buffers: BufferPool) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    /// Take the next block a peer with `peer_bitfield` should download, if it has any we
+    /// need. New pieces aren't started while their buffers would go over the memory
+    /// budget, but blocks of pieces already started are still handed out.
+    pub fn pop(&self, peer_bitfield: &[u8]) -> Option<BlockRange> {
+        self.take(None, peer_bitfield, None)
+    }
+
+    /// Like [`WorkQueue::pop`], assigning the block to `peer`, which was last given a
+    /// block of `last`, so the picker can keep it on contiguous pieces. The block is
+    /// the peer's until it's released or given back, or revoked.
+    pub fn pop_for(
+        &self,
+        peer: SocketAddr,
+        peer_bitfield: &[u8],
+        last: Option<usize>,
+    ) -> Option<BlockRange> {
+        self.take(Some(peer), peer_bitfield, last)
+    }
+
+    fn take(
+        &self,
+        peer: Option<SocketAddr>,
+        peer_bitfield: &[u8],
+        last: Option<usize>,
+    ) -> Option<BlockRange> {
+        let mut state = self.state.lock().unwrap();
+        let QueueState {
+            picker,
+            in_flight,
+            pieces,
+            assigned,
+            ..
+        } = &mut *state;
+
+        in_flight.set_affinity(last);
+        let block = picker.pick(peer_bitfield, in_flight);
+        in_flight.set_affinity(None);
+        let mut block = block?;
+        if let Entry::Vacant(entry) = pieces.entry(block.piece) {
+            match self.buffers.try_take(in_flight.piece_length(block.piece)) {
+                Some(buf) => {
+                    entry.insert(PieceOfWork::new(block.piece, buf));
+                }
+                // Over budget, so finish a piece which already has a buffer instead.
+                None => {
+                    block = pieces
+                        .keys()
+                        .filter(|&&p| peer_has(peer_bitfield, p))
+                        .filter_map(|&p| in_flight.next_block(p))
+                        .min_by_key(|b| b.piece)?;
+                }
+            }
+        }
+        in_flight.request(block);
+        if let Some(peer) = peer {
+            assigned.entry(block).or_default().push(peer);
+        }
+
+        Some(block)
+    }
+
+    /// In endgame, once every block we want has been requested, take a block another
+    /// peer was asked for and hasn't sent yet, so a slow peer doesn't hold up the end of
+    /// the download. Each block is only asked of one more peer, and never of a peer
+    /// it's already assigned to.
+    pub fn pop_duplicate(&self, peer: SocketAddr, peer_bitfield: &[u8]) -> Option<BlockRange> {
+        let mut state = self.state.lock().unwrap();
+        let QueueState {
+            in_flight,
+            pieces,
+            duplicated,
+            assigned,
+            ..
+        } = &mut *state;
+        if !in_flight.all_requested() {
+            return None;
+        }
+
+        // Pieces which aren't being assembled have all arrived, and are being checked.
+        let block = in_flight.requests().copied().find(|block| {
+            peer_has(peer_bitfield, block.piece)
+                && !duplicated.contains(block)
+                && !assigned
+                    .get(block)
+                    .is_some_and(|peers| peers.contains(&peer))
+                && pieces
+                    .get(&block.piece)
+                    .is_some_and(|p| !p.received.contains(block.begin, block.begin + block.length))
+        })?;
+        duplicated.insert(block);
+        assigned.entry(block).or_default().push(peer);
+
+        Some(block)
+    }
+
+    /// `peer` is done with `block`, having received all of it or given it back.
+    pub fn release(&self, peer: SocketAddr, block: &BlockRange) {
+        let mut state = self.state.lock().unwrap();
+        if let Entry::Occupied(mut entry) = state.assigned.entry(*block) {
+            entry.get_mut().retain(|&p| p != peer);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Whether `block` is still assigned to `peer`, rather than revoked.
+    pub fn is_assigned(&self, peer: SocketAddr, block: &BlockRange) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .assigned
+            .get(block)
+            .is_some_and(|peers| peers.contains(&peer))
+    }
+
+    /// Take back every block of `piece` from the peers it was handed to, and drop what
+    /// has been received of it. It's downloaded again from scratch if we still want it.
+    pub fn revoke(&self, piece: usize) {
+        let mut state = self.state.lock().unwrap();
+        revoke(&mut state, piece);
+        drop(state);
+        self.revoked.fetch_add(1, Ordering::Relaxed);
+        self.make_available();
+    }
+
+    /// A count which changes whenever blocks are revoked, for sessions to check whether
+    /// any of theirs were.
+    pub fn revocations(&self) -> u64 {
+        self.revoked.load(Ordering::Relaxed)
+    }
+
+    /// Give back a block which couldn't be downloaded, so another peer can try. Blocks
+    /// which were only partly downloaded are requested again in full.
+    pub fn push(&self, block: BlockRange) {
+        let mut state = self.state.lock().unwrap();
+        let received = state
+            .pieces
+            .get(&block.piece)
+            .map(|p| p.received.contains(block.begin, block.begin + block.length))
+            .unwrap_or(false);
+
+        if !received {
+            state.in_flight.cancel(&block);
+            self.make_available();
+        }
+    }
+
+    /// Store downloaded data at `block`'s offset, returning its piece if this was the
+    /// last of it missing. The data needn't line up with the blocks we requested.
+    pub fn receive(&self, block: BlockRange, data: &[u8]) -> anyhow::Result<Received> {
+        let mut state = self.state.lock().unwrap();
+        let QueueState {
+            in_flight, pieces, ..
+        } = &mut *state;
+
+        if in_flight.is_complete(block.piece) {
+            return Ok(Received::Pending);
+        }
+        if block.begin + data.len() > in_flight.piece_length(block.piece) {
+            return Err(anyhow!("Data too long for piece"));
+        }
+        // Blocks of pieces revoked or skipped since they were requested are dropped,
+        // as are copies of blocks of pieces already assembled, rather than starting a
+        // buffer nothing will finish. Requested pieces got theirs when they were popped.
+        let end = block.begin + data.len();
+        let requested = in_flight
+            .blocks(block.piece)
+            .filter(|b| b.begin < end && block.begin < b.begin + b.length)
+            .any(|b| in_flight.is_requested(&b));
+        let piece = match pieces.get_mut(&block.piece) {
+            Some(piece) if requested => piece,
+            _ => return Ok(Received::Pending),
+        };
+        piece.buf[block.begin..block.begin + data.len()].copy_from_slice(data);
+        piece.received.insert(block.begin, block.begin + data.len());
+
+        if !piece.received.contains(0, piece.length) {
+            return Ok(Received::Pending);
+        }
+
+        // The piece's blocks stay requested until it's verified, so they aren't
+        // handed out again meanwhile.
+        let piece = pieces.remove(&block.piece).unwrap();
+        Ok(Received::Assembled(WorkResult {
+            idx: piece.idx,
+            bytes: piece.buf,
+        }))
+    }
+
+    /// Check an assembled piece's hash, marking it complete if it passes or to be
+    /// downloaded again if it doesn't. This can take a while for large pieces.
+    pub fn verify(&self, piece: WorkResult) -> Verified {
+        let passed = self.verifier.verify(piece.idx, &piece.bytes);
+
+        let mut state = self.state.lock().unwrap();
+        let QueueState {
+            picker,
+            in_flight,
+            pieces,
+            duplicated,
+            assigned,
+            failures,
+            verified,
+        } = &mut *state;
+        if in_flight.is_complete(piece.idx) {
+            return Verified::Duplicate(piece.idx);
+        }
+        // Blocks requested again in endgame may have started another copy.
+        pieces.remove(&piece.idx);
+        duplicated.retain(|b| b.piece != piece.idx);
+        // Other copies of its blocks still on their way are no use now.
+        let before = assigned.len();
+        assigned.retain(|b, _| b.piece != piece.idx);
+        if assigned.len() != before {
+            self.revoked.fetch_add(1, Ordering::Relaxed);
+        }
+        if passed {
+            in_flight.set_complete(piece.idx);
+            self.wants.fetch_add(1, Ordering::Relaxed);
+            *verified += 1;
+            self.completions.send_modify(|count| *count += 1);
+            picker.on_piece_complete(piece.idx);
+            Verified::Passed(piece)
+        } else {
+            for block in in_flight.blocks(piece.idx).collect::<Vec<_>>() {
+                in_flight.cancel(&block);
+            }
+            *failures.entry(piece.idx).or_default() += 1;
+            self.make_available();
+            Verified::Failed(piece.idx)
+        }
+    }
+
+    /// How many pieces still need downloading.
+    pub fn remaining(&self) -> usize {
+        self.state.lock().unwrap().in_flight.remaining()
+    }
+
+    /// Take hashes a peer sent to prove pieces of a merkle torrent with.
+    pub fn add_hashes(&self, hashes: &[(usize, [u8; 20])]) {
+        self.verifier.add_hashes(hashes);
+    }
+
+    /// The hashes a peer needs along with piece `idx`, if it's from a merkle torrent.
+    pub fn hash_chain(&self, idx: usize) -> Option<Vec<(usize, [u8; 20])>> {
+        self.verifier.hash_chain(idx)
+    }
+
+    /// The hashes of a merkle torrent's tree proven so far, to check pieces on disk with.
+    pub fn known_hashes(&self) -> Vec<(usize, [u8; 20])> {
+        self.verifier.known_hashes()
+    }
+
+    /// Piece-layer hashes of a v2 torrent we should ask peers for.
+    pub fn hash_request(&self) -> Option<HashRequest> {
+        self.verifier.hash_request()
+    }
+
+    /// Take piece-layer hashes a peer sent, returning whether they were valid.
+    pub fn add_layer_hashes(&self, request: &HashRequest, hashes: &[[u8; 32]]) -> bool {
+        self.verifier.add_layer_hashes(request, hashes)
+    }
+
+    /// Piece-layer hashes a peer asked for, if we have them.
+    pub fn layer_hashes(&self, request: &HashRequest) -> Option<Vec<[u8; 32]>> {
+        self.verifier.layer_hashes(request)
+    }
+
+    /// Bytes in pieces we don't have yet, whether or not we want them.
+    pub fn left(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        let in_flight = &state.in_flight;
+        (0..in_flight.piece_count())
+            .filter(|&idx| !in_flight.is_complete(idx))
+            .map(|idx| in_flight.piece_length(idx) as u64)
+            .sum()
+    }
+
+    pub fn verified_count(&self) -> usize {
+        self.state.lock().unwrap().verified
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().in_flight.all_complete()
+    }
+
+    /// Whether a peer with `peer_bitfield` has any piece we want and don't have yet.
+    /// This goes through every piece, so sessions keep the answer until
+    /// [`WorkQueue::wants_changes`] changes.
+    pub fn wants_any(&self, peer_bitfield: &[u8]) -> bool {
+        let state = self.state.lock().unwrap();
+        (0..state.in_flight.piece_count())
+            .any(|idx| peer_has(peer_bitfield, idx) && wanted(&state.in_flight, idx))
+    }
+
+    /// Whether we want piece `idx` and don't have it yet.
+    pub fn wants(&self, idx: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        idx < state.in_flight.piece_count() && wanted(&state.in_flight, idx)
+    }
+
+    /// A count which changes whenever the pieces we want and don't have do, for
+    /// sessions to check whether to ask [`WorkQueue::wants_any`] again.
+    pub fn wants_changes(&self) -> u64 {
+        self.wants.load(Ordering::Relaxed)
+    }
+
+    pub fn has_piece(&self, idx: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        idx < state.in_flight.piece_count() && state.in_flight.is_complete(idx)
+    }
+
+    /// Record a piece we already have, e.g. one found on disk, so it isn't downloaded.
+    pub fn mark_complete(&self, idx: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.pieces.remove(&idx);
+        state.in_flight.set_complete(idx);
+        state.picker.on_piece_complete(idx);
+        self.wants.fetch_add(1, Ordering::Relaxed);
+        self.completions.send_modify(|count| *count += 1);
+    }
+
+    /// Watch for pieces being completed.
+    pub fn completions(&self) -> watch::Receiver<u64> {
+        self.completions.subscribe()
+    }
+
+    /// Forget a piece we thought we had, e.g. one which is corrupt on disk, so it's
+    /// downloaded again.
+    pub fn mark_missing(&self, idx: usize) {
+        self.state.lock().unwrap().in_flight.set_incomplete(idx);
+        self.wants.fetch_add(1, Ordering::Relaxed);
+        self.make_available();
+    }
+
+    /// Change how much we want a piece. Pieces with [`Priority::Skip`] aren't downloaded,
+    /// and don't need to be for the download to finish.
+    pub fn set_priority(&self, idx: usize, priority: Priority) {
+        let mut state = self.state.lock().unwrap();
+        if priority == Priority::Skip {
+            revoke(&mut state, idx);
+            self.revoked.fetch_add(1, Ordering::Relaxed);
+        }
+        state.in_flight.set_priority(idx, priority);
+        self.wants.fetch_add(1, Ordering::Relaxed);
+        self.reselect(&state);
+        if priority != Priority::Skip {
+            self.make_available();
+        }
+    }
+
+    /// Change the priority of every piece at once, e.g. when the files wanted change
+    /// during a download. Blocks of pieces no longer wanted are taken back from the
+    /// peers downloading them, and pieces newly wanted are handed out, with no session
+    /// seeing a mix of the old and new selections.
+    pub fn set_priorities(&self, priorities: &[Priority]) -> anyhow::Result<Selection> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.in_flight.piece_count();
+        if priorities.len() != count {
+            return Err(anyhow!(
+                "Expected priorities for {} pieces, got {}",
+                count,
+                priorities.len()
+            ));
+        }
+
+        let (mut skipped, mut wanted) = (false, false);
+        for (idx, &priority) in priorities.iter().enumerate() {
+            let was_skipped = state.in_flight.priority(idx) == Priority::Skip;
+            if priority == Priority::Skip && !was_skipped {
+                revoke(&mut state, idx);
+                skipped = true;
+            }
+            wanted |= was_skipped && priority != Priority::Skip;
+            state.in_flight.set_priority(idx, priority);
+        }
+        let selection = self.reselect(&state);
+        self.wants.fetch_add(1, Ordering::Relaxed);
+        drop(state);
+
+        if skipped {
+            self.revoked.fetch_add(1, Ordering::Relaxed);
+        }
+        if wanted {
+            self.make_available();
+        }
+        Ok(selection)
+    }
+
+    /// The pieces wanted, and how many of them we have.
+    pub fn selection(&self) -> Selection {
+        Selection::of(&self.state.lock().unwrap().in_flight)
+    }
+
+    /// Watch for changes to which pieces are wanted, with progress recalculated
+    /// against the new selection.
+    pub fn selection_changes(&self) -> watch::Receiver<Selection> {
+        self.selection.subscribe()
+    }
+
+    fn reselect(&self, state: &QueueState) -> Selection {
+        let selection = Selection::of(&state.in_flight);
+        self.selection.send_if_modified(|current| {
+            let changed = *current != selection;
+            *current = selection;
+            changed
+        });
+        selection
+    }
+
+    /// What the scheduler is doing: the pieces being downloaded, which peers have
+    /// which blocks, and the pieces which have failed their hash check.
+    pub fn status(&self) -> SchedulerStatus {
+        let state = self.state.lock().unwrap();
+        let in_flight = &state.in_flight;
+        let requested = in_flight.requested();
+
+        let mut pieces: Vec<_> = state
+            .pieces
+            .values()
+            .map(|piece| PieceProgress {
+                piece: piece.idx,
+                length: piece.length,
+                received: piece.received.len(),
+                requested: requested.iter().filter(|b| b.piece == piece.idx).count(),
+                blocks: in_flight.blocks(piece.idx).count(),
+            })
+            .collect();
+        pieces.sort_by_key(|p| p.piece);
+
+        let mut assignments: BTreeMap<SocketAddr, Vec<BlockRange>> = BTreeMap::new();
+        for (block, peers) in &state.assigned {
+            for peer in peers {
+                assignments.entry(*peer).or_default().push(*block);
+            }
+        }
+        for blocks in assignments.values_mut() {
+            blocks.sort();
+        }
+
+        SchedulerStatus {
+            remaining: in_flight.remaining(),
+            verified: state.verified,
+            pieces,
+            assignments,
+            failures: state.failures.iter().map(|(&p, &n)| (p, n)).collect(),
+        }
+    }
+
+    /// Watch for blocks becoming available to sessions which found none to request.
+    pub fn watch(&self) -> WorkWatch {
+        WorkWatch {
+            available: self.available.subscribe(),
+            given_back: self.buffers.given_back(),
+        }
+    }
+
+    fn make_available(&self) {
+        self.available.send_modify(|count| *count += 1);
+    }
+
+    /// The pieces which aren't skipped, as a bitfield.
+    pub fn wanted(&self) -> Vec<u8> {
+        let state = self.state.lock().unwrap();
+        let count = state.in_flight.piece_count();
+        let mut bitfield = vec![0; count.div_ceil(8)];
+        for idx in (0..count).filter(|&idx| state.in_flight.priority(idx) != Priority::Skip) {
+            bitfield.set_piece(idx);
+        }
+
+        bitfield
+    }
+
+    /// The pieces we have, in the form sent in a `Bitfield` message.
+    pub fn bitfield(&self) -> Vec<u8> {
+        let state = self.state.lock().unwrap();
+        let count = state.in_flight.piece_count();
+        let mut bitfield = vec![0; count.div_ceil(8)];
+        for idx in (0..count).filter(|&idx| state.in_flight.is_complete(idx)) {
+            bitfield.set_piece(idx);
+        }
+
+        bitfield
+    }
+
+    /// A peer has piece `idx`. Pieces past the end are ignored, so a peer can't make
+    /// the picker grow without bound.
+    pub fn on_have(&self, idx: usize) {
+        let mut state = self.state.lock().unwrap();
+        if idx < state.in_flight.piece_count() {
+            state.picker.on_have(idx);
+        }
+    }
+
+    /// A peer sent its bitfield. Bytes past the last piece are ignored.
+    pub fn on_bitfield(&self, bitfield: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let len = bitfield
+            .len()
+            .min(state.in_flight.piece_count().div_ceil(8));
+        state.picker.on_bitfield(&bitfield[..len]);
+    }
+
+    pub fn on_peer_disconnected(&self, bitfield: &[u8]) {
+        self.state
+            .lock()
+            .unwrap()
+            .picker
+            .on_peer_disconnected(bitfield);
+    }
+}
+
+fn wanted(in_flight: &InFlight, idx: usize) -> bool {
+    !in_flight.is_complete(idx) && in_flight.priority(idx) != Priority::Skip
+}
+
+/// Take back every block of `piece`, see [`WorkQueue::revoke`].
+fn revoke(state: &mut QueueState, piece: usize) {
+    state.pieces.remove(&piece);
+    state.duplicated.retain(|b| b.piece != piece);
+    state.assigned.retain(|b, _| b.piece != piece);
+    let blocks: Vec<_> = state.in_flight.blocks(piece).collect();
+    for block in &blocks {
+        state.in_flight.cancel(block);
+    }
+}
+
+/// A snapshot of a [`WorkQueue`], from [`WorkQueue::status`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SchedulerStatus {
+    /// Pieces we want which aren't complete yet.
+    pub remaining: usize,
+    /// Pieces downloaded which passed their hash check.
+    pub verified: usize,
+    /// Pieces being assembled.
+    pub pieces: Vec<PieceProgress>,
+    /// The blocks handed to each peer which it hasn't sent yet.
+    pub assignments: BTreeMap<SocketAddr, Vec<BlockRange>>,
+    /// How many times pieces have failed their hash check, and been downloaded again.
+    pub failures: BTreeMap<usize, u32>,
+}
+
+/// Which pieces a torrent wants, from [`WorkQueue::selection`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Selection {
+    /// Pieces which aren't skipped.
+    pub wanted: usize,
+    /// Wanted pieces we have.
+    pub done: usize,
+    /// Bytes in wanted pieces we don't have yet.
+    pub left: u64,
+}
+
+impl Selection {
+    fn of(in_flight: &InFlight) -> Self {
+        let mut selection = Self::default();
+        for idx in (0..in_flight.piece_count()).filter(|&p| in_flight.priority(p) != Priority::Skip)
+        {
+            selection.wanted += 1;
+            if in_flight.is_complete(idx) {
+                selection.done += 1;
+            } else {
+                selection.left += in_flight.piece_length(idx) as u64;
+            }
+        }
+        selection
+    }
+
+    /// The fraction of the wanted pieces we have, which is 1 when none are wanted.
+    pub fn progress(&self) -> f64 {
+        match self.wanted {
+            0 => 1.0,
+            wanted => self.done as f64 / wanted as f64,
+        }
+    }
+}
+
+/// How far a piece being assembled has got.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PieceProgress {
+    pub piece: usize,
+    pub length: usize,
+    /// Bytes received.
+    pub received: usize,
+    /// Blocks requested from peers, and how many blocks the piece has.
+    pub requested: usize,
+    pub blocks: usize,
+}
+
+/// Holds back pieces which complete ahead of earlier ones, so they can be passed on in
+/// index order.
+#[derive(Debug)]
+pub struct InOrder {
+    next: usize,
+    max_buffered: usize,
+    buffered: BTreeMap<usize, WorkResult>,
+}
+
+impl InOrder {
+    pub fn new(max_buffered: usize) -> Self {
+        Self {
+            next: 0,
+            max_buffered,
+            buffered: BTreeMap::new(),
+        }
+    }
+
+    /// Accept a completed piece, returning the pieces which can now be delivered, in order.
+    /// Fails if more than `max_buffered` pieces are waiting for an earlier one.
+    pub fn push(&mut self, result: WorkResult) -> anyhow::Result<Vec<WorkResult>> {
+        if result.idx < self.next {
+            return Ok(Vec::new());
+        }
+        self.buffered.insert(result.idx, result);
+
+        let mut ready = Vec::new();
+        while let Some(result) = self.buffered.remove(&self.next) {
+            ready.push(result);
+            self.next += 1;
+        }
+
+        if self.buffered.len() > self.max_buffered {
+            return Err(anyhow!(
+                "More than {} pieces completed while waiting for piece {}",
+                self.max_buffered,
+                self.next
+            ));
+        }
+
+        Ok(ready)
+    }
+
+    /// Number of pieces waiting for an earlier piece to complete.
+    #[cfg(test)]
+    pub fn buffered(&self) -> usize {
+        self.buffered.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::picker::{Sequential, BLOCK_SIZE};
+    use crate::piece_hash::Sha1Pieces;
+    use sha1::{Digest, Sha1};
+    use tokio::time::{self, Duration};
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn queue(data: &[u8], piece_length: usize) -> WorkQueue {
+        let hashes = data
+            .chunks(piece_length)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let verifier = Box::new(Sha1Pieces::new(hashes));
+        WorkQueue::new(verifier, piece_length, data.len(), Box::new(Sequential))
+    }
+
+    #[test]
+    fn assemble_piece_from_blocks_of_different_peers() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| i as u8).collect();
+        let queue = queue(&data, BLOCK_SIZE * 2);
+
+        let first = queue.pop(&[0xff]).unwrap();
+        let second = queue.pop(&[0xff]).unwrap();
+        assert_ne!(first, second);
+        assert!(queue.pop(&[0xff]).is_none());
+
+        let received = queue
+            .receive(second, &data[second.begin..second.begin + second.length])
+            .unwrap();
+        assert!(matches!(received, Received::Pending));
+
+        let received = queue
+            .receive(first, &data[first.begin..first.begin + first.length])
+            .unwrap();
+        let piece = match received {
+            Received::Assembled(piece) => piece,
+            other => panic!("Expected assembled piece, got {:?}", other),
+        };
+        assert!(!queue.is_finished());
+        match queue.verify(piece) {
+            Verified::Passed(result) => assert_eq!(result.bytes, data),
+            other => panic!("Expected piece to pass, got {:?}", other),
+        }
+        assert!(queue.is_finished());
+        assert_eq!(queue.verified_count(), 1);
+    }
+
+    #[test]
+    fn assemble_piece_from_partial_blocks() {
+        let data: Vec<u8> = (0..BLOCK_SIZE).map(|i| i as u8).collect();
+        let queue = queue(&data, BLOCK_SIZE);
+        let block = queue.pop(&[0xff]).unwrap();
+
+        let half = BLOCK_SIZE / 2;
+        let second = BlockRange {
+            begin: half,
+            length: half,
+            ..block
+        };
+        let received = queue.receive(second, &data[half..]).unwrap();
+        assert!(matches!(received, Received::Pending));
+
+        // A block given back when it was only partly received is requested again.
+        queue.push(block);
+        assert_eq!(queue.pop(&[0xff]), Some(block));
+
+        let received = queue.receive(block, &data[..half]).unwrap();
+        assert!(matches!(received, Received::Assembled(_)));
+    }
+
+    #[test]
+    fn merge_byte_ranges() {
+        let mut ranges = ByteRanges::default();
+        ranges.insert(10, 20);
+        ranges.insert(30, 40);
+        assert!(!ranges.contains(10, 40));
+
+        ranges.insert(15, 30);
+        assert!(ranges.contains(10, 40));
+        assert!(!ranges.contains(9, 40));
+        assert_eq!(ranges.ranges.len(), 1);
+    }
+
+    #[test]
+    fn missing_piece_is_requested_again() {
+        let data = vec![1; BLOCK_SIZE * 2];
+        let queue = queue(&data, BLOCK_SIZE);
+        queue.mark_complete(0);
+        queue.mark_complete(1);
+        assert!(queue.is_finished());
+
+        queue.mark_missing(1);
+        assert_eq!(queue.remaining(), 1);
+        assert!(!queue.has_piece(1));
+        assert_eq!(queue.pop(&[0xff]).map(|b| b.piece), Some(1));
+    }
+
+    #[test]
+    fn failed_piece_is_requested_again() {
+        let data = vec![1; BLOCK_SIZE];
+        let queue = queue(&data, BLOCK_SIZE);
+
+        let block = queue.pop(&[0xff]).unwrap();
+        let piece = match queue.receive(block, &vec![2; BLOCK_SIZE]).unwrap() {
+            Received::Assembled(piece) => piece,
+            other => panic!("Expected assembled piece, got {:?}", other),
+        };
+        // Nobody else is given the piece's blocks while it's being checked.
+        assert_eq!(queue.pop(&[0xff]), None);
+        assert!(matches!(queue.verify(piece), Verified::Failed(0)));
+
+        assert_eq!(queue.pop(&[0xff]), Some(block));
+    }
+
+    #[test]
+    fn pushed_blocks_are_handed_out_again() {
+        let data = vec![1; BLOCK_SIZE];
+        let queue = queue(&data, BLOCK_SIZE);
+
+        let block = queue.pop(&[0xff]).unwrap();
+        assert!(queue.pop(&[0xff]).is_none());
+
+        queue.push(block);
+        assert_eq!(queue.pop(&[0xff]), Some(block));
+    }
+
+    #[test]
+    fn dont_count_a_piece_assembled_twice() {
+        let data = vec![1; BLOCK_SIZE];
+        let queue = queue(&data, BLOCK_SIZE);
+        let block = queue.pop(&[0xff]).unwrap();
+
+        // Two peers send the block, the second while the first copy is being verified,
+        // which isn't assembled again.
+        let first = match queue.receive(block, &data).unwrap() {
+            Received::Assembled(piece) => piece,
+            other => panic!("Expected assembled piece, got {:?}", other),
+        };
+        assert!(matches!(
+            queue.receive(block, &data).unwrap(),
+            Received::Pending
+        ));
+        let copy = first.clone();
+        assert!(matches!(queue.verify(first), Verified::Passed(_)));
+        assert!(matches!(queue.verify(copy), Verified::Duplicate(0)));
+        assert_eq!(queue.verified_count(), 1);
+        assert!(matches!(
+            queue.receive(block, &data).unwrap(),
+            Received::Pending
+        ));
+    }
+
+    #[test]
+    fn only_start_pieces_within_memory_budget() {
+        let data = vec![1; BLOCK_SIZE * 4];
+        let buffers = BufferPool::new(BLOCK_SIZE * 2);
+        let queue = queue(&data, BLOCK_SIZE * 2).with_buffers(buffers.clone());
+
+        let first = queue.pop(&[0xff]).unwrap();
+        // The piece already started can be finished, but the next one has to wait.
+        assert_eq!(queue.pop(&[0xff]).map(|b| b.piece), Some(0));
+        assert_eq!(queue.pop(&[0xff]), None);
+        assert_eq!(buffers.in_use(), BLOCK_SIZE * 2);
+
+        let received = queue.receive(first, &data[..BLOCK_SIZE]).unwrap();
+        assert!(matches!(received, Received::Pending));
+        let second = BlockRange {
+            begin: BLOCK_SIZE,
+            ..first
+        };
+        let piece = match queue.receive(second, &data[..BLOCK_SIZE]).unwrap() {
+            Received::Assembled(piece) => piece,
+            other => panic!("Expected assembled piece, got {:?}", other),
+        };
+        assert!(matches!(queue.verify(piece), Verified::Passed(_)));
+        assert_eq!(buffers.in_use(), 0);
+        assert_eq!(queue.pop(&[0xff]).map(|b| b.piece), Some(1));
+    }
+
+    /// Picks the last piece it can, to start new pieces while others are unfinished.
+    #[derive(Debug)]
+    struct Backwards;
+
+    impl PiecePicker for Backwards {
+        fn pick(&mut self, peer_bitfield: &[u8], in_flight: &InFlight) -> Option<BlockRange> {
+            (0..in_flight.piece_count())
+                .rev()
+                .filter(|&p| peer_has(peer_bitfield, p))
+                .find_map(|p| in_flight.next_block(p))
+        }
     }
 
-    pub async fn push(&self, msg: PieceOfWork) -> Result<(), SendError<PieceOfWork>> {
-        self.tx.send(msg).await
+    #[test]
+    fn finish_started_pieces_over_memory_budget() {
+        let data = vec![1; BLOCK_SIZE * 6];
+        let hashes = data
+            .chunks(BLOCK_SIZE * 2)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let verifier = Box::new(Sha1Pieces::new(hashes));
+        let buffers = BufferPool::new(BLOCK_SIZE * 2);
+        let queue = WorkQueue::new(verifier, BLOCK_SIZE * 2, data.len(), Box::new(Backwards))
+            .with_buffers(buffers.clone());
+
+        // The picker would rather start another piece, but there's only room for one,
+        // so the rest of the started piece is handed out instead.
+        assert_eq!(queue.pop(&[0xff]).map(|b| b.piece), Some(2));
+        assert_eq!(
+            queue.pop(&[0xff]),
+            Some(BlockRange {
+                piece: 2,
+                begin: BLOCK_SIZE,
+                length: BLOCK_SIZE
+            })
+        );
+        assert_eq!(queue.pop(&[0xff]), None);
+        // Peers which don't have the started piece have to wait.
+        assert_eq!(queue.pop(&[0b1100_0000]), None);
+        assert_eq!(buffers.in_use(), BLOCK_SIZE * 2);
+    }
+
+    #[test]
+    fn duplicate_blocks_only_in_endgame() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| i as u8).collect();
+        let queue = queue(&data, BLOCK_SIZE * 3);
+
+        let (a, b) = (peer(1), peer(2));
+
+        let first = queue.pop_for(a, &[0xff], None).unwrap();
+        assert_eq!(queue.pop_duplicate(b, &[0xff]), None);
+        let second = queue.pop_for(a, &[0xff], None).unwrap();
+        let third = queue.pop_for(b, &[0xff], None).unwrap();
+        queue.receive(first, &data[..BLOCK_SIZE]).unwrap();
+
+        // Every block is requested: blocks which haven't arrived go to one more peer.
+        assert_eq!(queue.pop_duplicate(a, &[0x00]), None);
+        assert_eq!(queue.pop_duplicate(b, &[0xff]), Some(second));
+        assert_eq!(queue.pop_duplicate(a, &[0xff]), Some(third));
+        assert_eq!(queue.pop_duplicate(b, &[0xff]), None);
+
+        let received = queue
+            .receive(second, &data[second.begin..second.begin + second.length])
+            .unwrap();
+        assert!(matches!(received, Received::Pending));
+        let piece = match queue.receive(third, &data[third.begin..]).unwrap() {
+            Received::Assembled(piece) => piece,
+            other => panic!("Expected assembled piece, got {:?}", other),
+        };
+        // The second copies arriving late change nothing, and aren't waited for.
+        let revocations = queue.revocations();
+        assert!(matches!(queue.verify(piece), Verified::Passed(_)));
+        assert_ne!(queue.revocations(), revocations);
+        assert!(!queue.is_assigned(a, &third));
+        let received = queue.receive(third, &data[third.begin..]).unwrap();
+        assert!(matches!(received, Received::Pending));
+        assert!(queue.is_finished());
+    }
+
+    #[test]
+    fn inspect_and_revoke_work() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 4).map(|i| i as u8).collect();
+        let queue = queue(&data, BLOCK_SIZE * 2);
+        let (a, b) = (peer(1), peer(2));
+
+        let first = queue.pop_for(a, &[0xff], None).unwrap();
+        let second = queue.pop_for(b, &[0xff], None).unwrap();
+        let third = queue.pop_for(b, &[0xff], None).unwrap();
+        queue.receive(first, &data[..BLOCK_SIZE]).unwrap();
+        queue.release(a, &first);
+        let status = queue.status();
+        assert_eq!(status.remaining, 2);
+        assert_eq!(
+            status.pieces,
+            vec![
+                PieceProgress {
+                    piece: 0,
+                    length: BLOCK_SIZE * 2,
+                    received: BLOCK_SIZE,
+                    requested: 2,
+                    blocks: 2,
+                },
+                PieceProgress {
+                    piece: 1,
+                    length: BLOCK_SIZE * 2,
+                    received: 0,
+                    requested: 1,
+                    blocks: 2,
+                }
+            ]
+        );
+        assert_eq!(status.assignments[&b], vec![second, third]);
+        assert!(!status.assignments.contains_key(&a));
+
+        // Revoked blocks are taken from the peer, and handed out afresh.
+        queue.revoke(1);
+        assert!(!queue.is_assigned(b, &third));
+        assert!(queue.is_assigned(b, &second));
+        assert_eq!(queue.status().pieces.len(), 1);
+        // Nor does the revoked block start the piece again if it arrives late.
+        let late = &data[third.piece * BLOCK_SIZE * 2 + third.begin..][..BLOCK_SIZE];
+        assert!(matches!(
+            queue.receive(third, late).unwrap(),
+            Received::Pending
+        ));
+        assert_eq!(queue.status().pieces.len(), 1);
+        assert_eq!(queue.pop_for(a, &[0xff], None), Some(third));
+
+        let piece = match queue.receive(second, &vec![0; BLOCK_SIZE]).unwrap() {
+            Received::Assembled(piece) => piece,
+            other => panic!("Expected assembled piece, got {:?}", other),
+        };
+        assert!(matches!(queue.verify(piece), Verified::Failed(0)));
+        assert_eq!(queue.status().failures, BTreeMap::from([(0, 1)]));
+    }
+
+    #[test]
+    fn change_the_wanted_pieces_mid_download() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| i as u8).collect();
+        let queue = queue(&data, BLOCK_SIZE);
+        let a = peer(1);
+        let mut changes = queue.selection_changes();
+        queue
+            .set_priorities(&[Priority::Normal, Priority::Skip, Priority::Skip])
+            .unwrap();
+        let first = queue.pop_for(a, &[0xff], None).unwrap();
+        assert_eq!(first.piece, 0);
+        assert!(queue.pop_for(a, &[0xff], None).is_none());
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(changes.borrow_and_update().wanted, 1);
+
+        // The piece being downloaded is taken back, and the others handed out instead.
+        let revocations = queue.revocations();
+        let selection = queue
+            .set_priorities(&[Priority::Skip, Priority::Normal, Priority::High])
+            .unwrap();
+        assert_ne!(queue.revocations(), revocations);
+        assert!(!queue.is_assigned(a, &first));
+        let mut pieces = vec![
+            queue.pop_for(a, &[0xff], None).unwrap().piece,
+            queue.pop_for(a, &[0xff], None).unwrap().piece,
+        ];
+        pieces.sort();
+        assert_eq!(pieces, vec![1, 2]);
+        assert!(queue.pop_for(a, &[0xff], None).is_none());
+        let expected = Selection {
+            wanted: 2,
+            done: 0,
+            left: BLOCK_SIZE as u64 * 2,
+        };
+        assert_eq!(selection, expected);
+        assert_eq!(*changes.borrow_and_update(), expected);
+
+        queue.mark_complete(1);
+        assert_eq!(queue.selection().progress(), 0.5);
+        assert!(queue.set_priorities(&[Priority::Normal]).is_err());
+    }
+
+    #[tokio::test]
+    async fn wake_idle_sessions_when_blocks_are_given_back() {
+        let data = vec![0; BLOCK_SIZE * 2];
+        let queue = queue(&data, BLOCK_SIZE * 2);
+        let mut work = queue.watch();
+        let first = queue.pop(&[0xff]).unwrap();
+        let second = queue.pop(&[0xff]).unwrap();
+
+        // Nothing's left for a session which finds the queue empty.
+        work.mark_seen();
+        assert!(queue.pop(&[0xff]).is_none());
+        let idle = time::timeout(Duration::from_millis(20), work.changed());
+        assert!(idle.await.is_err());
+
+        queue.push(first);
+        time::timeout(Duration::from_secs(5), work.changed())
+            .await
+            .unwrap();
+        work.mark_seen();
+        assert_eq!(queue.pop(&[0xff]), Some(first));
+
+        // Pieces which fail their checks are downloaded again.
+        queue.receive(second, &[1; BLOCK_SIZE]).unwrap();
+        let Received::Assembled(piece) = queue.receive(first, &[1; BLOCK_SIZE]).unwrap() else {
+            panic!("Expected assembled piece");
+        };
+        assert!(matches!(queue.verify(piece), Verified::Failed(_)));
+        time::timeout(Duration::from_secs(5), work.changed())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn wake_one_session_for_each_buffer_given_back() {
+        let data = vec![0; BLOCK_SIZE * 2];
+        let buffers = BufferPool::new(BLOCK_SIZE);
+        let queue = queue(&data, BLOCK_SIZE).with_buffers(buffers.clone());
+        let mut watches = [queue.watch(), queue.watch()];
+        for work in &mut watches {
+            work.mark_seen();
+        }
+
+        let [first, second] = &mut watches;
+        drop(buffers.take(BLOCK_SIZE));
+        let wait = Duration::from_millis(20);
+        assert!(time::timeout(wait, first.changed()).await.is_ok());
+        assert!(time::timeout(wait, second.changed()).await.is_err());
+    }
+
+    #[test]
+    fn bitfield_of_complete_pieces() {
+        let data = vec![1; BLOCK_SIZE * 9];
+        let queue = queue(&data, BLOCK_SIZE);
+
+        queue.mark_complete(0);
+        queue.mark_complete(8);
+        assert!(queue.has_piece(8));
+        assert!(!queue.has_piece(9));
+        assert_eq!(queue.bitfield(), vec![0b1000_0000, 0b1000_0000]);
+    }
+
+    #[test]
+    fn note_changes_to_the_pieces_we_want() {
+        let data = vec![1; BLOCK_SIZE * 3];
+        let queue = queue(&data, BLOCK_SIZE);
+        assert!(queue.wants_any(&[0b0100_0000]));
+
+        let changes = queue.wants_changes();
+        queue.mark_complete(1);
+        assert_ne!(queue.wants_changes(), changes);
+        assert!(!queue.wants_any(&[0b0100_0000]));
+        assert!(!queue.wants(1) && queue.wants(2) && !queue.wants(3));
+
+        let changes = queue.wants_changes();
+        queue.set_priority(2, Priority::Skip);
+        assert_ne!(queue.wants_changes(), changes);
+        assert!(!queue.wants_any(&[0b0110_0000]));
+        let changes = queue.wants_changes();
+        queue.mark_missing(1);
+        assert_ne!(queue.wants_changes(), changes);
+        assert!(queue.wants_any(&[0b0110_0000]));
+    }
+
+    #[test]
+    fn left_counts_pieces_we_dont_have() {
+        let data = vec![1; BLOCK_SIZE * 2 + 10];
+        let queue = queue(&data, BLOCK_SIZE);
+        assert_eq!(queue.left(), data.len() as u64);
+
+        queue.mark_complete(2);
+        assert_eq!(queue.left(), BLOCK_SIZE as u64 * 2);
+        queue.set_priority(0, Priority::Skip);
+        assert_eq!(queue.left(), BLOCK_SIZE as u64 * 2);
+    }
+
+    fn piece(idx: usize) -> WorkResult {
+        WorkResult {
+            idx,
+            bytes: vec![idx as u8].into(),
+        }
+    }
+
+    #[test]
+    fn deliver_pieces_in_order() {
+        let mut in_order = InOrder::new(4);
+
+        assert!(in_order.push(piece(2)).unwrap().is_empty());
+        assert!(in_order.push(piece(1)).unwrap().is_empty());
+        assert_eq!(in_order.buffered(), 2);
+
+        let ready: Vec<_> = in_order
+            .push(piece(0))
+            .unwrap()
+            .iter()
+            .map(|r| r.idx)
+            .collect();
+        assert_eq!(ready, vec![0, 1, 2]);
+        assert_eq!(in_order.buffered(), 0);
+
+        // Duplicates of delivered pieces are dropped.
+        assert!(in_order.push(piece(1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fail_when_too_many_pieces_are_buffered() {
+        let mut in_order = InOrder::new(1);
+
+        in_order.push(piece(1)).unwrap();
+        assert!(in_order.push(piece(2)).is_err());
     }
 }
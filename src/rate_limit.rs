@@ -0,0 +1,298 @@
+//! Capping how fast torrents transfer data.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::{self, Duration, Instant};
+
+/// How long a torrent counts towards splitting a shared cap after it last transferred.
+const ACTIVE_FOR: Duration = Duration::from_secs(5);
+
+/// A token bucket which lets through `rate` bytes a second on average, in bursts of up
+/// to a second's worth.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes which can be sent without waiting. Negative when callers are waiting for
+    /// bytes they've already reserved.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Wait until `bytes` more bytes can be transferred without going over the rate.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long the caller must wait for them.
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        self.bucket.lock().unwrap().reserve(bytes, now, self.rate)
+    }
+}
+
+impl Bucket {
+    /// Take `bytes`, refilling at `rate` bytes a second since the last caller, returning
+    /// how long the caller must wait for them.
+    fn reserve(&mut self, bytes: usize, now: Instant, rate: u64) -> Duration {
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// A cap shared between torrents by weight. Each torrent which transferred lately gets
+/// a part of the rate in proportion to its weight, so one busy torrent can't take it
+/// all while others wait.
+#[derive(Debug)]
+pub struct SharedLimiter {
+    rate: u64,
+    /// Each share's weight, and when it last transferred.
+    shares: Mutex<HashMap<u64, (u64, Option<Instant>)>>,
+    next_id: AtomicU64,
+}
+
+impl SharedLimiter {
+    pub fn new(rate: u64) -> Arc<Self> {
+        Arc::new(Self {
+            rate: rate.max(1),
+            shares: Default::default(),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// A share of the cap, such as a torrent's, taking part of it in proportion to
+    /// `weight`. It's released when dropped.
+    pub fn share(self: &Arc<Self>, weight: u32) -> RateShare {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let weight = u64::from(weight.max(1));
+        self.shares.lock().unwrap().insert(id, (weight, None));
+
+        RateShare {
+            limiter: Arc::clone(self),
+            id,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// The part of the rate share `id` gets, transferring `now`, among the shares which
+    /// transferred lately.
+    fn rate_for(&self, id: u64, now: Instant) -> u64 {
+        let mut shares = self.shares.lock().unwrap();
+        let weight = match shares.get_mut(&id) {
+            Some((weight, last)) => {
+                *last = Some(now);
+                *weight
+            }
+            None => return self.rate,
+        };
+        let active: u64 = shares
+            .values()
+            .filter(|(_, last)| {
+                last.is_some_and(|last| now.saturating_duration_since(last) < ACTIVE_FOR)
+            })
+            .map(|(weight, _)| weight)
+            .sum();
+
+        (self.rate.saturating_mul(weight) / active.max(weight)).max(1)
+    }
+}
+
+/// One torrent's part of a [`SharedLimiter`].
+#[derive(Debug)]
+pub struct RateShare {
+    limiter: Arc<SharedLimiter>,
+    id: u64,
+    bucket: Mutex<Bucket>,
+}
+
+impl Drop for RateShare {
+    fn drop(&mut self) {
+        self.limiter.shares.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl RateShare {
+    /// The whole cap, however it's split.
+    #[cfg(test)]
+    pub fn rate(&self) -> u64 {
+        self.limiter.rate
+    }
+
+    /// Wait until `bytes` more bytes can be transferred without going over this share's
+    /// part of the cap.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let rate = self.limiter.rate_for(self.id, now);
+        self.bucket.lock().unwrap().reserve(bytes, now, rate)
+    }
+}
+
+/// A torrent's download and upload caps, shared by all of its peer sessions.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub download: Option<Arc<RateLimiter>>,
+    pub upload: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimits {
+    /// Limits of `download` and `upload` bytes a second, or unlimited for `None`.
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: download.map(|rate| Arc::new(RateLimiter::new(rate))),
+            upload: upload.map(|rate| Arc::new(RateLimiter::new(rate))),
+        }
+    }
+}
+
+/// Bytes a second the alternate speeds cap transfers at, unless they're configured.
+pub const DEFAULT_ALT_SPEED: u64 = 50 * 1024;
+
+/// Caps on the whole client's transfer rates, which apply on top of each torrent's own
+/// while they're switched on, like Transmission's turtle mode. Torrents split the caps
+/// by weight through shares of them.
+#[derive(Debug, Clone)]
+pub struct AltSpeed {
+    enabled: Arc<AtomicBool>,
+    download: Arc<RateShare>,
+    upload: Arc<RateShare>,
+}
+
+impl AltSpeed {
+    /// Caps of `download` and `upload` bytes a second, switched off to begin with.
+    pub fn new(download: u64, upload: u64) -> Self {
+        Self {
+            enabled: Arc::new(AtomicBool::new(false)),
+            download: Arc::new(SharedLimiter::new(download).share(1)),
+            upload: Arc::new(SharedLimiter::new(upload).share(1)),
+        }
+    }
+
+    /// The same caps, switched on and off together, taking a part of them of its own
+    /// with `weight`.
+    pub fn share(&self, weight: u32) -> Self {
+        Self {
+            enabled: Arc::clone(&self.enabled),
+            download: Arc::new(self.download.limiter.share(weight)),
+            upload: Arc::new(self.upload.limiter.share(weight)),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// The download cap, while the caps are switched on.
+    pub fn download(&self) -> Option<&RateShare> {
+        self.is_enabled().then_some(&*self.download)
+    }
+
+    /// The upload cap, while the caps are switched on.
+    pub fn upload(&self) -> Option<&RateShare> {
+        self.is_enabled().then_some(&*self.upload)
+    }
+}
+
+impl Default for AltSpeed {
+    fn default() -> Self {
+        Self::new(DEFAULT_ALT_SPEED, DEFAULT_ALT_SPEED)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wait_once_burst_is_used_up() {
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+
+        assert_eq!(limiter.reserve(1000, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(500, start), Duration::from_millis(500));
+        // The next caller queues behind the bytes already reserved.
+        assert_eq!(limiter.reserve(500, start), Duration::from_secs(1));
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(1000, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn split_shared_caps_by_weight() {
+        let limiter = SharedLimiter::new(1000);
+        let heavy = limiter.share(3);
+        let light = limiter.share(1);
+        let start = Instant::now() + Duration::from_secs(10);
+
+        // Alone, a share has the whole rate.
+        assert_eq!(light.reserve(0, start), Duration::ZERO);
+        // Together, they split it three to one.
+        assert_eq!(heavy.reserve(750, start), Duration::ZERO);
+        assert_eq!(heavy.reserve(750, start), Duration::from_secs(1));
+        assert_eq!(light.reserve(250, start), Duration::ZERO);
+        assert_eq!(light.reserve(250, start), Duration::from_secs(1));
+
+        // Shares which stopped transferring leave their part to the others.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(heavy.reserve(1000, later), Duration::ZERO);
+        assert_eq!(heavy.reserve(1000, later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn alt_speed_only_caps_while_enabled() {
+        let alt_speed = AltSpeed::new(1000, 2000);
+        let shared = alt_speed.clone();
+        assert!(shared.download().is_none());
+
+        alt_speed.set_enabled(true);
+        assert_eq!(shared.download().unwrap().rate(), 1000);
+        assert_eq!(shared.upload().unwrap().rate(), 2000);
+
+        alt_speed.set_enabled(false);
+        assert!(shared.upload().is_none());
+    }
+}
@@ -0,0 +1,491 @@
+//! A small control protocol for talking to a running client: one JSON request per line,
+//! answered by one JSON response per line. If the server has a token, connections must
+//! send it in an `auth` request first.
+
+use crate::client::{Client, TorrentHandle};
+use crate::magnet::hex;
+use crate::options::AddTorrentOptions;
+use crate::picker::Priority;
+use crate::queues::Selection;
+use crate::stats::TorrentStatus;
+use crate::storage::FileProgress;
+use crate::verify::Verification;
+use anyhow::anyhow;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{debug, warn};
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:6880";
+/// The longest request line we'll read, so a connection can't make us buffer without end.
+const MAX_REQUEST_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Request {
+    /// Give the server's token, which must come before any other request if it has one.
+    Auth {
+        token: String,
+    },
+    /// Status of all torrents, or those whose info hash starts with the given prefix,
+    /// limited to a category if one is given.
+    Status {
+        info_hash: Option<String>,
+        #[serde(default)]
+        category: Option<String>,
+    },
+    /// Add a torrent from a path on the client's machine, a URL, a magnet link or an
+    /// info hash.
+    Add {
+        source: String,
+        #[serde(default)]
+        options: AddTorrentOptions,
+    },
+    /// Announce to the trackers of the torrent whose info hash starts with the prefix,
+    /// without waiting for the interval.
+    Reannounce {
+        info_hash: String,
+    },
+    /// Stop and forget the torrent whose info hash starts with the prefix, deleting its
+    /// content if `delete_data` is set.
+    Remove {
+        info_hash: String,
+        #[serde(default)]
+        delete_data: bool,
+    },
+    /// Hash the content of the torrent whose info hash starts with the prefix again.
+    Recheck {
+        info_hash: String,
+    },
+    /// Stop the recheck running for the torrent whose info hash starts with the prefix.
+    CancelCheck {
+        info_hash: String,
+    },
+    /// End the session with a peer of the torrent whose info hash starts with the prefix.
+    DisconnectPeer {
+        info_hash: String,
+        peer: SocketAddr,
+    },
+    /// Disconnect from and refuse a peer's IP for `seconds`.
+    BanPeer {
+        info_hash: String,
+        peer: IpAddr,
+        seconds: u64,
+    },
+    /// Show the torrent whose info hash starts with the prefix by a name of its own, or
+    /// by its own name again.
+    SetName {
+        info_hash: String,
+        name: Option<String>,
+    },
+    /// Move the content of the torrent whose info hash starts with the prefix to another
+    /// directory on the client's machine, while it runs.
+    MoveStorage {
+        info_hash: String,
+        save_path: PathBuf,
+    },
+    /// Put the torrent whose info hash starts with the prefix in a category, or in none.
+    SetCategory {
+        info_hash: String,
+        category: Option<String>,
+    },
+    /// Change which files of the torrent whose info hash starts with the prefix are
+    /// downloaded, giving their priorities in order.
+    SetFilePriorities {
+        info_hash: String,
+        priorities: Vec<Priority>,
+    },
+    /// How much of each file of the torrent whose info hash starts with the prefix has
+    /// been saved.
+    FileProgress {
+        info_hash: String,
+    },
+    PauseAll,
+    ResumeAll,
+    /// Switch the alternate speeds on or off, or toggle them if `enabled` isn't given.
+    SetAltSpeed {
+        #[serde(default)]
+        enabled: Option<bool>,
+    },
+    /// Answer with a `file_completed` response whenever a file of a torrent whose info
+    /// hash starts with the prefix, or of any torrent, is saved in full, until the
+    /// connection is closed. Nothing else can be asked on the connection after this.
+    WatchFiles {
+        #[serde(default)]
+        info_hash: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Status(Vec<TorrentStatus>),
+    Added(TorrentStatus),
+    Reannounced,
+    Removed,
+    Rechecked(Verification),
+    CheckCancelled,
+    Disconnected,
+    Banned,
+    NameSet,
+    StorageMoved,
+    CategorySet,
+    /// The pieces now wanted.
+    FilePrioritiesSet(Selection),
+    FileProgress(Vec<FileProgress>),
+    PausedAll,
+    ResumedAll,
+    /// Whether the alternate speeds are now on.
+    AltSpeed(bool),
+    /// A file was saved in full, see [`Request::WatchFiles`].
+    FileCompleted {
+        info_hash: String,
+        /// The file's index in the torrent's `file_progress`.
+        index: usize,
+        path: PathBuf,
+    },
+    Authenticated,
+    Error(String),
+}
+
+pub async fn handle(client: &Client, request: Request) -> Response {
+    match request {
+        // Connections are authenticated before anything is handled.
+        Request::Auth { .. } => Response::Authenticated,
+        Request::Status {
+            info_hash,
+            category,
+        } => {
+            let mut torrents = match info_hash {
+                Some(prefix) => client.find(&prefix),
+                None => client.torrents(),
+            };
+            if let Some(category) = category {
+                torrents.retain(|t| t.category().as_deref() == Some(&*category));
+            }
+            Response::Status(torrents.iter().map(|t| t.status()).collect())
+        }
+        Request::Add { source, options } => match add(client, &source, options).await {
+            Ok(handle) => Response::Added(handle.status()),
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::Reannounce { info_hash } => match find_one(client, &info_hash) {
+            Ok(handle) => {
+                handle.reannounce();
+                Response::Reannounced
+            }
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::Remove {
+            info_hash,
+            delete_data,
+        } => match remove(client, &info_hash, delete_data).await {
+            Ok(()) => Response::Removed,
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::Recheck { info_hash } => match recheck(client, &info_hash).await {
+            Ok(verification) => Response::Rechecked(verification),
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::CancelCheck { info_hash } => {
+            match find_one(client, &info_hash).and_then(|handle| handle.cancel_check()) {
+                Ok(()) => Response::CheckCancelled,
+                Err(e) => Response::Error(e.to_string()),
+            }
+        }
+        Request::DisconnectPeer { info_hash, peer } => {
+            match find_one(client, &info_hash).and_then(|handle| handle.disconnect_peer(peer)) {
+                Ok(()) => Response::Disconnected,
+                Err(e) => Response::Error(e.to_string()),
+            }
+        }
+        Request::BanPeer {
+            info_hash,
+            peer,
+            seconds,
+        } => match find_one(client, &info_hash) {
+            Ok(handle) => {
+                handle.ban_peer(peer, Duration::from_secs(seconds));
+                Response::Banned
+            }
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::SetName { info_hash, name } => {
+            match find_one(client, &info_hash).and_then(|handle| handle.set_name(name)) {
+                Ok(()) => Response::NameSet,
+                Err(e) => Response::Error(e.to_string()),
+            }
+        }
+        Request::MoveStorage {
+            info_hash,
+            save_path,
+        } => match move_storage(client, &info_hash, &save_path).await {
+            Ok(()) => Response::StorageMoved,
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::SetCategory {
+            info_hash,
+            category,
+        } => match find_one(client, &info_hash).and_then(|handle| handle.set_category(category)) {
+            Ok(()) => Response::CategorySet,
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::SetFilePriorities {
+            info_hash,
+            priorities,
+        } => match find_one(client, &info_hash)
+            .and_then(|handle| handle.set_file_priorities(priorities))
+        {
+            Ok(selection) => Response::FilePrioritiesSet(selection),
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::FileProgress { info_hash } => match find_one(client, &info_hash) {
+            Ok(handle) => Response::FileProgress(handle.file_progress()),
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::PauseAll => match client.pause_all() {
+            Ok(()) => Response::PausedAll,
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::ResumeAll => match client.resume_all() {
+            Ok(()) => Response::ResumedAll,
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::SetAltSpeed { enabled } => {
+            client.set_alt_speed(enabled.unwrap_or(!client.alt_speed()));
+            Response::AltSpeed(client.alt_speed())
+        }
+        Request::WatchFiles { .. } => {
+            Response::Error(String::from("Files can only be watched over a connection"))
+        }
+    }
+}
+
+/// Send a [`Response::FileCompleted`] for each file saved in full of the torrents whose
+/// info hash starts with `prefix`, until the connection is closed.
+async fn watch_files(
+    client: &Client,
+    prefix: &str,
+    lines: &mut Framed<TcpStream, LinesCodec>,
+) -> anyhow::Result<()> {
+    let prefix = prefix.to_lowercase();
+    let mut completions = client.file_completions();
+    loop {
+        let completed = tokio::select! {
+            completed = completions.recv() => completed,
+            // Anything more the connection sends is ignored, until it closes.
+            line = lines.next() => match line {
+                Some(line) => {
+                    line?;
+                    continue;
+                }
+                None => return Ok(()),
+            },
+        };
+        let completed = match completed {
+            Ok(completed) => completed,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Control connection missed {} completed files", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let info_hash = hex(&completed.info_hash);
+        if !info_hash.starts_with(&prefix) {
+            continue;
+        }
+        let response = Response::FileCompleted {
+            info_hash,
+            index: completed.index,
+            path: completed.path,
+        };
+        lines.send(serde_json::to_string(&response)?).await?;
+    }
+}
+
+/// The one torrent whose info hash starts with `prefix`.
+fn find_one(client: &Client, prefix: &str) -> anyhow::Result<TorrentHandle> {
+    let mut torrents = client.find(prefix);
+    match torrents.len() {
+        0 => Err(anyhow!("No torrent matches {}", prefix)),
+        1 => Ok(torrents.remove(0)),
+        n => Err(anyhow!("{} torrents match {}", n, prefix)),
+    }
+}
+
+async fn recheck(client: &Client, prefix: &str) -> anyhow::Result<Verification> {
+    find_one(client, prefix)?.recheck().await
+}
+
+async fn move_storage(client: &Client, prefix: &str, save_path: &Path) -> anyhow::Result<()> {
+    find_one(client, prefix)?.move_storage(save_path).await
+}
+
+async fn remove(client: &Client, prefix: &str, delete_data: bool) -> anyhow::Result<()> {
+    let handle = find_one(client, prefix)?;
+    client
+        .remove_torrent(&handle.info_hash(), delete_data)
+        .await
+}
+
+async fn add(
+    client: &Client,
+    source: &str,
+    options: AddTorrentOptions,
+) -> anyhow::Result<TorrentHandle> {
+    client.add(&source.parse()?, options).await
+}
+
+/// Whether `a` and `b` are equal, taking the same time whichever bytes differ, so
+/// secrets can't be guessed a byte at a time.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let longest = a.len().max(b.len());
+    let mut diff = a.len() ^ b.len();
+    for i in 0..longest {
+        diff |= (a.get(i).copied().unwrap_or(0) ^ b.get(i).copied().unwrap_or(0)) as usize;
+    }
+    diff == 0
+}
+
+/// Accept control connections on `addr` until the listener fails. If there's a `token`,
+/// connections must give it before anything else.
+pub async fn serve(client: Client, addr: SocketAddr, token: Option<String>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    debug!("Listening for control connections on {}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let client = client.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(client, stream, token).await {
+                warn!("Control connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(
+    client: Client,
+    stream: TcpStream,
+    token: Option<String>,
+) -> anyhow::Result<()> {
+    let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(MAX_REQUEST_LENGTH));
+
+    if let Some(expected) = token {
+        let line = match lines.next().await {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        match serde_json::from_str(&line) {
+            Ok(Request::Auth { token })
+                if constant_time_eq(token.as_bytes(), expected.as_bytes()) =>
+            {
+                lines
+                    .send(serde_json::to_string(&Response::Authenticated)?)
+                    .await?;
+            }
+            _ => {
+                let response = Response::Error(String::from("Not authenticated"));
+                lines.send(serde_json::to_string(&response)?).await?;
+                return Err(anyhow!("Connection didn't give the token"));
+            }
+        }
+    }
+
+    while let Some(line) = lines.next().await {
+        let response = match serde_json::from_str(&line?) {
+            Ok(Request::WatchFiles { info_hash }) => {
+                let prefix = info_hash.unwrap_or_default();
+                return watch_files(&client, &prefix, &mut lines).await;
+            }
+            Ok(request) => handle(&client, request).await,
+            Err(e) => Response::Error(format!("Invalid request: {}", e)),
+        };
+        lines.send(serde_json::to_string(&response)?).await?;
+    }
+
+    Ok(())
+}
+
+/// Send a single request to the client listening on `addr`, giving it `token` first if
+/// there is one.
+pub async fn call(
+    addr: SocketAddr,
+    token: Option<&str>,
+    request: &Request,
+) -> anyhow::Result<Response> {
+    let mut lines = connect(addr, token).await?;
+    exchange(&mut lines, request).await
+}
+
+/// Send `request` to the client listening on `addr`, and call `on_response` with each
+/// response until the client hangs up or `on_response` fails, as for
+/// [`Request::WatchFiles`].
+pub async fn watch(
+    addr: SocketAddr,
+    token: Option<&str>,
+    request: &Request,
+    mut on_response: impl FnMut(Response) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut lines = connect(addr, token).await?;
+    lines.send(serde_json::to_string(request)?).await?;
+    while let Some(line) = lines.next().await {
+        on_response(serde_json::from_str(&line?)?)?;
+    }
+
+    Ok(())
+}
+
+async fn connect(
+    addr: SocketAddr,
+    token: Option<&str>,
+) -> anyhow::Result<Framed<TcpStream, LinesCodec>> {
+    let stream = TcpStream::connect(addr).await?;
+    let mut lines = Framed::new(stream, LinesCodec::new());
+
+    if let Some(token) = token {
+        let auth = Request::Auth {
+            token: token.to_owned(),
+        };
+        match exchange(&mut lines, &auth).await? {
+            Response::Authenticated => {}
+            Response::Error(e) => return Err(anyhow!(e)),
+            response => return Err(anyhow!("Unexpected response: {:?}", response)),
+        }
+    }
+
+    Ok(lines)
+}
+
+async fn exchange(
+    lines: &mut Framed<TcpStream, LinesCodec>,
+    request: &Request,
+) -> anyhow::Result<Response> {
+    lines.send(serde_json::to_string(request)?).await?;
+    let line = lines
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("Connection closed before response"))??;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compare_secrets() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cret2"));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,402 @@
+//! Watching RSS feeds, such as those of trackers and indexers, and adding the torrents
+//! whose items match rules, filed under the rule's category. This is how new episodes
+//! of a series get downloaded without anyone adding them.
+
+use crate::client::Client;
+use crate::fetch::TorrentSource;
+use crate::options::AddTorrentOptions;
+use anyhow::anyhow;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use regex::{Regex, RegexBuilder};
+use reqwest::{redirect, Url};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::time::{self, Duration};
+use tracing::{debug, info, warn};
+
+const MAX_FEED_SIZE: usize = 5 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Feeds to watch and the rules for what to add from them.
+#[derive(Debug, Clone)]
+pub struct RssConfig {
+    pub feeds: Vec<Url>,
+    pub rules: Vec<FeedRule>,
+    /// How often to fetch each feed.
+    pub interval: Duration,
+}
+
+/// An item in a feed, which usually links to a torrent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeedItem {
+    pub title: String,
+    pub guid: Option<String>,
+    pub link: Option<String>,
+    /// URL of the enclosure, which is the .torrent file or magnet link in most feeds.
+    pub enclosure: Option<String>,
+    /// Size of the torrent's content, if the feed gives it.
+    pub size: Option<u64>,
+}
+
+impl FeedItem {
+    /// What identifies the item within its feed.
+    pub fn id(&self) -> &str {
+        self.guid
+            .as_deref()
+            .or(self.enclosure.as_deref())
+            .or(self.link.as_deref())
+            .unwrap_or(&self.title)
+    }
+
+    /// The torrent the item links to: its enclosure, or else its link, as long as that's
+    /// a URL or magnet link.
+    pub fn source(&self) -> Option<TorrentSource> {
+        [&self.enclosure, &self.link]
+            .into_iter()
+            .flatten()
+            .filter_map(|link| link.parse().ok())
+            .find(|source| !matches!(source, TorrentSource::File(_)))
+    }
+}
+
+/// Which items of feeds to add, and how. Parsed from comma separated settings, such as
+/// `match=Some Show S\d+E\d+,exclude=720p,max-size=2048,category=tv`. Patterns are
+/// regular expressions, matched anywhere in the item's title ignoring case. Sizes are in
+/// MiB, and items whose feed doesn't give a size aren't held to them.
+#[derive(Debug, Clone)]
+pub struct FeedRule {
+    pub pattern: Regex,
+    /// Items whose titles match this aren't added, even if they match the pattern.
+    pub exclude: Option<Regex>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub category: Option<String>,
+    pub save_path: Option<PathBuf>,
+}
+
+impl FeedRule {
+    pub fn matches(&self, item: &FeedItem) -> bool {
+        self.pattern.is_match(&item.title)
+            && !self
+                .exclude
+                .as_ref()
+                .is_some_and(|e| e.is_match(&item.title))
+            && item.size.is_none_or(|size| {
+                self.min_size.is_none_or(|min| size >= min)
+                    && self.max_size.is_none_or(|max| size <= max)
+            })
+    }
+
+    /// Options for adding the torrent of an item the rule matches.
+    pub fn options(&self) -> AddTorrentOptions {
+        AddTorrentOptions {
+            category: self.category.clone(),
+            save_path: self.save_path.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+impl FromStr for FeedRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const KEYS: &[&str] = &[
+            "match",
+            "exclude",
+            "min-size",
+            "max-size",
+            "category",
+            "save-path",
+        ];
+        // Commas which don't start a setting belong to the last one's value, so patterns
+        // such as `S\d{1,2}` survive the split.
+        let mut settings: Vec<(&str, String)> = Vec::new();
+        for part in s.split(',') {
+            match part.split_once('=') {
+                Some((key, value)) if KEYS.contains(&key.trim()) => {
+                    settings.push((key.trim(), value.to_owned()))
+                }
+                _ => match settings.last_mut() {
+                    Some((_, value)) => {
+                        value.push(',');
+                        value.push_str(part);
+                    }
+                    None => return Err(anyhow!("Expected key=value in feed rule, got {}", part)),
+                },
+            }
+        }
+
+        let regex = |pattern: &str| RegexBuilder::new(pattern).case_insensitive(true).build();
+        let mib = |value: &str| -> anyhow::Result<u64> {
+            let mib: u64 = value.trim().parse()?;
+            mib.checked_mul(1024 * 1024)
+                .ok_or_else(|| anyhow!("Feed rule size {} MiB is too large", mib))
+        };
+        let (mut pattern, mut exclude, mut min_size, mut max_size) = (None, None, None, None);
+        let (mut category, mut save_path) = (None, None);
+        for (key, value) in settings {
+            match key {
+                "match" => pattern = Some(regex(&value)?),
+                "exclude" => exclude = Some(regex(&value)?),
+                "min-size" => min_size = Some(mib(&value)?),
+                "max-size" => max_size = Some(mib(&value)?),
+                "category" => category = Some(value.trim().to_owned()),
+                "save-path" => save_path = Some(PathBuf::from(value.trim())),
+                _ => unreachable!(),
+            }
+        }
+
+        Ok(FeedRule {
+            pattern: pattern.ok_or_else(|| anyhow!("Feed rule needs a match pattern"))?,
+            exclude,
+            min_size,
+            max_size,
+            category,
+            save_path,
+        })
+    }
+}
+
+/// Fetch the feeds every interval, adding the torrents of new items which match a rule.
+/// Items whose torrents couldn't be added are tried again next time.
+pub async fn watch(client: &Client, config: &RssConfig) -> anyhow::Result<()> {
+    if config.interval.is_zero() {
+        return Err(anyhow!("Feeds can't be checked continuously"));
+    }
+    let mut added = HashSet::new();
+    let mut interval = time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        for feed in &config.feeds {
+            match poll(client, feed, &config.rules, &mut added).await {
+                Ok(0) => debug!("Nothing new in {}", feed),
+                Ok(count) => info!("Added {} torrents from {}", count, feed),
+                Err(e) => warn!("Couldn't check feed {}: {}", feed, e),
+            }
+        }
+    }
+}
+
+/// Fetch a feed once and add the torrents of items which match a rule and haven't been
+/// added before. Added items are remembered in `added`, and in the client's session
+/// store if it has one. Returns how many torrents were added.
+pub async fn poll(
+    client: &Client,
+    feed: &Url,
+    rules: &[FeedRule],
+    added: &mut HashSet<(String, String)>,
+) -> anyhow::Result<usize> {
+    let items = parse_feed(&fetch_feed(feed, client.config().bind_address).await?)?;
+    let store = client.session_store();
+
+    let mut count = 0;
+    for item in &items {
+        let key = (feed.to_string(), item.id().to_owned());
+        let seen = added.contains(&key)
+            || match store {
+                Some(store) => store.has_feed_item(&key.0, &key.1)?,
+                None => false,
+            };
+        if seen {
+            continue;
+        }
+        let rule = match rules.iter().find(|rule| rule.matches(item)) {
+            Some(rule) => rule,
+            None => continue,
+        };
+        let source = match item.source() {
+            Some(source) => source,
+            None => {
+                debug!("{} has no torrent link", item.title);
+                continue;
+            }
+        };
+
+        match client.add(&source, rule.options()).await {
+            Ok(handle) => {
+                info!("Added {} from {}", handle.name(), feed);
+                count += 1;
+            }
+            Err(e) => {
+                warn!("Couldn't add {}: {}", item.title, e);
+                continue;
+            }
+        }
+        if let Some(store) = store {
+            store.add_feed_item(&key.0, &key.1)?;
+        }
+        added.insert(key);
+    }
+
+    Ok(count)
+}
+
+async fn fetch_feed(url: &Url, bind_address: Option<std::net::IpAddr>) -> anyhow::Result<String> {
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(redirect::Policy::limited(MAX_REDIRECTS))
+        .local_address(bind_address)
+        .build()?;
+    let mut response = client.get(url.clone()).send().await?.error_for_status()?;
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if bytes.len() + chunk.len() > MAX_FEED_SIZE {
+            return Err(anyhow!("Feed is too large"));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// The items of an RSS feed, including the sizes Torznab feeds give as attributes.
+pub fn parse_feed(xml: &str) -> anyhow::Result<Vec<FeedItem>> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut items = Vec::new();
+    let mut item: Option<FeedItem> = None;
+    let mut field: Option<Vec<u8>> = None;
+    loop {
+        let text = match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"item" => {
+                item = Some(FeedItem::default());
+                continue;
+            }
+            Event::Start(e) => {
+                if let Some(item) = &mut item {
+                    read_attributes(item, &e)?;
+                }
+                field = Some(e.local_name().as_ref().to_vec());
+                continue;
+            }
+            Event::Empty(e) => {
+                if let Some(item) = &mut item {
+                    read_attributes(item, &e)?;
+                }
+                continue;
+            }
+            Event::End(e) => {
+                if e.local_name().as_ref() == b"item" {
+                    items.extend(item.take());
+                }
+                field = None;
+                continue;
+            }
+            Event::Text(t) => t.unescape()?.into_owned(),
+            Event::CData(c) => String::from_utf8_lossy(&c.into_inner()).into_owned(),
+            Event::Eof => break,
+            _ => continue,
+        };
+
+        let (item, field) = match (&mut item, &field) {
+            (Some(item), Some(field)) => (item, field),
+            _ => continue,
+        };
+        match field.as_slice() {
+            b"title" => item.title = text,
+            b"guid" => item.guid = Some(text),
+            b"link" => item.link = Some(text),
+            b"size" | b"contentLength" => item.size = text.trim().parse().ok().or(item.size),
+            _ => {}
+        }
+    }
+
+    Ok(items)
+}
+
+/// Read the enclosure, and Torznab's size attribute, from an element within an item.
+fn read_attributes(item: &mut FeedItem, e: &BytesStart) -> anyhow::Result<()> {
+    let attribute = |name: &str| -> anyhow::Result<Option<String>> {
+        Ok(match e.try_get_attribute(name)? {
+            Some(attribute) => Some(attribute.unescape_value()?.into_owned()),
+            None => None,
+        })
+    };
+    match e.local_name().as_ref() {
+        b"enclosure" => {
+            item.enclosure = attribute("url")?;
+            if let Some(length) = attribute("length")?.and_then(|l| l.parse().ok()) {
+                item.size = Some(length);
+            }
+        }
+        b"attr" if attribute("name")?.as_deref() == Some("size") => {
+            item.size = attribute("value")?.and_then(|v| v.parse().ok());
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:torznab="http://torznab.com/schemas/2015/feed">
+  <channel>
+    <title>Indexer</title>
+    <link>https://indexer.example/</link>
+    <item>
+      <title><![CDATA[Some Show S02E05 1080p]]></title>
+      <guid>https://indexer.example/details/1</guid>
+      <link>https://indexer.example/details/1</link>
+      <enclosure url="https://indexer.example/download/1.torrent?key=a&amp;b=c" length="1500000000" type="application/x-bittorrent"/>
+    </item>
+    <item>
+      <title>Some Show S02E05 720p</title>
+      <link>magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a</link>
+      <torznab:attr name="size" value="700000000"/>
+    </item>
+  </channel>
+</rss>"#;
+
+    #[test]
+    fn parse_rss_and_torznab_items() {
+        let items = parse_feed(FEED).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].title, "Some Show S02E05 1080p");
+        assert_eq!(
+            items[0].enclosure.as_deref(),
+            Some("https://indexer.example/download/1.torrent?key=a&b=c")
+        );
+        assert_eq!(items[0].size, Some(1_500_000_000));
+        assert!(matches!(items[0].source(), Some(TorrentSource::Url(_))));
+
+        assert_eq!(items[1].size, Some(700_000_000));
+        assert!(matches!(items[1].source(), Some(TorrentSource::Magnet(_))));
+        assert_eq!(items[1].id(), items[1].link.as_deref().unwrap());
+    }
+
+    #[test]
+    fn match_items_by_title_and_size() {
+        let rule: FeedRule = r"match=some show s\d{1,2}e\d+,exclude=720p,max-size=2048,category=tv"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.pattern.as_str(), r"some show s\d{1,2}e\d+");
+        assert_eq!(rule.options().category.as_deref(), Some("tv"));
+
+        let item = |title: &str, size| FeedItem {
+            title: title.to_owned(),
+            size,
+            ..Default::default()
+        };
+        assert!(rule.matches(&item("Some Show S02E05 1080p", Some(1_500_000_000))));
+        assert!(rule.matches(&item("Some Show S02E05 1080p", None)));
+        assert!(!rule.matches(&item("Some Show S02E05 720p", None)));
+        assert!(!rule.matches(&item("Some Show S02E05 2160p", Some(8_000_000_000))));
+        assert!(!rule.matches(&item("Other Show S01E01", None)));
+
+        assert!("category=tv".parse::<FeedRule>().is_err());
+        assert!("colour=blue".parse::<FeedRule>().is_err());
+        assert!("match=a,min-size=18446744073709551615"
+            .parse::<FeedRule>()
+            .is_err());
+    }
+}
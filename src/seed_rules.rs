@@ -0,0 +1,169 @@
+//! Rules for how long torrents seed, chosen by the trackers they announce to, and what
+//! happens to them once they have. Private trackers each ask for a ratio or a seeding
+//! time, so torrents from each are seeded to suit it.
+
+use anyhow::anyhow;
+use std::str::FromStr;
+use std::time::Duration;
+use url::Url;
+
+/// What happens to a torrent once it has seeded for long enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeedAction {
+    /// Stop seeding, leaving the torrent in the client as complete.
+    #[default]
+    Pause,
+    /// Remove the torrent from the client, leaving its content on disk.
+    Remove,
+    /// Remove the torrent and delete its content.
+    RemoveData,
+}
+
+impl FromStr for SeedAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pause" => Ok(Self::Pause),
+            "remove" => Ok(Self::Remove),
+            "remove-data" => Ok(Self::RemoveData),
+            _ => Err(anyhow!("Unknown seed action: {}", s)),
+        }
+    }
+}
+
+/// How long a torrent seeds, and what happens to it then.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SeedGoal {
+    /// Stop once we've uploaded this many bytes in total.
+    pub uploaded: Option<u64>,
+    /// Stop once the torrent has been seeding for this long, across runs.
+    pub seeding_time: Option<Duration>,
+    pub action: SeedAction,
+}
+
+impl SeedGoal {
+    /// Whether the torrent has done either of what the goal asks.
+    pub fn is_met(&self, uploaded: u64, seeding_time: Duration) -> bool {
+        self.uploaded.is_some_and(|goal| uploaded >= goal)
+            || self.seeding_time.is_some_and(|goal| seeding_time >= goal)
+    }
+}
+
+/// A goal for the torrents announcing to a tracker, or for every torrent. Parsed from
+/// comma separated settings, such as `tracker=example.org,ratio=1.5,days=14,action=remove`.
+/// Torrents stop at whichever of the ratio and seeding time they reach first.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SeedRule {
+    /// Host of the trackers the rule is for, including their subdomains. Rules without
+    /// one are for every torrent.
+    pub tracker: Option<String>,
+    pub ratio: Option<f64>,
+    pub seeding_time: Option<Duration>,
+    pub action: SeedAction,
+}
+
+impl SeedRule {
+    /// Whether the rule is for a torrent announcing to `trackers`.
+    pub fn matches(&self, trackers: &[Vec<String>]) -> bool {
+        let host = match &self.tracker {
+            Some(host) => host,
+            None => return true,
+        };
+        trackers.iter().flatten().any(|url| {
+            let url = match Url::parse(url) {
+                Ok(url) => url,
+                Err(_) => return false,
+            };
+            url.host_str().is_some_and(|h| {
+                h.eq_ignore_ascii_case(host)
+                    || h.to_ascii_lowercase()
+                        .ends_with(&format!(".{}", host.to_ascii_lowercase()))
+            })
+        })
+    }
+
+    /// The goal for a torrent of `total_length` bytes.
+    pub fn goal(&self, total_length: u64) -> SeedGoal {
+        SeedGoal {
+            uploaded: self.ratio.map(|ratio| (ratio * total_length as f64) as u64),
+            seeding_time: self.seeding_time,
+            action: self.action,
+        }
+    }
+}
+
+impl FromStr for SeedRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rule = SeedRule::default();
+        for setting in s.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected key=value in seed rule, got {}", setting))?;
+            match key.trim() {
+                "tracker" => rule.tracker = Some(value.trim().to_owned()),
+                "ratio" => rule.ratio = Some(value.trim().parse()?),
+                "days" => {
+                    let days: f64 = value.trim().parse()?;
+                    rule.seeding_time = Some(Duration::from_secs_f64(days * 24.0 * 60.0 * 60.0));
+                }
+                "action" => rule.action = value.trim().parse()?,
+                key => return Err(anyhow!("Unknown seed rule setting: {}", key)),
+            }
+        }
+        if rule.ratio.is_none() && rule.seeding_time.is_none() {
+            return Err(anyhow!("Seed rule needs a ratio or a number of days"));
+        }
+
+        Ok(rule)
+    }
+}
+
+/// The goal of the first of `rules` for a torrent announcing to `trackers`.
+pub fn goal_for(
+    rules: &[SeedRule],
+    trackers: &[Vec<String>],
+    total_length: u64,
+) -> Option<SeedGoal> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(trackers))
+        .map(|rule| rule.goal(total_length))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn first_matching_rule_sets_the_goal() {
+        let rules: Vec<SeedRule> = [
+            "tracker=example.org,ratio=1.5,days=14,action=remove-data",
+            "ratio=1",
+        ]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+        let private = vec![vec!["https://tracker.example.org/announce".to_owned()]];
+        let public = vec![vec!["udp://tracker.example.com:1337".to_owned()]];
+
+        let goal = goal_for(&rules, &private, 100).unwrap();
+        assert_eq!(goal.uploaded, Some(150));
+        assert_eq!(goal.action, SeedAction::RemoveData);
+        assert!(!goal.is_met(149, Duration::from_secs(13 * 24 * 60 * 60)));
+        assert!(goal.is_met(0, Duration::from_secs(14 * 24 * 60 * 60)));
+
+        let goal = goal_for(&rules, &public, 100).unwrap();
+        assert_eq!(goal.uploaded, Some(100));
+        assert_eq!(goal.seeding_time, None);
+        assert_eq!(goal.action, SeedAction::Pause);
+    }
+
+    #[test]
+    fn reject_rules_without_a_goal() {
+        assert!("tracker=example.org".parse::<SeedRule>().is_err());
+        assert!("ratio=2,colour=blue".parse::<SeedRule>().is_err());
+    }
+}
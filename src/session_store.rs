@@ -0,0 +1,697 @@
+//! Persisting the client's torrents in a SQLite database, so a restarted client picks up
+//! where it left off.
+
+use crate::choker::Reciprocation;
+use crate::dht::routing::{Node, NodeId};
+use crate::dht::DhtState;
+use crate::magnet::hex;
+use crate::options::AddTorrentOptions;
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Schema changes, applied in order. The database's `user_version` is the number of
+/// migrations which have been applied to it.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE torrents (
+        info_hash TEXT PRIMARY KEY,
+        metainfo BLOB NOT NULL,
+        save_path TEXT NOT NULL,
+        state TEXT NOT NULL,
+        downloaded INTEGER NOT NULL DEFAULT 0,
+        uploaded INTEGER NOT NULL DEFAULT 0,
+        added_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+    );",
+    // The torrent's AddTorrentOptions, as JSON.
+    "ALTER TABLE torrents ADD COLUMN options TEXT NOT NULL DEFAULT '{}';",
+    // The DHT node's id, and the nodes in its routing table when the client last stopped.
+    "CREATE TABLE dht_state (node_id TEXT NOT NULL);
+    CREATE TABLE dht_nodes (
+        node_id TEXT NOT NULL,
+        addr TEXT NOT NULL,
+        last_seen INTEGER NOT NULL
+    );",
+    // Seconds the torrent has spent seeding, across runs, for seeding time goals.
+    "ALTER TABLE torrents ADD COLUMN seeding_time INTEGER NOT NULL DEFAULT 0;",
+    // Items of RSS feeds whose torrents have been added, so they aren't added again.
+    "CREATE TABLE feed_items (
+        feed TEXT NOT NULL,
+        item TEXT NOT NULL,
+        added_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+        PRIMARY KEY (feed, item)
+    );",
+    // Bytes each peer sent us the last time we met it, to prefer it in the choker.
+    "CREATE TABLE peer_history (
+        ip TEXT PRIMARY KEY,
+        given INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );",
+    // The peers trackers last gave each torrent, to dial straight away after a restart
+    // or while the trackers are down.
+    "CREATE TABLE known_peers (
+        info_hash TEXT NOT NULL,
+        addr TEXT NOT NULL,
+        last_seen INTEGER NOT NULL,
+        PRIMARY KEY (info_hash, addr)
+    );",
+];
+
+/// How long peers are remembered for after we last met them.
+const PEER_HISTORY_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// How long after a tracker gave us a peer we'll still dial it on startup.
+const KNOWN_PEERS_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// What the client was doing with a stored torrent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoredState {
+    Downloading,
+    Complete,
+    Seeding,
+}
+
+impl StoredState {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Downloading => "downloading",
+            Self::Complete => "complete",
+            Self::Seeding => "seeding",
+        }
+    }
+}
+
+impl std::str::FromStr for StoredState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "downloading" => Ok(Self::Downloading),
+            "complete" => Ok(Self::Complete),
+            "seeding" => Ok(Self::Seeding),
+            _ => Err(anyhow!("Unknown torrent state: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentRecord {
+    pub info_hash: [u8; 20],
+    /// The torrent's .torrent file.
+    pub metainfo: Vec<u8>,
+    pub save_path: PathBuf,
+    pub state: StoredState,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Seconds spent seeding.
+    pub seeding_time: u64,
+    pub options: AddTorrentOptions,
+}
+
+#[derive(Debug)]
+pub struct SessionStore {
+    conn: Mutex<Connection>,
+}
+
+impl SessionStore {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> anyhow::Result<Self> {
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn schema_version(&self) -> anyhow::Result<usize> {
+        schema_version(&self.conn.lock().unwrap())
+    }
+
+    /// Add a torrent, replacing any stored torrent with the same info hash.
+    pub fn save_torrent(&self, record: &TorrentRecord) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO torrents
+                (info_hash, metainfo, save_path, state, downloaded, uploaded, seeding_time,
+                    options)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                hex(&record.info_hash),
+                record.metainfo,
+                path_to_str(&record.save_path)?,
+                record.state.as_str(),
+                record.downloaded as i64,
+                record.uploaded as i64,
+                record.seeding_time as i64,
+                serde_json::to_string(&record.options)?,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn set_state(&self, info_hash: &[u8; 20], state: StoredState) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE torrents SET state = ?2 WHERE info_hash = ?1",
+            params![hex(info_hash), state.as_str()],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn set_options(
+        &self,
+        info_hash: &[u8; 20],
+        options: &AddTorrentOptions,
+    ) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE torrents SET options = ?2 WHERE info_hash = ?1",
+            params![hex(info_hash), serde_json::to_string(options)?],
+        )?;
+
+        Ok(())
+    }
+
+    /// Record the torrent's total bytes transferred and seconds spent seeding, across
+    /// all runs.
+    pub fn set_totals(
+        &self,
+        info_hash: &[u8; 20],
+        downloaded: u64,
+        uploaded: u64,
+        seeding_time: u64,
+    ) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE torrents SET downloaded = ?2, uploaded = ?3, seeding_time = ?4
+                WHERE info_hash = ?1",
+            params![
+                hex(info_hash),
+                downloaded as i64,
+                uploaded as i64,
+                seeding_time as i64
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM torrents WHERE info_hash = ?1",
+            params![hex(info_hash)],
+        )?;
+        tx.execute(
+            "DELETE FROM known_peers WHERE info_hash = ?1",
+            params![hex(info_hash)],
+        )?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Replace the peers remembered for a torrent with those a tracker just gave us.
+    pub fn save_known_peers(
+        &self,
+        info_hash: &[u8; 20],
+        peers: &[SocketAddr],
+    ) -> anyhow::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM known_peers WHERE info_hash = ?1",
+            params![hex(info_hash)],
+        )?;
+        for addr in peers {
+            tx.execute(
+                "INSERT OR REPLACE INTO known_peers (info_hash, addr, last_seen)
+                VALUES (?1, ?2, ?3)",
+                params![hex(info_hash), addr.to_string(), now],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// The peers a tracker last gave a torrent, unless that was long ago.
+    pub fn known_peers(&self, info_hash: &[u8; 20]) -> anyhow::Result<Vec<SocketAddr>> {
+        let oldest = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(KNOWN_PEERS_TTL)
+            .as_secs() as i64;
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT addr FROM known_peers WHERE info_hash = ?1 AND last_seen >= ?2 ORDER BY addr",
+        )?;
+        let rows = stmt.query_map(params![hex(info_hash), oldest], |row| {
+            row.get::<_, String>(0)
+        })?;
+        let mut peers = Vec::new();
+        for row in rows {
+            peers.push(row?.parse()?);
+        }
+
+        Ok(peers)
+    }
+
+    /// Remember that the torrent of `item` in `feed` has been added. Returns false if it
+    /// already was.
+    pub fn add_feed_item(&self, feed: &str, item: &str) -> anyhow::Result<bool> {
+        let inserted = self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO feed_items (feed, item) VALUES (?1, ?2)",
+            params![feed, item],
+        )?;
+
+        Ok(inserted > 0)
+    }
+
+    pub fn has_feed_item(&self, feed: &str, item: &str) -> anyhow::Result<bool> {
+        let found = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT 1 FROM feed_items WHERE feed = ?1 AND item = ?2",
+                params![feed, item],
+                |_| Ok(()),
+            )
+            .optional()?;
+
+        Ok(found.is_some())
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> anyhow::Result<Option<TorrentRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let record = conn
+            .query_row(
+                "SELECT metainfo, save_path, state, downloaded, uploaded, seeding_time, options
+                    FROM torrents WHERE info_hash = ?1",
+                params![hex(info_hash)],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, i64>(4)?,
+                        row.get::<_, i64>(5)?,
+                        row.get::<_, String>(6)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        record
+            .map(
+                |(metainfo, save_path, state, downloaded, uploaded, seeding_time, options)| {
+                    Ok(TorrentRecord {
+                        info_hash: *info_hash,
+                        metainfo,
+                        save_path: save_path.into(),
+                        state: state.parse()?,
+                        downloaded: downloaded as u64,
+                        uploaded: uploaded as u64,
+                        seeding_time: seeding_time as u64,
+                        options: serde_json::from_str(&options)?,
+                    })
+                },
+            )
+            .transpose()
+    }
+
+    /// Every stored torrent, in the order they were added.
+    pub fn torrents(&self) -> anyhow::Result<Vec<TorrentRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT info_hash, metainfo, save_path, state, downloaded, uploaded, seeding_time,
+                options FROM torrents ORDER BY added_at, rowid",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, i64>(4)?,
+                row.get::<_, i64>(5)?,
+                row.get::<_, i64>(6)?,
+                row.get::<_, String>(7)?,
+            ))
+        })?;
+
+        let mut records = Vec::new();
+        for row in rows {
+            let (
+                info_hash,
+                metainfo,
+                save_path,
+                state,
+                downloaded,
+                uploaded,
+                seeding_time,
+                options,
+            ) = row?;
+            records.push(TorrentRecord {
+                info_hash: parse_info_hash(&info_hash)?,
+                metainfo,
+                save_path: save_path.into(),
+                state: state.parse()?,
+                downloaded: downloaded as u64,
+                uploaded: uploaded as u64,
+                seeding_time: seeding_time as u64,
+                options: serde_json::from_str(&options)?,
+            });
+        }
+
+        Ok(records)
+    }
+
+    /// Replace the stored DHT state with `state`.
+    pub fn save_dht(&self, state: &DhtState) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM dht_state", [])?;
+        tx.execute("DELETE FROM dht_nodes", [])?;
+        tx.execute(
+            "INSERT INTO dht_state (node_id) VALUES (?1)",
+            params![hex(&state.id.0)],
+        )?;
+        for node in &state.nodes {
+            let last_seen = node
+                .last_seen
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            tx.execute(
+                "INSERT INTO dht_nodes (node_id, addr, last_seen) VALUES (?1, ?2, ?3)",
+                params![
+                    hex(&node.id.0),
+                    node.addr.to_string(),
+                    last_seen.as_secs() as i64
+                ],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// The DHT state saved by the last run, if there was one.
+    pub fn dht(&self) -> anyhow::Result<Option<DhtState>> {
+        let conn = self.conn.lock().unwrap();
+        let id: Option<String> = conn
+            .query_row("SELECT node_id FROM dht_state", [], |row| row.get(0))
+            .optional()?;
+        let id = match id {
+            Some(id) => NodeId(parse_info_hash(&id)?),
+            None => return Ok(None),
+        };
+
+        let mut stmt = conn.prepare("SELECT node_id, addr, last_seen FROM dht_nodes")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+        let mut nodes = Vec::new();
+        for row in rows {
+            let (node_id, addr, last_seen) = row?;
+            nodes.push(Node {
+                id: NodeId(parse_info_hash(&node_id)?),
+                addr: addr.parse()?,
+                last_seen: SystemTime::UNIX_EPOCH + Duration::from_secs(last_seen as u64),
+            });
+        }
+
+        Ok(Some(DhtState { id, nodes }))
+    }
+
+    /// Replace the stored peer history with `history`, leaving out peers we haven't
+    /// met for a long time.
+    pub fn save_peer_history(
+        &self,
+        history: &HashMap<IpAddr, Reciprocation>,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM peer_history", [])?;
+        for (ip, reciprocation) in history {
+            let age = reciprocation.last_seen.elapsed().unwrap_or_default();
+            if age > PEER_HISTORY_TTL {
+                continue;
+            }
+            let last_seen = reciprocation
+                .last_seen
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            tx.execute(
+                "INSERT INTO peer_history (ip, given, last_seen) VALUES (?1, ?2, ?3)",
+                params![
+                    ip.to_string(),
+                    reciprocation.given as i64,
+                    last_seen.as_secs() as i64
+                ],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// What peers sent us the last time we met them, in earlier runs.
+    pub fn peer_history(&self) -> anyhow::Result<HashMap<IpAddr, Reciprocation>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT ip, given, last_seen FROM peer_history")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+        let mut history = HashMap::new();
+        for row in rows {
+            let (ip, given, last_seen) = row?;
+            let reciprocation = Reciprocation {
+                given: given as u64,
+                last_seen: SystemTime::UNIX_EPOCH + Duration::from_secs(last_seen as u64),
+            };
+            history.insert(ip.parse()?, reciprocation);
+        }
+
+        Ok(history)
+    }
+}
+
+fn schema_version(conn: &Connection) -> anyhow::Result<usize> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let version = schema_version(conn)?;
+    if version > MIGRATIONS.len() {
+        return Err(anyhow!(
+            "Session database is from a newer version (schema {})",
+            version
+        ));
+    }
+
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", (idx + 1) as i64)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+fn path_to_str(path: &Path) -> anyhow::Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("Save path isn't valid UTF-8: {}", path.display()))
+}
+
+fn parse_info_hash(s: &str) -> anyhow::Result<[u8; 20]> {
+    let mut info_hash = [0; 20];
+    if s.len() != 40 {
+        return Err(anyhow!("Invalid info hash: {}", s));
+    }
+    for (byte, chunk) in info_hash.iter_mut().zip(s.as_bytes().chunks(2)) {
+        let chunk = std::str::from_utf8(chunk)?;
+        *byte = u8::from_str_radix(chunk, 16)?;
+    }
+
+    Ok(info_hash)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(byte: u8) -> TorrentRecord {
+        TorrentRecord {
+            info_hash: [byte; 20],
+            metainfo: vec![byte],
+            save_path: PathBuf::from("/downloads"),
+            state: StoredState::Downloading,
+            downloaded: 0,
+            uploaded: 0,
+            seeding_time: 0,
+            options: AddTorrentOptions {
+                upload_limit: Some(byte as u64),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn apply_migrations() {
+        let store = SessionStore::open_in_memory().unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn store_and_update_torrents() {
+        let store = SessionStore::open_in_memory().unwrap();
+        store.save_torrent(&record(1)).unwrap();
+        store.save_torrent(&record(0xab)).unwrap();
+
+        store.set_state(&[1; 20], StoredState::Complete).unwrap();
+        store.set_totals(&[1; 20], 100, 50, 3600).unwrap();
+        let options = AddTorrentOptions {
+            trackers: Some(vec![vec![String::from("http://tracker/announce")]]),
+            ..Default::default()
+        };
+        store.set_options(&[1; 20], &options).unwrap();
+        store.remove_torrent(&[0xab; 20]).unwrap();
+
+        let torrents = store.torrents().unwrap();
+        assert_eq!(
+            torrents,
+            vec![TorrentRecord {
+                state: StoredState::Complete,
+                downloaded: 100,
+                uploaded: 50,
+                seeding_time: 3600,
+                options,
+                ..record(1)
+            }]
+        );
+        assert_eq!(store.torrent(&[1; 20]).unwrap(), Some(torrents[0].clone()));
+        assert_eq!(store.torrent(&[0xab; 20]).unwrap(), None);
+    }
+
+    #[test]
+    fn remember_added_feed_items() {
+        let store = SessionStore::open_in_memory().unwrap();
+        assert!(!store.has_feed_item("https://feed/rss", "a").unwrap());
+        assert!(store.add_feed_item("https://feed/rss", "a").unwrap());
+        assert!(!store.add_feed_item("https://feed/rss", "a").unwrap());
+        assert!(store.has_feed_item("https://feed/rss", "a").unwrap());
+        assert!(!store.has_feed_item("https://other/rss", "a").unwrap());
+    }
+
+    #[test]
+    fn store_peer_history() {
+        let store = SessionStore::open_in_memory().unwrap();
+        let seen = |secs_ago| {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap();
+            SystemTime::UNIX_EPOCH + Duration::from_secs(now.as_secs() - secs_ago)
+        };
+        let recent = Reciprocation {
+            given: 1 << 30,
+            last_seen: seen(60),
+        };
+        let history = HashMap::from([
+            ("192.0.2.1".parse().unwrap(), recent),
+            (
+                "2001:db8::1".parse().unwrap(),
+                Reciprocation { given: 5, ..recent },
+            ),
+            (
+                "192.0.2.2".parse().unwrap(),
+                Reciprocation {
+                    given: 5,
+                    last_seen: seen(PEER_HISTORY_TTL.as_secs() + 60),
+                },
+            ),
+        ]);
+        store.save_peer_history(&history).unwrap();
+
+        let mut expected = history;
+        expected.remove(&"192.0.2.2".parse().unwrap());
+        assert_eq!(store.peer_history().unwrap(), expected);
+    }
+
+    #[test]
+    fn remember_known_peers() {
+        let store = SessionStore::open_in_memory().unwrap();
+        let peers: Vec<SocketAddr> = vec![
+            "192.0.2.1:6881".parse().unwrap(),
+            "192.0.2.2:51413".parse().unwrap(),
+        ];
+        store.save_known_peers(&[1; 20], &peers).unwrap();
+        store.save_known_peers(&[2; 20], &peers[..1]).unwrap();
+        assert_eq!(store.known_peers(&[1; 20]).unwrap(), peers);
+
+        // Each announce replaces the last one's peers.
+        store.save_known_peers(&[1; 20], &peers[1..]).unwrap();
+        assert_eq!(store.known_peers(&[1; 20]).unwrap(), &peers[1..]);
+
+        store.save_torrent(&record(2)).unwrap();
+        store.remove_torrent(&[2; 20]).unwrap();
+        assert!(store.known_peers(&[2; 20]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn store_dht_state() {
+        let store = SessionStore::open_in_memory().unwrap();
+        assert_eq!(store.dht().unwrap(), None);
+
+        let node = |byte| Node {
+            id: NodeId([byte; 20]),
+            addr: format!("192.0.2.{}:6881", byte).parse().unwrap(),
+            last_seen: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+        let state = DhtState {
+            id: NodeId([0xab; 20]),
+            nodes: vec![node(1), node(2)],
+        };
+        store.save_dht(&state).unwrap();
+        store
+            .save_dht(&DhtState {
+                nodes: vec![node(1), node(3)],
+                ..state.clone()
+            })
+            .unwrap();
+
+        assert_eq!(
+            store.dht().unwrap(),
+            Some(DhtState {
+                nodes: vec![node(1), node(3)],
+                ..state
+            })
+        );
+    }
+}
@@ -0,0 +1,577 @@
+use crate::peer::{Extensions, PeerSource};
+use crate::tracker::Transfer;
+use crate::verify::CheckProgress;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+/// How much each new latency sample moves a peer's average, as a fraction 1/n.
+const LATENCY_SMOOTHING: u64 = 8;
+
+/// Measures a transfer rate over a short sliding window.
+#[derive(Debug, Default)]
+pub struct RateMeter {
+    samples: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl RateMeter {
+    pub fn record(&self, bytes: u64) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back((now, bytes));
+        Self::expire(&mut samples, now);
+    }
+
+    /// Bytes per second over the last few seconds.
+    pub fn rate(&self) -> u64 {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        Self::expire(&mut samples, now);
+        let total: u64 = samples.iter().map(|(_, bytes)| bytes).sum();
+
+        total / RATE_WINDOW.as_secs()
+    }
+
+    fn expire(samples: &mut VecDeque<(Instant, u64)>, now: Instant) {
+        while let Some((at, _)) = samples.front() {
+            if now.duration_since(*at) > RATE_WINDOW {
+                samples.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+/// Bytes sent and received over peer connections, split into piece data and the
+/// protocol overhead around it: handshakes, message headers, haves, bitfields and so on.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    payload_down: AtomicU64,
+    overhead_down: AtomicU64,
+    payload_up: AtomicU64,
+    overhead_up: AtomicU64,
+}
+
+impl Traffic {
+    /// Count `total` bytes received, `payload` of which were piece data.
+    pub fn received(&self, total: usize, payload: usize) {
+        self.payload_down
+            .fetch_add(payload as u64, Ordering::Relaxed);
+        self.overhead_down
+            .fetch_add((total - payload) as u64, Ordering::Relaxed);
+    }
+
+    /// Count `total` bytes sent, `payload` of which were piece data.
+    pub fn sent(&self, total: usize, payload: usize) {
+        self.payload_up.fetch_add(payload as u64, Ordering::Relaxed);
+        self.overhead_up
+            .fetch_add((total - payload) as u64, Ordering::Relaxed);
+    }
+
+    pub fn status(&self) -> TrafficStatus {
+        TrafficStatus {
+            payload_down: self.payload_down.load(Ordering::Relaxed),
+            overhead_down: self.overhead_down.load(Ordering::Relaxed),
+            payload_up: self.payload_up.load(Ordering::Relaxed),
+            overhead_up: self.overhead_up.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficStatus {
+    pub payload_down: u64,
+    pub overhead_down: u64,
+    pub payload_up: u64,
+    pub overhead_up: u64,
+}
+
+/// Live counters for a single peer connection.
+#[derive(Debug)]
+pub struct PeerStats {
+    pub source: PeerSource,
+    pub client: Mutex<Option<String>>,
+    /// Extensions the peer advertised in its handshake.
+    pub extensions: Mutex<Extensions>,
+    pub downloaded: AtomicU64,
+    pub uploaded: AtomicU64,
+    /// Every byte of the connection, counted as the messages are coded.
+    pub traffic: Arc<Traffic>,
+    pub pieces: AtomicUsize,
+    pub am_choking: AtomicBool,
+    pub am_interested: AtomicBool,
+    pub peer_choking: AtomicBool,
+    pub peer_interested: AtomicBool,
+    /// Moving average of the time from requesting a block to its first bytes
+    /// arriving, in microseconds, or 0 before any has arrived.
+    latency: AtomicU64,
+    /// When the peer was added.
+    since: Instant,
+    download_rate: RateMeter,
+    upload_rate: RateMeter,
+}
+
+impl PeerStats {
+    pub fn new(source: PeerSource) -> Self {
+        Self {
+            source,
+            client: Default::default(),
+            extensions: Default::default(),
+            downloaded: Default::default(),
+            uploaded: Default::default(),
+            traffic: Default::default(),
+            pieces: Default::default(),
+            am_choking: AtomicBool::new(true),
+            am_interested: Default::default(),
+            peer_choking: AtomicBool::new(true),
+            peer_interested: Default::default(),
+            latency: Default::default(),
+            since: Instant::now(),
+            download_rate: Default::default(),
+            upload_rate: Default::default(),
+        }
+    }
+
+    pub fn record_download(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.download_rate.record(bytes as u64);
+    }
+
+    pub fn record_upload(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.upload_rate.record(bytes as u64);
+    }
+
+    pub fn download_rate(&self) -> u64 {
+        self.download_rate.rate()
+    }
+
+    /// Fold the time a request took to start being answered into the peer's latency.
+    pub fn record_latency(&self, sample: Duration) {
+        let sample = (sample.as_micros() as u64).max(1);
+        let average = match self.latency.load(Ordering::Relaxed) {
+            0 => sample,
+            old => old - old / LATENCY_SMOOTHING + sample / LATENCY_SMOOTHING,
+        };
+        self.latency.store(average.max(1), Ordering::Relaxed);
+    }
+
+    /// The peer's average request latency, once a request has been answered.
+    pub fn latency(&self) -> Option<Duration> {
+        match self.latency.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    pub fn upload_rate(&self) -> u64 {
+        self.upload_rate.rate()
+    }
+
+    pub fn set_client(&self, client: String) {
+        *self.client.lock().unwrap() = Some(client);
+    }
+
+    pub fn set_extensions(&self, extensions: Extensions) {
+        *self.extensions.lock().unwrap() = extensions;
+    }
+
+    pub fn status(&self, addr: SocketAddr, piece_count: usize) -> PeerStatus {
+        let pieces = self.pieces.load(Ordering::Relaxed);
+
+        PeerStatus {
+            addr,
+            source: self.source,
+            client: self.client.lock().unwrap().clone(),
+            extensions: *self.extensions.lock().unwrap(),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            traffic: self.traffic.status(),
+            download_rate: self.download_rate.rate(),
+            upload_rate: self.upload_rate.rate(),
+            latency_ms: self.latency().map(|l| l.as_millis() as u64),
+            progress: fraction(pieces, piece_count),
+            am_choking: self.am_choking.load(Ordering::Relaxed),
+            am_interested: self.am_interested.load(Ordering::Relaxed),
+            peer_choking: self.peer_choking.load(Ordering::Relaxed),
+            peer_interested: self.peer_interested.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Live counters for a torrent and all of its peer connections.
+#[derive(Debug, Default)]
+pub struct TorrentStats {
+    pub piece_count: usize,
+    pub pieces_done: AtomicUsize,
+    pub downloaded: AtomicU64,
+    pub uploaded: AtomicU64,
+    /// Every byte of every peer connection the torrent has had.
+    pub traffic: Arc<Traffic>,
+    /// Seconds spent seeding, while not paused.
+    pub seeding_time: AtomicU64,
+    download_rate: RateMeter,
+    upload_rate: RateMeter,
+    peers: Mutex<HashMap<SocketAddr, Arc<PeerStats>>>,
+    trackers: Mutex<HashMap<String, TrackerStatus>>,
+    sources: Mutex<BTreeMap<PeerSource, SourceStatus>>,
+}
+
+impl TorrentStats {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            piece_count,
+            ..Default::default()
+        }
+    }
+
+    /// Register a peer we're connecting to, returning the counters its session should update.
+    pub fn add_peer(&self, addr: SocketAddr, source: PeerSource) -> Arc<PeerStats> {
+        self.source(source, |s| s.attempted += 1);
+        let stats = Arc::new(PeerStats::new(source));
+        self.peers.lock().unwrap().insert(addr, Arc::clone(&stats));
+
+        stats
+    }
+
+    fn source(&self, source: PeerSource, f: impl FnOnce(&mut SourceStatus)) {
+        f(self.sources.lock().unwrap().entry(source).or_default());
+    }
+
+    pub fn peers_discovered(&self, source: PeerSource, count: usize) {
+        self.source(source, |s| s.discovered += count);
+    }
+
+    /// Record that we refused to talk to a peer because of the IP filter.
+    pub fn peer_blocked(&self, source: PeerSource) {
+        self.source(source, |s| s.blocked += 1);
+    }
+
+    /// Record that a peer completed the handshake.
+    pub fn peer_connected(&self, source: PeerSource) {
+        self.source(source, |s| s.connected += 1);
+    }
+
+    pub fn has_peer(&self, addr: &SocketAddr) -> bool {
+        self.peers.lock().unwrap().contains_key(addr)
+    }
+
+    pub fn remove_peer(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+
+    /// The connected peer sending us the least, if it sends less than half as much as
+    /// the median. Only peers which have been connected for `min_age`, and are
+    /// unchoking us while we want something from them, are judged, so peers aren't
+    /// dropped before they've had a chance or for having nothing we want.
+    pub fn slowest_peer(&self, min_age: Duration) -> Option<SocketAddr> {
+        let peers = self.peers.lock().unwrap();
+        let mut judged: Vec<_> = peers
+            .iter()
+            .filter(|(_, p)| {
+                p.since.elapsed() >= min_age
+                    && p.am_interested.load(Ordering::Relaxed)
+                    && !p.peer_choking.load(Ordering::Relaxed)
+            })
+            .map(|(addr, p)| (p.download_rate(), *addr))
+            .collect();
+        if judged.len() < 2 {
+            return None;
+        }
+        judged.sort();
+
+        let median = judged[judged.len() / 2].0;
+        let (rate, addr) = judged[0];
+        (rate * 2 < median).then_some(addr)
+    }
+
+    /// How many peers have a lower latency than `stats`. Peers whose latency isn't
+    /// known yet come after all those whose is.
+    pub fn latency_rank(&self, stats: &PeerStats) -> usize {
+        let peers = self.peers.lock().unwrap();
+        let latencies = peers.values().filter_map(|p| p.latency());
+        match stats.latency() {
+            Some(latency) => latencies.filter(|&l| l < latency).count(),
+            None => latencies.count(),
+        }
+    }
+
+    pub fn record_download(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.download_rate.record(bytes as u64);
+    }
+
+    pub fn record_upload(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.upload_rate.record(bytes as u64);
+    }
+
+    /// What to report to trackers, for a torrent with `left` bytes still to download.
+    pub fn transfer(&self, left: u64, partial_seed: bool) -> Transfer {
+        Transfer {
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left,
+            partial_seed,
+        }
+    }
+
+    pub fn piece_done(&self) -> usize {
+        self.pieces_done.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn set_pieces_done(&self, count: usize) {
+        self.pieces_done.store(count, Ordering::Relaxed);
+    }
+
+    /// Replace the status of every tracker, dropping trackers which aren't in `statuses`.
+    pub fn set_tracker_statuses(&self, statuses: Vec<TrackerStatus>) {
+        *self.trackers.lock().unwrap() = statuses
+            .into_iter()
+            .map(|status| (status.url.clone(), status))
+            .collect();
+    }
+
+    pub fn status(&self, info_hash: &[u8; 20], name: &str) -> TorrentStatus {
+        let mut peers: Vec<_> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, stats)| stats.status(*addr, self.piece_count))
+            .collect();
+        peers.sort_by_key(|p| p.addr);
+
+        let mut trackers: Vec<_> = self.trackers.lock().unwrap().values().cloned().collect();
+        trackers.sort_by(|a, b| a.url.cmp(&b.url));
+
+        let pieces_done = self.pieces_done.load(Ordering::Relaxed);
+
+        TorrentStatus {
+            info_hash: crate::magnet::hex(info_hash),
+            name: name.to_owned(),
+            category: None,
+            checking: None,
+            progress: fraction(pieces_done, self.piece_count),
+            pieces_done,
+            piece_count: self.piece_count,
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            traffic: self.traffic.status(),
+            seeding_time: self.seeding_time.load(Ordering::Relaxed),
+            download_rate: self.download_rate.rate(),
+            upload_rate: self.upload_rate.rate(),
+            peers,
+            trackers,
+            sources: self.sources.lock().unwrap().clone(),
+        }
+    }
+}
+
+fn fraction(done: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        done as f64 / total as f64
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub addr: SocketAddr,
+    pub source: PeerSource,
+    pub client: Option<String>,
+    pub extensions: Extensions,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    #[serde(default)]
+    pub traffic: TrafficStatus,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    /// Average time for the peer to start answering a request.
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+    pub progress: f64,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl PeerStatus {
+    /// Compact flag string in the style of other clients: D/d (we're interested and
+    /// unchoked/choked), U/u (peer is interested and unchoked/choked by us).
+    pub fn flags(&self) -> String {
+        let mut flags = String::new();
+        if self.am_interested {
+            flags.push(if self.peer_choking { 'd' } else { 'D' });
+        }
+        if self.peer_interested {
+            flags.push(if self.am_choking { 'u' } else { 'U' });
+        }
+
+        flags
+    }
+}
+
+/// How many peers a discovery mechanism has found, and how many of those were usable.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceStatus {
+    pub discovered: usize,
+    pub attempted: usize,
+    pub connected: usize,
+    /// Peers the IP filter stopped us connecting to or accepting.
+    pub blocked: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackerStatus {
+    pub url: String,
+    pub status: String,
+    pub peers: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub name: String,
+    #[serde(default)]
+    pub category: Option<String>,
+    /// How far the recheck running has got, if one is.
+    #[serde(default)]
+    pub checking: Option<CheckProgress>,
+    pub progress: f64,
+    pub pieces_done: usize,
+    pub piece_count: usize,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bytes on the wire, rather than of verified pieces, split by direction and into
+    /// payload and protocol overhead.
+    #[serde(default)]
+    pub traffic: TrafficStatus,
+    /// Seconds spent seeding.
+    pub seeding_time: u64,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub peers: Vec<PeerStatus>,
+    pub trackers: Vec<TrackerStatus>,
+    pub sources: BTreeMap<PeerSource, SourceStatus>,
+}
+
+/// Describe a peer's client from an Azureus-style peer id, e.g. `-TR2940-...` becomes `TR 2940`.
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+
+    let id = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let version = std::str::from_utf8(&peer_id[3..7]).ok()?;
+    if !id.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    Some(format!("{} {}", id, version))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_azureus_client_name() {
+        assert_eq!(
+            client_name(b"-TR2940-k8hj0wgej6ch"),
+            Some(String::from("TR 2940"))
+        );
+        assert_eq!(client_name(b"M7-2-0--k8hj0wgej6ch"), None);
+    }
+
+    #[test]
+    fn torrent_status_includes_peers() {
+        let stats = TorrentStats::new(4);
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        stats.peers_discovered(PeerSource::Tracker, 3);
+        let peer = stats.add_peer(addr, PeerSource::Tracker);
+        stats.peer_connected(PeerSource::Tracker);
+        stats.peer_blocked(PeerSource::Tracker);
+        peer.record_download(100);
+        peer.pieces.store(2, Ordering::Relaxed);
+        peer.am_interested.store(true, Ordering::Relaxed);
+        stats.record_download(100);
+        stats.piece_done();
+
+        let status = stats.status(&[0; 20], "test");
+        assert_eq!(status.pieces_done, 1);
+        assert_eq!(status.progress, 0.25);
+        assert_eq!(status.downloaded, 100);
+        assert_eq!(status.peers.len(), 1);
+        assert_eq!(status.peers[0].progress, 0.5);
+        assert_eq!(status.peers[0].flags(), "d");
+        assert_eq!(
+            status.sources[&PeerSource::Tracker],
+            SourceStatus {
+                discovered: 3,
+                attempted: 1,
+                connected: 1,
+                blocked: 1,
+            }
+        );
+
+        stats.remove_peer(&addr);
+        assert!(stats.status(&[0; 20], "test").peers.is_empty());
+    }
+
+    #[test]
+    fn find_the_slowest_peer_unchoking_us() {
+        let stats = TorrentStats::new(1);
+        let peer = |port: u16, bytes: usize, unchoking: bool| {
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let peer = stats.add_peer(addr, PeerSource::Tracker);
+            peer.record_download(bytes);
+            peer.am_interested.store(true, Ordering::Relaxed);
+            peer.peer_choking.store(!unchoking, Ordering::Relaxed);
+            addr
+        };
+        peer(1, 100_000, true);
+        let choking = peer(2, 0, false);
+        assert_eq!(stats.slowest_peer(Duration::ZERO), None);
+
+        let slow = peer(3, 40_000, true);
+        peer(4, 90_000, true);
+        assert_eq!(stats.slowest_peer(Duration::ZERO), Some(slow));
+        assert_eq!(stats.slowest_peer(Duration::from_secs(60)), None);
+
+        // Peers which are all about as quick are left alone.
+        stats.remove_peer(&slow);
+        stats.remove_peer(&choking);
+        assert_eq!(stats.slowest_peer(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn rank_peers_by_average_latency() {
+        let stats = TorrentStats::new(1);
+        let fast = stats.add_peer("127.0.0.1:1".parse().unwrap(), PeerSource::Tracker);
+        let slow = stats.add_peer("127.0.0.1:2".parse().unwrap(), PeerSource::Tracker);
+        let new = stats.add_peer("127.0.0.1:3".parse().unwrap(), PeerSource::Tracker);
+
+        fast.record_latency(Duration::from_millis(20));
+        slow.record_latency(Duration::from_millis(10));
+        assert_eq!(slow.latency(), Some(Duration::from_millis(10)));
+        // One slow answer only moves the average part of the way.
+        slow.record_latency(Duration::from_millis(170));
+        assert_eq!(slow.latency(), Some(Duration::from_millis(30)));
+
+        assert_eq!(stats.latency_rank(&fast), 0);
+        assert_eq!(stats.latency_rank(&slow), 1);
+        assert_eq!(stats.latency_rank(&new), 2);
+        assert_eq!(new.latency(), None);
+        let status = stats.status(&[0; 20], "test");
+        assert_eq!(status.peers[1].latency_ms, Some(30));
+    }
+}
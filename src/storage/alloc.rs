@@ -0,0 +1,160 @@
+//! Setting files to their full size before pieces are written into them. Without this,
+//! writing a piece near the end of a file on Windows makes NTFS fill everything before
+//! it with zeroes first, and on any platform the disk can fill up partway through.
+
+use std::fs::File;
+use std::io;
+
+/// How the space for a torrent's files is set aside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preallocate {
+    /// Grow files as pieces are written to them.
+    None,
+    /// Set files to their full size, as sparse files which only take up the space
+    /// written to so far.
+    #[default]
+    Sparse,
+    /// Reserve all the space on disk up front, so the disk can't fill up partway
+    /// through and files aren't fragmented.
+    Full,
+}
+
+impl std::str::FromStr for Preallocate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "sparse" => Ok(Self::Sparse),
+            "full" => Ok(Self::Full),
+            _ => Err(anyhow::anyhow!("Unknown preallocation: {}", s)),
+        }
+    }
+}
+
+/// Set aside space for `file` to be `length` bytes long. Files which are already that
+/// long are left as they are.
+pub fn allocate(file: &File, length: u64, mode: Preallocate) -> io::Result<()> {
+    if mode == Preallocate::None || file.metadata()?.len() >= length {
+        return Ok(());
+    }
+    if mode == Preallocate::Sparse {
+        platform::set_sparse(file)?;
+    }
+    file.set_len(length)?;
+    if mode == Preallocate::Full {
+        platform::reserve(file, length)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::{ERROR_INVALID_FUNCTION, ERROR_NOT_SUPPORTED, HANDLE};
+    use windows_sys::Win32::System::Ioctl::FSCTL_SET_SPARSE;
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    /// Mark the file sparse, as files on NTFS aren't unless asked. Filesystems which
+    /// can't have sparse files, like FAT and exFAT, are left to grow them as usual.
+    pub fn set_sparse(file: &File) -> io::Result<()> {
+        let mut returned = 0;
+        // SAFETY: the handle is open for as long as `file` is borrowed, and setting the
+        // sparse flag without an input buffer takes no other pointers.
+        let ok = unsafe {
+            DeviceIoControl(
+                file.as_raw_handle() as HANDLE,
+                FSCTL_SET_SPARSE,
+                std::ptr::null(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        if ok != 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error().map(|code| code as u32) {
+            Some(ERROR_INVALID_FUNCTION | ERROR_NOT_SUPPORTED) => {
+                tracing::debug!("Filesystem can't make sparse files: {}", e);
+                Ok(())
+            }
+            _ => Err(e),
+        }
+    }
+
+    /// Setting the length already has NTFS reserve the space, which it zeroes as it's
+    /// written. Skipping that with `SetFileValidData` would let whatever the disk held
+    /// before be read back, so it isn't done.
+    pub fn reserve(_file: &File, _length: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::fs::File;
+    use std::io;
+
+    /// Files on Unix filesystems are sparse until they're written to.
+    pub fn set_sparse(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    pub fn reserve(file: &File, length: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: the descriptor is open for as long as `file` is borrowed.
+        match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+
+    /// Other Unixes have no portable way to reserve space, so files stay sparse.
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    pub fn reserve(_file: &File, _length: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allocate_files_to_their_full_length() {
+        let dir = std::env::temp_dir().join(format!("alloc-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for (name, mode, expected) in [
+            ("none", Preallocate::None, 0),
+            ("sparse", Preallocate::Sparse, 1 << 20),
+            ("full", Preallocate::Full, 1 << 20),
+        ] {
+            let file = File::create(dir.join(name)).unwrap();
+            allocate(&file, 1 << 20, mode).unwrap();
+            assert_eq!(file.metadata().unwrap().len(), expected, "{}", name);
+        }
+
+        // Files which are already long enough aren't touched.
+        let file = File::create(dir.join("long")).unwrap();
+        file.set_len(2 << 20).unwrap();
+        allocate(&file, 1 << 20, Preallocate::Full).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 2 << 20);
+
+        assert_eq!(
+            "sparse".parse::<Preallocate>().unwrap(),
+            Preallocate::Sparse
+        );
+        assert!("lazy".parse::<Preallocate>().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
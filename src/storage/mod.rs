@@ -0,0 +1,805 @@
+//! Reading and writing pieces to the files they belong to.
+
+use crate::bitfield::{Bitfield, BitfieldMut};
+use crate::picker::Priority;
+use crate::Torrent;
+use alloc::Preallocate;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::RwLock;
+use tracing::warn;
+
+pub mod alloc;
+pub mod paths;
+#[cfg(feature = "io-uring")]
+mod uring;
+
+/// One of the torrent's files, and where it sits in the torrent's content.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+    pub md5sum: Option<String>,
+}
+
+/// How much of one of the torrent's files has been saved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileProgress {
+    pub path: PathBuf,
+    pub length: u64,
+    /// Bytes of the file in pieces which have been saved.
+    pub saved: u64,
+}
+
+impl FileProgress {
+    /// The fraction of the file saved, from 0 to 1.
+    pub fn progress(&self) -> f64 {
+        match self.length {
+            0 => 1.0,
+            length => self.saved as f64 / length as f64,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.saved == self.length
+    }
+}
+
+/// Most bytes of pieces kept read ahead of upload requests.
+const READ_AHEAD_BYTES: usize = 16 * 1024 * 1024;
+/// How many recent block reads are remembered to spot pieces being read in order.
+const RECENT_READS: usize = 64;
+
+/// How torrent content is read from and written to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoBackend {
+    /// Tokio's file API, which does each read and write on a blocking thread.
+    Tokio,
+    /// io_uring, which hands reads and writes to the kernel from a single thread.
+    #[cfg(feature = "io-uring")]
+    IoUring,
+}
+
+impl Default for IoBackend {
+    /// io_uring when it's compiled in and the kernel supports it.
+    fn default() -> Self {
+        #[cfg(feature = "io-uring")]
+        if uring::available() {
+            return Self::IoUring;
+        }
+        Self::Tokio
+    }
+}
+
+/// Lays a torrent's pieces out over its files under a download directory.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    layout: Arc<Mutex<Layout>>,
+    /// Held for reading by reads and writes, and for writing while the files move.
+    io: Arc<RwLock<()>>,
+    piece_length: u64,
+    total_length: u64,
+    backend: IoBackend,
+    preallocate: Preallocate,
+    /// Files which have been preallocated since the storage was made.
+    allocated: Arc<Mutex<HashSet<PathBuf>>>,
+    read_ahead: Arc<Mutex<ReadAhead>>,
+    /// Pieces saved intact, which aren't written again: those written since the
+    /// storage was made, and those [`Storage::mark_saved`] was told of.
+    saved: Arc<Mutex<Vec<u8>>>,
+}
+
+/// Where the torrent's files are.
+#[derive(Debug, Clone)]
+struct Layout {
+    /// The download directory, below which nothing is followed through symlinks.
+    root: PathBuf,
+    files: Arc<[FileEntry]>,
+}
+
+/// Whole pieces read from disk because peers were requesting their blocks in order,
+/// so the rest of their blocks can be served without a read each.
+#[derive(Debug, Default)]
+struct ReadAhead {
+    /// Least recently used first.
+    pieces: VecDeque<(usize, Arc<Vec<u8>>)>,
+    bytes: usize,
+    /// The piece and end offset of recent block reads.
+    recent: VecDeque<(usize, usize)>,
+}
+
+impl ReadAhead {
+    fn get(&mut self, idx: usize) -> Option<Arc<Vec<u8>>> {
+        let pos = self.pieces.iter().position(|(i, _)| *i == idx)?;
+        let entry = self.pieces.remove(pos)?;
+        let piece = Arc::clone(&entry.1);
+        self.pieces.push_back(entry);
+        Some(piece)
+    }
+
+    /// Note a block read, returning whether it carries on from where an earlier read of
+    /// the piece ended.
+    fn sequential(&mut self, idx: usize, begin: usize, end: usize) -> bool {
+        let pos = self.recent.iter().position(|&read| read == (idx, begin));
+        if let Some(pos) = pos {
+            self.recent.remove(pos);
+        }
+        self.recent.push_back((idx, end));
+        if self.recent.len() > RECENT_READS {
+            self.recent.pop_front();
+        }
+        pos.is_some()
+    }
+
+    fn insert(&mut self, idx: usize, piece: Arc<Vec<u8>>) {
+        self.remove(idx);
+        self.bytes += piece.len();
+        self.pieces.push_back((idx, piece));
+        while self.bytes > READ_AHEAD_BYTES {
+            match self.pieces.pop_front() {
+                Some((_, evicted)) => self.bytes -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, idx: usize) {
+        if let Some(pos) = self.pieces.iter().position(|(i, _)| *i == idx) {
+            let (_, piece) = self.pieces.remove(pos).unwrap();
+            self.bytes -= piece.len();
+        }
+    }
+}
+
+impl Storage {
+    /// Single-file torrents are stored at `root/name`, multi-file torrents in the
+    /// directory `root/name`. Fails if any of the torrent's paths is unsafe to use.
+    pub fn new(torrent: &Torrent, root: &Path) -> anyhow::Result<Self> {
+        let info = &torrent.file.info;
+        let base = paths::join(root, std::slice::from_ref(&info.name))?;
+        let mut files = match &info.files {
+            None => vec![FileEntry {
+                path: base,
+                offset: 0,
+                length: info.total_length(),
+                md5sum: info.md5sum.clone(),
+            }],
+            Some(files) => {
+                // Padding files take up room in the pieces, but aren't stored.
+                let mut offset = 0;
+                let mut entries = Vec::with_capacity(files.len());
+                for file in files {
+                    let length = file.length as u64;
+                    if !file.is_padding() {
+                        entries.push(FileEntry {
+                            path: paths::join(&base, &file.path)?,
+                            offset,
+                            length,
+                            md5sum: file.md5sum.clone(),
+                        });
+                    }
+                    offset += length;
+                }
+                entries
+            }
+        };
+        // Names Windows had characters replaced in can end up the same.
+        if cfg!(windows) {
+            paths::disambiguate(files.iter_mut().map(|f| &mut f.path));
+        }
+
+        Ok(Self {
+            layout: Arc::new(Mutex::new(Layout {
+                root: root.to_path_buf(),
+                files: files.into(),
+            })),
+            io: Default::default(),
+            piece_length: info.piece_length as u64,
+            total_length: info.total_length(),
+            backend: IoBackend::default(),
+            preallocate: Preallocate::default(),
+            allocated: Default::default(),
+            read_ahead: Default::default(),
+            saved: Arc::new(Mutex::new(vec![0; info.piece_count().div_ceil(8)])),
+        })
+    }
+
+    pub fn with_backend(mut self, backend: IoBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Set aside the space for each file like this when it's first written to.
+    pub fn with_preallocate(mut self, preallocate: Preallocate) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
+
+    fn layout(&self) -> Layout {
+        self.layout.lock().unwrap().clone()
+    }
+
+    pub fn files(&self) -> Arc<[FileEntry]> {
+        self.layout().files
+    }
+
+    /// Delete the torrent's files, and the directories under `root` they were in if that
+    /// leaves them empty. Files which were never written are skipped.
+    pub async fn delete(&self, root: &Path) -> anyhow::Result<()> {
+        let _io = self.io.write().await;
+        let layout = self.layout();
+        for file in layout.files.iter() {
+            paths::check_no_symlinks(&layout.root, &file.path).await?;
+            match fs::remove_file(&file.path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            remove_empty_dirs(&file.path, root).await;
+        }
+
+        Ok(())
+    }
+
+    /// Move the torrent's files to the same places under `root` instead of the download
+    /// directory, and read and write them there from then on. Reads and writes wait
+    /// until the move is done. Files are hard linked to their new paths where they can
+    /// be and copied where they can't, as when `root` is on another file system, and
+    /// files never written are skipped. If a file can't be moved, those already moved
+    /// are moved back.
+    pub async fn relocate(&self, root: &Path) -> anyhow::Result<()> {
+        let _io = self.io.write().await;
+        let layout = self.layout();
+        if layout.root == root {
+            return Ok(());
+        }
+        let files: Vec<_> = layout
+            .files
+            .iter()
+            .map(|file| {
+                let relative = file
+                    .path
+                    .strip_prefix(&layout.root)
+                    .expect("Files are under the root");
+                FileEntry {
+                    path: root.join(relative),
+                    ..file.clone()
+                }
+            })
+            .collect();
+
+        let mut moved = Vec::new();
+        for (from, to) in layout.files.iter().zip(&files) {
+            let result = async {
+                paths::check_no_symlinks(&layout.root, &from.path).await?;
+                paths::check_no_symlinks(root, &to.path).await?;
+                move_file(&from.path, &to.path).await
+            }
+            .await;
+            match result {
+                Ok(true) => moved.push((&from.path, &to.path)),
+                Ok(false) => {}
+                Err(e) => {
+                    for (from, to) in moved.into_iter().rev() {
+                        if let Err(e) = move_file(to, from).await {
+                            warn!("Couldn't move {} back: {}", to.display(), e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        for (from, _) in &moved {
+            remove_empty_dirs(from, &layout.root).await;
+        }
+
+        let mut allocated = self.allocated.lock().unwrap();
+        for (from, to) in layout.files.iter().zip(&files) {
+            if allocated.remove(&from.path) {
+                allocated.insert(to.path.clone());
+            }
+        }
+        *self.layout.lock().unwrap() = Layout {
+            root: root.to_path_buf(),
+            files: files.into(),
+        };
+
+        Ok(())
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.total_length.div_ceil(self.piece_length) as usize
+    }
+
+    /// The file a piece starts in.
+    pub fn piece_file(&self, idx: usize) -> Option<FileEntry> {
+        let (begin, end) = self.piece_bounds(idx);
+        spans(&self.files(), begin, end)
+            .next()
+            .map(|(file, _, _, _)| file.clone())
+    }
+
+    /// The pieces holding any of the content of the file at `index`.
+    pub fn file_pieces(&self, index: usize) -> Range<usize> {
+        let file = &self.files()[index];
+        if file.length == 0 {
+            return 0..0;
+        }
+        let first = file.offset / self.piece_length;
+        let end = (file.offset + file.length).div_ceil(self.piece_length);
+        first as usize..end as usize
+    }
+
+    /// How much of each file has been saved, in the order of [`Storage::files`].
+    pub fn file_progress(&self) -> Vec<FileProgress> {
+        let saved = self.saved.lock().unwrap();
+        self.files()
+            .iter()
+            .enumerate()
+            .map(|(index, file)| FileProgress {
+                path: file.path.clone(),
+                length: file.length,
+                saved: self
+                    .file_pieces(index)
+                    .filter(|&idx| saved.has_piece(idx))
+                    .map(|idx| {
+                        let (begin, end) = self.piece_bounds(idx);
+                        end.min(file.offset + file.length) - begin.max(file.offset)
+                    })
+                    .sum(),
+            })
+            .collect()
+    }
+
+    /// The files which saving piece `idx` finished, by index: those it holds some of
+    /// whose other pieces are all saved.
+    pub fn files_completed_by(&self, idx: usize) -> Vec<usize> {
+        let saved = self.saved.lock().unwrap();
+        (0..self.files().len())
+            .filter(|&index| {
+                let mut pieces = self.file_pieces(index);
+                pieces.contains(&idx) && pieces.all(|p| saved.has_piece(p))
+            })
+            .collect()
+    }
+
+    /// Each piece's priority, the highest of the files it overlaps, given the files'
+    /// priorities in order. Files without a priority are normal priority.
+    pub fn piece_priorities(&self, file_priorities: &[Priority]) -> Vec<Priority> {
+        (0..self.piece_count())
+            .map(|idx| {
+                let (begin, end) = self.piece_bounds(idx);
+                self.files()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, f)| f.offset < end && f.offset + f.length > begin)
+                    .map(|(i, _)| file_priorities.get(i).copied().unwrap_or_default())
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Byte range of the torrent's content covered by a piece.
+    fn piece_bounds(&self, idx: usize) -> (u64, u64) {
+        let begin = idx as u64 * self.piece_length;
+        let end = (begin + self.piece_length).min(self.total_length);
+        (begin, end)
+    }
+
+    /// Write a piece to its files, unless it's been written already, as happens when
+    /// a piece is delivered twice. Returns whether it was written. A write which fails
+    /// part way through can be tried again.
+    pub async fn write_piece(&self, idx: usize, bytes: &[u8]) -> anyhow::Result<bool> {
+        if self.saved.lock().unwrap().has_piece(idx) {
+            return Ok(false);
+        }
+        self.read_ahead.lock().unwrap().remove(idx);
+        let _io = self.io.read().await;
+        let layout = self.layout();
+        let (begin, end) = self.piece_bounds(idx);
+        for (file, offset, len, at) in spans(&layout.files, begin, end) {
+            paths::check_no_symlinks(&layout.root, &file.path).await?;
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            let mut f = paths::open_no_follow(
+                &file.path,
+                OpenOptions::new().write(true).create(true).truncate(false),
+            )
+            .await?;
+            if self.allocated.lock().unwrap().insert(file.path.clone()) {
+                f = self.allocate(f, file).await.inspect_err(|_| {
+                    self.allocated.lock().unwrap().remove(&file.path);
+                })?;
+            }
+            let data = &bytes[at..at + len];
+            match self.backend {
+                IoBackend::Tokio => {
+                    f.seek(SeekFrom::Start(offset)).await?;
+                    f.write_all(data).await?;
+                }
+                #[cfg(feature = "io-uring")]
+                IoBackend::IoUring => {
+                    uring::write_at(f.into_std().await, offset, data.to_vec()).await?;
+                }
+            }
+        }
+        self.saved.lock().unwrap().set_piece(idx);
+
+        Ok(true)
+    }
+
+    /// Create the torrent's empty files, which no piece is written to, returning their
+    /// indices in [`Storage::files`].
+    pub async fn create_empty_files(&self) -> anyhow::Result<Vec<usize>> {
+        let _io = self.io.read().await;
+        let layout = self.layout();
+        let mut created = Vec::new();
+        for (index, file) in layout.files.iter().enumerate() {
+            if file.length > 0 {
+                continue;
+            }
+            paths::check_no_symlinks(&layout.root, &file.path).await?;
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            paths::open_no_follow(
+                &file.path,
+                OpenOptions::new().write(true).create(true).truncate(false),
+            )
+            .await?;
+            created.push(index);
+        }
+
+        Ok(created)
+    }
+
+    /// Record a piece as saved without writing it, as it was found intact on disk or
+    /// was passed on somewhere other than the files.
+    pub fn mark_saved(&self, idx: usize) {
+        self.saved.lock().unwrap().set_piece(idx);
+    }
+
+    /// Forget that a piece was saved, e.g. as it was found corrupt on disk, so it's
+    /// written again when it's downloaded again.
+    pub fn forget(&self, idx: usize) {
+        self.saved.lock().unwrap().unset_piece(idx);
+    }
+
+    /// The pieces saved, as a bitfield.
+    pub fn saved(&self) -> Vec<u8> {
+        self.saved.lock().unwrap().clone()
+    }
+
+    async fn allocate(&self, f: fs::File, file: &FileEntry) -> anyhow::Result<fs::File> {
+        let (f, length, mode) = (f.into_std().await, file.length, self.preallocate);
+        let f = tokio::task::spawn_blocking(move || alloc::allocate(&f, length, mode).map(|_| f))
+            .await??;
+        Ok(fs::File::from_std(f))
+    }
+
+    pub async fn read_piece(&self, idx: usize) -> anyhow::Result<Vec<u8>> {
+        let (begin, end) = self.piece_bounds(idx);
+        self.read_range(begin, end).await
+    }
+
+    /// Read `length` bytes starting `begin` bytes into a piece. Once a piece's blocks
+    /// are being read in order, the whole piece is read and kept for the reads to come.
+    pub async fn read_block(
+        &self,
+        idx: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let (piece_begin, piece_end) = self.piece_bounds(idx);
+        let end = begin + length;
+        if piece_begin + end as u64 > piece_end {
+            return Err(anyhow!("Block extends past the end of piece {}", idx));
+        }
+
+        let sequential = {
+            let mut read_ahead = self.read_ahead.lock().unwrap();
+            if let Some(piece) = read_ahead.get(idx) {
+                return Ok(piece[begin..end].to_vec());
+            }
+            read_ahead.sequential(idx, begin, end)
+        };
+        let piece_length = (piece_end - piece_begin) as usize;
+        if sequential && piece_length <= READ_AHEAD_BYTES {
+            let piece = Arc::new(self.read_range(piece_begin, piece_end).await?);
+            let block = piece[begin..end].to_vec();
+            self.read_ahead.lock().unwrap().insert(idx, piece);
+            return Ok(block);
+        }
+
+        self.read_range(piece_begin + begin as u64, piece_begin + end as u64)
+            .await
+    }
+
+    /// Read the torrent's content from `begin` to `end`.
+    async fn read_range(&self, begin: u64, end: u64) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0; (end - begin) as usize];
+        let _io = self.io.read().await;
+        let layout = self.layout();
+        for (file, offset, len, at) in spans(&layout.files, begin, end) {
+            paths::check_no_symlinks(&layout.root, &file.path).await?;
+            let mut f = paths::open_no_follow(&file.path, OpenOptions::new().read(true)).await?;
+            match self.backend {
+                IoBackend::Tokio => {
+                    f.seek(SeekFrom::Start(offset)).await?;
+                    f.read_exact(&mut buf[at..at + len]).await?;
+                }
+                #[cfg(feature = "io-uring")]
+                IoBackend::IoUring => {
+                    let data = uring::read_at(f.into_std().await, offset, len).await?;
+                    buf[at..at + len].copy_from_slice(&data);
+                }
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+/// The parts of `files` which hold the content from `begin` to `end`, as the file, the
+/// offset within it, the number of bytes and where they start after `begin`. Padding
+/// files have no parts, so their content is left as zeroes.
+fn spans(
+    files: &[FileEntry],
+    begin: u64,
+    end: u64,
+) -> impl Iterator<Item = (&FileEntry, u64, usize, usize)> {
+    files
+        .iter()
+        .filter(move |f| f.offset < end && f.offset + f.length > begin)
+        .map(move |f| {
+            let start = begin.max(f.offset);
+            let stop = end.min(f.offset + f.length);
+            let at = (start - begin) as usize;
+            (f, start - f.offset, (stop - start) as usize, at)
+        })
+}
+
+/// Move the file at `from` to `to`, which mustn't exist, returning whether there was a
+/// file to move.
+async fn move_file(from: &Path, to: &Path) -> anyhow::Result<bool> {
+    if fs::symlink_metadata(from).await.is_err() {
+        return Ok(false);
+    }
+    if fs::symlink_metadata(to).await.is_ok() {
+        return Err(anyhow!("{} already exists", to.display()));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    if fs::hard_link(from, to).await.is_err() {
+        fs::copy(from, to).await?;
+    }
+    fs::remove_file(from).await?;
+
+    Ok(true)
+}
+
+/// Remove the directories `path` was in, up to but not including `root`, as far as
+/// they're empty.
+async fn remove_empty_dirs(path: &Path, root: &Path) {
+    let mut dir = path.parent();
+    while let Some(parent) = dir.filter(|dir| dir.starts_with(root) && *dir != root) {
+        if fs::remove_dir(parent).await.is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent_file::{File, Info, TorrentFile};
+    use serde_bytes::ByteBuf;
+
+    fn multi_file_torrent() -> Torrent {
+        let file = |name: &str, length| File {
+            path: vec!["dir".into(), name.into()],
+            length,
+            md5sum: None,
+            attr: None,
+        };
+        TorrentFile {
+            info: Info {
+                name: "multi".into(),
+                pieces: ByteBuf::from(vec![0; 40]),
+                piece_length: 4,
+                md5sum: None,
+                length: None,
+                files: Some(vec![file("a", 3), file("b", 3)]),
+                private: None,
+                path: None,
+                root_hash: None,
+                meta_version: None,
+                file_tree: None,
+            },
+            announce: None,
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            piece_layers: None,
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn pieces_spanning_files() {
+        let root = std::env::temp_dir().join(format!("storage-test-{}", std::process::id()));
+        let storage = Storage::new(&multi_file_torrent(), &root).unwrap();
+        assert_eq!(storage.piece_count(), 2);
+
+        storage.write_piece(1, b"ef").await.unwrap();
+        storage.write_piece(0, b"abcd").await.unwrap();
+
+        let a = root.join("multi").join("dir").join("a");
+        let b = root.join("multi").join("dir").join("b");
+        assert_eq!(fs::read(&a).await.unwrap(), b"abc");
+        assert_eq!(fs::read(&b).await.unwrap(), b"def");
+        assert_eq!(storage.read_piece(0).await.unwrap(), b"abcd");
+        assert_eq!(storage.read_block(0, 2, 2).await.unwrap(), b"cd");
+        assert!(storage.read_block(1, 1, 2).await.is_err());
+        assert_eq!(
+            storage.piece_priorities(&[Priority::Skip]),
+            vec![Priority::Normal, Priority::Normal]
+        );
+        assert_eq!(
+            storage.piece_priorities(&[Priority::High, Priority::Skip]),
+            vec![Priority::High, Priority::Skip]
+        );
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn relocate_files_while_in_use() {
+        let root = std::env::temp_dir().join(format!("relocate-test-{}", std::process::id()));
+        let (from, to) = (root.join("from"), root.join("to"));
+        let storage = Storage::new(&multi_file_torrent(), &from).unwrap();
+        // Only the first file is written before the move.
+        storage.write_piece(0, b"abcd").await.unwrap();
+        fs::create_dir_all(to.join("multi").join("dir"))
+            .await
+            .unwrap();
+        fs::write(to.join("multi").join("dir").join("b"), b"xyz")
+            .await
+            .unwrap();
+
+        // Files already at the destination aren't overwritten, and nothing moves.
+        assert!(storage.relocate(&to).await.is_err());
+        assert_eq!(storage.read_piece(0).await.unwrap(), b"abcd");
+        fs::remove_file(to.join("multi").join("dir").join("b"))
+            .await
+            .unwrap();
+
+        storage.relocate(&to).await.unwrap();
+        assert!(!from.join("multi").exists());
+        assert_eq!(
+            storage.files()[0].path,
+            to.join("multi").join("dir").join("a")
+        );
+        assert_eq!(storage.read_piece(0).await.unwrap(), b"abcd");
+        storage.write_piece(1, b"ef").await.unwrap();
+        assert_eq!(
+            fs::read(to.join("multi").join("dir").join("b"))
+                .await
+                .unwrap(),
+            b"def"
+        );
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dont_follow_symlinked_files() {
+        let root = std::env::temp_dir().join(format!("symlink-test-{}", std::process::id()));
+        let outside = root.join("outside");
+        let dir = root.join("downloads").join("multi").join("dir");
+        fs::create_dir_all(&dir).await.unwrap();
+        fs::write(&outside, b"secret").await.unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("a")).unwrap();
+        let storage = Storage::new(&multi_file_torrent(), &root.join("downloads")).unwrap();
+
+        assert!(storage.read_piece(0).await.is_err());
+        assert!(storage.write_piece(0, b"abcd").await.is_err());
+        assert_eq!(fs::read(&outside).await.unwrap(), b"secret");
+        // Nor when the symlink turns up after it's been checked.
+        let link = dir.join("a");
+        assert!(paths::open_no_follow(&link, OpenOptions::new().read(true))
+            .await
+            .is_err());
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[test]
+    fn track_each_files_progress() {
+        let storage = Storage::new(&multi_file_torrent(), Path::new("/downloads")).unwrap();
+        assert_eq!(storage.file_pieces(0), 0..1);
+        assert_eq!(storage.file_pieces(1), 0..2);
+
+        storage.mark_saved(1);
+        assert!(storage.files_completed_by(1).is_empty());
+        let progress = storage.file_progress();
+        assert_eq!((progress[0].saved, progress[1].saved), (0, 2));
+        assert!(!progress[1].is_complete());
+
+        storage.mark_saved(0);
+        assert_eq!(storage.files_completed_by(0), vec![0, 1]);
+        let progress = storage.file_progress();
+        assert!(progress.iter().all(|file| file.is_complete()));
+        assert_eq!(progress[1].progress(), 1.0);
+    }
+
+    #[test]
+    fn refuse_torrents_with_unsafe_paths() {
+        let mut torrent = multi_file_torrent();
+        torrent.file.info.files.as_mut().unwrap()[1].path = vec!["..".into(), "escape".into()];
+        assert!(Storage::new(&torrent, Path::new("/downloads")).is_err());
+
+        let mut torrent = multi_file_torrent();
+        torrent.file.info.name = "..".into();
+        assert!(Storage::new(&torrent, Path::new("/downloads")).is_err());
+    }
+
+    #[tokio::test]
+    async fn delete_only_the_torrents_files() {
+        let root = std::env::temp_dir().join(format!("delete-test-{}", std::process::id()));
+        let storage = Storage::new(&multi_file_torrent(), &root).unwrap();
+        // Only the second file was written.
+        storage.write_piece(1, b"ef").await.unwrap();
+        fs::write(root.join("other"), b"keep").await.unwrap();
+
+        storage.delete(&root).await.unwrap();
+        assert!(!fs::try_exists(root.join("multi")).await.unwrap());
+        assert!(fs::try_exists(root.join("other")).await.unwrap());
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_ahead_in_order_reads() {
+        let root = std::env::temp_dir().join(format!("read-ahead-test-{}", std::process::id()));
+        let storage = Storage::new(&multi_file_torrent(), &root).unwrap();
+        storage.write_piece(0, b"abcd").await.unwrap();
+        let a = root.join("multi").join("dir").join("a");
+
+        // Out of order reads go to disk each time.
+        assert_eq!(storage.read_block(0, 2, 1).await.unwrap(), b"c");
+        fs::write(&a, b"xyz").await.unwrap();
+        assert_eq!(storage.read_block(0, 0, 1).await.unwrap(), b"x");
+
+        // The second in order read fetches the rest of the piece.
+        assert_eq!(storage.read_block(0, 1, 1).await.unwrap(), b"y");
+        fs::write(&a, b"abc").await.unwrap();
+        assert_eq!(storage.read_block(0, 2, 2).await.unwrap(), b"zd");
+
+        // A piece already written isn't written again, until it's forgotten, and
+        // writing it replaces what was read ahead.
+        assert!(!storage.write_piece(0, b"efgh").await.unwrap());
+        assert_eq!(storage.read_block(0, 0, 2).await.unwrap(), b"xy");
+        storage.forget(0);
+        assert!(storage.write_piece(0, b"efgh").await.unwrap());
+        assert_eq!(storage.read_block(0, 0, 2).await.unwrap(), b"ef");
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+//! Turning the file names in a torrent into paths under the download directory. The
+//! names come from whoever made the torrent, so every part of every path is checked
+//! before it's used, and files aren't read or written through symlinks already in the
+//! directory.
+
+use anyhow::anyhow;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, OpenOptions};
+
+/// The longest file or directory name most filesystems allow, in bytes.
+pub const MAX_COMPONENT_LENGTH: usize = 255;
+
+/// Characters Windows doesn't allow in file names, besides control characters.
+const WINDOWS_ILLEGAL: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names Windows reserves, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Check `component` can be used as a file or directory name without escaping the
+/// directory it's in, on any platform.
+pub fn check_component(component: &str) -> anyhow::Result<()> {
+    let reason = if component.is_empty() {
+        "it's empty"
+    } else if component.trim_end_matches(['.', ' ']).is_empty() {
+        // Windows drops trailing dots and spaces, so ". ." and the like mean "..".
+        "it names a directory by dots"
+    } else if component.contains(['/', '\\']) {
+        "it contains a path separator"
+    } else if component.contains('\0') {
+        "it contains a null byte"
+    } else if is_drive(component) {
+        "it starts with a drive letter"
+    } else if cfg!(windows) && component.contains(':') {
+        "it names an alternate data stream"
+    } else if is_reserved(component) {
+        "Windows reserves it for a device"
+    } else if component.len() > MAX_COMPONENT_LENGTH {
+        "it's too long"
+    } else {
+        return Ok(());
+    };
+
+    Err(anyhow!(
+        "Unsafe file name {:?} in torrent: {}",
+        component,
+        reason
+    ))
+}
+
+fn is_drive(component: &str) -> bool {
+    matches!(component.as_bytes(), [letter, b':', ..] if letter.is_ascii_alphabetic())
+}
+
+fn is_reserved(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or_default().trim_end();
+    RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+}
+
+/// `component` with the characters Windows doesn't allow in file names replaced by
+/// underscores, as are trailing dots and spaces, which Windows would drop.
+pub fn windows_name(component: &str) -> Cow<'_, str> {
+    let kept = component.trim_end_matches(['.', ' ']).len();
+    let illegal =
+        |(i, c): (usize, char)| i >= kept || c.is_control() || WINDOWS_ILLEGAL.contains(&c);
+    if !component.char_indices().any(illegal) {
+        return Cow::Borrowed(component);
+    }
+
+    component
+        .char_indices()
+        .map(|(i, c)| if illegal((i, c)) { '_' } else { c })
+        .collect()
+}
+
+/// The path under `base` given by the parts of a path in a torrent, checking each. On
+/// Windows, characters it doesn't allow are replaced.
+pub fn join(base: &Path, components: &[String]) -> anyhow::Result<PathBuf> {
+    if components.is_empty() {
+        return Err(anyhow!("File in torrent has no path"));
+    }
+    let mut path = base.to_path_buf();
+    for component in components {
+        check_component(component)?;
+        match cfg!(windows) {
+            true => path.push(windows_name(component).as_ref()),
+            false => path.push(component),
+        }
+    }
+
+    Ok(path)
+}
+
+/// Rename paths which collide with earlier ones, as happens when Windows has characters
+/// replaced in names which differed only by those, like `a?` and `a*`. A number is
+/// added to the name, before its extension. Windows ignores case, so this does too.
+pub fn disambiguate<'a>(paths: impl IntoIterator<Item = &'a mut PathBuf>) {
+    let key = |path: &Path| path.to_string_lossy().to_lowercase();
+    let mut seen = HashSet::new();
+    for path in paths {
+        if seen.insert(key(path)) {
+            continue;
+        }
+        let stem = path.file_stem().unwrap_or_default().to_owned();
+        let extension = path.extension().map(|e| e.to_owned());
+        for n in 1.. {
+            let mut name = OsString::from(&stem);
+            name.push(format!(" ({})", n));
+            if let Some(extension) = &extension {
+                name.push(".");
+                name.push(extension);
+            }
+            let renamed = path.with_file_name(name);
+            if seen.insert(key(&renamed)) {
+                *path = renamed;
+                break;
+            }
+        }
+    }
+}
+
+/// Check nothing which already exists between `root` and `path` is a symlink, so opening
+/// `path` can't reach outside `root`. `root` itself can be one.
+pub async fn check_no_symlinks(root: &Path, path: &Path) -> anyhow::Result<()> {
+    let relative = path
+        .strip_prefix(root)
+        .map_err(|_| anyhow!("{} isn't under {}", path.display(), root.display()))?;
+    let mut current = root.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match fs::symlink_metadata(&current).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(anyhow!("Refusing to follow symlink {}", current.display()))
+            }
+            Ok(_) => {}
+            // Nothing further down can exist either.
+            Err(e) if e.kind() == ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Open `path` with `options`, failing rather than following it if it's a symlink, so
+/// one put there since [`check_no_symlinks`] can't be followed either.
+pub async fn open_no_follow(path: &Path, options: &mut OpenOptions) -> io::Result<fs::File> {
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    options.open(path).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accept_ordinary_names() {
+        for name in [
+            "file.txt",
+            ".hidden",
+            "..hidden",
+            "a..b",
+            "Season 1",
+            "CONSOLE.txt",
+            "com10",
+            "ファイル",
+            "C",
+            &"a".repeat(MAX_COMPONENT_LENGTH),
+        ] {
+            assert!(check_component(name).is_ok(), "{}", name);
+        }
+        assert_eq!(check_component("file:stream").is_err(), cfg!(windows));
+    }
+
+    #[test]
+    fn reject_unsafe_names() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "...",
+            ". .",
+            ".. ",
+            "/",
+            "/etc",
+            "dir/file",
+            "..\\..\\Windows",
+            "dir\\file",
+            "file\0.txt",
+            "C:",
+            "c:file",
+            "C:\\Windows",
+            "CON",
+            "con",
+            "nul.txt",
+            "Aux.tar.gz",
+            "COM1",
+            "lpt9.log",
+            "PRN .txt",
+            &"a".repeat(MAX_COMPONENT_LENGTH + 1),
+        ] {
+            assert!(check_component(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn join_checked_components() {
+        let base = Path::new("/downloads/torrent");
+        let path = |parts: &[&str]| {
+            let parts: Vec<String> = parts.iter().map(|p| p.to_string()).collect();
+            join(base, &parts)
+        };
+
+        assert_eq!(
+            path(&["dir", "file"]).unwrap(),
+            Path::new("/downloads/torrent/dir/file")
+        );
+        assert!(path(&[]).is_err());
+        assert!(path(&["dir", "..", "..", "etc", "passwd"]).is_err());
+        assert!(path(&["/etc/passwd"]).is_err());
+        assert!(path(&["dir", ""]).is_err());
+    }
+
+    #[test]
+    fn disambiguate_colliding_names() {
+        let mut paths: Vec<PathBuf> = ["d/a_.txt", "d/a_.txt", "d/A_.TXT", "d/a_ (1).txt", "d/b"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        disambiguate(&mut paths);
+        assert_eq!(
+            paths,
+            [
+                "d/a_.txt",
+                "d/a_ (1).txt",
+                "d/A_ (2).TXT",
+                "d/a_ (1) (1).txt",
+                "d/b"
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn replace_characters_windows_doesnt_allow() {
+        assert_eq!(windows_name("file.txt"), "file.txt");
+        assert!(matches!(windows_name("Season 1"), Cow::Borrowed(_)));
+        assert_eq!(windows_name("Movie: The Sequel?"), "Movie_ The Sequel_");
+        assert_eq!(windows_name("a<b>c\"d|e*f"), "a_b_c_d_e_f");
+        assert_eq!(windows_name("tab\there"), "tab_here");
+        assert_eq!(windows_name("ends with dots.."), "ends with dots__");
+        assert_eq!(windows_name("ends with space "), "ends with space_");
+        assert_eq!(windows_name("ファイル?"), "ファイル_");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuse_symlinks_under_the_root() {
+        let root = std::env::temp_dir().join(format!("paths-test-{}", std::process::id()));
+        let outside = root.join("outside");
+        let downloads = root.join("downloads");
+        fs::create_dir_all(&outside).await.unwrap();
+        fs::create_dir_all(downloads.join("real")).await.unwrap();
+        std::os::unix::fs::symlink(&outside, downloads.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("f"), downloads.join("real/file")).unwrap();
+
+        assert!(
+            check_no_symlinks(&downloads, &downloads.join("real/new/file"))
+                .await
+                .is_ok()
+        );
+        assert!(check_no_symlinks(&downloads, &downloads.join("link/file"))
+            .await
+            .is_err());
+        assert!(check_no_symlinks(&downloads, &downloads.join("real/file"))
+            .await
+            .is_err());
+        // The root being a symlink is up to the user.
+        std::os::unix::fs::symlink(&downloads, root.join("root-link")).unwrap();
+        let linked_root = root.join("root-link");
+        assert!(check_no_symlinks(&linked_root, &linked_root.join("real/x"))
+            .await
+            .is_ok());
+        assert!(check_no_symlinks(&downloads, &outside.join("file"))
+            .await
+            .is_err());
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! Reading and writing files with io_uring. One thread owns the ring, submitting the
+//! operations sent to it in batches and replying to each as it completes.
+
+use io_uring::{opcode, types, IoUring};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{mpsc, OnceLock};
+use tokio::sync::oneshot;
+use tracing::{error, warn};
+
+/// Most operations in flight at once, and the size of the submission queue.
+const ENTRIES: usize = 256;
+
+static RING: OnceLock<Option<mpsc::Sender<Op>>> = OnceLock::new();
+
+enum Kind {
+    Read,
+    Write,
+}
+
+struct Op {
+    kind: Kind,
+    /// Kept open until the operation completes.
+    file: File,
+    offset: u64,
+    buf: Vec<u8>,
+    /// How much of `buf` has been read or written. Reads and writes can complete
+    /// partially, and are submitted again for the rest.
+    done: usize,
+    reply: oneshot::Sender<io::Result<Vec<u8>>>,
+}
+
+/// The ring's thread, started the first time it's needed.
+fn ring() -> Option<&'static mpsc::Sender<Op>> {
+    RING.get_or_init(|| {
+        let ring = match IoUring::new(ENTRIES as u32) {
+            Ok(ring) => ring,
+            Err(e) => {
+                warn!("io_uring isn't available: {}", e);
+                return None;
+            }
+        };
+        let (tx, rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("io-uring".into())
+            .spawn(move || run(ring, rx))
+            .ok()?;
+        Some(tx)
+    })
+    .as_ref()
+}
+
+/// Whether the kernel lets us set up a ring.
+pub fn available() -> bool {
+    ring().is_some()
+}
+
+pub async fn read_at(file: File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    submit(Kind::Read, file, offset, vec![0; len]).await
+}
+
+pub async fn write_at(file: File, offset: u64, data: Vec<u8>) -> io::Result<()> {
+    submit(Kind::Write, file, offset, data).await.map(|_| ())
+}
+
+async fn submit(kind: Kind, file: File, offset: u64, buf: Vec<u8>) -> io::Result<Vec<u8>> {
+    let stopped = || io::Error::other("io_uring thread stopped");
+    let ring = ring().ok_or_else(|| io::Error::other("io_uring isn't available"))?;
+    let (reply, rx) = oneshot::channel();
+    let op = Op {
+        kind,
+        file,
+        offset,
+        buf,
+        done: 0,
+        reply,
+    };
+    ring.send(op).map_err(|_| stopped())?;
+    rx.await.map_err(|_| stopped())?
+}
+
+fn run(mut ring: IoUring, ops: mpsc::Receiver<Op>) {
+    // Operations in flight, indexed by their entries' user data.
+    let mut in_flight: Vec<Option<Op>> = Vec::new();
+    let mut count = 0;
+    loop {
+        if count == 0 {
+            match ops.recv() {
+                Ok(op) => push(&mut ring, &mut in_flight, op),
+                Err(_) => return,
+            }
+            count += 1;
+        }
+        while count < ENTRIES {
+            match ops.try_recv() {
+                Ok(op) => push(&mut ring, &mut in_flight, op),
+                Err(_) => break,
+            }
+            count += 1;
+        }
+
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("io_uring failed: {}", e);
+                fail(ring, in_flight, e);
+                return;
+            }
+        }
+
+        let completed: Vec<_> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+            .collect();
+        for (slot, result) in completed {
+            let mut op = in_flight[slot]
+                .take()
+                .expect("Completion for unknown operation");
+            count -= 1;
+            if result < 0 {
+                let _ = op.reply.send(Err(io::Error::from_raw_os_error(-result)));
+                continue;
+            }
+            op.done += result as usize;
+            if op.done == op.buf.len() {
+                let _ = op.reply.send(Ok(op.buf));
+            } else if result == 0 {
+                let _ = op.reply.send(Err(io::ErrorKind::UnexpectedEof.into()));
+            } else {
+                push(&mut ring, &mut in_flight, op);
+                count += 1;
+            }
+        }
+    }
+}
+
+/// Fail every operation in flight with `e`. The kernel may still be using their files
+/// and buffers, so those are leaked rather than freed, along with the ring.
+fn fail(ring: IoUring, in_flight: Vec<Option<Op>>, e: io::Error) {
+    for op in in_flight.into_iter().flatten() {
+        let Op {
+            file, buf, reply, ..
+        } = op;
+        std::mem::forget((file, buf));
+        let _ = reply.send(Err(io::Error::new(e.kind(), e.to_string())));
+    }
+    std::mem::forget(ring);
+}
+
+/// Queue the rest of an operation, which stays in `in_flight` until it completes.
+fn push(ring: &mut IoUring, in_flight: &mut Vec<Option<Op>>, mut op: Op) {
+    let slot = match in_flight.iter().position(Option::is_none) {
+        Some(slot) => slot,
+        None => {
+            in_flight.push(None);
+            in_flight.len() - 1
+        }
+    };
+    let fd = types::Fd(op.file.as_raw_fd());
+    let offset = op.offset + op.done as u64;
+    let rest = &mut op.buf[op.done..];
+    let (ptr, len) = (rest.as_mut_ptr(), rest.len() as u32);
+    let entry = match op.kind {
+        Kind::Read => opcode::Read::new(fd, ptr, len).offset(offset).build(),
+        Kind::Write => opcode::Write::new(fd, ptr, len).offset(offset).build(),
+    };
+    in_flight[slot] = Some(op);
+
+    // Safety: the file and buffer live in `in_flight` until the operation completes,
+    // and moving the `Op` there doesn't move the buffer's contents. There are never
+    // more than `ENTRIES` operations queued, so the queue has room.
+    unsafe {
+        ring.submission()
+            .push(&entry.user_data(slot as u64))
+            .expect("io_uring submission queue is full");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_and_write() {
+        if !available() {
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("uring-test-{}", std::process::id()));
+        let file = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .unwrap()
+        };
+
+        write_at(file(), 2, b"cd".to_vec()).await.unwrap();
+        write_at(file(), 0, b"ab".to_vec()).await.unwrap();
+        assert_eq!(read_at(file(), 1, 3).await.unwrap(), b"bcd");
+        assert_eq!(
+            read_at(file(), 2, 4).await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
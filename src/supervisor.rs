@@ -0,0 +1,783 @@
+//! Keeping track of a torrent's peer sessions, so they can be stopped together when the
+//! torrent is done with them, and so each session's exit is logged. Sessions which end
+//! are replaced with peers we know of but haven't connected to yet, and operators can
+//! disconnect or ban particular peers. Peers which time out when dialed are retried a
+//! few times, and after the first few timeouts we ask for help getting through their NAT.
+//!
+//! Candidates are pooled from every tracker and other source, and dialed best first:
+//! peers which sent us the most before, then peers we've connected to before, then
+//! those we heard of most recently. Peers which keep failing to connect go last. They're
+//! scored as they're heard of, and kept in order, so the best is found straight away.
+
+use crate::choker::PeerHistory;
+use crate::peer::{ConnectTimeout, PeerData};
+use futures::future::{AbortHandle, Abortable, Aborted, BoxFuture};
+use futures::FutureExt;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+
+/// Runs peer sessions as tasks. Sessions still running when the last clone of the
+/// supervisor is dropped are aborted.
+#[derive(Debug, Clone, Default)]
+pub struct Supervisor {
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+/// Dial timeouts in a row before we ask for help reaching a peer.
+const HOLEPUNCH_AFTER: u32 = 2;
+/// Most times in a row we dial a peer which times out.
+const MAX_DIAL_ATTEMPTS: u32 = 3;
+/// How long since we last heard of a candidate before it's assumed to have gone.
+const STALE_CANDIDATE: Duration = Duration::from_secs(30 * 60);
+
+/// Starts a session with a peer we know of.
+type DialFn = dyn Fn(PeerData) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync;
+
+struct Dialer(Arc<DialFn>);
+
+impl std::fmt::Debug for Dialer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Dialer")
+    }
+}
+
+/// Tries another way of reaching a peer which keeps timing out.
+struct Unreachable(Arc<dyn Fn(SocketAddr) + Send + Sync>);
+
+impl std::fmt::Debug for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unreachable")
+    }
+}
+
+/// A peer we know of but aren't connected to.
+#[derive(Debug)]
+struct Candidate {
+    peer: PeerData,
+    /// When a tracker or other source last told us about the peer.
+    last_seen: Instant,
+}
+
+/// How promising a candidate is to dial, higher being better: by dials which failed,
+/// then what it sent us before, whether we've connected to it and when we last heard
+/// of it.
+type Score = (Reverse<u32>, u64, bool, Instant);
+
+/// Where a candidate is in [`Candidates`]: its score, then the order it was first
+/// heard of in, so the first of equally good candidates goes first.
+type Rank = (Score, Reverse<u64>);
+
+/// The peers we know of but aren't connected to, best last.
+#[derive(Debug, Default)]
+struct Candidates {
+    ranked: BTreeMap<Rank, Candidate>,
+    ranks: HashMap<SocketAddr, Rank>,
+    next: u64,
+}
+
+impl Candidates {
+    fn score(peer: &PeerData, last_seen: Instant, history: Option<&PeerHistory>) -> Score {
+        let ip = peer.addr().ip();
+        let dials = history.map(|h| h.dials(ip)).unwrap_or_default();
+        let given = history.map_or(0, |h| h.given(ip));
+        (Reverse(dials.failures), given, dials.connected, last_seen)
+    }
+
+    fn len(&self) -> usize {
+        self.ranked.len()
+    }
+
+    fn contains(&self, addr: SocketAddr) -> bool {
+        self.ranks.contains_key(&addr)
+    }
+
+    /// Add a peer, or note a peer we know of as heard of again at `last_seen`.
+    fn insert(&mut self, peer: PeerData, last_seen: Instant, history: Option<&PeerHistory>) {
+        let order = match self.remove(peer.addr()) {
+            Some((order, _)) => order,
+            None => {
+                self.next += 1;
+                Reverse(self.next)
+            }
+        };
+        let rank = (Self::score(&peer, last_seen, history), order);
+        self.ranks.insert(peer.addr(), rank);
+        self.ranked.insert(rank, Candidate { peer, last_seen });
+    }
+
+    fn remove(&mut self, addr: SocketAddr) -> Option<(Reverse<u64>, Candidate)> {
+        let rank = self.ranks.remove(&addr)?;
+        let candidate = self.ranked.remove(&rank)?;
+        Some((rank.1, candidate))
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Candidate) -> bool) {
+        let ranks = &mut self.ranks;
+        self.ranked.retain(|_, c| {
+            let kept = keep(c);
+            if !kept {
+                ranks.remove(&c.peer.addr());
+            }
+            kept
+        });
+    }
+
+    fn pop_best(&mut self) -> Option<Candidate> {
+        let (_, candidate) = self.ranked.pop_last()?;
+        self.ranks.remove(&candidate.peer.addr());
+        Some(candidate)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    next_id: u64,
+    running: HashMap<u64, (SocketAddr, AbortHandle)>,
+    /// Peers we know of but aren't connected to.
+    candidates: Candidates,
+    /// What we know of peers from earlier sessions, to choose candidates by.
+    history: Option<Arc<PeerHistory>>,
+    /// How many sessions to keep running, dialing candidates as sessions end.
+    target: usize,
+    dialer: Option<Dialer>,
+    unreachable: Option<Unreachable>,
+    /// How many times in a row dialing each peer has timed out, and when it last did.
+    timeouts: HashMap<SocketAddr, (u32, Instant)>,
+    stopped: bool,
+    /// Set while the torrent is paused. Unlike being stopped, this outlasts
+    /// [`Supervisor::start`].
+    paused: bool,
+    /// Addresses we won't talk to, until when, or ever if `None`.
+    bans: HashMap<IpAddr, Option<Instant>>,
+    /// Peers we dropped, which aren't taken as candidates again until when.
+    backoffs: HashMap<SocketAddr, Instant>,
+}
+
+impl Sessions {
+    fn is_banned(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.bans
+            .retain(|_, until| until.is_none_or(|until| until > now));
+        self.bans.contains_key(&ip)
+    }
+
+    fn is_backed_off(&mut self, addr: SocketAddr) -> bool {
+        let now = Instant::now();
+        self.backoffs.retain(|_, until| *until > now);
+        self.backoffs.contains_key(&addr)
+    }
+
+    fn is_known(&self, addr: SocketAddr) -> bool {
+        self.running.values().any(|(running, _)| *running == addr) || self.candidates.contains(addr)
+    }
+
+    /// Take the best candidate to dial, skipping those we haven't heard of for a while.
+    /// Of equally good candidates, the one we heard of first is taken.
+    fn pop_candidate(&mut self) -> Option<PeerData> {
+        loop {
+            let candidate = self.candidates.pop_best()?;
+            if candidate.last_seen.elapsed() < STALE_CANDIDATE {
+                return Some(candidate.peer);
+            }
+        }
+    }
+}
+
+impl Drop for Sessions {
+    fn drop(&mut self) {
+        for (_, abort) in self.running.values() {
+            abort.abort();
+        }
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A supervisor which keeps `target` sessions running while it has candidates,
+    /// starting them with `dial`.
+    #[cfg(test)]
+    pub fn dialing<F>(target: usize, dial: F) -> Self
+    where
+        F: Fn(PeerData) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync + 'static,
+    {
+        let supervisor = Self::new();
+        supervisor.start(target, dial);
+        supervisor
+    }
+
+    /// Keep `target` sessions running while there are candidates, starting them with
+    /// `dial`, including after [`Supervisor::abort_all`].
+    pub fn start<F>(&self, target: usize, dial: F)
+    where
+        F: Fn(PeerData) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync + 'static,
+    {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.target = target;
+        sessions.dialer = Some(Dialer(Arc::new(dial)));
+        sessions.stopped = false;
+    }
+
+    /// Call `unreachable` with peers which keep timing out when we dial them, so it can
+    /// try to reach them some other way.
+    pub fn on_unreachable<F>(&self, unreachable: F)
+    where
+        F: Fn(SocketAddr) + Send + Sync + 'static,
+    {
+        self.sessions.lock().unwrap().unreachable = Some(Unreachable(Arc::new(unreachable)));
+    }
+
+    /// Choose which candidates to dial first by what `history` says of them.
+    pub fn score_with(&self, history: Arc<PeerHistory>) {
+        self.sessions.lock().unwrap().history = Some(history);
+    }
+
+    /// Remember peers to dial, ignoring those we're already connected to, and dial as
+    /// many as we're short of the target. Peers we already know of are noted as just
+    /// heard of again.
+    pub fn add_candidates(&self, peers: impl IntoIterator<Item = PeerData>) {
+        {
+            let now = Instant::now();
+            let mut sessions = self.sessions.lock().unwrap();
+            // Forget those we haven't heard of for a while, so they aren't counted.
+            sessions
+                .candidates
+                .retain(|c| now.duration_since(c.last_seen) < STALE_CANDIDATE);
+            for peer in peers {
+                let addr = peer.addr();
+                let wanted = sessions.candidates.contains(addr)
+                    || (!sessions.is_known(addr)
+                        && !sessions.is_banned(addr.ip())
+                        && !sessions.is_backed_off(addr));
+                if wanted {
+                    let sessions = &mut *sessions;
+                    let history = sessions.history.as_deref();
+                    sessions.candidates.insert(peer, now, history);
+                }
+            }
+        }
+        self.fill();
+    }
+
+    /// How many peers are waiting to be dialed.
+    pub fn candidates(&self) -> usize {
+        self.sessions.lock().unwrap().candidates.len()
+    }
+
+    /// Dial candidates until the target number of sessions are running.
+    fn fill(&self) {
+        loop {
+            let (peer, dial) = {
+                let mut sessions = self.sessions.lock().unwrap();
+                let dial = match &sessions.dialer {
+                    Some(Dialer(dial)) => Arc::clone(dial),
+                    None => return,
+                };
+                if sessions.stopped || sessions.paused || sessions.running.len() >= sessions.target
+                {
+                    return;
+                }
+                match sessions.pop_candidate() {
+                    Some(peer) => (peer, dial),
+                    None => return,
+                }
+            };
+            self.run(peer.addr(), dial(peer.clone()), Some(peer));
+        }
+    }
+
+    /// Dial `peer` straight away, ahead of the candidates, unless we're already talking
+    /// to it or have as many sessions as we want.
+    pub fn dial(&self, peer: PeerData) {
+        let dial = {
+            let sessions = self.sessions.lock().unwrap();
+            let connected = sessions
+                .running
+                .values()
+                .any(|(addr, _)| *addr == peer.addr());
+            let full = sessions.running.len() >= sessions.target;
+            match &sessions.dialer {
+                Some(Dialer(dial))
+                    if !sessions.stopped && !sessions.paused && !connected && !full =>
+                {
+                    Arc::clone(dial)
+                }
+                _ => return,
+            }
+        };
+        self.run(peer.addr(), dial(peer.clone()), Some(peer));
+    }
+
+    /// Run `session`, with the peer at `addr`, until it ends or is aborted.
+    pub fn spawn<F>(&self, addr: SocketAddr, session: F)
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.run(addr, session, None);
+    }
+
+    /// Run `session`, noting whether it timed out if it's with a peer we `dialed`.
+    fn run<F>(&self, addr: SocketAddr, session: F, dialed: Option<PeerData>)
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let (abort, registration) = AbortHandle::new_pair();
+        let id = {
+            let mut sessions = self.sessions.lock().unwrap();
+            if sessions.is_banned(addr.ip()) {
+                debug!("Not talking to {}, as it's banned", addr);
+                return;
+            }
+            if sessions.paused {
+                debug!("Not talking to {}, as the torrent is paused", addr);
+                return;
+            }
+            let id = sessions.next_id;
+            sessions.next_id += 1;
+            sessions.running.insert(id, (addr, abort));
+            id
+        };
+
+        let sessions = Arc::downgrade(&self.sessions);
+        let session = AssertUnwindSafe(Abortable::new(session, registration)).catch_unwind();
+        tokio::spawn(async move {
+            let exit = session.await;
+            let timed_out = matches!(&exit, Ok(Ok(Err(e))) if e.is::<ConnectTimeout>());
+            replace(&sessions, id, dialed.map(|peer| (peer, timed_out)));
+            match exit {
+                Ok(Ok(Ok(()))) => debug!("Session with {} ended", addr),
+                Ok(Ok(Err(e))) => debug!("Session with {} ended: {}", addr, e),
+                Ok(Err(Aborted)) => debug!("Session with {} was stopped", addr),
+                Err(_) => error!("Session with {} panicked", addr),
+            }
+        });
+    }
+
+    /// How many sessions are running.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().running.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether as many sessions are running as we want.
+    pub fn is_full(&self) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.running.len() >= sessions.target
+    }
+
+    /// End the session with the peer at `addr`, returning whether there was one. The
+    /// peer may be dialed again if we hear of it again.
+    pub fn disconnect(&self, addr: SocketAddr) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let ids: Vec<_> = sessions
+            .running
+            .iter()
+            .filter(|(_, (running, _))| *running == addr)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in &ids {
+            if let Some((_, abort)) = sessions.running.remove(id) {
+                debug!("Disconnecting from {}", addr);
+                abort.abort();
+            }
+        }
+        drop(sessions);
+        self.fill();
+
+        !ids.is_empty()
+    }
+
+    /// End the session with the peer at `addr`, and don't take it as a candidate again
+    /// for `duration`. Unlike a ban, other peers at the same IP aren't affected.
+    pub fn back_off(&self, addr: SocketAddr, duration: Duration) -> bool {
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.backoffs.insert(addr, Instant::now() + duration);
+            sessions.candidates.remove(addr);
+        }
+        self.disconnect(addr)
+    }
+
+    /// Disconnect from every peer at `ip`, and don't talk to it again for `duration`, or
+    /// ever if that's too long to count.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions
+            .bans
+            .insert(ip, Instant::now().checked_add(duration));
+        sessions.candidates.retain(|c| c.peer.addr().ip() != ip);
+        let addrs: Vec<_> = sessions
+            .running
+            .values()
+            .map(|(addr, _)| *addr)
+            .filter(|addr| addr.ip() == ip)
+            .collect();
+        drop(sessions);
+
+        for addr in addrs {
+            self.disconnect(addr);
+        }
+    }
+
+    /// Stop every running session, and start no more until [`Supervisor::resume`].
+    /// Candidates are kept to be dialed then.
+    pub fn pause(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.paused = true;
+        for (_, (addr, abort)) in sessions.running.drain() {
+            debug!("Pausing session with {}", addr);
+            abort.abort();
+        }
+    }
+
+    pub fn resume(&self) {
+        self.sessions.lock().unwrap().paused = false;
+        self.fill();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.sessions.lock().unwrap().paused
+    }
+
+    /// Stop every running session, however far it has got, and dial no more.
+    pub fn abort_all(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.stopped = true;
+        for (_, (addr, abort)) in sessions.running.drain() {
+            debug!("Stopping session with {}", addr);
+            abort.abort();
+        }
+    }
+}
+
+/// Forget a session which has ended, and dial a candidate in its place. Peers we
+/// dialed which timed out are dialed again later, up to a limit.
+fn replace(sessions: &Weak<Mutex<Sessions>>, id: u64, dialed: Option<(PeerData, bool)>) {
+    let sessions = match sessions.upgrade() {
+        Some(sessions) => sessions,
+        None => return,
+    };
+    let unreachable = {
+        let mut sessions = sessions.lock().unwrap();
+        sessions.running.remove(&id);
+        match dialed {
+            Some((peer, true)) => {
+                let addr = peer.addr();
+                let now = Instant::now();
+                // Peers which stopped timing out long ago start afresh.
+                sessions
+                    .timeouts
+                    .retain(|_, (_, last)| now.duration_since(*last) < STALE_CANDIDATE);
+                let (timeouts, last) = sessions.timeouts.entry(addr).or_insert((0, now));
+                *timeouts += 1;
+                *last = now;
+                let timeouts = *timeouts;
+                if timeouts < MAX_DIAL_ATTEMPTS && !sessions.is_known(addr) {
+                    let sessions = &mut *sessions;
+                    let history = sessions.history.as_deref();
+                    sessions.candidates.insert(peer, now, history);
+                }
+                match &sessions.unreachable {
+                    Some(Unreachable(f)) if timeouts >= HOLEPUNCH_AFTER => {
+                        Some((Arc::clone(f), addr))
+                    }
+                    _ => None,
+                }
+            }
+            Some((peer, false)) => {
+                sessions.timeouts.remove(&peer.addr());
+                None
+            }
+            None => None,
+        }
+    };
+    if let Some((unreachable, addr)) = unreachable {
+        debug!("Dialing {} keeps timing out", addr);
+        unreachable(addr);
+    }
+    Supervisor { sessions }.fill();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::peer::PeerSource;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use tokio::sync::oneshot;
+    use tokio::time::{self, Duration};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    async fn wait_for_len(supervisor: &Supervisor, len: usize) {
+        while supervisor.len() != len {
+            time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn reap_finished_and_panicked_sessions() {
+        let supervisor = Supervisor::new();
+        let (tx, rx) = oneshot::channel::<()>();
+        supervisor.spawn(addr(1), async move {
+            let _ = rx.await;
+            Ok(())
+        });
+        supervisor.spawn(addr(2), async {
+            Err(anyhow::anyhow!("Peer closed the connection"))
+        });
+        supervisor.spawn(addr(3), async { panic!("Session bug") });
+
+        time::timeout(Duration::from_secs(5), wait_for_len(&supervisor, 1))
+            .await
+            .unwrap();
+        tx.send(()).unwrap();
+        time::timeout(Duration::from_secs(5), wait_for_len(&supervisor, 0))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn replace_sessions_which_end() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let supervisor = Supervisor::dialing(2, move |peer: PeerData| {
+            let tx = tx.clone();
+            async move {
+                // Peers on even ports hang until they're stopped, the rest fail.
+                let port = peer.addr().port();
+                tx.send(port).unwrap();
+                if port.is_multiple_of(2) {
+                    futures::future::pending().await
+                } else {
+                    Err(anyhow::anyhow!("Connection refused"))
+                }
+            }
+            .boxed()
+        });
+
+        let peer = |port| {
+            let addr = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port);
+            PeerData::new(addr, PeerSource::Tracker)
+        };
+        supervisor.add_candidates([1, 2, 3, 2, 5, 6, 8].into_iter().map(peer));
+
+        // The duplicate is dropped, and each failed peer is replaced until two hanging
+        // sessions are all that's running.
+        let mut dialed = Vec::new();
+        for _ in 0..5 {
+            dialed.push(rx.recv().await.unwrap());
+        }
+        time::timeout(Duration::from_secs(5), async {
+            while supervisor.len() < 2 {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        dialed.sort_unstable();
+        assert_eq!(dialed, vec![1, 2, 3, 5, 6]);
+        assert_eq!(supervisor.candidates(), 1);
+
+        // Stopping the sessions doesn't dial the rest.
+        supervisor.abort_all();
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(supervisor.is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn disconnect_and_ban_peers() {
+        let supervisor = Supervisor::dialing(2, |_| futures::future::pending().boxed());
+        let peer = |port| {
+            let addr = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port);
+            PeerData::new(addr, PeerSource::Tracker)
+        };
+        let other = PeerData::new(
+            SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 1),
+            PeerSource::Tracker,
+        );
+        supervisor.add_candidates([peer(1), other.clone(), peer(2)]);
+        assert_eq!((supervisor.len(), supervisor.candidates()), (2, 1));
+
+        // The candidate takes the disconnected peer's place.
+        assert!(supervisor.disconnect(peer(1).addr()));
+        assert!(!supervisor.disconnect(peer(1).addr()));
+        assert_eq!((supervisor.len(), supervisor.candidates()), (2, 0));
+
+        supervisor.ban(peer(2).addr().ip(), Duration::MAX);
+        assert_eq!(supervisor.len(), 1);
+        supervisor.add_candidates([peer(3)]);
+        supervisor.spawn(peer(4).addr(), futures::future::pending());
+        assert_eq!((supervisor.len(), supervisor.candidates()), (1, 0));
+
+        // Bans run out.
+        supervisor.ban(other.addr().ip(), Duration::ZERO);
+        assert!(supervisor.is_empty());
+        supervisor.add_candidates([other]);
+        assert_eq!(supervisor.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn back_off_from_dropped_peers() {
+        let supervisor = Supervisor::dialing(2, |_| futures::future::pending().boxed());
+        let peer = |port| {
+            let addr = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port);
+            PeerData::new(addr, PeerSource::Tracker)
+        };
+        supervisor.add_candidates([peer(1), peer(2)]);
+
+        // Other peers at the same address are still dialed.
+        assert!(supervisor.back_off(peer(1).addr(), Duration::from_secs(60)));
+        assert_eq!(supervisor.len(), 1);
+        supervisor.add_candidates([peer(1), peer(3)]);
+        assert_eq!((supervisor.len(), supervisor.candidates()), (2, 0));
+
+        supervisor.disconnect(peer(3).addr());
+        supervisor.back_off(peer(3).addr(), Duration::ZERO);
+        supervisor.add_candidates([peer(3)]);
+        assert_eq!(supervisor.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_sessions() {
+        let supervisor = Supervisor::dialing(1, |_| futures::future::pending().boxed());
+        let peer = |port| {
+            let addr = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port);
+            PeerData::new(addr, PeerSource::Tracker)
+        };
+        supervisor.add_candidates([peer(1)]);
+        assert_eq!(supervisor.len(), 1);
+
+        // Nothing runs while paused, even when started again, but candidates are kept.
+        supervisor.pause();
+        supervisor.start(1, |_| futures::future::pending().boxed());
+        supervisor.add_candidates([peer(2)]);
+        supervisor.dial(peer(3));
+        supervisor.spawn(peer(4).addr(), futures::future::pending());
+        assert_eq!((supervisor.len(), supervisor.candidates()), (0, 1));
+
+        supervisor.resume();
+        assert_eq!((supervisor.len(), supervisor.candidates()), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn retry_peers_which_time_out() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let supervisor = Supervisor::dialing(1, move |peer: PeerData| {
+            let _ = tx.send(peer.addr());
+            async { Err(ConnectTimeout.into()) }.boxed()
+        });
+        let (unreachable_tx, mut unreachable_rx) = tokio::sync::mpsc::unbounded_channel();
+        supervisor.on_unreachable(move |addr| {
+            let _ = unreachable_tx.send(addr);
+        });
+
+        let peer = PeerData::new(
+            SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 1),
+            PeerSource::Tracker,
+        );
+        supervisor.add_candidates([peer.clone()]);
+        for _ in 0..MAX_DIAL_ATTEMPTS {
+            assert_eq!(rx.recv().await, Some(peer.addr()));
+        }
+        // We ask for help after the second timeout, and again after the last.
+        assert_eq!(unreachable_rx.recv().await, Some(peer.addr()));
+        assert_eq!(unreachable_rx.recv().await, Some(peer.addr()));
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(rx.try_recv().is_err());
+        assert!(supervisor.is_empty());
+
+        // Peers can be dialed straight away when a relay puts us in touch, as long as
+        // there's room for them.
+        supervisor.spawn(addr(9), futures::future::pending());
+        supervisor.dial(peer.clone());
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(rx.try_recv().is_err());
+        supervisor.disconnect(addr(9));
+        supervisor.dial(peer.clone());
+        assert_eq!(rx.recv().await, Some(peer.addr()));
+    }
+
+    #[tokio::test]
+    async fn dial_best_candidates_first() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let supervisor = Supervisor::dialing(1, move |peer: PeerData| {
+            let _ = tx.send(peer.addr().ip());
+            futures::future::pending().boxed()
+        });
+        let history = Arc::new(PeerHistory::default());
+        let peer = |host| {
+            let addr = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, host), 6881);
+            PeerData::new(addr, PeerSource::Tracker)
+        };
+        let ip = |host| IpAddr::from([192, 0, 2, host]);
+        history.dialed(ip(1), false);
+        history.dialed(ip(3), true);
+        history.record(ip(4), 1000);
+        supervisor.score_with(Arc::clone(&history));
+
+        // Hold the one session slot, so the rest queue up as candidates.
+        supervisor.spawn(peer(9).addr(), futures::future::pending());
+        supervisor.add_candidates([peer(1), peer(2), peer(3), peer(4), peer(5)]);
+        // Another tracker returning some of the same peers doesn't add them twice, but
+        // makes them the freshest.
+        supervisor.add_candidates([peer(5), peer(2)]);
+        assert_eq!(supervisor.candidates(), 5);
+
+        let mut dialed = Vec::new();
+        for _ in 0..5 {
+            supervisor.disconnect(peer(9).addr());
+            let next = rx.recv().await.unwrap();
+            dialed.push(next);
+            supervisor.disconnect(SocketAddr::new(next, 6881));
+        }
+        // Ties go to whichever was heard of first.
+        assert_eq!(dialed, vec![ip(4), ip(3), ip(2), ip(5), ip(1)]);
+    }
+
+    #[tokio::test]
+    async fn abort_hung_sessions() {
+        let supervisor = Supervisor::new();
+        let (tx, rx) = oneshot::channel::<()>();
+        // Dropped, so the channel closes, when the session is aborted.
+        supervisor.spawn(addr(1), async move {
+            let _tx = tx;
+            futures::future::pending().await
+        });
+        assert_eq!(supervisor.len(), 1);
+
+        supervisor.abort_all();
+        assert!(supervisor.is_empty());
+        time::timeout(Duration::from_secs(5), rx)
+            .await
+            .unwrap()
+            .unwrap_err();
+
+        // Dropping the supervisor stops sessions too.
+        let (tx, rx) = oneshot::channel::<()>();
+        let supervisor = Supervisor::new();
+        supervisor.spawn(addr(2), async move {
+            let _tx = tx;
+            futures::future::pending().await
+        });
+        drop(supervisor);
+        time::timeout(Duration::from_secs(5), rx)
+            .await
+            .unwrap()
+            .unwrap_err();
+    }
+}
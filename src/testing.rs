@@ -0,0 +1,813 @@
+//! An in-process swarm for end-to-end tests: fake torrents, and fake peers which serve
+//! them over in-memory streams, optionally misbehaving.
+
+use crate::bitfield::{Bitfield, BitfieldMut};
+use crate::choker::{Choker, SlotPolicy};
+use crate::disk::DiskQueue;
+use crate::external_ip::ExternalIp;
+use crate::fair_share::FairShare;
+use crate::net::SocketOptions;
+use crate::peer::stream::make_message_stream;
+use crate::peer::{
+    Direction, ExtendedHandshake, Handshake, HandshakeCodec, HashPiece, HavePolicy, Holepunch,
+    PeerData, PeerMessage, PeerMessageCodec, PeerSession, PeerSource, PeerStream, SessionContext,
+    WireRecord, DEFAULT_RESERVED, EXTENDED_HANDSHAKE_ID, TR_HASHPIECE,
+};
+use crate::picker::{PickerKind, BLOCK_SIZE};
+use crate::piece_hash::{MerkleTree, PieceVerifier};
+use crate::queues::WorkResult;
+use crate::rate_limit::{AltSpeed, RateLimits};
+use crate::stats::{PeerStats, TorrentStats};
+use crate::storage::Storage;
+use crate::torrent_file::{Info, TorrentFile};
+use crate::Torrent;
+use anyhow::anyhow;
+use futures::{SinkExt, StreamExt};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+use tokio_util::codec::Framed;
+
+/// Internals the benchmarks and fuzz targets drive directly, which aren't otherwise
+/// public.
+pub mod internals {
+    pub use crate::peer::fuzz;
+    pub use crate::picker::{RarestFirst, BLOCK_SIZE};
+    pub use crate::piece_hash::Sha1Pieces;
+    pub use crate::queues::WorkQueue;
+    pub use crate::storage::{IoBackend, Storage};
+}
+
+/// Buffer size of the in-memory streams between sessions and fake peers.
+const STREAM_BUFFER: usize = 256 * 1024;
+/// How long a [`Replay`] waits for the session to send each message the log says it
+/// sent.
+const REPLAY_WAIT: Duration = Duration::from_secs(5);
+
+/// A single-file torrent of `content`, split into pieces of `piece_length` bytes.
+pub fn torrent(name: &str, content: &[u8], piece_length: usize) -> Torrent {
+    let pieces: Vec<u8> = content
+        .chunks(piece_length)
+        .flat_map(Sha1::digest)
+        .collect();
+    TorrentFile {
+        info: Info {
+            name: name.into(),
+            pieces: ByteBuf::from(pieces),
+            piece_length: piece_length as i64,
+            md5sum: None,
+            length: Some(content.len() as i64),
+            files: None,
+            private: None,
+            path: None,
+            root_hash: None,
+            meta_version: None,
+            file_tree: None,
+        },
+        announce: None,
+        nodes: None,
+        encoding: None,
+        httpseeds: None,
+        announce_list: None,
+        creation_date: None,
+        comment: None,
+        created_by: None,
+        piece_layers: None,
+    }
+    .into()
+}
+
+/// Like [`torrent`], but a merkle torrent (BEP 30) with only the root hash.
+pub fn merkle_torrent(name: &str, content: &[u8], piece_length: usize) -> Torrent {
+    let mut file = torrent(name, content, piece_length).file;
+    file.info.use_merkle_root();
+    file.into()
+}
+
+/// A context for downloading `torrent` in sessions, and the channel completed pieces
+/// arrive on. Pieces are kept in the channel rather than saved, so it holds them all.
+pub fn session_context(torrent: Torrent) -> (SessionContext, Receiver<WorkResult>) {
+    let torrent = Arc::new(torrent);
+    let piece_count = torrent.file.info.piece_count();
+    let (save_tx, save_rx) = channel(piece_count.max(1));
+    let work_queue = torrent
+        .work_queue(PickerKind::Sequential.build())
+        .expect("Fake torrents have valid hashes");
+    let turns = FairShare::new(1).share(1);
+    let disk = DiskQueue::start(work_queue.clone(), 1, usize::MAX, turns, save_tx);
+    let ctx = SessionContext {
+        stats: Arc::new(TorrentStats::new(piece_count)),
+        work_queue,
+        storage: Arc::new(
+            Storage::new(&torrent, &std::env::temp_dir()).expect("Fake torrents have safe paths"),
+        ),
+        choker: Arc::new(Choker::new(4, SlotPolicy::RoundRobin)),
+        disk,
+        peer_id: *b"-RS0001-fakeclient00",
+        seed: false,
+        limits: RateLimits::default(),
+        alt_speed: AltSpeed::default(),
+        connections: FairShare::new(usize::MAX).share(1),
+        bind_address: None,
+        socket: SocketOptions::default(),
+        reserved: DEFAULT_RESERVED,
+        holepunch: Holepunch::new(),
+        external_ip: ExternalIp::new(),
+        have_policy: HavePolicy::All,
+        strict_protocol: false,
+        wire_log: None,
+        stop: watch::channel(false).1,
+        torrent,
+    };
+
+    (ctx, save_rx)
+}
+
+/// Ways a [`FakePeer`] can misbehave.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Send the wrong bytes for this piece, so it fails its hash check.
+    CorruptPiece(usize),
+    /// Close the connection once this many blocks have been sent.
+    DisconnectAfter(usize),
+    /// Choke us once this many blocks have been sent, dropping our other requests.
+    ChokeAfter(usize),
+    /// Never answer requests.
+    IgnoreRequests,
+    /// Send each block in two halves.
+    SplitBlocks,
+    /// Send every block of the torrent straight away, without waiting for requests.
+    Unsolicited,
+    /// Handshake with a different info hash, then hang up.
+    WrongInfoHash,
+    /// Send an empty bitfield, whatever pieces we have.
+    EmptyBitfield,
+    /// Say we have a piece far past the end of the torrent.
+    HavePastEnd,
+}
+
+/// What a [`FakePeer`] saw of the session it talked to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FakePeerReport {
+    pub blocks_sent: usize,
+    /// Whether the session told us it was interested.
+    pub interested: bool,
+    /// Pieces the session told us it completed.
+    pub haves: Vec<usize>,
+}
+
+/// A peer which serves a torrent's content from memory. It waits for the session's
+/// handshake, so sessions must dial it with [`PeerSession::connect`].
+#[derive(Debug, Clone)]
+pub struct FakePeer {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    reserved: [u8; 8],
+    content: Arc<Vec<u8>>,
+    piece_length: usize,
+    bitfield: Vec<u8>,
+    /// The hash tree of a merkle torrent, whose hashes go with the first block of a piece.
+    tree: Option<Arc<MerkleTree>>,
+    unchoke: bool,
+    faults: Vec<Fault>,
+}
+
+impl FakePeer {
+    /// A peer seeding `torrent`, whose content is `content`.
+    pub fn seeder(torrent: &Torrent, content: &[u8]) -> Self {
+        let piece_count = torrent.file.info.piece_count();
+        let mut bitfield = vec![0; piece_count.div_ceil(8)];
+        for idx in 0..piece_count {
+            bitfield.set_piece(idx);
+        }
+        let piece_length = torrent.file.info.piece_length as usize;
+        let tree = torrent.file.info.root_hash.is_some().then(|| {
+            let leaves: Vec<[u8; 20]> = content
+                .chunks(piece_length)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect();
+            Arc::new(MerkleTree::from_leaves(&leaves))
+        });
+
+        Self {
+            info_hash: torrent.info_hash,
+            peer_id: *b"-FP0001-fakepeer0000",
+            reserved: DEFAULT_RESERVED,
+            content: Arc::new(content.to_vec()),
+            piece_length,
+            bitfield,
+            tree,
+            unchoke: true,
+            faults: Vec::new(),
+        }
+    }
+
+    /// Only have the pieces in `pieces`.
+    pub fn with_pieces(mut self, pieces: impl IntoIterator<Item = usize>) -> Self {
+        self.bitfield.iter_mut().for_each(|byte| *byte = 0);
+        for idx in pieces {
+            self.bitfield.set_piece(idx);
+        }
+        self
+    }
+
+    pub fn with_peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    pub fn with_reserved(mut self, reserved: [u8; 8]) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
+    /// Keep the session choked, so it can't request anything.
+    pub fn choking(mut self) -> Self {
+        self.unchoke = false;
+        self
+    }
+
+    pub fn with_fault(mut self, fault: Fault) -> Self {
+        self.faults.push(fault);
+        self
+    }
+
+    /// Serve a session over an in-memory stream, returning a session ready to dial the
+    /// peer and the task running the peer.
+    pub fn spawn(
+        self,
+        ctx: SessionContext,
+    ) -> (
+        PeerSession<HandshakeCodec, DuplexStream>,
+        JoinHandle<anyhow::Result<FakePeerReport>>,
+    ) {
+        let (ours, theirs) = tokio::io::duplex(STREAM_BUFFER);
+        let peer = tokio::spawn(self.run(theirs));
+        (fake_session(ours, ctx), peer)
+    }
+
+    /// Talk to the session on the other end of `stream` until it hangs up, or a fault
+    /// ends the connection.
+    pub async fn run<S: PeerStream>(self, stream: S) -> anyhow::Result<FakePeerReport> {
+        let mut stream = Framed::new(stream, HandshakeCodec);
+        let theirs = stream
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("Connection closed before handshake"))??;
+        if theirs.info_hash != self.info_hash {
+            return Err(anyhow!("Session asked for a different torrent"));
+        }
+        let mut report = FakePeerReport::default();
+        if self.faults.contains(&Fault::WrongInfoHash) {
+            let mut info_hash = self.info_hash;
+            info_hash[0] ^= 0xff;
+            let handshake = Handshake::with_reserved(&info_hash, &self.peer_id, self.reserved);
+            stream.send(handshake).await?;
+            return Ok(report);
+        }
+        let handshake = Handshake::with_reserved(&self.info_hash, &self.peer_id, self.reserved);
+        stream.send(handshake).await?;
+
+        let mut stream = make_message_stream(stream, PeerMessageCodec::default());
+        if self.faults.contains(&Fault::EmptyBitfield) {
+            stream.send(PeerMessage::Bitfield(Vec::new())).await?;
+        } else if self.bitfield.count_pieces() > 0 {
+            stream
+                .send(PeerMessage::Bitfield(self.bitfield.clone()))
+                .await?;
+        }
+        if self.faults.contains(&Fault::HavePastEnd) {
+            stream.send(PeerMessage::Have(100_000)).await?;
+        }
+        let mut choking = true;
+        if self.unchoke {
+            stream.send(PeerMessage::Unchoke).await?;
+            choking = false;
+        }
+        if self.faults.contains(&Fault::Unsolicited) {
+            for begin in (0..self.content.len()).step_by(BLOCK_SIZE) {
+                let idx = begin / self.piece_length;
+                let offset = begin % self.piece_length;
+                let length = BLOCK_SIZE.min(self.content.len() - begin);
+                let data = self.content[begin..begin + length].to_vec();
+                let msg = PeerMessage::Piece(idx as u32, offset as u32, data.into());
+                if stream.send(msg).await.is_err() {
+                    // The session hung up on us.
+                    return Ok(report);
+                }
+                report.blocks_sent += 1;
+            }
+        }
+
+        let mut hashpiece_id = None;
+        while let Some(msg) = stream.next().await {
+            match msg? {
+                PeerMessage::Extended(EXTENDED_HANDSHAKE_ID, payload) => {
+                    let handshake = ExtendedHandshake::from_bytes(&payload)?;
+                    hashpiece_id = handshake.m.get(TR_HASHPIECE).map(|&id| id as u8);
+                }
+                PeerMessage::Interested => report.interested = true,
+                PeerMessage::NotInterested => report.interested = false,
+                PeerMessage::Have(idx) => report.haves.push(idx as usize),
+                PeerMessage::Request(idx, begin, length) => {
+                    if choking || self.faults.contains(&Fault::IgnoreRequests) {
+                        continue;
+                    }
+                    let mut data = self.block(idx as usize, begin as usize, length as usize)?;
+                    if self.faults.contains(&Fault::SplitBlocks) && data.len() > 1 {
+                        let second = data.split_off(data.len() / 2);
+                        let second_begin = begin + data.len() as u32;
+                        let first = self.piece(hashpiece_id, idx, begin, data)?;
+                        stream.send(first).await?;
+                        let second = self.piece(hashpiece_id, idx, second_begin, second)?;
+                        stream.send(second).await?;
+                    } else {
+                        stream
+                            .send(self.piece(hashpiece_id, idx, begin, data)?)
+                            .await?;
+                    }
+                    report.blocks_sent += 1;
+
+                    if self
+                        .faults
+                        .contains(&Fault::DisconnectAfter(report.blocks_sent))
+                    {
+                        break;
+                    }
+                    if self.faults.contains(&Fault::ChokeAfter(report.blocks_sent)) {
+                        stream.send(PeerMessage::Choke).await?;
+                        choking = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// A block of a piece, with the hashes to check the piece with if it's the first
+    /// block of a merkle torrent's piece and the session asked for them.
+    fn piece(
+        &self,
+        hashpiece_id: Option<u8>,
+        idx: u32,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> anyhow::Result<PeerMessage> {
+        let hashes = self
+            .tree
+            .as_ref()
+            .filter(|_| begin == 0)
+            .and_then(|tree| tree.hash_chain(idx as usize));
+        match (hashpiece_id, hashes) {
+            (Some(id), Some(hashes)) => {
+                let msg = HashPiece {
+                    piece: idx,
+                    begin,
+                    hashes,
+                    data,
+                };
+                Ok(PeerMessage::Extended(id, msg.to_bytes()?))
+            }
+            _ => Ok(PeerMessage::Piece(idx, begin, data.into())),
+        }
+    }
+
+    fn block(&self, idx: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        if !self.bitfield.has_piece(idx) {
+            return Err(anyhow!("Session requested piece {} we don't have", idx));
+        }
+        let start = idx * self.piece_length + begin;
+        let mut data = self
+            .content
+            .get(start..start + length)
+            .ok_or_else(|| anyhow!("Session requested a block past the end"))?
+            .to_vec();
+        if self.faults.contains(&Fault::CorruptPiece(idx)) {
+            data.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+
+        Ok(data)
+    }
+}
+
+/// Where sessions with fake peers think the peer is, a documentation address so
+/// nothing real is ever dialed.
+pub const FAKE_PEER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881);
+
+/// A session over `stream`, to a peer at [`FAKE_PEER`].
+fn fake_session(
+    stream: DuplexStream,
+    ctx: SessionContext,
+) -> PeerSession<HandshakeCodec, DuplexStream> {
+    let data = PeerData::new(FAKE_PEER, PeerSource::Tracker);
+    let peer_stats = Arc::new(PeerStats::new(PeerSource::Tracker));
+    PeerSession::with_stream(data, stream, peer_stats, ctx)
+}
+
+/// A peer which plays back one session's messages from a wire log, to turn a report
+/// of a problem with another client into a test. Messages the logged session received
+/// are sent to the session under test, with block data taken from the content, and
+/// messages it sent are waited for. Extension and hash messages are skipped, as the log
+/// doesn't have their payloads.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    info_hash: [u8; 20],
+    content: Arc<Vec<u8>>,
+    piece_length: usize,
+    records: Vec<WireRecord>,
+}
+
+/// What the session under test did while a log was replayed to it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplayReport {
+    /// Every message the session sent.
+    pub sent: Vec<PeerMessage>,
+    /// Kinds of the messages the log says were sent which the session didn't send.
+    pub missing: Vec<String>,
+    /// Whether the whole log was played, rather than the session hanging up first.
+    pub finished: bool,
+}
+
+impl Replay {
+    /// Replay the messages in `records` of the session with `peer` for `torrent`,
+    /// whose content is `content`. Other sessions' messages in the log are skipped.
+    pub fn new(
+        torrent: &Torrent,
+        content: &[u8],
+        records: Vec<WireRecord>,
+        peer: SocketAddr,
+    ) -> Self {
+        let info_hash = crate::magnet::hex(&torrent.info_hash);
+        let records = records
+            .into_iter()
+            .filter(|r| r.info_hash == info_hash && r.peer == peer)
+            .collect();
+        Self {
+            info_hash: torrent.info_hash,
+            content: Arc::new(content.to_vec()),
+            piece_length: torrent.file.info.piece_length as usize,
+            records,
+        }
+    }
+
+    /// Replay to a session over an in-memory stream, returning a session ready to dial
+    /// the peer and the task replaying the log.
+    pub fn spawn(
+        self,
+        ctx: SessionContext,
+    ) -> (
+        PeerSession<HandshakeCodec, DuplexStream>,
+        JoinHandle<anyhow::Result<ReplayReport>>,
+    ) {
+        let (ours, theirs) = tokio::io::duplex(STREAM_BUFFER);
+        let replay = tokio::spawn(self.run(theirs));
+        (fake_session(ours, ctx), replay)
+    }
+
+    pub async fn run<S: PeerStream>(self, stream: S) -> anyhow::Result<ReplayReport> {
+        let mut stream = Framed::new(stream, HandshakeCodec);
+        stream
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("Connection closed before handshake"))??;
+        let handshake =
+            Handshake::with_reserved(&self.info_hash, b"-FP0001-replaypeer00", DEFAULT_RESERVED);
+        stream.send(handshake).await?;
+        let mut stream = make_message_stream(stream, PeerMessageCodec::default());
+
+        let mut report = ReplayReport::default();
+        for record in &self.records {
+            match record.direction {
+                Direction::Received => {
+                    let msg = match self.message(record)? {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+                    if stream.send(msg).await.is_err() {
+                        return Ok(report);
+                    }
+                }
+                // Keep-alives depend on timing, not on anything to reproduce.
+                Direction::Sent if record.kind == "keep_alive" => {}
+                Direction::Sent => {
+                    let sent = time::timeout(REPLAY_WAIT, async {
+                        while let Some(msg) = stream.next().await {
+                            let msg = msg?;
+                            let kind = msg.kind();
+                            report.sent.push(msg);
+                            if kind == record.kind {
+                                return Ok(true);
+                            }
+                        }
+                        anyhow::Ok(false)
+                    })
+                    .await;
+                    match sent {
+                        Ok(Ok(true)) => {}
+                        Ok(Ok(false)) => {
+                            report.missing.push(record.kind.clone());
+                            return Ok(report);
+                        }
+                        Ok(Err(e)) => return Err(e),
+                        Err(_) => report.missing.push(record.kind.clone()),
+                    }
+                }
+            }
+        }
+
+        report.finished = true;
+        Ok(report)
+    }
+
+    /// The message a record is of, if it can be rebuilt.
+    fn message(&self, record: &WireRecord) -> anyhow::Result<Option<PeerMessage>> {
+        let field = |i: usize| {
+            record
+                .fields
+                .get(i)
+                .copied()
+                .ok_or_else(|| anyhow!("Record of a {} is missing fields", record.kind))
+        };
+        let msg = match record.kind.as_str() {
+            "keep_alive" => PeerMessage::KeepAlive,
+            "choke" => PeerMessage::Choke,
+            "unchoke" => PeerMessage::Unchoke,
+            "interested" => PeerMessage::Interested,
+            "not_interested" => PeerMessage::NotInterested,
+            "have" => PeerMessage::Have(field(0)?),
+            "bitfield" => {
+                let hex = record.bitfield.as_deref().unwrap_or_default();
+                if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+                    return Err(anyhow!("Logged bitfield {:?} isn't hex", hex));
+                }
+                let bitfield = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                    .collect::<Result<_, _>>()?;
+                PeerMessage::Bitfield(bitfield)
+            }
+            "request" => PeerMessage::Request(field(0)?, field(1)?, field(2)?),
+            "cancel" => PeerMessage::Cancel(field(0)?, field(1)?, field(2)?),
+            "reject_request" => PeerMessage::RejectRequest(field(0)?, field(1)?, field(2)?),
+            "piece" => {
+                let (idx, begin) = (field(0)?, field(1)?);
+                // The length prefix, ID, index and offset come before the data.
+                let length = record.length.saturating_sub(13);
+                let start = idx as usize * self.piece_length + begin as usize;
+                let data = self
+                    .content
+                    .get(start..start + length)
+                    .ok_or_else(|| anyhow!("Logged block is past the end of the content"))?;
+                PeerMessage::Piece(idx, begin, data.to_vec().into())
+            }
+            "unknown" => {
+                let id = record.id.unwrap_or_default();
+                PeerMessage::Unknown(id, vec![0; record.length.saturating_sub(5)])
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(msg))
+    }
+}
+
+/// Put completed pieces back together in order, with zeroes for any which are missing.
+pub fn assemble(results: &mut Receiver<WorkResult>, piece_length: usize, len: usize) -> Vec<u8> {
+    let mut content = vec![0; len];
+    while let Ok(result) = results.try_recv() {
+        let start = result.idx * piece_length;
+        content[start..start + result.bytes.len()].copy_from_slice(&result.bytes);
+    }
+
+    content
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn download_from_swarm_with_faulty_peers() {
+        let content = content(BLOCK_SIZE * 6);
+        let torrent = torrent("swarm", &content, BLOCK_SIZE * 2);
+        let seeder = FakePeer::seeder(&torrent, &content);
+        let (ctx, mut results) = session_context(torrent);
+
+        // The first peer corrupts a piece and the second only has that piece, and both
+        // hang up part way through. The last peer finishes the download.
+        let peers = [
+            seeder
+                .clone()
+                .with_fault(Fault::CorruptPiece(0))
+                .with_fault(Fault::DisconnectAfter(4)),
+            seeder
+                .clone()
+                .with_pieces([0])
+                .with_fault(Fault::DisconnectAfter(1)),
+            seeder,
+        ];
+        let mut reports = Vec::new();
+        for peer in peers {
+            let (session, peer) = peer.spawn(ctx.clone());
+            let mut session = session.connect().await.unwrap();
+            // Sessions end with an error when a peer disconnects.
+            let _ = session.start_download().await;
+            drop(session);
+            reports.push(peer.await.unwrap().unwrap());
+        }
+
+        assert!(ctx.work_queue.is_finished());
+        assert_eq!(
+            assemble(&mut results, BLOCK_SIZE * 2, content.len()),
+            content
+        );
+        assert!(reports[0].interested && reports[1].interested);
+        // Once the download finishes there's nothing left to want from the last peer.
+        assert!(!reports[2].interested);
+        assert_eq!(reports[1].blocks_sent, 1);
+        assert!(reports[2].blocks_sent > 0);
+    }
+
+    #[tokio::test]
+    async fn download_blocks_sent_in_parts() {
+        let content = content(BLOCK_SIZE * 3 + 100);
+        let torrent = torrent("split", &content, BLOCK_SIZE * 2);
+        let peer = FakePeer::seeder(&torrent, &content).with_fault(Fault::SplitBlocks);
+        let (ctx, mut results) = session_context(torrent);
+
+        let (session, peer) = peer.spawn(ctx.clone());
+        let mut session = session.connect().await.unwrap();
+        session.start_download().await.unwrap();
+        drop(session);
+
+        assert_eq!(peer.await.unwrap().unwrap().blocks_sent, 4);
+        assert_eq!(
+            assemble(&mut results, BLOCK_SIZE * 2, content.len()),
+            content
+        );
+    }
+
+    #[tokio::test]
+    async fn download_merkle_torrent() {
+        let content = content(BLOCK_SIZE * 5 + 100);
+        let torrent = merkle_torrent("merkle", &content, BLOCK_SIZE * 2);
+        assert!(torrent.file.info.pieces.is_empty());
+        let seeder = FakePeer::seeder(&torrent, &content);
+        let (ctx, mut results) = session_context(torrent);
+
+        let peers = [
+            seeder
+                .clone()
+                .with_fault(Fault::CorruptPiece(1))
+                .with_fault(Fault::DisconnectAfter(4)),
+            seeder,
+        ];
+        for peer in peers {
+            let (session, peer) = peer.spawn(ctx.clone());
+            let mut session = session.connect().await.unwrap();
+            let _ = session.start_download().await;
+            drop(session);
+            peer.await.unwrap().unwrap();
+        }
+
+        assert!(ctx.work_queue.is_finished());
+        assert_eq!(
+            assemble(&mut results, BLOCK_SIZE * 2, content.len()),
+            content
+        );
+        // Having checked every piece, we can send their hashes on as a seed.
+        assert!((0..3).all(|idx| ctx.work_queue.hash_chain(idx).is_some()));
+    }
+
+    #[tokio::test]
+    async fn disconnect_peer_sending_unrequested_blocks() {
+        // Big enough that the peer goes over the limit before its first request arrives.
+        let content = content(BLOCK_SIZE * 80);
+        let torrent = torrent("unsolicited", &content, BLOCK_SIZE * 4);
+        let peer = FakePeer::seeder(&torrent, &content)
+            .choking()
+            .with_fault(Fault::Unsolicited);
+        let (ctx, mut results) = session_context(torrent);
+
+        let (session, peer) = peer.spawn(ctx);
+        let mut session = session.connect().await.unwrap();
+        assert!(session.start_download().await.is_err());
+        drop(session);
+        peer.await.unwrap().unwrap();
+
+        assert!(results.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn refuse_peer_with_wrong_info_hash() {
+        let content = content(100);
+        let torrent = torrent("wrong-hash", &content, 50);
+        let peer = FakePeer::seeder(&torrent, &content).with_fault(Fault::WrongInfoHash);
+        let (ctx, _results) = session_context(torrent);
+
+        let (session, peer) = peer.spawn(ctx);
+        assert!(session.connect().await.is_err());
+        assert!(peer.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn replay_a_logged_download() {
+        let content = content(BLOCK_SIZE * 5 + 100);
+        let replay_torrent = || torrent("replay", &content, BLOCK_SIZE * 2);
+        let path = std::env::temp_dir().join(format!("replay-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (mut ctx, _results) = session_context(replay_torrent());
+        ctx.wire_log = Some(crate::peer::WireLog::open(&path).unwrap());
+        let (session, peer) = FakePeer::seeder(&ctx.torrent, &content).spawn(ctx);
+        let mut session = session.connect().await.unwrap();
+        session.start_download().await.unwrap();
+        drop(session);
+        peer.await.unwrap().unwrap();
+        let records = crate::peer::read_wire_log(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Other sessions in the same log are left out.
+        let mut log = records.clone();
+        for record in &records {
+            let mut other_peer = record.clone();
+            other_peer.peer = "192.0.2.2:6881".parse().unwrap();
+            let mut other_torrent = record.clone();
+            other_torrent.info_hash = "00".repeat(20);
+            log.extend([other_peer, other_torrent]);
+        }
+
+        // A fresh session given the same messages downloads the same content, asking
+        // for the same blocks.
+        let (ctx, mut results) = session_context(replay_torrent());
+        let replay = Replay::new(&ctx.torrent, &content, log, FAKE_PEER.into());
+        assert_eq!(replay.records, records);
+        let (session, replay) = replay.spawn(ctx.clone());
+        let mut session = session.connect().await.unwrap();
+        session.start_download().await.unwrap();
+        drop(session);
+        let report = replay.await.unwrap().unwrap();
+
+        assert!(report.finished && report.missing.is_empty(), "{:?}", report);
+        assert!(ctx.work_queue.is_finished());
+        assert_eq!(
+            assemble(&mut results, BLOCK_SIZE * 2, content.len()),
+            content
+        );
+        let requests = |messages: Vec<PeerMessage>| {
+            let mut requests: Vec<_> = messages
+                .into_iter()
+                .filter(|msg| matches!(msg, PeerMessage::Request(..)))
+                .collect();
+            requests.sort_by_key(|msg| msg.to_string());
+            requests
+        };
+        let logged = records
+            .iter()
+            .filter(|r| r.direction == Direction::Sent && r.kind == "request")
+            .map(|r| PeerMessage::Request(r.fields[0], r.fields[1], r.fields[2]))
+            .collect();
+        assert_eq!(requests(report.sent), requests(logged));
+    }
+
+    #[test]
+    fn refuse_logged_bitfields_which_arent_hex() {
+        let content = content(100);
+        let replay = Replay::new(
+            &torrent("hex", &content, 50),
+            &content,
+            vec![],
+            FAKE_PEER.into(),
+        );
+        let mut record = WireRecord {
+            time: 0,
+            info_hash: String::new(),
+            peer: FAKE_PEER.into(),
+            direction: Direction::Received,
+            kind: "bitfield".into(),
+            id: Some(5),
+            extended_id: None,
+            length: 6,
+            fields: Vec::new(),
+            bitfield: Some("c".into()),
+        };
+        assert!(replay.message(&record).is_err());
+        record.bitfield = Some("é0".into());
+        assert!(replay.message(&record).is_err());
+        record.bitfield = Some("c0".into());
+        assert_eq!(
+            replay.message(&record).unwrap(),
+            Some(PeerMessage::Bitfield(vec![0xc0]))
+        );
+    }
+}
@@ -1,14 +1,24 @@
-use anyhow::anyhow;
-use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+#[cfg(feature = "engine")]
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+#[cfg(feature = "engine")]
 use std::convert::TryFrom;
-use std::{borrow::Cow, convert::TryInto};
+#[cfg(feature = "engine")]
+use url::Url;
 
-use crate::queues::{PieceOfWork, WorkQueue};
+#[cfg(feature = "engine")]
+use crate::picker::PiecePicker;
+#[cfg(feature = "engine")]
+use crate::piece_hash::{MerkleTree, PieceVerifier, Sha1Pieces, V2Pieces};
+#[cfg(feature = "engine")]
+use crate::queues::WorkQueue;
+#[cfg(feature = "engine")]
+use crate::tracker::{AnnounceParams, Transfer};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Node(String, i64);
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -17,11 +27,41 @@ pub struct File {
     pub length: i64,
     #[serde(default)]
     pub md5sum: Option<String>,
+    /// Attributes of the file (BEP 47), where `p` marks padding, which isn't stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl File {
+    /// Whether the file is padding which aligns the next file to a piece boundary.
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+/// A v2 torrent's directory of files (BEP 52), where a file is a directory with a
+/// single entry named by the empty string.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTree {
+    File(BTreeMap<String, FileTreeEntry>),
+    Directory(BTreeMap<String, FileTree>),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FileTreeEntry {
+    pub length: i64,
+    /// The root of the merkle tree over the file's 16 KiB blocks. Empty files have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "pieces root")]
+    pub pieces_root: Option<ByteBuf>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct Info {
     pub name: String,
+    /// Merkle torrents have a root hash instead of the hash of every piece.
+    #[serde(default, skip_serializing_if = "is_empty")]
     pub pieces: ByteBuf,
     #[serde(rename = "piece length")]
     pub piece_length: i64,
@@ -35,9 +75,20 @@ pub struct Info {
     pub private: Option<u8>,
     #[serde(default)]
     pub path: Option<Vec<String>>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     #[serde(rename = "root hash")]
-    pub root_hash: Option<String>,
+    pub root_hash: Option<ByteBuf>,
+    /// 2 for v2 and hybrid torrents (BEP 52).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "meta version")]
+    pub meta_version: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "file tree")]
+    pub file_tree: Option<BTreeMap<String, FileTree>>,
+}
+
+fn is_empty(bytes: &ByteBuf) -> bool {
+    bytes.is_empty()
 }
 
 impl std::fmt::Debug for Info {
@@ -54,10 +105,92 @@ impl Info {
         Ok(result.into())
     }
 
+    /// The SHA-256 info hash of v2 and hybrid torrents (BEP 52).
+    pub fn hash_v2(&self) -> anyhow::Result<Option<[u8; 32]>> {
+        use sha2::Sha256;
+
+        if self.meta_version != Some(2) {
+            return Ok(None);
+        }
+        let bytes = serde_bencode::ser::to_bytes(self)?;
+        Ok(Some(Sha256::digest(&bytes).into()))
+    }
+
+    /// The length and pieces root of each file in a v2 torrent's file tree, in the order
+    /// its pieces are in.
+    pub fn v2_files(&self) -> anyhow::Result<Vec<(usize, Option<[u8; 32]>)>> {
+        fn walk(
+            tree: &BTreeMap<String, FileTree>,
+            files: &mut Vec<(usize, Option<[u8; 32]>)>,
+        ) -> anyhow::Result<()> {
+            for node in tree.values() {
+                match node {
+                    FileTree::File(file) => {
+                        let entry = file
+                            .get("")
+                            .ok_or_else(|| anyhow::anyhow!("File tree entry isn't a file"))?;
+                        let root = entry
+                            .pieces_root
+                            .as_ref()
+                            .map(|root| root[..].try_into())
+                            .transpose()
+                            .map_err(|_| anyhow::anyhow!("Pieces root isn't 32 bytes long"))?;
+                        files.push((entry.length.max(0) as usize, root));
+                    }
+                    FileTree::Directory(children) => walk(children, files)?,
+                }
+            }
+            Ok(())
+        }
+
+        let mut files = Vec::new();
+        if let Some(tree) = &self.file_tree {
+            walk(tree, &mut files)?;
+        }
+        Ok(files)
+    }
+
     pub fn hash_pieces(&self) -> std::slice::ChunksExact<u8> {
         self.pieces.chunks_exact(20)
     }
 
+    pub fn piece_count(&self) -> usize {
+        self.total_length().div_ceil(self.piece_length as u64) as usize
+    }
+
+    /// Check the fields the pieces are worked out from, so they can be used without.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.piece_length <= 0 {
+            anyhow::bail!("Piece length {} isn't positive", self.piece_length);
+        }
+        Ok(())
+    }
+
+    /// Make this a merkle torrent (BEP 30), replacing the hash of every piece with the
+    /// root of a hash tree over them.
+    #[cfg(feature = "engine")]
+    pub fn use_merkle_root(&mut self) {
+        let leaves: Vec<[u8; 20]> = self
+            .hash_pieces()
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
+        let root = MerkleTree::from_leaves(&leaves).root();
+        self.root_hash = Some(ByteBuf::from(root.to_vec()));
+        self.pieces = ByteBuf::new();
+    }
+
+    /// The root of the hash tree over the pieces, if this is a merkle torrent (BEP 30).
+    pub fn merkle_root(&self) -> anyhow::Result<Option<[u8; 20]>> {
+        self.root_hash
+            .as_ref()
+            .map(|root| {
+                root[..]
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Root hash isn't 20 bytes long"))
+            })
+            .transpose()
+    }
+
     pub fn piece_bounds(&self, index: usize) -> (usize, usize) {
         let length = self.piece_length as usize;
         let begin = index * length;
@@ -74,30 +207,44 @@ impl Info {
         let (begin, end) = self.piece_bounds(index);
         end - begin
     }
+
+    /// Total size of the torrent's content, summing the file lengths for multi-file torrents.
+    pub fn total_length(&self) -> u64 {
+        match (&self.length, &self.files) {
+            (Some(length), _) => *length as u64,
+            (None, Some(files)) => files.iter().map(|f| f.length as u64).sum(),
+            (None, None) => 0,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct TorrentFile {
     pub info: Info,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub announce: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub nodes: Option<Vec<Node>>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub encoding: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub httpseeds: Option<Vec<String>>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     #[serde(rename = "announce-list")]
     pub announce_list: Option<Vec<Vec<String>>>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     #[serde(rename = "creation date")]
     pub creation_date: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     #[serde(rename = "comment")]
     pub comment: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     #[serde(rename = "created by")]
     pub created_by: Option<String>,
+    /// The piece layer of each v2 file larger than a piece, by the file's pieces root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "piece layers")]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
 }
 
 #[derive(Debug)]
@@ -107,50 +254,120 @@ pub struct Torrent {
 }
 
 impl Torrent {
-    pub fn build_tracker_url(&self, peer_id: &[u8], port: u16) -> anyhow::Result<Url> {
-        let announce = self
-            .file
-            .announce
-            .as_ref()
-            .ok_or_else(|| anyhow!("No announce found"))?;
-        let mut base = Url::parse(&announce)?;
+    /// Tiers of tracker URLs, from the announce list if there is one or the announce URL otherwise.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        match (&self.file.announce_list, &self.file.announce) {
+            (Some(tiers), _) if tiers.iter().any(|t| !t.is_empty()) => {
+                tiers.iter().filter(|t| !t.is_empty()).cloned().collect()
+            }
+            (_, Some(announce)) => vec![vec![announce.clone()]],
+            _ => Vec::new(),
+        }
+    }
 
-        base.query_pairs_mut()
-            .append_pair("port", &format!("{}", port))
-            .append_pair("uploaded", "0")
-            .append_pair("downloaded", "0")
-            .append_pair("compact", "1")
-            .append_pair(
-                "left",
-                &(self.file.info.length.expect("No length given").to_string()),
-            )
-            .encoding_override(Some(&iso_8859_1_encode))
-            .append_pair("info_hash", &iso_8859_1_decode(&self.info_hash))
-            .append_pair("peer_id", &iso_8859_1_decode(peer_id));
+    /// Replace the torrent's trackers with `tiers`.
+    pub fn set_trackers(&mut self, tiers: Vec<Vec<String>>) {
+        self.file.announce = tiers.iter().flatten().next().cloned();
+        self.file.announce_list = (!tiers.is_empty()).then_some(tiers);
+    }
 
-        Ok(base)
+    #[cfg(feature = "engine")]
+    pub fn build_tracker_url(&self, params: &AnnounceParams) -> anyhow::Result<Url> {
+        let transfer = Transfer {
+            left: self.file.info.total_length(),
+            ..Default::default()
+        };
+        tracker_url(params, &self.info_hash, &transfer)
     }
 
     pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Torrent> {
         let torrent: TorrentFile = serde_bencode::from_bytes(bytes)?;
+        torrent.info.check()?;
         Ok(torrent.into())
     }
 
-    pub async fn work_queue(&self) -> anyhow::Result<WorkQueue> {
-        let pieces = self.file.info.hash_pieces();
-        let (tx, rx) = async_channel::bounded(pieces.len());
+    /// Build a torrent from an info dictionary fetched from peers, announcing to
+    /// `trackers`, each in its own tier.
+    pub fn from_info(info: &[u8], trackers: Vec<String>) -> anyhow::Result<Torrent> {
+        let info_hash: [u8; 20] = Sha1::digest(info).into();
+        let info: Info = serde_bencode::from_bytes(info)?;
+        info.check()?;
+        let torrent: Torrent = TorrentFile {
+            info,
+            announce: trackers.first().cloned(),
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            announce_list: (!trackers.is_empty())
+                .then(|| trackers.into_iter().map(|t| vec![t]).collect()),
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            piece_layers: None,
+        }
+        .into();
 
-        for (idx, hash) in pieces.into_iter().enumerate() {
-            let length = self.file.info.piece_length(idx);
-            tx.send(PieceOfWork {
-                idx,
-                hash: hash.try_into()?,
-                length,
-            })
-            .await?;
+        if torrent.info_hash != info_hash {
+            return Err(anyhow::anyhow!(
+                "Info dictionary has fields which aren't supported"
+            ));
+        }
+        Ok(torrent)
+    }
+
+    /// Encode the torrent as a .torrent file.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(&self.file)?)
+    }
+
+    /// Checks pieces against the torrent's hashes: SHA-1 hashes, a SHA-1 hash tree, or
+    /// for v2 and hybrid torrents, each file's piece layer, so v2 peers can be sent and
+    /// asked for the layers.
+    #[cfg(feature = "engine")]
+    pub fn piece_verifier(&self) -> anyhow::Result<Box<dyn PieceVerifier>> {
+        let info = &self.file.info;
+        if info.meta_version == Some(2) {
+            let mut layers = std::collections::HashMap::new();
+            for (root, layer) in self.file.piece_layers.iter().flatten() {
+                let root: [u8; 32] = root[..]
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Pieces root isn't 32 bytes long"))?;
+                let layer = layer
+                    .chunks_exact(32)
+                    .map(|hash| hash.try_into().unwrap())
+                    .collect();
+                layers.insert(root, layer);
+            }
+            let pieces = V2Pieces::new(info.piece_length as usize, &info.v2_files()?, &layers)?;
+            if pieces.piece_count() != info.piece_count() {
+                anyhow::bail!("File tree doesn't match the torrent's files");
+            }
+            return Ok(Box::new(pieces));
         }
+        Ok(match info.merkle_root()? {
+            Some(root) => Box::new(MerkleTree::new(root, info.piece_count())),
+            None => Box::new(Sha1Pieces::from_bytes(&info.pieces)?),
+        })
+    }
+
+    #[cfg(feature = "engine")]
+    pub fn work_queue(&self, picker: Box<dyn PiecePicker>) -> anyhow::Result<WorkQueue> {
+        Ok(self.work_queue_with(self.piece_verifier()?, picker))
+    }
 
-        Ok(WorkQueue { tx, rx })
+    /// A work queue checking pieces with `verifier` rather than one made from the torrent.
+    #[cfg(feature = "engine")]
+    pub fn work_queue_with(
+        &self,
+        verifier: Box<dyn PieceVerifier>,
+        picker: Box<dyn PiecePicker>,
+    ) -> WorkQueue {
+        WorkQueue::new(
+            verifier,
+            self.file.info.piece_length as usize,
+            self.file.info.total_length() as usize,
+            picker,
+        )
     }
 }
 
@@ -164,10 +381,65 @@ impl From<TorrentFile> for Torrent {
     }
 }
 
+/// The HTTP announce URL for the torrent with `info_hash`, reporting `transfer`.
+#[cfg(feature = "engine")]
+pub fn tracker_url(
+    params: &AnnounceParams,
+    info_hash: &[u8; 20],
+    transfer: &Transfer,
+) -> anyhow::Result<Url> {
+    let mut base = Url::parse(params.announce)?;
+
+    base.query_pairs_mut()
+        .append_pair("port", &format!("{}", params.port))
+        .append_pair("uploaded", &transfer.uploaded.to_string())
+        .append_pair("downloaded", &transfer.downloaded.to_string())
+        .append_pair("compact", "1")
+        .append_pair("left", &transfer.left.to_string());
+
+    // Joining or leaving the swarm matters more to the tracker than being a partial seed.
+    match params.event {
+        Some(event) => {
+            base.query_pairs_mut().append_pair("event", event.as_str());
+        }
+        None if transfer.partial_seed => {
+            base.query_pairs_mut().append_pair("event", "paused");
+        }
+        None => {}
+    }
+
+    if let Some(key) = params.key {
+        base.query_pairs_mut()
+            .append_pair("key", &format!("{:08x}", key));
+    }
+
+    if let Some(numwant) = params.numwant {
+        base.query_pairs_mut()
+            .append_pair("numwant", &numwant.to_string());
+    }
+
+    if let Some(tracker_id) = params.tracker_id {
+        base.query_pairs_mut().append_pair("trackerid", tracker_id);
+    }
+
+    if let Some(ip) = params.ip {
+        base.query_pairs_mut().append_pair("ip", &ip.to_string());
+    }
+
+    base.query_pairs_mut()
+        .encoding_override(Some(&iso_8859_1_encode))
+        .append_pair("info_hash", &iso_8859_1_decode(info_hash))
+        .append_pair("peer_id", &iso_8859_1_decode(params.peer_id));
+
+    Ok(base)
+}
+
+#[cfg(feature = "engine")]
 fn iso_8859_1_decode(bytes: &[u8]) -> String {
     bytes.iter().map(|&byte| char::from(byte)).collect()
 }
 
+#[cfg(feature = "engine")]
 fn iso_8859_1_encode(string: &str) -> Cow<[u8]> {
     string
         .chars()
@@ -0,0 +1,138 @@
+//! Keeping tracker requests on the public internet, so a crafted torrent can't use its
+//! announce URLs to make us send requests to services on this machine or its network.
+
+use crate::net::is_public;
+use anyhow::anyhow;
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::{redirect, Url};
+use std::net::{IpAddr, SocketAddr};
+use url::Host;
+
+/// How many redirects an HTTP tracker can send us through.
+pub const MAX_REDIRECTS: usize = 5;
+
+/// Check `url` is a tracker URL we can announce to: HTTP(S) or UDP, and unless
+/// `allow_local` is set, not for this machine or an address on a private network.
+/// Hosts given by name are checked again when they're resolved.
+pub fn check_url(url: &str, allow_local: bool) -> anyhow::Result<Url> {
+    let parsed = Url::parse(url).map_err(|e| anyhow!("Invalid tracker {}: {}", url, e))?;
+    match parsed.scheme() {
+        "http" | "https" | "udp" => {}
+        scheme => return Err(anyhow!("Can't announce to {} trackers", scheme)),
+    }
+    if !allow_local {
+        check_host(&parsed)?;
+    }
+
+    Ok(parsed)
+}
+
+fn check_host(url: &Url) -> anyhow::Result<()> {
+    let ip = match url.host() {
+        None => return Err(anyhow!("Tracker {} has no host", url)),
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        // Addresses in URLs of schemes other than HTTP(S) are left as names.
+        Some(Host::Domain(domain)) => match domain.parse() {
+            Ok(ip) => ip,
+            Err(_) => {
+                let domain = domain.to_ascii_lowercase();
+                if domain == "localhost" || domain.ends_with(".localhost") {
+                    return Err(anyhow!("Refusing to announce to local tracker {}", url));
+                }
+                return Ok(());
+            }
+        },
+    };
+
+    match is_public(ip) {
+        true => Ok(()),
+        false => Err(anyhow!("Refusing to announce to local tracker {}", url)),
+    }
+}
+
+/// The addresses of `host`, a name or address and a port, leaving out any which
+/// aren't public unless `allow_local` is set.
+pub async fn resolve(host: &str, allow_local: bool) -> anyhow::Result<Vec<SocketAddr>> {
+    let addrs = tokio::net::lookup_host(host).await?;
+    let addrs: Vec<_> = addrs
+        .filter(|addr| allow_local || is_public(addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        return Err(anyhow!("{} has no public addresses", host));
+    }
+
+    Ok(addrs)
+}
+
+/// Resolves names for HTTP trackers to public addresses only, which catches names
+/// pointing at local addresses and redirects to them.
+#[derive(Debug)]
+pub struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = resolve(&format!("{}:0", name.as_str()), false).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Follow at most [`MAX_REDIRECTS`] redirects, each to an HTTP(S) URL which passes
+/// [`check_url`].
+pub fn redirect_policy(allow_local: bool) -> redirect::Policy {
+    redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > MAX_REDIRECTS {
+            return attempt.error(anyhow!("Tracker redirected too many times"));
+        }
+        if !matches!(attempt.url().scheme(), "http" | "https") {
+            let scheme = attempt.url().scheme().to_owned();
+            return attempt.error(anyhow!("Tracker redirected to a {} URL", scheme));
+        }
+        match check_url(attempt.url().as_str(), allow_local) {
+            Ok(_) => attempt.follow(),
+            Err(e) => attempt.error(e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reject_local_and_unknown_trackers() {
+        assert!(check_url("http://tracker.example/announce", false).is_ok());
+        assert!(check_url("udp://203.0.113.7:6969", false).is_ok());
+
+        for url in [
+            "file:///etc/passwd",
+            "gopher://tracker.example/",
+            "http://localhost:8080/announce",
+            "http://127.0.0.1/announce",
+            "http://10.0.0.1/announce",
+            "http://169.254.169.254/latest/meta-data",
+            "https://[::1]/announce",
+            "http://[::ffff:192.168.1.1]/announce",
+            "udp://127.0.0.1:6969",
+            "udp://api.localhost:6969",
+        ] {
+            assert!(check_url(url, false).is_err(), "{}", url);
+        }
+
+        assert!(check_url("http://127.0.0.1/announce", true).is_ok());
+        assert!(check_url("file:///etc/passwd", true).is_err());
+    }
+
+    #[tokio::test]
+    async fn resolve_only_public_addresses() {
+        assert!(resolve("127.0.0.1:6969", false).await.is_err());
+        assert_eq!(
+            resolve("127.0.0.1:6969", true).await.unwrap(),
+            vec!["127.0.0.1:6969".parse().unwrap()]
+        );
+        assert!(resolve("203.0.113.7:6969", false).await.is_ok());
+    }
+}
@@ -0,0 +1,713 @@
+use crate::peer::{PeerData, PeerSource};
+use crate::stats::TrackerStatus;
+use crate::torrent_file::{tracker_url, Torrent};
+use anyhow::anyhow;
+use rand::{seq::SliceRandom, Rng};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::time::{self, Duration};
+use tracing::{debug, warn};
+use udp::{UdpAnnounce, UdpTracker};
+
+pub mod guard;
+pub mod udp;
+
+pub use guard::check_url;
+
+#[derive(Debug, Deserialize)]
+struct TrackerResponse {
+    interval: u16,
+    #[serde(default)]
+    peers: ByteBuf,
+    #[serde(default)]
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<String>,
+    /// The address the tracker saw the announce come from (BEP 24).
+    #[serde(default)]
+    #[serde(rename = "external ip")]
+    external_ip: Option<ByteBuf>,
+}
+
+#[derive(Debug)]
+pub struct PeersInfo {
+    pub interval: u16,
+    pub peers: Vec<PeerData>,
+    /// The address the tracker sees us at, if it told us.
+    pub external_ip: Option<IpAddr>,
+}
+
+impl From<TrackerResponse> for PeersInfo {
+    fn from(res: TrackerResponse) -> Self {
+        let peers = res
+            .peers
+            .chunks_exact(6)
+            .map(|bytes| PeerData::from_bytes(bytes, PeerSource::Tracker))
+            .collect();
+
+        let external_ip = res.external_ip.and_then(|ip| match ip.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip.as_slice()).unwrap())),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip.as_slice()).unwrap())),
+            _ => None,
+        });
+
+        Self {
+            interval: res.interval,
+            peers,
+            external_ip,
+        }
+    }
+}
+
+/// Parameters sent to the tracker alongside the torrent's details on each announce.
+#[derive(Debug, Clone)]
+pub struct AnnounceParams<'a> {
+    /// The tracker's announce URL.
+    pub announce: &'a str,
+    pub peer_id: &'a [u8],
+    pub port: u16,
+    /// Random value identifying this client to the tracker if our IP address changes.
+    pub key: Option<u32>,
+    /// The `tracker id` the tracker returned on a previous announce.
+    pub tracker_id: Option<&'a str>,
+    /// How many peers we'd like the tracker to return.
+    pub numwant: Option<u32>,
+    /// Our public address, for trackers which can't tell it from the announce.
+    pub ip: Option<IpAddr>,
+    pub event: Option<AnnounceEvent>,
+}
+
+/// Events telling trackers when we join and leave a torrent's swarm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// The event as HTTP trackers expect it.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+
+    /// The event's code in UDP announces (BEP 15).
+    pub fn udp_code(self) -> u32 {
+        match self {
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+/// How much of the torrent we've transferred, as reported on each announce. Private
+/// trackers use these to keep track of users' ratios.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Transfer {
+    /// Bytes uploaded since the torrent started.
+    pub uploaded: u64,
+    /// Bytes downloaded since the torrent started.
+    pub downloaded: u64,
+    /// Bytes of the torrent we don't have verified pieces of yet.
+    pub left: u64,
+    /// We have every piece we want but not the whole torrent, so we'll only upload. Sent
+    /// as BEP 21's `paused` event so trackers don't count us as a leecher.
+    pub partial_seed: bool,
+}
+
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_DEMOTION: Duration = Duration::from_secs(30 * 60);
+
+/// Exponential backoff for the given attempt number, randomly jittered by up to 50%
+/// either way so many clients don't retry in lockstep.
+pub fn backoff(base: Duration, attempt: u32, max: Duration) -> Duration {
+    let exponential = base.saturating_mul(2u32.saturating_pow(attempt)).min(max);
+    exponential.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
+
+#[derive(Debug)]
+struct Tracker {
+    url: String,
+    udp: Option<UdpTracker>,
+    tracker_id: Option<String>,
+    failures: u32,
+    demoted_until: Option<Instant>,
+    /// Whether the tracker has been told we've started, and not since that we've stopped.
+    started: bool,
+    status: TrackerStatus,
+}
+
+impl Tracker {
+    fn new(url: String) -> Self {
+        Self {
+            status: TrackerStatus {
+                url: url.clone(),
+                status: String::from("not contacted"),
+                peers: 0,
+            },
+            url,
+            udp: None,
+            tracker_id: None,
+            failures: 0,
+            demoted_until: None,
+            started: false,
+        }
+    }
+
+    fn is_demoted(&self, now: Instant) -> bool {
+        matches!(self.demoted_until, Some(until) if until > now)
+    }
+}
+
+/// Announces a single torrent to its trackers, working through the tiers of its
+/// announce list and remembering the state trackers expect to be replayed on later announces.
+#[derive(Debug)]
+pub struct Announcer {
+    key: u32,
+    numwant: Option<u32>,
+    tiers: Vec<Vec<Tracker>>,
+    client: reqwest::Client,
+    bind_address: Option<IpAddr>,
+    /// Whether trackers on this machine or private networks can be announced to.
+    allow_local: bool,
+    external_ip: Option<IpAddr>,
+}
+
+impl Announcer {
+    pub fn new(torrent: &Torrent) -> anyhow::Result<Self> {
+        Self::from_trackers(torrent.trackers())
+    }
+
+    /// Announce to tiers of tracker URLs, such as those in a magnet link.
+    pub fn from_trackers(tiers: Vec<Vec<String>>) -> anyhow::Result<Self> {
+        let client = http_client(None, false)?;
+
+        let tiers = tiers
+            .into_iter()
+            .map(|mut tier| {
+                tier.shuffle(&mut rand::thread_rng());
+                tier.into_iter().map(Tracker::new).collect()
+            })
+            .collect();
+
+        Ok(Self {
+            key: rand::random(),
+            numwant: None,
+            tiers,
+            client,
+            bind_address: None,
+            allow_local: false,
+            external_ip: None,
+        })
+    }
+
+    /// Ask trackers for this many peers, rather than leaving it up to them.
+    pub fn with_numwant(mut self, numwant: Option<u32>) -> Self {
+        self.numwant = numwant;
+        self
+    }
+
+    /// Send announces from this local address.
+    pub fn with_bind_address(mut self, bind_address: Option<IpAddr>) -> anyhow::Result<Self> {
+        self.client = http_client(bind_address, self.allow_local)?;
+        self.bind_address = bind_address;
+        Ok(self)
+    }
+
+    /// Announce to trackers on this machine or private networks too, which are refused
+    /// by default so torrents can't make us send requests to internal services.
+    pub fn with_local_trackers(mut self, allow_local: bool) -> anyhow::Result<Self> {
+        self.client = http_client(self.bind_address, allow_local)?;
+        self.allow_local = allow_local;
+        Ok(self)
+    }
+
+    /// Tell trackers our public address is `ip` on later announces.
+    pub fn set_external_ip(&mut self, ip: Option<IpAddr>) {
+        self.external_ip = ip;
+    }
+
+    /// The tracker URLs in each tier, in the order they'll next be tried.
+    #[cfg(test)]
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        self.tiers
+            .iter()
+            .map(|tier| tier.iter().map(|t| t.url.clone()).collect())
+            .collect()
+    }
+
+    /// Announce to `tiers` from now on. Trackers which were already in the list keep
+    /// their tracker id and failure count.
+    pub fn set_trackers(&mut self, tiers: Vec<Vec<String>>) {
+        let mut existing: HashMap<_, _> = self
+            .tiers
+            .drain(..)
+            .flatten()
+            .map(|t| (t.url.clone(), t))
+            .collect();
+        self.tiers = tiers
+            .into_iter()
+            .map(|tier| {
+                tier.into_iter()
+                    .map(|url| existing.remove(&url).unwrap_or_else(|| Tracker::new(url)))
+                    .collect()
+            })
+            .collect();
+    }
+
+    /// The outcome of the most recent announce to each tracker.
+    pub fn statuses(&self) -> Vec<TrackerStatus> {
+        self.tiers
+            .iter()
+            .flatten()
+            .map(|t| t.status.clone())
+            .collect()
+    }
+
+    /// Announce to the first tracker which answers, trying each tier in order. Trackers
+    /// which keep failing are skipped for a while, unless every tracker has failed.
+    pub async fn announce(
+        &mut self,
+        torrent: &Torrent,
+        transfer: Transfer,
+        peer_id: &[u8],
+        port: u16,
+    ) -> anyhow::Result<PeersInfo> {
+        self.announce_info_hash(&torrent.info_hash, transfer, peer_id, port)
+            .await
+    }
+
+    /// Like [`Announcer::announce`], for a torrent we may not have the metainfo for yet.
+    #[tracing::instrument(skip(self, info_hash, peer_id))]
+    pub async fn announce_info_hash(
+        &mut self,
+        info_hash: &[u8; 20],
+        transfer: Transfer,
+        peer_id: &[u8],
+        port: u16,
+    ) -> anyhow::Result<PeersInfo> {
+        let now = Instant::now();
+        let all_demoted = self.tiers.iter().flatten().all(|t| t.is_demoted(now));
+        let mut last_error = anyhow!("Torrent has no trackers");
+
+        for tier in 0..self.tiers.len() {
+            for idx in 0..self.tiers[tier].len() {
+                if !all_demoted && self.tiers[tier][idx].is_demoted(now) {
+                    continue;
+                }
+                let tracker = &mut self.tiers[tier][idx];
+                if let Err(e) = check_url(&tracker.url, self.allow_local) {
+                    // Retrying won't help, so these aren't counted as failures.
+                    tracker.status.status = e.to_string();
+                    last_error = e;
+                    continue;
+                }
+
+                match self
+                    .announce_with_retry(tier, idx, info_hash, transfer, peer_id, port)
+                    .await
+                {
+                    Ok(info) => {
+                        // Prefer the tracker which answered next time, as BEP 12 describes.
+                        let tracker = self.tiers[tier].remove(idx);
+                        self.tiers[tier].insert(0, tracker);
+                        return Ok(info);
+                    }
+                    Err(e) => {
+                        let tracker = &mut self.tiers[tier][idx];
+                        tracker.failures += 1;
+                        let demotion = backoff(RETRY_BACKOFF, tracker.failures, MAX_DEMOTION);
+                        tracker.demoted_until = Some(Instant::now() + demotion);
+                        warn!(
+                            "Tracker {} failed {} times, skipping it for {:?}: {}",
+                            tracker.url, tracker.failures, demotion, e
+                        );
+                        last_error = e;
+                    }
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Tell every tracker we've announced to that the torrent has stopped, so they stop
+    /// handing out our address. Each is tried once, as there's nothing to gain from
+    /// retrying, and the next announce tells them we've started again.
+    pub async fn announce_stopped(
+        &mut self,
+        info_hash: &[u8; 20],
+        transfer: Transfer,
+        peer_id: &[u8],
+        port: u16,
+    ) {
+        for tier in 0..self.tiers.len() {
+            for idx in 0..self.tiers[tier].len() {
+                if !self.tiers[tier][idx].started {
+                    continue;
+                }
+                let event = Some(AnnounceEvent::Stopped);
+                if let Err(e) = self
+                    .announce_to(tier, idx, info_hash, transfer, peer_id, port, event)
+                    .await
+                {
+                    debug!(
+                        "Couldn't tell {} we've stopped: {}",
+                        self.tiers[tier][idx].url, e
+                    );
+                }
+                self.tiers[tier][idx].started = false;
+            }
+        }
+    }
+
+    async fn announce_with_retry(
+        &mut self,
+        tier: usize,
+        idx: usize,
+        info_hash: &[u8; 20],
+        transfer: Transfer,
+        peer_id: &[u8],
+        port: u16,
+    ) -> anyhow::Result<PeersInfo> {
+        let mut attempt = 0;
+        loop {
+            match self
+                .announce_to(tier, idx, info_hash, transfer, peer_id, port, None)
+                .await
+            {
+                Ok(info) => return Ok(info),
+                Err(e) if attempt + 1 < MAX_ATTEMPTS => {
+                    let delay = backoff(RETRY_BACKOFF, attempt, MAX_DEMOTION);
+                    debug!("Announce failed, retrying in {:?}: {}", delay, e);
+                    time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Announce to one tracker, with `event`, or the started event if it hasn't been
+    /// told we've started yet.
+    #[allow(clippy::too_many_arguments)]
+    async fn announce_to(
+        &mut self,
+        tier: usize,
+        idx: usize,
+        info_hash: &[u8; 20],
+        transfer: Transfer,
+        peer_id: &[u8],
+        port: u16,
+        event: Option<AnnounceEvent>,
+    ) -> anyhow::Result<PeersInfo> {
+        let tracker = &mut self.tiers[tier][idx];
+        let event = event.or((!tracker.started).then_some(AnnounceEvent::Started));
+        let params = AnnounceParams {
+            announce: &tracker.url,
+            peer_id,
+            port,
+            key: Some(self.key),
+            tracker_id: tracker.tracker_id.as_deref(),
+            numwant: self.numwant,
+            ip: self.external_ip,
+            event,
+        };
+
+        let result = if tracker.url.starts_with("udp://") {
+            let udp = match &mut tracker.udp {
+                Some(udp) => udp,
+                None => tracker.udp.insert(UdpTracker::new(
+                    &tracker.url,
+                    self.bind_address,
+                    self.allow_local,
+                )?),
+            };
+            let announce = UdpAnnounce {
+                info_hash: *info_hash,
+                downloaded: transfer.downloaded,
+                left: transfer.left,
+                uploaded: transfer.uploaded,
+            };
+            udp.announce(&params, &announce)
+                .await
+                .map(|info| (info, None))
+        } else {
+            http_announce(&self.client, info_hash, &transfer, &params).await
+        };
+
+        match result {
+            Ok((info, tracker_id)) => {
+                if let Some(tracker_id) = tracker_id {
+                    debug!("Tracker {} gave us tracker id {}", tracker.url, tracker_id);
+                    tracker.tracker_id = Some(tracker_id);
+                }
+                tracker.failures = 0;
+                tracker.demoted_until = None;
+                tracker.started = event != Some(AnnounceEvent::Stopped);
+                tracker.status.status = String::from(match tracker.started {
+                    true => "working",
+                    false => "stopped",
+                });
+                tracker.status.peers = info.peers.len();
+
+                Ok(info)
+            }
+            Err(e) => {
+                tracker.status.status = e.to_string();
+                tracker.status.peers = 0;
+
+                Err(e)
+            }
+        }
+    }
+}
+
+fn http_client(bind_address: Option<IpAddr>, allow_local: bool) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(15))
+        .local_address(bind_address)
+        .redirect(guard::redirect_policy(allow_local));
+    if !allow_local {
+        builder = builder.dns_resolver(Arc::new(guard::PublicResolver));
+    }
+    Ok(builder.build()?)
+}
+
+/// Announce over HTTP, returning the peers and any tracker id the tracker gave us.
+async fn http_announce(
+    client: &reqwest::Client,
+    info_hash: &[u8; 20],
+    transfer: &Transfer,
+    params: &AnnounceParams<'_>,
+) -> anyhow::Result<(PeersInfo, Option<String>)> {
+    let url = tracker_url(params, info_hash, transfer)?;
+    let req = client.get(url).build()?;
+    let tracker_response = client.execute(req).await?.error_for_status()?;
+
+    let bytes = tracker_response.bytes().await?;
+    let mut tracker_response: TrackerResponse = serde_bencode::from_bytes(&bytes)?;
+    let tracker_id = tracker_response.tracker_id.take();
+
+    Ok((tracker_response.into(), tracker_id))
+}
+
+pub async fn request_peer_info(
+    torrent: &Torrent,
+    peer_id: &[u8],
+    port: u16,
+) -> anyhow::Result<PeersInfo> {
+    let transfer = Transfer {
+        left: torrent.file.info.total_length(),
+        ..Default::default()
+    };
+    Announcer::new(torrent)?
+        .announce(torrent, transfer, peer_id, port)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_tracker_id() {
+        let bytes = b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe110:tracker id3:abce";
+        let response: TrackerResponse = serde_bencode::from_bytes(bytes).unwrap();
+
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+
+        let info = PeersInfo::from(response);
+        assert_eq!(info.interval, 1800);
+        assert_eq!(info.peers[0].addr(), "127.0.0.1:6881".parse().unwrap());
+        assert_eq!(info.external_ip, None);
+
+        let bytes = b"d11:external ip4:\xcb\x00\x71\x078:intervali1800ee";
+        let info = PeersInfo::from(serde_bencode::from_bytes::<TrackerResponse>(bytes).unwrap());
+        assert_eq!(info.external_ip, Some("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn report_transfer_in_announce_url() {
+        let params = AnnounceParams {
+            announce: "http://tracker/announce",
+            peer_id: b"-RS0001-123456789012",
+            port: 6881,
+            key: None,
+            tracker_id: None,
+            numwant: None,
+            ip: None,
+            event: None,
+        };
+        let transfer = Transfer {
+            uploaded: 300,
+            downloaded: 200,
+            left: 100,
+            partial_seed: false,
+        };
+        let url = tracker_url(&params, &[1; 20], &transfer).unwrap();
+        let query: HashMap<_, _> = url.query_pairs().collect();
+
+        assert_eq!(query["uploaded"], "300");
+        assert_eq!(query["downloaded"], "200");
+        assert_eq!(query["left"], "100");
+        assert!(!query.contains_key("event"));
+
+        let transfer = Transfer {
+            partial_seed: true,
+            ..transfer
+        };
+        let url = tracker_url(&params, &[1; 20], &transfer).unwrap();
+        let query: HashMap<_, _> = url.query_pairs().collect();
+        assert_eq!(query["event"], "paused");
+        assert!(!query.contains_key("ip"));
+
+        let params = AnnounceParams {
+            ip: Some("203.0.113.7".parse().unwrap()),
+            ..params
+        };
+        let url = tracker_url(&params, &[1; 20], &transfer).unwrap();
+        let query: HashMap<_, _> = url.query_pairs().collect();
+        assert_eq!(query["ip"], "203.0.113.7");
+
+        let params = AnnounceParams {
+            event: Some(AnnounceEvent::Started),
+            ..params
+        };
+        let url = tracker_url(&params, &[1; 20], &transfer).unwrap();
+        let query: HashMap<_, _> = url.query_pairs().collect();
+        assert_eq!(query["event"], "started");
+    }
+
+    #[test]
+    fn backoff_is_jittered_and_capped() {
+        let base = Duration::from_secs(1);
+        let max = Duration::from_secs(60);
+
+        for attempt in 0..4 {
+            let delay = backoff(base, attempt, max);
+            let expected = base * 2u32.pow(attempt);
+            assert!(delay >= expected / 2 && delay < expected * 3 / 2);
+        }
+
+        assert!(backoff(base, 30, max) < max * 3 / 2);
+    }
+
+    #[test]
+    fn replace_trackers_keeping_state() {
+        let mut announcer = Announcer::from_trackers(vec![
+            vec![String::from("http://a/announce")],
+            vec![String::from("http://b/announce")],
+        ])
+        .unwrap();
+        announcer.tiers[0][0].tracker_id = Some(String::from("abc"));
+
+        announcer.set_trackers(vec![
+            vec![String::from("http://c/announce")],
+            vec![String::from("http://a/announce")],
+        ]);
+
+        assert_eq!(
+            announcer.trackers(),
+            vec![vec!["http://c/announce"], vec!["http://a/announce"]]
+        );
+        assert_eq!(announcer.tiers[0][0].tracker_id, None);
+        assert_eq!(announcer.tiers[1][0].tracker_id.as_deref(), Some("abc"));
+        assert_eq!(announcer.statuses().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn skip_local_trackers_unless_allowed() {
+        let mut announcer =
+            Announcer::from_trackers(vec![vec![String::from("http://127.0.0.1:1/announce")]])
+                .unwrap();
+        let result = announcer
+            .announce_info_hash(&[1; 20], Transfer::default(), b"-RS0001-123456789012", 6881)
+            .await;
+
+        assert!(result.unwrap_err().to_string().contains("local tracker"));
+        assert_eq!(announcer.tiers[0][0].failures, 0);
+        assert!(announcer.statuses()[0].status.contains("local tracker"));
+    }
+
+    #[tokio::test]
+    async fn tell_trackers_when_we_start_and_stop() {
+        use bytes::{Buf, BufMut, BytesMut};
+        use tokio::net::UdpSocket;
+
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        // Answers connects and announces, returning the events announced with.
+        let fake_tracker = tokio::spawn(async move {
+            let mut events = Vec::new();
+            let mut buf = [0; 2048];
+            while events.len() < 4 {
+                let (len, from) = server.recv_from(&mut buf).await.unwrap();
+                let mut request = &buf[8..len];
+                let action = request.get_u32();
+                let transaction_id = request.get_u32();
+                let mut response = BytesMut::new();
+                response.put_u32(action);
+                response.put_u32(transaction_id);
+                if action == 0 {
+                    response.put_u64(1234);
+                } else {
+                    events.push(u32::from_be_bytes(buf[80..84].try_into().unwrap()));
+                    response.put_u32(1800);
+                    response.put_u64(0);
+                }
+                server.send_to(&response, from).await.unwrap();
+            }
+            events
+        });
+
+        let mut announcer = Announcer::from_trackers(vec![vec![format!("udp://{}", addr)]])
+            .unwrap()
+            .with_local_trackers(true)
+            .unwrap();
+        let peer_id = b"-RS0001-123456789012";
+        let transfer = Transfer::default();
+        announcer
+            .announce_info_hash(&[1; 20], transfer, peer_id, 6881)
+            .await
+            .unwrap();
+        announcer
+            .announce_info_hash(&[1; 20], transfer, peer_id, 6881)
+            .await
+            .unwrap();
+        announcer
+            .announce_stopped(&[1; 20], transfer, peer_id, 6881)
+            .await;
+        assert_eq!(announcer.statuses()[0].status, "stopped");
+        // Trackers which weren't told we started aren't told we've stopped.
+        announcer
+            .announce_stopped(&[1; 20], transfer, peer_id, 6881)
+            .await;
+        announcer
+            .announce_info_hash(&[1; 20], transfer, peer_id, 6881)
+            .await
+            .unwrap();
+
+        assert_eq!(fake_tracker.await.unwrap(), vec![2, 0, 3, 2]);
+    }
+
+    #[test]
+    fn parse_response_without_peers() {
+        let bytes = b"d8:intervali900ee";
+        let info = PeersInfo::from(serde_bencode::from_bytes::<TrackerResponse>(bytes).unwrap());
+
+        assert_eq!(info.interval, 900);
+        assert!(info.peers.is_empty());
+
+        let bytes = b"d8:intervali900e5:peers0:e";
+        let info = PeersInfo::from(serde_bencode::from_bytes::<TrackerResponse>(bytes).unwrap());
+
+        assert!(info.peers.is_empty());
+    }
+}
@@ -0,0 +1,319 @@
+//! The UDP tracker protocol described in BEP 15.
+
+use super::{guard, AnnounceEvent, AnnounceParams, PeersInfo};
+use crate::net;
+use crate::peer::{PeerData, PeerSource};
+use anyhow::anyhow;
+use bytes::{Buf, BufMut, BytesMut};
+use reqwest::Url;
+use std::net::IpAddr;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration};
+use tracing::debug;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+
+/// How long a connection id may be used for after the tracker hands it out.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Details of the announce which aren't covered by [`AnnounceParams`].
+#[derive(Debug, Clone)]
+pub struct UdpAnnounce {
+    pub info_hash: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+}
+
+#[derive(Debug)]
+pub struct UdpTracker {
+    host: String,
+    bind_address: Option<IpAddr>,
+    /// Whether the tracker's name can resolve to a local address.
+    allow_local: bool,
+    socket: Option<UdpSocket>,
+    connection: Option<(u64, Instant)>,
+}
+
+impl UdpTracker {
+    pub fn new(url: &str, bind_address: Option<IpAddr>, allow_local: bool) -> anyhow::Result<Self> {
+        let url = Url::parse(url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("UDP tracker URL has no host"))?;
+        let port = url
+            .port()
+            .ok_or_else(|| anyhow!("UDP tracker URL has no port"))?;
+
+        Ok(Self {
+            host: format!("{}:{}", host, port),
+            bind_address,
+            allow_local,
+            socket: None,
+            connection: None,
+        })
+    }
+
+    async fn socket(&mut self) -> anyhow::Result<&UdpSocket> {
+        if self.socket.is_none() {
+            let addrs = guard::resolve(&self.host, self.allow_local).await?;
+            let socket = net::bind_udp(self.bind_address).await?;
+            socket.connect(&addrs[..]).await?;
+            self.socket = Some(socket);
+        }
+
+        Ok(self.socket.as_ref().unwrap())
+    }
+
+    /// Send a request and wait for the response with the same transaction id, returning
+    /// the response's action and the body following its header.
+    async fn round_trip(
+        &mut self,
+        request: &[u8],
+        transaction_id: u32,
+    ) -> anyhow::Result<(u32, BytesMut)> {
+        let socket = self.socket().await?;
+        socket.send(request).await?;
+
+        let mut buf = vec![0; 2048];
+        loop {
+            let len = time::timeout(RESPONSE_TIMEOUT, socket.recv(&mut buf))
+                .await
+                .map_err(|_| anyhow!("Timed out waiting for UDP tracker"))??;
+            let mut response = BytesMut::from(&buf[..len]);
+
+            if response.remaining() < 8 {
+                return Err(anyhow!("UDP tracker response too short"));
+            }
+            let action = response.get_u32();
+            if response.get_u32() != transaction_id {
+                // A late response to an earlier request.
+                continue;
+            }
+
+            if action == ACTION_ERROR {
+                let message = String::from_utf8_lossy(&response).into_owned();
+                return Err(anyhow!("UDP tracker error: {}", message));
+            }
+
+            return Ok((action, response));
+        }
+    }
+
+    /// The connection id to use for the next request, connecting to the tracker
+    /// again if we don't have one or it has expired.
+    async fn connection_id(&mut self) -> anyhow::Result<u64> {
+        if let Some((id, at)) = self.connection {
+            if at.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(id);
+            }
+        }
+
+        let transaction_id = rand::random();
+        let mut request = BytesMut::with_capacity(16);
+        request.put_u64(PROTOCOL_ID);
+        request.put_u32(ACTION_CONNECT);
+        request.put_u32(transaction_id);
+
+        let (action, mut response) = self.round_trip(&request, transaction_id).await?;
+        if action != ACTION_CONNECT || response.remaining() < 8 {
+            return Err(anyhow!("Invalid UDP tracker connect response"));
+        }
+        let id = response.get_u64();
+        debug!("Connected to UDP tracker {}", self.host);
+        self.connection = Some((id, Instant::now()));
+
+        Ok(id)
+    }
+
+    /// Run a request which needs a connection id, connecting again once if the
+    /// request fails with a cached id in case the tracker has forgotten it.
+    async fn request(
+        &mut self,
+        build: impl Fn(u64, u32) -> BytesMut,
+        action: u32,
+    ) -> anyhow::Result<BytesMut> {
+        let cached = self.connection.is_some();
+        for attempt in 0..2 {
+            let connection_id = self.connection_id().await?;
+            let transaction_id = rand::random();
+            let request = build(connection_id, transaction_id);
+
+            match self.round_trip(&request, transaction_id).await {
+                Ok((response_action, response)) if response_action == action => {
+                    return Ok(response)
+                }
+                Ok(_) => return Err(anyhow!("Unexpected UDP tracker response")),
+                Err(e) if cached && attempt == 0 => {
+                    debug!("Request with cached connection id failed: {}", e);
+                    self.connection = None;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        unreachable!()
+    }
+
+    pub async fn announce(
+        &mut self,
+        params: &AnnounceParams<'_>,
+        announce: &UdpAnnounce,
+    ) -> anyhow::Result<PeersInfo> {
+        let mut response = self
+            .request(
+                |connection_id, transaction_id| {
+                    encode_announce(connection_id, transaction_id, params, announce)
+                },
+                ACTION_ANNOUNCE,
+            )
+            .await?;
+
+        parse_announce(&mut response)
+    }
+}
+
+fn encode_announce(
+    connection_id: u64,
+    transaction_id: u32,
+    params: &AnnounceParams,
+    announce: &UdpAnnounce,
+) -> BytesMut {
+    let mut request = BytesMut::with_capacity(98);
+    request.put_u64(connection_id);
+    request.put_u32(ACTION_ANNOUNCE);
+    request.put_u32(transaction_id);
+    request.extend_from_slice(&announce.info_hash);
+    request.extend_from_slice(params.peer_id);
+    request.put_u64(announce.downloaded);
+    request.put_u64(announce.left);
+    request.put_u64(announce.uploaded);
+    // BEP 21's paused event is only defined for HTTP trackers, so partial seeds send none.
+    request.put_u32(params.event.map_or(0, AnnounceEvent::udp_code));
+    // ip: 0 lets the tracker use the packet's source address. Only IPv4 fits.
+    let ip = match params.ip {
+        Some(IpAddr::V4(ip)) => ip.into(),
+        _ => 0,
+    };
+    request.put_u32(ip);
+    request.put_u32(params.key.unwrap_or_default());
+    request.put_i32(params.numwant.map(|n| n as i32).unwrap_or(-1));
+    request.put_u16(params.port);
+
+    request
+}
+
+/// Parse an announce response, after the action and transaction id.
+fn parse_announce(response: &mut BytesMut) -> anyhow::Result<PeersInfo> {
+    if response.remaining() < 12 {
+        return Err(anyhow!("UDP announce response too short"));
+    }
+    let interval = response.get_u32();
+    let _leechers = response.get_u32();
+    let _seeders = response.get_u32();
+
+    Ok(PeersInfo {
+        interval: interval.min(u16::MAX as u32) as u16,
+        peers: response
+            .chunks_exact(6)
+            .map(|bytes| PeerData::from_bytes(bytes, PeerSource::Tracker))
+            .collect(),
+        external_ip: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params() -> AnnounceParams<'static> {
+        AnnounceParams {
+            announce: "udp://127.0.0.1:6969",
+            peer_id: b"-TR2940-k8hj0wgej6ch",
+            port: 6881,
+            key: Some(7),
+            tracker_id: None,
+            numwant: None,
+            ip: None,
+            event: None,
+        }
+    }
+
+    fn announce() -> UdpAnnounce {
+        UdpAnnounce {
+            info_hash: [1; 20],
+            downloaded: 0,
+            left: 100,
+            uploaded: 0,
+        }
+    }
+
+    #[test]
+    fn encode_announce_request() {
+        let request = encode_announce(42, 9, &params(), &announce());
+
+        assert_eq!(request.len(), 98);
+        assert_eq!(&request[..8], &42u64.to_be_bytes());
+        assert_eq!(&request[12..16], &9u32.to_be_bytes());
+        assert_eq!(&request[80..84], &0u32.to_be_bytes());
+        assert_eq!(&request[92..96], &(-1i32).to_be_bytes());
+        assert_eq!(&request[96..], &6881u16.to_be_bytes());
+
+        let params = AnnounceParams {
+            event: Some(AnnounceEvent::Stopped),
+            ..params()
+        };
+        let request = encode_announce(42, 9, &params, &announce());
+        assert_eq!(&request[80..84], &3u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn reuse_connection_id_between_announces() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let fake_tracker = tokio::spawn(async move {
+            let mut connects = 0;
+            let mut buf = [0; 2048];
+            for _ in 0..3 {
+                let (len, from) = server.recv_from(&mut buf).await.unwrap();
+                let mut request = &buf[..len];
+                let connection_id = request.get_u64();
+                let action = request.get_u32();
+                let transaction_id = request.get_u32();
+
+                let mut response = BytesMut::new();
+                response.put_u32(action);
+                response.put_u32(transaction_id);
+                if action == ACTION_CONNECT {
+                    assert_eq!(connection_id, PROTOCOL_ID);
+                    connects += 1;
+                    response.put_u64(1234);
+                } else {
+                    assert_eq!(connection_id, 1234);
+                    response.put_u32(1800);
+                    response.put_u32(0);
+                    response.put_u32(1);
+                    response.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+                }
+                server.send_to(&response, from).await.unwrap();
+            }
+
+            connects
+        });
+
+        let mut tracker = UdpTracker::new(&format!("udp://{}", addr), None, true).unwrap();
+        let info = tracker.announce(&params(), &announce()).await.unwrap();
+        assert_eq!(info.interval, 1800);
+        assert_eq!(info.peers[0].addr(), "127.0.0.1:6881".parse().unwrap());
+
+        tracker.announce(&params(), &announce()).await.unwrap();
+
+        assert_eq!(fake_tracker.await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,492 @@
+//! Checking content on disk against the hashes in the torrent.
+
+use crate::bitfield::{Bitfield, BitfieldMut};
+use crate::hash_cache::HashCache;
+use crate::magnet::hex;
+use crate::piece_hash::{MerkleTree, PieceVerifier};
+use crate::storage::{FileEntry, Storage};
+use crate::Torrent;
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
+use tracing::{debug, info};
+
+/// The result of checking a download against its torrent.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Verification {
+    pub piece_count: usize,
+    /// Pieces which are missing, short or don't match their hash.
+    pub bad_pieces: Vec<usize>,
+    /// Files which don't exist.
+    pub missing_files: Vec<PathBuf>,
+    /// Files whose `md5sum` in the torrent doesn't match their content.
+    pub md5_mismatches: Vec<PathBuf>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.bad_pieces.is_empty()
+            && self.missing_files.is_empty()
+            && self.md5_mismatches.is_empty()
+    }
+
+    /// The pieces which are intact, in the form sent in a `Bitfield` message.
+    pub fn bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0; self.piece_count.div_ceil(8)];
+        for idx in (0..self.piece_count).filter(|idx| !self.bad_pieces.contains(idx)) {
+            bitfield.set_piece(idx);
+        }
+
+        bitfield
+    }
+}
+
+impl std::fmt::Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} pieces ok, {} missing files, {} md5 mismatches",
+            self.piece_count - self.bad_pieces.len(),
+            self.piece_count,
+            self.missing_files.len(),
+            self.md5_mismatches.len()
+        )
+    }
+}
+
+/// How far a hash check has got.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CheckProgress {
+    pub pieces_checked: usize,
+    pub piece_count: usize,
+    /// The file being read.
+    pub file: Option<PathBuf>,
+}
+
+impl CheckProgress {
+    pub fn percent(&self) -> f64 {
+        match self.piece_count {
+            0 => 100.0,
+            n => self.pieces_checked as f64 * 100.0 / n as f64,
+        }
+    }
+}
+
+/// Follows a hash check as it runs, and cancels it. Clones follow the same check.
+#[derive(Clone)]
+pub struct HashCheck {
+    progress: Arc<watch::Sender<CheckProgress>>,
+    cancelled: Arc<AtomicBool>,
+    /// Told each piece's result as soon as it's known.
+    on_piece: Option<Arc<dyn Fn(usize, bool) + Send + Sync>>,
+}
+
+impl std::fmt::Debug for HashCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HashCheck")
+            .field("progress", &*self.progress.borrow())
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl Default for HashCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HashCheck {
+    pub fn new() -> Self {
+        Self {
+            progress: Arc::new(watch::Sender::new(CheckProgress::default())),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            on_piece: None,
+        }
+    }
+
+    /// Call `on_piece` with each piece's index and whether it's intact, as the check
+    /// gets to it. Merkle torrents' pieces are only known once they've all been read.
+    pub fn with_on_piece(mut self, on_piece: impl Fn(usize, bool) + Send + Sync + 'static) -> Self {
+        self.on_piece = Some(Arc::new(on_piece));
+        self
+    }
+
+    pub fn progress(&self) -> CheckProgress {
+        self.progress.borrow().clone()
+    }
+
+    /// Watch the check's progress as it changes.
+    pub fn subscribe(&self) -> watch::Receiver<CheckProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Stop the check before the next file or piece it reads, failing it.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn ensure_running(&self) -> anyhow::Result<()> {
+        if self.is_cancelled() {
+            anyhow::bail!("Hash check cancelled");
+        }
+        Ok(())
+    }
+
+    fn update(&self, pieces_checked: usize, piece_count: usize, file: Option<&Path>) {
+        let file = file.map(Path::to_path_buf);
+        self.progress.send_if_modified(|progress| {
+            let step = |p: &CheckProgress| (p.percent() / 10.0) as u32;
+            let new = CheckProgress {
+                pieces_checked,
+                piece_count,
+                file,
+            };
+            if step(&new) > step(progress) && pieces_checked < piece_count {
+                info!("Checked {:.0}% of pieces", new.percent());
+            }
+            let modified = *progress != new;
+            *progress = new;
+            modified
+        });
+    }
+}
+
+/// Re-hash the torrent's content under `root` (the directory it was downloaded to),
+/// checking every piece and, for files which have one, the `md5sum`.
+pub async fn verify(torrent: &Torrent, root: &Path) -> anyhow::Result<Verification> {
+    verify_with(torrent, root, &HashCheck::new()).await
+}
+
+/// Like [`verify`], reporting progress to `check`, and failing if it's cancelled.
+pub async fn verify_with(
+    torrent: &Torrent,
+    root: &Path,
+    check: &HashCheck,
+) -> anyhow::Result<Verification> {
+    Ok(verify_with_tree(torrent, root, check, &[]).await?.0)
+}
+
+/// Like [`verify_with`], checking a merkle torrent's pieces with `tree_hashes` from
+/// [`PieceVerifier::known_hashes`] too, and giving back the hashes proven by the check.
+async fn verify_with_tree(
+    torrent: &Torrent,
+    root: &Path,
+    check: &HashCheck,
+    tree_hashes: &[(usize, [u8; 20])],
+) -> anyhow::Result<(Verification, Vec<(usize, [u8; 20])>)> {
+    let storage = Storage::new(torrent, root)?;
+    let piece_count = storage.piece_count();
+    let mut verification = Verification {
+        piece_count,
+        ..Default::default()
+    };
+
+    for file in storage.files().iter() {
+        check.ensure_running()?;
+        if fs::metadata(&file.path).await.is_err() {
+            verification.missing_files.push(file.path.clone());
+        } else if let Some(expected) = &file.md5sum {
+            check.update(0, piece_count, Some(&file.path));
+            if !expected.eq_ignore_ascii_case(&md5_file(file).await?) {
+                verification.md5_mismatches.push(file.path.clone());
+            }
+        }
+    }
+
+    if let Some(root_hash) = torrent.file.info.merkle_root()? {
+        let (tree, bad_pieces) = check_tree(&storage, root_hash, tree_hashes, check).await?;
+        verification.bad_pieces = bad_pieces;
+        return Ok((verification, tree.known_hashes()));
+    }
+
+    let verifier = torrent.piece_verifier()?;
+    for idx in 0..verifier.piece_count() {
+        check.ensure_running()?;
+        let file = storage.piece_file(idx).map(|f| f.path);
+        check.update(idx, piece_count, file.as_deref());
+        let ok = match storage.read_piece(idx).await {
+            Ok(buf) => verifier.verify(idx, &buf),
+            Err(_) => false,
+        };
+        if !ok {
+            verification.bad_pieces.push(idx);
+        }
+        if let Some(on_piece) = &check.on_piece {
+            on_piece(idx, ok);
+        }
+    }
+    check.update(piece_count, piece_count, None);
+
+    Ok((verification, Vec::new()))
+}
+
+/// The hash tree of the merkle torrent's intact pieces under `root`, with the hashes
+/// `cache` has from earlier checks, as a seed needs it to send peers hash chains.
+pub async fn merkle_tree(
+    torrent: &Torrent,
+    root: &Path,
+    cache: Option<&HashCache>,
+) -> anyhow::Result<MerkleTree> {
+    let root_hash = torrent
+        .file
+        .info
+        .merkle_root()?
+        .ok_or_else(|| anyhow::anyhow!("Not a merkle torrent"))?;
+    let tree_hashes = match cache {
+        Some(cache) => cache.load_tree(&torrent.info_hash).await,
+        None => Vec::new(),
+    };
+    let storage = Storage::new(torrent, root)?;
+    let (tree, _) = check_tree(&storage, root_hash, &tree_hashes, &HashCheck::new()).await?;
+
+    Ok(tree)
+}
+
+/// Check a merkle torrent's pieces one by one against `root_hash`, giving the tree the
+/// check proved and the bad pieces. Hashes over pieces come from `tree_hashes` where
+/// they're known, and are otherwise worked out from the pieces on disk, so without
+/// hashes from an earlier check or download one bad piece fails the pieces it's hashed
+/// together with.
+async fn check_tree(
+    storage: &Storage,
+    root_hash: [u8; 20],
+    tree_hashes: &[(usize, [u8; 20])],
+    check: &HashCheck,
+) -> anyhow::Result<(MerkleTree, Vec<usize>)> {
+    let piece_count = storage.piece_count();
+    let mut leaves = Vec::with_capacity(piece_count);
+    for idx in 0..piece_count {
+        check.ensure_running()?;
+        let file = storage.piece_file(idx).map(|f| f.path);
+        check.update(idx, piece_count, file.as_deref());
+        if let Ok(buf) = storage.read_piece(idx).await {
+            leaves.push((idx, Sha1::digest(&buf).into()));
+        }
+    }
+
+    let tree = MerkleTree::new(root_hash, piece_count);
+    tree.add_leaves(&leaves);
+    // Hashes proven before take the place of those worked out from what's on disk now.
+    tree.add_hashes(tree_hashes);
+    let mut leaves = leaves.into_iter().peekable();
+    let mut bad_pieces = Vec::new();
+    for idx in 0..piece_count {
+        let ok = match leaves.next_if(|(leaf, _)| *leaf == idx) {
+            Some((_, hash)) => tree.verify_hash(idx, hash),
+            None => false,
+        };
+        if !ok {
+            bad_pieces.push(idx);
+        }
+        if let Some(on_piece) = &check.on_piece {
+            on_piece(idx, ok);
+        }
+    }
+    check.update(piece_count, piece_count, None);
+
+    Ok((tree, bad_pieces))
+}
+
+/// Like [`verify`], but trusts the pieces `cache` says are intact if the files haven't
+/// changed since, and remembers the result otherwise.
+pub async fn verify_cached(
+    torrent: &Torrent,
+    root: &Path,
+    cache: &HashCache,
+) -> anyhow::Result<Verification> {
+    let storage = Storage::new(torrent, root)?;
+    let piece_count = storage.piece_count();
+    if let Some(bitfield) = cache.load(&torrent.info_hash, &storage).await {
+        if bitfield.len() == piece_count.div_ceil(8) {
+            debug!("Using cached hash check for {}", torrent.file.info.name);
+            return Ok(Verification {
+                piece_count,
+                bad_pieces: (0..piece_count)
+                    .filter(|&idx| !bitfield.has_piece(idx))
+                    .collect(),
+                ..Default::default()
+            });
+        }
+    }
+
+    verify_and_cache(torrent, root, cache).await
+}
+
+/// Like [`verify`], remembering the result in `cache` for [`verify_cached`] to trust.
+pub async fn verify_and_cache(
+    torrent: &Torrent,
+    root: &Path,
+    cache: &HashCache,
+) -> anyhow::Result<Verification> {
+    verify_and_cache_with(torrent, root, cache, &HashCheck::new()).await
+}
+
+/// Like [`verify_and_cache`], reporting progress to `check`, and failing if it's cancelled.
+pub async fn verify_and_cache_with(
+    torrent: &Torrent,
+    root: &Path,
+    cache: &HashCache,
+    check: &HashCheck,
+) -> anyhow::Result<Verification> {
+    let tree_hashes = cache.load_tree(&torrent.info_hash).await;
+    let (verification, tree_hashes) = verify_with_tree(torrent, root, check, &tree_hashes).await?;
+    let storage = Storage::new(torrent, root)?;
+    cache
+        .store(&torrent.info_hash, &storage, &verification.bitfield())
+        .await?;
+    if !tree_hashes.is_empty() {
+        cache.store_tree(&torrent.info_hash, &tree_hashes).await?;
+    }
+
+    Ok(verification)
+}
+
+async fn md5_file(file: &FileEntry) -> anyhow::Result<String> {
+    let mut f = fs::File::open(&file.path).await?;
+    let mut hasher = Md5::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hex(&hasher.finalize()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent_file::{Info, TorrentFile};
+    use serde_bytes::ByteBuf;
+
+    fn torrent(content: &[u8], md5sum: Option<String>) -> Torrent {
+        let pieces: Vec<u8> = content.chunks(4).flat_map(Sha1::digest).collect();
+        TorrentFile {
+            info: Info {
+                name: "file".into(),
+                pieces: ByteBuf::from(pieces),
+                piece_length: 4,
+                md5sum,
+                length: Some(content.len() as i64),
+                files: None,
+                private: None,
+                path: None,
+                root_hash: None,
+                meta_version: None,
+                file_tree: None,
+            },
+            announce: None,
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            piece_layers: None,
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn find_corrupt_pieces_and_md5_mismatches() {
+        let root = std::env::temp_dir().join(format!("verify-test-{}", std::process::id()));
+        fs::create_dir_all(&root).await.unwrap();
+        let md5sum = hex(&Md5::digest(b"hello world"));
+        let torrent = torrent(b"hello world", Some(md5sum));
+
+        let verification = verify(&torrent, &root).await.unwrap();
+        assert_eq!(verification.missing_files.len(), 1);
+        assert_eq!(verification.bad_pieces, vec![0, 1, 2]);
+
+        fs::write(root.join("file"), b"hello world").await.unwrap();
+        assert!(verify(&torrent, &root).await.unwrap().is_ok());
+
+        fs::write(root.join("file"), b"hello wOrld").await.unwrap();
+        let verification = verify(&torrent, &root).await.unwrap();
+        assert_eq!(verification.bad_pieces, vec![1]);
+        assert_eq!(verification.md5_mismatches.len(), 1);
+        assert_eq!(verification.bitfield(), vec![0b1010_0000]);
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn report_progress_and_cancel_checks() {
+        let root = std::env::temp_dir().join(format!("verify-progress-{}", std::process::id()));
+        fs::create_dir_all(&root).await.unwrap();
+        let torrent = torrent(b"hello world", None);
+        fs::write(root.join("file"), b"hello wOrld").await.unwrap();
+
+        let results = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let check = HashCheck::new().with_on_piece({
+            let results = Arc::clone(&results);
+            move |idx, ok| results.lock().unwrap().push((idx, ok))
+        });
+        let verification = verify_with(&torrent, &root, &check).await.unwrap();
+        assert_eq!(verification.bad_pieces, vec![1]);
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![(0, true), (1, false), (2, true)]
+        );
+        assert_eq!(check.progress().percent(), 100.0);
+
+        let check = HashCheck::new();
+        check.cancel();
+        assert!(verify_with(&torrent, &root, &check).await.is_err());
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_merkle_torrent_against_root_hash() {
+        let root = std::env::temp_dir().join(format!("verify-merkle-{}", std::process::id()));
+        fs::create_dir_all(&root).await.unwrap();
+        let mut torrent = torrent(b"hello world", None);
+        torrent.file.info.use_merkle_root();
+
+        fs::write(root.join("file"), b"hello world").await.unwrap();
+        assert!(verify(&torrent, &root).await.unwrap().is_ok());
+
+        // Without the hashes over it, a bad piece can't be told from its neighbours.
+        fs::write(root.join("file"), b"hello wOrld").await.unwrap();
+        let verification = verify(&torrent, &root).await.unwrap();
+        assert_eq!(verification.bad_pieces, vec![0, 1, 2]);
+
+        // With those an earlier check proved, only the bad piece fails.
+        let cache = HashCache::new(root.join("state"));
+        fs::write(root.join("file"), b"hello world").await.unwrap();
+        assert!(verify_and_cache(&torrent, &root, &cache)
+            .await
+            .unwrap()
+            .is_ok());
+        fs::write(root.join("file"), b"hello wOrld").await.unwrap();
+        let verification = verify_and_cache(&torrent, &root, &cache).await.unwrap();
+        assert_eq!(verification.bad_pieces, vec![1]);
+
+        // A seed's tree has the hash chains of the intact pieces.
+        let tree = merkle_tree(&torrent, &root, Some(&cache)).await.unwrap();
+        assert!(tree.hash_chain(0).is_some());
+
+        // Missing content fails only its own pieces.
+        fs::write(root.join("file"), b"hello w").await.unwrap();
+        let verification = verify_and_cache(&torrent, &root, &cache).await.unwrap();
+        assert_eq!(verification.bad_pieces, vec![1, 2]);
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
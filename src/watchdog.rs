@@ -0,0 +1,288 @@
+//! Holding torrents back while the disk they're saved to is nearly full or the network
+//! is metered, and letting them carry on once that's no longer so.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// How far above the minimum free space a full disk has to get before torrents saved to
+/// it resume, as a fraction of the minimum, so they don't flap around it.
+const RESUME_MARGIN: f64 = 0.05;
+
+/// What the watchdog last found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Conditions {
+    /// Save paths on disks with less free space than the minimum.
+    pub low_disks: BTreeSet<PathBuf>,
+    /// Whether the system says the network is metered.
+    pub metered: bool,
+}
+
+impl Conditions {
+    /// Whether a torrent saved to `save_path` should be held back. Seeding only reads
+    /// from disk, so full disks only hold back torrents which are still downloading.
+    pub fn holds(&self, save_path: &Path, downloading: bool) -> bool {
+        self.metered || (downloading && self.low_disks.contains(save_path))
+    }
+}
+
+/// A change in the conditions, and what it means for torrents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchdogEvent {
+    /// The disk `path` is on has only `free` bytes free, so torrents downloading to it
+    /// are paused.
+    DiskLow { path: PathBuf, free: u64 },
+    /// The disk `path` is on has room again, so torrents downloading to it resume.
+    DiskRecovered { path: PathBuf, free: u64 },
+    /// The network became metered, so every torrent is paused.
+    Metered,
+    /// The network is no longer metered, so torrents resume.
+    Unmetered,
+}
+
+impl std::fmt::Display for WatchdogEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DiskLow { path, free } => write!(
+                f,
+                "{} has {} MiB free, pausing torrents downloading there",
+                path.display(),
+                free / 1024 / 1024
+            ),
+            Self::DiskRecovered { path, free } => write!(
+                f,
+                "{} has {} MiB free again, resuming torrents downloading there",
+                path.display(),
+                free / 1024 / 1024
+            ),
+            Self::Metered => write!(f, "Network is metered, pausing torrents"),
+            Self::Unmetered => write!(f, "Network is no longer metered, resuming torrents"),
+        }
+    }
+}
+
+/// Check the free space on the disks of `save_paths` against `min_free`, if set, and
+/// whether the network is metered, if `check_metered`, given what was found last time.
+pub async fn check(
+    last: &Conditions,
+    save_paths: &BTreeSet<PathBuf>,
+    min_free: Option<u64>,
+    check_metered: bool,
+) -> (Conditions, Vec<WatchdogEvent>) {
+    let mut free = BTreeMap::new();
+    if min_free.is_some() {
+        for path in save_paths {
+            match free_space(path) {
+                Ok(bytes) => {
+                    free.insert(path.clone(), bytes);
+                }
+                Err(e) => tracing::debug!("Couldn't check free space of {}: {}", path.display(), e),
+            }
+        }
+    }
+    let metered = match check_metered {
+        true => is_metered().await.unwrap_or(last.metered),
+        false => false,
+    };
+
+    compare(last, &free, min_free, metered)
+}
+
+/// The conditions given the free space found on each disk and whether the network is
+/// metered, and the events of getting there from `last`. Disks whose free space isn't
+/// known are taken to be as they were.
+fn compare(
+    last: &Conditions,
+    free: &BTreeMap<PathBuf, u64>,
+    min_free: Option<u64>,
+    metered: bool,
+) -> (Conditions, Vec<WatchdogEvent>) {
+    let mut conditions = Conditions {
+        low_disks: BTreeSet::new(),
+        metered,
+    };
+    let mut events = Vec::new();
+    if let Some(min_free) = min_free {
+        for (path, &bytes) in free {
+            let was_low = last.low_disks.contains(path);
+            let low = match was_low {
+                true => (bytes as f64) < min_free as f64 * (1.0 + RESUME_MARGIN),
+                false => bytes < min_free,
+            };
+            if low {
+                conditions.low_disks.insert(path.clone());
+            }
+            let (path, free) = (path.clone(), bytes);
+            match (was_low, low) {
+                (false, true) => events.push(WatchdogEvent::DiskLow { path, free }),
+                (true, false) => events.push(WatchdogEvent::DiskRecovered { path, free }),
+                _ => {}
+            }
+        }
+        conditions.low_disks.extend(
+            last.low_disks
+                .iter()
+                .filter(|path| !free.contains_key(*path))
+                .cloned(),
+        );
+    }
+    match (last.metered, metered) {
+        (false, true) => events.push(WatchdogEvent::Metered),
+        (true, false) => events.push(WatchdogEvent::Unmetered),
+        _ => {}
+    }
+
+    (conditions, events)
+}
+
+/// Bytes free on the disk `path` is on, or would be on once it's created.
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let existing = path
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    platform::free_space(existing)
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    pub fn free_space(path: &Path) -> io::Result<u64> {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: the path is null-terminated, and statvfs fills in `stat` if it succeeds.
+        let stat = unsafe {
+            if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            stat.assume_init()
+        };
+        #[allow(clippy::unnecessary_cast)]
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    pub fn free_space(path: &Path) -> io::Result<u64> {
+        let path: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+        let mut free = 0;
+        // SAFETY: the path is null-terminated, and the totals we don't want can be null.
+        let ok = unsafe {
+            GetDiskFreeSpaceExW(
+                path.as_ptr(),
+                &mut free,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        match ok {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(free),
+        }
+    }
+}
+
+/// Whether NetworkManager says the connection is metered, or `None` if it can't be
+/// asked, such as on systems without it.
+pub async fn is_metered() -> Option<bool> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    let output = Command::new("busctl")
+        .args([
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ])
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_metered(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse NetworkManager's `Metered` property as busctl prints it, such as `u 1`. It's
+/// metered if it's 1, or guessed to be if it's 3.
+fn parse_metered(output: &str) -> Option<bool> {
+    let value: u32 = output.trim().strip_prefix("u ")?.parse().ok()?;
+    Some(matches!(value, 1 | 3))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hold_torrents_until_disks_recover() {
+        let path = PathBuf::from("/downloads");
+        let free = |bytes| BTreeMap::from([(path.clone(), bytes)]);
+        let min = Some(1000);
+
+        let (low, events) = compare(&Conditions::default(), &free(900), min, false);
+        assert_eq!(
+            events,
+            vec![WatchdogEvent::DiskLow {
+                path: path.clone(),
+                free: 900
+            }]
+        );
+        assert!(low.holds(&path, true));
+        assert!(!low.holds(&path, false));
+        assert!(!low.holds(Path::new("/elsewhere"), true));
+
+        // Just over the minimum isn't enough to resume.
+        let (still_low, events) = compare(&low, &free(1010), min, false);
+        assert_eq!(still_low, low);
+        assert!(events.is_empty());
+        // Nor is not knowing.
+        assert_eq!(compare(&low, &BTreeMap::new(), min, false).0, low);
+
+        let (recovered, events) = compare(&low, &free(1100), min, false);
+        assert!(recovered.low_disks.is_empty());
+        assert_eq!(
+            events,
+            vec![WatchdogEvent::DiskRecovered { path, free: 1100 }]
+        );
+    }
+
+    #[test]
+    fn hold_every_torrent_while_metered() {
+        let (metered, events) = compare(&Conditions::default(), &BTreeMap::new(), None, true);
+        assert_eq!(events, vec![WatchdogEvent::Metered]);
+        assert!(metered.holds(Path::new("/downloads"), false));
+
+        let (_, events) = compare(&metered, &BTreeMap::new(), None, false);
+        assert_eq!(events, vec![WatchdogEvent::Unmetered]);
+
+        assert_eq!(parse_metered("u 1\n"), Some(true));
+        assert_eq!(parse_metered("u 3\n"), Some(true));
+        assert_eq!(parse_metered("u 4\n"), Some(false));
+        assert_eq!(parse_metered("garbage"), None);
+    }
+
+    #[tokio::test]
+    async fn check_free_space_of_real_disks() {
+        let dir = std::env::temp_dir().join("not-created-yet");
+        assert!(free_space(&dir).unwrap() > 0);
+
+        let paths = BTreeSet::from([dir.clone()]);
+        let (conditions, _) = check(&Conditions::default(), &paths, Some(u64::MAX), false).await;
+        assert!(conditions.low_disks.contains(&dir));
+        let (conditions, _) = check(&conditions, &paths, Some(0), false).await;
+        assert!(conditions.low_disks.is_empty());
+    }
+}
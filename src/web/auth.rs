@@ -0,0 +1,275 @@
+//! Checking who's talking to the HTTP API, with a token or a username and password, and
+//! locking out addresses which keep getting them wrong, and everyone but loopback and
+//! those who logged in lately while guesses pour in from many addresses.
+
+use crate::rpc::constant_time_eq;
+use base64::Engine;
+use hyper::header::AUTHORIZATION;
+use hyper::HeaderMap;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Failed attempts an address can make before it's locked out.
+const MAX_FAILURES: u32 = 5;
+/// How long an address is locked out for after its last failed attempt.
+const LOCKOUT: Duration = Duration::from_secs(60);
+/// Failed attempts from every address together, within [`LOCKOUT`] of each other, after
+/// which only trusted addresses are checked until they age out.
+const MAX_TOTAL_FAILURES: u32 = 100;
+/// How long an address which logged in is trusted for, so guesses from elsewhere can't
+/// lock it out.
+const TRUSTED_FOR: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What clients of the HTTP API must give.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// A bearer token, which clients that only know usernames and passwords can give as
+    /// the password, with any username.
+    Token(String),
+    Password {
+        username: String,
+        password: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    Denied,
+    /// The address has failed too often recently, so isn't checked at all.
+    LockedOut,
+}
+
+#[derive(Debug)]
+pub struct Auth {
+    credentials: Option<Credentials>,
+    attempts: Mutex<Attempts>,
+}
+
+/// What addresses have tried lately, IPv6 ones by /64.
+#[derive(Debug, Default)]
+struct Attempts {
+    /// Recent failed attempts of each address, and when the last was.
+    failures: HashMap<IpAddr, (u32, Instant)>,
+    /// When each address last logged in.
+    trusted: HashMap<IpAddr, Instant>,
+}
+
+impl Auth {
+    /// Check requests against `credentials`, or allow everything without any.
+    pub fn new(credentials: Option<Credentials>) -> Self {
+        Self {
+            credentials,
+            attempts: Default::default(),
+        }
+    }
+
+    /// Check the `Authorization` header of a request from `ip`, which can be basic
+    /// auth, or a bearer token.
+    pub fn check_header(&self, ip: IpAddr, headers: &HeaderMap) -> Verdict {
+        if self.credentials.is_none() {
+            return Verdict::Allowed;
+        }
+        let header = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let (scheme, value) = header.split_once(' ').unwrap_or((header, ""));
+        let (username, password) = match scheme.to_ascii_lowercase().as_str() {
+            "bearer" => (None, value.trim().to_owned()),
+            "basic" => match decode_basic(value.trim()) {
+                Some((username, password)) => (Some(username), password),
+                None => (None, String::new()),
+            },
+            _ => (None, String::new()),
+        };
+
+        self.check(ip, username.as_deref(), &password)
+    }
+
+    /// Check a username and password from `ip`, such as those of a login form.
+    pub fn check_login(&self, ip: IpAddr, username: &str, password: &str) -> Verdict {
+        self.check(ip, Some(username), password)
+    }
+
+    /// Whether `ip` would be locked out, so there's no need to read what it sent.
+    pub fn is_locked_out(&self, ip: IpAddr) -> bool {
+        self.credentials.is_some() && self.attempts.lock().unwrap().is_locked_out(ip)
+    }
+
+    fn check(&self, ip: IpAddr, username: Option<&str>, password: &str) -> Verdict {
+        let credentials = match &self.credentials {
+            Some(credentials) => credentials,
+            None => return Verdict::Allowed,
+        };
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.is_locked_out(ip) {
+            return Verdict::LockedOut;
+        }
+        let ip = subnet(ip);
+        let now = Instant::now();
+
+        let allowed = match credentials {
+            Credentials::Token(token) => constant_time_eq(password.as_bytes(), token.as_bytes()),
+            Credentials::Password {
+                username: expected_username,
+                password: expected_password,
+            } => {
+                // Both are compared, so the time taken doesn't say which was wrong.
+                let username_ok = constant_time_eq(
+                    username.unwrap_or_default().as_bytes(),
+                    expected_username.as_bytes(),
+                );
+                let password_ok =
+                    constant_time_eq(password.as_bytes(), expected_password.as_bytes());
+                username_ok & password_ok
+            }
+        };
+        if allowed {
+            attempts.failures.remove(&ip);
+            attempts.trusted.insert(ip, now);
+            Verdict::Allowed
+        } else {
+            let entry = attempts.failures.entry(ip).or_insert((0, now));
+            *entry = (entry.0 + 1, now);
+            Verdict::Denied
+        }
+    }
+}
+
+impl Attempts {
+    /// Whether `ip` has failed too often lately, or everyone has and `ip` isn't trusted,
+    /// forgetting what's long enough ago.
+    fn is_locked_out(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.failures
+            .retain(|_, (_, last)| now.duration_since(*last) < LOCKOUT);
+        self.trusted
+            .retain(|_, last| now.duration_since(*last) < TRUSTED_FOR);
+        let ip = subnet(ip);
+        if self
+            .failures
+            .get(&ip)
+            .is_some_and(|(count, _)| *count >= MAX_FAILURES)
+        {
+            return true;
+        }
+        let total: u32 = self.failures.values().map(|(count, _)| count).sum();
+        total >= MAX_TOTAL_FAILURES && !ip.is_loopback() && !self.trusted.contains_key(&ip)
+    }
+}
+
+/// The address failures are counted against: IPv6 hosts usually have a /64 to pick
+/// addresses from.
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            octets[8..].fill(0);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+/// The username and password of a basic auth header's value.
+fn decode_basic(value: &str) -> Option<(String, String)> {
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_owned(), password.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn basic(username: &str, password: &str) -> HeaderMap {
+        let encoded =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Basic {}", encoded)).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn check_passwords_and_tokens() {
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let auth = Auth::new(Some(Credentials::Password {
+            username: String::from("admin"),
+            password: String::from("hunter2"),
+        }));
+        assert_eq!(
+            auth.check_header(ip, &basic("admin", "hunter2")),
+            Verdict::Allowed
+        );
+        assert_eq!(
+            auth.check_header(ip, &basic("admin", "hunter3")),
+            Verdict::Denied
+        );
+        assert_eq!(auth.check_header(ip, &HeaderMap::new()), Verdict::Denied);
+        assert_eq!(auth.check_login(ip, "admin", "hunter2"), Verdict::Allowed);
+
+        let auth = Auth::new(Some(Credentials::Token(String::from("s3cret"))));
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cret"));
+        assert_eq!(auth.check_header(ip, &headers), Verdict::Allowed);
+        assert_eq!(
+            auth.check_header(ip, &basic("anyone", "s3cret")),
+            Verdict::Allowed
+        );
+
+        assert_eq!(
+            Auth::new(None).check_header(ip, &HeaderMap::new()),
+            Verdict::Allowed
+        );
+    }
+
+    #[test]
+    fn lock_out_addresses_which_keep_failing() {
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let auth = Auth::new(Some(Credentials::Token(String::from("s3cret"))));
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(auth.check_login(ip, "", "guess"), Verdict::Denied);
+        }
+        assert_eq!(auth.check_login(ip, "", "s3cret"), Verdict::LockedOut);
+        assert_eq!(
+            auth.check_login(IpAddr::from([192, 0, 2, 2]), "", "s3cret"),
+            Verdict::Allowed
+        );
+
+        // Once the last failure is long enough ago, the address can try again.
+        for (_, last) in auth.attempts.lock().unwrap().failures.values_mut() {
+            *last -= LOCKOUT;
+        }
+        assert_eq!(auth.check_login(ip, "", "s3cret"), Verdict::Allowed);
+
+        // Addresses in the same IPv6 /64 share their failures.
+        for host in 0..MAX_FAILURES as u16 {
+            let ip = IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, host]);
+            assert_eq!(auth.check_login(ip, "", "guess"), Verdict::Denied);
+        }
+        assert!(auth.is_locked_out(IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 99])));
+        assert!(!auth.is_locked_out(IpAddr::from([0x2001, 0xdb8, 0, 1, 0, 0, 0, 1])));
+
+        // Guesses from many addresses lock out everyone but loopback and those who
+        // logged in lately.
+        for host in 0..MAX_TOTAL_FAILURES {
+            let ip = IpAddr::from((0xc6336400 + host).to_be_bytes());
+            auth.check_login(ip, "", "guess");
+        }
+        let stranger = IpAddr::from([192, 0, 2, 3]);
+        assert_eq!(auth.check_login(stranger, "", "s3cret"), Verdict::LockedOut);
+        assert_eq!(auth.check_login(ip, "", "s3cret"), Verdict::Allowed);
+        let loopback = IpAddr::from([127, 0, 0, 1]);
+        assert_eq!(auth.check_login(loopback, "", "s3cret"), Verdict::Allowed);
+    }
+}
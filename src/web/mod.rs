@@ -0,0 +1,296 @@
+//! An HTTP server for controlling the client with the protocols other clients speak, so
+//! tools built for them, such as Sonarr, Radarr and phone apps, work with this one too.
+//! It speaks Transmission's RPC protocol at `/transmission/rpc`, and qBittorrent's Web
+//! API under `/api/v2`. It can ask for credentials, see [`auth`], and serve HTTPS.
+
+pub mod auth;
+pub mod qbittorrent;
+pub mod transmission;
+
+use crate::client::Client;
+use anyhow::anyhow;
+use auth::{Auth, Credentials, Verdict};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use qbittorrent::QBittorrent;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_native_tls::TlsAcceptor;
+use tracing::debug;
+
+/// Largest request body we read, which leaves room for a large .torrent in base64.
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// A control protocol the server speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Transmission,
+    QBittorrent,
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "transmission" => Ok(Self::Transmission),
+            "qbittorrent" => Ok(Self::QBittorrent),
+            _ => Err(anyhow!("Unknown protocol: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebConfig {
+    pub addr: SocketAddr,
+    pub protocols: Vec<Protocol>,
+    /// What clients must give, if anything.
+    pub credentials: Option<Credentials>,
+    /// PEM certificate chain and PKCS #8 private key to serve HTTPS with.
+    pub tls: Option<(PathBuf, PathBuf)>,
+}
+
+impl WebConfig {
+    /// Serve every protocol on `addr` over HTTP, to anyone.
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            protocols: vec![Protocol::Transmission, Protocol::QBittorrent],
+            credentials: None,
+            tls: None,
+        }
+    }
+}
+
+/// State shared by the server's connections.
+#[derive(Debug)]
+pub struct WebApi {
+    client: Client,
+    protocols: Vec<Protocol>,
+    auth: Auth,
+    /// Transmission clients must echo this back, so other sites can't make browsers
+    /// send requests to us.
+    session_id: String,
+    qbittorrent: QBittorrent,
+}
+
+impl WebApi {
+    pub fn new(client: Client, config: &WebConfig) -> Self {
+        Self {
+            client,
+            protocols: config.protocols.clone(),
+            auth: Auth::new(config.credentials.clone()),
+            session_id: random_token(),
+            qbittorrent: QBittorrent::default(),
+        }
+    }
+
+    /// Respond to a request from `ip`.
+    pub async fn respond(&self, ip: IpAddr, request: Request<Body>) -> Response<Body> {
+        let path = request.uri().path().trim_end_matches('/').to_owned();
+        let speaks = |protocol| self.protocols.contains(&protocol);
+        if let Some(path) = path.strip_prefix("/api/v2") {
+            if speaks(Protocol::QBittorrent) {
+                return self
+                    .qbittorrent
+                    .respond(&self.client, &self.auth, ip, path, request)
+                    .await;
+            }
+        }
+        match (request.method(), path.as_str()) {
+            (&Method::POST, "/transmission/rpc") if speaks(Protocol::Transmission) => {
+                self.transmission(ip, request).await
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    async fn transmission(&self, ip: IpAddr, request: Request<Body>) -> Response<Body> {
+        match self.auth.check_header(ip, request.headers()) {
+            Verdict::Allowed => {}
+            Verdict::Denied => {
+                return Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header("www-authenticate", "Basic realm=\"torrent\"")
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            Verdict::LockedOut => return status(StatusCode::TOO_MANY_REQUESTS),
+        }
+        let header = transmission::SESSION_ID_HEADER;
+        if !has_header(request.headers(), header, &self.session_id) {
+            return Response::builder()
+                .status(StatusCode::CONFLICT)
+                .header(header, &self.session_id)
+                .body(Body::from(format!("{}: {}", header, self.session_id)))
+                .unwrap();
+        }
+
+        let body = match read_body(request.into_body()).await {
+            Ok(body) => body,
+            Err(e) => return text(StatusCode::BAD_REQUEST, e.to_string()),
+        };
+        let request = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return text(StatusCode::BAD_REQUEST, format!("Invalid request: {}", e)),
+        };
+        let response = transmission::handle(&self.client, request).await;
+
+        Response::builder()
+            .header("content-type", "application/json")
+            .header(header, &self.session_id)
+            .body(Body::from(serde_json::to_vec(&response).unwrap()))
+            .unwrap()
+    }
+}
+
+/// A random string which can't be guessed, for session ids.
+fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect()
+}
+
+fn has_header(headers: &HeaderMap, name: &str, value: &str) -> bool {
+    headers
+        .get(name)
+        .is_some_and(|v| v.as_bytes() == value.as_bytes())
+}
+
+async fn read_body(mut body: Body) -> anyhow::Result<Vec<u8>> {
+    use hyper::body::HttpBody;
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(anyhow!("Request is too large"));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn text(code: StatusCode, text: String) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::from(text))
+        .unwrap()
+}
+
+/// Load the certificate chain and key to serve HTTPS with.
+async fn tls_acceptor(cert: &PathBuf, key: &PathBuf) -> anyhow::Result<TlsAcceptor> {
+    let cert = tokio::fs::read(cert).await?;
+    let key = tokio::fs::read(key).await?;
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)?;
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
+}
+
+/// Serve the HTTP API until the listener fails.
+pub async fn serve(client: Client, config: WebConfig) -> anyhow::Result<()> {
+    let tls = match &config.tls {
+        Some((cert, key)) => Some(tls_acceptor(cert, key).await?),
+        None => None,
+    };
+    let listener = TcpListener::bind(config.addr).await?;
+    debug!("Serving the HTTP API on {}", config.addr);
+    let api = Arc::new(WebApi::new(client, &config));
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let api = Arc::clone(&api);
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let api = Arc::clone(&api);
+                async move { Ok::<_, Infallible>(api.respond(peer.ip(), request).await) }
+            });
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => Http::new()
+                        .serve_connection(stream, service)
+                        .await
+                        .map_err(Into::into),
+                    Err(e) => Err(anyhow::Error::from(e)),
+                },
+                None => Http::new()
+                    .serve_connection(stream, service)
+                    .await
+                    .map_err(Into::into),
+            };
+            if let Err(e) = result {
+                debug!("HTTP connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{ClientConfig, Output};
+
+    #[tokio::test]
+    async fn transmission_clients_need_the_session_id() {
+        let client = Client::new(ClientConfig {
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(".")
+        });
+        let config = WebConfig {
+            protocols: vec![Protocol::Transmission],
+            ..WebConfig::new("127.0.0.1:9091".parse().unwrap())
+        };
+        let api = WebApi::new(client, &config);
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let request = |session_id: Option<&str>| {
+            let mut request = Request::post("/transmission/rpc");
+            if let Some(id) = session_id {
+                request = request.header(transmission::SESSION_ID_HEADER, id);
+            }
+            request
+                .body(Body::from(r#"{"method": "session-get"}"#))
+                .unwrap()
+        };
+
+        let response = api.respond(ip, request(None)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let session_id = response.headers()[transmission::SESSION_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let response = api.respond(ip, request(Some("wrong"))).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = api.respond(ip, request(Some(&session_id))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["result"], "success");
+        assert_eq!(body["arguments"]["rpc-version"], 17);
+
+        let request = Request::post("/api/v2/auth/login")
+            .body(Body::empty())
+            .unwrap();
+        let response = api.respond(ip, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,634 @@
+//! The endpoints of qBittorrent's Web API v2 which tools automating downloads use:
+//! logging in, listing, adding, pausing, resuming and removing torrents, and categories.
+//! Requests other than logging in need the `SID` cookie the login sets.
+
+use super::auth::{Auth, Verdict};
+use super::{random_token, read_body, status, text};
+use crate::client::{Client, TorrentHandle, TorrentState};
+use crate::fetch::TorrentSource;
+use crate::magnet::hex;
+use crate::options::AddTorrentOptions;
+use crate::Torrent;
+use anyhow::anyhow;
+use hyper::header::{CONTENT_TYPE, COOKIE, SET_COOKIE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tracing::warn;
+
+/// The qBittorrent and Web API versions whose behaviour we copy.
+const APP_VERSION: &str = "v4.6.0";
+const API_VERSION: &str = "2.9.3";
+/// What qBittorrent gives as the ETA of torrents which aren't going to finish.
+const NO_ETA: u64 = 8_640_000;
+
+/// The logged in sessions of qBittorrent clients.
+#[derive(Debug, Default)]
+pub struct QBittorrent {
+    sessions: Mutex<HashSet<String>>,
+}
+
+impl QBittorrent {
+    /// Respond to a request from `ip` for `path`, which is below `/api/v2`. Logins are
+    /// checked by `auth`.
+    pub async fn respond(
+        &self,
+        client: &Client,
+        auth: &Auth,
+        ip: IpAddr,
+        path: &str,
+        request: Request<Body>,
+    ) -> Response<Body> {
+        let sid = cookie(&request, "SID").filter(|sid| self.sessions.lock().unwrap().contains(sid));
+        // Only logged in clients, or those which may log in, have their bodies read.
+        let logging_in = path == "/auth/login";
+        if logging_in && auth.is_locked_out(ip) {
+            return locked_out();
+        }
+        if !logging_in && sid.is_none() {
+            return status(StatusCode::FORBIDDEN);
+        }
+        let method = request.method().clone();
+        let content_type = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let query = request.uri().query().unwrap_or_default().to_owned();
+        let body = match read_body(request.into_body()).await {
+            Ok(body) => body,
+            Err(e) => return text(StatusCode::BAD_REQUEST, e.to_string()),
+        };
+        let form = match method {
+            Method::POST => match parse_form(&content_type, &body) {
+                Ok(form) => form,
+                Err(e) => return text(StatusCode::BAD_REQUEST, e.to_string()),
+            },
+            _ => parse_urlencoded(query.as_bytes()),
+        };
+
+        if logging_in {
+            let username = form.text("username").unwrap_or_default();
+            let password = form.text("password").unwrap_or_default();
+            return match auth.check_login(ip, &username, &password) {
+                Verdict::Allowed => self.login(),
+                Verdict::Denied => text(StatusCode::OK, String::from("Fails.")),
+                Verdict::LockedOut => locked_out(),
+            };
+        }
+        let sid = match sid {
+            Some(sid) => sid,
+            None => return status(StatusCode::FORBIDDEN),
+        };
+
+        let result = match path {
+            "/auth/logout" => {
+                self.sessions.lock().unwrap().remove(&sid);
+                Ok(Reply::Ok)
+            }
+            "/app/version" => Ok(Reply::Text(APP_VERSION.to_owned())),
+            "/app/webapiVersion" => Ok(Reply::Text(API_VERSION.to_owned())),
+            "/app/preferences" => Ok(Reply::Json(preferences(client))),
+            "/torrents/info" => Ok(Reply::Json(info(client, &form))),
+            "/torrents/categories" => Ok(Reply::Json(categories(client))),
+            "/torrents/add" => add(client, &form).await,
+            "/torrents/pause" | "/torrents/stop" => {
+                each(client, &form, TorrentHandle::pause).map(|()| Reply::Ok)
+            }
+            "/torrents/resume" | "/torrents/start" => {
+                each(client, &form, TorrentHandle::resume).map(|()| Reply::Ok)
+            }
+            "/torrents/setCategory" => {
+                let category = form.text("category").filter(|c| !c.is_empty());
+                each(client, &form, |handle| {
+                    handle.set_category(category.clone())
+                })
+                .map(|()| Reply::Ok)
+            }
+            "/torrents/recheck" => each(client, &form, |handle| {
+                let handle = handle.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle.recheck().await {
+                        warn!("Couldn't check {}: {}", handle.name(), e);
+                    }
+                });
+                Ok(())
+            })
+            .map(|()| Reply::Ok),
+            "/torrents/reannounce" => each(client, &form, |handle| {
+                handle.reannounce();
+                Ok(())
+            })
+            .map(|()| Reply::Ok),
+            "/torrents/delete" => delete(client, &form).await.map(|()| Reply::Ok),
+            "/transfer/speedLimitsMode" => {
+                Ok(Reply::Text(u8::from(client.alt_speed()).to_string()))
+            }
+            "/transfer/toggleSpeedLimitsMode" => {
+                client.set_alt_speed(!client.alt_speed());
+                Ok(Reply::Ok)
+            }
+            _ => return status(StatusCode::NOT_FOUND),
+        };
+
+        match result {
+            Ok(Reply::Ok) => text(StatusCode::OK, String::from("Ok.")),
+            Ok(Reply::Text(body)) => text(StatusCode::OK, body),
+            Ok(Reply::Json(json)) => Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(json.to_string()))
+                .unwrap(),
+            Ok(Reply::Fails) => text(StatusCode::OK, String::from("Fails.")),
+            Err(e) => text(StatusCode::BAD_REQUEST, e.to_string()),
+        }
+    }
+
+    fn login(&self) -> Response<Body> {
+        let sid = random_token();
+        self.sessions.lock().unwrap().insert(sid.clone());
+        Response::builder()
+            .header(
+                SET_COOKIE,
+                format!("SID={}; HttpOnly; SameSite=Strict; path=/", sid),
+            )
+            .body(Body::from("Ok."))
+            .unwrap()
+    }
+}
+
+enum Reply {
+    Ok,
+    /// qBittorrent's answer when it does nothing, such as adding torrents it already has.
+    Fails,
+    Text(String),
+    Json(Value),
+}
+
+fn locked_out() -> Response<Body> {
+    text(
+        StatusCode::FORBIDDEN,
+        String::from("Too many failed login attempts, try again later."),
+    )
+}
+
+fn cookie<T>(request: &Request<T>, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then(|| value.to_owned())
+        })
+}
+
+/// A field of a submitted form.
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    name: String,
+    /// Name of the uploaded file, for files.
+    filename: Option<String>,
+    value: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Form(Vec<Field>);
+
+impl Form {
+    fn text(&self, name: &str) -> Option<String> {
+        self.0
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| String::from_utf8_lossy(&f.value).into_owned())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.text(name).as_deref() == Some("true")
+    }
+
+    fn files(&self, name: &str) -> impl Iterator<Item = &[u8]> + '_ {
+        let name = name.to_owned();
+        self.0
+            .iter()
+            .filter(move |f| f.name == name && f.filename.is_some())
+            .map(|f| f.value.as_slice())
+    }
+}
+
+fn parse_form(content_type: &str, body: &[u8]) -> anyhow::Result<Form> {
+    let boundary = content_type
+        .split(';')
+        .map(str::trim)
+        .find_map(|param| param.strip_prefix("boundary="));
+    match boundary {
+        Some(boundary) if content_type.starts_with("multipart/form-data") => {
+            parse_multipart(boundary.trim_matches('"'), body)
+        }
+        _ => Ok(parse_urlencoded(body)),
+    }
+}
+
+fn parse_urlencoded(body: &[u8]) -> Form {
+    Form(
+        url::form_urlencoded::parse(body)
+            .map(|(name, value)| Field {
+                name: name.into_owned(),
+                filename: None,
+                value: value.into_owned().into_bytes(),
+            })
+            .collect(),
+    )
+}
+
+/// Split a `multipart/form-data` body into its fields.
+fn parse_multipart(boundary: &str, body: &[u8]) -> anyhow::Result<Form> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut fields = Vec::new();
+    for part in split(body, &delimiter).skip(1) {
+        // The last delimiter is followed by `--`.
+        if part.starts_with(b"--") {
+            break;
+        }
+        let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        let header_end = find(part, b"\r\n\r\n")
+            .ok_or_else(|| anyhow!("Form part has no end to its headers"))?;
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        let disposition = headers
+            .lines()
+            .find(|line| {
+                line.to_ascii_lowercase()
+                    .starts_with("content-disposition:")
+            })
+            .ok_or_else(|| anyhow!("Form part has no Content-Disposition"))?;
+        let param = |key: &str| {
+            disposition.split(';').map(str::trim).find_map(|param| {
+                param
+                    .strip_prefix(key)
+                    .and_then(|v| v.strip_prefix('='))
+                    .map(|v| v.trim_matches('"').to_owned())
+            })
+        };
+
+        fields.push(Field {
+            name: param("name").ok_or_else(|| anyhow!("Form part has no name"))?,
+            filename: param("filename"),
+            value: part[header_end + 4..].to_vec(),
+        });
+    }
+
+    Ok(Form(fields))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn split<'a>(mut haystack: &'a [u8], delimiter: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+    std::iter::from_fn(move || {
+        if haystack.is_empty() {
+            return None;
+        }
+        match find(haystack, delimiter) {
+            Some(idx) => {
+                let part = &haystack[..idx];
+                haystack = &haystack[idx + delimiter.len()..];
+                Some(part)
+            }
+            None => Some(std::mem::take(&mut haystack)),
+        }
+    })
+}
+
+/// The torrents the `hashes` field names, separated by `|`, or all of them for `all`.
+fn selected(client: &Client, form: &Form) -> Vec<TorrentHandle> {
+    let hashes = match form.text("hashes") {
+        Some(hashes) if hashes != "all" => hashes,
+        _ => return client.torrents(),
+    };
+    let hashes: HashSet<_> = hashes.split('|').map(str::to_ascii_lowercase).collect();
+    client
+        .torrents()
+        .into_iter()
+        .filter(|handle| hashes.contains(&hex(&handle.info_hash())))
+        .collect()
+}
+
+fn each(
+    client: &Client,
+    form: &Form,
+    f: impl Fn(&TorrentHandle) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    selected(client, form).iter().try_for_each(f)
+}
+
+async fn delete(client: &Client, form: &Form) -> anyhow::Result<()> {
+    for handle in selected(client, form) {
+        client
+            .remove_torrent(&handle.info_hash(), form.flag("deleteFiles"))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Add the torrents uploaded as `torrents`, and those the lines of `urls` refer to.
+async fn add(client: &Client, form: &Form) -> anyhow::Result<Reply> {
+    let options = AddTorrentOptions {
+        save_path: form
+            .text("savepath")
+            .filter(|p| !p.is_empty())
+            .map(Into::into),
+        category: form.text("category").filter(|c| !c.is_empty()),
+        paused: form.flag("paused") || form.flag("stopped"),
+        sequential: form.flag("sequentialDownload"),
+        ..Default::default()
+    };
+
+    let mut torrents = Vec::new();
+    for file in form.files("torrents") {
+        torrents.push(Torrent::from_bytes(file)?);
+    }
+    let urls = form.text("urls").unwrap_or_default();
+    let config = client.config();
+    for url in urls.lines().map(str::trim).filter(|url| !url.is_empty()) {
+        let source: TorrentSource = url.parse()?;
+        if let TorrentSource::File(_) = source {
+            return Err(anyhow!("Not a URL or magnet link: {}", url));
+        }
+        torrents.push(
+            crate::fetch::resolve(
+                &source,
+                &config.peer_id,
+                config.port,
+                config.bind_address,
+                &config.socket,
+                client.dht().as_ref(),
+            )
+            .await?,
+        );
+    }
+
+    let mut added = 0;
+    for torrent in torrents {
+        if client.get(&torrent.info_hash).is_some() {
+            continue;
+        }
+        client
+            .add_torrent_with_options(torrent, options.clone())
+            .await?;
+        added += 1;
+    }
+
+    Ok(if added > 0 { Reply::Ok } else { Reply::Fails })
+}
+
+fn preferences(client: &Client) -> Value {
+    let config = client.config();
+    json!({
+        "save_path": config.save_path,
+        "listen_port": config.port,
+        "dht": config.dht,
+        "queueing_enabled": config.max_active.is_some()
+            || config.max_active_downloads.is_some()
+            || config.max_active_seeds.is_some(),
+        "max_ratio_enabled": false,
+        "max_ratio": -1,
+        "max_seeding_time_enabled": false,
+        "max_seeding_time": -1,
+    })
+}
+
+fn categories(client: &Client) -> Value {
+    let config = client.config();
+    let mut categories: HashMap<String, Value> = config
+        .category_paths
+        .iter()
+        .map(|(name, path)| (name.clone(), json!({ "name": name, "savePath": path })))
+        .collect();
+    for handle in client.torrents() {
+        if let Some(name) = handle.category() {
+            categories
+                .entry(name.clone())
+                .or_insert_with(|| json!({ "name": name, "savePath": "" }));
+        }
+    }
+
+    json!(categories)
+}
+
+/// qBittorrent's name for what a torrent is doing.
+fn state_name(state: &TorrentState, progress: f64, download_rate: u64, upload_rate: u64) -> &str {
+    let done = progress >= 1.0;
+    match state {
+        TorrentState::Paused | TorrentState::Complete if done => "pausedUP",
+        TorrentState::Paused | TorrentState::Complete => "pausedDL",
+        TorrentState::Queued if done => "queuedUP",
+        TorrentState::Queued => "queuedDL",
+        TorrentState::Downloading if download_rate == 0 => "stalledDL",
+        TorrentState::Downloading => "downloading",
+        TorrentState::Seeding if upload_rate == 0 => "stalledUP",
+        TorrentState::Seeding => "uploading",
+        TorrentState::Failed(_) => "error",
+    }
+}
+
+/// The torrents matching the `filter`, `category` and `hashes` parameters.
+fn info(client: &Client, form: &Form) -> Value {
+    let category = form.text("category");
+    let filter = form.text("filter").unwrap_or_else(|| String::from("all"));
+    let torrents: Vec<Value> = selected(client, form)
+        .iter()
+        .filter(|handle| {
+            category
+                .as_ref()
+                .is_none_or(|c| handle.category().unwrap_or_default() == *c)
+        })
+        .map(torrent_info)
+        .filter(|info| {
+            let state = info["state"].as_str().unwrap_or_default();
+            match filter.as_str() {
+                "downloading" => state.ends_with("DL") || state == "downloading",
+                "seeding" => state == "uploading" || state == "stalledUP",
+                "completed" => info["progress"].as_f64() == Some(1.0),
+                "paused" | "stopped" => state.starts_with("paused"),
+                "active" => info["dlspeed"] != 0 || info["upspeed"] != 0,
+                "errored" => state == "error",
+                "checking" => state.starts_with("checking"),
+                _ => true,
+            }
+        })
+        .collect();
+
+    json!(torrents)
+}
+
+fn torrent_info(handle: &TorrentHandle) -> Value {
+    let status = handle.status();
+    let options = handle.options();
+    let state = handle.state();
+    let size = handle.torrent().file.info.total_length();
+    let left = ((1.0 - status.progress) * size as f64).round() as u64;
+    let save_path = handle.save_path();
+
+    json!({
+        "hash": hex(&handle.info_hash()),
+        "name": handle.name(),
+        "size": size,
+        "total_size": size,
+        "progress": status.progress,
+        "dlspeed": status.download_rate,
+        "upspeed": status.upload_rate,
+        "downloaded": status.downloaded,
+        "uploaded": status.uploaded,
+        "amount_left": left,
+        "eta": match status.download_rate {
+            0 if left > 0 => NO_ETA,
+            0 => 0,
+            rate => left / rate,
+        },
+        "ratio": match status.downloaded {
+            0 => 0.0,
+            downloaded => status.uploaded as f64 / downloaded as f64,
+        },
+        "ratio_limit": options.seed_ratio.unwrap_or(-2.0),
+        "seeding_time": status.seeding_time,
+        "seeding_time_limit": -2,
+        "state": match &status.checking {
+            Some(_) if status.progress >= 1.0 => "checkingUP",
+            Some(_) => "checkingDL",
+            None => state_name(&state, status.progress, status.download_rate, status.upload_rate),
+        },
+        "category": status.category.unwrap_or_default(),
+        "tags": "",
+        "save_path": save_path,
+        "content_path": save_path.join(&handle.torrent().file.info.name),
+        "num_leechs": status.peers.len(),
+        "priority": handle.id(),
+        "force_start": options.force_start,
+        "seq_dl": options.sequential,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_multipart_forms() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"category\"\r\n\r\n\
+            tv\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"torrents\"; filename=\"a.torrent\"\r\n\
+            Content-Type: application/x-bittorrent\r\n\r\n\
+            d4:infod\r\n\r\nee\r\n\
+            --XyZ--\r\n";
+        let form = parse_form("multipart/form-data; boundary=XyZ", body).unwrap();
+
+        assert_eq!(form.text("category").as_deref(), Some("tv"));
+        assert_eq!(
+            form.files("torrents").collect::<Vec<_>>(),
+            vec![&b"d4:infod\r\n\r\nee"[..]]
+        );
+        assert_eq!(form.files("category").count(), 0);
+    }
+
+    #[tokio::test]
+    async fn log_in_and_list_torrents() {
+        use crate::client::{ClientConfig, Output};
+        use crate::web::auth::Credentials;
+
+        let client = Client::new(ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(std::env::temp_dir())
+        });
+        let torrent = crate::testing::torrent("qbittorrent-test", &[7; 100], 64);
+        let hash = hex(&torrent.info_hash);
+        let options = AddTorrentOptions {
+            paused: true,
+            category: Some(String::from("tv")),
+            ..Default::default()
+        };
+        client
+            .add_torrent_with_options(torrent, options)
+            .await
+            .unwrap();
+        let api = QBittorrent::default();
+        let auth = Auth::new(Some(Credentials::Password {
+            username: String::from("admin"),
+            password: String::from("hunter2"),
+        }));
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let get = |path: &str, sid: &str| {
+            Request::get(format!("/api/v2{}", path))
+                .header(COOKIE, format!("SID={}", sid))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let request = get("/torrents/info", "none");
+        let response = api
+            .respond(&client, &auth, ip, "/torrents/info", request)
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let login = |password: &str| {
+            Request::post("/api/v2/auth/login")
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(format!("username=admin&password={}", password)))
+                .unwrap()
+        };
+        let response = api
+            .respond(&client, &auth, ip, "/auth/login", login("guess"))
+            .await;
+        assert!(!response.headers().contains_key(SET_COOKIE));
+        let response = api
+            .respond(&client, &auth, ip, "/auth/login", login("hunter2"))
+            .await;
+        let sid = response.headers()[SET_COOKIE].to_str().unwrap();
+        let sid = sid
+            .trim_start_matches("SID=")
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+
+        let request = get("/torrents/info?category=tv", &sid);
+        let response = api
+            .respond(&client, &auth, ip, "/torrents/info", request)
+            .await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info[0]["hash"], hash);
+        assert_eq!(info[0]["state"], "pausedDL");
+        assert_eq!(info[0]["category"], "tv");
+
+        let request = get("/torrents/info?category=films", &sid);
+        let response = api
+            .respond(&client, &auth, ip, "/torrents/info", request)
+            .await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"[]");
+
+        let request = Request::post("/api/v2/torrents/resume")
+            .header(COOKIE, format!("SID={}", sid))
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!("hashes={}", hash)))
+            .unwrap();
+        let response = api
+            .respond(&client, &auth, ip, "/torrents/resume", request)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!client.torrents()[0].is_paused());
+    }
+}
@@ -0,0 +1,422 @@
+//! The parts of Transmission's RPC protocol which tools automating downloads use: adding,
+//! listing, starting, stopping and removing torrents. Requests are JSON objects naming a
+//! method and its arguments, described in Transmission's `rpc-spec.md`.
+
+use crate::client::{Client, TorrentHandle, TorrentState};
+use crate::fetch::{self, TorrentSource};
+use crate::magnet::hex;
+use crate::options::AddTorrentOptions;
+use crate::Torrent;
+use anyhow::anyhow;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tracing::warn;
+
+/// Header carrying the session id, which requests without get turned away with a 409.
+pub const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+/// The protocol version we speak, that of Transmission 4.0.
+const RPC_VERSION: u32 = 17;
+const RPC_VERSION_MINIMUM: u32 = 14;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Request {
+    pub method: String,
+    #[serde(default)]
+    pub arguments: Value,
+    pub tag: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Response {
+    /// "success", or what went wrong.
+    pub result: String,
+    pub arguments: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<Value>,
+}
+
+pub async fn handle(client: &Client, request: Request) -> Response {
+    let (result, arguments) = match call(client, &request.method, request.arguments).await {
+        Ok(arguments) => (String::from("success"), arguments),
+        Err(e) => (e.to_string(), json!({})),
+    };
+
+    Response {
+        result,
+        arguments,
+        tag: request.tag,
+    }
+}
+
+async fn call(client: &Client, method: &str, arguments: Value) -> anyhow::Result<Value> {
+    let args = |arguments| serde_json::from_value::<Arguments>(arguments);
+    match method {
+        "session-get" => Ok(session(client)),
+        "session-set" => session_set(client, &arguments),
+        "session-stats" => Ok(session_stats(client)),
+        "torrent-get" => {
+            let args = args(arguments)?;
+            let torrents = select(client, args.ids)
+                .iter()
+                .map(|handle| torrent_fields(handle, args.fields.as_deref()))
+                .collect::<Vec<_>>();
+            Ok(json!({ "torrents": torrents }))
+        }
+        "torrent-add" => add(client, serde_json::from_value(arguments)?).await,
+        "torrent-start" => each(client, args(arguments)?, TorrentHandle::resume),
+        "torrent-start-now" => each(client, args(arguments)?, |h| h.set_force_start(true)),
+        "torrent-stop" => each(client, args(arguments)?, TorrentHandle::pause),
+        "torrent-reannounce" => each(client, args(arguments)?, |handle| {
+            handle.reannounce();
+            Ok(())
+        }),
+        "torrent-verify" => each(client, args(arguments)?, |handle| {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle.recheck().await {
+                    warn!("Couldn't check {}: {}", handle.name(), e);
+                }
+            });
+            Ok(())
+        }),
+        "torrent-set" => {
+            let args = args(arguments)?;
+            match &args.labels {
+                Some(labels) => each(client, args.clone(), |handle| {
+                    handle.set_category(labels.first().cloned())
+                }),
+                None => Ok(json!({})),
+            }
+        }
+        "torrent-remove" => {
+            let args = args(arguments)?;
+            for handle in select(client, args.ids) {
+                client
+                    .remove_torrent(&handle.info_hash(), args.delete_local_data)
+                    .await?;
+            }
+            Ok(json!({}))
+        }
+        _ => Err(anyhow!("method name not recognized")),
+    }
+}
+
+/// The arguments of the methods acting on torrents.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct Arguments {
+    ids: Option<Ids>,
+    fields: Option<Vec<String>>,
+    labels: Option<Vec<String>>,
+    delete_local_data: bool,
+}
+
+/// Which torrents a request is for: ids, info hashes, or `recently-active`, which we
+/// take to mean all of them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Ids {
+    One(Id),
+    Many(Vec<Id>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Id {
+    Number(u64),
+    Hash(String),
+}
+
+impl Id {
+    fn matches(&self, handle: &TorrentHandle) -> bool {
+        match self {
+            Id::Number(id) => handle.id() == *id,
+            Id::Hash(hash) => hex(&handle.info_hash()).eq_ignore_ascii_case(hash),
+        }
+    }
+}
+
+/// The torrents `ids` refers to, or all of them without any.
+fn select(client: &Client, ids: Option<Ids>) -> Vec<TorrentHandle> {
+    let ids = match ids {
+        None => return client.torrents(),
+        Some(Ids::One(Id::Hash(hash))) if hash == "recently-active" => return client.torrents(),
+        Some(Ids::One(id)) => vec![id],
+        Some(Ids::Many(ids)) => ids,
+    };
+    client
+        .torrents()
+        .into_iter()
+        .filter(|handle| ids.iter().any(|id| id.matches(handle)))
+        .collect()
+}
+
+fn each(
+    client: &Client,
+    args: Arguments,
+    f: impl Fn(&TorrentHandle) -> anyhow::Result<()>,
+) -> anyhow::Result<Value> {
+    for handle in select(client, args.ids) {
+        f(&handle)?;
+    }
+    Ok(json!({}))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct AddArguments {
+    /// Path, URL or magnet link of the torrent.
+    filename: Option<String>,
+    /// The .torrent file, in base64.
+    metainfo: Option<String>,
+    download_dir: Option<String>,
+    paused: bool,
+    labels: Vec<String>,
+}
+
+async fn add(client: &Client, args: AddArguments) -> anyhow::Result<Value> {
+    let torrent = match (&args.metainfo, &args.filename) {
+        (Some(metainfo), _) => {
+            let metainfo: String = metainfo.split_whitespace().collect();
+            Torrent::from_bytes(&base64::engine::general_purpose::STANDARD.decode(metainfo)?)?
+        }
+        (None, Some(filename)) => {
+            let source: TorrentSource = filename.parse()?;
+            let config = client.config();
+            let dht = client.dht();
+            fetch::resolve(
+                &source,
+                &config.peer_id,
+                config.port,
+                config.bind_address,
+                &config.socket,
+                dht.as_ref(),
+            )
+            .await?
+        }
+        (None, None) => return Err(anyhow!("no filename or metainfo specified")),
+    };
+
+    if let Some(handle) = client.get(&torrent.info_hash) {
+        return Ok(json!({ "torrent-duplicate": added(&handle) }));
+    }
+    let options = AddTorrentOptions {
+        save_path: args.download_dir.map(Into::into),
+        paused: args.paused,
+        category: args.labels.into_iter().next(),
+        ..Default::default()
+    };
+    let handle = client.add_torrent_with_options(torrent, options).await?;
+
+    Ok(json!({ "torrent-added": added(&handle) }))
+}
+
+fn added(handle: &TorrentHandle) -> Value {
+    json!({
+        "id": handle.id(),
+        "name": handle.name(),
+        "hashString": hex(&handle.info_hash()),
+    })
+}
+
+fn session(client: &Client) -> Value {
+    let config = client.config();
+    json!({
+        "version": format!("4.0.0 (torrent {})", env!("CARGO_PKG_VERSION")),
+        "rpc-version": RPC_VERSION,
+        "rpc-version-minimum": RPC_VERSION_MINIMUM,
+        "download-dir": config.save_path,
+        "peer-port": config.port,
+        "dht-enabled": config.dht,
+        "seedRatioLimited": false,
+        "seedRatioLimit": 0,
+        "idle-seeding-limit-enabled": false,
+        "alt-speed-enabled": client.alt_speed(),
+        "alt-speed-down": config.alt_download_limit / 1024,
+        "alt-speed-up": config.alt_upload_limit / 1024,
+    })
+}
+
+/// Change the session settings which can change while the client runs, ignoring the
+/// rest.
+fn session_set(client: &Client, arguments: &Value) -> anyhow::Result<Value> {
+    if let Some(enabled) = arguments.get("alt-speed-enabled") {
+        let enabled = enabled
+            .as_bool()
+            .ok_or_else(|| anyhow!("alt-speed-enabled must be a boolean"))?;
+        client.set_alt_speed(enabled);
+    }
+    Ok(json!({}))
+}
+
+fn session_stats(client: &Client) -> Value {
+    let torrents = client.torrents();
+    let statuses: Vec<_> = torrents.iter().map(|t| t.status()).collect();
+    let paused = torrents.iter().filter(|t| t.is_paused()).count();
+    json!({
+        "torrentCount": torrents.len(),
+        "activeTorrentCount": torrents.len() - paused,
+        "pausedTorrentCount": paused,
+        "downloadSpeed": statuses.iter().map(|s| s.download_rate).sum::<u64>(),
+        "uploadSpeed": statuses.iter().map(|s| s.upload_rate).sum::<u64>(),
+    })
+}
+
+/// Transmission's codes for what a torrent is doing.
+fn status_code(handle: &TorrentHandle, state: &TorrentState) -> u8 {
+    const STOPPED: u8 = 0;
+    const CHECK: u8 = 2;
+    const DOWNLOAD_WAIT: u8 = 3;
+    const DOWNLOAD: u8 = 4;
+    const SEED_WAIT: u8 = 5;
+    const SEED: u8 = 6;
+    if handle.checking().is_some() {
+        return CHECK;
+    }
+    match state {
+        TorrentState::Queued if handle.status().progress >= 1.0 => SEED_WAIT,
+        TorrentState::Queued => DOWNLOAD_WAIT,
+        TorrentState::Downloading => DOWNLOAD,
+        TorrentState::Seeding => SEED,
+        TorrentState::Paused | TorrentState::Complete | TorrentState::Failed(_) => STOPPED,
+    }
+}
+
+/// A torrent's fields as Transmission names them, only those in `fields` if given.
+fn torrent_fields(handle: &TorrentHandle, fields: Option<&[String]>) -> Value {
+    let status = handle.status();
+    let state = handle.state();
+    let options = handle.options();
+    let torrent = handle.torrent();
+    let total_size = torrent.file.info.total_length();
+    let left = ((1.0 - status.progress) * total_size as f64).round() as u64;
+    let eta = match status.download_rate {
+        0 if left > 0 => -1,
+        0 => 0,
+        rate => (left / rate) as i64,
+    };
+    let error = match &state {
+        TorrentState::Failed(e) => e.clone(),
+        _ => String::new(),
+    };
+
+    let all = json!({
+        "id": handle.id(),
+        "hashString": hex(&handle.info_hash()),
+        "name": handle.name(),
+        "status": status_code(handle, &state),
+        // Transmission's code for errors on our side, rather than the tracker's.
+        "error": if error.is_empty() { 0 } else { 3 },
+        "errorString": error,
+        "percentDone": status.progress,
+        "recheckProgress": status.checking.as_ref().map_or(0.0, |c| c.percent() / 100.0),
+        "totalSize": total_size,
+        "sizeWhenDone": total_size,
+        "leftUntilDone": left,
+        "isFinished": state == TorrentState::Complete,
+        "eta": eta,
+        "rateDownload": status.download_rate,
+        "rateUpload": status.upload_rate,
+        "downloadedEver": status.downloaded,
+        "uploadedEver": status.uploaded,
+        "uploadRatio": match status.downloaded {
+            0 => -1.0,
+            downloaded => status.uploaded as f64 / downloaded as f64,
+        },
+        "secondsSeeding": status.seeding_time,
+        "downloadDir": handle.save_path(),
+        "labels": status.category.into_iter().collect::<Vec<_>>(),
+        "peersConnected": status.peers.len(),
+        "queuePosition": handle.id(),
+        "fileCount": torrent.file.info.files.as_ref().map_or(1, |files| files.len()),
+        // 0 uses the session's limit, and 1 the torrent's own.
+        "seedRatioMode": if options.seed_ratio.is_some() { 1 } else { 0 },
+        "seedRatioLimit": options.seed_ratio.unwrap_or(0.0),
+        "seedIdleMode": 0,
+        "seedIdleLimit": 0,
+    });
+
+    let mut all = match all {
+        Value::Object(all) => all,
+        _ => unreachable!(),
+    };
+    match fields {
+        Some(fields) => Value::Object(
+            fields
+                .iter()
+                .filter_map(|field| Some((field.clone(), all.remove(field)?)))
+                .collect::<Map<_, _>>(),
+        ),
+        None => Value::Object(all),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{ClientConfig, Output};
+
+    async fn call_json(client: &Client, request: Value) -> Value {
+        let response = handle(client, serde_json::from_value(request).unwrap()).await;
+        serde_json::to_value(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn add_list_and_stop_torrents() {
+        let client = Client::new(ClientConfig {
+            port: 0,
+            output: Output::Discard,
+            dht: false,
+            lsd: false,
+            ..ClientConfig::new(std::env::temp_dir())
+        });
+        let torrent = crate::testing::torrent("transmission-test", &[7; 100], 64);
+        let metainfo =
+            base64::engine::general_purpose::STANDARD.encode(torrent.to_bytes().unwrap());
+        let hash = hex(&torrent.info_hash);
+
+        let add = json!({
+            "method": "torrent-add",
+            "arguments": { "metainfo": metainfo, "paused": true, "labels": ["tv"] },
+            "tag": 7,
+        });
+        let response = call_json(&client, add.clone()).await;
+        assert_eq!(response["result"], "success");
+        assert_eq!(response["tag"], 7);
+        assert_eq!(response["arguments"]["torrent-added"]["hashString"], hash);
+        let response = call_json(&client, add).await;
+        assert_eq!(
+            response["arguments"]["torrent-duplicate"]["hashString"],
+            hash
+        );
+
+        let get = json!({
+            "method": "torrent-get",
+            "arguments": { "ids": [hash], "fields": ["name", "status", "labels", "totalSize"] },
+        });
+        assert_eq!(
+            call_json(&client, get.clone()).await["arguments"]["torrents"],
+            json!([{ "name": "transmission-test", "status": 0, "labels": ["tv"], "totalSize": 100 }])
+        );
+
+        let start = json!({ "method": "torrent-start", "arguments": { "ids": 1 } });
+        assert_eq!(call_json(&client, start).await["result"], "success");
+        assert!(!client.torrents()[0].is_paused());
+
+        let remove = json!({ "method": "torrent-remove", "arguments": { "ids": [hash] } });
+        assert_eq!(call_json(&client, remove).await["result"], "success");
+        assert!(client.torrents().is_empty());
+
+        let turtle = json!({ "method": "session-set", "arguments": { "alt-speed-enabled": true } });
+        assert_eq!(call_json(&client, turtle).await["result"], "success");
+        let session = call_json(&client, json!({ "method": "session-get" })).await;
+        assert_eq!(session["arguments"]["alt-speed-enabled"], true);
+
+        let unknown = json!({ "method": "blocklist-update" });
+        assert_eq!(
+            call_json(&client, unknown).await["result"],
+            "method name not recognized"
+        );
+    }
+}